@@ -2,6 +2,7 @@ using System.Net;
 using System.Threading.Channels;
 using FalconNode.Core.Dht;
 using FalconNode.Core.FS;
+using FalconNode.Core.Interop;
 using FalconNode.Core.Messages;
 using FalconNode.Core.Network;
 using FalconNode.Core.Social;
@@ -139,8 +140,14 @@ if (isDebugMode)
 
 // --- APPLICATION BOOTSTRAP ---
 
+// Refuse to start against a native library built for another ABI
+RustVersion.EnsureCompatible();
+
 IHost host = builder.Build();
 
+// Native (Rust) logs go through the same logging pipeline
+RustLogBridge.Attach(host.Services.GetRequiredService<ILoggerFactory>().CreateLogger("freedom_core"));
+
 if (isDebugMode)
 {
     // Starts the services on background
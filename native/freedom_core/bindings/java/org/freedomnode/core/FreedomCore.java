@@ -0,0 +1,39 @@
+package org.freedomnode.core;
+
+/**
+ * JNI entry points of the native core (built with {@code cargo build --features jni}).
+ * Invalid arguments and malformed frames throw {@link IllegalArgumentException};
+ * failed decryption (tampered or replayed messages) throws {@link SecurityException}.
+ */
+public final class FreedomCore {
+    static {
+        System.loadLibrary("freedom_core");
+    }
+
+    private FreedomCore() {}
+
+    /**
+     * Derives a session from our X25519 private key, the peer's public key and a salt from each side
+     * (32 bytes each). Each side fills its salt from {@link java.security.SecureRandom} for every new
+     * session and sends it to the other.
+     */
+    public static native long sessionCreate(byte[] myPrivateKey, byte[] peerPublicKey, byte[] mySalt, byte[] peerSalt);
+
+    /** Encrypts the next message: [Counter (8)] + [Ciphertext + Tag]. */
+    public static native byte[] sessionEncrypt(long session, byte[] plaintext);
+
+    /** Decrypts a received message; counters may skip ahead but never repeat. */
+    public static native byte[] sessionDecrypt(long session, byte[] message);
+
+    /** Releases a session. The handle must not be used afterwards. */
+    public static native void sessionDestroy(long session);
+
+    /** Builds a wire frame (Header + Payload). {@code requestId} is an unsigned 32-bit value. */
+    public static native byte[] packetBuild(int messageType, int requestId, byte[] payload);
+
+    /** Returns the payload of a frame after validating its length and CRC32. */
+    public static native byte[] packetPayload(byte[] frame);
+
+    /** Returns {version, flags, messageType, requestId, payloadLength, checksum} of a validated frame. */
+    public static native int[] packetHeader(byte[] frame);
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+use freedom_core::ffi::crypto::ffi_validate_handshake;
+use freedom_core::ffi::protocol::{ ffi_header_parse, ffi_packet_parse, FfiFixedHeader };
+use libfuzzer_sys::fuzz_target;
+
+// Drives the exports the host feeds with network bytes, including the null-output size query
+fuzz_target!(|data: &[u8]| {
+    let mut header = FfiFixedHeader::default();
+    let mut payload = [0u8; 256];
+
+    unsafe {
+        ffi_header_parse(data.as_ptr(), data.len(), &mut header);
+
+        let required = ffi_packet_parse(data.as_ptr(), data.len(), &mut header, std::ptr::null_mut(), 0);
+        let written = ffi_packet_parse(data.as_ptr(), data.len(), &mut header, payload.as_mut_ptr(), payload.len());
+        if required >= 0 && (required as usize) <= payload.len() {
+            assert_eq!(written, required);
+        }
+
+        ffi_validate_handshake(data.as_ptr(), data.len());
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use freedom_core::protocol::header::{ FixedHeader, HEADER_SIZE };
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = FixedHeader::from_bytes(data) {
+        // Every field except the message type (unknown values collapse to 0xFF) survives a round trip
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[..2], data[..2]);
+        assert_eq!(bytes[3..HEADER_SIZE], data[3..HEADER_SIZE]);
+    }
+});
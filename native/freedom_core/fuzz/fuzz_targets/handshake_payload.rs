@@ -0,0 +1,11 @@
+#![no_main]
+
+use freedom_core::crypto::handshake::HandshakePayload;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(payload) = HandshakePayload::from_bytes(data) {
+        assert_eq!(payload.to_bytes()[..], data[..]);
+        let _ = payload.verify();
+    }
+});
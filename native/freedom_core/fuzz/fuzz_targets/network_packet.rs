@@ -0,0 +1,11 @@
+#![no_main]
+
+use freedom_core::protocol::packet::NetworkPacket;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = NetworkPacket::from_bytes(data) {
+        // An accepted frame always carries exactly the payload its header announced
+        assert_eq!(packet.payload.len(), packet.header.payload_length as usize);
+    }
+});
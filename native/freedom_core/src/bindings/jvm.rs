@@ -0,0 +1,184 @@
+use std::panic::{ self, AssertUnwindSafe };
+
+use jni::JNIEnv;
+use jni::objects::{ JByteArray, JClass, JIntArray };
+use jni::sys::{ jint, jlong };
+
+use crate::crypto::session::{ Session, SESSION_SALT_SIZE };
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+use super::to_array;
+
+// JNI surface for an Android node, matching `org.freedomnode.core.FreedomCore`
+// (bindings/java/org/freedomnode/core/FreedomCore.java). Everything is byte[] based; sessions
+// are opaque `long` handles released with `sessionDestroy`.
+
+const ILLEGAL_ARGUMENT: &str = "java/lang/IllegalArgumentException";
+const SECURITY_EXCEPTION: &str = "java/lang/SecurityException";
+const RUNTIME_EXCEPTION: &str = "java/lang/RuntimeException";
+
+/// Java exception to raise: (class, message)
+type JavaError = (&'static str, String);
+
+fn illegal_argument(error: impl ToString) -> JavaError {
+    (ILLEGAL_ARGUMENT, error.to_string())
+}
+
+fn security_error(error: impl ToString) -> JavaError {
+    (SECURITY_EXCEPTION, error.to_string())
+}
+
+/// Runs a JNI body, turning errors and panics into a pending Java exception.
+/// The returned default value is ignored by the JVM while an exception is pending.
+fn run<'local, T: Default>(
+    env: &mut JNIEnv<'local>,
+    body: impl FnOnce(&mut JNIEnv<'local>) -> Result<T, JavaError>
+) -> T {
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| body(env)));
+    let (class, message) = match outcome {
+        Ok(Ok(value)) => return value,
+        Ok(Err(error)) => error,
+        Err(_) => (RUNTIME_EXCEPTION, "Native panic".to_string()),
+    };
+
+    let _ = env.throw_new(class, message);
+    T::default()
+}
+
+fn read_bytes(env: &JNIEnv, array: &JByteArray) -> Result<Vec<u8>, JavaError> {
+    env.convert_byte_array(array).map_err(illegal_argument)
+}
+
+fn new_bytes<'local>(env: &JNIEnv<'local>, bytes: &[u8]) -> Result<JByteArray<'local>, JavaError> {
+    env.byte_array_from_slice(bytes).map_err(|e| (RUNTIME_EXCEPTION, e.to_string()))
+}
+
+/// # Safety (upheld by the Java wrapper)
+/// `handle` must come from `sessionCreate` and not have been destroyed.
+fn session_mut<'a>(handle: jlong) -> Result<&'a mut Session, JavaError> {
+    unsafe { (handle as *mut Session).as_mut() }.ok_or_else(|| illegal_argument("Null session handle"))
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_sessionCreate<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    my_private_key: JByteArray<'local>,
+    peer_public_key: JByteArray<'local>,
+    my_salt: JByteArray<'local>,
+    peer_salt: JByteArray<'local>,
+) -> jlong {
+    run(&mut env, |env| {
+        let my_private_key = to_array::<32>(&read_bytes(env, &my_private_key)?, "myPrivateKey").map_err(illegal_argument)?;
+        let peer_public_key = to_array::<32>(&read_bytes(env, &peer_public_key)?, "peerPublicKey").map_err(illegal_argument)?;
+        let my_salt = to_array::<SESSION_SALT_SIZE>(&read_bytes(env, &my_salt)?, "mySalt").map_err(illegal_argument)?;
+        let peer_salt = to_array::<SESSION_SALT_SIZE>(&read_bytes(env, &peer_salt)?, "peerSalt").map_err(illegal_argument)?;
+
+        let session = Session::new(
+            &x25519_dalek::StaticSecret::from(my_private_key),
+            &x25519_dalek::PublicKey::from(peer_public_key),
+            &my_salt,
+            &peer_salt
+        );
+        Ok(Box::into_raw(Box::new(session)) as jlong)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_sessionEncrypt<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    plaintext: JByteArray<'local>,
+) -> JByteArray<'local> {
+    run(&mut env, |env| {
+        let plaintext = read_bytes(env, &plaintext)?;
+        let message = session_mut(handle)?.encrypt(&plaintext).map_err(security_error)?;
+        new_bytes(env, &message)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_sessionDecrypt<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    message: JByteArray<'local>,
+) -> JByteArray<'local> {
+    run(&mut env, |env| {
+        let message = read_bytes(env, &message)?;
+        let plaintext = session_mut(handle)?.decrypt(&message).map_err(security_error)?;
+        new_bytes(env, &plaintext)
+    })
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_sessionDestroy<'local>(
+    _env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+) {
+    if handle != 0 {
+        drop(unsafe { Box::from_raw(handle as *mut Session) });
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_packetBuild<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    message_type: jint,
+    request_id: jint,
+    payload: JByteArray<'local>,
+) -> JByteArray<'local> {
+    run(&mut env, |env| {
+        let message_type = u8::try_from(message_type).map(MessageType::from).unwrap_or(MessageType::Unknown);
+        if message_type == MessageType::Unknown {
+            return Err(illegal_argument("Unknown message type"));
+        }
+
+        let payload = read_bytes(env, &payload)?;
+        // request_id is unsigned on the wire; Java ints carry the same 32 bits
+        let packet = NetworkPacket::new(message_type, request_id as u32, payload);
+        new_bytes(env, &packet.to_bytes())
+    })
+}
+
+/// Returns the payload of a validated frame (length + CRC32).
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_packetPayload<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    frame: JByteArray<'local>,
+) -> JByteArray<'local> {
+    run(&mut env, |env| {
+        let packet = NetworkPacket::from_bytes(&read_bytes(env, &frame)?).map_err(illegal_argument)?;
+        new_bytes(env, &packet.payload)
+    })
+}
+
+/// Returns the header of a validated frame as
+/// `{ version, flags, messageType, requestId, payloadLength, checksum }`.
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_org_freedomnode_core_FreedomCore_packetHeader<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    frame: JByteArray<'local>,
+) -> JIntArray<'local> {
+    run(&mut env, |env| {
+        let header = NetworkPacket::from_bytes(&read_bytes(env, &frame)?).map_err(illegal_argument)?.header;
+        let fields = [
+            header.version as jint,
+            header.flags as jint,
+            header.message_type as jint,
+            header.request_id as jint,
+            header.payload_length as jint,
+            header.checksum as jint,
+        ];
+
+        let array = env.new_int_array(fields.len() as jint).map_err(|e| (RUNTIME_EXCEPTION, e.to_string()))?;
+        env.set_int_array_region(&array, 0, &fields).map_err(|e| (RUNTIME_EXCEPTION, e.to_string()))?;
+        Ok(array)
+    })
+}
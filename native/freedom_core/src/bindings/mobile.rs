@@ -0,0 +1,193 @@
+use std::sync::{ Arc, Mutex };
+
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::identity::{ self, NodeIdentity, IDENTITY_SECRET_SIZE };
+use crate::crypto::{ helper, session };
+use crate::crypto::session::SESSION_SALT_SIZE;
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+use super::to_array;
+
+// UniFFI surface for Kotlin (Android) and Swift (iOS).
+// Generate bindings with `uniffi-bindgen generate --library libfreedom_core.so --language kotlin|swift`.
+
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+#[uniffi(flat_error)]
+pub enum FreedomError {
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Crypto error: {0}")]
+    Crypto(String),
+    #[error("Parse error: {0}")]
+    Parse(String),
+    #[error("Verification failed: {0}")]
+    Verification(String),
+}
+
+impl From<helper::CryptoError> for FreedomError {
+    fn from(e: helper::CryptoError) -> Self {
+        FreedomError::Crypto(e.to_string())
+    }
+}
+
+/// A node identity (Ed25519 identity key + X25519 onion key).
+#[derive(uniffi::Object)]
+pub struct Identity {
+    inner: NodeIdentity,
+}
+
+#[uniffi::export]
+impl Identity {
+    #[uniffi::constructor]
+    pub fn generate() -> Arc<Self> {
+        Arc::new(Self { inner: NodeIdentity::generate() })
+    }
+
+    /// Restores an identity from the 64 bytes returned by `secret_bytes`.
+    #[uniffi::constructor]
+    pub fn from_secret_bytes(secret: Vec<u8>) -> Result<Arc<Self>, FreedomError> {
+        let secret = to_array::<IDENTITY_SECRET_SIZE>(&secret, "secret").map_err(FreedomError::InvalidArgument)?;
+        Ok(Arc::new(Self { inner: NodeIdentity::from_secret_bytes(&secret) }))
+    }
+
+    pub fn secret_bytes(&self) -> Vec<u8> {
+        self.inner.to_secret_bytes().to_vec()
+    }
+
+    pub fn identity_key(&self) -> Vec<u8> {
+        self.inner.identity_keypair.verifying_key().to_bytes().to_vec()
+    }
+
+    pub fn onion_key(&self) -> Vec<u8> {
+        x25519_dalek::PublicKey::from(&self.inner.onion_secret).to_bytes().to_vec()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.inner.fingerprint().to_string()
+    }
+
+    pub fn sign(&self, message: Vec<u8>) -> Vec<u8> {
+        self.inner.sign(&message).to_bytes().to_vec()
+    }
+
+    /// Builds a signed 136-byte handshake payload.
+    pub fn sign_handshake(&self, timestamp: u64) -> Vec<u8> {
+        self.inner.sign_handshake(timestamp).to_bytes().to_vec()
+    }
+}
+
+#[uniffi::export]
+pub fn validate_handshake(payload: Vec<u8>) -> Result<(), FreedomError> {
+    let payload = HandshakePayload::from_bytes(&payload).map_err(|e| FreedomError::Parse(e.to_string()))?;
+    payload.verify().map_err(|e| FreedomError::Verification(e.to_string()))
+}
+
+#[uniffi::export]
+pub fn verify_signature(identity_key: Vec<u8>, message: Vec<u8>, signature: Vec<u8>) -> Result<(), FreedomError> {
+    let key = to_array::<32>(&identity_key, "identity_key").map_err(FreedomError::InvalidArgument)?;
+    let signature = to_array::<64>(&signature, "signature").map_err(FreedomError::InvalidArgument)?;
+    identity::verify_raw(&key, &message, &signature).map_err(|e| FreedomError::Verification(e.to_string()))
+}
+
+/// Header fields of a parsed wire frame.
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct PacketHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub message_type: u8,
+    pub request_id: u32,
+    pub payload_length: u32,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct Packet {
+    pub header: PacketHeader,
+    pub payload: Vec<u8>,
+}
+
+/// Builds a wire frame (Header + Payload).
+#[uniffi::export]
+pub fn build_packet(message_type: u8, request_id: u32, payload: Vec<u8>) -> Result<Vec<u8>, FreedomError> {
+    let message_type = MessageType::from(message_type);
+    if message_type == MessageType::Unknown {
+        return Err(FreedomError::InvalidArgument("Unknown message type".to_string()));
+    }
+    Ok(NetworkPacket::new(message_type, request_id, payload).to_bytes())
+}
+
+/// Parses and validates (length + CRC32) a wire frame.
+#[uniffi::export]
+pub fn parse_packet(frame: Vec<u8>) -> Result<Packet, FreedomError> {
+    let packet = NetworkPacket::from_bytes(&frame).map_err(|e| FreedomError::Parse(e.to_string()))?;
+    Ok(Packet {
+        header: PacketHeader {
+            version: packet.header.version,
+            flags: packet.header.flags,
+            message_type: packet.header.message_type as u8,
+            request_id: packet.header.request_id,
+            payload_length: packet.header.payload_length,
+            checksum: packet.header.checksum,
+        },
+        payload: packet.payload,
+    })
+}
+
+#[uniffi::export]
+pub fn encrypt_layer(key: Vec<u8>, plaintext: Vec<u8>) -> Result<Vec<u8>, FreedomError> {
+    let key = to_array::<32>(&key, "key").map_err(FreedomError::InvalidArgument)?;
+    Ok(helper::encrypt_layer(&key, &plaintext)?)
+}
+
+#[uniffi::export]
+pub fn decrypt_layer(key: Vec<u8>, ciphertext: Vec<u8>) -> Result<Vec<u8>, FreedomError> {
+    let key = to_array::<32>(&key, "key").map_err(FreedomError::InvalidArgument)?;
+    Ok(helper::try_decrypt_layer(&key, &ciphertext)?)
+}
+
+/// Fresh salt for `Session::new`
+#[uniffi::export]
+pub fn generate_session_salt() -> Vec<u8> {
+    session::Session::generate_salt().to_vec()
+}
+
+/// Encrypted channel with a peer; keys and nonce counters stay native.
+#[derive(uniffi::Object)]
+pub struct Session {
+    inner: Mutex<session::Session>,
+}
+
+#[uniffi::export]
+impl Session {
+    #[uniffi::constructor]
+    /// Each side generates its salt with `generate_session_salt` for every new session and sends it
+    /// to the other.
+    pub fn new(
+        my_private_key: Vec<u8>,
+        peer_public_key: Vec<u8>,
+        my_salt: Vec<u8>,
+        peer_salt: Vec<u8>,
+    ) -> Result<Arc<Self>, FreedomError> {
+        let my_private_key = to_array::<32>(&my_private_key, "my_private_key").map_err(FreedomError::InvalidArgument)?;
+        let peer_public_key = to_array::<32>(&peer_public_key, "peer_public_key").map_err(FreedomError::InvalidArgument)?;
+        let my_salt = to_array::<SESSION_SALT_SIZE>(&my_salt, "my_salt").map_err(FreedomError::InvalidArgument)?;
+        let peer_salt = to_array::<SESSION_SALT_SIZE>(&peer_salt, "peer_salt").map_err(FreedomError::InvalidArgument)?;
+
+        let session = session::Session::new(
+            &x25519_dalek::StaticSecret::from(my_private_key),
+            &x25519_dalek::PublicKey::from(peer_public_key),
+            &my_salt,
+            &peer_salt
+        );
+        Ok(Arc::new(Self { inner: Mutex::new(session) }))
+    }
+
+    pub fn encrypt(&self, plaintext: Vec<u8>) -> Result<Vec<u8>, FreedomError> {
+        Ok(self.inner.lock().unwrap_or_else(|e| e.into_inner()).encrypt(&plaintext)?)
+    }
+
+    pub fn decrypt(&self, message: Vec<u8>) -> Result<Vec<u8>, FreedomError> {
+        Ok(self.inner.lock().unwrap_or_else(|e| e.into_inner()).decrypt(&message)?)
+    }
+}
@@ -0,0 +1,19 @@
+// Idiomatic wrappers for other languages, each behind its own feature. They share the core
+// types and only translate arguments and errors; wire formats stay defined in `crypto` / `protocol`.
+#[cfg(feature = "jni")]
+pub mod jvm;
+#[cfg(feature = "uniffi")]
+pub mod mobile;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+/// Converts a host-provided byte vector into a fixed-size key, naming the argument on failure.
+#[allow(dead_code)] // Unused when no binding feature is enabled
+pub(crate) fn to_array<const N: usize>(bytes: &[u8], name: &str) -> Result<[u8; N], String> {
+    bytes.try_into().map_err(|_| format!("`{name}` must be {N} bytes, got {}", bytes.len()))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,101 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::onion;
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+use super::to_array;
+
+// PyO3 module for research scripts and test harnesses, importable as `freedom_core`.
+// Build with `maturin develop --features python` (see pyproject.toml).
+
+fn value_error(error: impl ToString) -> PyErr {
+    PyValueError::new_err(error.to_string())
+}
+
+/// A parsed and validated wire frame.
+#[pyclass(frozen, get_all)]
+pub struct Packet {
+    version: u8,
+    flags: u8,
+    message_type: u8,
+    request_id: u32,
+    checksum: u32,
+    payload: Vec<u8>,
+}
+
+#[pymethods]
+impl Packet {
+    fn __repr__(&self) -> String {
+        format!(
+            "Packet(message_type=0x{:02x}, request_id={}, payload_len={})",
+            self.message_type,
+            self.request_id,
+            self.payload.len()
+        )
+    }
+}
+
+/// Parses and validates (length + CRC32) a wire frame. Raises ValueError if it is malformed.
+#[pyfunction]
+fn parse_packet(frame: &[u8]) -> PyResult<Packet> {
+    let packet = NetworkPacket::from_bytes(frame).map_err(value_error)?;
+    Ok(Packet {
+        version: packet.header.version,
+        flags: packet.header.flags,
+        message_type: packet.header.message_type as u8,
+        request_id: packet.header.request_id,
+        checksum: packet.header.checksum,
+        payload: packet.payload,
+    })
+}
+
+/// Builds a wire frame (Header + Payload).
+#[pyfunction]
+fn build_packet<'py>(py: Python<'py>, message_type: u8, request_id: u32, payload: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let message_type = MessageType::from(message_type);
+    if message_type == MessageType::Unknown {
+        return Err(value_error("Unknown message type"));
+    }
+    Ok(PyBytes::new(py, &NetworkPacket::new(message_type, request_id, payload.to_vec()).to_bytes()))
+}
+
+/// Returns True if the 136-byte handshake payload is well-formed and correctly signed.
+#[pyfunction]
+fn verify_handshake(payload: &[u8]) -> bool {
+    HandshakePayload::from_bytes(payload).is_ok_and(|payload| payload.verify().is_ok())
+}
+
+/// Wraps a payload in one layer per hop. `hop_keys` is a list of 32-byte keys, first hop first.
+#[pyfunction]
+fn onion_wrap<'py>(py: Python<'py>, hop_keys: Vec<Vec<u8>>, payload: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let keys = hop_keys
+        .iter()
+        .map(|key| to_array::<32>(key, "hop_keys[i]"))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(value_error)?;
+    let cell = onion::wrap(&keys, payload).map_err(value_error)?;
+    Ok(PyBytes::new(py, &cell))
+}
+
+/// Removes one layer with the given hop key.
+#[pyfunction]
+fn onion_peel<'py>(py: Python<'py>, key: &[u8], cell: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    let key = to_array::<32>(key, "key").map_err(value_error)?;
+    let inner = onion::peel(&key, cell).map_err(value_error)?;
+    Ok(PyBytes::new(py, &inner))
+}
+
+#[pymodule]
+fn freedom_core(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Packet>()?;
+    module.add_function(wrap_pyfunction!(parse_packet, module)?)?;
+    module.add_function(wrap_pyfunction!(build_packet, module)?)?;
+    module.add_function(wrap_pyfunction!(verify_handshake, module)?)?;
+    module.add_function(wrap_pyfunction!(onion_wrap, module)?)?;
+    module.add_function(wrap_pyfunction!(onion_peel, module)?)?;
+    Ok(())
+}
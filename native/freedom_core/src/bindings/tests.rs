@@ -0,0 +1,30 @@
+#[cfg(feature = "uniffi")]
+use super::mobile;
+
+/// Unit test: The mobile surface round trips identity, handshake, packet and session calls
+#[cfg(feature = "uniffi")]
+#[test]
+fn test_mobile_surface_roundtrip() {
+    let identity = mobile::Identity::generate();
+    let restored = mobile::Identity::from_secret_bytes(identity.secret_bytes()).unwrap();
+    assert_eq!(identity.identity_key(), restored.identity_key());
+
+    mobile::validate_handshake(identity.sign_handshake(1700000000)).unwrap();
+    assert!(matches!(mobile::Identity::from_secret_bytes(vec![0; 3]), Err(mobile::FreedomError::InvalidArgument(_))));
+
+    let frame = mobile::build_packet(0x07, 9, b"fetch".to_vec()).unwrap();
+    let packet = mobile::parse_packet(frame).unwrap();
+    assert_eq!((packet.header.message_type, packet.header.request_id), (0x07, 9));
+    assert_eq!(packet.payload, b"fetch");
+
+    let alice = [1u8; 32];
+    let bob = [2u8; 32];
+    let alice_public = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(alice)).to_bytes().to_vec();
+    let bob_public = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(bob)).to_bytes().to_vec();
+    let alice_salt = mobile::generate_session_salt();
+    let bob_salt = mobile::generate_session_salt();
+    let alice_session = mobile::Session::new(alice.to_vec(), bob_public, alice_salt.clone(), bob_salt.clone()).unwrap();
+    let bob_session = mobile::Session::new(bob.to_vec(), alice_public, bob_salt, alice_salt).unwrap();
+    let message = alice_session.encrypt(b"hi".to_vec()).unwrap();
+    assert_eq!(bob_session.decrypt(message).unwrap(), b"hi");
+}
@@ -0,0 +1,294 @@
+use wasm_bindgen::prelude::*;
+
+use crate::clock::unix_now_ms;
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::identity::{ NodeIdentity, IDENTITY_SECRET_SIZE };
+use crate::crypto::onion;
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+use crate::transport::websocket::{ WebSocketTransport, WsCommand, WsConfig, WsEvent };
+use crate::transport::{ ConnectionId, TransportEvent };
+
+use super::to_array;
+
+// wasm-bindgen surface for a browser light client.
+// Build with `cargo build --target wasm32-unknown-unknown --features wasm` + `wasm-bindgen --target web`.
+
+fn js_error(message: impl ToString) -> JsError {
+    JsError::new(&message.to_string())
+}
+
+/// A node identity (Ed25519 identity key + X25519 onion key).
+#[wasm_bindgen]
+pub struct Identity {
+    inner: NodeIdentity,
+}
+
+#[wasm_bindgen]
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity { inner: NodeIdentity::generate() }
+    }
+
+    #[wasm_bindgen(js_name = fromSecretBytes)]
+    pub fn from_secret_bytes(secret: &[u8]) -> Result<Identity, JsError> {
+        let secret = to_array::<IDENTITY_SECRET_SIZE>(secret, "secret").map_err(js_error)?;
+        Ok(Identity { inner: NodeIdentity::from_secret_bytes(&secret) })
+    }
+
+    #[wasm_bindgen(js_name = secretBytes)]
+    pub fn secret_bytes(&self) -> Vec<u8> {
+        self.inner.to_secret_bytes().to_vec()
+    }
+
+    #[wasm_bindgen(js_name = identityKey)]
+    pub fn identity_key(&self) -> Vec<u8> {
+        self.inner.identity_keypair.verifying_key().to_bytes().to_vec()
+    }
+
+    #[wasm_bindgen(js_name = onionKey)]
+    pub fn onion_key(&self) -> Vec<u8> {
+        x25519_dalek::PublicKey::from(&self.inner.onion_secret).to_bytes().to_vec()
+    }
+
+    #[wasm_bindgen(js_name = signHandshake)]
+    pub fn sign_handshake(&self, timestamp: u64) -> Vec<u8> {
+        self.inner.sign_handshake(timestamp).to_bytes().to_vec()
+    }
+}
+
+#[wasm_bindgen(js_name = validateHandshake)]
+pub fn validate_handshake(payload: &[u8]) -> Result<(), JsError> {
+    HandshakePayload::from_bytes(payload).map_err(js_error)?.verify().map_err(js_error)
+}
+
+/// Builds a wire frame (Header + Payload).
+#[wasm_bindgen(js_name = buildPacket)]
+pub fn build_packet(message_type: u8, request_id: u32, payload: &[u8]) -> Result<Vec<u8>, JsError> {
+    let message_type = MessageType::from(message_type);
+    if message_type == MessageType::Unknown {
+        return Err(js_error("Unknown message type"));
+    }
+    Ok(NetworkPacket::new(message_type, request_id, payload.to_vec()).to_bytes())
+}
+
+/// A parsed and validated wire frame.
+#[wasm_bindgen]
+pub struct Packet {
+    inner: NetworkPacket,
+}
+
+#[wasm_bindgen]
+impl Packet {
+    #[wasm_bindgen(getter, js_name = messageType)]
+    pub fn message_type(&self) -> u8 {
+        self.inner.header.message_type as u8
+    }
+
+    #[wasm_bindgen(getter, js_name = requestId)]
+    pub fn request_id(&self) -> u32 {
+        self.inner.header.request_id
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn payload(&self) -> Vec<u8> {
+        self.inner.payload.clone()
+    }
+}
+
+/// Parses and validates (length + CRC32) a wire frame.
+#[wasm_bindgen(js_name = parsePacket)]
+pub fn parse_packet(frame: &[u8]) -> Result<Packet, JsError> {
+    Ok(Packet { inner: NetworkPacket::from_bytes(frame).map_err(js_error)? })
+}
+
+/// Wraps a payload in one layer per hop. `hop_keys` holds the 32-byte keys back to back,
+/// ordered from the first hop to the last.
+#[wasm_bindgen(js_name = onionWrap)]
+pub fn onion_wrap(hop_keys: &[u8], payload: &[u8]) -> Result<Vec<u8>, JsError> {
+    if hop_keys.is_empty() || !hop_keys.len().is_multiple_of(32) {
+        return Err(js_error("`hop_keys` must hold one or more 32-byte keys"));
+    }
+    let keys: Vec<[u8; 32]> = hop_keys
+        .chunks_exact(32)
+        .map(|key| key.try_into().expect("chunks of 32 bytes"))
+        .collect();
+    onion::wrap(&keys, payload).map_err(js_error)
+}
+
+/// Removes one layer with the given hop key.
+#[wasm_bindgen(js_name = onionPeel)]
+pub fn onion_peel(key: &[u8], cell: &[u8]) -> Result<Vec<u8>, JsError> {
+    let key = to_array::<32>(key, "key").map_err(js_error)?;
+    onion::peel(&key, cell).map_err(js_error)
+}
+
+/// What the page does with its WebSockets: "open" `url`, "send" `bytes` or "close".
+#[wasm_bindgen]
+pub struct SocketCommand {
+    kind: &'static str,
+    connection: u64,
+    url: Option<String>,
+    bytes: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl SocketCommand {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn url(&self) -> Option<String> {
+        self.url.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// "connected" to the gateway with `identityKey`, a "packet" received, "migrated" to another
+/// address, or "closed" with `error`.
+#[wasm_bindgen]
+pub struct SocketEvent {
+    kind: &'static str,
+    connection: u64,
+    identity_key: Vec<u8>,
+    packet: Option<NetworkPacket>,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl SocketEvent {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> String {
+        self.kind.to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    #[wasm_bindgen(getter, js_name = identityKey)]
+    pub fn identity_key(&self) -> Vec<u8> {
+        self.identity_key.clone()
+    }
+
+    /// The packet of a "packet" event, once
+    #[wasm_bindgen(js_name = takePacket)]
+    pub fn take_packet(&mut self) -> Option<Packet> {
+        self.packet.take().map(|inner| Packet { inner })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+/// A browser node's connections to gateways over WebSockets. The page opens, feeds and
+/// closes the sockets as `takeCommands` says, reports their events, and calls `onTimeout`
+/// at `nextTimeout`.
+#[wasm_bindgen]
+pub struct WebSocketClient {
+    inner: WebSocketTransport,
+}
+
+#[wasm_bindgen]
+impl WebSocketClient {
+    /// `path` is where gateways serve the network, "/freedom" by default; `secure` picks wss://.
+    #[wasm_bindgen(constructor)]
+    pub fn new(identity: &Identity, path: Option<String>, secure: bool) -> WebSocketClient {
+        let identity = NodeIdentity::from_secret_bytes(&identity.inner.to_secret_bytes());
+        let defaults = WsConfig::default();
+        let config = WsConfig { path: path.unwrap_or(defaults.path), secure, ..defaults };
+        WebSocketClient { inner: WebSocketTransport::new(identity, config) }
+    }
+
+    /// Dials the gateway at "ip:port". Returns the connection.
+    pub fn dial(&mut self, addr: &str) -> Result<u64, JsError> {
+        let addr = addr.parse().map_err(js_error)?;
+        Ok(self.inner.dial(addr, unix_now_ms()).to_raw())
+    }
+
+    pub fn opened(&mut self, connection: u64) {
+        self.inner.on_opened(ConnectionId::from_raw(connection));
+    }
+
+    pub fn message(&mut self, connection: u64, bytes: &[u8]) {
+        self.inner.on_message(ConnectionId::from_raw(connection), bytes, unix_now_ms());
+    }
+
+    pub fn closed(&mut self, connection: u64) {
+        self.inner.on_closed(ConnectionId::from_raw(connection));
+    }
+
+    pub fn send(&mut self, connection: u64, message_type: u8, request_id: u32, payload: &[u8]) -> Result<(), JsError> {
+        let packet = NetworkPacket::from_bytes(&build_packet(message_type, request_id, payload)?).map_err(js_error)?;
+        self.inner.send(ConnectionId::from_raw(connection), &packet).map_err(js_error)
+    }
+
+    pub fn close(&mut self, connection: u64) -> bool {
+        self.inner.close(ConnectionId::from_raw(connection))
+    }
+
+    /// When to call `onTimeout` next, in milliseconds since the epoch
+    #[wasm_bindgen(js_name = nextTimeout)]
+    pub fn next_timeout(&self) -> Option<f64> {
+        self.inner.next_timeout().map(|at| at as f64)
+    }
+
+    #[wasm_bindgen(js_name = onTimeout)]
+    pub fn on_timeout(&mut self) {
+        self.inner.on_timeout(unix_now_ms());
+    }
+
+    #[wasm_bindgen(js_name = takeCommands)]
+    pub fn take_commands(&mut self) -> Vec<SocketCommand> {
+        self.inner
+            .take_outgoing()
+            .into_iter()
+            .map(|command| match command {
+                WsCommand::Open { connection, url } => SocketCommand { kind: "open", connection: connection.to_raw(), url: Some(url), bytes: Vec::new() },
+                WsCommand::Send { connection, bytes } => SocketCommand { kind: "send", connection: connection.to_raw(), url: None, bytes },
+                WsCommand::Close(connection) => SocketCommand { kind: "close", connection: connection.to_raw(), url: None, bytes: Vec::new() },
+            })
+            .collect()
+    }
+
+    #[wasm_bindgen(js_name = takeEvents)]
+    pub fn take_events(&mut self) -> Vec<SocketEvent> {
+        self.inner
+            .take_events()
+            .into_iter()
+            .map(|event: WsEvent| {
+                let mut out = SocketEvent { kind: "", connection: 0, identity_key: Vec::new(), packet: None, error: None };
+                match event {
+                    TransportEvent::Connected { connection, peer, .. } => {
+                        (out.kind, out.connection) = ("connected", connection.to_raw());
+                        out.identity_key = peer.identity_key.to_bytes().to_vec();
+                    }
+                    TransportEvent::Packet { connection, packet } => {
+                        (out.kind, out.connection, out.packet) = ("packet", connection.to_raw(), Some(packet));
+                    }
+                    TransportEvent::Migrated { connection, .. } => {
+                        (out.kind, out.connection) = ("migrated", connection.to_raw());
+                    }
+                    TransportEvent::Closed { connection, error } => {
+                        (out.kind, out.connection, out.error) = ("closed", connection.to_raw(), Some(error.to_string()));
+                    }
+                }
+                out
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+#[derive(Debug, thiserror::Error)]
+#[error("Operation was cancelled")]
+pub struct Cancelled;
+
+/// Shared flag that long-running operations poll between units of work.
+/// Clones observe the same flag, so the host keeps one and hands clones to jobs.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Returns `Err(Cancelled)` once cancelled, for use with `?` inside loops
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+    }
+}
@@ -0,0 +1,33 @@
+/// Current time in seconds since UNIX epoch (the unit used by every timestamp on the wire)
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_now() -> u64 {
+    use std::time::{ SystemTime, UNIX_EPOCH };
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Current time in seconds since UNIX epoch (`SystemTime` is unavailable in the browser)
+#[cfg(target_arch = "wasm32")]
+pub fn unix_now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+/// Current time in milliseconds since UNIX epoch, as the sans-IO state machines count it
+#[cfg(not(target_arch = "wasm32"))]
+pub fn unix_now_ms() -> u64 {
+    use std::time::{ SystemTime, UNIX_EPOCH };
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Current time in milliseconds since UNIX epoch
+#[cfg(target_arch = "wasm32")]
+pub fn unix_now_ms() -> u64 {
+    js_sys::Date::now() as u64
+}
@@ -0,0 +1,109 @@
+use std::io;
+use std::sync::Mutex;
+
+use rand::rngs::{ OsRng, StdRng };
+use rand::{ RngCore, SeedableRng };
+
+use crate::crypto::handshake::{ HANDSHAKE_PAYLOAD_SIZE, HANDSHAKE_WINDOW_SECS };
+use crate::protocol::packet::DEFAULT_MAX_PAYLOAD_LEN;
+use crate::worker::WorkerPool;
+
+pub const MAX_WORKER_THREADS: usize = 1024;
+pub const MAX_HANDSHAKE_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("worker_threads must be at most {MAX_WORKER_THREADS}, got {0}")]
+    TooManyThreads(usize),
+    #[error("max_payload_len must be between {HANDSHAKE_PAYLOAD_SIZE} and {max}, got {got}", max = u32::MAX)]
+    InvalidMaxPayload {
+        got: usize,
+    },
+    #[error("handshake_window_secs must be between 1 and {MAX_HANDSHAKE_WINDOW_SECS}, got {0}")]
+    InvalidHandshakeWindow(u64),
+    #[error("log_level must be between -1 and 6, got {0}")]
+    InvalidLogLevel(i32),
+}
+
+/// Settings fixed when a context is created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextSettings {
+    /// Threads in the worker pool (0 = one per available core)
+    pub worker_threads: usize,
+    /// Largest frame payload the context's exports accept
+    pub max_payload_len: usize,
+    /// Allowed clock drift of handshake timestamps, in either direction
+    pub handshake_window_secs: u64,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            worker_threads: 0,
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            handshake_window_secs: HANDSHAKE_WINDOW_SECS,
+        }
+    }
+}
+
+impl ContextSettings {
+    /// Rejects values no deployment should run with (a payload limit too small for a handshake,
+    /// a drift window that makes replayed handshakes acceptable for days, ...)
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.worker_threads > MAX_WORKER_THREADS {
+            return Err(ConfigError::TooManyThreads(self.worker_threads));
+        }
+        if !(HANDSHAKE_PAYLOAD_SIZE..=u32::MAX as usize).contains(&self.max_payload_len) {
+            return Err(ConfigError::InvalidMaxPayload { got: self.max_payload_len });
+        }
+        if !(1..=MAX_HANDSHAKE_WINDOW_SECS).contains(&self.handshake_window_secs) {
+            return Err(ConfigError::InvalidHandshakeWindow(self.handshake_window_secs));
+        }
+        Ok(())
+    }
+}
+
+/// Everything a running core instance owns: settings, the worker pool and a seeded RNG.
+/// Several contexts can coexist (e.g. one per hosted node); nothing here is process-global.
+///
+/// `FreedomContext` is `Send + Sync`: every method takes `&self` and mutable state is behind
+/// its own lock, so a single context may be shared by all host threads.
+pub struct FreedomContext {
+    settings: ContextSettings,
+    pool: WorkerPool,
+    rng: Mutex<StdRng>,
+}
+
+// Compile-time check of the guarantee documented above
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<FreedomContext>();
+};
+
+impl FreedomContext {
+    pub fn new(settings: ContextSettings) -> io::Result<Self> {
+        let pool = match settings.worker_threads {
+            0 => WorkerPool::with_default_size()?,
+            threads => WorkerPool::new(threads)?,
+        };
+
+        Ok(Self {
+            settings,
+            pool,
+            rng: Mutex::new(StdRng::from_rng(OsRng).map_err(io::Error::other)?),
+        })
+    }
+
+    pub fn settings(&self) -> &ContextSettings {
+        &self.settings
+    }
+
+    pub fn pool(&self) -> &WorkerPool {
+        &self.pool
+    }
+
+    /// Fills `buf` from the context RNG (ChaCha-based, seeded from the OS)
+    pub fn fill_random(&self, buf: &mut [u8]) {
+        self.rng.lock().unwrap_or_else(|e| e.into_inner()).fill_bytes(buf);
+    }
+}
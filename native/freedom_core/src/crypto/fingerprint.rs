@@ -0,0 +1,130 @@
+use ed25519_dalek::VerifyingKey;
+use sha2::{ Digest, Sha256, Sha512 };
+use std::fmt;
+
+// Domain separation labels so fingerprints never collide with other hashes of the same key
+const FINGERPRINT_LABEL: &[u8] = b"FreedomNode-Fingerprint-v1";
+const SAFETY_NUMBER_LABEL: &[u8] = b"FreedomNode-SafetyNumber-v1";
+
+pub const FINGERPRINT_SIZE: usize = 32;
+const SAFETY_NUMBER_GROUPS: usize = 12;
+const SAFETY_NUMBER_GROUP_BYTES: usize = 5;
+const EMOJI_COUNT: usize = 8;
+
+/// 64 visually distinct emoji, indexed by 6 bits of the hash.
+const EMOJI_TABLE: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐴", "🦄", "🐷", "🐘", "🐰",
+    "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌",
+    "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🏠", "🚂", "🚲", "✈️", "🚀", "🏆", "⚽", "🎸",
+    "🎺", "🔔", "⚓", "🎧", "📁", "📌", "🔑", "🔒",
+    "🔨", "📎", "✂️", "💡", "📖", "✏️", "🎁", "⏰",
+    "☂️", "⭐", "🍀", "🌈", "👑", "🎩", "👓", "🧩",
+];
+
+/// Short, stable digest of an identity key that users can compare out-of-band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint([u8; FINGERPRINT_SIZE]);
+
+impl Fingerprint {
+    /// Computes the fingerprint of an Ed25519 identity key.
+    pub fn of(identity_key: &VerifyingKey) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(FINGERPRINT_LABEL);
+        hasher.update(identity_key.as_bytes());
+
+        Self(hasher.finalize().into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; FINGERPRINT_SIZE] {
+        &self.0
+    }
+
+    /// Renders the first 16 bytes as 8 groups of 4 uppercase hex digits.
+    /// Format: "A1B2 C3D4 ..."
+    pub fn to_hex_groups(&self) -> String {
+        self.0[..16]
+            .chunks(2)
+            .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Renders the fingerprint as a short emoji sequence, easier to compare by eye on mobile screens.
+    pub fn to_emoji(&self) -> String {
+        emoji_sequence(&self.0)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex_groups())
+    }
+}
+
+/// Pairwise verification code shared by two peers.
+/// Both sides compute the same value regardless of argument order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    digits: String,
+    emoji: String,
+}
+
+impl SafetyNumber {
+    /// 60 decimal digits, formatted as 12 groups of 5 separated by spaces.
+    pub fn digits(&self) -> &str {
+        &self.digits
+    }
+
+    pub fn emoji(&self) -> &str {
+        &self.emoji
+    }
+}
+
+impl fmt::Display for SafetyNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.digits)
+    }
+}
+
+/// Computes the safety number between two identity keys.
+/// The keys are sorted first, so `safety_number(a, b) == safety_number(b, a)`.
+pub fn safety_number(a: &VerifyingKey, b: &VerifyingKey) -> SafetyNumber {
+    let (first, second) = if a.as_bytes() <= b.as_bytes() { (a, b) } else { (b, a) };
+
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_NUMBER_LABEL);
+    hasher.update(first.as_bytes());
+    hasher.update(second.as_bytes());
+    let digest = hasher.finalize();
+
+    // Each 5-byte chunk becomes a 5-digit group (Signal-style)
+    let digits = digest[..SAFETY_NUMBER_GROUPS * SAFETY_NUMBER_GROUP_BYTES]
+        .chunks(SAFETY_NUMBER_GROUP_BYTES)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    SafetyNumber {
+        digits,
+        // The digit groups consume most of the digest, so the emoji form is derived from a re-hash
+        emoji: emoji_sequence(&Sha256::digest(digest)),
+    }
+}
+
+fn emoji_sequence(bytes: &[u8]) -> String {
+    // Read 6 bits per symbol from the start of the buffer
+    (0..EMOJI_COUNT)
+        .map(|i| {
+            let bit = i * 6;
+            let window = (u16::from(bytes[bit / 8]) << 8) | u16::from(bytes[bit / 8 + 1]);
+            let index = (window >> (10 - (bit % 8))) & 0x3F;
+            EMOJI_TABLE[index as usize]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -2,11 +2,16 @@ use ed25519_dalek::{ Verifier, Signature };
 use x25519_dalek::{ PublicKey as X25519PublicKey };
 use std::convert::TryInto;
 
-const IDENTITY_KEY_SIZE: usize = 32;
-const ONION_KEY_SIZE: usize = 32;
-const TIMESTAMP_SIZE: usize = 8;
-const SIGNATURE_SIZE: usize = 64;
-const HANDSHAKE_PAYLOAD_SIZE: usize = 136;
+use crate::cancel::{ CancellationToken, Cancelled };
+
+pub const IDENTITY_KEY_SIZE: usize = 32;
+pub const ONION_KEY_SIZE: usize = 32;
+pub const TIMESTAMP_SIZE: usize = 8;
+pub const SIGNATURE_SIZE: usize = 64;
+pub const HANDSHAKE_PAYLOAD_SIZE: usize = 136;
+
+/// How far a handshake timestamp may drift from the local clock, in either direction, before it is stale
+pub const HANDSHAKE_WINDOW_SECS: u64 = 300;
 
 #[derive(Debug, Clone)]
 pub struct HandshakePayload {
@@ -30,6 +35,11 @@ pub enum HandshakeError {
     InvalidSignature,
     #[error("Signature verification failed")]
     VerificationFailed,
+    #[error("Stale handshake timestamp {timestamp} (now {now})")]
+    StaleTimestamp {
+        timestamp: u64,
+        now: u64,
+    },
 }
 
 impl HandshakePayload {
@@ -66,6 +76,10 @@ impl HandshakePayload {
 
         let timestamp = u64::from_be_bytes(bytes[64..72].try_into().unwrap());
 
+        // The scalar half of an Ed25519 signature is below 2^253, so the top three bits are always clear
+        if bytes[HANDSHAKE_PAYLOAD_SIZE - 1] & 0xe0 != 0 {
+            return Err(HandshakeError::InvalidSignature);
+        }
         let signature = Signature::from_bytes(bytes[72..136].try_into().unwrap());
 
         Ok(Self {
@@ -87,4 +101,34 @@ impl HandshakePayload {
             .verify(&message, &self.signature)
             .map_err(|_| HandshakeError::VerificationFailed)
     }
+
+    /// Rejects payloads whose timestamp is more than `window_secs` away from `now`
+    pub fn check_freshness(&self, now: u64, window_secs: u64) -> Result<(), HandshakeError> {
+        if self.timestamp.abs_diff(now) > window_secs {
+            return Err(HandshakeError::StaleTimestamp { timestamp: self.timestamp, now });
+        }
+        Ok(())
+    }
+}
+
+/// Fully validates (encoding, signature, freshness) a run of back-to-back 136-byte payloads,
+/// checking `token` between payloads so a large batch can be abandoned.
+pub fn validate_batch(
+    payloads: &[u8],
+    now: u64,
+    window_secs: u64,
+    token: &CancellationToken
+) -> Result<Vec<Result<(), HandshakeError>>, Cancelled> {
+    payloads
+        .chunks(HANDSHAKE_PAYLOAD_SIZE)
+        .map(|bytes| {
+            token.check()?;
+            Ok(
+                HandshakePayload::from_bytes(bytes).and_then(|payload| {
+                    payload.verify()?;
+                    payload.check_freshness(now, window_secs)
+                })
+            )
+        })
+        .collect()
 }
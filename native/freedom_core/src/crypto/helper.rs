@@ -1,4 +1,4 @@
-use chacha20poly1305::{ aead::{ Aead, KeyInit }, ChaCha20Poly1305, Nonce };
+use chacha20poly1305::{ aead::{ Aead, KeyInit, Payload }, ChaCha20Poly1305, Nonce };
 use hkdf::Hkdf;
 use sha2::Sha256;
 use x25519_dalek::{ PublicKey, StaticSecret };
@@ -7,6 +7,10 @@ use rand::rngs::OsRng;
 
 const NONCE_SIZE: usize = 12;
 const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+
+/// Bytes `encrypt_layer` adds to a plaintext: [Nonce (12)] + [Tag (16)]
+pub const LAYER_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
 
 #[derive(Debug, thiserror::Error)]
 pub enum CryptoError {
@@ -16,6 +20,10 @@ pub enum CryptoError {
     DecryptionError,
     #[error("Invalid data length")]
     InvalidLength,
+    #[error("Message was already received (replay)")]
+    ReplayedMessage,
+    #[error("Session nonce space exhausted; a new session is required")]
+    NonceExhausted,
 }
 
 /// Derives a session key exactly as NSec's KeyAgreementAlgorithm.X25519 + HkdfSha256 does.
@@ -38,6 +46,11 @@ pub fn create_session_key(my_private_key: &StaticSecret, other_public_key: &Publ
 /// Encrypts data matching C# format: [Nonce (12)] + [Ciphertext]
 /// C# Reference: EncryptLayer method
 pub fn encrypt_layer(session_key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    encrypt_layer_with_aad(session_key, plaintext, &[])
+}
+
+/// Same as `encrypt_layer`, but also authenticates `aad`, which decrypting must be given again.
+pub fn encrypt_layer_with_aad(session_key: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let cipher = ChaCha20Poly1305::new(session_key.into());
 
     // Generate random Nonce (12 bytes)
@@ -46,8 +59,10 @@ pub fn encrypt_layer(session_key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     // Encrypt
-    // Note: NSec's "default" associated data is empty, which matches here.
-    let ciphertext = cipher.encrypt(nonce, plaintext).map_err(|_| CryptoError::EncryptionError)?;
+    // Note: NSec's "default" associated data is empty, which matches `encrypt_layer`.
+    let ciphertext = cipher
+        .encrypt(nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| CryptoError::EncryptionError)?;
 
     // Combine: [Nonce] + [Ciphertext]
     let mut output = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
@@ -62,6 +77,15 @@ pub fn encrypt_layer(session_key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>
 pub fn try_decrypt_layer(
     session_key: &[u8; 32],
     encrypted_packet: &[u8]
+) -> Result<Vec<u8>, CryptoError> {
+    try_decrypt_layer_with_aad(session_key, encrypted_packet, &[])
+}
+
+/// Same as `try_decrypt_layer`, for data encrypted by `encrypt_layer_with_aad` with the same `aad`.
+pub fn try_decrypt_layer_with_aad(
+    session_key: &[u8; 32],
+    encrypted_packet: &[u8],
+    aad: &[u8]
 ) -> Result<Vec<u8>, CryptoError> {
     if encrypted_packet.len() < NONCE_SIZE {
         return Err(CryptoError::InvalidLength);
@@ -76,5 +100,5 @@ pub fn try_decrypt_layer(
     let ciphertext = &encrypted_packet[NONCE_SIZE..];
 
     // Decrypt
-    cipher.decrypt(nonce, ciphertext).map_err(|_| CryptoError::DecryptionError)
+    cipher.decrypt(nonce, Payload { msg: ciphertext, aad }).map_err(|_| CryptoError::DecryptionError)
 }
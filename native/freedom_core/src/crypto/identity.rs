@@ -1,7 +1,18 @@
-use ed25519_dalek::{SigningKey, Signer};
+use ed25519_dalek::{SigningKey, Signer, Signature, VerifyingKey, Verifier};
 use x25519_dalek::{StaticSecret};
 use rand::rngs::OsRng;
 
+/// Size of the exported private material: [identity_secret (32 bytes) | onion_secret (32 bytes)]
+pub const IDENTITY_SECRET_SIZE: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("Invalid identity key bytes")]
+    InvalidIdentityKey,
+    #[error("Signature verification failed")]
+    VerificationFailed,
+}
+
 pub struct NodeIdentity {
     pub identity_keypair: SigningKey,  // Holds both Public and Private keys for Identity
     pub onion_secret: StaticSecret,    // X25519 Private key
@@ -14,7 +25,7 @@ impl NodeIdentity {
     pub fn generate() -> Self {
         let mut csprng = OsRng;
         let identity_keypair = SigningKey::generate(&mut csprng);
-        let onion_secret = StaticSecret::random_from_rng(&mut csprng);
+        let onion_secret = StaticSecret::random_from_rng(csprng);
 
         Self {
             identity_keypair,
@@ -22,6 +33,36 @@ impl NodeIdentity {
         }
     }
 
+    /// Restores an identity from bytes produced by `to_secret_bytes`
+    pub fn from_secret_bytes(bytes: &[u8; IDENTITY_SECRET_SIZE]) -> Self {
+        let identity_keypair = SigningKey::from_bytes(bytes[0..32].try_into().unwrap());
+        let onion_secret = StaticSecret::from(<[u8; 32]>::try_from(&bytes[32..64]).unwrap());
+
+        Self {
+            identity_keypair,
+            onion_secret,
+        }
+    }
+
+    /// Exports the private keys
+    /// Format: [identity_secret (32 bytes) | onion_secret (32 bytes)]
+    pub fn to_secret_bytes(&self) -> [u8; IDENTITY_SECRET_SIZE] {
+        let mut bytes = [0u8; IDENTITY_SECRET_SIZE];
+        bytes[0..32].copy_from_slice(self.identity_keypair.as_bytes());
+        bytes[32..64].copy_from_slice(self.onion_secret.as_bytes());
+        bytes
+    }
+
+    /// Returns the fingerprint of this node's identity key, for out-of-band verification
+    pub fn fingerprint(&self) -> super::fingerprint::Fingerprint {
+        super::fingerprint::Fingerprint::of(&self.identity_keypair.verifying_key())
+    }
+
+    /// Signs arbitrary application data with the identity key (Ed25519)
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.identity_keypair.sign(message)
+    }
+
     /// Signs a handshake payload with the identity key
     pub fn sign_handshake(
         &self,
@@ -35,7 +76,7 @@ impl NodeIdentity {
         message[32..64].copy_from_slice(onion_pub.as_bytes());
         message[64..72].copy_from_slice(&timestamp.to_be_bytes());
 
-        let signature = self.identity_keypair.sign(&message);
+        let signature = self.sign(&message);
 
         super::handshake::HandshakePayload {
             identity_key: identity_pub,
@@ -44,4 +85,25 @@ impl NodeIdentity {
             signature,
         }
     }
-}
\ No newline at end of file
+}
+
+/// Verifies a signature produced by `NodeIdentity::sign`
+pub fn verify(
+    identity_key: &VerifyingKey,
+    message: &[u8],
+    signature: &Signature
+) -> Result<(), IdentityError> {
+    identity_key
+        .verify(message, signature)
+        .map_err(|_| IdentityError::VerificationFailed)
+}
+
+/// Same as `verify`, but takes the raw 32-byte identity key as received from the wire
+pub fn verify_raw(
+    identity_key: &[u8; 32],
+    message: &[u8],
+    signature: &[u8; 64]
+) -> Result<(), IdentityError> {
+    let key = VerifyingKey::from_bytes(identity_key).map_err(|_| IdentityError::InvalidIdentityKey)?;
+    verify(&key, message, &Signature::from_bytes(signature))
+}
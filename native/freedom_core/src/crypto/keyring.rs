@@ -0,0 +1,328 @@
+use argon2::Argon2;
+use ed25519_dalek::VerifyingKey;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use zeroize::Zeroizing;
+
+use super::helper::{ self, CryptoError };
+use super::identity::{ NodeIdentity, IDENTITY_SECRET_SIZE };
+use crate::protocol::codec::{ CodecError, Reader };
+
+const KEYRING_MAGIC: &[u8; 4] = b"FNKR";
+const KEYRING_VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const MAX_LABEL_LEN: usize = 255;
+
+// Entry flags
+const FLAG_ENCRYPTED: u8 = 0x01;
+const FLAG_DEFAULT: u8 = 0x02;
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyringError {
+    #[error("Identity label already in use: {0}")]
+    DuplicateLabel(String),
+    #[error("Identity label must be between 1 and 255 bytes")]
+    InvalidLabel,
+    #[error("Unknown identity: {0}")]
+    NotFound(String),
+    #[error("Identity is encrypted and must be unlocked first: {0}")]
+    Locked(String),
+    #[error("Wrong passphrase or corrupted identity")]
+    WrongPassphrase,
+    /// The sealed secret decrypted, but isn't the one of the entry's public key
+    #[error("Identity does not match its public key: {0}")]
+    KeyMismatch(String),
+    #[error("Key derivation failed")]
+    KeyDerivation,
+    #[error("Sealing the identity failed: {0}")]
+    Seal(CryptoError),
+    #[error("Malformed keyring data: {0}")]
+    Malformed(#[from] CodecError),
+}
+
+/// Private material of a keyring entry, either in clear or sealed with a passphrase.
+enum StoredSecret {
+    Plain(Box<NodeIdentity>),
+    Encrypted {
+        salt: [u8; SALT_SIZE],
+        sealed: Vec<u8>, // [Nonce (12)] + [Ciphertext + Tag]
+    },
+}
+
+struct KeyringEntry {
+    label: String,
+    identity_key: VerifyingKey,
+    secret: StoredSecret,
+}
+
+/// Holds several node identities (personas) under human-readable labels.
+/// Example: a public "relay" identity and a private "client" identity on the same installation.
+#[derive(Default)]
+pub struct Keyring {
+    entries: Vec<KeyringEntry>,
+    default_index: Option<usize>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an identity stored in clear. The first identity added becomes the default.
+    pub fn add(&mut self, label: &str, identity: NodeIdentity) -> Result<(), KeyringError> {
+        self.check_label(label)?;
+
+        self.push(KeyringEntry {
+            label: label.to_string(),
+            identity_key: identity.identity_keypair.verifying_key(),
+            secret: StoredSecret::Plain(Box::new(identity)),
+        });
+        Ok(())
+    }
+
+    /// Adds an identity whose private keys are sealed with a key derived from `passphrase` (Argon2id).
+    pub fn add_encrypted(
+        &mut self,
+        label: &str,
+        identity: &NodeIdentity,
+        passphrase: &[u8]
+    ) -> Result<(), KeyringError> {
+        self.check_label(label)?;
+
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+
+        let key = derive_key(passphrase, &salt)?;
+        let secret = Zeroizing::new(identity.to_secret_bytes());
+        let sealed = helper
+            ::encrypt_layer_with_aad(&key, secret.as_slice(), &sealing_aad(label))
+            .map_err(KeyringError::Seal)?;
+
+        self.push(KeyringEntry {
+            label: label.to_string(),
+            identity_key: identity.identity_keypair.verifying_key(),
+            secret: StoredSecret::Encrypted { salt, sealed },
+        });
+        Ok(())
+    }
+
+    /// Removes an identity. If it was the default, no identity is selected afterwards.
+    pub fn remove(&mut self, label: &str) -> Result<(), KeyringError> {
+        let index = self.index_of(label)?;
+        self.entries.remove(index);
+
+        self.default_index = match self.default_index {
+            Some(d) if d == index => None,
+            Some(d) if d > index => Some(d - 1),
+            other => other,
+        };
+        Ok(())
+    }
+
+    pub fn set_default(&mut self, label: &str) -> Result<(), KeyringError> {
+        self.default_index = Some(self.index_of(label)?);
+        Ok(())
+    }
+
+    pub fn default_label(&self) -> Option<&str> {
+        self.default_index.map(|i| self.entries[i].label.as_str())
+    }
+
+    /// Returns the default identity, if one is selected and stored in clear.
+    pub fn default_identity(&self) -> Result<&NodeIdentity, KeyringError> {
+        match self.default_label() {
+            Some(label) => self.get(label),
+            None => Err(KeyringError::NotFound("<default>".to_string())),
+        }
+    }
+
+    /// Labels in insertion order.
+    pub fn labels(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.label.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Public identity key of an entry. Available even when the entry is encrypted.
+    pub fn public_key(&self, label: &str) -> Result<VerifyingKey, KeyringError> {
+        Ok(self.entries[self.index_of(label)?].identity_key)
+    }
+
+    pub fn is_encrypted(&self, label: &str) -> Result<bool, KeyringError> {
+        let entry = &self.entries[self.index_of(label)?];
+        Ok(matches!(entry.secret, StoredSecret::Encrypted { .. }))
+    }
+
+    /// Borrows an identity stored in clear.
+    pub fn get(&self, label: &str) -> Result<&NodeIdentity, KeyringError> {
+        match &self.entries[self.index_of(label)?].secret {
+            StoredSecret::Plain(identity) => Ok(identity),
+            StoredSecret::Encrypted { .. } => Err(KeyringError::Locked(label.to_string())),
+        }
+    }
+
+    /// Decrypts an encrypted identity and returns a usable copy. The entry itself stays sealed.
+    /// Fails unless the identity is the one of the entry's public key.
+    pub fn unlock(&self, label: &str, passphrase: &[u8]) -> Result<NodeIdentity, KeyringError> {
+        let entry = &self.entries[self.index_of(label)?];
+        match &entry.secret {
+            StoredSecret::Plain(identity) => {
+                Ok(NodeIdentity::from_secret_bytes(&Zeroizing::new(identity.to_secret_bytes())))
+            }
+            StoredSecret::Encrypted { salt, sealed } => {
+                let key = derive_key(passphrase, salt)?;
+                let plain = Zeroizing::new(
+                    helper
+                        ::try_decrypt_layer_with_aad(&key, sealed, &sealing_aad(label))
+                        .map_err(|_| KeyringError::WrongPassphrase)?
+                );
+
+                let secret: Zeroizing<[u8; IDENTITY_SECRET_SIZE]> = Zeroizing::new(
+                    plain
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| CodecError::InvalidField("sealed secret"))?
+                );
+                let identity = NodeIdentity::from_secret_bytes(&secret);
+                if identity.identity_keypair.verifying_key() != entry.identity_key {
+                    return Err(KeyringError::KeyMismatch(label.to_string()));
+                }
+                Ok(identity)
+            }
+        }
+    }
+
+    /// Serializes the keyring for persistence.
+    /// Format: [Magic "FNKR" (4)] [Version (1)] [Count (2)] + N * Entry
+    /// Entry: [LabelLen (1)] [Label] [Flags (1)] [IdentityKey (32)] +
+    ///        Plain: [Secret (64)] | Encrypted: [Salt (16)] [SealedLen (2)] [Sealed]
+    /// Sealed secrets authenticate the magic, version and label (see `sealing_aad`).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(KEYRING_MAGIC);
+        out.push(KEYRING_VERSION);
+        out.extend_from_slice(&(self.entries.len() as u16).to_be_bytes());
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            out.push(entry.label.len() as u8);
+            out.extend_from_slice(entry.label.as_bytes());
+
+            let mut flags = 0u8;
+            if self.default_index == Some(i) {
+                flags |= FLAG_DEFAULT;
+            }
+            if let StoredSecret::Encrypted { .. } = entry.secret {
+                flags |= FLAG_ENCRYPTED;
+            }
+            out.push(flags);
+            out.extend_from_slice(entry.identity_key.as_bytes());
+
+            match &entry.secret {
+                StoredSecret::Plain(identity) => out.extend_from_slice(Zeroizing::new(identity.to_secret_bytes()).as_slice()),
+                StoredSecret::Encrypted { salt, sealed } => {
+                    out.extend_from_slice(salt);
+                    out.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+                    out.extend_from_slice(sealed);
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Parses a keyring produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeyringError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(4)? != KEYRING_MAGIC || reader.u8()? != KEYRING_VERSION {
+            return Err(CodecError::InvalidField("header").into());
+        }
+
+        let count = reader.u16()?;
+        let mut keyring = Keyring::new();
+
+        for i in 0..count as usize {
+            let label_len = reader.u8()? as usize;
+            let label = std::str
+                ::from_utf8(reader.take(label_len)?)
+                .map_err(|_| CodecError::InvalidField("label"))?
+                .to_string();
+            let flags = reader.u8()?;
+            let identity_key = VerifyingKey::from_bytes(&reader.take_array()?).map_err(
+                |_| CodecError::InvalidField("identity key")
+            )?;
+
+            let secret = if flags & FLAG_ENCRYPTED != 0 {
+                let salt = reader.take_array()?;
+                let sealed_len = reader.u16()? as usize;
+                StoredSecret::Encrypted { salt, sealed: reader.take(sealed_len)?.to_vec() }
+            } else {
+                let identity = NodeIdentity::from_secret_bytes(&Zeroizing::new(reader.take_array()?));
+                if identity.identity_keypair.verifying_key() != identity_key {
+                    return Err(KeyringError::KeyMismatch(label));
+                }
+                StoredSecret::Plain(Box::new(identity))
+            };
+
+            keyring.check_label(&label)?;
+            keyring.entries.push(KeyringEntry { label, identity_key, secret });
+
+            if flags & FLAG_DEFAULT != 0 {
+                keyring.default_index = Some(i);
+            }
+        }
+
+        reader.finish()?;
+        Ok(keyring)
+    }
+
+    fn push(&mut self, entry: KeyringEntry) {
+        self.entries.push(entry);
+        if self.default_index.is_none() {
+            self.default_index = Some(self.entries.len() - 1);
+        }
+    }
+
+    fn check_label(&self, label: &str) -> Result<(), KeyringError> {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(KeyringError::InvalidLabel);
+        }
+        if self.entries.iter().any(|e| e.label == label) {
+            return Err(KeyringError::DuplicateLabel(label.to_string()));
+        }
+        Ok(())
+    }
+
+    fn index_of(&self, label: &str) -> Result<usize, KeyringError> {
+        self.entries
+            .iter()
+            .position(|e| e.label == label)
+            .ok_or_else(|| KeyringError::NotFound(label.to_string()))
+    }
+}
+
+/// Derives a 32-byte sealing key from a passphrase using Argon2id with default parameters.
+fn derive_key(passphrase: &[u8], salt: &[u8; SALT_SIZE]) -> Result<Zeroizing<[u8; 32]>, KeyringError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase, salt, key.as_mut_slice())
+        .map_err(|_| KeyringError::KeyDerivation)?;
+    Ok(key)
+}
+
+/// What a sealed secret authenticates besides itself, so it can't be moved to another entry
+/// or read back under another format version.
+/// Format: [Magic "FNKR" (4)] [Version (1)] [LabelLen (1)] [Label]
+fn sealing_aad(label: &str) -> Vec<u8> {
+    let mut aad = KEYRING_MAGIC.to_vec();
+    aad.push(KEYRING_VERSION);
+    aad.push(label.len() as u8);
+    aad.extend_from_slice(label.as_bytes());
+    aad
+}
@@ -1,3 +1,11 @@
 pub mod handshake;
 pub mod identity;
-pub mod helper;
\ No newline at end of file
+pub mod helper;
+pub mod fingerprint;
+pub mod keyring;
+pub mod onion;
+pub mod session;
+pub mod trust;
+
+#[cfg(test)]
+mod tests;
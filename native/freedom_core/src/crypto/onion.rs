@@ -0,0 +1,18 @@
+use super::helper::{ self, CryptoError };
+
+/// Wraps a payload in one ChaCha20-Poly1305 layer per hop.
+/// `hop_keys` is ordered from the first hop to the last: the last hop's layer is the innermost,
+/// so each relay peels exactly one layer with its own key.
+/// Format per layer: [Nonce (12)] + [Ciphertext] (same as `helper::encrypt_layer`)
+pub fn wrap(hop_keys: &[[u8; 32]], payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let mut cell = payload.to_vec();
+    for key in hop_keys.iter().rev() {
+        cell = helper::encrypt_layer(key, &cell)?;
+    }
+    Ok(cell)
+}
+
+/// Removes the outermost layer with this hop's key.
+pub fn peel(hop_key: &[u8; 32], cell: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    helper::try_decrypt_layer(hop_key, cell)
+}
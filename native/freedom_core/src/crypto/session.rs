@@ -0,0 +1,137 @@
+use chacha20poly1305::{ aead::{ Aead, KeyInit }, ChaCha20Poly1305, Nonce };
+use hkdf::Hkdf;
+use rand::{ rngs::OsRng, RngCore };
+use sha2::Sha256;
+use x25519_dalek::{ PublicKey, StaticSecret };
+
+use super::helper::{ self, CryptoError };
+
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// Bytes added to every message by `Session::encrypt`: [Counter (8)] + [Tag (16)]
+pub const SESSION_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+/// Random bytes each side contributes to a session, see `Session::generate_salt`
+pub const SESSION_SALT_SIZE: usize = 32;
+
+// HKDF labels for the two directions. The side with the lower public key sends on "low".
+const LABEL_LOW_TO_HIGH: &[u8] = b"FreedomNode-Session-v1 low->high";
+const LABEL_HIGH_TO_LOW: &[u8] = b"FreedomNode-Session-v1 high->low";
+
+/// Authenticated channel between two nodes, keyed once from X25519 and a salt from each side.
+/// Each direction has its own key and a counter nonce. The salts make the keys fresh for every
+/// session, even between the same two static keys, so no key and nonce pair is ever reused;
+/// replayed or reordered-backwards messages are rejected.
+pub struct Session {
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_counter: u64,
+    recv_next: u64, // Lowest counter still accepted
+}
+
+impl Session {
+    /// Derives a session from our X25519 key, the peer's, and the salt each side generated with
+    /// `generate_salt` and sent to the other for this session.
+    pub fn new(
+        my_private_key: &StaticSecret,
+        peer_public_key: &PublicKey,
+        my_salt: &[u8; SESSION_SALT_SIZE],
+        peer_salt: &[u8; SESSION_SALT_SIZE],
+    ) -> Self {
+        // Ordered like the labels, so both ends feed HKDF the same salt
+        let salt = if is_low(my_private_key, peer_public_key) {
+            [my_salt.as_slice(), peer_salt].concat()
+        } else {
+            [peer_salt.as_slice(), my_salt].concat()
+        };
+        Self::derive(my_private_key, peer_public_key, Some(&salt))
+    }
+
+    /// Derives a session from a key pair made for this session alone, like the ephemeral keys of
+    /// the transport and end-to-end handshakes: the shared secret is already fresh, so no salt is
+    /// needed. Never use it with a long-lived key.
+    pub(crate) fn from_ephemeral(my_private_key: &StaticSecret, peer_public_key: &PublicKey) -> Self {
+        Self::derive(my_private_key, peer_public_key, None)
+    }
+
+    /// Random salt to send to the peer before both sides call `new`.
+    pub fn generate_salt() -> [u8; SESSION_SALT_SIZE] {
+        let mut salt = [0u8; SESSION_SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    fn derive(my_private_key: &StaticSecret, peer_public_key: &PublicKey, salt: Option<&[u8]>) -> Self {
+        let session_key = helper::create_session_key(my_private_key, peer_public_key);
+        let hk = Hkdf::<Sha256>::new(salt, &session_key);
+
+        let (send_label, recv_label) = if is_low(my_private_key, peer_public_key) {
+            (LABEL_LOW_TO_HIGH, LABEL_HIGH_TO_LOW)
+        } else {
+            (LABEL_HIGH_TO_LOW, LABEL_LOW_TO_HIGH)
+        };
+
+        let mut send_key = [0u8; 32];
+        let mut recv_key = [0u8; 32];
+        hk.expand(send_label, &mut send_key).expect("32 bytes is a valid length for SHA-256 HKDF");
+        hk.expand(recv_label, &mut recv_key).expect("32 bytes is a valid length for SHA-256 HKDF");
+
+        Self {
+            send_cipher: ChaCha20Poly1305::new(&send_key.into()),
+            recv_cipher: ChaCha20Poly1305::new(&recv_key.into()),
+            send_counter: 0,
+            recv_next: 0,
+        }
+    }
+
+    /// Encrypts the next outgoing message.
+    /// Format: [Counter (8)] + [Ciphertext + Tag]
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let counter = self.send_counter;
+        self.send_counter = counter.checked_add(1).ok_or(CryptoError::NonceExhausted)?;
+
+        let ciphertext = self.send_cipher
+            .encrypt(&counter_nonce(counter), plaintext)
+            .map_err(|_| CryptoError::EncryptionError)?;
+
+        let mut output = Vec::with_capacity(COUNTER_SIZE + ciphertext.len());
+        output.extend_from_slice(&counter.to_be_bytes());
+        output.extend_from_slice(&ciphertext);
+        Ok(output)
+    }
+
+    /// Decrypts an incoming message. Counters may skip ahead (lost messages) but never go back.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if message.len() < SESSION_OVERHEAD {
+            return Err(CryptoError::InvalidLength);
+        }
+
+        let (counter_bytes, ciphertext) = message.split_at(COUNTER_SIZE);
+        let counter = u64::from_be_bytes(counter_bytes.try_into().expect("split at COUNTER_SIZE"));
+        if counter < self.recv_next {
+            log::debug!("Dropped replayed session message (counter {counter}, expected >= {})", self.recv_next);
+            return Err(CryptoError::ReplayedMessage);
+        }
+
+        let plaintext = self.recv_cipher
+            .decrypt(&counter_nonce(counter), ciphertext)
+            .map_err(|_| CryptoError::DecryptionError)?;
+
+        // Only advance once the message is authentic, so forged counters can't burn the window
+        self.recv_next = counter.saturating_add(1);
+        Ok(plaintext)
+    }
+}
+
+/// Whether our public key is the lower of the two
+fn is_low(my_private_key: &StaticSecret, peer_public_key: &PublicKey) -> bool {
+    PublicKey::from(my_private_key).as_bytes() < peer_public_key.as_bytes()
+}
+
+/// Nonce: [Zero (4)] + [Counter BE (8)]
+fn counter_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
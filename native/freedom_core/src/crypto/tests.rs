@@ -0,0 +1,219 @@
+use crate::crypto::fingerprint::{ safety_number, Fingerprint };
+use crate::crypto::identity::{ self, IdentityError, NodeIdentity };
+use crate::crypto::keyring::{ Keyring, KeyringError };
+use crate::crypto::trust::{ TrustError, TrustStatus, TrustStore };
+use crate::dht::node_id::NodeId;
+
+/// Unit test: Fingerprint is deterministic and human-readable
+#[test]
+fn test_fingerprint_is_stable_and_formatted() {
+    let node = NodeIdentity::generate();
+
+    let first = node.fingerprint();
+    let second = Fingerprint::of(&node.identity_keypair.verifying_key());
+    assert_eq!(first, second);
+
+    // 8 groups of 4 hex digits
+    let text = first.to_string();
+    assert_eq!(text.split(' ').count(), 8);
+    assert!(text.split(' ').all(|g| g.len() == 4));
+
+    assert_eq!(first.to_emoji().split(' ').count(), 8);
+}
+
+/// Unit test: Safety number is symmetric and unique per pair
+#[test]
+fn test_safety_number_is_symmetric() {
+    let alice = NodeIdentity::generate().identity_keypair.verifying_key();
+    let bob = NodeIdentity::generate().identity_keypair.verifying_key();
+    let carol = NodeIdentity::generate().identity_keypair.verifying_key();
+
+    let ab = safety_number(&alice, &bob);
+    let ba = safety_number(&bob, &alice);
+    assert_eq!(ab, ba);
+
+    // 12 groups of 5 digits
+    assert_eq!(ab.digits().len(), 12 * 5 + 11);
+    assert!(ab.digits().split(' ').all(|g| g.len() == 5 && g.chars().all(|c| c.is_ascii_digit())));
+
+    assert_ne!(ab, safety_number(&alice, &carol));
+}
+
+/// Unit test: Keyring persistence with a plain and a passphrase-protected identity
+#[test]
+fn test_keyring_roundtrip_and_unlock() {
+    let relay = NodeIdentity::generate();
+    let client = NodeIdentity::generate();
+    let client_key = client.identity_keypair.verifying_key();
+
+    let mut keyring = Keyring::new();
+    keyring.add("relay", relay).unwrap();
+    keyring.add_encrypted("client", &client, b"correct horse").unwrap();
+    keyring.set_default("client").unwrap();
+
+    assert!(matches!(keyring.add("relay", NodeIdentity::generate()), Err(KeyringError::DuplicateLabel(_))));
+
+    // Persist and reload
+    let restored = Keyring::from_bytes(&keyring.to_bytes()).expect("Failed to parse keyring");
+    assert_eq!(restored.labels().collect::<Vec<_>>(), vec!["relay", "client"]);
+    assert_eq!(restored.default_label(), Some("client"));
+    assert!(restored.get("relay").is_ok());
+
+    // Encrypted entries stay locked until the right passphrase is given
+    assert!(matches!(restored.get("client"), Err(KeyringError::Locked(_))));
+    assert!(matches!(restored.unlock("client", b"wrong"), Err(KeyringError::WrongPassphrase)));
+
+    let unlocked = restored.unlock("client", b"correct horse").unwrap();
+    assert_eq!(unlocked.identity_keypair.verifying_key(), client_key);
+    assert_eq!(restored.public_key("client").unwrap(), client_key);
+}
+
+/// Unit test: An encrypted entry only unlocks under its own label and public key
+#[test]
+fn test_keyring_sealed_entry_bindings() {
+    let client = NodeIdentity::generate();
+    let mut keyring = Keyring::new();
+    keyring.add_encrypted("client", &client, b"correct horse").unwrap();
+    let bytes = keyring.to_bytes();
+    // [Magic (4)] [Version (1)] [Count (2)] [LabelLen (1)] [Label (6)] [Flags (1)] [IdentityKey (32)] ...
+    let (label, identity_key) = (8..14, 15..47);
+
+    let mut relabelled = bytes.clone();
+    relabelled[label].copy_from_slice(b"server");
+    let restored = Keyring::from_bytes(&relabelled).unwrap();
+    assert!(matches!(restored.unlock("server", b"correct horse"), Err(KeyringError::WrongPassphrase)));
+
+    let mut rekeyed = bytes;
+    rekeyed[identity_key].copy_from_slice(NodeIdentity::generate().identity_keypair.verifying_key().as_bytes());
+    let restored = Keyring::from_bytes(&rekeyed).unwrap();
+    assert!(matches!(restored.unlock("client", b"correct horse"), Err(KeyringError::KeyMismatch(_))));
+}
+
+/// Unit test: Arbitrary data signing and verification
+#[test]
+fn test_sign_and_verify_application_data() {
+    let node = NodeIdentity::generate();
+    let key = node.identity_keypair.verifying_key();
+
+    let signature = node.sign(b"post #42");
+    identity::verify(&key, b"post #42", &signature).expect("Valid signature rejected");
+
+    // Tampered message
+    assert!(matches!(
+        identity::verify(&key, b"post #43", &signature),
+        Err(IdentityError::VerificationFailed)
+    ));
+
+    // Raw variant, as used by the FFI layer
+    identity::verify_raw(key.as_bytes(), b"post #42", &signature.to_bytes()).unwrap();
+}
+
+/// Unit test: Trust-on-first-use, pinning and denylist
+#[test]
+fn test_trust_store_tofu_and_persistence() {
+    let node_id = NodeId::random();
+    let addr = "198.51.100.4:4433".parse().unwrap();
+    let original = *NodeIdentity::generate().identity_keypair.verifying_key().as_bytes();
+    let impostor = *NodeIdentity::generate().identity_keypair.verifying_key().as_bytes();
+
+    let mut store = TrustStore::new();
+    assert_eq!(store.check(node_id, Some(addr), &original, 100).unwrap(), TrustStatus::NewlyRecorded);
+    assert_eq!(store.check(node_id, Some(addr), &original, 200).unwrap(), TrustStatus::KnownFirstSeen);
+
+    // A different key for the same node or address is rejected
+    assert!(matches!(store.check(node_id, None, &impostor, 300), Err(TrustError::KeyChanged(_))));
+    assert!(matches!(store.check(NodeId::random(), Some(addr), &impostor, 300), Err(TrustError::KeyChanged(_))));
+
+    store.deny(impostor);
+    assert!(matches!(store.check(NodeId::random(), None, &impostor, 300), Err(TrustError::Denied)));
+
+    store.pin(node_id, original, 400);
+
+    let mut restored = TrustStore::from_bytes(&store.to_bytes()).expect("Failed to parse trust store");
+    assert_eq!(restored.check(node_id, Some(addr), &original, 500).unwrap(), TrustStatus::KnownPinned);
+    assert_eq!(restored.entry(&node_id).unwrap().first_seen, 100);
+    assert!(restored.is_denied(&impostor));
+}
+
+/// Unit test: Pinning a rotated key moves the peer's address over to it
+#[test]
+fn test_trust_store_pin_after_rotation() {
+    let node_id = NodeId::random();
+    let addr = "198.51.100.4:4433".parse().unwrap();
+    let old_key = *NodeIdentity::generate().identity_keypair.verifying_key().as_bytes();
+    let new_key = *NodeIdentity::generate().identity_keypair.verifying_key().as_bytes();
+
+    let mut store = TrustStore::new();
+    assert_eq!(store.check(node_id, Some(addr), &old_key, 100).unwrap(), TrustStatus::NewlyRecorded);
+    assert!(matches!(store.check(node_id, Some(addr), &new_key, 200), Err(TrustError::KeyChanged(_))));
+
+    store.pin(node_id, new_key, 300);
+    assert_eq!(store.check(node_id, Some(addr), &new_key, 400).unwrap(), TrustStatus::KnownPinned);
+    assert!(matches!(store.check(node_id, Some(addr), &old_key, 500), Err(TrustError::KeyChanged(_))));
+}
+
+/// Unit test: Both ends derive matching directional keys and replays are rejected
+#[test]
+fn test_session_roundtrip_and_replay() {
+    use crate::crypto::session::Session;
+    use crate::crypto::helper::CryptoError;
+    use x25519_dalek::{ PublicKey, StaticSecret };
+
+    let alice_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let bob_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let (alice_salt, bob_salt) = (Session::generate_salt(), Session::generate_salt());
+    let mut alice = Session::new(&alice_secret, &PublicKey::from(&bob_secret), &alice_salt, &bob_salt);
+    let mut bob = Session::new(&bob_secret, &PublicKey::from(&alice_secret), &bob_salt, &alice_salt);
+
+    let first = alice.encrypt(b"first").unwrap();
+    let second = alice.encrypt(b"second").unwrap();
+    assert_ne!(first[8..], second[8..]);
+
+    // Skipping a message is fine, going back is not
+    assert_eq!(bob.decrypt(&second).unwrap(), b"second");
+    assert!(matches!(bob.decrypt(&first), Err(CryptoError::ReplayedMessage)));
+    assert!(matches!(bob.decrypt(&second), Err(CryptoError::ReplayedMessage)));
+
+    // The other direction uses its own key
+    let reply = bob.encrypt(b"reply").unwrap();
+    assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+    assert!(alice.decrypt(&first).is_err());
+}
+
+/// Unit test: Two sessions between the same static keys get fresh keys from their salts
+#[test]
+fn test_session_salts_separate_sessions() {
+    use crate::crypto::session::Session;
+    use x25519_dalek::{ PublicKey, StaticSecret };
+
+    let alice_secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+    let bob_public = PublicKey::from(&StaticSecret::random_from_rng(rand::rngs::OsRng));
+
+    let mut first = Session::new(&alice_secret, &bob_public, &Session::generate_salt(), &Session::generate_salt());
+    let mut second = Session::new(&alice_secret, &bob_public, &Session::generate_salt(), &Session::generate_salt());
+
+    // Same counter, same plaintext: only the keys can tell them apart
+    let a = first.encrypt(b"same message").unwrap();
+    let b = second.encrypt(b"same message").unwrap();
+    assert_eq!(a[..8], b[..8]);
+    assert_ne!(a[8..], b[8..]);
+}
+
+/// Unit test: Each hop peels exactly its own layer, in circuit order
+#[test]
+fn test_onion_wrap_peels_in_hop_order() {
+    use crate::crypto::helper::try_decrypt_layer;
+    use crate::crypto::onion;
+
+    let hop_keys = [[1u8; 32], [2u8; 32], [3u8; 32]];
+    let mut cell = onion::wrap(&hop_keys, b"to the exit").unwrap();
+    assert_eq!(cell.len(), b"to the exit".len() + 3 * 28);
+
+    // The last hop's key can't open the outer layer
+    assert!(try_decrypt_layer(&hop_keys[2], &cell).is_err());
+
+    for key in &hop_keys {
+        cell = try_decrypt_layer(key, &cell).unwrap();
+    }
+    assert_eq!(cell, b"to the exit");
+}
@@ -0,0 +1,259 @@
+use std::collections::{ HashMap, HashSet };
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+
+const TRUST_STORE_MAGIC: &[u8; 4] = b"FNTS";
+const TRUST_STORE_VERSION: u8 = 1;
+
+// Record kinds in the persisted file
+const RECORD_NODE: u8 = 0x01;
+const RECORD_ADDRESS: u8 = 0x02;
+const RECORD_DENIED: u8 = 0x03;
+
+/// How an identity key came to be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TrustLevel {
+    /// Recorded automatically on first contact (trust on first use)
+    FirstSeen = 1,
+    /// Explicitly pinned by the operator (e.g. after comparing safety numbers)
+    Pinned = 2,
+}
+
+/// Result of checking a peer's identity key against the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustStatus {
+    /// Never seen before; the key has now been recorded
+    NewlyRecorded,
+    /// Matches the key recorded on first use
+    KnownFirstSeen,
+    /// Matches an explicitly pinned key
+    KnownPinned,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrustError {
+    #[error("Identity key for {0} changed unexpectedly")]
+    KeyChanged(String),
+    #[error("Identity key is on the denylist")]
+    Denied,
+    #[error("Malformed trust store: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Trust store I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustEntry {
+    pub identity_key: [u8; 32],
+    pub level: TrustLevel,
+    pub first_seen: u64, // Seconds since UNIX epoch
+}
+
+/// Remembers which identity key belongs to each NodeId and address (TOFU),
+/// with explicit pins and a denylist. Handshakes whose key changed are rejected.
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    nodes: HashMap<NodeId, TrustEntry>,
+    addresses: HashMap<SocketAddr, [u8; 32]>,
+    denied: HashSet<[u8; 32]>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks a peer's identity key (e.g. from a verified HandshakePayload) and records it on first use.
+    pub fn check(
+        &mut self,
+        node_id: NodeId,
+        address: Option<SocketAddr>,
+        identity_key: &[u8; 32],
+        now: u64
+    ) -> Result<TrustStatus, TrustError> {
+        if self.denied.contains(identity_key) {
+            log::warn!("Rejected denied identity key for {node_id}");
+            return Err(TrustError::Denied);
+        }
+
+        if let Some(addr) = address
+            && let Some(known) = self.addresses.get(&addr)
+            && known != identity_key
+        {
+            log::warn!("Identity key changed for address {addr}");
+            return Err(TrustError::KeyChanged(addr.to_string()));
+        }
+
+        let status = match self.nodes.get(&node_id) {
+            Some(entry) if entry.identity_key != *identity_key => {
+                log::warn!("Identity key changed for node {node_id}");
+                return Err(TrustError::KeyChanged(node_id.to_string()));
+            }
+            Some(entry) if entry.level == TrustLevel::Pinned => TrustStatus::KnownPinned,
+            Some(_) => TrustStatus::KnownFirstSeen,
+            None => {
+                log::debug!("Recorded first identity key for node {node_id}");
+                self.nodes.insert(node_id, TrustEntry {
+                    identity_key: *identity_key,
+                    level: TrustLevel::FirstSeen,
+                    first_seen: now,
+                });
+                TrustStatus::NewlyRecorded
+            }
+        };
+
+        if let Some(addr) = address {
+            self.addresses.entry(addr).or_insert(*identity_key);
+        }
+
+        Ok(status)
+    }
+
+    /// Pins a key for a NodeId, replacing whatever was recorded before. Addresses bound to the
+    /// key it replaces (a rotation) are bound to the new one.
+    pub fn pin(&mut self, node_id: NodeId, identity_key: [u8; 32], now: u64) {
+        let first_seen = self.nodes.get(&node_id).map_or(now, |e| e.first_seen);
+        let previous = self.nodes.insert(node_id, TrustEntry {
+            identity_key,
+            level: TrustLevel::Pinned,
+            first_seen,
+        });
+
+        if let Some(previous) = previous
+            && previous.identity_key != identity_key
+        {
+            for key in self.addresses.values_mut().filter(|key| **key == previous.identity_key) {
+                *key = identity_key;
+            }
+        }
+    }
+
+    /// Forgets everything recorded for a NodeId (e.g. after a legitimate key rotation).
+    pub fn forget(&mut self, node_id: &NodeId) -> bool {
+        match self.nodes.remove(node_id) {
+            Some(entry) => {
+                self.addresses.retain(|_, key| *key != entry.identity_key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn deny(&mut self, identity_key: [u8; 32]) {
+        self.denied.insert(identity_key);
+    }
+
+    pub fn undeny(&mut self, identity_key: &[u8; 32]) -> bool {
+        self.denied.remove(identity_key)
+    }
+
+    pub fn is_denied(&self, identity_key: &[u8; 32]) -> bool {
+        self.denied.contains(identity_key)
+    }
+
+    pub fn entry(&self, node_id: &NodeId) -> Option<&TrustEntry> {
+        self.nodes.get(node_id)
+    }
+
+    /// Serializes the store.
+    /// Format: [Magic "FNTS" (4)] [Version (1)] [Count (4)] + N * Record
+    /// Node: [0x01] [NodeId (32)] [Key (32)] [Level (1)] [FirstSeen (8)]
+    /// Address: [0x02] [IP_Len (1) | IP | Port (2)] [Key (32)]
+    /// Denied: [0x03] [Key (32)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(TRUST_STORE_MAGIC);
+        out.push(TRUST_STORE_VERSION);
+
+        let count = self.nodes.len() + self.addresses.len() + self.denied.len();
+        out.extend_from_slice(&(count as u32).to_be_bytes());
+
+        for (node_id, entry) in &self.nodes {
+            out.push(RECORD_NODE);
+            out.extend_from_slice(node_id.as_bytes());
+            out.extend_from_slice(&entry.identity_key);
+            out.push(entry.level as u8);
+            out.extend_from_slice(&entry.first_seen.to_be_bytes());
+        }
+        for (addr, key) in &self.addresses {
+            out.push(RECORD_ADDRESS);
+            codec::write_socket_addr(&mut out, addr);
+            out.extend_from_slice(key);
+        }
+        for key in &self.denied {
+            out.push(RECORD_DENIED);
+            out.extend_from_slice(key);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrustError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(4)? != TRUST_STORE_MAGIC || reader.u8()? != TRUST_STORE_VERSION {
+            return Err(CodecError::InvalidField("header").into());
+        }
+
+        let mut store = TrustStore::new();
+        let count = reader.u32()?;
+
+        for _ in 0..count {
+            match reader.u8()? {
+                RECORD_NODE => {
+                    let node_id = NodeId::from_bytes(reader.take_array()?);
+                    let identity_key = reader.take_array()?;
+                    let level = match reader.u8()? {
+                        1 => TrustLevel::FirstSeen,
+                        2 => TrustLevel::Pinned,
+                        _ => {
+                            return Err(CodecError::InvalidField("trust level").into());
+                        }
+                    };
+                    let first_seen = reader.u64()?;
+                    store.nodes.insert(node_id, TrustEntry { identity_key, level, first_seen });
+                }
+                RECORD_ADDRESS => {
+                    let addr = codec::read_socket_addr(&mut reader)?;
+                    store.addresses.insert(addr, reader.take_array()?);
+                }
+                RECORD_DENIED => {
+                    store.denied.insert(reader.take_array()?);
+                }
+                _ => {
+                    return Err(CodecError::InvalidField("record kind").into());
+                }
+            }
+        }
+
+        reader.finish()?;
+        Ok(store)
+    }
+
+    /// Loads the store from disk. A missing file yields an empty store.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: &Path) -> Result<Self, TrustError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Persists the store atomically (write to a temporary file, then rename).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &Path) -> Result<(), TrustError> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_bytes())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
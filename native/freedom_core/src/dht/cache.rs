@@ -0,0 +1,39 @@
+use super::contact::Contact;
+use super::lookup::LookupResult;
+use super::messages::StoreRequest;
+use super::record::Record;
+
+/// Shortest lifetime worth caching a record for
+pub const MIN_CACHE_TTL_SECS: u32 = 60;
+
+/// TTL for a cached copy: the record's remaining lifetime, halved for every node between the
+/// cache and the key (closer caches are hit by more lookups, so they keep copies longer).
+pub fn cache_ttl(record: &Record, nodes_between: usize, now: u64) -> u32 {
+    let remaining = record.expires_at().saturating_sub(now);
+    let shift = nodes_between.min(63) as u32;
+    (remaining >> shift).min(u32::MAX as u64) as u32
+}
+
+/// Copy of `record` to cache for `ttl` seconds, never outliving the original.
+pub fn cached_copy(record: &Record, ttl: u32, now: u64) -> Record {
+    let ttl = (ttl as u64).min(record.expires_at().saturating_sub(now)) as u32;
+    Record { created_at: now, ttl, ..record.clone() }
+}
+
+/// After a successful GET_VALUE lookup: the STORE that caches the value at the closest node on
+/// the path that did not have it. `None` if there is no such node, the value is not a `Record`,
+/// or the shortened TTL would be below `MIN_CACHE_TTL_SECS`. The request carries no write token;
+/// set the one the candidate issued if it requires them.
+pub fn path_cache_store(result: &LookupResult, now: u64) -> Option<(Contact, StoreRequest)> {
+    let candidate = result.cache_candidate?;
+    let record = Record::from_bytes(result.value.as_deref()?).ok()?;
+
+    // Answering nodes closer to the key than the candidate (`closest` is sorted by distance)
+    let nodes_between = result.closest.iter().position(|c| c.id == candidate.id)?;
+    let ttl = cache_ttl(&record, nodes_between, now);
+    if ttl < MIN_CACHE_TTL_SECS {
+        return None;
+    }
+
+    Some((candidate, StoreRequest::new(cached_copy(&record, ttl, now))))
+}
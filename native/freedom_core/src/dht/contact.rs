@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+
+use super::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+
+/// A peer as seen by the DHT: its id and where to reach it.
+/// C# Reference: FalconNode.Core.Dht.Contact
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Contact {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self { id, addr }
+    }
+
+    /// Format: [NodeID (32)] [IP_Len (1) | IP | Port (2)]
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.id.as_bytes());
+        codec::write_socket_addr(out, &self.addr);
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let id = NodeId::from_bytes(reader.take_array()?);
+        let addr = codec::read_socket_addr(reader)?;
+        Ok(Self { id, addr })
+    }
+}
+
+/// Writes a contact list as [Count (1)] + N * Contact, the FIND_NODE response layout.
+/// At most 255 contacts are written.
+pub fn write_contacts(out: &mut Vec<u8>, contacts: &[Contact]) {
+    let contacts = &contacts[..contacts.len().min(u8::MAX as usize)];
+    out.push(contacts.len() as u8);
+    for contact in contacts {
+        contact.write(out);
+    }
+}
+
+/// Read side of `write_contacts`
+pub fn read_contacts(reader: &mut Reader<'_>) -> Result<Vec<Contact>, CodecError> {
+    let count = reader.u8()? as usize;
+    (0..count).map(|_| Contact::read(reader)).collect()
+}
+
+/// Addresses advertised per contact
+pub const MAX_CONTACT_ADDRESSES: usize = 8;
+
+/// Where an address comes from, which decides how it can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum AddressKind {
+    /// Bound on a public interface (IPv4 or IPv6)
+    Public = 1,
+    /// A relay forwarding to the node
+    Relay = 2,
+    /// The node's address as seen by a peer (e.g. after NAT)
+    Observed = 3,
+}
+
+impl TryFrom<u8> for AddressKind {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, CodecError> {
+        match value {
+            1 => Ok(AddressKind::Public),
+            2 => Ok(AddressKind::Relay),
+            3 => Ok(AddressKind::Observed),
+            _ => Err(CodecError::InvalidField("address kind")),
+        }
+    }
+}
+
+/// Whether a node accepts inbound connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Reachability {
+    #[default]
+    Unknown = 0,
+    /// Accepts inbound connections on its public addresses
+    Direct = 1,
+    /// Behind a NAT or firewall: reachable through relays (or hole punching on the observed address)
+    BehindNat = 2,
+}
+
+impl TryFrom<u8> for Reachability {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, CodecError> {
+        match value {
+            0 => Ok(Reachability::Unknown),
+            1 => Ok(Reachability::Direct),
+            2 => Ok(Reachability::BehindNat),
+            _ => Err(CodecError::InvalidField("reachability")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransportAddress {
+    pub addr: SocketAddr,
+    pub kind: AddressKind,
+}
+
+/// Full contact information: every known address of a node and how reachable it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContactInfo {
+    pub id: NodeId,
+    pub reachability: Reachability,
+    pub addresses: Vec<TransportAddress>,
+}
+
+impl ContactInfo {
+    pub fn new(id: NodeId, reachability: Reachability, mut addresses: Vec<TransportAddress>) -> Self {
+        addresses.truncate(MAX_CONTACT_ADDRESSES);
+        Self { id, reachability, addresses }
+    }
+
+    /// Addresses in the order they should be tried. Direct nodes are dialed on their public
+    /// addresses first; NATed nodes through their relays first.
+    pub fn dial_order(&self) -> Vec<SocketAddr> {
+        let preference: &[AddressKind] = match self.reachability {
+            Reachability::Direct => &[AddressKind::Public, AddressKind::Observed, AddressKind::Relay],
+            Reachability::BehindNat => &[AddressKind::Relay, AddressKind::Observed, AddressKind::Public],
+            Reachability::Unknown => &[AddressKind::Public, AddressKind::Observed, AddressKind::Relay],
+        };
+
+        preference
+            .iter()
+            .flat_map(|kind| self.addresses.iter().filter(move |a| a.kind == *kind))
+            .map(|a| a.addr)
+            .collect()
+    }
+
+    /// The contact to use for routing: the first address of `dial_order`
+    pub fn contact(&self) -> Option<Contact> {
+        self.dial_order().first().map(|addr| Contact::new(self.id, *addr))
+    }
+
+    /// Format: [NodeID (32)] [Reachability (1)] [AddrCount (1)] + N * [Kind (1) | IP_Len (1) | IP | Port (2)]
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let addresses = &self.addresses[..self.addresses.len().min(MAX_CONTACT_ADDRESSES)];
+        out.extend_from_slice(self.id.as_bytes());
+        out.push(self.reachability as u8);
+        out.push(addresses.len() as u8);
+        for address in addresses {
+            out.push(address.kind as u8);
+            codec::write_socket_addr(out, &address.addr);
+        }
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let id = NodeId::from_bytes(reader.take_array()?);
+        let reachability = Reachability::try_from(reader.u8()?)?;
+
+        let count = reader.u8()? as usize;
+        if count > MAX_CONTACT_ADDRESSES {
+            return Err(CodecError::InvalidField("address count"));
+        }
+        let addresses = (0..count)
+            .map(|_| {
+                let kind = AddressKind::try_from(reader.u8()?)?;
+                let addr = codec::read_socket_addr(reader)?;
+                Ok(TransportAddress { addr, kind })
+            })
+            .collect::<Result<Vec<_>, CodecError>>()?;
+
+        Ok(Self { id, reachability, addresses })
+    }
+}
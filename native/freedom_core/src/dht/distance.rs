@@ -0,0 +1,80 @@
+use std::fmt;
+
+use super::node_id::{ NodeId, NODE_ID_SIZE };
+
+/// Number of routing-table buckets (one per bit of the keyspace)
+pub const BUCKET_COUNT: usize = NODE_ID_SIZE * 8;
+
+/// XOR distance between two NodeIds. Compares as a 256-bit big-endian integer,
+/// so the derived `Ord` sorts from closest to farthest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance([u8; NODE_ID_SIZE]);
+
+impl Distance {
+    pub const ZERO: Distance = Distance([0u8; NODE_ID_SIZE]);
+    pub const MAX: Distance = Distance([0xFF; NODE_ID_SIZE]);
+
+    pub const fn from_bytes(bytes: [u8; NODE_ID_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; NODE_ID_SIZE] {
+        &self.0
+    }
+
+    /// Leading zero bits, i.e. the length of the prefix both ids share (256 for equal ids)
+    pub fn leading_zeros(&self) -> u32 {
+        match self.0.iter().position(|&b| b != 0) {
+            Some(i) => (i as u32) * 8 + self.0[i].leading_zeros(),
+            None => BUCKET_COUNT as u32,
+        }
+    }
+
+    /// Routing-table bucket for this distance (0 = farthest half of the keyspace, 255 = closest / self).
+    /// The C# RoutingTable takes its buckets from here (`ffi_bucket_index`). Its managed
+    /// GetBucketIndex counted every zero bit of the distance, not only the leading ones, so
+    /// contacts land in other buckets than they did there.
+    pub fn bucket_index(&self) -> usize {
+        (self.leading_zeros() as usize).min(BUCKET_COUNT - 1)
+    }
+}
+
+impl fmt::Debug for Distance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Distance(")?;
+        for byte in &self.0[..4] {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+/// XOR distance between two ids.
+pub fn distance(a: &NodeId, b: &NodeId) -> Distance {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    Distance(std::array::from_fn(|i| a[i] ^ b[i]))
+}
+
+/// Sorts items from closest to farthest from `target`. Ties (equal ids) keep their order.
+pub fn sort_by_distance<T>(items: &mut [T], target: &NodeId, id_of: impl Fn(&T) -> &NodeId) {
+    items.sort_by_cached_key(|item| distance(id_of(item), target));
+}
+
+/// Returns the `count` items closest to `target`, closest first.
+/// Shared by lookups (which peers to ask next) and storage placement (which peers hold a key).
+pub fn closest<T>(
+    items: impl IntoIterator<Item = T>,
+    target: &NodeId,
+    count: usize,
+    id_of: impl Fn(&T) -> &NodeId
+) -> Vec<T> {
+    let mut items: Vec<T> = items.into_iter().collect();
+    sort_by_distance(&mut items, target, &id_of);
+    items.truncate(count);
+    items
+}
+
+/// Whether `candidate` is strictly closer to `target` than `reference`.
+pub fn is_closer(candidate: &NodeId, reference: &NodeId, target: &NodeId) -> bool {
+    distance(candidate, target) < distance(reference, target)
+}
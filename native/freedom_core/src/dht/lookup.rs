@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+
+use super::contact::Contact;
+use super::distance::{ distance, Distance };
+use super::node_id::NodeId;
+use super::stats::LookupStats;
+
+/// Replication parameter: how many closest nodes a lookup converges on (and a bucket holds)
+pub const K: usize = 20;
+/// Lookup concurrency: queries in flight at once
+pub const ALPHA: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    NotQueried,
+    InFlight,
+    /// Answered with closer nodes only
+    Responded,
+    /// Answered with the value
+    HadValue,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    contact: Contact,
+    state: PeerState,
+    /// 1 for seeds, n + 1 for peers learned from a peer at hop n
+    hop: u32,
+}
+
+/// Outcome of a finished lookup.
+#[derive(Debug)]
+pub struct LookupResult {
+    /// The value, if any peer returned one (GET_VALUE lookups only)
+    pub value: Option<Vec<u8>>,
+    /// The closest peers that answered, closest first (at most K)
+    pub closest: Vec<Contact>,
+    /// The closest peer that answered without the value: where the caller should cache it
+    pub cache_candidate: Option<Contact>,
+    pub stats: LookupStats,
+}
+
+/// Iterative Kademlia lookup as a sans-IO state machine: the caller sends the queries it is
+/// handed (`FindNode` or `GetValueReq`) and feeds the responses back, so the same logic runs
+/// over QUIC, through the FFI, or in a simulation.
+#[derive(Debug)]
+pub struct Lookup {
+    target: NodeId,
+    local_id: Option<NodeId>,
+    wants_value: bool,
+    candidates: BTreeMap<Distance, Candidate>,
+    value: Option<Vec<u8>>,
+}
+
+impl Lookup {
+    /// Lookup for the K closest nodes to `target` (FIND_NODE).
+    pub fn find_node(target: NodeId, seeds: impl IntoIterator<Item = Contact>) -> Self {
+        Self::new(target, seeds, false)
+    }
+
+    /// Lookup for the value stored under `target` (GET_VALUE); stops at the first value found.
+    pub fn get_value(target: NodeId, seeds: impl IntoIterator<Item = Contact>) -> Self {
+        Self::new(target, seeds, true)
+    }
+
+    fn new(target: NodeId, seeds: impl IntoIterator<Item = Contact>, wants_value: bool) -> Self {
+        let mut lookup = Self {
+            target,
+            local_id: None,
+            wants_value,
+            candidates: BTreeMap::new(),
+            value: None,
+        };
+        lookup.add_contacts(seeds, 1);
+        lookup
+    }
+
+    /// Never queries (or returns) the local node, even if peers report it.
+    pub fn with_local_id(mut self, local_id: NodeId) -> Self {
+        self.candidates.remove(&distance(&local_id, &self.target));
+        self.local_id = Some(local_id);
+        self
+    }
+
+    pub fn target(&self) -> &NodeId {
+        &self.target
+    }
+
+    /// Picks the next peers to query, keeping at most ALPHA in flight, and marks them in flight.
+    pub fn next_queries(&mut self) -> Vec<Contact> {
+        if self.is_finished() {
+            return Vec::new();
+        }
+
+        let in_flight = self.count(PeerState::InFlight);
+        let mut queries = Vec::new();
+        for candidate in self.live_candidates().take(K) {
+            if in_flight + queries.len() >= ALPHA {
+                break;
+            }
+            if candidate.state == PeerState::NotQueried {
+                queries.push(candidate.contact);
+            }
+        }
+
+        for contact in &queries {
+            self.set_state(&contact.id, PeerState::InFlight);
+        }
+        queries
+    }
+
+    /// Records a response carrying closer nodes (FIND_NODE response, or GET_VALUE without the value).
+    pub fn on_nodes(&mut self, from: &NodeId, nodes: impl IntoIterator<Item = Contact>) {
+        if self.set_state(from, PeerState::Responded) {
+            let hop = self.candidates.get(&distance(from, &self.target)).map_or(1, |c| c.hop);
+            self.add_contacts(nodes, hop + 1);
+        }
+    }
+
+    /// Records a response carrying the value. The first value wins and ends the lookup.
+    pub fn on_value(&mut self, from: &NodeId, value: Vec<u8>) {
+        if self.set_state(from, PeerState::HadValue) && self.value.is_none() {
+            self.value = Some(value);
+        }
+    }
+
+    /// Records a timeout or an unusable response.
+    pub fn on_failure(&mut self, from: &NodeId) {
+        self.set_state(from, PeerState::Failed);
+    }
+
+    /// Done when a value was found, or when the K closest live peers have all answered
+    /// and nothing is in flight.
+    pub fn is_finished(&self) -> bool {
+        if self.wants_value && self.value.is_some() {
+            return true;
+        }
+
+        self.count(PeerState::InFlight) == 0
+            && self.live_candidates().take(K).all(|c| c.state != PeerState::NotQueried)
+    }
+
+    /// Counters for telemetry; final once the lookup is finished.
+    pub fn stats(&self) -> LookupStats {
+        let answered = self.candidates
+            .values()
+            .filter(|c| matches!(c.state, PeerState::Responded | PeerState::HadValue));
+        LookupStats {
+            queries: self.candidates.values().filter(|c| c.state != PeerState::NotQueried).count() as u32,
+            responses: answered.clone().count() as u32,
+            failures: self.count(PeerState::Failed) as u32,
+            hops: answered.map(|c| c.hop).max().unwrap_or(0),
+            found_value: self.value.is_some(),
+        }
+    }
+
+    pub fn into_result(self) -> LookupResult {
+        let stats = self.stats();
+        let answered = |c: &&Candidate| matches!(c.state, PeerState::Responded | PeerState::HadValue);
+        LookupResult {
+            closest: self.candidates.values().filter(answered).take(K).map(|c| c.contact).collect(),
+            cache_candidate: self.value.as_ref().and_then(|_| {
+                self.candidates
+                    .values()
+                    .find(|c| c.state == PeerState::Responded)
+                    .map(|c| c.contact)
+            }),
+            value: self.value,
+            stats,
+        }
+    }
+
+    fn add_contacts(&mut self, contacts: impl IntoIterator<Item = Contact>, hop: u32) {
+        for contact in contacts {
+            if Some(contact.id) == self.local_id {
+                continue;
+            }
+            self.candidates
+                .entry(distance(&contact.id, &self.target))
+                .or_insert(Candidate { contact, state: PeerState::NotQueried, hop });
+        }
+    }
+
+    /// Updates a queried peer; returns false for peers we never asked (unsolicited responses)
+    fn set_state(&mut self, id: &NodeId, state: PeerState) -> bool {
+        match self.candidates.get_mut(&distance(id, &self.target)) {
+            Some(candidate) if candidate.state == PeerState::InFlight || state == PeerState::InFlight => {
+                candidate.state = state;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn live_candidates(&self) -> impl Iterator<Item = &Candidate> {
+        self.candidates.values().filter(|c| c.state != PeerState::Failed)
+    }
+
+    fn count(&self, state: PeerState) -> usize {
+        self.candidates.values().filter(|c| c.state == state).count()
+    }
+}
@@ -0,0 +1,515 @@
+use super::contact::{ self, Contact, ContactInfo };
+use super::mutable::{ MutableRecord, MutableRecordError };
+use super::namespace::KeyPath;
+use super::node_id::NodeId;
+use super::record::{ Record, RecordError };
+use super::token::WriteToken;
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DhtMessageError {
+    #[error("Malformed DHT payload: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Invalid record: {0}")]
+    Record(#[from] RecordError),
+    #[error("Invalid mutable record: {0}")]
+    MutableRecord(#[from] MutableRecordError),
+    #[error("Message type {0:?} is not a DHT message")]
+    NotDht(MessageType),
+}
+
+/// Asks a peer for the contacts it knows closest to `target`.
+/// Format: [TargetID (32)]
+/// C# Reference: FalconNode.Core.Dht.FindNodeRequest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FindNodeRequest {
+    pub target: NodeId,
+}
+
+impl FindNodeRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.target.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let target = NodeId::from_bytes(reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { target })
+    }
+}
+
+/// Format: [Count (1)] + N * [NodeID (32) | IP_Len (1) | IP | Port (2)]
+///         then, optionally: [InfoCount (1)] + N * ContactInfo, [Token (16)]
+/// The trailing sections carry every address of the returned nodes (relays, observed
+/// addresses) and a write token for later STOREs; peers that predate them read the first
+/// list only and ignore the rest.
+/// C# Reference: FalconNode.Core.Dht.FindNodeResponse
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FindNodeResponse {
+    pub contacts: Vec<Contact>,
+    pub infos: Vec<ContactInfo>,
+    pub token: Option<WriteToken>,
+}
+
+impl FindNodeResponse {
+    /// Builds a response from full contact information, filling the legacy list with each node's preferred address.
+    pub fn from_infos(infos: Vec<ContactInfo>) -> Self {
+        let contacts = infos.iter().filter_map(ContactInfo::contact).collect();
+        Self { contacts, infos, token: None }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        contact::write_contacts(&mut out, &self.contacts);
+        if !self.infos.is_empty() || self.token.is_some() {
+            let infos = &self.infos[..self.infos.len().min(u8::MAX as usize)];
+            out.push(infos.len() as u8);
+            for info in infos {
+                info.write(&mut out);
+            }
+        }
+        if let Some(token) = &self.token {
+            out.extend_from_slice(&token.0);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let contacts = contact::read_contacts(&mut reader)?;
+
+        let mut infos = Vec::new();
+        if reader.remaining() > 0 {
+            let count = reader.u8()? as usize;
+            infos = (0..count).map(|_| ContactInfo::read(&mut reader)).collect::<Result<_, _>>()?;
+        }
+        let token = match reader.remaining() {
+            0 => None,
+            _ => Some(WriteToken(reader.take_array()?)),
+        };
+
+        reader.finish()?;
+        Ok(Self { contacts, infos, token })
+    }
+}
+
+/// Asks a peer for the value stored under `key`, a step of an iterative lookup.
+/// Format: [Key (32)]
+/// Not the C# GetValueRequest, though the layout is the same: C# nodes send the owner's raw
+/// public key there and ask that one peer for its MutableRecord, while `key` is a DHT key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GetValueRequest {
+    pub key: NodeId,
+}
+
+impl GetValueRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.key.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let key = NodeId::from_bytes(reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { key })
+    }
+}
+
+/// Asks a peer to hold a record until it expires. Peers that require write tokens refuse
+/// requests without the token they issued us in a FIND_NODE or GET_VALUE response. The key
+/// path lets the receiver run the validator of the record's namespace.
+/// Format: [Flags (1): 0x01 = token, 0x02 = key path] [Token (16)] [KeyPath] [Record]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreRequest {
+    pub token: Option<WriteToken>,
+    pub path: Option<KeyPath>,
+    pub record: Record,
+}
+
+const STORE_FLAG_TOKEN: u8 = 0x01;
+const STORE_FLAG_PATH: u8 = 0x02;
+
+impl StoreRequest {
+    pub fn new(record: Record) -> Self {
+        Self { token: None, path: None, record }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.token.is_some() {
+            flags |= STORE_FLAG_TOKEN;
+        }
+        if self.path.is_some() {
+            flags |= STORE_FLAG_PATH;
+        }
+
+        let mut out = vec![flags];
+        if let Some(token) = &self.token {
+            out.extend_from_slice(&token.0);
+        }
+        if let Some(path) = &self.path {
+            path.write(&mut out);
+        }
+        out.extend_from_slice(&self.record.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RecordError> {
+        let mut reader = Reader::new(bytes);
+        let flags = reader.u8()?;
+        if flags & !(STORE_FLAG_TOKEN | STORE_FLAG_PATH) != 0 {
+            return Err(CodecError::InvalidField("store flags").into());
+        }
+
+        let token = match flags & STORE_FLAG_TOKEN {
+            0 => None,
+            _ => Some(WriteToken(reader.take_array()?)),
+        };
+        let path = match flags & STORE_FLAG_PATH {
+            0 => None,
+            _ => Some(KeyPath::read(&mut reader)?),
+        };
+        let record = Record::from_bytes(reader.rest())?;
+        Ok(Self { token, path, record })
+    }
+}
+
+/// Acknowledges a STORE with the key the record was stored under.
+/// Format: [Key (32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreResponse {
+    pub key: NodeId,
+}
+
+impl StoreResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.key.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let key = NodeId::from_bytes(reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { key })
+    }
+}
+
+/// Asks a peer for a blob by its content hash, or for the value of the record stored under a
+/// key (see `HsDescriptor::fetch_request`).
+/// Format: [Hash (32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchRequest {
+    pub hash: [u8; 32],
+}
+
+impl FetchRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.hash.to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let hash = reader.take_array()?;
+        reader.finish()?;
+        Ok(Self { hash })
+    }
+}
+
+/// The requested blob.
+/// Format: [Data (rest)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchResponse {
+    pub data: Vec<u8>,
+}
+
+impl FetchResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        Ok(Self { data: bytes.to_vec() })
+    }
+}
+
+/// Publishes a mutable record.
+/// Format: [MutableRecord]
+/// C# Reference: FalconNode.Core.Dht.PutValueRequest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PutRequest {
+    pub record: MutableRecord,
+}
+
+impl PutRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.record.to_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MutableRecordError> {
+        Ok(Self { record: MutableRecord::from_bytes(bytes)? })
+    }
+}
+
+/// Either the value, or the peers closest to the key that the responder knows of with, like
+/// FIND_NODE responses, a write token for a later STORE (say, caching the value found).
+/// Format: [Found (1)] + Found=1: [Value (rest)] | Found=0: [Count (1)] + N * Contact, then
+///         optionally: [Token (16)]
+/// C# nodes answer with the Found flag alone when they don't hold the record, read as no closer
+/// nodes, and with their encoded `MutableRecord` as the value when they do. They send no
+/// closer nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetValueResponse {
+    Value(Vec<u8>),
+    CloserNodes {
+        nodes: Vec<Contact>,
+        token: Option<WriteToken>,
+    },
+}
+
+impl GetValueResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            GetValueResponse::Value(value) => {
+                out.push(1);
+                out.extend_from_slice(value);
+            }
+            GetValueResponse::CloserNodes { nodes, token } => {
+                out.push(0);
+                contact::write_contacts(&mut out, nodes);
+                if let Some(token) = token {
+                    out.extend_from_slice(&token.0);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let response = match reader.u8()? {
+            1 => GetValueResponse::Value(reader.rest().to_vec()),
+            0 if reader.remaining() == 0 => GetValueResponse::CloserNodes { nodes: Vec::new(), token: None },
+            0 => {
+                let nodes = contact::read_contacts(&mut reader)?;
+                let token = match reader.remaining() {
+                    0 => None,
+                    _ => Some(WriteToken(reader.take_array()?)),
+                };
+                GetValueResponse::CloserNodes { nodes, token }
+            }
+            _ => {
+                return Err(CodecError::InvalidField("found flag"));
+            }
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+/// Announces that `provider` holds the content under `key`, for `ttl` seconds.
+/// Format: [Key (32)] [TTL (4)] [Provider Contact]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProvideRequest {
+    pub key: NodeId,
+    pub ttl: u32,
+    pub provider: Contact,
+}
+
+impl ProvideRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(88);
+        out.extend_from_slice(self.key.as_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+        self.provider.write(&mut out);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let key = NodeId::from_bytes(reader.take_array()?);
+        let ttl = reader.u32()?;
+        let provider = Contact::read(&mut reader)?;
+        reader.finish()?;
+        Ok(Self { key, ttl, provider })
+    }
+}
+
+/// Asks a peer who provides the content under `key`.
+/// Format: [Key (32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FindProvidersRequest {
+    pub key: NodeId,
+}
+
+impl FindProvidersRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.key.as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let key = NodeId::from_bytes(reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { key })
+    }
+}
+
+/// The providers the responder knows of, plus closer nodes to continue the lookup with.
+/// Format: [Count (1)] + N * Provider Contact, [Count (1)] + N * Closer Contact
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FindProvidersResponse {
+    pub providers: Vec<Contact>,
+    pub closer: Vec<Contact>,
+}
+
+impl FindProvidersResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        contact::write_contacts(&mut out, &self.providers);
+        contact::write_contacts(&mut out, &self.closer);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let providers = contact::read_contacts(&mut reader)?;
+        let closer = contact::read_contacts(&mut reader)?;
+        reader.finish()?;
+        Ok(Self { providers, closer })
+    }
+}
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Unreadable or invalid request
+    BadRequest = 0x01,
+    /// The peer is over its rate limit (or banned); retry after the given delay
+    Overloaded = 0x02,
+    /// Storage quota or capacity reached
+    StorageFull = 0x03,
+    Internal = 0xFF,
+}
+
+impl TryFrom<u8> for ErrorCode {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, CodecError> {
+        match value {
+            0x01 => Ok(ErrorCode::BadRequest),
+            0x02 => Ok(ErrorCode::Overloaded),
+            0x03 => Ok(ErrorCode::StorageFull),
+            0xFF => Ok(ErrorCode::Internal),
+            _ => Err(CodecError::InvalidField("error code")),
+        }
+    }
+}
+
+/// Sent instead of the regular response when a request is refused.
+/// Format: [Code (1)] [RetryAfter (4)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// Seconds before the peer should try again (0 = no hint)
+    pub retry_after_secs: u32,
+}
+
+impl ErrorResponse {
+    pub fn overloaded(retry_after_secs: u32) -> Self {
+        Self { code: ErrorCode::Overloaded, retry_after_secs }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5);
+        out.push(self.code as u8);
+        out.extend_from_slice(&self.retry_after_secs.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let code = ErrorCode::try_from(reader.u8()?)?;
+        let retry_after_secs = reader.u32()?;
+        reader.finish()?;
+        Ok(Self { code, retry_after_secs })
+    }
+}
+
+/// Any DHT payload, tagged with the `MessageType` it travels under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DhtMessage {
+    FindNode(FindNodeRequest),
+    FindNodeRes(FindNodeResponse),
+    Store(StoreRequest),
+    StoreRes(StoreResponse),
+    Fetch(FetchRequest),
+    FetchRes(FetchResponse),
+    Put(PutRequest),
+    GetValueReq(GetValueRequest),
+    GetValueRes(GetValueResponse),
+    Error(ErrorResponse),
+}
+
+impl DhtMessage {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            DhtMessage::FindNode(_) => MessageType::DhtFindNode,
+            DhtMessage::FindNodeRes(_) => MessageType::DhtFindNodeRes,
+            DhtMessage::Store(_) => MessageType::Store,
+            DhtMessage::StoreRes(_) => MessageType::StoreRes,
+            DhtMessage::Fetch(_) => MessageType::Fetch,
+            DhtMessage::FetchRes(_) => MessageType::FetchRes,
+            DhtMessage::Put(_) => MessageType::Put,
+            DhtMessage::GetValueReq(_) => MessageType::GetValueReq,
+            DhtMessage::GetValueRes(_) => MessageType::GetValueRes,
+            DhtMessage::Error(_) => MessageType::Error,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            DhtMessage::FindNode(m) => m.to_bytes(),
+            DhtMessage::FindNodeRes(m) => m.to_bytes(),
+            DhtMessage::Store(m) => m.to_bytes(),
+            DhtMessage::StoreRes(m) => m.to_bytes(),
+            DhtMessage::Fetch(m) => m.to_bytes(),
+            DhtMessage::FetchRes(m) => m.to_bytes(),
+            DhtMessage::Put(m) => m.to_bytes(),
+            DhtMessage::GetValueReq(m) => m.to_bytes(),
+            DhtMessage::GetValueRes(m) => m.to_bytes(),
+            DhtMessage::Error(m) => m.to_bytes(),
+        }
+    }
+
+    /// Decodes the payload of a packet of type `message_type`.
+    pub fn decode(message_type: MessageType, payload: &[u8]) -> Result<Self, DhtMessageError> {
+        Ok(match message_type {
+            MessageType::DhtFindNode => DhtMessage::FindNode(FindNodeRequest::from_bytes(payload)?),
+            MessageType::DhtFindNodeRes => DhtMessage::FindNodeRes(FindNodeResponse::from_bytes(payload)?),
+            MessageType::Store => DhtMessage::Store(StoreRequest::from_bytes(payload)?),
+            MessageType::StoreRes => DhtMessage::StoreRes(StoreResponse::from_bytes(payload)?),
+            MessageType::Fetch => DhtMessage::Fetch(FetchRequest::from_bytes(payload)?),
+            MessageType::FetchRes => DhtMessage::FetchRes(FetchResponse::from_bytes(payload)?),
+            MessageType::Put => DhtMessage::Put(PutRequest::from_bytes(payload)?),
+            MessageType::GetValueReq => DhtMessage::GetValueReq(GetValueRequest::from_bytes(payload)?),
+            MessageType::GetValueRes => DhtMessage::GetValueRes(GetValueResponse::from_bytes(payload)?),
+            MessageType::Error => DhtMessage::Error(ErrorResponse::from_bytes(payload)?),
+            other => {
+                return Err(DhtMessageError::NotDht(other));
+            }
+        })
+    }
+
+    pub fn from_packet(packet: &NetworkPacket) -> Result<Self, DhtMessageError> {
+        Self::decode(packet.header.message_type, &packet.payload)
+    }
+
+    pub fn to_packet(&self, request_id: u32) -> NetworkPacket {
+        NetworkPacket::new(self.message_type(), request_id, self.to_bytes())
+    }
+}
@@ -0,0 +1,40 @@
+pub mod cache;
+pub mod contact;
+pub mod distance;
+pub mod lookup;
+pub mod messages;
+pub mod mutable;
+pub mod namespace;
+pub mod node_id;
+pub mod peer_store;
+pub mod provider;
+pub mod quorum;
+pub mod rate_limit;
+pub mod record;
+pub mod republish;
+pub mod routing;
+pub mod sim;
+pub mod stats;
+pub mod sybil;
+pub mod token;
+pub mod validate;
+
+pub use contact::{ Contact, ContactInfo };
+pub use distance::{ closest, distance, Distance };
+pub use lookup::{ Lookup, LookupResult, ALPHA, K };
+pub use mutable::{ MutableRecord, MutableRecordStore };
+pub use namespace::{ KeyPath, Namespace, NamespacedKey };
+pub use peer_store::PeerStore;
+pub use provider::ProviderStore;
+pub use quorum::{ QuorumRead, QuorumResult };
+pub use rate_limit::{ RateDecision, RateLimitConfig, RateLimiter };
+pub use record::{ Record, RecordStore, StorageLimits };
+pub use republish::{ RepublishAction, RepublishConfig, RepublishScheduler };
+pub use routing::{ InsertOutcome, RoutingTable };
+pub use stats::{ DhtStats, DhtTelemetry, LookupStats };
+pub use sybil::AdmissionPolicy;
+pub use token::{ WriteToken, WriteTokens };
+pub use validate::{ RecordValidator, ValidatorRegistry };
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::{ Signature, VerifyingKey };
+
+use super::node_id::NodeId;
+use super::record::MAX_RECORD_VALUE_LEN;
+use crate::crypto::identity::{ self, NodeIdentity };
+use crate::protocol::codec::{ CodecError, Reader };
+
+#[derive(Debug, thiserror::Error)]
+pub enum MutableRecordError {
+    #[error("Malformed mutable record: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Mutable record value too large: {len} bytes (max {max})")]
+    ValueTooLarge {
+        len: usize,
+        max: usize,
+    },
+    #[error("Invalid owner key bytes")]
+    InvalidOwnerKey,
+    #[error("Signature verification failed")]
+    VerificationFailed,
+    #[error("Sequence {got} is not newer than {current}")]
+    StaleSequence {
+        got: u64,
+        current: u64,
+    },
+    #[error("Conflicting value for sequence {0}")]
+    Conflict(u64),
+}
+
+/// Updatable value owned by an Ed25519 key (BEP44-style): every update carries a higher
+/// sequence number and a fresh signature, so peers converge on the latest one.
+/// Stored under `NodeId::from_identity_key(owner)`, e.g. a node's current descriptor.
+/// C# Reference: FalconNode.Core.Dht.MutableRecord
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutableRecord {
+    pub owner: VerifyingKey,
+    pub seq: u64,
+    pub value: Vec<u8>,
+    pub signature: Signature,
+}
+
+impl MutableRecord {
+    pub fn new_signed(identity: &NodeIdentity, seq: u64, value: Vec<u8>) -> Result<Self, MutableRecordError> {
+        check_value_len(&value)?;
+        let signature = identity.sign(&signed_message(seq, &value));
+        Ok(Self {
+            owner: identity.identity_keypair.verifying_key(),
+            seq,
+            value,
+            signature,
+        })
+    }
+
+    /// DHT key the record lives under
+    pub fn key(&self) -> NodeId {
+        NodeId::from_identity_key(&self.owner)
+    }
+
+    /// Checks the owner's signature over [Seq (8) | Value] (same message as the C# node signs).
+    pub fn verify(&self) -> Result<(), MutableRecordError> {
+        identity
+            ::verify(&self.owner, &signed_message(self.seq, &self.value), &self.signature)
+            .map_err(|_| MutableRecordError::VerificationFailed)
+    }
+
+    /// Whether this record should replace `current`. Equal sequences only match if the records are identical.
+    pub fn supersedes(&self, current: &MutableRecord) -> Result<bool, MutableRecordError> {
+        if self.seq > current.seq {
+            return Ok(true);
+        }
+        if self.seq == current.seq {
+            return if self == current { Ok(false) } else { Err(MutableRecordError::Conflict(self.seq)) };
+        }
+        Err(MutableRecordError::StaleSequence { got: self.seq, current: current.seq })
+    }
+
+    /// Serializes the record.
+    /// Format: [Owner (32)] [Seq (8)] [Signature (64)] [ValLen (2)] [Value]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(106 + self.value.len());
+        out.extend_from_slice(self.owner.as_bytes());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        out.extend_from_slice(&(self.value.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.value);
+        out
+    }
+
+    /// Parses a record. Does not check the signature; call `verify` for that.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MutableRecordError> {
+        let mut reader = Reader::new(bytes);
+
+        let owner = VerifyingKey::from_bytes(&reader.take_array()?).map_err(
+            |_| MutableRecordError::InvalidOwnerKey
+        )?;
+        let seq = reader.u64()?;
+        let signature = Signature::from_bytes(&reader.take_array()?);
+        let len = reader.u16()? as usize;
+        let value = reader.take(len)?.to_vec();
+        reader.finish()?;
+
+        Ok(Self { owner, seq, value, signature })
+    }
+}
+
+/// Mutable records held by this node, keeping only the highest valid sequence per owner.
+/// C# Reference: FalconNode.Core.Dht.DhtStore
+#[derive(Debug, Default)]
+pub struct MutableRecordStore {
+    records: HashMap<NodeId, MutableRecord>,
+}
+
+impl MutableRecordStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verifies and stores a record. Returns true if it replaced (or created) the stored copy,
+    /// false if the exact same record was already held.
+    pub fn put(&mut self, record: MutableRecord) -> Result<bool, MutableRecordError> {
+        check_value_len(&record.value)?;
+        record.verify()?;
+
+        let key = record.key();
+        if let Some(current) = self.records.get(&key)
+            && !record.supersedes(current)?
+        {
+            return Ok(false);
+        }
+
+        log::debug!("Stored mutable record for {key} at sequence {}", record.seq);
+        self.records.insert(key, record);
+        Ok(true)
+    }
+
+    pub fn get(&self, key: &NodeId) -> Option<&MutableRecord> {
+        self.records.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+fn check_value_len(value: &[u8]) -> Result<(), MutableRecordError> {
+    if value.len() > MAX_RECORD_VALUE_LEN {
+        return Err(MutableRecordError::ValueTooLarge { len: value.len(), max: MAX_RECORD_VALUE_LEN });
+    }
+    Ok(())
+}
+
+fn signed_message(seq: u64, value: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + value.len());
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(value);
+    message
+}
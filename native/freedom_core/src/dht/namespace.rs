@@ -0,0 +1,128 @@
+use sha2::{ Digest, Sha256 };
+use std::fmt;
+
+use super::node_id::NodeId;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Separate region of the DHT keyspace. Keys from different namespaces never collide,
+/// so each can have its own validation rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Namespace(&'static str);
+
+impl Namespace {
+    /// Signed node descriptors, keyed by NodeId
+    pub const DESCRIPTOR: Namespace = Namespace("descriptor");
+    /// Provider announcements, keyed by content hash
+    pub const PROVIDER: Namespace = Namespace("provider");
+    /// Hidden service descriptors, keyed by the service's blinded key for the period
+    pub const HIDDEN_SERVICE: Namespace = Namespace("hs-descriptor");
+    /// Application data
+    pub const APP: Namespace = Namespace("app");
+
+    /// Namespace names are at most 255 bytes (they are length-prefixed when hashed)
+    pub const fn new(name: &'static str) -> Self {
+        assert!(name.len() <= u8::MAX as usize, "Namespace name too long");
+        Self(name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// A key inside a namespace. Placed in the keyspace at SHA-256([NameLen (1)] [Name] [Key]);
+/// the length prefix keeps ("ab", "c") and ("a", "bc") apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NamespacedKey {
+    namespace: Namespace,
+    key: Vec<u8>,
+}
+
+impl NamespacedKey {
+    pub fn new(namespace: Namespace, key: impl Into<Vec<u8>>) -> Self {
+        Self { namespace, key: key.into() }
+    }
+
+    /// Where a node's descriptor is published
+    pub fn descriptor(node_id: &NodeId) -> Self {
+        Self::new(Namespace::DESCRIPTOR, node_id.as_bytes().to_vec())
+    }
+
+    /// Where providers of a piece of content announce themselves
+    pub fn provider(content_hash: &[u8; 32]) -> Self {
+        Self::new(Namespace::PROVIDER, content_hash.to_vec())
+    }
+
+    /// Where a hidden service's descriptor is published (see `HsDescriptor`)
+    pub fn hidden_service(blinded_key: &[u8; 32]) -> Self {
+        Self::new(Namespace::HIDDEN_SERVICE, blinded_key.to_vec())
+    }
+
+    pub fn app(key: impl Into<Vec<u8>>) -> Self {
+        Self::new(Namespace::APP, key)
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Position of the key in the DHT keyspace
+    pub fn node_id(&self) -> NodeId {
+        let name = self.namespace.name().as_bytes();
+        let mut hasher = Sha256::new();
+        hasher.update([name.len() as u8]);
+        hasher.update(name);
+        hasher.update(&self.key);
+        NodeId::from_bytes(hasher.finalize().into())
+    }
+}
+
+impl From<&NamespacedKey> for NodeId {
+    fn from(key: &NamespacedKey) -> Self {
+        key.node_id()
+    }
+}
+
+/// A namespaced key as carried on the wire, before its namespace is resolved against the
+/// ones this node knows (see `ValidatorRegistry`).
+/// Format: [NameLen (1)] [Name] [KeyLen (2)] [Key]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPath {
+    pub namespace: String,
+    pub key: Vec<u8>,
+}
+
+impl KeyPath {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.namespace.len() as u8);
+        out.extend_from_slice(self.namespace.as_bytes());
+        out.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.key);
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let len = reader.u8()? as usize;
+        let namespace = std::str::from_utf8(reader.take(len)?)
+            .map_err(|_| CodecError::InvalidField("namespace"))?
+            .to_owned();
+        let len = reader.u16()? as usize;
+        let key = reader.take(len)?.to_vec();
+        Ok(Self { namespace, key })
+    }
+}
+
+impl From<&NamespacedKey> for KeyPath {
+    fn from(key: &NamespacedKey) -> Self {
+        Self { namespace: key.namespace.name().to_owned(), key: key.key.clone() }
+    }
+}
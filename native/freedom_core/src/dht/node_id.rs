@@ -0,0 +1,99 @@
+use ed25519_dalek::VerifyingKey;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{ Digest, Sha256 };
+use std::fmt;
+
+use super::distance::{ self, Distance };
+
+pub const NODE_ID_SIZE: usize = 32;
+
+/// Unique 256-bit identifier of a node in the DHT keyspace.
+/// C# Reference: FalconNode.Core.Dht.NodeId
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; NODE_ID_SIZE]);
+
+impl NodeId {
+    pub const fn from_bytes(bytes: [u8; NODE_ID_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parses a NodeId from a slice, returning None if it is not exactly 32 bytes
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(Self)
+    }
+
+    /// Derives the NodeId of a peer: SHA-256 of its Ed25519 identity key.
+    /// C# Reference: new NodeId(SHA256.HashData(originKey))
+    pub fn from_identity_key(identity_key: &VerifyingKey) -> Self {
+        Self::hash_of(identity_key.as_bytes())
+    }
+
+    /// Places arbitrary bytes (a key, a content name) in the keyspace: SHA-256 of the bytes.
+    pub fn hash_of(bytes: &[u8]) -> Self {
+        Self(Sha256::digest(bytes).into())
+    }
+
+    /// Generates a random NodeId (used for lookups of arbitrary keyspace regions)
+    pub fn random() -> Self {
+        let mut bytes = [0u8; NODE_ID_SIZE];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// Random id that falls in bucket `index` of this node's routing table (used to refresh the bucket).
+    pub fn random_in_bucket(&self, index: usize) -> Self {
+        let index = index.min(distance::BUCKET_COUNT - 1);
+        let mut offset = [0u8; NODE_ID_SIZE];
+        OsRng.fill_bytes(&mut offset);
+
+        // Clear the bits above `index`, then set bit `index` so the shared prefix is exactly `index` bits
+        let (byte, bit) = (index / 8, index % 8);
+        offset[..byte].fill(0);
+        offset[byte] &= 0xFF >> bit;
+        offset[byte] |= 0x80 >> bit;
+
+        Self(std::array::from_fn(|i| self.0[i] ^ offset[i]))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; NODE_ID_SIZE] {
+        &self.0
+    }
+
+    /// XOR distance to another id.
+    pub fn distance(&self, other: &NodeId) -> Distance {
+        distance::distance(self, other)
+    }
+
+    /// XOR distance to another id, as big-endian bytes (smaller = closer).
+    /// C# Reference: NodeId.Distance(a, b)
+    pub fn xor(&self, other: &NodeId) -> [u8; NODE_ID_SIZE] {
+        *self.distance(other).as_bytes()
+    }
+
+    /// Number of leading bits shared with another id (256 if they are equal).
+    pub fn common_prefix_len(&self, other: &NodeId) -> u32 {
+        self.distance(other).leading_zeros()
+    }
+
+    /// Routing-table bucket holding `other` (0 = farthest half of the keyspace, 255 = closest / self).
+    pub fn bucket_index(&self, other: &NodeId) -> usize {
+        self.distance(other).bucket_index()
+    }
+}
+
+impl fmt::Display for NodeId {
+    /// Short hexadecimal form (first 8 hex digits), matching the C# ToString()
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0[..4] {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
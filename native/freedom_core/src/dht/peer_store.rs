@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::Path;
+
+use super::contact::Contact;
+use super::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+
+const PEER_STORE_MAGIC: &[u8; 4] = b"FNPS";
+const PEER_STORE_VERSION: u8 = 1;
+
+/// Addresses remembered per peer; the most recently used ones are kept
+pub const MAX_PEER_ADDRESSES: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PeerStoreError {
+    #[error("Malformed peer store: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Peer store I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Most recently used first
+    pub addresses: Vec<SocketAddr>,
+    pub last_seen: u64, // Seconds since UNIX epoch
+    pub reputation: i32,
+}
+
+impl PeerInfo {
+    /// Preferred address to dial, as a routing-table contact
+    pub fn contact(&self, node_id: NodeId) -> Option<Contact> {
+        self.addresses.first().map(|addr| Contact::new(node_id, *addr))
+    }
+}
+
+/// Peers this node has talked to, persisted across restarts so startup can rejoin the
+/// network from them instead of the seed nodes.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<NodeId, PeerInfo>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that we reached `node_id` at `addr`.
+    pub fn record_seen(&mut self, node_id: NodeId, addr: SocketAddr, now: u64) {
+        let peer = self.peers.entry(node_id).or_insert_with(|| PeerInfo {
+            addresses: Vec::new(),
+            last_seen: now,
+            reputation: 0,
+        });
+
+        peer.addresses.retain(|a| *a != addr);
+        peer.addresses.insert(0, addr);
+        peer.addresses.truncate(MAX_PEER_ADDRESSES);
+        peer.last_seen = peer.last_seen.max(now);
+    }
+
+    /// Adds `delta` to a peer's reputation (saturating). Returns false for unknown peers.
+    pub fn adjust_reputation(&mut self, node_id: &NodeId, delta: i32) -> bool {
+        match self.peers.get_mut(node_id) {
+            Some(peer) => {
+                peer.reputation = peer.reputation.saturating_add(delta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<&PeerInfo> {
+        self.peers.get(node_id)
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) -> Option<PeerInfo> {
+        self.peers.remove(node_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Forgets peers not seen for `max_age` seconds. Returns how many were removed.
+    pub fn prune(&mut self, now: u64, max_age: u64) -> usize {
+        let before = self.peers.len();
+        self.peers.retain(|_, peer| now.saturating_sub(peer.last_seen) < max_age);
+        before - self.peers.len()
+    }
+
+    /// Up to `count` peers to bootstrap from: best reputation first, then most recently seen.
+    pub fn bootstrap_contacts(&self, count: usize) -> Vec<Contact> {
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by(|(_, a), (_, b)| b.reputation.cmp(&a.reputation).then(b.last_seen.cmp(&a.last_seen)));
+        peers
+            .into_iter()
+            .filter_map(|(id, peer)| peer.contact(*id))
+            .take(count)
+            .collect()
+    }
+
+    /// Serializes the store.
+    /// Format: [Magic "FNPS" (4)] [Version (1)] [Count (4)] + N * Peer
+    /// Peer: [NodeId (32)] [LastSeen (8)] [Reputation (4)] [AddrCount (1)] + N * [IP_Len (1) | IP | Port (2)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(PEER_STORE_MAGIC);
+        out.push(PEER_STORE_VERSION);
+        out.extend_from_slice(&(self.peers.len() as u32).to_be_bytes());
+
+        for (node_id, peer) in &self.peers {
+            out.extend_from_slice(node_id.as_bytes());
+            out.extend_from_slice(&peer.last_seen.to_be_bytes());
+            out.extend_from_slice(&peer.reputation.to_be_bytes());
+            out.push(peer.addresses.len() as u8);
+            for addr in &peer.addresses {
+                codec::write_socket_addr(&mut out, addr);
+            }
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PeerStoreError> {
+        let mut reader = Reader::new(bytes);
+
+        if reader.take(4)? != PEER_STORE_MAGIC || reader.u8()? != PEER_STORE_VERSION {
+            return Err(CodecError::InvalidField("header").into());
+        }
+
+        let mut store = PeerStore::new();
+        let count = reader.u32()?;
+
+        for _ in 0..count {
+            let node_id = NodeId::from_bytes(reader.take_array()?);
+            let last_seen = reader.u64()?;
+            let reputation = reader.u32()? as i32;
+
+            let addr_count = reader.u8()? as usize;
+            if addr_count > MAX_PEER_ADDRESSES {
+                return Err(CodecError::InvalidField("address count").into());
+            }
+            let addresses = (0..addr_count)
+                .map(|_| codec::read_socket_addr(&mut reader))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            store.peers.insert(node_id, PeerInfo { addresses, last_seen, reputation });
+        }
+
+        reader.finish()?;
+        Ok(store)
+    }
+
+    /// Loads the store from disk. A missing file yields an empty store.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load(path: &Path) -> Result<Self, PeerStoreError> {
+        match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Persists the store atomically (write to a temporary file, then rename).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self, path: &Path) -> Result<(), PeerStoreError> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.to_bytes())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
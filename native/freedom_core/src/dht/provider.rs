@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+use super::contact::Contact;
+use super::node_id::NodeId;
+use super::record::MAX_RECORD_TTL_SECS;
+
+/// Most providers remembered per content key; the ones expiring soonest make room for new ones
+pub const MAX_PROVIDERS_PER_KEY: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Provider {
+    contact: Contact,
+    expires_at: u64,
+}
+
+/// Who announced holding which content. Kept apart from `RecordStore`: a provider record
+/// says where content can be fetched, not what it is.
+#[derive(Debug, Default)]
+pub struct ProviderStore {
+    providers: HashMap<NodeId, Vec<Provider>>,
+}
+
+impl ProviderStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records (or refreshes) `provider` for `key`. The TTL is capped at `MAX_RECORD_TTL_SECS`.
+    pub fn add(&mut self, key: NodeId, provider: Contact, ttl: u32, now: u64) {
+        let expires_at = now.saturating_add(ttl.min(MAX_RECORD_TTL_SECS) as u64);
+        let providers = self.providers.entry(key).or_default();
+
+        if let Some(existing) = providers.iter_mut().find(|p| p.contact.id == provider.id) {
+            *existing = Provider { contact: provider, expires_at };
+            return;
+        }
+
+        providers.retain(|p| p.expires_at > now);
+        if providers.len() >= MAX_PROVIDERS_PER_KEY {
+            let Some((soonest, _)) = providers.iter().enumerate().min_by_key(|(_, p)| p.expires_at) else {
+                return;
+            };
+            providers.swap_remove(soonest);
+        }
+        providers.push(Provider { contact: provider, expires_at });
+    }
+
+    /// Live providers for `key`, latest expiry first.
+    pub fn get(&self, key: &NodeId, now: u64) -> Vec<Contact> {
+        let Some(providers) = self.providers.get(key) else {
+            return Vec::new();
+        };
+
+        let mut live: Vec<_> = providers.iter().filter(|p| p.expires_at > now).collect();
+        live.sort_by_key(|p| std::cmp::Reverse(p.expires_at));
+        live.into_iter().map(|p| p.contact).collect()
+    }
+
+    pub fn remove(&mut self, key: &NodeId, provider: &NodeId) -> bool {
+        let Some(providers) = self.providers.get_mut(key) else {
+            return false;
+        };
+        let before = providers.len();
+        providers.retain(|p| p.contact.id != *provider);
+        let removed = providers.len() != before;
+        if providers.is_empty() {
+            self.providers.remove(key);
+        }
+        removed
+    }
+
+    /// Number of content keys with at least one provider record
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Evicts expired provider records. Returns how many were removed.
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let mut evicted = 0;
+        self.providers.retain(|_, providers| {
+            let before = providers.len();
+            providers.retain(|p| p.expires_at > now);
+            evicted += before - providers.len();
+            !providers.is_empty()
+        });
+        evicted
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use super::contact::Contact;
+use super::messages::PutRequest;
+use super::mutable::MutableRecord;
+use super::node_id::NodeId;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Answer {
+    Pending,
+    Record(u64),
+    Missing,
+    Failed,
+}
+
+/// Outcome of a quorum read.
+#[derive(Debug)]
+pub struct QuorumResult {
+    /// The valid record with the highest sequence number seen
+    pub record: Option<MutableRecord>,
+    /// Whether at least `quorum` holders gave a usable answer
+    pub reached_quorum: bool,
+    /// Holders that returned an older sequence or nothing: send them these PUTs (read-repair)
+    pub repairs: Vec<(Contact, PutRequest)>,
+}
+
+/// Reads a mutable record from the K closest holders (found by a `Lookup`) and keeps the
+/// latest valid version. Sans-IO: the host sends GET_VALUE to every holder and feeds the answers back.
+#[derive(Debug)]
+pub struct QuorumRead {
+    key: NodeId,
+    quorum: usize,
+    holders: Vec<Contact>,
+    answers: HashMap<NodeId, Answer>,
+    best: Option<MutableRecord>,
+}
+
+impl QuorumRead {
+    /// `quorum` is the number of holders that must answer (with a record or "not found").
+    pub fn new(key: NodeId, holders: Vec<Contact>, quorum: usize) -> Self {
+        let answers = holders.iter().map(|c| (c.id, Answer::Pending)).collect();
+        Self { key, quorum: quorum.max(1), holders, answers, best: None }
+    }
+
+    pub fn holders(&self) -> &[Contact] {
+        &self.holders
+    }
+
+    /// A holder returned a record. Invalid signatures, or records stored under another key, count as failures.
+    pub fn on_record(&mut self, from: &NodeId, record: MutableRecord) {
+        if record.key() != self.key || record.verify().is_err() {
+            log::warn!("Holder {from} returned an invalid mutable record");
+            self.answer(from, Answer::Failed);
+            return;
+        }
+
+        if !self.answer(from, Answer::Record(record.seq)) {
+            return;
+        }
+        if self.best.as_ref().is_none_or(|best| record.seq > best.seq) {
+            self.best = Some(record);
+        }
+    }
+
+    /// A holder answered without the record.
+    pub fn on_missing(&mut self, from: &NodeId) {
+        self.answer(from, Answer::Missing);
+    }
+
+    /// A holder timed out or sent garbage.
+    pub fn on_failure(&mut self, from: &NodeId) {
+        self.answer(from, Answer::Failed);
+    }
+
+    /// Every holder answered (or failed).
+    pub fn is_finished(&self) -> bool {
+        self.answers.values().all(|a| *a != Answer::Pending)
+    }
+
+    pub fn has_quorum(&self) -> bool {
+        self.answered() >= self.quorum
+    }
+
+    pub fn finish(self) -> QuorumResult {
+        let reached_quorum = self.has_quorum();
+        let repairs = match &self.best {
+            Some(best) => self.holders
+                .iter()
+                .filter(|c| {
+                    match self.answers.get(&c.id) {
+                        Some(Answer::Record(seq)) => *seq < best.seq,
+                        Some(Answer::Missing) => true,
+                        _ => false,
+                    }
+                })
+                .map(|c| (*c, PutRequest { record: best.clone() }))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        QuorumResult { record: self.best, reached_quorum, repairs }
+    }
+
+    fn answered(&self) -> usize {
+        self.answers
+            .values()
+            .filter(|a| matches!(a, Answer::Record(_) | Answer::Missing))
+            .count()
+    }
+
+    /// Records the first answer of a holder we asked; ignores the rest
+    fn answer(&mut self, from: &NodeId, answer: Answer) -> bool {
+        match self.answers.get_mut(from) {
+            Some(slot) if *slot == Answer::Pending => {
+                *slot = answer;
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use super::node_id::NodeId;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    /// Sustained queries per second allowed from one peer
+    pub queries_per_sec: f64,
+    /// Queries a peer may send in a burst above the sustained rate
+    pub burst: u32,
+    /// Throttled queries tolerated before the peer is banned
+    pub max_violations: u32,
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            queries_per_sec: 10.0,
+            burst: 50,
+            max_violations: 100,
+            ban_secs: 10 * 60,
+        }
+    }
+}
+
+/// Verdict on an inbound query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateDecision {
+    Allow,
+    /// Over the limit: answer with `ErrorResponse::overloaded(retry_after_secs)`
+    Throttle {
+        retry_after_secs: u32,
+    },
+    /// Temporarily banned: drop the query without answering
+    Banned {
+        until: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: u64,
+    violations: u32,
+    banned_until: u64,
+}
+
+/// Token bucket per key (NodeId or IP address).
+#[derive(Debug)]
+struct Limiter<K> {
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Eq + Hash + Copy> Limiter<K> {
+    fn new() -> Self {
+        Self { buckets: HashMap::new() }
+    }
+
+    fn check(&mut self, key: K, config: &RateLimitConfig, now: u64) -> RateDecision {
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: config.burst as f64,
+            updated_at: now,
+            violations: 0,
+            banned_until: 0,
+        });
+
+        if now < bucket.banned_until {
+            return RateDecision::Banned { until: bucket.banned_until };
+        }
+
+        let elapsed = now.saturating_sub(bucket.updated_at) as f64;
+        bucket.tokens = (bucket.tokens + elapsed * config.queries_per_sec).min(config.burst as f64);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return RateDecision::Allow;
+        }
+
+        bucket.violations += 1;
+        if bucket.violations > config.max_violations {
+            bucket.violations = 0;
+            bucket.banned_until = now.saturating_add(config.ban_secs);
+            return RateDecision::Banned { until: bucket.banned_until };
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / config.queries_per_sec).ceil().max(1.0);
+        RateDecision::Throttle { retry_after_secs: retry_after as u32 }
+    }
+
+    /// Drops peers that are back to a full bucket and not banned
+    fn prune(&mut self, config: &RateLimitConfig, now: u64) {
+        let full_after = (config.burst as f64 / config.queries_per_sec).ceil() as u64;
+        self.buckets.retain(|_, b| now < b.banned_until || now.saturating_sub(b.updated_at) < full_after);
+    }
+}
+
+/// Limits inbound DHT queries per NodeId and per IP address, so a flood from one peer
+/// (or one host cycling NodeIds) can't monopolize a relay.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    by_node: Limiter<NodeId>,
+    by_ip: Limiter<IpAddr>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, by_node: Limiter::new(), by_ip: Limiter::new() }
+    }
+
+    /// Accounts for one query from `ip` (claiming `node_id`, if known). Both limits must allow it.
+    pub fn check(&mut self, node_id: Option<&NodeId>, ip: IpAddr, now: u64) -> RateDecision {
+        let by_ip = self.by_ip.check(ip, &self.config, now);
+        let by_node = match node_id {
+            Some(id) => self.by_node.check(*id, &self.config, now),
+            None => RateDecision::Allow,
+        };
+
+        match (by_ip, by_node) {
+            (RateDecision::Banned { until }, _) | (_, RateDecision::Banned { until }) => {
+                log::debug!("Dropped query from banned peer {ip}");
+                RateDecision::Banned { until }
+            }
+            (RateDecision::Throttle { retry_after_secs: a }, RateDecision::Throttle { retry_after_secs: b }) => {
+                RateDecision::Throttle { retry_after_secs: a.max(b) }
+            }
+            (RateDecision::Throttle { retry_after_secs }, _) | (_, RateDecision::Throttle { retry_after_secs }) => {
+                RateDecision::Throttle { retry_after_secs }
+            }
+            _ => RateDecision::Allow,
+        }
+    }
+
+    /// Forgets idle peers; call periodically to bound memory.
+    pub fn prune(&mut self, now: u64) {
+        self.by_node.prune(&self.config, now);
+        self.by_ip.prune(&self.config, now);
+    }
+
+    /// Number of peers (NodeIds plus addresses) currently tracked
+    pub fn tracked(&self) -> usize {
+        self.by_node.buckets.len() + self.by_ip.buckets.len()
+    }
+}
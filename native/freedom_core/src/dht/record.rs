@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use super::node_id::NodeId;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Largest value a record can carry (the length is encoded on 2 bytes)
+pub const MAX_RECORD_VALUE_LEN: usize = u16::MAX as usize;
+/// Longest lifetime a peer may ask us to keep a record for
+pub const MAX_RECORD_TTL_SECS: u32 = 24 * 60 * 60;
+/// How often `RecordStore::maybe_sweep` actually walks the store
+pub const SWEEP_INTERVAL_SECS: u64 = 60;
+/// Encoded size of a record without its value: [Key (32)] [Publisher (32)] [CreatedAt (8)] [TTL (4)] [ValLen (2)]
+pub const RECORD_OVERHEAD: usize = 78;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordError {
+    #[error("Malformed record: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Record value too large: {len} bytes (max {max})")]
+    ValueTooLarge {
+        len: usize,
+        max: usize,
+    },
+    #[error("Record TTL too long: {0}s (max {MAX_RECORD_TTL_SECS}s)")]
+    TtlTooLong(u32),
+    #[error("Publisher {0} exceeded its storage quota")]
+    QuotaExceeded(NodeId),
+    #[error("Record store is full")]
+    StoreFull,
+    #[error("Record expired at {expires_at} (now {now})")]
+    Expired {
+        expires_at: u64,
+        now: u64,
+    },
+}
+
+/// A value stored in the DHT, carried by STORE requests and GET_VALUE responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub key: NodeId,
+    pub value: Vec<u8>,
+    pub publisher: NodeId,
+    pub ttl: u32, // Seconds, counted from created_at
+    pub created_at: u64, // Seconds since UNIX epoch
+}
+
+impl Record {
+    pub fn new(key: NodeId, value: Vec<u8>, publisher: NodeId, ttl: u32, created_at: u64) -> Result<Self, RecordError> {
+        let record = Self { key, value, publisher, ttl, created_at };
+        record.check_limits()?;
+        Ok(record)
+    }
+
+    pub fn expires_at(&self) -> u64 {
+        self.created_at.saturating_add(self.ttl as u64)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at()
+    }
+
+    /// Serializes the record.
+    /// Format: [Key (32)] [Publisher (32)] [CreatedAt (8)] [TTL (4)] [ValLen (2)] [Value]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(RECORD_OVERHEAD + self.value.len());
+        out.extend_from_slice(self.key.as_bytes());
+        out.extend_from_slice(self.publisher.as_bytes());
+        out.extend_from_slice(&self.created_at.to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+        out.extend_from_slice(&(self.value.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.value);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RecordError> {
+        let mut reader = Reader::new(bytes);
+
+        let key = NodeId::from_bytes(reader.take_array()?);
+        let publisher = NodeId::from_bytes(reader.take_array()?);
+        let created_at = reader.u64()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()? as usize;
+        let value = reader.take(len)?.to_vec();
+        reader.finish()?;
+
+        Self::new(key, value, publisher, ttl, created_at)
+    }
+
+    fn check_limits(&self) -> Result<(), RecordError> {
+        if self.value.len() > MAX_RECORD_VALUE_LEN {
+            return Err(RecordError::ValueTooLarge { len: self.value.len(), max: MAX_RECORD_VALUE_LEN });
+        }
+        if self.ttl > MAX_RECORD_TTL_SECS {
+            return Err(RecordError::TtlTooLong(self.ttl));
+        }
+        Ok(())
+    }
+}
+
+/// Which records make room when the store is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// The record closest to expiring goes first
+    #[default]
+    ExpireSoonest,
+    /// The record served the fewest times goes first (ties: expire soonest)
+    LeastRequested,
+}
+
+/// Bounds on what peers can make this node store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageLimits {
+    /// Total bytes across all records (value plus per-record overhead)
+    pub max_total_bytes: usize,
+    /// Bytes a single publisher may occupy
+    pub max_bytes_per_publisher: usize,
+    /// Largest accepted value (never above `MAX_RECORD_VALUE_LEN`)
+    pub max_value_len: usize,
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for StorageLimits {
+    fn default() -> Self {
+        Self {
+            max_total_bytes: 64 * 1024 * 1024,
+            max_bytes_per_publisher: 1024 * 1024,
+            max_value_len: MAX_RECORD_VALUE_LEN,
+            eviction: EvictionPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Stored {
+    record: Record,
+    requests: u64,
+}
+
+/// Records held by this node for the DHT. Expired records are never served,
+/// and are evicted by periodic sweeps. Storage is bounded by `StorageLimits`.
+#[derive(Debug, Default)]
+pub struct RecordStore {
+    records: HashMap<NodeId, Stored>,
+    limits: StorageLimits,
+    total_bytes: usize,
+    publisher_bytes: HashMap<NodeId, usize>,
+    last_sweep: u64,
+}
+
+impl RecordStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(limits: StorageLimits) -> Self {
+        Self { limits, ..Self::default() }
+    }
+
+    pub fn limits(&self) -> &StorageLimits {
+        &self.limits
+    }
+
+    /// Stores a record, replacing an older one under the same key.
+    /// A record older than the one already held is ignored. When the store is full,
+    /// expired records are swept, then others are evicted following the eviction policy.
+    pub fn put(&mut self, record: Record, now: u64) -> Result<(), RecordError> {
+        record.check_limits()?;
+        let max_value_len = self.limits.max_value_len.min(MAX_RECORD_VALUE_LEN);
+        if record.value.len() > max_value_len {
+            return Err(RecordError::ValueTooLarge { len: record.value.len(), max: max_value_len });
+        }
+        if record.is_expired(now) {
+            return Err(RecordError::Expired { expires_at: record.expires_at(), now });
+        }
+
+        if let Some(existing) = self.records.get(&record.key)
+            && existing.record.created_at > record.created_at
+            && !existing.record.is_expired(now)
+        {
+            log::debug!("Ignored stale record for {}", record.key);
+            return Ok(());
+        }
+
+        let size = stored_size(&record);
+        let replaced = self.records.get(&record.key).map(|s| &s.record);
+        let publisher_used = self.publisher_bytes.get(&record.publisher).copied().unwrap_or(0)
+            - replaced.filter(|r| r.publisher == record.publisher).map_or(0, stored_size);
+        if publisher_used + size > self.limits.max_bytes_per_publisher {
+            return Err(RecordError::QuotaExceeded(record.publisher));
+        }
+        if size > self.limits.max_total_bytes {
+            return Err(RecordError::StoreFull);
+        }
+
+        let requests = self.remove_entry(&record.key).map_or(0, |s| s.requests);
+        if self.total_bytes + size > self.limits.max_total_bytes {
+            self.sweep(now);
+        }
+        while self.total_bytes + size > self.limits.max_total_bytes {
+            let Some(victim) = self.eviction_victim() else {
+                return Err(RecordError::StoreFull);
+            };
+            log::debug!("Evicted DHT record {victim} to make room");
+            self.remove_entry(&victim);
+        }
+
+        self.insert_entry(Stored { record, requests });
+        Ok(())
+    }
+
+    /// The record under `key`, unless it has expired.
+    pub fn get(&self, key: &NodeId, now: u64) -> Option<&Record> {
+        self.records
+            .get(key)
+            .map(|s| &s.record)
+            .filter(|r| !r.is_expired(now))
+    }
+
+    /// Same as `get`, but counts the lookup as a request served to a peer (for `LeastRequested` eviction).
+    pub fn fetch(&mut self, key: &NodeId, now: u64) -> Option<&Record> {
+        let stored = self.records.get_mut(key).filter(|s| !s.record.is_expired(now))?;
+        stored.requests += 1;
+        Some(&stored.record)
+    }
+
+    pub fn remove(&mut self, key: &NodeId) -> Option<Record> {
+        self.remove_entry(key).map(|s| s.record)
+    }
+
+    /// Every held record, including expired ones not swept yet
+    pub fn iter(&self) -> impl Iterator<Item = &Record> {
+        self.records.values().map(|s| &s.record)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Bytes currently accounted against `StorageLimits::max_total_bytes`
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// When the next record expires, so the host can schedule the next sweep.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.iter().map(Record::expires_at).min()
+    }
+
+    /// Evicts every expired record. Returns how many were removed.
+    pub fn sweep(&mut self, now: u64) -> usize {
+        let expired: Vec<NodeId> = self
+            .iter()
+            .filter(|r| r.is_expired(now))
+            .map(|r| r.key)
+            .collect();
+        for key in &expired {
+            self.remove_entry(key);
+        }
+        self.last_sweep = now;
+
+        if !expired.is_empty() {
+            log::debug!("Evicted {} expired DHT records", expired.len());
+        }
+        expired.len()
+    }
+
+    /// Sweeper tick: sweeps at most once every `SWEEP_INTERVAL_SECS`. Meant to be called from the host's timer.
+    pub fn maybe_sweep(&mut self, now: u64) -> usize {
+        if now.saturating_sub(self.last_sweep) < SWEEP_INTERVAL_SECS {
+            return 0;
+        }
+        self.sweep(now)
+    }
+
+    fn eviction_victim(&self) -> Option<NodeId> {
+        let stored = self.records.values();
+        let victim = match self.limits.eviction {
+            EvictionPolicy::ExpireSoonest => stored.min_by_key(|s| s.record.expires_at()),
+            EvictionPolicy::LeastRequested => stored.min_by_key(|s| (s.requests, s.record.expires_at())),
+        };
+        victim.map(|s| s.record.key)
+    }
+
+    fn insert_entry(&mut self, stored: Stored) {
+        let size = stored_size(&stored.record);
+        self.total_bytes += size;
+        *self.publisher_bytes.entry(stored.record.publisher).or_default() += size;
+        self.records.insert(stored.record.key, stored);
+    }
+
+    fn remove_entry(&mut self, key: &NodeId) -> Option<Stored> {
+        let stored = self.records.remove(key)?;
+        let size = stored_size(&stored.record);
+        self.total_bytes -= size;
+        if let Some(used) = self.publisher_bytes.get_mut(&stored.record.publisher) {
+            *used -= size;
+            if *used == 0 {
+                self.publisher_bytes.remove(&stored.record.publisher);
+            }
+        }
+        Some(stored)
+    }
+}
+
+/// Bytes a record is accounted for: its encoded size
+fn stored_size(record: &Record) -> usize {
+    RECORD_OVERHEAD + record.value.len()
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use super::contact::Contact;
+use super::distance::distance;
+use super::lookup::K;
+use super::node_id::NodeId;
+use super::record::{ Record, RecordStore };
+use super::routing::RoutingTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RepublishConfig {
+    /// How often records this node published are re-sent with a fresh TTL
+    pub republish_interval_secs: u64,
+    /// How often records held for others are re-sent to the current closest nodes
+    pub replicate_interval_secs: u64,
+}
+
+impl Default for RepublishConfig {
+    fn default() -> Self {
+        Self {
+            republish_interval_secs: 60 * 60,
+            replicate_interval_secs: 60 * 60,
+        }
+    }
+}
+
+/// Work the host must perform: a STORE of the record to the K closest nodes to its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RepublishAction {
+    /// One of our own records, re-stamped with `created_at = now`
+    Republish(Record),
+    /// A record held for someone else, sent unchanged (its expiry does not move)
+    Replicate(Record),
+}
+
+impl RepublishAction {
+    pub fn record(&self) -> &Record {
+        match self {
+            RepublishAction::Republish(record) | RepublishAction::Replicate(record) => record,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Published {
+    record: Record,
+    next_at: u64,
+}
+
+/// Decides when records must be sent again so values survive churn. Sans-IO: the host calls
+/// `due` from its timer (`next_deadline` says when) and performs the returned STOREs.
+#[derive(Debug, Default)]
+pub struct RepublishScheduler {
+    config: RepublishConfig,
+    published: HashMap<NodeId, Published>,
+    replicate_at: HashMap<NodeId, u64>,
+}
+
+impl RepublishScheduler {
+    pub fn new(config: RepublishConfig) -> Self {
+        Self { config, ..Self::default() }
+    }
+
+    /// Tracks a record this node just published (the caller performs the first STORE itself).
+    pub fn publish(&mut self, record: Record) {
+        let next_at = Self::republish_deadline(&self.config, &record);
+        self.published.insert(record.key, Published { record, next_at });
+    }
+
+    /// Stops republishing one of our records; it will expire from the network on its own.
+    pub fn unpublish(&mut self, key: &NodeId) -> Option<Record> {
+        self.published.remove(key).map(|p| p.record)
+    }
+
+    /// Collects every STORE due at `now`, and schedules the next round for each of them.
+    pub fn due(&mut self, store: &RecordStore, now: u64) -> Vec<RepublishAction> {
+        let mut actions = Vec::new();
+
+        for published in self.published.values_mut() {
+            if now >= published.next_at {
+                published.record.created_at = now;
+                published.next_at = Self::republish_deadline(&self.config, &published.record);
+                actions.push(RepublishAction::Republish(published.record.clone()));
+            }
+        }
+
+        let interval = self.config.replicate_interval_secs;
+        self.replicate_at.retain(|key, _| store.get(key, now).is_some());
+        for record in store.iter() {
+            if record.is_expired(now) || self.published.contains_key(&record.key) {
+                continue;
+            }
+
+            let next_at = self.replicate_at.entry(record.key).or_insert(now.saturating_add(interval));
+            if now >= *next_at {
+                *next_at = now.saturating_add(interval);
+                actions.push(RepublishAction::Replicate(record.clone()));
+            }
+        }
+
+        actions
+    }
+
+    /// Earliest time `due` has something to do, for scheduling the host's timer.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let published = self.published.values().map(|p| p.next_at);
+        let replicated = self.replicate_at.values().copied();
+        published.chain(replicated).min()
+    }
+
+    /// The configured interval, but always before the TTL lapses (at three quarters of it at the latest)
+    fn republish_deadline(config: &RepublishConfig, record: &Record) -> u64 {
+        let ttl = record.ttl as u64;
+        let delay = config.republish_interval_secs.min(ttl - ttl / 4).max(1);
+        record.created_at.saturating_add(delay)
+    }
+}
+
+/// Records to hand to a peer we just learned about (Kademlia's replicate-on-arrival): every live
+/// record the newcomer belongs to the K closest nodes of, as long as this node is among them too
+/// (otherwise we are only caching the record and someone closer will replicate it).
+/// Call before inserting the newcomer into the routing table.
+pub fn replicate_to_new_peer(table: &RoutingTable, store: &RecordStore, newcomer: &Contact, now: u64) -> Vec<Record> {
+    if newcomer.id == *table.local_id() || table.get(&newcomer.id).is_some() {
+        return Vec::new();
+    }
+
+    store
+        .iter()
+        .filter(|record| !record.is_expired(now))
+        .filter(|record| {
+            // The K closest we know of, not counting ourselves
+            let closest = table.closest(&record.key, K);
+            let is_within = |id: &NodeId| {
+                closest.len() < K || distance(id, &record.key) < distance(&closest[K - 1].id, &record.key)
+            };
+            is_within(&newcomer.id) && is_within(table.local_id())
+        })
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,252 @@
+use ed25519_dalek::VerifyingKey;
+
+use super::contact::Contact;
+use super::distance::{ self, BUCKET_COUNT };
+use super::lookup::K;
+use super::node_id::NodeId;
+use super::sybil::{ AdmissionError, AdmissionPolicy };
+
+/// Buckets without activity for this long get a refresh lookup
+pub const REFRESH_INTERVAL_SECS: u64 = 60 * 60;
+/// How long the least recently seen contact has to answer a liveness ping before it is evicted
+pub const PING_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub contact: Contact,
+    pub last_seen: u64, // Seconds since UNIX epoch
+}
+
+/// A full bucket waiting to learn whether its oldest contact is still alive.
+#[derive(Debug, Clone, Copy)]
+struct PendingEviction {
+    oldest: NodeId,
+    replacement: Contact,
+    deadline: u64,
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// Least recently seen first
+    entries: Vec<Entry>,
+    last_refreshed: u64,
+    pending: Option<PendingEviction>,
+}
+
+impl Bucket {
+    fn position(&self, id: &NodeId) -> Option<usize> {
+        self.entries.iter().position(|e| e.contact.id == *id)
+    }
+
+    fn touch(&mut self, position: usize, contact: Contact, now: u64) {
+        self.entries.remove(position);
+        self.entries.push(Entry { contact, last_seen: now });
+    }
+
+    /// Drops the oldest contact for the replacement waiting on it
+    fn evict_for(&mut self, pending: PendingEviction, now: u64) {
+        if let Some(position) = self.position(&pending.oldest) {
+            self.entries.remove(position);
+        }
+        if self.entries.len() < K && self.position(&pending.replacement.id).is_none() {
+            self.entries.push(Entry { contact: pending.replacement, last_seen: now });
+        }
+    }
+}
+
+/// What `RoutingTable::insert` did with a contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Inserted,
+    /// Already known; moved to the most recently seen position
+    Updated,
+    /// The bucket is full: ping this (least recently seen) contact, then report the result with
+    /// `ping_succeeded` / `ping_failed`. The new contact replaces it only if it does not answer.
+    PingRequired(Contact),
+    /// The bucket is full and already waiting on a ping; the contact was dropped
+    BucketFull,
+    /// The local node itself
+    Ignored,
+    /// Rejected by the admission policy (see `RoutingTable::insert_verified` for the reason)
+    Rejected,
+}
+
+/// Kademlia routing table: 256 buckets of at most K contacts, indexed by shared prefix length.
+/// C# Reference: FalconNode.Core.Dht.RoutingTable
+#[derive(Debug)]
+pub struct RoutingTable {
+    local_id: NodeId,
+    buckets: Vec<Bucket>,
+    policy: AdmissionPolicy,
+}
+
+impl RoutingTable {
+    pub fn new(local_id: NodeId, now: u64) -> Self {
+        let buckets = (0..BUCKET_COUNT)
+            .map(|_| Bucket { last_refreshed: now, ..Bucket::default() })
+            .collect();
+        Self { local_id, buckets, policy: AdmissionPolicy::default() }
+    }
+
+    /// Applies an admission policy (S/Kademlia puzzle) to every contact inserted from now on.
+    pub fn with_policy(mut self, policy: AdmissionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &AdmissionPolicy {
+        &self.policy
+    }
+
+    pub fn local_id(&self) -> &NodeId {
+        &self.local_id
+    }
+
+    /// Inserts a peer we completed a handshake with: its NodeId must be the hash of `identity_key`.
+    pub fn insert_verified(
+        &mut self,
+        contact: Contact,
+        identity_key: &VerifyingKey,
+        now: u64
+    ) -> Result<InsertOutcome, AdmissionError> {
+        self.policy.check_peer(&contact.id, identity_key)?;
+        Ok(self.insert(contact, now))
+    }
+
+    /// Records that we heard from `contact`. Activity in a bucket counts as a refresh.
+    /// Contacts whose NodeId fails the puzzle are rejected.
+    pub fn insert(&mut self, contact: Contact, now: u64) -> InsertOutcome {
+        if contact.id == self.local_id {
+            return InsertOutcome::Ignored;
+        }
+        if let Err(e) = self.policy.check_id(&contact.id) {
+            log::debug!("Rejected contact: {e}");
+            return InsertOutcome::Rejected;
+        }
+
+        let bucket = &mut self.buckets[self.local_id.bucket_index(&contact.id)];
+        bucket.last_refreshed = now;
+
+        if let Some(position) = bucket.position(&contact.id) {
+            // Hearing from the contact we were about to ping settles it
+            if bucket.pending.is_some_and(|p| p.oldest == contact.id) {
+                bucket.pending = None;
+            }
+            bucket.touch(position, contact, now);
+            return InsertOutcome::Updated;
+        }
+
+        if bucket.entries.len() < K {
+            bucket.entries.push(Entry { contact, last_seen: now });
+            return InsertOutcome::Inserted;
+        }
+
+        if bucket.pending.is_some() {
+            return InsertOutcome::BucketFull;
+        }
+
+        let oldest = bucket.entries[0].contact;
+        bucket.pending = Some(PendingEviction {
+            oldest: oldest.id,
+            replacement: contact,
+            deadline: now.saturating_add(PING_TIMEOUT_SECS),
+        });
+        InsertOutcome::PingRequired(oldest)
+    }
+
+    /// The pinged contact answered: it stays (as most recently seen) and the newcomer is discarded.
+    pub fn ping_succeeded(&mut self, id: &NodeId, now: u64) {
+        let bucket = &mut self.buckets[self.local_id.bucket_index(id)];
+        if let Some(position) = bucket.position(id) {
+            let contact = bucket.entries[position].contact;
+            bucket.touch(position, contact, now);
+        }
+        if bucket.pending.is_some_and(|p| p.oldest == *id) {
+            bucket.pending = None;
+        }
+    }
+
+    /// The pinged contact did not answer: it is evicted in favour of the newcomer.
+    pub fn ping_failed(&mut self, id: &NodeId, now: u64) {
+        let bucket = &mut self.buckets[self.local_id.bucket_index(id)];
+        match bucket.pending {
+            Some(pending) if pending.oldest == *id => {
+                bucket.pending = None;
+                bucket.evict_for(pending, now);
+            }
+            _ => {}
+        }
+    }
+
+    /// Treats every ping unanswered after `PING_TIMEOUT_SECS` as failed. Returns how many contacts were evicted.
+    pub fn expire_pings(&mut self, now: u64) -> usize {
+        let mut evicted = 0;
+        for bucket in &mut self.buckets {
+            if let Some(pending) = bucket.pending
+                && now >= pending.deadline
+            {
+                bucket.pending = None;
+                bucket.evict_for(pending, now);
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> Option<Contact> {
+        let bucket = &mut self.buckets[self.local_id.bucket_index(id)];
+        let position = bucket.position(id)?;
+        Some(bucket.entries.remove(position).contact)
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&Entry> {
+        self.buckets[self.local_id.bucket_index(id)].entries.iter().find(|e| e.contact.id == *id)
+    }
+
+    /// The `count` known contacts closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let contacts = self.buckets.iter().flat_map(|b| b.entries.iter().map(|e| e.contact));
+        distance::closest(contacts, target, count, |c| &c.id)
+    }
+
+    /// Contacts in one bucket, least recently seen first
+    pub fn bucket(&self, index: usize) -> impl Iterator<Item = &Entry> {
+        self.buckets.get(index).into_iter().flat_map(|b| b.entries.iter())
+    }
+
+    /// Number of contacts in each bucket, index 0 (farthest) first
+    pub fn bucket_occupancy(&self) -> Vec<usize> {
+        self.buckets.iter().map(|b| b.entries.len()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|b| b.entries.is_empty())
+    }
+
+    /// Refresh task tick: returns a random lookup target for every bucket idle for `interval` seconds,
+    /// and marks those buckets refreshed. The host runs a FIND_NODE lookup for each target.
+    /// Only buckets up to the closest non-empty one are refreshed; closer ones cover a keyspace too
+    /// small to hold any peer yet.
+    pub fn refresh_targets(&mut self, now: u64, interval: u64) -> Vec<NodeId> {
+        let Some(deepest) = self.buckets.iter().rposition(|b| !b.entries.is_empty()) else {
+            return Vec::new();
+        };
+
+        let mut targets = Vec::new();
+        for (index, bucket) in self.buckets[..=deepest].iter_mut().enumerate() {
+            if now.saturating_sub(bucket.last_refreshed) >= interval {
+                bucket.last_refreshed = now;
+                targets.push(self.local_id.random_in_bucket(index));
+            }
+        }
+
+        if !targets.is_empty() {
+            log::debug!("Refreshing {} stale routing buckets", targets.len());
+        }
+        targets
+    }
+}
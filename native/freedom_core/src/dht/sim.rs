@@ -0,0 +1,388 @@
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, HashMap, HashSet };
+use std::net::{ Ipv4Addr, SocketAddr };
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{ Rng, RngCore, SeedableRng };
+
+use super::contact::Contact;
+use super::distance::distance;
+use super::lookup::{ Lookup, LookupResult, K };
+use super::messages::{
+    DhtMessage,
+    FindNodeRequest,
+    FindNodeResponse,
+    GetValueRequest,
+    GetValueResponse,
+    StoreRequest,
+    StoreResponse,
+};
+use super::node_id::NodeId;
+use super::record::{ Record, RecordStore };
+use super::routing::{ InsertOutcome, RoutingTable };
+use crate::protocol::packet::NetworkPacket;
+
+/// Simulated wall clock at time zero, in seconds since epoch
+const SIM_EPOCH_SECS: u64 = 1_000_000;
+
+/// Network conditions of a simulation.
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// One-way delay, drawn uniformly from this range (milliseconds)
+    pub min_latency_ms: u64,
+    pub max_latency_ms: u64,
+    /// Probability that a packet is dropped (0.0 ..= 1.0)
+    pub loss: f64,
+    /// How long a request waits for its response before counting as failed
+    pub timeout_ms: u64,
+    /// Seed of every random choice (ids, latency, loss, churn), so runs are reproducible
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            min_latency_ms: 10,
+            max_latency_ms: 100,
+            loss: 0.0,
+            timeout_ms: 1_000,
+            seed: 0,
+        }
+    }
+}
+
+/// A virtual node: the same routing table and record store a real node runs.
+pub struct SimNode {
+    pub contact: Contact,
+    pub table: RoutingTable,
+    pub store: RecordStore,
+    pub online: bool,
+}
+
+enum Event {
+    /// An encoded packet reaches `to`
+    Deliver {
+        from: usize,
+        to: usize,
+        packet: Vec<u8>,
+    },
+    /// The request `request_id` sent to `peer` is given up on
+    Timeout {
+        peer: usize,
+        request_id: u32,
+    },
+}
+
+/// Hundreds of virtual DHT nodes exchanging real, encoded DHT packets over an in-process
+/// message bus with latency, loss and churn. Time is virtual: events are processed in
+/// delivery order, so a whole run takes milliseconds and is deterministic for a seed.
+///
+/// Operations (`find_node`, `store`, `get_value`) run one at a time from an origin node and
+/// return once the origin's lookup is finished and the bus is idle.
+pub struct Simulation {
+    config: SimConfig,
+    nodes: Vec<SimNode>,
+    index: HashMap<NodeId, usize>,
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    events: HashMap<u64, Event>,
+    next_event: u64,
+    next_request_id: u32,
+    now_ms: u64,
+    rng: StdRng,
+}
+
+impl Simulation {
+    /// Creates `size` unconnected nodes; call `bootstrap` to let them find each other.
+    pub fn new(size: usize, config: SimConfig) -> Self {
+        let mut sim = Self {
+            config,
+            nodes: Vec::with_capacity(size),
+            index: HashMap::new(),
+            queue: BinaryHeap::new(),
+            events: HashMap::new(),
+            next_event: 0,
+            next_request_id: 1,
+            now_ms: 0,
+            rng: StdRng::seed_from_u64(config.seed),
+        };
+        for _ in 0..size {
+            sim.spawn();
+        }
+        sim
+    }
+
+    /// Joins every node through node 0 with a lookup of its own id, as a real node joins
+    /// through a bootstrap peer.
+    pub fn bootstrap(&mut self) {
+        for i in 1..self.nodes.len() {
+            self.join(i, 0);
+        }
+    }
+
+    /// Takes `leave` random online nodes offline and adds `join` new nodes, each joining
+    /// through a random online node. Returns the indices of the new nodes.
+    pub fn churn(&mut self, leave: usize, join: usize) -> Vec<usize> {
+        let mut online = self.online();
+        online.shuffle(&mut self.rng);
+        for &i in online.iter().take(leave) {
+            self.nodes[i].online = false;
+        }
+
+        let mut joined = Vec::with_capacity(join);
+        for _ in 0..join {
+            let online = self.online();
+            let Some(&via) = online.choose(&mut self.rng) else {
+                break;
+            };
+            let node = self.spawn();
+            self.join(node, via);
+            joined.push(node);
+        }
+        joined
+    }
+
+    /// Changes the packet loss probability for everything sent from now on.
+    pub fn set_loss(&mut self, loss: f64) {
+        self.config.loss = loss;
+    }
+
+    pub fn set_online(&mut self, node: usize, online: bool) {
+        self.nodes[node].online = online;
+    }
+
+    /// Iterative FIND_NODE for `target` from `origin`.
+    pub fn find_node(&mut self, origin: usize, target: NodeId) -> LookupResult {
+        self.run_lookup(origin, target, false)
+    }
+
+    /// Iterative GET_VALUE for `key` from `origin`.
+    pub fn get_value(&mut self, origin: usize, key: NodeId) -> LookupResult {
+        self.run_lookup(origin, key, true)
+    }
+
+    /// Stores a record on the K closest nodes `origin` can find. Returns how many acknowledged it.
+    pub fn store(&mut self, origin: usize, record: Record) -> usize {
+        let closest = self.find_node(origin, record.key).closest;
+        let mut pending = HashSet::new();
+        for contact in closest {
+            let request_id = self.request(origin, &contact, &DhtMessage::Store(StoreRequest::new(record.clone())));
+            pending.insert(request_id);
+        }
+
+        let mut acks = 0;
+        while let Some(event) = self.pop() {
+            if let Some((request_id, _, DhtMessage::StoreRes(_))) = self.process(origin, event)
+                && pending.remove(&request_id)
+            {
+                acks += 1;
+            }
+        }
+        acks
+    }
+
+    /// The ids of the `count` online nodes truly closest to `target`, closest first.
+    pub fn closest_online(&self, target: &NodeId, count: usize) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = self.nodes
+            .iter()
+            .filter(|n| n.online)
+            .map(|n| n.contact.id)
+            .collect();
+        ids.sort_by_key(|id| distance(id, target));
+        ids.truncate(count);
+        ids
+    }
+
+    /// Indices of the nodes currently online
+    pub fn online(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&i| self.nodes[i].online).collect()
+    }
+
+    pub fn node(&self, index: usize) -> &SimNode {
+        &self.nodes[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Virtual time elapsed since the start, in milliseconds
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    /// Virtual wall clock handed to the DHT state machines
+    pub fn now(&self) -> u64 {
+        SIM_EPOCH_SECS + self.now_ms / 1000
+    }
+
+    fn spawn(&mut self) -> usize {
+        let mut bytes = [0u8; 32];
+        self.rng.fill_bytes(&mut bytes);
+        let id = NodeId::from_bytes(bytes);
+
+        let index = self.nodes.len();
+        let ip = Ipv4Addr::from(0x0a00_0000 | index as u32);
+        let contact = Contact::new(id, SocketAddr::new(ip.into(), 4000));
+        self.nodes.push(SimNode {
+            contact,
+            table: RoutingTable::new(id, self.now()),
+            store: RecordStore::new(),
+            online: true,
+        });
+        self.index.insert(id, index);
+        index
+    }
+
+    fn join(&mut self, node: usize, via: usize) {
+        let seed = self.nodes[via].contact;
+        self.learn(node, seed);
+        let own_id = self.nodes[node].contact.id;
+        self.find_node(node, own_id);
+    }
+
+    fn run_lookup(&mut self, origin: usize, target: NodeId, wants_value: bool) -> LookupResult {
+        let local_id = self.nodes[origin].contact.id;
+        let seeds = self.nodes[origin].table.closest(&target, K);
+        let mut lookup = if wants_value { Lookup::get_value(target, seeds) } else { Lookup::find_node(target, seeds) };
+        lookup = lookup.with_local_id(local_id);
+
+        let mut pending = HashSet::new();
+        loop {
+            for contact in lookup.next_queries() {
+                let message = match wants_value {
+                    true => DhtMessage::GetValueReq(GetValueRequest { key: target }),
+                    false => DhtMessage::FindNode(FindNodeRequest { target }),
+                };
+                pending.insert(self.request(origin, &contact, &message));
+            }
+            if lookup.is_finished() {
+                break;
+            }
+
+            let Some(event) = self.pop() else {
+                break;
+            };
+            if let Event::Timeout { peer, request_id } = event {
+                if pending.remove(&request_id) {
+                    lookup.on_failure(&self.nodes[peer].contact.id);
+                }
+                continue;
+            }
+
+            let Some((request_id, from, message)) = self.process(origin, event) else {
+                continue;
+            };
+            if !pending.remove(&request_id) {
+                continue;
+            }
+            match message {
+                DhtMessage::FindNodeRes(response) => lookup.on_nodes(&from, response.contacts),
+                DhtMessage::GetValueRes(GetValueResponse::Value(value)) => lookup.on_value(&from, value),
+                DhtMessage::GetValueRes(GetValueResponse::CloserNodes { nodes, .. }) => lookup.on_nodes(&from, nodes),
+                _ => lookup.on_failure(&from),
+            }
+        }
+
+        // Let late responses and the requests still in flight settle
+        while let Some(event) = self.pop() {
+            self.process(origin, event);
+        }
+        lookup.into_result()
+    }
+
+    /// Sends a request and arms its timeout. Returns the request id.
+    fn request(&mut self, origin: usize, to: &Contact, message: &DhtMessage) -> u32 {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let peer = self.index[&to.id];
+        self.send(origin, peer, message, request_id);
+        let deadline = self.now_ms + self.config.timeout_ms;
+        self.schedule(deadline, Event::Timeout { peer, request_id });
+        request_id
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: &DhtMessage, request_id: u32) {
+        if self.rng.gen_bool(self.config.loss.clamp(0.0, 1.0)) {
+            return;
+        }
+        let latency = self.rng.gen_range(self.config.min_latency_ms..=self.config.max_latency_ms.max(self.config.min_latency_ms));
+        let packet = message.to_packet(request_id).to_bytes();
+        self.schedule(self.now_ms + latency, Event::Deliver { from, to, packet });
+    }
+
+    fn schedule(&mut self, at_ms: u64, event: Event) {
+        let id = self.next_event;
+        self.next_event += 1;
+        self.queue.push(Reverse((at_ms, id)));
+        self.events.insert(id, event);
+    }
+
+    fn pop(&mut self) -> Option<Event> {
+        let Reverse((at_ms, id)) = self.queue.pop()?;
+        self.now_ms = self.now_ms.max(at_ms);
+        self.events.remove(&id)
+    }
+
+    /// Handles an event on the receiving node. Responses addressed to `origin` are returned
+    /// as (request id, responder, message); everything else is answered or dropped here.
+    fn process(&mut self, origin: usize, event: Event) -> Option<(u32, NodeId, DhtMessage)> {
+        let Event::Deliver { from, to, packet } = event else {
+            return None;
+        };
+        if !self.nodes[to].online {
+            return None;
+        }
+
+        let packet = NetworkPacket::from_bytes(&packet).ok()?;
+        let request_id = packet.header.request_id;
+        let message = DhtMessage::from_packet(&packet).ok()?;
+        let sender = self.nodes[from].contact;
+        self.learn(to, sender);
+
+        let now = self.now();
+        let node = &mut self.nodes[to];
+        let response = match message {
+            DhtMessage::FindNode(request) => DhtMessage::FindNodeRes(FindNodeResponse {
+                contacts: node.table.closest(&request.target, K),
+                ..Default::default()
+            }),
+            DhtMessage::GetValueReq(request) => DhtMessage::GetValueRes(match node.store.fetch(&request.key, now) {
+                Some(record) => GetValueResponse::Value(record.value.clone()),
+                None => GetValueResponse::CloserNodes { nodes: node.table.closest(&request.key, K), token: None },
+            }),
+            DhtMessage::Store(request) => {
+                let key = request.record.key;
+                if node.store.put(request.record, now).is_err() {
+                    return None;
+                }
+                DhtMessage::StoreRes(StoreResponse { key })
+            }
+            response => {
+                return (to == origin).then_some((request_id, sender.id, response));
+            }
+        };
+
+        self.send(to, from, &response, request_id);
+        None
+    }
+
+    /// Adds a peer to a node's routing table, settling bucket-full pings immediately
+    /// (the old contact answers if it is online).
+    fn learn(&mut self, node: usize, contact: Contact) {
+        let now = self.now();
+        if let InsertOutcome::PingRequired(oldest) = self.nodes[node].table.insert(contact, now) {
+            let alive = self.index.get(&oldest.id).is_some_and(|&i| self.nodes[i].online);
+            let table = &mut self.nodes[node].table;
+            match alive {
+                true => table.ping_succeeded(&oldest.id, now),
+                false => table.ping_failed(&oldest.id, now),
+            }
+        }
+    }
+}
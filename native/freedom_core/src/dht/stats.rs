@@ -0,0 +1,85 @@
+use super::routing::RoutingTable;
+
+/// Counters of a single lookup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /// Peers queried
+    pub queries: u32,
+    pub responses: u32,
+    /// Timeouts and unusable responses
+    pub failures: u32,
+    /// Longest referral chain that produced an answer (1 = a seed answered)
+    pub hops: u32,
+    pub found_value: bool,
+}
+
+/// Point-in-time view of the DHT for operators, see `DhtTelemetry::snapshot`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DhtStats {
+    pub lookups: u64,
+    pub values_found: u64,
+    pub queries: u64,
+    pub failures: u64,
+    /// Share of queries that failed (0.0 - 1.0)
+    pub timeout_rate: f64,
+    pub avg_hops: f64,
+    pub max_hops: u32,
+    pub avg_latency_ms: f64,
+    pub max_latency_ms: u64,
+    pub routing_table_size: usize,
+    /// Contacts per bucket, index 0 (farthest) first
+    pub bucket_occupancy: Vec<usize>,
+}
+
+/// Accumulates lookup statistics. The host times each lookup (the core has no clock of its own
+/// at that resolution) and reports it with `record_lookup`.
+#[derive(Debug, Default)]
+pub struct DhtTelemetry {
+    lookups: u64,
+    values_found: u64,
+    queries: u64,
+    failures: u64,
+    total_hops: u64,
+    max_hops: u32,
+    total_latency_ms: u64,
+    max_latency_ms: u64,
+}
+
+impl DhtTelemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_lookup(&mut self, stats: &LookupStats, latency_ms: u64) {
+        self.lookups += 1;
+        self.values_found += stats.found_value as u64;
+        self.queries += stats.queries as u64;
+        self.failures += stats.failures as u64;
+        self.total_hops += stats.hops as u64;
+        self.max_hops = self.max_hops.max(stats.hops);
+        self.total_latency_ms = self.total_latency_ms.saturating_add(latency_ms);
+        self.max_latency_ms = self.max_latency_ms.max(latency_ms);
+    }
+
+    pub fn snapshot(&self, table: &RoutingTable) -> DhtStats {
+        let per_lookup = |total: u64| if self.lookups == 0 { 0.0 } else { total as f64 / self.lookups as f64 };
+
+        DhtStats {
+            lookups: self.lookups,
+            values_found: self.values_found,
+            queries: self.queries,
+            failures: self.failures,
+            timeout_rate: if self.queries == 0 { 0.0 } else { self.failures as f64 / self.queries as f64 },
+            avg_hops: per_lookup(self.total_hops),
+            max_hops: self.max_hops,
+            avg_latency_ms: per_lookup(self.total_latency_ms),
+            max_latency_ms: self.max_latency_ms,
+            routing_table_size: table.len(),
+            bucket_occupancy: table.bucket_occupancy(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
@@ -0,0 +1,74 @@
+use ed25519_dalek::VerifyingKey;
+use sha2::{ Digest, Sha256 };
+
+use super::distance::Distance;
+use super::node_id::NodeId;
+use crate::crypto::identity::NodeIdentity;
+
+/// Highest puzzle difficulty accepted in a policy (each bit doubles the cost of generating an id)
+pub const MAX_PUZZLE_DIFFICULTY: u32 = 32;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AdmissionError {
+    #[error("NodeId {0} is not the hash of its identity key")]
+    NodeIdMismatch(NodeId),
+    #[error("NodeId {node_id} solves the puzzle with {got} bits (need {required})")]
+    PuzzleNotSolved {
+        node_id: NodeId,
+        got: u32,
+        required: u32,
+    },
+}
+
+/// S/Kademlia static puzzle: the number of leading zero bits of SHA-256(NodeId).
+/// Since the NodeId is itself the hash of the identity key, meeting a difficulty of `d`
+/// takes about 2^d key generations.
+pub fn puzzle_difficulty(node_id: &NodeId) -> u32 {
+    Distance::from_bytes(Sha256::digest(node_id.as_bytes()).into()).leading_zeros()
+}
+
+/// Generates identities until one solves the puzzle at `difficulty`.
+pub fn generate_identity(difficulty: u32) -> NodeIdentity {
+    let difficulty = difficulty.min(MAX_PUZZLE_DIFFICULTY);
+    loop {
+        let identity = NodeIdentity::generate();
+        let node_id = NodeId::from_identity_key(&identity.identity_keypair.verifying_key());
+        if puzzle_difficulty(&node_id) >= difficulty {
+            return identity;
+        }
+    }
+}
+
+/// Which peers the routing table accepts. The puzzle only depends on the NodeId, so it is
+/// checked for every contact; the key binding needs the identity key from a handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionPolicy {
+    /// Required leading zero bits of SHA-256(NodeId); 0 disables the puzzle
+    pub puzzle_difficulty: u32,
+}
+
+impl AdmissionPolicy {
+    pub fn with_puzzle_difficulty(difficulty: u32) -> Self {
+        Self { puzzle_difficulty: difficulty.min(MAX_PUZZLE_DIFFICULTY) }
+    }
+
+    pub fn check_id(&self, node_id: &NodeId) -> Result<(), AdmissionError> {
+        if self.puzzle_difficulty == 0 {
+            return Ok(());
+        }
+
+        let got = puzzle_difficulty(node_id);
+        if got < self.puzzle_difficulty {
+            return Err(AdmissionError::PuzzleNotSolved { node_id: *node_id, got, required: self.puzzle_difficulty });
+        }
+        Ok(())
+    }
+
+    /// Full check for a peer whose identity key is known: the NodeId must be its hash, and solve the puzzle.
+    pub fn check_peer(&self, node_id: &NodeId, identity_key: &VerifyingKey) -> Result<(), AdmissionError> {
+        if NodeId::from_identity_key(identity_key) != *node_id {
+            return Err(AdmissionError::NodeIdMismatch(*node_id));
+        }
+        self.check_id(node_id)
+    }
+}
@@ -0,0 +1,778 @@
+use std::net::SocketAddr;
+
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::cache;
+use crate::dht::contact::{ AddressKind, Contact, ContactInfo, Reachability, TransportAddress };
+use crate::dht::distance::{ self, Distance };
+use crate::dht::lookup::{ Lookup, ALPHA, K };
+use crate::dht::messages::{
+    DhtMessage,
+    DhtMessageError,
+    ErrorCode,
+    ErrorResponse,
+    FetchRequest,
+    FetchResponse,
+    FindNodeRequest,
+    FindNodeResponse,
+    FindProvidersResponse,
+    GetValueRequest,
+    GetValueResponse,
+    ProvideRequest,
+    PutRequest,
+    StoreRequest,
+    StoreResponse,
+};
+use crate::dht::mutable::{ MutableRecord, MutableRecordError, MutableRecordStore };
+use crate::dht::namespace::{ KeyPath, Namespace, NamespacedKey };
+use crate::dht::node_id::NodeId;
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+use crate::dht::peer_store::{ PeerStore, MAX_PEER_ADDRESSES };
+use crate::dht::provider::{ ProviderStore, MAX_PROVIDERS_PER_KEY };
+use crate::dht::quorum::QuorumRead;
+use crate::dht::rate_limit::{ RateDecision, RateLimitConfig, RateLimiter };
+use crate::dht::record::{
+    EvictionPolicy,
+    Record,
+    RecordError,
+    RecordStore,
+    StorageLimits,
+    MAX_RECORD_TTL_SECS,
+    RECORD_OVERHEAD,
+    SWEEP_INTERVAL_SECS,
+};
+use crate::dht::republish::{ self, RepublishAction, RepublishConfig, RepublishScheduler };
+use crate::dht::routing::{ InsertOutcome, RoutingTable, PING_TIMEOUT_SECS, REFRESH_INTERVAL_SECS };
+use crate::dht::sim::{ SimConfig, Simulation };
+use crate::dht::stats::DhtTelemetry;
+use crate::dht::token::{ WriteToken, WriteTokens, TOKEN_ROTATION_SECS };
+use crate::dht::sybil::{ self, AdmissionError, AdmissionPolicy };
+use crate::dht::validate::{ SizeLimit, ValidationError, ValidatorRegistry };
+use crate::protocol::descriptor::{ Capabilities, NodeDescriptor };
+
+fn id_with_first_byte(byte: u8) -> NodeId {
+    let mut bytes = [0u8; 32];
+    bytes[0] = byte;
+    NodeId::from_bytes(bytes)
+}
+
+/// Unit test: Distance orders as a big-endian integer and maps to the leading-zero bucket
+#[test]
+fn test_distance_ordering_and_bucket_index() {
+    let origin = NodeId::from_bytes([0u8; 32]);
+    let near = id_with_first_byte(0x01);
+    let far = id_with_first_byte(0x80);
+
+    assert_eq!(distance::distance(&origin, &origin), Distance::ZERO);
+    assert!(distance::distance(&origin, &near) < distance::distance(&origin, &far));
+    assert_eq!(distance::distance(&near, &far), distance::distance(&far, &near));
+
+    assert_eq!(origin.bucket_index(&far), 0);
+    assert_eq!(origin.bucket_index(&near), 7);
+    assert_eq!(origin.bucket_index(&origin), 255);
+    assert_eq!(origin.common_prefix_len(&origin), 256);
+}
+
+/// Unit test: Candidates sort by closeness to the target, not to each other
+#[test]
+fn test_closest_sorts_candidates_by_target() {
+    let target = id_with_first_byte(0x10);
+    let candidates = [0x80, 0x11, 0x00, 0x30].map(id_with_first_byte);
+
+    let sorted = distance::closest(candidates, &target, 3, |id| id);
+    assert_eq!(sorted, [0x11, 0x00, 0x30].map(id_with_first_byte));
+
+    assert!(distance::is_closer(&candidates[1], &candidates[0], &target));
+}
+
+fn contact_with_first_byte(byte: u8) -> Contact {
+    let addr: SocketAddr = format!("10.0.0.{byte}:4000").parse().unwrap();
+    Contact::new(id_with_first_byte(byte), addr)
+}
+
+/// Integration test: GET_VALUE stops at the first value and reports the closest node without it
+#[test]
+fn test_get_value_lookup_short_circuits_on_value() {
+    let target = id_with_first_byte(0x00);
+    let seeds = [0x40, 0x50, 0x60, 0x70].map(contact_with_first_byte);
+    let mut lookup = Lookup::get_value(target, seeds);
+
+    let first = lookup.next_queries();
+    assert_eq!(first.len(), ALPHA);
+    assert_eq!(first[0].id, id_with_first_byte(0x40));
+    assert!(lookup.next_queries().is_empty(), "Concurrency must stay within ALPHA");
+
+    // 0x40 knows closer peers; 0x50 has nothing better
+    let closer = [0x08, 0x10].map(contact_with_first_byte);
+    lookup.on_nodes(&first[0].id, closer);
+    lookup.on_nodes(&first[1].id, []);
+
+    let second = lookup.next_queries();
+    assert_eq!(second[0].id, id_with_first_byte(0x08));
+
+    lookup.on_nodes(&second[0].id, []);
+    lookup.on_value(&id_with_first_byte(0x60), b"value".to_vec());
+    assert!(lookup.is_finished());
+    assert!(lookup.next_queries().is_empty());
+
+    let result = lookup.into_result();
+    assert_eq!(result.value.as_deref(), Some(&b"value"[..]));
+    assert_eq!(result.cache_candidate.map(|c| c.id), Some(id_with_first_byte(0x08)));
+}
+
+/// Integration test: A lookup without a value converges once the closest live peers answered
+#[test]
+fn test_lookup_converges_without_value() {
+    let target = id_with_first_byte(0x00);
+    let mut lookup = Lookup::get_value(target, [0x20, 0x30].map(contact_with_first_byte));
+
+    let queries = lookup.next_queries();
+    lookup.on_failure(&queries[0].id);
+    lookup.on_nodes(&queries[1].id, [contact_with_first_byte(0x30)]);
+    // Unsolicited answers are ignored
+    lookup.on_value(&id_with_first_byte(0x99), b"spoofed".to_vec());
+
+    assert!(lookup.is_finished());
+    let result = lookup.into_result();
+    assert!(result.value.is_none());
+    assert!(result.cache_candidate.is_none());
+    assert_eq!(result.closest, vec![contact_with_first_byte(0x30)]);
+}
+
+/// Unit test: GetValueResponse roundtrips both variants, with and without a write token, and rejects unknown flags
+#[test]
+fn test_get_value_response_roundtrip() {
+    let value = GetValueResponse::Value(b"record".to_vec());
+    assert_eq!(GetValueResponse::from_bytes(&value.to_bytes()).unwrap(), value);
+
+    let mut nodes = GetValueResponse::CloserNodes {
+        nodes: vec![contact_with_first_byte(1), contact_with_first_byte(2)],
+        token: None,
+    };
+    assert_eq!(GetValueResponse::from_bytes(&nodes.to_bytes()).unwrap(), nodes);
+    if let GetValueResponse::CloserNodes { token, .. } = &mut nodes {
+        *token = Some(WriteToken([7; 16]));
+    }
+    assert_eq!(GetValueResponse::from_bytes(&nodes.to_bytes()).unwrap(), nodes);
+
+    assert!(GetValueResponse::from_bytes(&[2]).is_err());
+}
+
+/// Unit test: GetValue responses as a C# node sends them decode: a bare Found flag when it doesn't hold the record,
+/// its MutableRecord when it does
+#[test]
+fn test_get_value_response_from_csharp() {
+    assert_eq!(GetValueResponse::from_bytes(&[0]).unwrap(), GetValueResponse::CloserNodes { nodes: Vec::new(), token: None });
+
+    let record = MutableRecord::new_signed(&NodeIdentity::generate(), 3, b"profile".to_vec()).unwrap();
+    let mut found = vec![1];
+    found.extend_from_slice(&record.to_bytes());
+    let GetValueResponse::Value(value) = GetValueResponse::from_bytes(&found).unwrap() else {
+        panic!("Expected the value");
+    };
+    assert_eq!(MutableRecord::from_bytes(&value).unwrap(), record);
+}
+
+/// Unit test: Records roundtrip and enforce their limits when decoded
+#[test]
+fn test_record_roundtrip_and_limits() {
+    let record = Record::new(id_with_first_byte(1), b"hello".to_vec(), id_with_first_byte(2), 600, 1_000).unwrap();
+    assert_eq!(Record::from_bytes(&record.to_bytes()).unwrap(), record);
+    assert_eq!(record.expires_at(), 1_600);
+
+    let mut too_long = record.to_bytes();
+    too_long[72..76].copy_from_slice(&(MAX_RECORD_TTL_SECS + 1).to_be_bytes());
+    assert!(matches!(Record::from_bytes(&too_long), Err(RecordError::TtlTooLong(_))));
+
+    let mut truncated = record.to_bytes();
+    truncated.pop();
+    assert!(matches!(Record::from_bytes(&truncated), Err(RecordError::Malformed(_))));
+}
+
+/// Integration test: Expired records are hidden immediately and evicted by the sweeper
+#[test]
+fn test_record_store_expiry_and_sweep() {
+    let mut store = RecordStore::new();
+    let short = Record::new(id_with_first_byte(1), vec![1], id_with_first_byte(9), 10, 1_000).unwrap();
+    let long = Record::new(id_with_first_byte(2), vec![2], id_with_first_byte(9), 600, 1_000).unwrap();
+
+    assert!(matches!(store.put(short.clone(), 1_010), Err(RecordError::Expired { .. })));
+    store.put(short.clone(), 1_000).unwrap();
+    store.put(long, 1_000).unwrap();
+    assert_eq!(store.next_expiry(), Some(1_010));
+
+    // Older copies never replace newer ones
+    let mut older = short.clone();
+    older.created_at = 995;
+    store.put(older, 1_000).unwrap();
+    assert_eq!(store.get(&short.key, 1_000).unwrap().created_at, 1_000);
+
+    assert!(store.get(&short.key, 1_010).is_none());
+    assert_eq!(store.len(), 2);
+
+    assert_eq!(store.maybe_sweep(1_010), 1);
+    assert_eq!(store.maybe_sweep(1_010 + SWEEP_INTERVAL_SECS - 1), 0);
+    assert_eq!(store.len(), 1);
+}
+
+/// Integration test: Own records are re-stamped before their TTL lapses; held records are replicated unchanged
+#[test]
+fn test_republish_scheduler_due_actions() {
+    let config = RepublishConfig { republish_interval_secs: 3_600, replicate_interval_secs: 600 };
+    let mut scheduler = RepublishScheduler::new(config);
+
+    // TTL shorter than the interval: republished at 3/4 of the TTL
+    let own = Record::new(id_with_first_byte(1), vec![1], id_with_first_byte(9), 400, 1_000).unwrap();
+    scheduler.publish(own.clone());
+    assert_eq!(scheduler.next_deadline(), Some(1_300));
+
+    let mut store = RecordStore::new();
+    let held = Record::new(id_with_first_byte(2), vec![2], id_with_first_byte(7), 3_600, 1_000).unwrap();
+    store.put(held.clone(), 1_000).unwrap();
+
+    assert!(scheduler.due(&store, 1_000).is_empty(), "Nothing is due right after publishing");
+
+    let actions = scheduler.due(&store, 1_300);
+    assert_eq!(actions.len(), 1);
+    let RepublishAction::Republish(republished) = &actions[0] else {
+        panic!("Expected a republish");
+    };
+    assert_eq!(republished.created_at, 1_300);
+    assert_eq!(republished.value, own.value);
+    assert!(scheduler.unpublish(&own.key).is_some());
+
+    let actions = scheduler.due(&store, 1_600);
+    assert_eq!(actions, vec![RepublishAction::Replicate(held)]);
+    assert_eq!(scheduler.next_deadline(), Some(2_200));
+}
+
+/// Integration test: Mutable records keep the highest valid sequence and reject forgeries and conflicts
+#[test]
+fn test_mutable_record_sequence_resolution() {
+    let owner = NodeIdentity::generate();
+    let v1 = MutableRecord::new_signed(&owner, 1, b"descriptor-v1".to_vec()).unwrap();
+    let v2 = MutableRecord::new_signed(&owner, 2, b"descriptor-v2".to_vec()).unwrap();
+
+    let parsed = MutableRecord::from_bytes(&v1.to_bytes()).unwrap();
+    assert_eq!(parsed, v1);
+    parsed.verify().unwrap();
+
+    let mut store = MutableRecordStore::new();
+    assert!(store.put(v2.clone()).unwrap());
+    assert!(!store.put(v2.clone()).unwrap());
+    assert!(matches!(store.put(v1), Err(MutableRecordError::StaleSequence { got: 1, current: 2 })));
+
+    let fork = MutableRecord::new_signed(&owner, 2, b"fork".to_vec()).unwrap();
+    assert!(matches!(store.put(fork), Err(MutableRecordError::Conflict(2))));
+
+    let mut forged = v2.clone();
+    forged.seq = 3;
+    assert!(matches!(store.put(forged), Err(MutableRecordError::VerificationFailed)));
+
+    assert_eq!(store.get(&v2.key()).unwrap().value, b"descriptor-v2");
+}
+
+/// Integration test: Provider announcements are stored per key, refreshed, capped and swept
+#[test]
+fn test_provider_store_announcements() {
+    let content = id_with_first_byte(0xaa);
+    let announce = ProvideRequest { key: content, ttl: 100, provider: contact_with_first_byte(1) };
+    let announce = ProvideRequest::from_bytes(&announce.to_bytes()).unwrap();
+
+    let mut store = ProviderStore::new();
+    store.add(announce.key, announce.provider, announce.ttl, 1_000);
+    store.add(content, contact_with_first_byte(2), 50, 1_000);
+    // Re-announcing refreshes instead of duplicating
+    store.add(content, contact_with_first_byte(2), 500, 1_000);
+    assert_eq!(store.get(&content, 1_000), vec![contact_with_first_byte(2), contact_with_first_byte(1)]);
+
+    for byte in 10..10 + MAX_PROVIDERS_PER_KEY as u8 {
+        store.add(content, contact_with_first_byte(byte), 300, 1_000);
+    }
+    assert_eq!(store.get(&content, 1_000).len(), MAX_PROVIDERS_PER_KEY);
+    assert!(!store.get(&content, 1_000).contains(&contact_with_first_byte(1)), "The soonest expiry is evicted");
+
+    assert_eq!(store.sweep(1_300), MAX_PROVIDERS_PER_KEY - 1);
+    assert_eq!(store.get(&content, 1_300), vec![contact_with_first_byte(2)]);
+
+    let response = FindProvidersResponse { providers: store.get(&content, 1_300), closer: vec![contact_with_first_byte(3)] };
+    assert_eq!(FindProvidersResponse::from_bytes(&response.to_bytes()).unwrap(), response);
+}
+
+/// Unit test: Random refresh targets land in the requested bucket
+#[test]
+fn test_random_in_bucket() {
+    let local = NodeId::random();
+    for index in [0, 1, 7, 8, 100, 255] {
+        assert_eq!(local.bucket_index(&local.random_in_bucket(index)), index);
+    }
+}
+
+/// Integration test: Full buckets drop newcomers; idle buckets up to the deepest occupied one get refresh targets
+#[test]
+fn test_routing_table_insert_and_refresh() {
+    let local = id_with_first_byte(0x00);
+    let mut table = RoutingTable::new(local, 1_000);
+    assert!(table.refresh_targets(1_000 + REFRESH_INTERVAL_SECS, REFRESH_INTERVAL_SECS).is_empty());
+
+    assert_eq!(table.insert(Contact::new(local, "10.0.0.1:1".parse().unwrap()), 1_000), InsertOutcome::Ignored);
+    // Bucket 0 holds every id whose first bit differs from ours
+    for byte in 0x80..0x80 + K as u8 {
+        assert_eq!(table.insert(contact_with_first_byte(byte), 1_000), InsertOutcome::Inserted);
+    }
+    assert_eq!(table.insert(contact_with_first_byte(0xf0), 1_000), InsertOutcome::PingRequired(contact_with_first_byte(0x80)));
+    assert_eq!(table.insert(contact_with_first_byte(0x80), 1_010), InsertOutcome::Updated);
+    assert_eq!(table.bucket(0).last().unwrap().contact.id, id_with_first_byte(0x80));
+
+    table.insert(contact_with_first_byte(0x01), 1_000); // Bucket 7
+    assert_eq!(table.closest(&id_with_first_byte(0x02), 1), vec![contact_with_first_byte(0x01)]);
+
+    // Bucket 0 saw activity at 1_010, so it is not stale yet
+    let targets = table.refresh_targets(1_000 + REFRESH_INTERVAL_SECS, REFRESH_INTERVAL_SECS);
+    assert_eq!(targets.len(), 7);
+    assert!(targets.iter().all(|t| (1..=7).contains(&local.bucket_index(t))));
+    assert!(table.refresh_targets(1_000 + REFRESH_INTERVAL_SECS, REFRESH_INTERVAL_SECS).is_empty());
+}
+
+/// Integration test: The routing table enforces the id/key binding and the static puzzle
+#[test]
+fn test_admission_policy_puzzle_and_binding() {
+    let policy = AdmissionPolicy::with_puzzle_difficulty(4);
+    let mut table = RoutingTable::new(NodeId::random(), 1_000).with_policy(policy);
+    let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+    let solved = sybil::generate_identity(4);
+    let solved_key = solved.identity_keypair.verifying_key();
+    let solved_id = NodeId::from_identity_key(&solved_key);
+    assert!(sybil::puzzle_difficulty(&solved_id) >= 4);
+    assert_eq!(table.insert_verified(Contact::new(solved_id, addr), &solved_key, 1_000), Ok(InsertOutcome::Inserted));
+
+    // Claiming someone else's id
+    let other = NodeIdentity::generate().identity_keypair.verifying_key();
+    assert_eq!(
+        table.insert_verified(Contact::new(solved_id, addr), &other, 1_000),
+        Err(AdmissionError::NodeIdMismatch(solved_id))
+    );
+
+    let unsolved = (0..).map(|_| NodeId::random()).find(|id| sybil::puzzle_difficulty(id) < 4).unwrap();
+    assert_eq!(table.insert(Contact::new(unsolved, addr), 1_000), InsertOutcome::Rejected);
+    assert_eq!(table.len(), 1);
+}
+
+/// Integration test: The peer store survives a serialization roundtrip and ranks bootstrap candidates
+#[test]
+fn test_peer_store_roundtrip_and_bootstrap_order() {
+    let mut store = PeerStore::new();
+    let (a, b, c) = (id_with_first_byte(1), id_with_first_byte(2), id_with_first_byte(3));
+
+    store.record_seen(a, "10.0.0.1:4000".parse().unwrap(), 1_000);
+    store.record_seen(b, "10.0.0.2:4000".parse().unwrap(), 2_000);
+    store.record_seen(c, "[::1]:4000".parse().unwrap(), 500);
+    store.adjust_reputation(&c, 5);
+    for port in 0..MAX_PEER_ADDRESSES as u16 + 2 {
+        store.record_seen(a, SocketAddr::from(([10, 0, 1, 1], 5000 + port)), 1_500);
+    }
+    assert_eq!(store.get(&a).unwrap().addresses.len(), MAX_PEER_ADDRESSES);
+
+    let restored = PeerStore::from_bytes(&store.to_bytes()).unwrap();
+    assert_eq!(restored.get(&a), store.get(&a));
+    assert_eq!(restored.get(&c).unwrap().reputation, 5);
+
+    let order: Vec<_> = restored.bootstrap_contacts(3).into_iter().map(|c| c.id).collect();
+    assert_eq!(order, vec![c, b, a]);
+
+    let mut bad_magic = store.to_bytes();
+    bad_magic[0] = b'X';
+    assert!(PeerStore::from_bytes(&bad_magic).is_err());
+
+    let mut pruned = restored;
+    assert_eq!(pruned.prune(2_100, 500), 2);
+    assert!(pruned.get(&b).is_some());
+}
+
+/// Integration test: Every DHT payload survives a trip through a NetworkPacket under its MessageType
+#[test]
+fn test_dht_messages_roundtrip_through_packets() {
+    let owner = NodeIdentity::generate();
+    let record = Record::new(id_with_first_byte(1), b"v".to_vec(), id_with_first_byte(2), 60, 1_000).unwrap();
+    let messages = vec![
+        DhtMessage::FindNode(FindNodeRequest { target: id_with_first_byte(7) }),
+        DhtMessage::FindNodeRes(FindNodeResponse {
+            contacts: vec![contact_with_first_byte(1), contact_with_first_byte(2)],
+            infos: Vec::new(),
+            token: None,
+        }),
+        DhtMessage::Store(StoreRequest {
+            token: Some(WriteToken([3u8; 16])),
+            path: Some(KeyPath::from(&NamespacedKey::app(b"k".to_vec()))),
+            record: record.clone(),
+        }),
+        DhtMessage::StoreRes(StoreResponse { key: record.key }),
+        DhtMessage::Fetch(FetchRequest { hash: [9u8; 32] }),
+        DhtMessage::FetchRes(FetchResponse { data: b"blob".to_vec() }),
+        DhtMessage::Put(PutRequest { record: MutableRecord::new_signed(&owner, 1, b"m".to_vec()).unwrap() }),
+        DhtMessage::GetValueReq(GetValueRequest { key: record.key }),
+        DhtMessage::GetValueRes(GetValueResponse::Value(record.to_bytes())),
+    ];
+
+    for message in messages {
+        let bytes = message.to_packet(42).to_bytes();
+        let packet = NetworkPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(packet.header.message_type, message.message_type());
+        assert_eq!(DhtMessage::from_packet(&packet).unwrap(), message);
+    }
+
+    assert!(matches!(DhtMessage::decode(MessageType::Handshake, &[]), Err(DhtMessageError::NotDht(_))));
+    assert!(matches!(DhtMessage::decode(MessageType::DhtFindNode, &[0u8; 31]), Err(DhtMessageError::Malformed(_))));
+}
+
+/// Integration test: A full bucket only evicts its oldest contact when the liveness ping fails
+#[test]
+fn test_full_bucket_pings_before_eviction() {
+    let mut table = RoutingTable::new(id_with_first_byte(0x00), 1_000);
+    for byte in 0x80..0x80 + K as u8 {
+        table.insert(contact_with_first_byte(byte), 1_000);
+    }
+    let oldest = contact_with_first_byte(0x80);
+
+    // Oldest answers: the newcomer is discarded
+    assert_eq!(table.insert(contact_with_first_byte(0xf0), 1_001), InsertOutcome::PingRequired(oldest));
+    assert_eq!(table.insert(contact_with_first_byte(0xf1), 1_001), InsertOutcome::BucketFull);
+    table.ping_succeeded(&oldest.id, 1_002);
+    assert!(table.get(&id_with_first_byte(0xf0)).is_none());
+    assert_eq!(table.bucket(0).last().unwrap().contact, oldest);
+
+    // The next oldest never answers: evicted once the timeout passes
+    let next_oldest = contact_with_first_byte(0x81);
+    assert_eq!(table.insert(contact_with_first_byte(0xf2), 1_010), InsertOutcome::PingRequired(next_oldest));
+    assert_eq!(table.expire_pings(1_010 + PING_TIMEOUT_SECS - 1), 0);
+    assert_eq!(table.expire_pings(1_010 + PING_TIMEOUT_SECS), 1);
+    assert!(table.get(&next_oldest.id).is_none());
+    assert!(table.get(&id_with_first_byte(0xf2)).is_some());
+
+    // An explicit failure evicts right away
+    let third = contact_with_first_byte(0x82);
+    assert_eq!(table.insert(contact_with_first_byte(0xf3), 1_020), InsertOutcome::PingRequired(third));
+    table.ping_failed(&third.id, 1_021);
+    assert!(table.get(&id_with_first_byte(0xf3)).is_some());
+    assert_eq!(table.len(), K);
+}
+
+/// Integration test: Storage limits reject oversized values and greedy publishers, and evict by policy when full
+#[test]
+fn test_record_store_quotas_and_eviction() {
+    let entry = RECORD_OVERHEAD + 10;
+    let limits = StorageLimits {
+        max_total_bytes: entry * 3,
+        max_bytes_per_publisher: entry * 2,
+        max_value_len: 10,
+        eviction: EvictionPolicy::LeastRequested,
+    };
+    let mut store = RecordStore::with_limits(limits);
+    let record = |key: u8, publisher: u8, ttl: u32| {
+        Record::new(id_with_first_byte(key), vec![key; 10], id_with_first_byte(publisher), ttl, 1_000).unwrap()
+    };
+
+    let oversized = Record::new(id_with_first_byte(9), vec![0; 11], id_with_first_byte(1), 60, 1_000).unwrap();
+    assert!(matches!(store.put(oversized, 1_000), Err(RecordError::ValueTooLarge { max: 10, .. })));
+
+    store.put(record(1, 0xa0, 100), 1_000).unwrap();
+    store.put(record(2, 0xa0, 200), 1_000).unwrap();
+    assert!(matches!(store.put(record(3, 0xa0, 300), 1_000), Err(RecordError::QuotaExceeded(_))));
+    // Replacing one of its own records does not count twice
+    store.put(record(2, 0xa0, 250), 1_000).unwrap();
+
+    store.put(record(3, 0xb0, 300), 1_000).unwrap();
+    assert_eq!(store.total_bytes(), entry * 3);
+
+    // Full: the least requested record (key 3) makes room, even though key 1 expires sooner
+    store.fetch(&id_with_first_byte(1), 1_000);
+    store.fetch(&id_with_first_byte(2), 1_000);
+    store.put(record(4, 0xc0, 400), 1_000).unwrap();
+    assert!(store.get(&id_with_first_byte(3), 1_000).is_none());
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.total_bytes(), entry * 3);
+}
+
+/// Integration test: Floods are throttled with an overload hint, then banned; other peers are unaffected
+#[test]
+fn test_rate_limiter_throttles_then_bans() {
+    let config = RateLimitConfig { queries_per_sec: 1.0, burst: 2, max_violations: 2, ban_secs: 60 };
+    let mut limiter = RateLimiter::new(config);
+    let flooder = id_with_first_byte(1);
+    let ip = "10.0.0.1".parse().unwrap();
+
+    assert_eq!(limiter.check(Some(&flooder), ip, 1_000), RateDecision::Allow);
+    assert_eq!(limiter.check(Some(&flooder), ip, 1_000), RateDecision::Allow);
+    let RateDecision::Throttle { retry_after_secs } = limiter.check(Some(&flooder), ip, 1_000) else {
+        panic!("Expected the third query to be throttled");
+    };
+    let error = ErrorResponse::overloaded(retry_after_secs);
+    assert_eq!(ErrorResponse::from_bytes(&error.to_bytes()).unwrap().code, ErrorCode::Overloaded);
+
+    // Refilled after a second
+    assert_eq!(limiter.check(Some(&flooder), ip, 1_001), RateDecision::Allow);
+
+    assert!(matches!(limiter.check(Some(&flooder), ip, 1_001), RateDecision::Throttle { .. }));
+    assert_eq!(limiter.check(Some(&flooder), ip, 1_001), RateDecision::Banned { until: 1_061 });
+    // Changing NodeId does not escape the per-IP ban
+    assert!(matches!(limiter.check(Some(&id_with_first_byte(2)), ip, 1_030), RateDecision::Banned { .. }));
+
+    assert_eq!(limiter.check(Some(&id_with_first_byte(3)), "10.0.0.2".parse().unwrap(), 1_030), RateDecision::Allow);
+
+    limiter.prune(1_100);
+    assert_eq!(limiter.tracked(), 0);
+}
+
+/// Integration test: A quorum read returns the highest valid sequence and repairs stale holders
+#[test]
+fn test_quorum_read_with_read_repair() {
+    let owner = NodeIdentity::generate();
+    let v1 = MutableRecord::new_signed(&owner, 1, b"old".to_vec()).unwrap();
+    let v2 = MutableRecord::new_signed(&owner, 2, b"new".to_vec()).unwrap();
+    let holders: Vec<_> = (1..=5).map(contact_with_first_byte).collect();
+    let mut read = QuorumRead::new(v2.key(), holders.clone(), 3);
+
+    read.on_record(&holders[0].id, v1.clone());
+    read.on_record(&holders[1].id, v2.clone());
+    read.on_missing(&holders[2].id);
+    assert!(read.has_quorum());
+    assert!(!read.is_finished());
+
+    let mut forged = v1.clone();
+    forged.seq = 9;
+    read.on_record(&holders[3].id, forged);
+    read.on_failure(&holders[4].id);
+    // Late duplicate answers are ignored
+    read.on_missing(&holders[1].id);
+    assert!(read.is_finished());
+
+    let result = read.finish();
+    assert!(result.reached_quorum);
+    assert_eq!(result.record.as_ref().unwrap().seq, 2);
+    let repaired: Vec<_> = result.repairs.iter().map(|(c, _)| c.id).collect();
+    assert_eq!(repaired, vec![holders[0].id, holders[2].id]);
+    assert!(result.repairs.iter().all(|(_, put)| put.record == v2));
+}
+
+/// Integration test: NATed nodes are dialed through relays first, and legacy decoders still see one address per node
+#[test]
+fn test_contact_info_dial_order_and_legacy_response() {
+    let public: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+    let relay: SocketAddr = "198.51.100.7:4000".parse().unwrap();
+    let observed: SocketAddr = "[2001:db8::1]:53000".parse().unwrap();
+    let addresses = vec![
+        TransportAddress { addr: public, kind: AddressKind::Public },
+        TransportAddress { addr: observed, kind: AddressKind::Observed },
+        TransportAddress { addr: relay, kind: AddressKind::Relay },
+    ];
+
+    let natted = ContactInfo::new(id_with_first_byte(1), Reachability::BehindNat, addresses.clone());
+    let direct = ContactInfo::new(id_with_first_byte(2), Reachability::Direct, addresses);
+    assert_eq!(natted.dial_order(), vec![relay, observed, public]);
+    assert_eq!(direct.contact(), Some(Contact::new(direct.id, public)));
+
+    let response = FindNodeResponse::from_infos(vec![natted.clone(), direct]);
+    let bytes = response.to_bytes();
+    assert_eq!(FindNodeResponse::from_bytes(&bytes).unwrap(), response);
+
+    // What a peer without the extension reads: the leading contact list
+    let legacy_only = FindNodeResponse { contacts: response.contacts.clone(), ..Default::default() }.to_bytes();
+    assert!(bytes.starts_with(&legacy_only));
+    let legacy = FindNodeResponse::from_bytes(&legacy_only).unwrap();
+    assert_eq!(legacy.contacts, vec![Contact::new(natted.id, relay), Contact::new(id_with_first_byte(2), public)]);
+    assert!(legacy.infos.is_empty());
+}
+
+/// Integration test: Lookup counters (hops, failures) feed the telemetry snapshot alongside bucket occupancy
+#[test]
+fn test_lookup_stats_and_telemetry_snapshot() {
+    let target = id_with_first_byte(0x00);
+    let mut lookup = Lookup::find_node(target, [0x40, 0x50].map(contact_with_first_byte));
+
+    let first = lookup.next_queries();
+    lookup.on_nodes(&first[0].id, [contact_with_first_byte(0x10)]);
+    lookup.on_failure(&first[1].id);
+    let second = lookup.next_queries();
+    lookup.on_nodes(&second[0].id, [contact_with_first_byte(0x08)]);
+    let third = lookup.next_queries();
+    lookup.on_nodes(&third[0].id, []);
+    assert!(lookup.is_finished());
+
+    let stats = lookup.into_result().stats;
+    assert_eq!((stats.queries, stats.responses, stats.failures, stats.hops), (4, 3, 1, 3));
+
+    let mut table = RoutingTable::new(target, 1_000);
+    table.insert(contact_with_first_byte(0x80), 1_000);
+    table.insert(contact_with_first_byte(0x01), 1_000);
+
+    let mut telemetry = DhtTelemetry::new();
+    telemetry.record_lookup(&stats, 120);
+    telemetry.record_lookup(&Default::default(), 40);
+    let snapshot = telemetry.snapshot(&table);
+    assert_eq!(snapshot.lookups, 2);
+    assert_eq!(snapshot.timeout_rate, 0.25);
+    assert_eq!(snapshot.avg_hops, 1.5);
+    assert_eq!(snapshot.avg_latency_ms, 80.0);
+    assert_eq!(snapshot.max_latency_ms, 120);
+    assert_eq!(snapshot.routing_table_size, 2);
+    assert_eq!((snapshot.bucket_occupancy[0], snapshot.bucket_occupancy[7]), (1, 1));
+}
+
+/// Integration test: A found record is cached one hop out with a TTL halved per node between cache and key
+#[test]
+fn test_path_cache_store_shortens_ttl() {
+    let key = id_with_first_byte(0x00);
+    let record = Record::new(key, b"popular".to_vec(), id_with_first_byte(9), 4_000, 1_000).unwrap();
+    let mut lookup = Lookup::get_value(key, [0x08, 0x10, 0x20].map(contact_with_first_byte));
+
+    let queries = lookup.next_queries();
+    lookup.on_nodes(&queries[0].id, []);
+    lookup.on_nodes(&queries[1].id, []);
+    lookup.on_value(&queries[2].id, record.to_bytes());
+    let result = lookup.into_result();
+
+    let (target, store) = cache::path_cache_store(&result, 2_000).unwrap();
+    assert_eq!(target.id, id_with_first_byte(0x08));
+    assert_eq!(store.record.ttl, 3_000);
+    assert_eq!(store.record.expires_at(), record.expires_at());
+
+    // One node closer to the key than the cache halves the remaining lifetime
+    assert_eq!(cache::cache_ttl(&record, 1, 2_000), 1_500);
+    // Nearly expired values are not worth caching
+    assert!(cache::path_cache_store(&result, 4_990).is_none());
+}
+
+/// Unit test: The same raw key lands in disjoint places per namespace, and the name is length-prefixed
+#[test]
+fn test_namespaced_keys_are_disjoint() {
+    let node = id_with_first_byte(7);
+    let descriptor = NamespacedKey::descriptor(&node);
+    let app = NamespacedKey::app(node.as_bytes().to_vec());
+
+    assert_eq!(descriptor.namespace(), Namespace::DESCRIPTOR);
+    assert_ne!(descriptor.node_id(), app.node_id());
+    assert_ne!(descriptor.node_id(), NodeId::hash_of(node.as_bytes()));
+    assert_eq!(NodeId::from(&descriptor), NamespacedKey::descriptor(&node).node_id());
+
+    const AB: Namespace = Namespace::new("ab");
+    const A: Namespace = Namespace::new("a");
+    assert_ne!(NamespacedKey::new(AB, b"c".to_vec()).node_id(), NamespacedKey::new(A, b"bc".to_vec()).node_id());
+}
+
+/// Integration test: Write tokens bind NodeId and IP, survive one rotation, and travel in FIND_NODE responses
+#[test]
+fn test_write_tokens() {
+    let mut tokens = WriteTokens::new(1_000);
+    let peer = id_with_first_byte(1);
+    let ip = "10.0.0.1".parse().unwrap();
+
+    let token = tokens.issue(ip, &peer, 1_000);
+    assert!(tokens.verify(&token, ip, &peer, 1_000));
+    assert!(!tokens.verify(&token, "10.0.0.2".parse().unwrap(), &peer, 1_000), "Spoofed source address");
+    assert!(!tokens.verify(&token, ip, &id_with_first_byte(2), 1_000));
+
+    assert!(tokens.verify(&token, ip, &peer, 1_000 + TOKEN_ROTATION_SECS));
+    assert!(!tokens.verify(&token, ip, &peer, 1_000 + 2 * TOKEN_ROTATION_SECS));
+
+    let response = FindNodeResponse { contacts: vec![contact_with_first_byte(5)], infos: Vec::new(), token: Some(token) };
+    assert_eq!(FindNodeResponse::from_bytes(&response.to_bytes()).unwrap(), response);
+}
+
+/// Integration test: A newcomer closer to a stored key receives that record; far keys are not pushed
+#[test]
+fn test_replicate_to_new_peer() {
+    let local = id_with_first_byte(0x40);
+    let mut table = RoutingTable::new(local, 1_000);
+    for byte in 0x80..0x80 + K as u8 {
+        table.insert(contact_with_first_byte(byte), 1_000);
+    }
+
+    let mut store = RecordStore::new();
+    let near_key = Record::new(id_with_first_byte(0x01), vec![1], id_with_first_byte(9), 600, 1_000).unwrap();
+    let far_key = Record::new(id_with_first_byte(0xc0), vec![2], id_with_first_byte(9), 600, 1_000).unwrap();
+    store.put(near_key.clone(), 1_000).unwrap();
+    store.put(far_key, 1_000).unwrap();
+
+    // 0xc0 sits among K peers that are all closer to it than we are: not our job
+    let newcomer = contact_with_first_byte(0x02);
+    assert_eq!(republish::replicate_to_new_peer(&table, &store, &newcomer, 1_000), vec![near_key]);
+
+    table.insert(newcomer, 1_000);
+    assert!(republish::replicate_to_new_peer(&table, &store, &newcomer, 1_000).is_empty(), "Known peers were already served");
+}
+
+/// Unit test: Descriptor STOREs must carry a signed descriptor of the keyed node; unknown namespaces, and by default
+/// STOREs outside any namespace, are refused
+#[test]
+fn test_validator_registry() {
+    let identity = NodeIdentity::generate();
+    let descriptor = NodeDescriptor::new_signed(&identity, vec![], Capabilities::RELAY, 0, 1_000, 3_600).unwrap();
+    let store_of = |key: &NamespacedKey, value: Vec<u8>| StoreRequest {
+        token: None,
+        path: Some(KeyPath::from(key)),
+        record: Record::new(key.node_id(), value, id_with_first_byte(9), 600, 1_000).unwrap(),
+    };
+
+    let mut registry = ValidatorRegistry::default();
+    let key = NamespacedKey::descriptor(&descriptor.node_id);
+    assert_eq!(registry.validate_store(&store_of(&key, descriptor.to_bytes()), 1_000), Ok(()));
+    assert!(matches!(registry.validate_store(&store_of(&key, descriptor.to_bytes()), 5_000), Err(ValidationError::Invalid(_))));
+    assert!(matches!(registry.validate_store(&store_of(&key, vec![1, 2, 3]), 1_000), Err(ValidationError::Invalid(_))));
+
+    let other = NamespacedKey::descriptor(&id_with_first_byte(1));
+    assert_eq!(registry.validate_store(&store_of(&other, descriptor.to_bytes()), 1_000), Err(ValidationError::KeyMismatch));
+
+    let mut forged = store_of(&key, descriptor.to_bytes());
+    forged.record.key = id_with_first_byte(2);
+    assert_eq!(registry.validate_store(&forged, 1_000), Err(ValidationError::KeyMismatch));
+
+    // Without its key path, a STORE at the descriptor's key would skip the descriptor validator
+    let mut bypass = store_of(&key, vec![1, 2, 3]);
+    bypass.path = None;
+    assert_eq!(registry.validate_store(&bypass, 1_000), Err(ValidationError::MissingNamespace));
+
+    let chat = Namespace::new("chat");
+    let mut request = store_of(&NamespacedKey::new(chat, b"room".to_vec()), vec![0u8; 100]);
+    assert_eq!(registry.validate_store(&request, 1_000), Err(ValidationError::UnknownNamespace("chat".into())));
+    registry.register(chat, SizeLimit(64));
+    assert_eq!(registry.validate_store(&request, 1_000), Err(ValidationError::TooLarge { len: 100, max: 64 }));
+
+    request.path = None;
+    assert_eq!(registry.validate_store(&request, 1_000), Err(ValidationError::MissingNamespace));
+    registry.allow_unnamespaced = true;
+    assert_eq!(registry.validate_store(&request, 1_000), Ok(()));
+
+    let record = MutableRecord::new_signed(&identity, 1, descriptor.to_bytes()).unwrap();
+    assert_eq!(registry.validate_put(Namespace::DESCRIPTOR, &PutRequest { record }, 1_000), Ok(()));
+}
+
+/// Integration test: In a 200-node simulated network, lookups converge on the true closest nodes and
+/// stored records stay reachable through packet loss and churn
+#[test]
+fn test_simulated_network() {
+    let mut sim = Simulation::new(200, SimConfig { seed: 7, ..Default::default() });
+    sim.bootstrap();
+
+    for (origin, byte) in [(5, 0x00), (77, 0x5a), (199, 0xff)] {
+        let target = id_with_first_byte(byte);
+        let found: Vec<NodeId> = sim.find_node(origin, target).closest.iter().map(|c| c.id).collect();
+        let expected = sim.closest_online(&target, K);
+        assert_eq!(found[0], expected[0], "Lookup from {origin} missed the closest node");
+        let overlap = found.iter().filter(|id| expected.contains(id)).count();
+        assert!(overlap >= K - 2, "Lookup from {origin} found only {overlap} of the {K} closest");
+    }
+
+    let key = NodeId::hash_of(b"sim-record");
+    let record = Record::new(key, b"hello".to_vec(), sim.node(3).contact.id, 3_600, sim.now()).unwrap();
+    assert!(sim.store(3, record) >= K - 2);
+    assert_eq!(sim.get_value(150, key).value.as_deref(), Some(&b"hello"[..]));
+
+    // A fifth of the network leaves, newcomers join, and one packet in twenty is lost
+    sim.churn(40, 20);
+    sim.set_loss(0.05);
+    let newcomer = *sim.online().last().unwrap();
+    let result = sim.get_value(newcomer, key);
+    assert_eq!(result.value.as_deref(), Some(&b"hello"[..]), "Record lost after churn");
+    assert!(sim.now_ms() > 0);
+}
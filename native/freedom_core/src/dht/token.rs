@@ -0,0 +1,78 @@
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{ Digest, Sha256 };
+use std::net::IpAddr;
+use subtle::ConstantTimeEq;
+
+use super::node_id::NodeId;
+
+pub const WRITE_TOKEN_SIZE: usize = 16;
+/// Secrets rotate this often; a token stays valid for up to two rotations
+pub const TOKEN_ROTATION_SECS: u64 = 5 * 60;
+
+/// Opaque proof that a peer recently received a response at its claimed address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WriteToken(pub [u8; WRITE_TOKEN_SIZE]);
+
+/// Issues and checks write tokens (BitTorrent DHT style): a STORE is only accepted with a
+/// token we handed to the same NodeId at the same IP in a FIND_NODE or GET_VALUE response,
+/// so stores from spoofed source addresses are refused. No per-peer state is kept.
+#[derive(Debug)]
+pub struct WriteTokens {
+    current: [u8; 32],
+    previous: [u8; 32],
+    rotated_at: u64,
+}
+
+impl WriteTokens {
+    pub fn new(now: u64) -> Self {
+        Self { current: random_secret(), previous: random_secret(), rotated_at: now }
+    }
+
+    pub fn issue(&mut self, ip: IpAddr, node_id: &NodeId, now: u64) -> WriteToken {
+        self.rotate(now);
+        token_for(&self.current, ip, node_id)
+    }
+
+    /// Accepts tokens issued under the current or the previous secret. Compared in constant
+    /// time, so timing doesn't tell a forger how much of a guess was right.
+    pub fn verify(&mut self, token: &WriteToken, ip: IpAddr, node_id: &NodeId, now: u64) -> bool {
+        self.rotate(now);
+        let current = token.0.ct_eq(&token_for(&self.current, ip, node_id).0);
+        let previous = token.0.ct_eq(&token_for(&self.previous, ip, node_id).0);
+        (current | previous).into()
+    }
+
+    fn rotate(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.rotated_at);
+        if elapsed < TOKEN_ROTATION_SECS {
+            return;
+        }
+
+        // Idle for two periods or more: both secrets are stale
+        self.previous = if elapsed < 2 * TOKEN_ROTATION_SECS { self.current } else { random_secret() };
+        self.current = random_secret();
+        self.rotated_at = now;
+    }
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+fn token_for(secret: &[u8; 32], ip: IpAddr, node_id: &NodeId) -> WriteToken {
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(v4) => hasher.update(v4.octets()),
+        IpAddr::V6(v6) => hasher.update(v6.octets()),
+    }
+    hasher.update(node_id.as_bytes());
+
+    let digest = hasher.finalize();
+    let mut token = [0u8; WRITE_TOKEN_SIZE];
+    token.copy_from_slice(&digest[..WRITE_TOKEN_SIZE]);
+    WriteToken(token)
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use super::messages::{ PutRequest, StoreRequest };
+use super::namespace::{ KeyPath, Namespace, NamespacedKey };
+use super::node_id::NodeId;
+use crate::onion::hs_descriptor::{ HsDescriptor, MAX_HS_DESCRIPTOR_LEN };
+use crate::protocol::descriptor::NodeDescriptor;
+
+/// Largest encoded descriptor accepted (16 addresses fit comfortably)
+pub const MAX_DESCRIPTOR_LEN: usize = 1024;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Unknown namespace {0:?}")]
+    UnknownNamespace(String),
+    #[error("Records outside a namespace are not accepted")]
+    MissingNamespace,
+    #[error("Record key does not match its namespaced key")]
+    KeyMismatch,
+    #[error("Value too large: {len} bytes (max {max})")]
+    TooLarge {
+        len: usize,
+        max: usize,
+    },
+    #[error("Invalid value: {0}")]
+    Invalid(String),
+}
+
+/// Rules a namespace imposes on the values stored under it.
+pub trait RecordValidator: Send + Sync {
+    fn validate(&self, key: &NamespacedKey, value: &[u8], now: u64) -> Result<(), ValidationError>;
+}
+
+/// Accepts any value up to a size.
+#[derive(Debug, Clone, Copy)]
+pub struct SizeLimit(pub usize);
+
+impl RecordValidator for SizeLimit {
+    fn validate(&self, _key: &NamespacedKey, value: &[u8], _now: u64) -> Result<(), ValidationError> {
+        if value.len() > self.0 {
+            return Err(ValidationError::TooLarge { len: value.len(), max: self.0 });
+        }
+        Ok(())
+    }
+}
+
+/// `Namespace::DESCRIPTOR`: the value must be a valid, unexpired NodeDescriptor signed by the
+/// node whose NodeId is the key.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DescriptorValidator;
+
+impl RecordValidator for DescriptorValidator {
+    fn validate(&self, key: &NamespacedKey, value: &[u8], now: u64) -> Result<(), ValidationError> {
+        SizeLimit(MAX_DESCRIPTOR_LEN).validate(key, value, now)?;
+
+        let descriptor = NodeDescriptor::from_bytes(value).map_err(|e| ValidationError::Invalid(e.to_string()))?;
+        if NodeId::from_slice(key.key()) != Some(descriptor.node_id) {
+            return Err(ValidationError::KeyMismatch);
+        }
+        descriptor.verify(now).map_err(|e| ValidationError::Invalid(e.to_string()))
+    }
+}
+
+/// `Namespace::HIDDEN_SERVICE`: the value must be a valid, unexpired HsDescriptor signed by
+/// the blinded key it is stored under. Its content stays encrypted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HsDescriptorValidator;
+
+impl RecordValidator for HsDescriptorValidator {
+    fn validate(&self, key: &NamespacedKey, value: &[u8], now: u64) -> Result<(), ValidationError> {
+        SizeLimit(MAX_HS_DESCRIPTOR_LEN).validate(key, value, now)?;
+
+        let descriptor = HsDescriptor::from_bytes(value).map_err(|e| ValidationError::Invalid(e.to_string()))?;
+        if key.key() != descriptor.blinded_key.as_bytes() {
+            return Err(ValidationError::KeyMismatch);
+        }
+        descriptor.verify(now).map_err(|e| ValidationError::Invalid(e.to_string()))
+    }
+}
+
+/// Validators by namespace, run before a STORE or PUT is accepted.
+pub struct ValidatorRegistry {
+    validators: HashMap<&'static str, (Namespace, Box<dyn RecordValidator>)>,
+    /// Whether STOREs without a key path (plain hashed keys) are accepted. Off by default: a
+    /// plain key can't be told apart from one a namespace derives, so such a STORE could write
+    /// a descriptor's key without its validator ever running.
+    pub allow_unnamespaced: bool,
+}
+
+impl Default for ValidatorRegistry {
+    /// Node and hidden service descriptors are checked; application data is only size-bounded by the record store.
+    /// STOREs outside a namespace are refused.
+    fn default() -> Self {
+        let mut registry = Self { validators: HashMap::new(), allow_unnamespaced: false };
+        registry.register(Namespace::DESCRIPTOR, DescriptorValidator);
+        registry.register(Namespace::HIDDEN_SERVICE, HsDescriptorValidator);
+        // Provider announcements travel as PROVIDE, never as STORE values
+        registry.register(Namespace::PROVIDER, SizeLimit(0));
+        registry.register(Namespace::APP, SizeLimit(usize::MAX));
+        registry
+    }
+}
+
+impl ValidatorRegistry {
+    /// Registry without any namespace
+    pub fn empty() -> Self {
+        Self { validators: HashMap::new(), allow_unnamespaced: false }
+    }
+
+    /// Registers (or replaces) the validator of a namespace.
+    pub fn register(&mut self, namespace: Namespace, validator: impl RecordValidator + 'static) {
+        self.validators.insert(namespace.name(), (namespace, Box::new(validator)));
+    }
+
+    /// Resolves a wire key path to a known namespace.
+    pub fn resolve(&self, path: &KeyPath) -> Result<NamespacedKey, ValidationError> {
+        match self.validators.get(path.namespace.as_str()) {
+            Some((namespace, _)) => Ok(NamespacedKey::new(*namespace, path.key.clone())),
+            None => Err(ValidationError::UnknownNamespace(path.namespace.clone())),
+        }
+    }
+
+    pub fn validate(&self, key: &NamespacedKey, value: &[u8], now: u64) -> Result<(), ValidationError> {
+        match self.validators.get(key.namespace().name()) {
+            Some((_, validator)) => validator.validate(key, value, now),
+            None => Err(ValidationError::UnknownNamespace(key.namespace().name().to_owned())),
+        }
+    }
+
+    pub fn validate_store(&self, request: &StoreRequest, now: u64) -> Result<(), ValidationError> {
+        let Some(path) = &request.path else {
+            return if self.allow_unnamespaced { Ok(()) } else { Err(ValidationError::MissingNamespace) };
+        };
+
+        let key = self.resolve(path)?;
+        if key.node_id() != request.record.key {
+            return Err(ValidationError::KeyMismatch);
+        }
+        self.validate(&key, &request.record.value, now)
+    }
+
+    /// PUTs carry no namespace on the wire (mutable records are keyed by their owner), so the
+    /// host says which namespace the value belongs to. The key is the owner's NodeId.
+    pub fn validate_put(&self, namespace: Namespace, request: &PutRequest, now: u64) -> Result<(), ValidationError> {
+        let key = NamespacedKey::new(namespace, request.record.key().as_bytes().to_vec());
+        self.validate(&key, &request.record.value, now)
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use ed25519_dalek::{ SigningKey, VerifyingKey };
+
+use super::consensus::{ Consensus, DirectoryError };
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::node_id::NodeId;
+use crate::protocol::descriptor::NodeDescriptor;
+
+/// A directory authority: collects the descriptors relays publish to it, votes on the relays
+/// of each period, and signs the consensus combined from all authorities' votes.
+pub struct DirectoryAuthority {
+    signing_key: SigningKey,
+    descriptors: HashMap<NodeId, NodeDescriptor>,
+}
+
+impl DirectoryAuthority {
+    /// Runs an authority as `identity`, whose identity key clients list in their `AuthoritySet`.
+    pub fn new(identity: &NodeIdentity) -> Self {
+        Self { signing_key: identity.identity_keypair.clone(), descriptors: HashMap::new() }
+    }
+
+    pub fn key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// A relay published its descriptor. Returns false if we already hold one at least as recent.
+    pub fn publish(&mut self, descriptor: NodeDescriptor, now: u64) -> Result<bool, DirectoryError> {
+        descriptor.verify(now)?;
+        if self.descriptors.get(&descriptor.node_id).is_some_and(|known| known.published_at >= descriptor.published_at) {
+            return Ok(false);
+        }
+        self.descriptors.insert(descriptor.node_id, descriptor);
+        Ok(true)
+    }
+
+    /// Our signed vote for the period from `valid_after` to `valid_until`: every relay whose
+    /// descriptor is still valid at `valid_after`. Expired descriptors are forgotten.
+    pub fn vote(&mut self, valid_after: u64, valid_until: u64) -> Result<Consensus, DirectoryError> {
+        self.descriptors.retain(|_, d| !d.is_expired(valid_after));
+        let mut vote = Consensus::new(self.descriptors.values().cloned().collect(), valid_after, valid_until)?;
+        vote.sign(&self.signing_key);
+        Ok(vote)
+    }
+
+    /// Signs a consensus, normally the one `Consensus::from_votes` combined.
+    pub fn sign(&self, consensus: &mut Consensus) {
+        consensus.sign(&self.signing_key);
+    }
+
+    /// Relays we hold a descriptor of
+    pub fn len(&self) -> usize {
+        self.descriptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+}
@@ -0,0 +1,53 @@
+use super::consensus::{ AuthoritySet, Consensus, DirectoryError };
+use crate::onion::path::PathSelector;
+
+/// A client's view of the relays: the newest consensus it could verify against its trusted
+/// authorities, which it feeds to path selection.
+pub struct ConsensusCache {
+    authorities: AuthoritySet,
+    current: Option<Consensus>,
+}
+
+impl ConsensusCache {
+    pub fn new(authorities: AuthoritySet) -> Self {
+        Self { authorities, current: None }
+    }
+
+    /// Verifies a consensus fetched from a directory (see `Consensus::fetch_request`) and keeps
+    /// it if it is newer than ours. Returns whether it replaced ours.
+    pub fn update(&mut self, bytes: &[u8], now: u64) -> Result<bool, DirectoryError> {
+        let consensus = Consensus::from_bytes(bytes)?;
+        consensus.verify(&self.authorities, now)?;
+        if self.current.as_ref().is_some_and(|current| current.valid_after >= consensus.valid_after) {
+            return Ok(false);
+        }
+        log::debug!("New consensus with {} relays, valid until {}", consensus.relays().len(), consensus.valid_until);
+        self.current = Some(consensus);
+        Ok(true)
+    }
+
+    /// The consensus in use, if it is still valid
+    pub fn current(&self, now: u64) -> Option<&Consensus> {
+        self.current.as_ref().filter(|c| c.is_valid_at(now))
+    }
+
+    /// Whether to fetch a newer consensus: we have none valid, or ours is past the middle of
+    /// its validity, so a new one is out before ours runs out.
+    pub fn needs_refresh(&self, now: u64) -> bool {
+        self.current(now).is_none_or(|c| now >= c.valid_after + (c.valid_until - c.valid_after) / 2)
+    }
+
+    /// Puts the relays of the current consensus into `selector` and drops the expired ones.
+    /// Returns how many relays were added or updated.
+    pub fn update_selector(&self, selector: &mut PathSelector, now: u64) -> usize {
+        selector.prune(now);
+        let Some(consensus) = self.current(now) else {
+            return 0;
+        };
+        consensus.relays()
+            .iter()
+            .filter(|d| !d.is_expired(now))
+            .filter(|d| selector.insert(d))
+            .count()
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::{ BTreeMap, HashSet };
+
+use ed25519_dalek::{ Signature, Signer, SigningKey, Verifier, VerifyingKey };
+use sha2::{ Digest, Sha256 };
+
+use crate::dht::messages::FetchRequest;
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::protocol::descriptor::{ DescriptorError, NodeDescriptor };
+
+// Prefix of the signed message, so a consensus signature can't be replayed as another object
+const CONSENSUS_SIGNING_LABEL: &[u8] = b"FreedomNode-Consensus-v1";
+// Hashed into the key directories serve their latest consensus under
+const LATEST_CONSENSUS_LABEL: &[u8] = b"FreedomNode-Consensus-v1 latest";
+
+/// Most relays a consensus may list
+pub const MAX_CONSENSUS_RELAYS: usize = 8192;
+/// Most authorities a consensus may carry signatures of
+pub const MAX_AUTHORITIES: usize = 32;
+/// How long a consensus is valid by default; clients refresh halfway through
+pub const CONSENSUS_LIFETIME_SECS: u64 = 3 * 60 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DirectoryError {
+    #[error("Malformed consensus: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Invalid descriptor: {0}")]
+    Descriptor(#[from] DescriptorError),
+    #[error("Too many relays: {0} (max {MAX_CONSENSUS_RELAYS})")]
+    TooManyRelays(usize),
+    #[error("Signed by {valid} known authorities, {threshold} required")]
+    NotEnoughSignatures {
+        valid: usize,
+        threshold: usize,
+    },
+    #[error("Consensus valid from {valid_after} until {valid_until} (now {now})")]
+    NotValid {
+        valid_after: u64,
+        valid_until: u64,
+        now: u64,
+    },
+}
+
+/// The directory authorities a node trusts, and how many of them must sign a consensus for
+/// it to be believed. No authority alone can make clients use relays of its choosing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthoritySet {
+    keys: Vec<VerifyingKey>,
+    threshold: usize,
+}
+
+impl AuthoritySet {
+    /// `threshold` signatures of `keys` make a consensus valid (at least one).
+    pub fn new(keys: Vec<VerifyingKey>, threshold: usize) -> Self {
+        Self { keys, threshold: threshold.max(1) }
+    }
+
+    /// More than half of `keys` must sign.
+    pub fn majority(keys: Vec<VerifyingKey>) -> Self {
+        let threshold = keys.len() / 2 + 1;
+        Self::new(keys, threshold)
+    }
+
+    pub fn contains(&self, key: &VerifyingKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// One authority's signature over a consensus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthoritySignature {
+    pub authority: VerifyingKey,
+    pub signature: Signature,
+}
+
+/// The relays clients build paths from during a validity period, agreed on and signed by
+/// the directory authorities. Each authority first signs a vote (a consensus listing what it
+/// knows, see `DirectoryAuthority::vote`); `from_votes` combines the votes the same way at every
+/// authority, so each signs the same document and their signatures add up.
+/// Format: [ValidAfter (8)] [ValidUntil (8)] [RelayCount (2)] + N * [Len (2) | NodeDescriptor]
+///         [SigCount (1)] + N * [AuthorityKey (32) | Signature (64)]
+#[derive(Debug, Clone)]
+pub struct Consensus {
+    pub valid_after: u64, // Seconds since UNIX epoch
+    pub valid_until: u64, // Seconds since UNIX epoch
+    /// Ordered by NodeId
+    relays: Vec<NodeDescriptor>,
+    signatures: Vec<AuthoritySignature>,
+}
+
+impl Consensus {
+    /// An unsigned consensus listing `relays` (one descriptor per NodeId).
+    pub fn new(relays: Vec<NodeDescriptor>, valid_after: u64, valid_until: u64) -> Result<Self, DirectoryError> {
+        let relays: Vec<NodeDescriptor> = relays
+            .into_iter()
+            .map(|d| (d.node_id, d))
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .collect();
+        if relays.len() > MAX_CONSENSUS_RELAYS {
+            return Err(DirectoryError::TooManyRelays(relays.len()));
+        }
+        Ok(Self { valid_after, valid_until, relays, signatures: Vec::new() })
+    }
+
+    /// Combines the votes of `authorities` for one period (that of the first vote): a relay
+    /// is listed if more than half of the votes list it, with the newest descriptor they hold
+    /// for it. Votes not signed by a known authority, for another period, or repeating an
+    /// authority are ignored; at least the authorities' threshold of votes is needed.
+    pub fn from_votes(votes: &[Consensus], authorities: &AuthoritySet) -> Result<Self, DirectoryError> {
+        let Some(first) = votes.first() else {
+            return Err(DirectoryError::NotEnoughSignatures { valid: 0, threshold: authorities.threshold() });
+        };
+        let (valid_after, valid_until) = (first.valid_after, first.valid_until);
+
+        let mut voters = HashSet::new();
+        let mut listed: BTreeMap<NodeId, (usize, &NodeDescriptor)> = BTreeMap::new();
+        for vote in votes.iter().filter(|v| v.valid_after == valid_after && v.valid_until == valid_until) {
+            let digest = vote.digest();
+            let Some(voter) = vote.signatures
+                .iter()
+                .find(|s| authorities.contains(&s.authority) && s.authority.verify(&digest, &s.signature).is_ok())
+            else {
+                continue;
+            };
+            if !voters.insert(voter.authority) {
+                continue;
+            }
+            for descriptor in &vote.relays {
+                let entry = listed.entry(descriptor.node_id).or_insert((0, descriptor));
+                entry.0 += 1;
+                if descriptor.published_at > entry.1.published_at {
+                    entry.1 = descriptor;
+                }
+            }
+        }
+        if voters.len() < authorities.threshold() {
+            return Err(DirectoryError::NotEnoughSignatures { valid: voters.len(), threshold: authorities.threshold() });
+        }
+
+        let relays = listed
+            .into_values()
+            .filter(|(count, _)| count * 2 > voters.len())
+            .map(|(_, descriptor)| descriptor.clone())
+            .collect();
+        Self::new(relays, valid_after, valid_until)
+    }
+
+    /// The key directories serve their latest consensus under, for `FetchRequest`
+    pub fn fetch_request() -> FetchRequest {
+        FetchRequest { hash: Sha256::digest(LATEST_CONSENSUS_LABEL).into() }
+    }
+
+    pub fn relays(&self) -> &[NodeDescriptor] {
+        &self.relays
+    }
+
+    pub fn signatures(&self) -> &[AuthoritySignature] {
+        &self.signatures
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_after <= now && now < self.valid_until
+    }
+
+    /// Signs the consensus with an authority's identity key, replacing an earlier signature of it.
+    pub fn sign(&mut self, authority: &SigningKey) {
+        let signature = authority.sign(&self.digest());
+        self.add_signature(AuthoritySignature { authority: authority.verifying_key(), signature });
+    }
+
+    /// Adds another authority's signature, as collected from it. Returns false if it does not
+    /// sign this document.
+    pub fn add_signature(&mut self, signature: AuthoritySignature) -> bool {
+        if signature.authority.verify(&self.digest(), &signature.signature).is_err() {
+            return false;
+        }
+        self.signatures.retain(|s| s.authority != signature.authority);
+        self.signatures.push(signature);
+        true
+    }
+
+    /// Checks that enough of `authorities` signed the consensus and that it is valid `now`.
+    /// The descriptors it lists are trusted as the authorities' choice; they verified them.
+    pub fn verify(&self, authorities: &AuthoritySet, now: u64) -> Result<(), DirectoryError> {
+        let digest = self.digest();
+        let valid = self.signatures
+            .iter()
+            .filter(|s| authorities.contains(&s.authority) && s.authority.verify(&digest, &s.signature).is_ok())
+            .map(|s| s.authority)
+            .collect::<HashSet<_>>()
+            .len();
+        if valid < authorities.threshold() {
+            return Err(DirectoryError::NotEnoughSignatures { valid, threshold: authorities.threshold() });
+        }
+        if !self.is_valid_at(now) {
+            return Err(DirectoryError::NotValid { valid_after: self.valid_after, valid_until: self.valid_until, now });
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.body_bytes();
+        let signatures = &self.signatures[..self.signatures.len().min(MAX_AUTHORITIES)];
+        out.push(signatures.len() as u8);
+        for signature in signatures {
+            out.extend_from_slice(signature.authority.as_bytes());
+            out.extend_from_slice(&signature.signature.to_bytes());
+        }
+        out
+    }
+
+    /// Parses a consensus. Does not check signatures or validity; call `verify` for that.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DirectoryError> {
+        let mut reader = Reader::new(bytes);
+        let valid_after = reader.u64()?;
+        let valid_until = reader.u64()?;
+        let count = reader.u16()? as usize;
+        if count > MAX_CONSENSUS_RELAYS {
+            return Err(DirectoryError::TooManyRelays(count));
+        }
+        let mut relays = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len = reader.u16()? as usize;
+            relays.push(NodeDescriptor::from_bytes(reader.take(len)?)?);
+        }
+
+        let count = reader.u8()? as usize;
+        if count > MAX_AUTHORITIES {
+            return Err(CodecError::InvalidField("signature count").into());
+        }
+        let mut signatures = Vec::with_capacity(count);
+        for _ in 0..count {
+            let authority = VerifyingKey::from_bytes(&reader.take_array()?).map_err(|_| CodecError::InvalidField("authority key"))?;
+            let signature = Signature::from_bytes(&reader.take_array()?);
+            signatures.push(AuthoritySignature { authority, signature });
+        }
+        reader.finish()?;
+        Ok(Self { valid_after, valid_until, relays, signatures })
+    }
+
+    /// What authorities sign: a digest of the labelled body, so the signature is small to check
+    fn digest(&self) -> [u8; 32] {
+        Sha256::new().chain_update(CONSENSUS_SIGNING_LABEL).chain_update(self.body_bytes()).finalize().into()
+    }
+
+    fn body_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(18 + self.relays.len() * 256);
+        out.extend_from_slice(&self.valid_after.to_be_bytes());
+        out.extend_from_slice(&self.valid_until.to_be_bytes());
+        out.extend_from_slice(&(self.relays.len() as u16).to_be_bytes());
+        for relay in &self.relays {
+            let bytes = relay.to_bytes();
+            out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            out.extend_from_slice(&bytes);
+        }
+        out
+    }
+}
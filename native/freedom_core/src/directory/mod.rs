@@ -0,0 +1,10 @@
+pub mod authority;
+pub mod cache;
+pub mod consensus;
+
+pub use authority::DirectoryAuthority;
+pub use cache::ConsensusCache;
+pub use consensus::{ AuthoritySet, AuthoritySignature, Consensus, DirectoryError };
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,136 @@
+use ed25519_dalek::VerifyingKey;
+
+use crate::crypto::identity::NodeIdentity;
+use crate::directory::authority::DirectoryAuthority;
+use crate::directory::cache::ConsensusCache;
+use crate::directory::consensus::{ AuthoritySet, Consensus, DirectoryError, CONSENSUS_LIFETIME_SECS };
+use crate::onion::path::PathSelector;
+use crate::protocol::descriptor::{ Capabilities, NodeDescriptor };
+
+const VALID_AFTER: u64 = 2_000;
+const VALID_UNTIL: u64 = VALID_AFTER + CONSENSUS_LIFETIME_SECS;
+
+fn relay_descriptor(identity: &NodeIdentity, n: u8, published_at: u64) -> NodeDescriptor {
+    let addr = format!("10.{n}.0.1:5000").parse().unwrap();
+    NodeDescriptor::new_signed(identity, vec![addr], Capabilities::RELAY, 1_000, published_at, 4 * 3_600).unwrap()
+}
+
+fn trusted_keys(authorities: &[DirectoryAuthority]) -> Vec<VerifyingKey> {
+    authorities.iter().map(|a| a.key()).collect()
+}
+
+/// Integration test: Authorities vote on the relays published to them, sign the combined consensus, and clients accept it only with enough signatures
+#[test]
+fn test_directory_consensus() {
+    let authority_identities: Vec<NodeIdentity> = (0..3).map(|_| NodeIdentity::generate()).collect();
+    let mut authorities: Vec<DirectoryAuthority> = authority_identities.iter().map(DirectoryAuthority::new).collect();
+    let trusted = AuthoritySet::majority(trusted_keys(&authorities));
+    assert_eq!(trusted.threshold(), 2);
+
+    // Relays 0 and 1 publish to every authority, relay 2 to only one; relay 0 republishes later
+    let relays: Vec<NodeIdentity> = (0..3).map(|_| NodeIdentity::generate()).collect();
+    for authority in &mut authorities {
+        assert!(authority.publish(relay_descriptor(&relays[0], 0, 1_000), 1_000).unwrap());
+        assert!(authority.publish(relay_descriptor(&relays[1], 1, 1_000), 1_000).unwrap());
+    }
+    assert!(authorities[0].publish(relay_descriptor(&relays[2], 2, 1_000), 1_000).unwrap());
+    assert!(authorities[1].publish(relay_descriptor(&relays[0], 0, 1_500), 1_500).unwrap());
+    assert!(!authorities[1].publish(relay_descriptor(&relays[0], 0, 1_200), 1_500).unwrap());
+    assert_eq!(authorities[0].len(), 3);
+
+    // A forged descriptor is refused
+    let mut forged = relay_descriptor(&relays[1], 1, 1_600);
+    forged.bandwidth = u32::MAX;
+    assert!(matches!(authorities[2].publish(forged, 1_600), Err(DirectoryError::Descriptor(_))));
+
+    // Every authority combines the same votes into the same document, so the signatures add up
+    let votes: Vec<Consensus> = authorities.iter_mut().map(|a| a.vote(VALID_AFTER, VALID_UNTIL).unwrap()).collect();
+    let mut consensus = Consensus::from_votes(&votes, &trusted).unwrap();
+    let ids: Vec<_> = consensus.relays().iter().map(|d| d.node_id).collect();
+    assert_eq!(ids.len(), 2, "a relay listed by one authority of three is left out");
+    assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    let relay0 = consensus.relays().iter().find(|d| d.identity_key == relays[0].identity_keypair.verifying_key()).unwrap();
+    assert_eq!(relay0.published_at, 1_500, "the newest descriptor is listed");
+
+    authorities[0].sign(&mut consensus);
+    assert!(matches!(
+        Consensus::from_bytes(&consensus.to_bytes()).unwrap().verify(&trusted, VALID_AFTER),
+        Err(DirectoryError::NotEnoughSignatures { valid: 1, threshold: 2 })
+    ));
+    let mut other = Consensus::from_votes(&votes, &trusted).unwrap();
+    authorities[1].sign(&mut other);
+    assert!(consensus.add_signature(other.signatures()[0].clone()));
+    consensus.verify(&trusted, VALID_AFTER).unwrap();
+
+    // A signature over another document, or by an unknown key, doesn't count
+    let mut stray = Consensus::new(Vec::new(), VALID_AFTER, VALID_UNTIL).unwrap();
+    authorities[2].sign(&mut stray);
+    assert!(!consensus.add_signature(stray.signatures()[0].clone()));
+    let outsider = NodeIdentity::generate();
+    let mut unknown = consensus.clone();
+    unknown.sign(&outsider.identity_keypair);
+    assert!(matches!(
+        unknown.verify(&AuthoritySet::new(trusted_keys(&authorities), 3), VALID_AFTER),
+        Err(DirectoryError::NotEnoughSignatures { valid: 2, threshold: 3 })
+    ));
+
+    // Votes from too few authorities, or repeated, aren't enough
+    assert!(matches!(
+        Consensus::from_votes(&[votes[0].clone(), votes[0].clone()], &trusted),
+        Err(DirectoryError::NotEnoughSignatures { valid: 1, threshold: 2 })
+    ));
+
+    // Outside its validity the consensus is refused
+    assert!(matches!(consensus.verify(&trusted, VALID_UNTIL), Err(DirectoryError::NotValid { .. })));
+    assert!(matches!(consensus.verify(&trusted, VALID_AFTER - 1), Err(DirectoryError::NotValid { .. })));
+
+    // Truncated bytes don't parse
+    let bytes = consensus.to_bytes();
+    assert!(matches!(Consensus::from_bytes(&bytes[..bytes.len() - 1]), Err(DirectoryError::Malformed(_))));
+    assert_eq!(Consensus::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+}
+
+/// Integration test: Clients cache the newest verified consensus, refresh it halfway through, and feed its relays to path selection
+#[test]
+fn test_consensus_cache() {
+    let mut authorities: Vec<DirectoryAuthority> = (0..2).map(|_| DirectoryAuthority::new(&NodeIdentity::generate())).collect();
+    let trusted = AuthoritySet::new(trusted_keys(&authorities), 2);
+    let relays: Vec<NodeIdentity> = (0..3).map(|_| NodeIdentity::generate()).collect();
+    for authority in &mut authorities {
+        for (n, relay) in relays.iter().enumerate() {
+            authority.publish(relay_descriptor(relay, n as u8, 1_000), 1_000).unwrap();
+        }
+    }
+    let signed = |authorities: &mut [DirectoryAuthority], valid_after: u64| {
+        let votes: Vec<Consensus> = authorities.iter_mut().map(|a| a.vote(valid_after, valid_after + CONSENSUS_LIFETIME_SECS).unwrap()).collect();
+        let mut consensus = Consensus::from_votes(&votes, &trusted).unwrap();
+        authorities.iter().for_each(|a| a.sign(&mut consensus));
+        consensus.to_bytes()
+    };
+
+    let mut cache = ConsensusCache::new(trusted.clone());
+    assert!(cache.needs_refresh(VALID_AFTER));
+    let first = signed(&mut authorities, VALID_AFTER);
+    assert!(cache.update(&first, VALID_AFTER).unwrap());
+    assert!(!cache.update(&first, VALID_AFTER).unwrap(), "not newer");
+    assert!(!cache.needs_refresh(VALID_AFTER + 10));
+    assert!(cache.needs_refresh(VALID_AFTER + CONSENSUS_LIFETIME_SECS / 2));
+
+    // A consensus signed by only one of the two authorities is refused and the cache is kept
+    let votes: Vec<Consensus> = authorities.iter_mut().map(|a| a.vote(VALID_AFTER + 600, VALID_UNTIL + 600).unwrap()).collect();
+    let mut partial = Consensus::from_votes(&votes, &trusted).unwrap();
+    authorities[0].sign(&mut partial);
+    assert!(cache.update(&partial.to_bytes(), VALID_AFTER + 600).is_err());
+    assert_eq!(cache.current(VALID_AFTER + 600).unwrap().valid_after, VALID_AFTER);
+
+    let mut selector = PathSelector::new();
+    assert_eq!(cache.update_selector(&mut selector, VALID_AFTER + 10), 3);
+    assert_eq!(selector.len(), 3);
+
+    // Once the consensus runs out there is nothing to select from until a new one is fetched
+    assert!(cache.current(VALID_UNTIL).is_none());
+    assert_eq!(cache.update_selector(&mut selector, VALID_UNTIL), 0);
+    let second = signed(&mut authorities, VALID_UNTIL);
+    assert!(cache.update(&second, VALID_UNTIL).unwrap());
+    assert_eq!(cache.update_selector(&mut selector, VALID_UNTIL), 3);
+}
@@ -0,0 +1,54 @@
+use crate::cancel::CancellationToken;
+
+use super::error::{ clear_last_error, guard, set_last_error };
+use super::status::FfiStatus;
+
+
+// ==================================================================================
+// CANCELLATION EXPORTS (Aborting long-running jobs)
+// ==================================================================================
+
+/// Creates a cancellation token for the long-running exports.
+///
+/// Returns an opaque handle. Release it with `ffi_cancellation_destroy`.
+#[unsafe(no_mangle)]
+pub extern "C" fn ffi_cancellation_create() -> *mut CancellationToken {
+    guard(|| {
+        clear_last_error();
+
+        Box::into_raw(Box::new(CancellationToken::new()))
+    })
+}
+
+/// Cancels every job started with this token. Jobs stop at their next check and
+/// complete with `FfiStatus::Cancelled`. Cancelling twice is harmless.
+/// # Safety
+/// - `token` must be a pointer returned by `ffi_cancellation_create`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_cancellation_cancel(token: *const CancellationToken) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(token) = (unsafe { token.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null cancellation token");
+        };
+
+        token.cancel();
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Releases a cancellation token. Jobs already started keep their own reference,
+/// so the host may destroy the token right after cancelling.
+/// # Safety
+/// - `token` must be a pointer returned by `ffi_cancellation_create`, or null. It must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_cancellation_destroy(token: *mut CancellationToken) {
+    guard(|| {
+        if !token.is_null() {
+            drop(unsafe { Box::from_raw(token) });
+        }
+    })
+}
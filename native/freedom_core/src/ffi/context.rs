@@ -0,0 +1,143 @@
+use crate::context::{ ConfigError, ContextSettings, FreedomContext };
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::logging;
+use super::status::FfiStatus;
+
+
+// ==================================================================================
+// CONTEXT EXPORTS (Lifetime of a core instance)
+// ==================================================================================
+
+/// Creation settings for `ffi_init`. Zero selects the default for every limit.
+/// C# Reference: marshalled as a sequential struct (16 bytes)
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FfiConfig {
+    pub worker_threads: u32, // 0 = one per available core
+    pub max_payload_len: u32, // 0 = DEFAULT_MAX_PAYLOAD_LEN
+    pub handshake_window_secs: u32, // 0 = HANDSHAKE_WINDOW_SECS
+    pub log_level: i32, // Minimum level forwarded to the log callback (0..6, 6 = off); -1 keeps the current one
+}
+
+impl Default for FfiConfig {
+    fn default() -> Self {
+        Self { worker_threads: 0, max_payload_len: 0, handshake_window_secs: 0, log_level: -1 }
+    }
+}
+
+impl TryFrom<&FfiConfig> for ContextSettings {
+    type Error = ConfigError;
+
+    fn try_from(config: &FfiConfig) -> Result<Self, ConfigError> {
+        if !(-1..=6).contains(&config.log_level) {
+            return Err(ConfigError::InvalidLogLevel(config.log_level));
+        }
+
+        let defaults = ContextSettings::default();
+        let settings = Self {
+            worker_threads: config.worker_threads as usize,
+            max_payload_len: match config.max_payload_len {
+                0 => defaults.max_payload_len,
+                len => len as usize,
+            },
+            handshake_window_secs: match config.handshake_window_secs {
+                0 => defaults.handshake_window_secs,
+                secs => secs as u64,
+            },
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Creates a core instance. The context is thread-safe: it may be shared by every host thread
+/// and passed as the first argument to the exports that need it.
+/// The configuration is validated first; out-of-range values fail with `InvalidArgument`.
+/// # Safety
+/// - `config` must be null (defaults) or point to a valid `FfiConfig`.
+///
+/// Returns an opaque context, or null on failure. Release it with `ffi_shutdown`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_init(config: *const FfiConfig) -> *mut FreedomContext {
+    guard(|| {
+        clear_last_error();
+
+        let config = unsafe { config.as_ref() }.copied().unwrap_or_default();
+        let settings = match ContextSettings::try_from(&config) {
+            Ok(settings) => settings,
+            Err(e) => {
+                report(e);
+                return std::ptr::null_mut();
+            }
+        };
+        // The log sink is process-wide, so the level applies to every context
+        if config.log_level >= 0 {
+            logging::set_min_level(config.log_level);
+        }
+
+        match FreedomContext::new(settings) {
+            Ok(context) => Box::into_raw(Box::new(context)),
+            Err(e) => {
+                report(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Fills a buffer with random bytes from the context RNG.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `output_ptr` must point to a valid buffer of `len` bytes.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_random_bytes(
+    context: *const FreedomContext,
+    output_ptr: *mut u8,
+    len: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        if output_ptr.is_null() {
+            return set_last_error(FfiStatus::InvalidArgument, "Output buffer is null");
+        }
+
+        context.fill_random(unsafe { std::slice::from_raw_parts_mut(output_ptr, len) });
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Shuts a core instance down: queued jobs finish (their callbacks still run), then the workers stop.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`, or null. No other call may use it concurrently
+///   or afterwards.
+/// - It must not be called from a job completion callback: those run on the context's workers, which
+///   can't wait for themselves to stop. Such a call fails with `InvalidArgument` and leaves the context
+///   running.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_shutdown(context: *mut FreedomContext) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(running) = (unsafe { context.as_ref() }) else {
+            return FfiStatus::Ok.code();
+        };
+        if running.pool().is_worker_thread() {
+            return set_last_error(
+                FfiStatus::InvalidArgument,
+                "ffi_shutdown called from a job callback; call it from a host thread"
+            );
+        }
+
+        drop(unsafe { Box::from_raw(context) });
+        FfiStatus::Ok.code()
+    })
+}
@@ -0,0 +1,399 @@
+use crate::crypto::helper;
+use crate::clock::unix_now;
+use crate::context::FreedomContext;
+use crate::crypto::handshake::{ HandshakePayload, HANDSHAKE_WINDOW_SECS };
+use crate::crypto::identity;
+use ed25519_dalek::{ Signer, SigningKey };
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::memory::return_owned;
+use super::status::FfiStatus;
+use super::{ raw_to_array, raw_to_slice, write_or_size, write_to_buffer };
+
+// --- C# Exports ----
+
+
+/// Creates a session key using X25519 key exchange.
+/// # Safety
+/// - `my_private_key_ptr` must point to a valid 32-byte array.
+/// - `other_public_key_ptr` must point to a valid 32-byte array.
+/// - `output_ptr` must point to a valid 32-byte buffer to write the session key.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_create_session_key(
+    my_private_key_ptr: *const u8, // 32 bytes
+    other_public_key_ptr: *const u8, // 32 bytes
+    output_ptr: *mut u8, // 32 bytes Buffer to write the session key
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let my_private_bytes = match unsafe { raw_to_array::<32>(my_private_key_ptr, "my_private_key_ptr") } {
+            Ok(bytes) => bytes,
+            Err(code) => return code,
+        };
+        let other_public_bytes = match unsafe { raw_to_array::<32>(other_public_key_ptr, "other_public_key_ptr") } {
+            Ok(bytes) => bytes,
+            Err(code) => return code,
+        };
+
+        let my_secret = x25519_dalek::StaticSecret::from(*my_private_bytes);
+        let other_public = x25519_dalek::PublicKey::from(*other_public_bytes);
+
+        let session_key = helper::create_session_key(&my_secret, &other_public);
+
+        let written = unsafe { write_to_buffer(output_ptr, 32, &session_key) };
+        if written < 0 {
+            return written;
+        }
+
+        FfiStatus::Ok.code()
+    })
+}
+
+
+/// Validates a handshake payload: size, key and signature encoding, Ed25519 signature,
+/// and that the timestamp is within the default `HANDSHAKE_WINDOW_SECS` of the local clock.
+/// Hosts that created a context should call `ffi_validate_handshake_in`, which honours its
+/// `handshake_window_secs`.
+/// # Safety
+/// - `data_ptr` must point to a valid byte array of length `len`.
+///
+/// Returns `FfiStatus::Ok` if valid, or the status of the first failed check
+/// (`InvalidSize`, `InvalidKey`, `InvalidSignature`, `VerificationFailed`, `StaleTimestamp`).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_validate_handshake(
+    data_ptr: *const u8,
+    len: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+        validate_handshake(data, HANDSHAKE_WINDOW_SECS)
+    })
+}
+
+/// Same as `ffi_validate_handshake`, but checks the timestamp against the context's
+/// `handshake_window_secs`.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `data_ptr` must point to a valid byte array of length `len`.
+///
+/// Returns `FfiStatus::Ok` if valid, or the status of the first failed check.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_validate_handshake_in(
+    context: *const FreedomContext,
+    data_ptr: *const u8,
+    len: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+        validate_handshake(data, context.settings().handshake_window_secs)
+    })
+}
+
+fn validate_handshake(data: &[u8], window_secs: u64) -> i32 {
+    let result = HandshakePayload::from_bytes(data).and_then(|payload| {
+        payload.verify()?;
+        payload.check_freshness(unix_now(), window_secs)
+    });
+
+    match result {
+        Ok(_) => FfiStatus::Ok.code(), // Valid
+        Err(e) => report(e), // Invalid
+    }
+}
+
+
+/// Encrypts data using ChaCha20-Poly1305.
+/// # Safety
+/// - `key_ptr` must point to a valid 32-byte array.
+/// - `plaintext_ptr` must point to a valid byte array of length `plaintext_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap`, or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_encrypt_layer(
+    key_ptr: *const u8, // 32 bytes
+    plaintext_ptr: *const u8, // Original data
+    plaintext_len: usize,
+    output_ptr: *mut u8, // Buffer to write encrypted data
+    output_cap: usize, // Capacity of output buffer
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key_array = match unsafe { raw_to_array::<32>(key_ptr, "key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        let plaintext = unsafe { raw_to_slice(plaintext_ptr, plaintext_len) };
+
+        match helper::encrypt_layer(key_array, plaintext) {
+            Ok(encrypted_data) => {
+                unsafe { write_or_size(output_ptr, output_cap, &encrypted_data) }
+            },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Decrypts data using ChaCha20-Poly1305.
+/// # Safety
+/// - `key_ptr` must point to a valid 32-byte array.
+/// - `ciphertext_ptr` must point to a valid byte array of length `ciphertext_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap`, or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_decrypt_layer(
+    key_ptr: *const u8, // 32 bytes
+    ciphertext_ptr: *const u8, // Encrypted data
+    ciphertext_len: usize,
+    output_ptr: *mut u8, // Buffer to write decrypted data
+    output_cap: usize, // Capacity of output buffer
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key_bytes = unsafe { raw_to_slice(key_ptr, 32) };
+        let ciphertext = unsafe { raw_to_slice(ciphertext_ptr, ciphertext_len) };
+
+        let Ok(key_array) = <&[u8; 32]>::try_from(key_bytes) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Invalid key length"); // Invalid key length
+        };
+
+        match helper::try_decrypt_layer(key_array, ciphertext) {
+            Ok(decrypted_data) => {
+                unsafe { write_or_size(output_ptr, output_cap, &decrypted_data) }
+            },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Encrypts data using ChaCha20-Poly1305 into a Rust-allocated buffer.
+/// # Safety
+/// - `key_ptr` must point to a valid 32-byte array.
+/// - `plaintext_ptr` must point to a valid byte array of length `plaintext_len`.
+/// - `out_ptr` and `out_len` must point to writable locations. The result must be released with `ffi_free`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_encrypt_layer_alloc(
+    key_ptr: *const u8, // 32 bytes
+    plaintext_ptr: *const u8,
+    plaintext_len: usize,
+    out_ptr: *mut *mut u8, // Receives the encrypted data
+    out_len: *mut usize, // Receives its length
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key_array = match unsafe { raw_to_array::<32>(key_ptr, "key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        let plaintext = unsafe { raw_to_slice(plaintext_ptr, plaintext_len) };
+
+        match helper::encrypt_layer(key_array, plaintext) {
+            Ok(encrypted_data) => unsafe { return_owned(encrypted_data, out_ptr, out_len) },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Decrypts data using ChaCha20-Poly1305 into a Rust-allocated buffer.
+/// # Safety
+/// - `key_ptr` must point to a valid 32-byte array.
+/// - `ciphertext_ptr` must point to a valid byte array of length `ciphertext_len`.
+/// - `out_ptr` and `out_len` must point to writable locations. The result must be released with `ffi_free`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_decrypt_layer_alloc(
+    key_ptr: *const u8, // 32 bytes
+    ciphertext_ptr: *const u8,
+    ciphertext_len: usize,
+    out_ptr: *mut *mut u8, // Receives the decrypted data
+    out_len: *mut usize, // Receives its length
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key_array = match unsafe { raw_to_array::<32>(key_ptr, "key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        let ciphertext = unsafe { raw_to_slice(ciphertext_ptr, ciphertext_len) };
+
+        match helper::try_decrypt_layer(key_array, ciphertext) {
+            Ok(decrypted_data) => unsafe { return_owned(decrypted_data, out_ptr, out_len) },
+            Err(e) => report(e),
+        }
+    })
+}
+
+
+/// Encrypts `count` independent plaintexts, each with its own key, in a single call.
+/// Every output length is written to `output_lens` first; if any buffer is too small
+/// nothing is encrypted and `BufferTooSmall` is returned, so the host can resize and retry.
+/// # Safety
+/// - `keys_ptr` must point to `count` consecutive 32-byte keys.
+/// - `plaintext_ptrs` and `plaintext_lens` must point to `count` entries; each plaintext pointer must be
+///   valid for its length.
+/// - `output_ptrs` and `output_caps` must point to `count` entries; each output pointer must be valid
+///   for its capacity (at least plaintext length + 28).
+/// - `output_lens` must point to `count` writable entries.
+///
+/// Returns `count` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_encrypt_layer_batch(
+    keys_ptr: *const u8, // count * 32 bytes
+    plaintext_ptrs: *const *const u8,
+    plaintext_lens: *const usize,
+    count: usize,
+    output_ptrs: *const *mut u8,
+    output_caps: *const usize,
+    output_lens: *mut usize, // Receives the (required) length of each output
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        if count == 0 {
+            return 0;
+        }
+        let Ok(result) = i32::try_from(count) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Batch too large");
+        };
+        if keys_ptr.is_null()
+            || plaintext_ptrs.is_null()
+            || plaintext_lens.is_null()
+            || output_ptrs.is_null()
+            || output_caps.is_null()
+            || output_lens.is_null()
+        {
+            return set_last_error(FfiStatus::InvalidArgument, "Null batch array");
+        }
+        let Some(keys_len) = count.checked_mul(32) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Batch too large");
+        };
+
+        let keys = unsafe { raw_to_slice(keys_ptr, keys_len) };
+        let plaintext_ptrs = unsafe { std::slice::from_raw_parts(plaintext_ptrs, count) };
+        let plaintext_lens = unsafe { std::slice::from_raw_parts(plaintext_lens, count) };
+        let output_ptrs = unsafe { std::slice::from_raw_parts(output_ptrs, count) };
+        let output_caps = unsafe { std::slice::from_raw_parts(output_caps, count) };
+        let output_lens = unsafe { std::slice::from_raw_parts_mut(output_lens, count) };
+
+        // Size everything before encrypting, so a failed batch leaves no partial output
+        let mut too_small = false;
+        let mut total = 0usize;
+        for i in 0..count {
+            let Some(output_len) = plaintext_lens[i].checked_add(helper::LAYER_OVERHEAD) else {
+                return set_last_error(FfiStatus::InvalidArgument, "Plaintext too large");
+            };
+            let Some(sum) = total.checked_add(output_len) else {
+                return set_last_error(FfiStatus::InvalidArgument, "Batch too large");
+            };
+            total = sum;
+            output_lens[i] = output_len;
+            too_small |= output_ptrs[i].is_null() || output_caps[i] < output_len;
+        }
+        if too_small {
+            return set_last_error(FfiStatus::BufferTooSmall, "A batch output buffer is too small");
+        }
+
+        for (i, key) in keys.chunks_exact(32).enumerate() {
+            let key: &[u8; 32] = key.try_into().expect("chunks of 32 bytes");
+            let plaintext = unsafe { raw_to_slice(plaintext_ptrs[i], plaintext_lens[i]) };
+
+            let encrypted_data = match helper::encrypt_layer(key, plaintext) {
+                Ok(data) => data,
+                Err(e) => return report(e),
+            };
+            let written = unsafe { write_to_buffer(output_ptrs[i], output_caps[i], &encrypted_data) };
+            if written < 0 {
+                return written;
+            }
+        }
+
+        result
+    })
+}
+
+
+/// Signs arbitrary data with an Ed25519 identity key.
+/// # Safety
+/// - `identity_secret_ptr` must point to a valid 32-byte array (Ed25519 private key).
+/// - `message_ptr` must point to a valid byte array of length `message_len`.
+/// - `signature_out_ptr` must point to a valid 64-byte buffer.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_sign(
+    identity_secret_ptr: *const u8, // 32 bytes
+    message_ptr: *const u8,
+    message_len: usize,
+    signature_out_ptr: *mut u8, // 64 bytes
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let secret = match unsafe { raw_to_array::<32>(identity_secret_ptr, "identity_secret_ptr") } {
+            Ok(secret) => secret,
+            Err(code) => return code,
+        };
+        let message = unsafe { raw_to_slice(message_ptr, message_len) };
+
+        let signature = SigningKey::from_bytes(secret).sign(message);
+
+        let written = unsafe { write_to_buffer(signature_out_ptr, 64, &signature.to_bytes()) };
+        if written < 0 {
+            return written;
+        }
+
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Verifies an Ed25519 signature over arbitrary data.
+/// # Safety
+/// - `identity_key_ptr` must point to a valid 32-byte array (Ed25519 public key).
+/// - `message_ptr` must point to a valid byte array of length `message_len`.
+/// - `signature_ptr` must point to a valid 64-byte array.
+///
+/// Returns `FfiStatus::Ok` if valid, or an error status (e.g. `VerificationFailed`).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_verify(
+    identity_key_ptr: *const u8, // 32 bytes
+    message_ptr: *const u8,
+    message_len: usize,
+    signature_ptr: *const u8, // 64 bytes
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key = match unsafe { raw_to_array::<32>(identity_key_ptr, "identity_key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        let signature = match unsafe { raw_to_array::<64>(signature_ptr, "signature_ptr") } {
+            Ok(signature) => signature,
+            Err(code) => return code,
+        };
+        let message = unsafe { raw_to_slice(message_ptr, message_len) };
+
+        match identity::verify_raw(key, message, signature) {
+            Ok(_) => FfiStatus::Ok.code(), // Valid
+            Err(e) => report(e), // Invalid
+        }
+    })
+}
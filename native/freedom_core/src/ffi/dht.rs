@@ -0,0 +1,93 @@
+use crate::dht::node_id::{ NodeId, NODE_ID_SIZE };
+
+use super::error::{ clear_last_error, guard };
+use super::status::FfiStatus;
+use super::{ raw_to_array, raw_to_slice, write_to_buffer };
+
+
+// ==================================================================================
+// DHT EXPORTS (NodeId derivation / XOR distance)
+// ==================================================================================
+
+/// Derives the NodeId of a key: SHA-256 of its bytes (identity keys, content keys).
+/// # Safety
+/// - `key_ptr` must point to a valid byte array of length `key_len`.
+/// - `node_id_out_ptr` must point to a valid 32-byte buffer.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_node_id_from_key(
+    key_ptr: *const u8,
+    key_len: usize,
+    node_id_out_ptr: *mut u8, // 32 bytes
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key = unsafe { raw_to_slice(key_ptr, key_len) };
+        let node_id = NodeId::hash_of(key);
+
+        let written = unsafe { write_to_buffer(node_id_out_ptr, NODE_ID_SIZE, node_id.as_bytes()) };
+        if written < 0 {
+            return written;
+        }
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Computes the XOR distance between two NodeIds (big-endian, smaller = closer).
+/// # Safety
+/// - `a_ptr` and `b_ptr` must point to valid 32-byte arrays.
+/// - `distance_out_ptr` must point to a valid 32-byte buffer.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_xor_distance(
+    a_ptr: *const u8, // 32 bytes
+    b_ptr: *const u8, // 32 bytes
+    distance_out_ptr: *mut u8, // 32 bytes
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let (a, b) = match unsafe { read_pair(a_ptr, b_ptr) } {
+            Ok(pair) => pair,
+            Err(code) => return code,
+        };
+
+        let written = unsafe { write_to_buffer(distance_out_ptr, NODE_ID_SIZE, &a.xor(&b)) };
+        if written < 0 {
+            return written;
+        }
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Returns the routing-table bucket of `other_ptr` relative to `local_ptr`: the number of leading
+/// zero bits of their distance, capped at 255 (0 = farthest, 255 = closest).
+/// # Safety
+/// - `local_ptr` and `other_ptr` must point to valid 32-byte arrays.
+///
+/// Returns the bucket index (0..=255), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_bucket_index(
+    local_ptr: *const u8, // 32 bytes
+    other_ptr: *const u8, // 32 bytes
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        match unsafe { read_pair(local_ptr, other_ptr) } {
+            Ok((local, other)) => local.bucket_index(&other) as i32,
+            Err(code) => code,
+        }
+    })
+}
+
+/// # Safety
+/// - Both pointers must be null or point to 32-byte arrays.
+unsafe fn read_pair(a_ptr: *const u8, b_ptr: *const u8) -> Result<(NodeId, NodeId), i32> {
+    let a = unsafe { raw_to_array::<NODE_ID_SIZE>(a_ptr, "a_ptr") }?;
+    let b = unsafe { raw_to_array::<NODE_ID_SIZE>(b_ptr, "b_ptr") }?;
+    Ok((NodeId::from_bytes(*a), NodeId::from_bytes(*b)))
+}
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::panic::{ self, AssertUnwindSafe };
+
+use super::status::FfiStatus;
+use super::write_or_size;
+
+/// Details of the last failed FFI call on the current thread.
+struct LastError {
+    code: i32,
+    message: String,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+/// Records why the current call failed and returns the status code, so exports can `return set_last_error(...)`.
+pub(crate) fn set_last_error(status: FfiStatus, message: impl Display) -> i32 {
+    let code = status.code();
+    LAST_ERROR.with(|slot| {
+        *slot.borrow_mut() = Some(LastError { code, message: message.to_string() });
+    });
+    code
+}
+
+/// Records a domain error, deriving the status code from its type.
+pub(crate) fn report<E>(error: E) -> i32 where E: Display, for<'a> FfiStatus: From<&'a E> {
+    set_last_error(FfiStatus::from(&error), error)
+}
+
+/// Clears the error slot at the start of every export, so the slot always describes the latest call.
+pub(crate) fn clear_last_error() {
+    LAST_ERROR.with(|slot| {
+        *slot.borrow_mut() = None;
+    });
+}
+
+/// What an export returns when its body panicked.
+pub(crate) trait PanicFallback {
+    fn panic_fallback() -> Self;
+}
+
+impl PanicFallback for i32 {
+    fn panic_fallback() -> Self {
+        FfiStatus::Panic.code()
+    }
+}
+
+impl PanicFallback for u32 {
+    fn panic_fallback() -> Self {
+        0
+    }
+}
+
+impl PanicFallback for () {
+    fn panic_fallback() -> Self {}
+}
+
+impl<T> PanicFallback for Result<T, i32> {
+    fn panic_fallback() -> Self {
+        Err(FfiStatus::Panic.code())
+    }
+}
+
+impl<T> PanicFallback for *mut T {
+    fn panic_fallback() -> Self {
+        std::ptr::null_mut()
+    }
+}
+
+/// Runs an export body, turning a panic into `FfiStatus::Panic` (or the type's fallback) with the
+/// panic message in the error slot. Unwinding into the host is undefined behaviour / an abort.
+pub(crate) fn guard<R: PanicFallback>(body: impl FnOnce() -> R) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            log::error!("Panic caught at the FFI boundary: {message}");
+            set_last_error(FfiStatus::Panic, format!("Native panic: {message}"));
+            R::panic_fallback()
+        }
+    }
+}
+
+/// Returns the `FfiStatus` code of the last failed call on this thread, or 0 (`Ok`) if the last call succeeded.
+#[unsafe(no_mangle)]
+pub extern "C" fn ffi_last_error_code() -> i32 {
+    guard(|| {
+        LAST_ERROR.with(|slot| slot.borrow().as_ref().map_or(0, |e| e.code))
+    })
+}
+
+/// Copies the UTF-8 message of the last failed call on this thread into a C# allocated buffer.
+/// # Safety
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap`, or be null to query the length.
+///
+/// Returns the number of bytes written (0 if there is no error), or `BufferTooSmall`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_last_error_message(
+    output_ptr: *mut u8,
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        // Copied out first: a failed write records its own error in the slot
+        let message = LAST_ERROR.with(|slot| slot.borrow().as_ref().map(|e| e.message.clone()));
+        match message {
+            Some(message) => unsafe { write_or_size(output_ptr, output_cap, message.as_bytes()) },
+            None => 0,
+        }
+    })
+}
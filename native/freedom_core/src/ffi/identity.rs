@@ -0,0 +1,189 @@
+use crate::crypto::handshake::HANDSHAKE_PAYLOAD_SIZE;
+use crate::crypto::identity::{ NodeIdentity, IDENTITY_SECRET_SIZE };
+
+use super::error::{ clear_last_error, guard, set_last_error };
+use super::status::FfiStatus;
+use super::{ raw_to_array, write_to_buffer };
+
+
+// ==================================================================================
+// IDENTITY EXPORTS (Generation / Key export)
+// ==================================================================================
+
+/// Node identity owned by the native core. The private keys never leave Rust
+/// unless the handle was created as exportable.
+pub struct IdentityHandle {
+    pub(crate) identity: NodeIdentity,
+    exportable: bool,
+}
+
+/// Generates a new random identity (Ed25519 identity key + X25519 onion key).
+/// `exportable != 0` opts in to `ffi_identity_private_export` for this identity (needed to persist it).
+///
+/// Returns an opaque handle. Release it with `ffi_identity_free`.
+#[unsafe(no_mangle)]
+pub extern "C" fn ffi_identity_generate(exportable: i32) -> *mut IdentityHandle {
+    guard(|| {
+        clear_last_error();
+
+        Box::into_raw(Box::new(IdentityHandle {
+            identity: NodeIdentity::generate(),
+            exportable: exportable != 0,
+        }))
+    })
+}
+
+/// Restores an identity previously exported with `ffi_identity_private_export`.
+/// # Safety
+/// - `secret_ptr` must point to a valid 64-byte array.
+///
+/// Returns an opaque handle, or null on failure. Release it with `ffi_identity_free`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_identity_import(
+    secret_ptr: *const u8, // 64 bytes: [identity_secret (32) | onion_secret (32)]
+    exportable: i32,
+) -> *mut IdentityHandle {
+    guard(|| {
+        clear_last_error();
+
+        match unsafe { raw_to_array::<IDENTITY_SECRET_SIZE>(secret_ptr, "secret_ptr") } {
+            Ok(secret) => Box::into_raw(Box::new(IdentityHandle {
+                identity: NodeIdentity::from_secret_bytes(secret),
+                exportable: exportable != 0,
+            })),
+            Err(_) => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// Writes the public keys of an identity.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_identity_generate` or `ffi_identity_import`.
+/// - `identity_key_out_ptr` and `onion_key_out_ptr` must point to valid 32-byte buffers.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_identity_public_keys(
+    handle: *const IdentityHandle,
+    identity_key_out_ptr: *mut u8, // 32 bytes (Ed25519)
+    onion_key_out_ptr: *mut u8, // 32 bytes (X25519)
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null identity handle");
+        };
+
+        let identity_key = handle.identity.identity_keypair.verifying_key();
+        let onion_key = x25519_dalek::PublicKey::from(&handle.identity.onion_secret);
+
+        let written = unsafe { write_to_buffer(identity_key_out_ptr, 32, identity_key.as_bytes()) };
+        if written < 0 {
+            return written;
+        }
+        let written = unsafe { write_to_buffer(onion_key_out_ptr, 32, onion_key.as_bytes()) };
+        if written < 0 {
+            return written;
+        }
+
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Exports the private keys of an exportable identity, so the host can persist it.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_identity_generate` or `ffi_identity_import`.
+/// - `secret_out_ptr` must point to a valid 64-byte buffer.
+///
+/// Returns `FfiStatus::Ok` on success, `Denied` if the identity was not created as exportable,
+/// or another error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_identity_private_export(
+    handle: *const IdentityHandle,
+    secret_out_ptr: *mut u8, // 64 bytes: [identity_secret (32) | onion_secret (32)]
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null identity handle");
+        };
+        if !handle.exportable {
+            return set_last_error(FfiStatus::Denied, "Identity was not created as exportable");
+        }
+
+        let written = unsafe { write_to_buffer(secret_out_ptr, IDENTITY_SECRET_SIZE, &handle.identity.to_secret_bytes()) };
+        if written < 0 {
+            return written;
+        }
+
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Signs a handshake payload with an identity handle, mirroring `NodeIdentity::sign_handshake`.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_identity_generate` or `ffi_identity_import`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least 136 bytes).
+///
+/// Returns the number of bytes written (136), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_sign_handshake(
+    handle: *const IdentityHandle,
+    timestamp: u64, // Seconds since UNIX epoch
+    output_ptr: *mut u8, // 136 bytes HandshakePayload
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null identity handle");
+        };
+
+        let payload = handle.identity.sign_handshake(timestamp);
+        unsafe { write_to_buffer(output_ptr, output_cap, &payload.to_bytes()) }
+    })
+}
+
+/// Signs a handshake payload with raw private keys, for hosts that keep their own key storage.
+/// # Safety
+/// - `secret_ptr` must point to a valid 64-byte array: [identity_secret (32) | onion_secret (32)].
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least 136 bytes).
+///
+/// Returns the number of bytes written (136), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_sign_handshake_raw(
+    secret_ptr: *const u8, // 64 bytes
+    timestamp: u64,
+    output_ptr: *mut u8, // 136 bytes HandshakePayload
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let secret = match unsafe { raw_to_array::<IDENTITY_SECRET_SIZE>(secret_ptr, "secret_ptr") } {
+            Ok(secret) => secret,
+            Err(code) => return code,
+        };
+
+        let payload: [u8; HANDSHAKE_PAYLOAD_SIZE] = NodeIdentity::from_secret_bytes(secret)
+            .sign_handshake(timestamp)
+            .to_bytes();
+        unsafe { write_to_buffer(output_ptr, output_cap, &payload) }
+    })
+}
+
+/// Releases an identity handle.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_identity_generate` or `ffi_identity_import`, or null.
+///   It must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_identity_free(handle: *mut IdentityHandle) {
+    guard(|| {
+        if !handle.is_null() {
+            drop(unsafe { Box::from_raw(handle) });
+        }
+    })
+}
@@ -0,0 +1,143 @@
+use std::ffi::c_void;
+
+use crate::cancel::CancellationToken;
+use crate::clock::unix_now;
+use crate::context::FreedomContext;
+use crate::crypto::handshake::{ self, HANDSHAKE_PAYLOAD_SIZE };
+use crate::crypto::onion;
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::status::FfiStatus;
+use super::onion::read_hop_keys;
+use super::raw_to_slice;
+
+
+// ==================================================================================
+// ASYNC EXPORTS (Completion callbacks on the worker pool)
+// ==================================================================================
+
+/// Completion callback of the `_async` exports, invoked exactly once on a worker thread.
+/// `status` is `FfiStatus::Ok` with the result in `data_ptr` / `data_len` (valid only during the call),
+/// or an error status with no data.
+pub type CompletionCallback = extern "C" fn(user_data: *mut c_void, status: i32, data_ptr: *const u8, data_len: usize);
+
+/// Host context handed back to the callback untouched. The host guarantees it stays valid until then.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Runs `work` on the context's pool and reports its outcome through `callback`.
+fn spawn_job(
+    context: &FreedomContext,
+    callback: CompletionCallback,
+    user_data: *mut c_void,
+    work: impl FnOnce() -> Result<Vec<u8>, i32> + Send + 'static
+) -> i32 {
+    let user_data = UserData(user_data);
+    let queued = context.pool().execute(move || {
+        let user_data = user_data;
+        match guard(work) {
+            Ok(data) => callback(user_data.0, FfiStatus::Ok.code(), data.as_ptr(), data.len()),
+            Err(code) => callback(user_data.0, code, std::ptr::null(), 0),
+        }
+    });
+
+    if !queued {
+        return set_last_error(FfiStatus::IoError, "Worker pool is shutting down");
+    }
+    FfiStatus::Ok.code()
+}
+
+/// Wraps a payload in one ChaCha20-Poly1305 layer per hop on the worker pool.
+/// The last hop's layer is the innermost, so each relay peels one layer with its own key.
+/// The inputs are copied before returning, so the host may release them immediately.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `keys_ptr` must point to `num_keys` consecutive 32-byte keys, ordered from the first hop to the last.
+/// - `payload_ptr` must point to a valid byte array of length `payload_len`.
+/// - `user_data` is passed back to `callback` as is.
+///
+/// Returns `FfiStatus::Ok` once the job is queued (the result arrives through `callback`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_onion_wrap_async(
+    context: *const FreedomContext,
+    keys_ptr: *const u8, // num_keys * 32 bytes
+    num_keys: usize,
+    payload_ptr: *const u8,
+    payload_len: usize,
+    callback: Option<CompletionCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        let Some(callback) = callback else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null completion callback");
+        };
+        let keys = match unsafe { read_hop_keys(keys_ptr, num_keys) } {
+            Ok(keys) => keys,
+            Err(code) => return code,
+        };
+        let payload = unsafe { raw_to_slice(payload_ptr, payload_len) }.to_vec();
+
+        spawn_job(context, callback, user_data, move || onion::wrap(&keys, &payload).map_err(report))
+    })
+}
+
+/// Validates `count` back-to-back 136-byte handshake payloads on the worker pool.
+/// The result holds one byte per payload: its `FfiStatus` code as an `i8` (0 = valid).
+/// Timestamps are checked against the context's `handshake_window_secs`.
+/// If `token` is cancelled the job stops at the next payload and completes with `FfiStatus::Cancelled`.
+/// The inputs are copied before returning, so the host may release them immediately.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `data_ptr` must point to `count * 136` bytes.
+/// - `token` must be a pointer returned by `ffi_cancellation_create`, or null for a job that can't be cancelled.
+/// - `user_data` is passed back to `callback` as is.
+///
+/// Returns `FfiStatus::Ok` once the job is queued (the result arrives through `callback`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_validate_handshakes_async(
+    context: *const FreedomContext,
+    data_ptr: *const u8, // count * 136 bytes
+    count: usize,
+    token: *const CancellationToken,
+    callback: Option<CompletionCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        let Some(callback) = callback else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null completion callback");
+        };
+        let Some(len) = count.checked_mul(HANDSHAKE_PAYLOAD_SIZE) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Batch too large");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) }.to_vec();
+        if data.len() != len {
+            return set_last_error(FfiStatus::InvalidArgument, "Null handshake data");
+        }
+        let token = unsafe { token.as_ref() }.cloned().unwrap_or_default();
+        let window_secs = context.settings().handshake_window_secs;
+
+        spawn_job(context, callback, user_data, move || {
+            let results = handshake::validate_batch(&data, unix_now(), window_secs, &token).map_err(report)?;
+            Ok(
+                results
+                    .iter()
+                    .map(|result| match result {
+                        Ok(_) => FfiStatus::Ok.code() as i8 as u8,
+                        Err(e) => FfiStatus::from(e).code() as i8 as u8,
+                    })
+                    .collect()
+            )
+        })
+    })
+}
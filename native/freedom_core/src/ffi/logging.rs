@@ -0,0 +1,93 @@
+use std::sync::{ OnceLock, RwLock };
+
+use log::{ Level, LevelFilter, Log, Metadata, Record };
+
+use super::error::{ clear_last_error, guard, set_last_error };
+use super::status::FfiStatus;
+
+
+// ==================================================================================
+// LOGGING EXPORTS (Native logs routed to the host)
+// ==================================================================================
+
+/// Host log sink. `level` uses the Microsoft.Extensions.Logging.LogLevel values
+/// (Trace = 0, Debug = 1, Information = 2, Warning = 3, Error = 4); the message is UTF-8 and only valid during the call.
+pub type LogCallback = extern "C" fn(level: i32, message_ptr: *const u8, message_len: usize);
+
+static CALLBACK: RwLock<Option<LogCallback>> = RwLock::new(None);
+static LOGGER: HostLogger = HostLogger;
+static INSTALLED: OnceLock<bool> = OnceLock::new();
+
+/// `log` backend forwarding every record to the registered callback.
+struct HostLogger;
+
+impl Log for HostLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Copied out so the callback can call back into the library (and log) without holding the lock
+        let Some(callback) = *CALLBACK.read().unwrap_or_else(|e| e.into_inner()) else {
+            return;
+        };
+
+        let message = format!("[{}] {}", record.target(), record.args());
+        callback(host_level(record.level()), message.as_ptr(), message.len());
+    }
+
+    fn flush(&self) {}
+}
+
+fn host_level(level: Level) -> i32 {
+    match level {
+        Level::Trace => 0,
+        Level::Debug => 1,
+        Level::Info => 2,
+        Level::Warn => 3,
+        Level::Error => 4,
+    }
+}
+
+fn level_filter(min_level: i32) -> LevelFilter {
+    match min_level {
+        i32::MIN..=0 => LevelFilter::Trace,
+        1 => LevelFilter::Debug,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Warn,
+        4 => LevelFilter::Error,
+        _ => LevelFilter::Off,
+    }
+}
+
+/// Changes the minimum level forwarded to the registered sink; without a sink logging stays off.
+pub(crate) fn set_min_level(min_level: i32) {
+    if CALLBACK.read().unwrap_or_else(|e| e.into_inner()).is_some() {
+        log::set_max_level(level_filter(min_level));
+    }
+}
+
+/// Registers the host log sink and the minimum level forwarded to it (same scale as the callback;
+/// anything above 4 disables logging). A null callback unregisters it.
+///
+/// Returns `FfiStatus::Ok` on success, or `InvalidArgument` if another logger already owns the process.
+#[unsafe(no_mangle)]
+pub extern "C" fn ffi_set_log_callback(callback: Option<LogCallback>, min_level: i32) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        // The logger is installed once; later calls only swap the callback
+        if !*INSTALLED.get_or_init(|| log::set_logger(&LOGGER).is_ok()) {
+            return set_last_error(FfiStatus::InvalidArgument, "Another logger is already installed");
+        }
+
+        *CALLBACK.write().unwrap_or_else(|e| e.into_inner()) = callback;
+        log::set_max_level(if callback.is_some() { level_filter(min_level) } else { LevelFilter::Off });
+
+        FfiStatus::Ok.code()
+    })
+}
@@ -0,0 +1,44 @@
+use std::ptr;
+
+use super::error::{ guard, set_last_error };
+use super::status::FfiStatus;
+
+
+// ==================================================================================
+// MEMORY EXPORTS (Rust-allocated results)
+// ==================================================================================
+
+/// Hands a result to the host as a Rust-allocated buffer, written to `out_ptr` / `out_len`.
+/// The host must release it with `ffi_free(ptr, len)`.
+/// # Safety
+/// - `out_ptr` and `out_len` must be null or point to writable locations.
+///
+/// Returns `FfiStatus::Ok`, or `InvalidArgument` if an out pointer is null (the data is dropped).
+pub(crate) unsafe fn return_owned(data: Vec<u8>, out_ptr: *mut *mut u8, out_len: *mut usize) -> i32 {
+    if out_ptr.is_null() || out_len.is_null() {
+        return set_last_error(FfiStatus::InvalidArgument, "Output pointer is null");
+    }
+
+    let boxed = data.into_boxed_slice();
+    let len = boxed.len();
+    let ptr = Box::into_raw(boxed) as *mut u8;
+
+    unsafe {
+        *out_ptr = ptr;
+        *out_len = len;
+    }
+    FfiStatus::Ok.code()
+}
+
+/// Releases a buffer returned by one of the `_alloc` exports.
+/// # Safety
+/// - `ptr` / `len` must be exactly the pair returned by an `_alloc` export, or `ptr` must be null.
+///   The buffer must not be used or freed again afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_free(ptr: *mut u8, len: usize) {
+    guard(|| {
+        if !ptr.is_null() {
+            drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)) });
+        }
+    })
+}
@@ -0,0 +1,89 @@
+// C# exports, grouped by subsystem. Every export clears the thread-local error slot on entry
+// and records a code + message on failure (see `ffi_last_error_code` / `ffi_last_error_message`).
+// Exports with variable-size output accept a null output pointer and then return the exact
+// number of bytes required, so the host can size its buffer with a first call. The `_alloc`
+// variants return a Rust-allocated buffer instead, released with `ffi_free`.
+// Every export body runs inside `error::guard`, so a panic never unwinds into the host.
+// State lives in a `FreedomContext` created by `ffi_init`; the only process-wide pieces are the
+// thread-local last error and the log callback (the `log` facade is global by design).
+pub mod cancel;
+pub mod context;
+pub mod crypto;
+pub mod dht;
+pub mod error;
+pub mod identity;
+pub mod jobs;
+pub mod logging;
+pub mod memory;
+pub mod onion;
+pub mod protocol;
+pub mod session;
+pub mod status;
+pub mod stream;
+pub mod trust;
+pub mod version;
+
+use std::slice;
+
+use status::FfiStatus;
+
+
+/// Helper to convert raw pointer and length to a byte slice.
+/// # Safety
+/// - `ptr` must be a valid pointer to a byte array of length `len`.
+unsafe fn raw_to_slice<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() || len == 0 {
+        &[]
+    } else {
+        unsafe { slice::from_raw_parts(ptr, len) }
+    }
+}
+
+/// Helper to read a fixed-size key or id from a raw pointer.
+/// Fails (with the last error set) if the pointer is null.
+/// # Safety
+/// - `ptr` must be null or point to a valid byte array of length `N`.
+unsafe fn raw_to_array<'a, const N: usize>(ptr: *const u8, name: &str) -> Result<&'a [u8; N], i32> {
+    unsafe { raw_to_slice(ptr, N) }
+        .try_into()
+        .map_err(|_| error::set_last_error(FfiStatus::InvalidArgument, format!("`{name}` must point to {N} bytes")))
+}
+
+// Helper to return a byte count, which must not wrap into the negative error statuses.
+fn length_or_error(len: usize) -> i32 {
+    i32::try_from(len).unwrap_or_else(|_| {
+        error::set_last_error(FfiStatus::InvalidArgument, format!("Output of {len} bytes exceeds i32::MAX"))
+    })
+}
+
+// Helper to write data to C# allocated buffer.
+unsafe fn write_to_buffer(ptr: *mut u8, len: usize, data: &[u8]) -> i32 {
+    if ptr.is_null() {
+        return error::set_last_error(FfiStatus::InvalidArgument, "Output buffer is null");
+    }
+    let written = length_or_error(data.len());
+    if written < 0 {
+        return written;
+    }
+    if len < data.len() {
+        return error::set_last_error(
+            FfiStatus::BufferTooSmall,
+            format!("Output buffer too small: need {} bytes, got {}", data.len(), len)
+        );
+    }
+
+    let output = unsafe { slice::from_raw_parts_mut(ptr, len) };
+    output[..data.len()].copy_from_slice(data);
+    written
+}
+
+// Helper for variable-size outputs: a null `ptr` is a size query and returns the required length.
+unsafe fn write_or_size(ptr: *mut u8, len: usize, data: &[u8]) -> i32 {
+    if ptr.is_null() {
+        return length_or_error(data.len());
+    }
+    unsafe { write_to_buffer(ptr, len, data) }
+}
+
+#[cfg(test)]
+mod tests;
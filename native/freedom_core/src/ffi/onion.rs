@@ -0,0 +1,95 @@
+use crate::crypto::onion;
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::status::FfiStatus;
+use super::{ raw_to_array, raw_to_slice, write_or_size };
+
+
+// ==================================================================================
+// ONION EXPORTS (Multi-layer wrap / peel)
+// ==================================================================================
+
+/// Reads `num_keys` consecutive 32-byte hop keys.
+/// # Safety
+/// - `keys_ptr` must be null or point to `num_keys * 32` bytes.
+pub(crate) unsafe fn read_hop_keys(keys_ptr: *const u8, num_keys: usize) -> Result<Vec<[u8; 32]>, i32> {
+    let Some(total) = num_keys.checked_mul(32) else {
+        return Err(set_last_error(FfiStatus::InvalidArgument, "Too many hop keys"));
+    };
+    if num_keys == 0 || keys_ptr.is_null() {
+        return Err(set_last_error(FfiStatus::InvalidArgument, "At least one hop key is required"));
+    }
+
+    Ok(
+        unsafe { raw_to_slice(keys_ptr, total) }
+            .chunks_exact(32)
+            .map(|key| key.try_into().expect("chunks of 32 bytes"))
+            .collect()
+    )
+}
+
+/// Wraps a payload in one ChaCha20-Poly1305 layer per hop, in a single call.
+/// The last hop's layer is the innermost; each layer adds 28 bytes ([Nonce (12)] + [Tag (16)]).
+/// # Safety
+/// - `keys_ptr` must point to `num_keys` consecutive 32-byte keys, ordered from the first hop to the last.
+/// - `payload_ptr` must point to a valid byte array of length `payload_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least `payload_len` + 28 * `num_keys`),
+///   or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_onion_wrap(
+    keys_ptr: *const u8, // num_keys * 32 bytes
+    num_keys: usize,
+    payload_ptr: *const u8,
+    payload_len: usize,
+    output_ptr: *mut u8,
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let keys = match unsafe { read_hop_keys(keys_ptr, num_keys) } {
+            Ok(keys) => keys,
+            Err(code) => return code,
+        };
+        let payload = unsafe { raw_to_slice(payload_ptr, payload_len) };
+
+        match onion::wrap(&keys, payload) {
+            Ok(cell) => unsafe { write_or_size(output_ptr, output_cap, &cell) },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Removes one layer from a cell with this hop's key.
+/// # Safety
+/// - `key_ptr` must point to a valid 32-byte array.
+/// - `cell_ptr` must point to a valid byte array of length `cell_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least `cell_len` - 28),
+///   or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_onion_peel(
+    key_ptr: *const u8, // 32 bytes
+    cell_ptr: *const u8,
+    cell_len: usize,
+    output_ptr: *mut u8,
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let key = match unsafe { raw_to_array::<32>(key_ptr, "key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+        let cell = unsafe { raw_to_slice(cell_ptr, cell_len) };
+
+        match onion::peel(key, cell) {
+            Ok(inner) => unsafe { write_or_size(output_ptr, output_cap, &inner) },
+            Err(e) => report(e),
+        }
+    })
+}
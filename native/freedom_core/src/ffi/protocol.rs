@@ -0,0 +1,310 @@
+use crate::context::FreedomContext;
+use crate::protocol::header::{ FixedHeader, MessageType };
+use crate::protocol::packet::{ NetworkPacket, PacketError, DEFAULT_MAX_PAYLOAD_LEN };
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::memory::return_owned;
+use super::status::FfiStatus;
+use super::{ raw_to_slice, write_or_size };
+
+
+/// C-compatible mirror of `FixedHeader`, filled by the parse exports. Integers are in host byte order.
+/// C# Reference: FalconNode.Core.Network.FixedHeader (sequential, 16 bytes, no padding)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FfiFixedHeader {
+    pub version: u8,
+    pub flags: u8,
+    pub message_type: u8,
+    pub reserved: u8,
+    pub request_id: u32,
+    pub payload_length: u32,
+    pub checksum: u32,
+}
+
+impl From<&FixedHeader> for FfiFixedHeader {
+    fn from(header: &FixedHeader) -> Self {
+        Self {
+            version: header.version,
+            flags: header.flags,
+            message_type: header.message_type as u8,
+            reserved: header.reserved,
+            request_id: header.request_id,
+            payload_length: header.payload_length,
+            checksum: header.checksum,
+        }
+    }
+}
+
+
+// ==================================================================================
+// PROTOCOL EXPORTS (CRC32 Check)
+// ==================================================================================
+
+/// Calculates CRC32 for a byte array using the fast hardware implementation.
+/// # Safety
+/// - `data_ptr` must point to a valid byte array of length `len`.
+///
+/// Returns the CRC32 checksum.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_calculate_crc32(
+    data_ptr: *const u8,
+    len: usize,
+) -> u32 {
+    guard(|| {
+        clear_last_error();
+
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
+    })
+}
+
+/// Validates the build arguments shared by the packet build exports.
+/// # Safety
+/// - `payload_ptr` must point to a valid byte array of length `payload_len`.
+unsafe fn build_packet(
+    message_type: u8,
+    request_id: u32,
+    payload_ptr: *const u8,
+    payload_len: usize,
+) -> Result<NetworkPacket, i32> {
+    let message_type = MessageType::from(message_type);
+    if message_type == MessageType::Unknown {
+        return Err(set_last_error(FfiStatus::InvalidArgument, "Unknown message type"));
+    }
+    if u32::try_from(payload_len).is_err() {
+        return Err(set_last_error(FfiStatus::InvalidArgument, "Payload too large"));
+    }
+
+    let payload = unsafe { raw_to_slice(payload_ptr, payload_len) };
+    Ok(NetworkPacket::new(message_type, request_id, payload.to_vec()))
+}
+
+/// Refuses a parsed header whose message type this library doesn't know: the mirror can only
+/// carry it as `MessageType::Unknown` (0xFF), which the host can't tell from a real type byte.
+fn check_known_type(header: &FixedHeader, data: &[u8]) -> Result<(), i32> {
+    if header.message_type == MessageType::Unknown {
+        return Err(set_last_error(FfiStatus::UnknownMessageType, format!("Unknown message type {:#04x}", data[2])));
+    }
+    Ok(())
+}
+
+/// Builds a wire frame (Header + Payload) with the canonical header layout and CRC32.
+/// # Safety
+/// - `payload_ptr` must point to a valid byte array of length `payload_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least 16 + `payload_len`),
+///   or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_packet_build(
+    message_type: u8,
+    request_id: u32,
+    payload_ptr: *const u8,
+    payload_len: usize,
+    output_ptr: *mut u8, // Buffer to write the frame
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let packet = match unsafe { build_packet(message_type, request_id, payload_ptr, payload_len) } {
+            Ok(packet) => packet,
+            Err(code) => return code,
+        };
+
+        unsafe { write_or_size(output_ptr, output_cap, &packet.to_bytes()) }
+    })
+}
+
+/// Builds a wire frame (Header + Payload) into a Rust-allocated buffer.
+/// # Safety
+/// - `payload_ptr` must point to a valid byte array of length `payload_len`.
+/// - `out_ptr` and `out_len` must point to writable locations. The result must be released with `ffi_free`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_packet_build_alloc(
+    message_type: u8,
+    request_id: u32,
+    payload_ptr: *const u8,
+    payload_len: usize,
+    out_ptr: *mut *mut u8, // Receives the frame
+    out_len: *mut usize, // Receives its length
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let packet = match unsafe { build_packet(message_type, request_id, payload_ptr, payload_len) } {
+            Ok(packet) => packet,
+            Err(code) => return code,
+        };
+
+        unsafe { return_owned(packet.to_bytes(), out_ptr, out_len) }
+    })
+}
+
+/// Parses and validates (length + CRC32) a wire frame, refusing payloads over the default
+/// `DEFAULT_MAX_PAYLOAD_LEN`. Hosts that created a context should call `ffi_packet_parse_limited`,
+/// which honours its `max_payload_len`.
+/// # Safety
+/// - `data_ptr` must point to a valid byte array of length `len`.
+/// - `header_out` must point to a valid `FfiFixedHeader`.
+/// - `payload_out_ptr` must point to a valid buffer with capacity `payload_out_cap`, or be null to query the length.
+///
+/// Returns the number of payload bytes written to `payload_out_ptr` (or required, for a null `payload_out_ptr`),
+/// or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_packet_parse(
+    data_ptr: *const u8,
+    len: usize,
+    header_out: *mut FfiFixedHeader,
+    payload_out_ptr: *mut u8,
+    payload_out_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(header_out) = (unsafe { header_out.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null header output");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+
+        unsafe { parse_packet(data, DEFAULT_MAX_PAYLOAD_LEN, header_out, payload_out_ptr, payload_out_cap) }
+    })
+}
+
+/// Same as `ffi_packet_parse`, but rejects frames whose header announces more than the context's
+/// `max_payload_len` before reading the payload.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `data_ptr` must point to a valid byte array of length `len`.
+/// - `header_out` must point to a valid `FfiFixedHeader`.
+/// - `payload_out_ptr` must point to a valid buffer with capacity `payload_out_cap`, or be null to query the length.
+///
+/// Returns the number of payload bytes written to `payload_out_ptr` (or required, for a null `payload_out_ptr`),
+/// or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_packet_parse_limited(
+    context: *const FreedomContext,
+    data_ptr: *const u8,
+    len: usize,
+    header_out: *mut FfiFixedHeader,
+    payload_out_ptr: *mut u8,
+    payload_out_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        let Some(header_out) = (unsafe { header_out.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null header output");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+
+        let max_payload_len = context.settings().max_payload_len;
+        unsafe { parse_packet(data, max_payload_len, header_out, payload_out_ptr, payload_out_cap) }
+    })
+}
+
+/// The body of the packet parse exports.
+/// # Safety
+/// - `payload_out_ptr` must point to a valid buffer with capacity `payload_out_cap`, or be null.
+unsafe fn parse_packet(
+    data: &[u8],
+    max_payload_len: usize,
+    header_out: &mut FfiFixedHeader,
+    payload_out_ptr: *mut u8,
+    payload_out_cap: usize,
+) -> i32 {
+    match NetworkPacket::from_bytes_limited(data, max_payload_len) {
+        Ok(packet) => {
+            if let Err(code) = check_known_type(&packet.header, data) {
+                return code;
+            }
+            let written = unsafe { write_or_size(payload_out_ptr, payload_out_cap, &packet.payload) };
+            if written >= 0 {
+                *header_out = FfiFixedHeader::from(&packet.header);
+            }
+            written
+        },
+        Err(e) => report(e),
+    }
+}
+
+/// Parses only the 16-byte header at the start of `data`, so the host can size the payload read.
+/// Does not check the payload checksum; `ffi_packet_parse` does that once the full frame is available.
+/// A message type this library doesn't know is refused with `FfiStatus::UnknownMessageType`, and a
+/// payload length over the default `DEFAULT_MAX_PAYLOAD_LEN` with `FfiStatus::ParseError`; hosts
+/// that created a context should call `ffi_header_parse_limited`, which honours its `max_payload_len`.
+/// # Safety
+/// - `data_ptr` must point to a valid byte array of length `len` (at least 16).
+/// - `header_out` must point to a valid `FfiFixedHeader`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_header_parse(
+    data_ptr: *const u8,
+    len: usize,
+    header_out: *mut FfiFixedHeader,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(header_out) = (unsafe { header_out.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null header output");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+
+        parse_header(data, DEFAULT_MAX_PAYLOAD_LEN, header_out)
+    })
+}
+
+/// Same as `ffi_header_parse`, but checks the payload length against the context's `max_payload_len`.
+/// # Safety
+/// - `context` must be a pointer returned by `ffi_init`.
+/// - `data_ptr` must point to a valid byte array of length `len` (at least 16).
+/// - `header_out` must point to a valid `FfiFixedHeader`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_header_parse_limited(
+    context: *const FreedomContext,
+    data_ptr: *const u8,
+    len: usize,
+    header_out: *mut FfiFixedHeader,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(context) = (unsafe { context.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null context");
+        };
+        let Some(header_out) = (unsafe { header_out.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null header output");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, len) };
+
+        parse_header(data, context.settings().max_payload_len, header_out)
+    })
+}
+
+/// The body of the header parse exports
+fn parse_header(data: &[u8], max_payload_len: usize, header_out: &mut FfiFixedHeader) -> i32 {
+    let header = match FixedHeader::from_bytes(data) {
+        Ok(header) => header,
+        Err(e) => return report(e),
+    };
+    if let Err(code) = check_known_type(&header, data) {
+        return code;
+    }
+    if header.payload_length as usize > max_payload_len {
+        return report(PacketError::PayloadTooLarge { len: header.payload_length, max: max_payload_len });
+    }
+    *header_out = FfiFixedHeader::from(&header);
+    FfiStatus::Ok.code()
+}
@@ -0,0 +1,150 @@
+use crate::crypto::session::{ Session, SESSION_OVERHEAD, SESSION_SALT_SIZE };
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::status::FfiStatus;
+use super::{ length_or_error, raw_to_array, raw_to_slice, write_to_buffer };
+
+
+// ==================================================================================
+// SESSION EXPORTS (Keys and nonce counters stay in Rust)
+// ==================================================================================
+
+/// Derives a session with a peer from X25519 keys and a salt from each side. The derived keys never
+/// cross the boundary. Each side fills its salt with `ffi_random_bytes` for every new session and sends
+/// it to the other; reusing a salt with the same keys reuses the session keys and nonces.
+/// # Safety
+/// - `my_private_key_ptr` and `peer_public_key_ptr` must point to valid 32-byte arrays.
+/// - `my_salt_ptr` and `peer_salt_ptr` must point to valid 32-byte arrays.
+///
+/// Returns an opaque handle, or null on failure. Release it with `ffi_session_destroy`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_session_create(
+    my_private_key_ptr: *const u8, // 32 bytes
+    peer_public_key_ptr: *const u8, // 32 bytes
+    my_salt_ptr: *const u8, // 32 bytes
+    peer_salt_ptr: *const u8, // 32 bytes
+) -> *mut Session {
+    guard(|| {
+        clear_last_error();
+
+        let my_private_bytes = match unsafe { raw_to_array::<32>(my_private_key_ptr, "my_private_key_ptr") } {
+            Ok(bytes) => bytes,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let peer_public_bytes = match unsafe { raw_to_array::<32>(peer_public_key_ptr, "peer_public_key_ptr") } {
+            Ok(bytes) => bytes,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let my_salt = match unsafe { raw_to_array::<SESSION_SALT_SIZE>(my_salt_ptr, "my_salt_ptr") } {
+            Ok(bytes) => bytes,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        let peer_salt = match unsafe { raw_to_array::<SESSION_SALT_SIZE>(peer_salt_ptr, "peer_salt_ptr") } {
+            Ok(bytes) => bytes,
+            Err(_) => return std::ptr::null_mut(),
+        };
+
+        let my_secret = x25519_dalek::StaticSecret::from(*my_private_bytes);
+        let peer_public = x25519_dalek::PublicKey::from(*peer_public_bytes);
+
+        Box::into_raw(Box::new(Session::new(&my_secret, &peer_public, my_salt, peer_salt)))
+    })
+}
+
+/// Encrypts the next message of a session.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_session_create`, not used concurrently from another thread.
+/// - `plaintext_ptr` must point to a valid byte array of length `plaintext_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least `plaintext_len` + 24),
+///   or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_session_encrypt(
+    handle: *mut Session,
+    plaintext_ptr: *const u8,
+    plaintext_len: usize,
+    output_ptr: *mut u8,
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(session) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null session handle");
+        };
+        let plaintext = unsafe { raw_to_slice(plaintext_ptr, plaintext_len) };
+
+        // Size checks happen before encrypting, so a size query or a short buffer doesn't consume a nonce
+        let required = plaintext.len() + SESSION_OVERHEAD;
+        if output_ptr.is_null() {
+            return length_or_error(required);
+        }
+        if output_cap < required {
+            return set_last_error(
+                FfiStatus::BufferTooSmall,
+                format!("Output buffer too small: need {required} bytes, got {output_cap}")
+            );
+        }
+
+        match session.encrypt(plaintext) {
+            Ok(encrypted_data) => unsafe { write_to_buffer(output_ptr, output_cap, &encrypted_data) },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Decrypts a message received on a session. Replayed messages fail with `CryptoError`.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_session_create`, not used concurrently from another thread.
+/// - `ciphertext_ptr` must point to a valid byte array of length `ciphertext_len`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap` (at least `ciphertext_len` - 24),
+///   or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or required, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_session_decrypt(
+    handle: *mut Session,
+    ciphertext_ptr: *const u8,
+    ciphertext_len: usize,
+    output_ptr: *mut u8,
+    output_cap: usize,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(session) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null session handle");
+        };
+        let ciphertext = unsafe { raw_to_slice(ciphertext_ptr, ciphertext_len) };
+
+        // Same for decryption: an accepted message advances the replay window, so it must not be lost
+        let required = ciphertext.len().saturating_sub(SESSION_OVERHEAD);
+        if output_ptr.is_null() {
+            return length_or_error(required);
+        }
+        if output_cap < required {
+            return set_last_error(
+                FfiStatus::BufferTooSmall,
+                format!("Output buffer too small: need {required} bytes, got {output_cap}")
+            );
+        }
+
+        match session.decrypt(ciphertext) {
+            Ok(decrypted_data) => unsafe { write_to_buffer(output_ptr, output_cap, &decrypted_data) },
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Destroys a session and releases its keys.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_session_create`, or null. It must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_session_destroy(handle: *mut Session) {
+    guard(|| {
+        if !handle.is_null() {
+            drop(unsafe { Box::from_raw(handle) });
+        }
+    })
+}
@@ -0,0 +1,153 @@
+use crate::cancel::Cancelled;
+use crate::context::ConfigError;
+use crate::crypto::handshake::HandshakeError;
+use crate::crypto::helper::CryptoError;
+use crate::crypto::identity::IdentityError;
+use crate::crypto::trust::TrustError;
+use crate::onion::address::AddressError;
+use crate::onion::e2e::E2eError;
+use crate::protocol::header::HeaderError;
+use crate::protocol::packet::PacketError;
+use std::io;
+
+/// Status codes shared by every export.
+/// Exports returning a length or count use non-negative values for success and these codes for failure;
+/// every other export returns `Ok` on success.
+/// C# Reference: FalconNode.Core.Interop.FfiStatus (keep both in sync)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum FfiStatus {
+    Ok = 0,
+    InvalidArgument = -1,
+    BufferTooSmall = -2,
+    CryptoError = -3,
+    ParseError = -4,
+    VerificationFailed = -5,
+    KeyChanged = -6,
+    Denied = -7,
+    IoError = -8,
+    InvalidSize = -9,
+    InvalidKey = -10,
+    InvalidSignature = -11,
+    StaleTimestamp = -12,
+    Cancelled = -13,
+    UnknownMessageType = -14,
+    Panic = -99,
+}
+
+impl FfiStatus {
+    pub const fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Maps a raw code back to a status, for hosts and tests reading `ffi_last_error_code`
+    pub fn from_code(code: i32) -> Option<Self> {
+        [
+            FfiStatus::Ok,
+            FfiStatus::InvalidArgument,
+            FfiStatus::BufferTooSmall,
+            FfiStatus::CryptoError,
+            FfiStatus::ParseError,
+            FfiStatus::VerificationFailed,
+            FfiStatus::KeyChanged,
+            FfiStatus::Denied,
+            FfiStatus::IoError,
+            FfiStatus::InvalidSize,
+            FfiStatus::InvalidKey,
+            FfiStatus::InvalidSignature,
+            FfiStatus::StaleTimestamp,
+            FfiStatus::Cancelled,
+            FfiStatus::UnknownMessageType,
+            FfiStatus::Panic,
+        ]
+            .into_iter()
+            .find(|status| status.code() == code)
+    }
+}
+
+impl From<&HandshakeError> for FfiStatus {
+    fn from(error: &HandshakeError) -> Self {
+        match error {
+            HandshakeError::InvalidSize { .. } => FfiStatus::InvalidSize,
+            HandshakeError::InvalidIdentityKey | HandshakeError::InvalidOnionKey => FfiStatus::InvalidKey,
+            HandshakeError::InvalidSignature => FfiStatus::InvalidSignature,
+            HandshakeError::VerificationFailed => FfiStatus::VerificationFailed,
+            HandshakeError::StaleTimestamp { .. } => FfiStatus::StaleTimestamp,
+        }
+    }
+}
+
+impl From<&CryptoError> for FfiStatus {
+    fn from(error: &CryptoError) -> Self {
+        match error {
+            CryptoError::InvalidLength => FfiStatus::InvalidArgument,
+            _ => FfiStatus::CryptoError,
+        }
+    }
+}
+
+impl From<&IdentityError> for FfiStatus {
+    fn from(error: &IdentityError) -> Self {
+        match error {
+            IdentityError::InvalidIdentityKey => FfiStatus::ParseError,
+            IdentityError::VerificationFailed => FfiStatus::VerificationFailed,
+        }
+    }
+}
+
+impl From<&TrustError> for FfiStatus {
+    fn from(error: &TrustError) -> Self {
+        match error {
+            TrustError::KeyChanged(_) => FfiStatus::KeyChanged,
+            TrustError::Denied => FfiStatus::Denied,
+            TrustError::Malformed(_) => FfiStatus::ParseError,
+            TrustError::Io(_) => FfiStatus::IoError,
+        }
+    }
+}
+
+impl From<&AddressError> for FfiStatus {
+    fn from(_: &AddressError) -> Self {
+        FfiStatus::ParseError
+    }
+}
+
+impl From<&E2eError> for FfiStatus {
+    fn from(error: &E2eError) -> Self {
+        match error {
+            E2eError::InvalidSignature => FfiStatus::InvalidSignature,
+            E2eError::RecordTooLarge(_) => FfiStatus::ParseError,
+            E2eError::Crypto(e) => FfiStatus::from(e),
+        }
+    }
+}
+
+impl From<&Cancelled> for FfiStatus {
+    fn from(_: &Cancelled) -> Self {
+        FfiStatus::Cancelled
+    }
+}
+
+impl From<&ConfigError> for FfiStatus {
+    fn from(_: &ConfigError) -> Self {
+        FfiStatus::InvalidArgument
+    }
+}
+
+impl From<&HeaderError> for FfiStatus {
+    fn from(_: &HeaderError) -> Self {
+        FfiStatus::ParseError
+    }
+}
+
+impl From<&PacketError> for FfiStatus {
+    fn from(_: &PacketError) -> Self {
+        FfiStatus::ParseError
+    }
+}
+
+impl From<&io::Error> for FfiStatus {
+    fn from(_: &io::Error) -> Self {
+        FfiStatus::IoError
+    }
+}
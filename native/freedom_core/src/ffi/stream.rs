@@ -0,0 +1,186 @@
+use std::str::FromStr;
+
+use crate::onion::address::OnionAddress;
+use crate::onion::e2e::FreedomStream;
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::identity::IdentityHandle;
+use super::status::FfiStatus;
+use super::{ length_or_error, raw_to_slice, write_to_buffer };
+
+
+// ==================================================================================
+// STREAM EXPORTS (End-to-end encryption of hidden service streams)
+// ==================================================================================
+
+// The host moves bytes between the stream of its circuit and the handle like a socket: what it
+// reads goes to `ffi_stream_on_data`, what `ffi_stream_take_outgoing` returns it writes. The
+// handshake keys and the session never cross the boundary.
+
+/// Starts a stream to the hidden service at `address` (text, e.g. "….freedom").
+/// # Safety
+/// - `address_ptr` must point to a valid UTF-8 string of length `address_len`.
+///
+/// Returns an opaque handle, or null on failure. Release it with `ffi_stream_destroy`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_connect(address_ptr: *const u8, address_len: usize) -> *mut FreedomStream {
+    guard(|| {
+        clear_last_error();
+
+        let Ok(text) = std::str::from_utf8(unsafe { raw_to_slice(address_ptr, address_len) }) else {
+            set_last_error(FfiStatus::ParseError, "Address is not valid UTF-8");
+            return std::ptr::null_mut();
+        };
+        match OnionAddress::from_str(text) {
+            Ok(address) => Box::into_raw(Box::new(FreedomStream::connect(&address))),
+            Err(e) => {
+                report(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Accepts a stream to our hidden service, whose address is the identity key of `identity`.
+/// # Safety
+/// - `identity` must be a pointer returned by `ffi_identity_generate` or `ffi_identity_import`.
+///
+/// Returns an opaque handle, or null on failure. Release it with `ffi_stream_destroy`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_accept(identity: *const IdentityHandle) -> *mut FreedomStream {
+    guard(|| {
+        clear_last_error();
+
+        let Some(identity) = (unsafe { identity.as_ref() }) else {
+            set_last_error(FfiStatus::InvalidArgument, "Null identity handle");
+            return std::ptr::null_mut();
+        };
+        Box::into_raw(Box::new(FreedomStream::accept(&identity.identity.identity_keypair)))
+    })
+}
+
+/// Feeds bytes read from the circuit stream. Any error is fatal: destroy the handle and close the stream.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`.
+/// - `data_ptr` must point to a valid byte array of length `data_len`.
+///
+/// Returns the number of decrypted bytes ready for `ffi_stream_read`, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_on_data(handle: *mut FreedomStream, data_ptr: *const u8, data_len: usize) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(stream) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null stream handle");
+        };
+        match stream.on_data(unsafe { raw_to_slice(data_ptr, data_len) }) {
+            Ok(readable) => readable.min(i32::MAX as usize) as i32,
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Moves decrypted bytes into `output_ptr`.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap`.
+///
+/// Returns the number of bytes read (0 if none are ready), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_read(handle: *mut FreedomStream, output_ptr: *mut u8, output_cap: usize) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(stream) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null stream handle");
+        };
+        if output_ptr.is_null() {
+            return set_last_error(FfiStatus::InvalidArgument, "Output buffer is null");
+        }
+        let output = unsafe { std::slice::from_raw_parts_mut(output_ptr, output_cap.min(i32::MAX as usize)) };
+        stream.read(output) as i32
+    })
+}
+
+/// Encrypts data for the other end; the records are collected with `ffi_stream_take_outgoing`.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`.
+/// - `data_ptr` must point to a valid byte array of length `data_len`.
+///
+/// Returns the number of bytes accepted (0 until the handshake is done), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_write(handle: *mut FreedomStream, data_ptr: *const u8, data_len: usize) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(stream) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null stream handle");
+        };
+        let data = unsafe { raw_to_slice(data_ptr, data_len.min(i32::MAX as usize)) };
+        match stream.write(data) {
+            Ok(accepted) => accepted as i32,
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Drains the bytes to write on the circuit stream: the handshake, then records.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`.
+/// - `output_ptr` must point to a valid buffer with capacity `output_cap`, or be null to query the length.
+///
+/// Returns the number of bytes written to `output_ptr` (or waiting, for a null `output_ptr`), or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_take_outgoing(handle: *mut FreedomStream, output_ptr: *mut u8, output_cap: usize) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(stream) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null stream handle");
+        };
+
+        // Checked before draining, so a size query or a short buffer doesn't lose records
+        let required = stream.outgoing_len();
+        if output_ptr.is_null() {
+            return length_or_error(required);
+        }
+        if output_cap < required {
+            return set_last_error(
+                FfiStatus::BufferTooSmall,
+                format!("Output buffer too small: need {required} bytes, got {output_cap}")
+            );
+        }
+
+        unsafe { write_to_buffer(output_ptr, output_cap, &stream.take_outgoing()) }
+    })
+}
+
+/// Whether the handshake is done, so `ffi_stream_write` accepts data.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`.
+///
+/// Returns 1 if established, 0 if not, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_is_established(handle: *const FreedomStream) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(stream) = (unsafe { handle.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null stream handle");
+        };
+        i32::from(stream.is_established())
+    })
+}
+
+/// Destroys a stream and releases its keys.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_stream_connect` or `ffi_stream_accept`, or null.
+///   It must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_stream_destroy(handle: *mut FreedomStream) {
+    guard(|| {
+        if !handle.is_null() {
+            drop(unsafe { Box::from_raw(handle) });
+        }
+    })
+}
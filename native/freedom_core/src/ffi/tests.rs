@@ -0,0 +1,693 @@
+use crate::clock::unix_now;
+use super::crypto::{ ffi_decrypt_layer, ffi_decrypt_layer_alloc, ffi_encrypt_layer, ffi_encrypt_layer_alloc, ffi_validate_handshake };
+use super::error::{ ffi_last_error_code, ffi_last_error_message, guard };
+use super::identity::{ ffi_identity_free, ffi_identity_generate, ffi_identity_import, ffi_identity_private_export, ffi_identity_public_keys, ffi_sign_handshake, ffi_sign_handshake_raw };
+use super::memory::ffi_free;
+use super::protocol::{ ffi_header_parse, ffi_packet_build, ffi_packet_parse, FfiFixedHeader };
+use super::status::FfiStatus;
+
+/// Reads the thread-local error message through the exported API
+fn last_error_message() -> String {
+    let mut buffer = [0u8; 256];
+    let written = unsafe { ffi_last_error_message(buffer.as_mut_ptr(), buffer.len()) };
+    assert!(written >= 0, "Error message did not fit in the buffer");
+    String::from_utf8(buffer[..written as usize].to_vec()).unwrap()
+}
+
+/// Unit test: Failed decryption leaves a code and message, success clears them
+#[test]
+fn test_last_error_reports_decryption_failure() {
+    let key = [7u8; 32];
+    let wrong_key = [8u8; 32];
+    let plaintext = b"hello relay";
+
+    let mut ciphertext = [0u8; 64];
+    let written = unsafe {
+        ffi_encrypt_layer(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), ciphertext.as_mut_ptr(), ciphertext.len())
+    };
+    assert_eq!(written, (plaintext.len() + 28) as i32);
+    assert_eq!(ffi_last_error_code(), 0);
+
+    let mut output = [0u8; 64];
+    let result = unsafe {
+        ffi_decrypt_layer(wrong_key.as_ptr(), ciphertext.as_ptr(), written as usize, output.as_mut_ptr(), output.len())
+    };
+    assert_eq!(result, FfiStatus::CryptoError.code());
+    assert_eq!(FfiStatus::from_code(ffi_last_error_code()), Some(FfiStatus::CryptoError));
+    assert_eq!(last_error_message(), "Decryption failed");
+
+    // A null key is reported as an invalid key
+    let result = unsafe {
+        ffi_decrypt_layer(std::ptr::null(), ciphertext.as_ptr(), written as usize, output.as_mut_ptr(), output.len())
+    };
+    assert_eq!(result, FfiStatus::InvalidArgument.code());
+
+    // Too small output buffer
+    let result = unsafe {
+        ffi_decrypt_layer(key.as_ptr(), ciphertext.as_ptr(), written as usize, output.as_mut_ptr(), 4)
+    };
+    assert_eq!(result, FfiStatus::BufferTooSmall.code());
+
+    // The next successful call clears the slot
+    let result = unsafe {
+        ffi_decrypt_layer(key.as_ptr(), ciphertext.as_ptr(), written as usize, output.as_mut_ptr(), output.len())
+    };
+    assert_eq!(&output[..result as usize], plaintext);
+    assert_eq!(ffi_last_error_code(), 0);
+    assert_eq!(last_error_message(), "");
+}
+
+/// Unit test: Identity generation, opt-in export and re-import
+#[test]
+fn test_identity_generate_export_import() {
+    let locked = ffi_identity_generate(0);
+    let exportable = ffi_identity_generate(1);
+
+    let mut secret = [0u8; 64];
+    assert_eq!(unsafe { ffi_identity_private_export(locked, secret.as_mut_ptr()) }, FfiStatus::Denied.code());
+    assert_eq!(unsafe { ffi_identity_private_export(exportable, secret.as_mut_ptr()) }, FfiStatus::Ok.code());
+
+    let (mut identity_key, mut onion_key) = ([0u8; 32], [0u8; 32]);
+    assert_eq!(
+        unsafe { ffi_identity_public_keys(exportable, identity_key.as_mut_ptr(), onion_key.as_mut_ptr()) },
+        FfiStatus::Ok.code()
+    );
+
+    // The restored identity has the same public keys
+    let restored = unsafe { ffi_identity_import(secret.as_ptr(), 0) };
+    let (mut restored_identity, mut restored_onion) = ([0u8; 32], [0u8; 32]);
+    unsafe { ffi_identity_public_keys(restored, restored_identity.as_mut_ptr(), restored_onion.as_mut_ptr()) };
+    assert_eq!(identity_key, restored_identity);
+    assert_eq!(onion_key, restored_onion);
+
+    unsafe {
+        ffi_identity_free(locked);
+        ffi_identity_free(exportable);
+        ffi_identity_free(restored);
+    }
+}
+
+/// Integration test: Handshake signed through the FFI validates through the FFI
+#[test]
+fn test_sign_handshake_roundtrip() {
+    let identity = ffi_identity_generate(1);
+    let now = unix_now();
+
+    let mut payload = [0u8; 136];
+    let written = unsafe { ffi_sign_handshake(identity, now, payload.as_mut_ptr(), payload.len()) };
+    assert_eq!(written, 136);
+    assert_eq!(unsafe { ffi_validate_handshake(payload.as_ptr(), payload.len()) }, FfiStatus::Ok.code());
+
+    // Raw-key variant produces a payload for the same identity key
+    let mut secret = [0u8; 64];
+    unsafe { ffi_identity_private_export(identity, secret.as_mut_ptr()) };
+    let mut raw_payload = [0u8; 136];
+    let written = unsafe { ffi_sign_handshake_raw(secret.as_ptr(), now, raw_payload.as_mut_ptr(), raw_payload.len()) };
+    assert_eq!(written, 136);
+    assert_eq!(raw_payload[..72], payload[..72]);
+
+    // Too small output
+    let mut small = [0u8; 64];
+    assert_eq!(
+        unsafe { ffi_sign_handshake(identity, 1700000000, small.as_mut_ptr(), small.len()) },
+        FfiStatus::BufferTooSmall.code()
+    );
+
+    unsafe { ffi_identity_free(identity) };
+}
+
+/// Unit test: Each kind of bad handshake gets its own status code
+#[test]
+fn test_validate_handshake_detailed_codes() {
+    let identity = ffi_identity_generate(0);
+    let validate = |payload: &[u8]| unsafe { ffi_validate_handshake(payload.as_ptr(), payload.len()) };
+
+    let mut payload = [0u8; 136];
+    unsafe { ffi_sign_handshake(identity, unix_now(), payload.as_mut_ptr(), payload.len()) };
+    assert_eq!(validate(&payload), FfiStatus::Ok.code());
+
+    assert_eq!(validate(&payload[..100]), FfiStatus::InvalidSize.code());
+
+    // y = 2 is not on the curve
+    let mut bad_key = payload;
+    bad_key[..32].copy_from_slice(&[0x02; 32]);
+    assert_eq!(validate(&bad_key), FfiStatus::InvalidKey.code());
+
+    let mut bad_encoding = payload;
+    bad_encoding[135] |= 0xe0;
+    assert_eq!(validate(&bad_encoding), FfiStatus::InvalidSignature.code());
+
+    let mut tampered = payload;
+    tampered[40] ^= 0x01;
+    assert_eq!(validate(&tampered), FfiStatus::VerificationFailed.code());
+
+    let mut stale = [0u8; 136];
+    unsafe { ffi_sign_handshake(identity, unix_now() - 3600, stale.as_mut_ptr(), stale.len()) };
+    assert_eq!(validate(&stale), FfiStatus::StaleTimestamp.code());
+    assert_eq!(ffi_last_error_code(), FfiStatus::StaleTimestamp.code());
+
+    unsafe { ffi_identity_free(identity) };
+}
+
+/// Integration test: Frame built through the FFI parses back with the same header
+#[test]
+fn test_packet_build_and_parse() {
+    let payload = b"find node please";
+
+    let mut frame = [0u8; 64];
+    let written = unsafe {
+        ffi_packet_build(0x03, 77, payload.as_ptr(), payload.len(), frame.as_mut_ptr(), frame.len())
+    };
+    assert_eq!(written, (16 + payload.len()) as i32);
+
+    let mut header = FfiFixedHeader::default();
+    let mut parsed = [0u8; 64];
+    let parsed_len = unsafe {
+        ffi_packet_parse(frame.as_ptr(), written as usize, &mut header, parsed.as_mut_ptr(), parsed.len())
+    };
+    assert_eq!(&parsed[..parsed_len as usize], payload);
+    assert_eq!(header.message_type, 0x03);
+    assert_eq!(header.request_id, 77);
+    assert_eq!(header.payload_length, payload.len() as u32);
+
+    // Corrupted payload fails the CRC check
+    frame[20] ^= 0xFF;
+    let result = unsafe {
+        ffi_packet_parse(frame.as_ptr(), written as usize, &mut header, parsed.as_mut_ptr(), parsed.len())
+    };
+    assert_eq!(result, FfiStatus::ParseError.code());
+}
+
+/// Unit test: Header-only parse fills the mirror struct without needing the payload
+#[test]
+fn test_header_parse_fills_struct() {
+    use crate::protocol::header::MessageType;
+    use crate::protocol::packet::NetworkPacket;
+
+    assert_eq!(std::mem::size_of::<FfiFixedHeader>(), 16);
+
+    let frame = NetworkPacket::new(MessageType::Store, 0xdead_beef, b"0123456789".to_vec()).to_bytes();
+
+    let mut header = FfiFixedHeader::default();
+    assert_eq!(unsafe { ffi_header_parse(frame.as_ptr(), 16, &mut header) }, FfiStatus::Ok.code());
+    assert_eq!(header.version, 1);
+    assert_eq!(header.message_type, MessageType::Store as u8);
+    assert_eq!(header.request_id, 0xdead_beef);
+    assert_eq!(header.payload_length, 10);
+
+    assert_eq!(unsafe { ffi_header_parse(frame.as_ptr(), 15, &mut header) }, FfiStatus::ParseError.code());
+
+    // A type byte this library doesn't know is refused, not reported as 0xFF
+    let mut unknown = frame.clone();
+    unknown[2] = 0x42;
+    let before = header;
+    assert_eq!(unsafe { ffi_header_parse(unknown.as_ptr(), 16, &mut header) }, FfiStatus::UnknownMessageType.code());
+    assert_eq!(header, before);
+    assert_eq!(last_error_message(), "Unknown message type 0x42");
+    let mut payload = [0u8; 16];
+    assert_eq!(
+        unsafe { ffi_packet_parse(unknown.as_ptr(), unknown.len(), &mut header, payload.as_mut_ptr(), payload.len()) },
+        FfiStatus::UnknownMessageType.code()
+    );
+    assert_eq!(
+        unsafe { ffi_header_parse(frame.as_ptr(), 16, std::ptr::null_mut()) },
+        FfiStatus::InvalidArgument.code()
+    );
+}
+
+/// Unit test: A null output pointer returns the exact size, which is then enough for the real call
+#[test]
+fn test_null_output_queries_required_size() {
+    let key = [9u8; 32];
+    let plaintext = b"sized by the first call";
+
+    let required = unsafe {
+        ffi_encrypt_layer(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), std::ptr::null_mut(), 0)
+    };
+    assert_eq!(required, (plaintext.len() + 28) as i32);
+
+    let mut ciphertext = vec![0u8; required as usize];
+    let written = unsafe {
+        ffi_encrypt_layer(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), ciphertext.as_mut_ptr(), ciphertext.len())
+    };
+    assert_eq!(written, required);
+
+    let required = unsafe {
+        ffi_decrypt_layer(key.as_ptr(), ciphertext.as_ptr(), ciphertext.len(), std::ptr::null_mut(), 0)
+    };
+    assert_eq!(required, plaintext.len() as i32);
+
+    // A too small buffer still fails, and its message can be sized the same way
+    let mut small = [0u8; 4];
+    let result = unsafe {
+        ffi_decrypt_layer(key.as_ptr(), ciphertext.as_ptr(), ciphertext.len(), small.as_mut_ptr(), small.len())
+    };
+    assert_eq!(result, FfiStatus::BufferTooSmall.code());
+    let message_len = unsafe { ffi_last_error_message(std::ptr::null_mut(), 0) };
+    assert_eq!(message_len as usize, last_error_message().len());
+}
+
+/// Unit test: A length past i32::MAX is an error status, not a wrapped count
+#[test]
+fn test_length_beyond_i32_is_rejected() {
+    assert_eq!(super::length_or_error(i32::MAX as usize), i32::MAX);
+    assert_eq!(super::length_or_error(i32::MAX as usize + 1), FfiStatus::InvalidArgument.code());
+    assert_eq!(ffi_last_error_code(), FfiStatus::InvalidArgument.code());
+}
+
+/// Unit test: Rust-allocated results round trip and are released with ffi_free
+#[test]
+fn test_alloc_variants_roundtrip() {
+    let key = [5u8; 32];
+    let plaintext = b"no capacity guessing";
+
+    let mut ct_ptr = std::ptr::null_mut();
+    let mut ct_len = 0usize;
+    let result = unsafe {
+        ffi_encrypt_layer_alloc(key.as_ptr(), plaintext.as_ptr(), plaintext.len(), &mut ct_ptr, &mut ct_len)
+    };
+    assert_eq!(result, FfiStatus::Ok.code());
+    assert_eq!(ct_len, plaintext.len() + 28);
+
+    let mut pt_ptr = std::ptr::null_mut();
+    let mut pt_len = 0usize;
+    let result = unsafe { ffi_decrypt_layer_alloc(key.as_ptr(), ct_ptr, ct_len, &mut pt_ptr, &mut pt_len) };
+    assert_eq!(result, FfiStatus::Ok.code());
+    assert_eq!(unsafe { std::slice::from_raw_parts(pt_ptr, pt_len) }, plaintext);
+
+    unsafe {
+        ffi_free(ct_ptr, ct_len);
+        ffi_free(pt_ptr, pt_len);
+    }
+}
+
+/// Unit test: A panicking export body returns Panic with the message, instead of unwinding
+#[test]
+fn test_guard_converts_panic_to_status() {
+    let result: i32 = guard(|| panic!("relay table corrupted"));
+    assert_eq!(result, FfiStatus::Panic.code());
+    assert_eq!(ffi_last_error_code(), FfiStatus::Panic.code());
+    assert!(last_error_message().contains("relay table corrupted"));
+
+    let handle: *mut u8 = guard(|| panic!("no handle"));
+    assert!(handle.is_null());
+}
+
+/// Unit test: Native log records reach the registered host callback
+#[test]
+fn test_log_callback_receives_records() {
+    use std::sync::Mutex;
+    use super::logging::ffi_set_log_callback;
+
+    static RECEIVED: Mutex<Vec<(i32, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn sink(level: i32, message_ptr: *const u8, message_len: usize) {
+        let message = unsafe { std::slice::from_raw_parts(message_ptr, message_len) };
+        RECEIVED.lock().unwrap().push((level, String::from_utf8_lossy(message).into_owned()));
+    }
+
+    assert_eq!(ffi_set_log_callback(Some(sink), 3), FfiStatus::Ok.code());
+    log::warn!(target: "freedom_core::test", "key changed");
+    log::info!(target: "freedom_core::test", "below the minimum level");
+
+    let received = RECEIVED.lock().unwrap().clone();
+    assert!(received.contains(&(3, "[freedom_core::test] key changed".to_string())));
+    assert!(!received.iter().any(|(_, m)| m.contains("below the minimum level")));
+}
+
+/// Integration test: The async wrap returns immediately and completes through the callback
+#[test]
+fn test_onion_wrap_async_completes_on_worker() {
+    use std::ffi::c_void;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::context::{ ffi_init, ffi_shutdown };
+    use super::jobs::ffi_onion_wrap_async;
+
+    // The callback owns the sender: a borrowed one could be dropped by the test while `send` still runs
+    extern "C" fn on_done(user_data: *mut c_void, status: i32, data_ptr: *const u8, data_len: usize) {
+        let sender = unsafe { Box::from_raw(user_data as *mut mpsc::Sender<(i32, Vec<u8>)>) };
+        let data = if data_ptr.is_null() { Vec::new() } else { unsafe { std::slice::from_raw_parts(data_ptr, data_len) }.to_vec() };
+        sender.send((status, data)).unwrap();
+    }
+
+    let context = unsafe { ffi_init(std::ptr::null()) };
+    assert!(!context.is_null());
+
+    let (sender, receiver) = mpsc::channel::<(i32, Vec<u8>)>();
+    let keys = [[4u8; 32], [5u8; 32]];
+    let payload = b"hidden service request";
+
+    let queued = unsafe {
+        ffi_onion_wrap_async(
+            context,
+            keys.as_ptr() as *const u8,
+            keys.len(),
+            payload.as_ptr(),
+            payload.len(),
+            Some(on_done),
+            Box::into_raw(Box::new(sender)) as *mut c_void,
+        )
+    };
+    assert_eq!(queued, FfiStatus::Ok.code());
+
+    let (status, cell) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(status, FfiStatus::Ok.code());
+    assert_eq!(cell.len(), payload.len() + 2 * 28);
+
+    // Without keys nothing is queued
+    let rejected = unsafe {
+        ffi_onion_wrap_async(context, std::ptr::null(), 0, payload.as_ptr(), payload.len(), Some(on_done), std::ptr::null_mut())
+    };
+    assert_eq!(rejected, FfiStatus::InvalidArgument.code());
+
+    assert_eq!(unsafe { ffi_shutdown(context) }, FfiStatus::Ok.code());
+}
+
+/// Integration test: Shutting down from a job callback is refused instead of deadlocking on the join
+#[test]
+fn test_shutdown_from_callback_is_refused() {
+    use std::ffi::c_void;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::context::FreedomContext;
+    use super::context::{ ffi_init, ffi_shutdown };
+    use super::jobs::ffi_onion_wrap_async;
+
+    type Job = (*mut FreedomContext, mpsc::Sender<(i32, i32)>);
+
+    extern "C" fn on_done(user_data: *mut c_void, _status: i32, _data_ptr: *const u8, _data_len: usize) {
+        let (context, sender) = *unsafe { Box::from_raw(user_data as *mut Job) };
+        let status = unsafe { ffi_shutdown(context) };
+        sender.send((status, ffi_last_error_code())).unwrap();
+    }
+
+    let context = unsafe { ffi_init(std::ptr::null()) };
+    assert!(!context.is_null());
+
+    let (sender, receiver) = mpsc::channel::<(i32, i32)>();
+    let keys = [[4u8; 32]];
+    let payload = b"shut down from here";
+    let queued = unsafe {
+        ffi_onion_wrap_async(
+            context,
+            keys.as_ptr() as *const u8,
+            keys.len(),
+            payload.as_ptr(),
+            payload.len(),
+            Some(on_done),
+            Box::into_raw(Box::new((context, sender))) as *mut c_void,
+        )
+    };
+    assert_eq!(queued, FfiStatus::Ok.code());
+
+    let (status, last_error) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(status, FfiStatus::InvalidArgument.code());
+    assert_eq!(last_error, FfiStatus::InvalidArgument.code());
+
+    // The context is still alive and shuts down from a host thread
+    assert_eq!(unsafe { ffi_shutdown(context) }, FfiStatus::Ok.code());
+}
+
+/// Integration test: One wrap call, then one peel call per hop, recovers the payload
+#[test]
+fn test_onion_wrap_and_peel() {
+    use super::onion::{ ffi_onion_peel, ffi_onion_wrap };
+
+    let keys = [[6u8; 32], [7u8; 32], [8u8; 32]];
+    let payload = b"circuit cell";
+
+    let required = unsafe {
+        ffi_onion_wrap(keys.as_ptr() as *const u8, keys.len(), payload.as_ptr(), payload.len(), std::ptr::null_mut(), 0)
+    };
+    assert_eq!(required, (payload.len() + 3 * 28) as i32);
+
+    let mut cell = vec![0u8; required as usize];
+    let written = unsafe {
+        ffi_onion_wrap(keys.as_ptr() as *const u8, keys.len(), payload.as_ptr(), payload.len(), cell.as_mut_ptr(), cell.len())
+    };
+    assert_eq!(written, required);
+
+    for key in &keys {
+        let mut inner = vec![0u8; cell.len()];
+        let written = unsafe { ffi_onion_peel(key.as_ptr(), cell.as_ptr(), cell.len(), inner.as_mut_ptr(), inner.len()) };
+        assert!(written > 0);
+        inner.truncate(written as usize);
+        cell = inner;
+    }
+    assert_eq!(cell, payload);
+}
+
+/// Unit test: Distance and bucket math match the routing-table conventions
+#[test]
+fn test_xor_distance_and_bucket_index() {
+    use super::dht::{ ffi_bucket_index, ffi_node_id_from_key, ffi_xor_distance };
+
+    let local = [0u8; 32];
+    let mut other = [0u8; 32];
+    other[1] = 0x10; // First set bit at position 8 + 3
+    other[31] = 0xFF; // Later bits must not change the bucket
+
+    let mut distance = [0u8; 32];
+    assert_eq!(unsafe { ffi_xor_distance(local.as_ptr(), other.as_ptr(), distance.as_mut_ptr()) }, 0);
+    assert_eq!(distance, other);
+
+    assert_eq!(unsafe { ffi_bucket_index(local.as_ptr(), other.as_ptr()) }, 11);
+    assert_eq!(unsafe { ffi_bucket_index(local.as_ptr(), local.as_ptr()) }, 255);
+
+    let mut node_id = [0u8; 32];
+    let key = [9u8; 32];
+    assert_eq!(unsafe { ffi_node_id_from_key(key.as_ptr(), key.len(), node_id.as_mut_ptr()) }, 0);
+    assert_eq!(node_id, *crate::dht::node_id::NodeId::hash_of(&key).as_bytes());
+}
+
+/// Unit test: The version export matches the crate and the ABI check accepts only the current ABI
+#[test]
+fn test_core_version_and_abi_check() {
+    use super::version::{ ffi_abi_compatible, ffi_core_version, FfiVersion, ABI_VERSION };
+
+    let mut version = FfiVersion::default();
+    assert_eq!(unsafe { ffi_core_version(&mut version) }, FfiStatus::Ok.code());
+    assert_eq!(
+        format!("{}.{}.{}", version.major, version.minor, version.patch),
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(version.wire_protocols & 1, 1);
+
+    assert_eq!(ffi_abi_compatible(ABI_VERSION), 1);
+    assert_eq!(ffi_abi_compatible(ABI_VERSION + 1), 0);
+}
+
+/// Integration test: A batch encrypts each item under its own key, and undersized outputs fail the whole batch
+#[test]
+fn test_encrypt_layer_batch() {
+    use super::crypto::ffi_encrypt_layer_batch;
+    use crate::crypto::helper::try_decrypt_layer;
+
+    let keys = [[1u8; 32], [2u8; 32], [3u8; 32]].concat();
+    let plaintexts: [&[u8]; 3] = [b"first cell", b"", b"third cell, a bit longer"];
+    let plaintext_ptrs = plaintexts.map(|p| p.as_ptr());
+    let plaintext_lens = plaintexts.map(|p| p.len());
+
+    let mut outputs = [[0u8; 64]; 3];
+    let output_ptrs = outputs.each_mut().map(|o| o.as_mut_ptr());
+    let mut output_caps = [64usize; 3];
+    let mut output_lens = [0usize; 3];
+
+    let result = unsafe {
+        ffi_encrypt_layer_batch(
+            keys.as_ptr(), plaintext_ptrs.as_ptr(), plaintext_lens.as_ptr(), 3,
+            output_ptrs.as_ptr(), output_caps.as_ptr(), output_lens.as_mut_ptr()
+        )
+    };
+    assert_eq!(result, 3);
+
+    for i in 0..3 {
+        assert_eq!(output_lens[i], plaintexts[i].len() + 28);
+        let key: [u8; 32] = keys[i * 32..(i + 1) * 32].try_into().unwrap();
+        let decrypted = try_decrypt_layer(&key, &outputs[i][..output_lens[i]]).unwrap();
+        assert_eq!(decrypted, plaintexts[i]);
+    }
+
+    output_caps[2] = 30;
+    let result = unsafe {
+        ffi_encrypt_layer_batch(
+            keys.as_ptr(), plaintext_ptrs.as_ptr(), plaintext_lens.as_ptr(), 3,
+            output_ptrs.as_ptr(), output_caps.as_ptr(), output_lens.as_mut_ptr()
+        )
+    };
+    assert_eq!(result, FfiStatus::BufferTooSmall.code());
+    assert_eq!(output_lens[2], plaintexts[2].len() + 28);
+
+    // Lengths whose output size overflows are refused before any pointer is read
+    for lens in [[usize::MAX, 0, 0], [usize::MAX / 2, usize::MAX / 2, 0]] {
+        let result = unsafe {
+            ffi_encrypt_layer_batch(
+                keys.as_ptr(), plaintext_ptrs.as_ptr(), lens.as_ptr(), 3,
+                output_ptrs.as_ptr(), output_caps.as_ptr(), output_lens.as_mut_ptr()
+            )
+        };
+        assert_eq!(result, FfiStatus::InvalidArgument.code());
+    }
+}
+
+/// Integration test: Batch handshake validation reports per-payload codes, and a cancelled token stops it
+#[test]
+fn test_validate_handshakes_async_honours_cancellation() {
+    use std::ffi::c_void;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::cancel::{ ffi_cancellation_cancel, ffi_cancellation_create, ffi_cancellation_destroy };
+    use super::context::{ ffi_init, ffi_shutdown };
+    use super::jobs::ffi_validate_handshakes_async;
+
+    extern "C" fn on_done(user_data: *mut c_void, status: i32, data_ptr: *const u8, data_len: usize) {
+        let sender = unsafe { Box::from_raw(user_data as *mut mpsc::Sender<(i32, Vec<u8>)>) };
+        let data = if data_ptr.is_null() { Vec::new() } else { unsafe { std::slice::from_raw_parts(data_ptr, data_len) }.to_vec() };
+        sender.send((status, data)).unwrap();
+    }
+
+    let context = unsafe { ffi_init(std::ptr::null()) };
+    let identity = ffi_identity_generate(0);
+    let mut payloads = [0u8; 2 * 136];
+    unsafe { ffi_sign_handshake(identity, unix_now(), payloads.as_mut_ptr(), 136) };
+    unsafe { ffi_sign_handshake(identity, unix_now(), payloads[136..].as_mut_ptr(), 136) };
+    payloads[136 + 40] ^= 0x01;
+
+    let token = ffi_cancellation_create();
+    let run = |sender: mpsc::Sender<(i32, Vec<u8>)>| unsafe {
+        ffi_validate_handshakes_async(
+            context, payloads.as_ptr(), 2, token, Some(on_done), Box::into_raw(Box::new(sender)) as *mut c_void
+        )
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    assert_eq!(run(sender), FfiStatus::Ok.code());
+    let (status, results) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(status, FfiStatus::Ok.code());
+    assert_eq!(results, [0, FfiStatus::VerificationFailed.code() as i8 as u8]);
+
+    assert_eq!(unsafe { ffi_cancellation_cancel(token) }, FfiStatus::Ok.code());
+    let (sender, receiver) = mpsc::channel();
+    assert_eq!(run(sender), FfiStatus::Ok.code());
+    unsafe { ffi_cancellation_destroy(token) };
+    let (status, results) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(status, FfiStatus::Cancelled.code());
+    assert!(results.is_empty());
+
+    unsafe {
+        ffi_identity_free(identity);
+        assert_eq!(ffi_shutdown(context), FfiStatus::Ok.code());
+    }
+}
+
+/// Unit test: ffi_init validates its configuration and the context enforces the configured limits
+#[test]
+fn test_init_config_limits() {
+    use super::context::{ ffi_init, ffi_shutdown, FfiConfig };
+    use super::crypto::ffi_validate_handshake_in;
+    use super::protocol::{ ffi_header_parse_limited, ffi_packet_parse_limited };
+    use crate::protocol::header::MessageType;
+    use crate::protocol::packet::{ NetworkPacket, DEFAULT_MAX_PAYLOAD_LEN };
+
+    let invalid = [
+        FfiConfig { worker_threads: 100_000, ..FfiConfig::default() },
+        FfiConfig { max_payload_len: 64, ..FfiConfig::default() },
+        FfiConfig { handshake_window_secs: 7 * 24 * 60 * 60, ..FfiConfig::default() },
+        FfiConfig { log_level: 9, ..FfiConfig::default() },
+    ];
+    for config in &invalid {
+        assert!(unsafe { ffi_init(config) }.is_null(), "{config:?} was accepted");
+        assert_eq!(ffi_last_error_code(), FfiStatus::InvalidArgument.code());
+    }
+
+    let config = FfiConfig { worker_threads: 1, max_payload_len: 256, handshake_window_secs: 60, ..FfiConfig::default() };
+    let context = unsafe { ffi_init(&config) };
+    assert!(!context.is_null());
+
+    // A handshake 2 minutes old passes the default window, not the context's
+    let identity = ffi_identity_generate(0);
+    let mut payload = [0u8; 136];
+    unsafe { ffi_sign_handshake(identity, unix_now() - 120, payload.as_mut_ptr(), payload.len()) };
+    assert_eq!(unsafe { ffi_validate_handshake(payload.as_ptr(), payload.len()) }, FfiStatus::Ok.code());
+    assert_eq!(
+        unsafe { ffi_validate_handshake_in(context, payload.as_ptr(), payload.len()) },
+        FfiStatus::StaleTimestamp.code()
+    );
+    unsafe { ffi_identity_free(identity) };
+
+    let mut header = FfiFixedHeader::default();
+    let small = NetworkPacket::new(MessageType::Store, 1, vec![1; 200]).to_bytes();
+    let result = unsafe { ffi_packet_parse_limited(context, small.as_ptr(), small.len(), &mut header, std::ptr::null_mut(), 0) };
+    assert_eq!(result, 200);
+
+    let large = NetworkPacket::new(MessageType::Store, 2, vec![1; 300]).to_bytes();
+    let result = unsafe { ffi_packet_parse_limited(context, large.as_ptr(), large.len(), &mut header, std::ptr::null_mut(), 0) };
+    assert_eq!(result, FfiStatus::ParseError.code());
+    assert_eq!(unsafe { ffi_header_parse_limited(context, small.as_ptr(), 16, &mut header) }, FfiStatus::Ok.code());
+    assert_eq!(unsafe { ffi_header_parse_limited(context, large.as_ptr(), 16, &mut header) }, FfiStatus::ParseError.code());
+
+    // The exports without a context apply the default limit
+    let oversized = NetworkPacket::new(MessageType::Store, 3, vec![1; DEFAULT_MAX_PAYLOAD_LEN + 1]).to_bytes();
+    let result = unsafe { ffi_packet_parse(oversized.as_ptr(), oversized.len(), &mut header, std::ptr::null_mut(), 0) };
+    assert_eq!(result, FfiStatus::ParseError.code());
+    assert_eq!(unsafe { ffi_header_parse(oversized.as_ptr(), 16, &mut header) }, FfiStatus::ParseError.code());
+    assert_eq!(unsafe { ffi_header_parse(large.as_ptr(), 16, &mut header) }, FfiStatus::Ok.code());
+
+    assert_eq!(unsafe { ffi_shutdown(context) }, FfiStatus::Ok.code());
+}
+
+/// Integration test: Streams connected and accepted through the FFI exchange data end to end
+#[test]
+fn test_stream_connect_and_accept() {
+    use super::stream::{ ffi_stream_accept, ffi_stream_connect, ffi_stream_destroy, ffi_stream_is_established, ffi_stream_on_data, ffi_stream_read, ffi_stream_take_outgoing, ffi_stream_write };
+    use crate::onion::address::OnionAddress;
+    use crate::onion::e2e::FreedomStream;
+
+    // Shuttles the outbox of `from` into `to`, as the host does with the circuit stream
+    fn pump(from: *mut FreedomStream, to: *mut FreedomStream) -> i32 {
+        let len = unsafe { ffi_stream_take_outgoing(from, std::ptr::null_mut(), 0) };
+        let mut bytes = vec![0u8; len as usize];
+        if len > 0 {
+            assert_eq!(unsafe { ffi_stream_take_outgoing(from, bytes.as_mut_ptr(), bytes.len()) }, len);
+        }
+        unsafe { ffi_stream_on_data(to, bytes.as_ptr(), bytes.len()) }
+    }
+
+    let identity = ffi_identity_generate(0);
+    let mut identity_key = [0u8; 32];
+    unsafe { ffi_identity_public_keys(identity, identity_key.as_mut_ptr(), [0u8; 32].as_mut_ptr()) };
+    let address = OnionAddress::new(ed25519_dalek::VerifyingKey::from_bytes(&identity_key).unwrap()).to_string();
+
+    let bad = b"not-an-address.freedom";
+    assert!(unsafe { ffi_stream_connect(bad.as_ptr(), bad.len()) }.is_null());
+    assert_eq!(ffi_last_error_code(), FfiStatus::ParseError.code());
+
+    let client = unsafe { ffi_stream_connect(address.as_ptr(), address.len()) };
+    let service = unsafe { ffi_stream_accept(identity) };
+    assert!(!client.is_null() && !service.is_null());
+
+    // Nothing is accepted before the handshake
+    let message = b"hello service";
+    assert_eq!(unsafe { ffi_stream_write(client, message.as_ptr(), message.len()) }, 0);
+    pump(client, service);
+    pump(service, client);
+    assert_eq!(unsafe { ffi_stream_is_established(client) }, 1);
+    assert_eq!(unsafe { ffi_stream_is_established(service) }, 1);
+
+    assert_eq!(unsafe { ffi_stream_write(client, message.as_ptr(), message.len()) }, message.len() as i32);
+    // A short buffer doesn't drain the records
+    let mut tiny = [0u8; 4];
+    assert_eq!(unsafe { ffi_stream_take_outgoing(client, tiny.as_mut_ptr(), tiny.len()) }, FfiStatus::BufferTooSmall.code());
+    assert_eq!(pump(client, service), message.len() as i32);
+
+    let mut received = [0u8; 64];
+    let read = unsafe { ffi_stream_read(service, received.as_mut_ptr(), received.len()) };
+    assert_eq!(&received[..read as usize], message);
+
+    unsafe {
+        ffi_stream_destroy(client);
+        ffi_stream_destroy(service);
+        ffi_identity_free(identity);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::crypto::trust::{ TrustStatus, TrustStore };
+use crate::dht::node_id::NodeId;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use super::error::{ clear_last_error, guard, report, set_last_error };
+use super::status::FfiStatus;
+use super::{ raw_to_array, raw_to_slice };
+
+
+// ==================================================================================
+// TRUST STORE EXPORTS (TOFU / Pinning)
+// ==================================================================================
+
+/// Trust store opened from the host, remembering where it must be saved.
+pub struct TrustStoreHandle {
+    store: TrustStore,
+    path: PathBuf,
+}
+
+/// Opens the trust store persisted at `path` (UTF-8), creating an empty one if the file does not exist.
+/// # Safety
+/// - `path_ptr` must point to a valid UTF-8 byte array of length `path_len`.
+///
+/// Returns an opaque handle, or null on failure. Release it with `ffi_trust_store_free`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_open(
+    path_ptr: *const u8,
+    path_len: usize,
+) -> *mut TrustStoreHandle {
+    guard(|| {
+        clear_last_error();
+
+        let path_bytes = unsafe { raw_to_slice(path_ptr, path_len) };
+        let Ok(path) = std::str::from_utf8(path_bytes) else {
+            set_last_error(FfiStatus::InvalidArgument, "Path is not valid UTF-8");
+            return std::ptr::null_mut();
+        };
+
+        match TrustStore::load(path.as_ref()) {
+            Ok(store) => Box::into_raw(Box::new(TrustStoreHandle { store, path: PathBuf::from(path) })),
+            Err(e) => {
+                report(e);
+                std::ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// Checks a peer's identity key against the trust store, recording it on first use. When the
+/// peer's address is given (UTF-8 "ip:port"), the key is also checked against, and bound to, it.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_trust_store_open`.
+/// - `node_id_ptr` and `identity_key_ptr` must point to valid 32-byte arrays.
+/// - `address_ptr` must point to a valid UTF-8 string of length `address_len`, or be null (with
+///   `address_len` 0) when the address isn't known.
+///
+/// Returns 1 if newly recorded, 2 if known (first seen), 3 if known (pinned),
+/// or an error status (`KeyChanged`, `Denied`, ...).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_check(
+    handle: *mut TrustStoreHandle,
+    node_id_ptr: *const u8, // 32 bytes
+    identity_key_ptr: *const u8, // 32 bytes
+    address_ptr: *const u8,
+    address_len: usize,
+    now: u64, // Seconds since UNIX epoch
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null trust store handle");
+        };
+        let node_id = match unsafe { raw_to_array::<32>(node_id_ptr, "node_id_ptr") } {
+            Ok(id) => NodeId::from_bytes(*id),
+            Err(code) => return code,
+        };
+        let key = match unsafe { raw_to_array::<32>(identity_key_ptr, "identity_key_ptr") } {
+            Ok(key) => key,
+            Err(code) => return code,
+        };
+
+        let address = if address_len == 0 {
+            None
+        } else {
+            let parsed = std::str::from_utf8(unsafe { raw_to_slice(address_ptr, address_len) })
+                .ok()
+                .and_then(|text| text.parse::<SocketAddr>().ok());
+            let Some(address) = parsed else {
+                return set_last_error(FfiStatus::InvalidArgument, "Address is not a valid ip:port");
+            };
+            Some(address)
+        };
+
+        match handle.store.check(node_id, address, key, now) {
+            Ok(TrustStatus::NewlyRecorded) => 1,
+            Ok(TrustStatus::KnownFirstSeen) => 2,
+            Ok(TrustStatus::KnownPinned) => 3,
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Pins an identity key for a NodeId, replacing any previously recorded key.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_trust_store_open`.
+/// - `node_id_ptr` and `identity_key_ptr` must point to valid 32-byte arrays.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_pin(
+    handle: *mut TrustStoreHandle,
+    node_id_ptr: *const u8, // 32 bytes
+    identity_key_ptr: *const u8, // 32 bytes
+    now: u64,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null trust store handle");
+        };
+        let node_id = match unsafe { raw_to_array::<32>(node_id_ptr, "node_id_ptr") } {
+            Ok(id) => NodeId::from_bytes(*id),
+            Err(code) => return code,
+        };
+        let key = match unsafe { raw_to_array::<32>(identity_key_ptr, "identity_key_ptr") } {
+            Ok(key) => *key,
+            Err(code) => return code,
+        };
+
+        handle.store.pin(node_id, key, now);
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Adds (`deny != 0`) or removes (`deny == 0`) an identity key from the denylist.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_trust_store_open`.
+/// - `identity_key_ptr` must point to a valid 32-byte array.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_deny(
+    handle: *mut TrustStoreHandle,
+    identity_key_ptr: *const u8, // 32 bytes
+    deny: i32,
+) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null trust store handle");
+        };
+        let key = match unsafe { raw_to_array::<32>(identity_key_ptr, "identity_key_ptr") } {
+            Ok(key) => *key,
+            Err(code) => return code,
+        };
+
+        if deny != 0 {
+            handle.store.deny(key);
+        } else {
+            handle.store.undeny(&key);
+        }
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Writes the trust store back to the path it was opened from.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_trust_store_open`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_save(handle: *const TrustStoreHandle) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(handle) = (unsafe { handle.as_ref() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null trust store handle");
+        };
+
+        match handle.store.save(&handle.path) {
+            Ok(_) => FfiStatus::Ok.code(),
+            Err(e) => report(e),
+        }
+    })
+}
+
+/// Releases a trust store handle. Does not save it.
+/// # Safety
+/// - `handle` must be a pointer returned by `ffi_trust_store_open`, or null. It must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_trust_store_free(handle: *mut TrustStoreHandle) {
+    guard(|| {
+        if !handle.is_null() {
+            drop(unsafe { Box::from_raw(handle) });
+        }
+    })
+}
@@ -0,0 +1,77 @@
+use crate::protocol::header::PROTOCOL_VERSION;
+
+use super::error::{ clear_last_error, guard, set_last_error };
+use super::status::FfiStatus;
+
+
+/// Version of the exported C ABI. Bump it whenever an export signature, a `#[repr(C)]` struct
+/// or the meaning of a status code changes, so hosts built against the old ABI refuse to load.
+/// C# Reference: FalconNode.Core.Interop.RustVersion.ExpectedAbiVersion
+pub const ABI_VERSION: u32 = 4;
+
+/// Bit N set means the core speaks wire protocol version N + 1 (`FixedHeader::version`)
+pub const WIRE_PROTOCOLS: u32 = 1 << (PROTOCOL_VERSION - 1);
+
+/// C-compatible description of the loaded library.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FfiVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub abi: u32,
+    pub wire_protocols: u32, // Bitmask, see `WIRE_PROTOCOLS`
+}
+
+impl FfiVersion {
+    pub fn current() -> Self {
+        Self {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            abi: ABI_VERSION,
+            wire_protocols: WIRE_PROTOCOLS,
+        }
+    }
+}
+
+
+// ==================================================================================
+// VERSION EXPORTS (Loader checks)
+// ==================================================================================
+
+/// Writes the crate version, ABI version and supported wire protocols.
+/// # Safety
+/// - `version_out` must point to a valid `FfiVersion`.
+///
+/// Returns `FfiStatus::Ok` on success, or an error status.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ffi_core_version(version_out: *mut FfiVersion) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        let Some(version_out) = (unsafe { version_out.as_mut() }) else {
+            return set_last_error(FfiStatus::InvalidArgument, "Null version output");
+        };
+
+        *version_out = FfiVersion::current();
+        FfiStatus::Ok.code()
+    })
+}
+
+/// Checks whether the host was built against this library's ABI.
+///
+/// Returns 1 if compatible, 0 if not.
+#[unsafe(no_mangle)]
+pub extern "C" fn ffi_abi_compatible(expected: u32) -> i32 {
+    guard(|| {
+        clear_last_error();
+
+        if expected == ABI_VERSION {
+            1
+        } else {
+            log::error!("Host expects ABI {expected}, library provides ABI {ABI_VERSION}");
+            0
+        }
+    })
+}
@@ -1,3 +1,21 @@
+pub mod bindings;
+pub mod cancel;
+pub mod clock;
+// Threads, the filesystem and the C ABI are native-only; the browser build keeps protocol + crypto
+#[cfg(not(target_arch = "wasm32"))]
+pub mod context;
 pub mod crypto;
+pub mod dht;
+pub mod directory;
+pub mod onion;
 pub mod protocol;
-pub mod ffi;
\ No newline at end of file
+pub mod scoring;
+pub mod store;
+pub mod transport;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod ffi;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod worker;
+
+#[cfg(feature = "uniffi")]
+uniffi::setup_scaffolding!();
@@ -0,0 +1,123 @@
+use ed25519_dalek::VerifyingKey;
+use sha2::{ Digest, Sha256 };
+use std::fmt;
+use std::str::FromStr;
+
+// Hashed with the key and version, so a mistyped address fails the checksum
+const CHECKSUM_LABEL: &[u8] = b".freedom checksum";
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// [PublicKey (32)] [Checksum (2)] [Version (1)]
+const DECODED_LEN: usize = 35;
+/// Base32 characters before the suffix (35 bytes, 5 bits per character)
+const ENCODED_LEN: usize = 56;
+
+pub const ADDRESS_SUFFIX: &str = ".freedom";
+pub const ADDRESS_VERSION: u8 = 1;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum AddressError {
+    #[error("Address does not end in {ADDRESS_SUFFIX}")]
+    MissingSuffix,
+    #[error("Address has {0} characters before the suffix (expected {ENCODED_LEN})")]
+    InvalidLength(usize),
+    #[error("Invalid character {0:?} in address")]
+    InvalidCharacter(char),
+    #[error("Unsupported address version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Address checksum mismatch")]
+    BadChecksum,
+    #[error("Address does not hold a valid identity key")]
+    InvalidKey,
+}
+
+/// The address of a hidden service: its identity key, which clients need to find and
+/// decrypt its descriptor (see `HsDescriptor`), written as text.
+/// Format: base32([PublicKey (32)] [Checksum (2)] [Version (1)]) + ".freedom", where Checksum is
+/// the first 2 bytes of SHA-256(".freedom checksum" | PublicKey | Version). Parsing ignores the
+/// case of letters but nothing else; `Display` writes the canonical lowercase form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress(VerifyingKey);
+
+impl OnionAddress {
+    pub fn new(identity_key: VerifyingKey) -> Self {
+        Self(identity_key)
+    }
+
+    pub fn identity_key(&self) -> &VerifyingKey {
+        &self.0
+    }
+
+    fn checksum(key: &[u8; 32], version: u8) -> [u8; 2] {
+        let hash = Sha256::new().chain_update(CHECKSUM_LABEL).chain_update(key).chain_update([version]).finalize();
+        [hash[0], hash[1]]
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = self.0.as_bytes();
+        let mut bytes = [0u8; DECODED_LEN];
+        bytes[..32].copy_from_slice(key);
+        bytes[32..34].copy_from_slice(&Self::checksum(key, ADDRESS_VERSION));
+        bytes[34] = ADDRESS_VERSION;
+
+        // 35 bytes are exactly 56 groups of 5 bits, so there is no padding
+        let mut encoded = String::with_capacity(ENCODED_LEN + ADDRESS_SUFFIX.len());
+        let (mut buffer, mut bits) = (0u16, 0);
+        for byte in bytes {
+            buffer = (buffer << 8) | byte as u16;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+            }
+        }
+        encoded.push_str(ADDRESS_SUFFIX);
+        f.write_str(&encoded)
+    }
+}
+
+impl FromStr for OnionAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = match s.len().checked_sub(ADDRESS_SUFFIX.len()) {
+            Some(end) if s.is_char_boundary(end) && s[end..].eq_ignore_ascii_case(ADDRESS_SUFFIX) => &s[..end],
+            _ => {
+                return Err(AddressError::MissingSuffix);
+            }
+        };
+        if encoded.chars().count() != ENCODED_LEN {
+            return Err(AddressError::InvalidLength(encoded.chars().count()));
+        }
+
+        let mut bytes = [0u8; DECODED_LEN];
+        let (mut buffer, mut bits, mut len) = (0u16, 0, 0);
+        for c in encoded.chars() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|&a| a as char == c.to_ascii_lowercase())
+                .ok_or(AddressError::InvalidCharacter(c))?;
+            buffer = (buffer << 5) | value as u16;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes[len] = (buffer >> bits) as u8;
+                len += 1;
+            }
+        }
+
+        let key: [u8; 32] = bytes[..32].try_into().expect("32-byte slice");
+        let version = bytes[34];
+        if version != ADDRESS_VERSION {
+            return Err(AddressError::UnsupportedVersion(version));
+        }
+        if bytes[32..34] != Self::checksum(&key, version) {
+            return Err(AddressError::BadChecksum);
+        }
+        match VerifyingKey::from_bytes(&key) {
+            Ok(key) if !key.is_weak() => Ok(Self(key)),
+            _ => Err(AddressError::InvalidKey),
+        }
+    }
+}
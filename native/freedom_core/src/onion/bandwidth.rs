@@ -0,0 +1,218 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+
+use super::cell::{ Cell, CellCommand, CELL_SIZE };
+use super::relay::CircuitLink;
+use super::stream::CIRCUIT_WINDOW;
+
+/// Cells one circuit may have waiting for bandwidth. Flow control keeps honest clients well
+/// below it (a window per hop that ends streams); a circuit past it is flooding us.
+pub const MAX_QUEUED_CELLS: usize = 4 * CIRCUIT_WINDOW as usize;
+/// Share of the monthly cap after which new circuits are refused, so the ones we carry can finish
+pub(crate) const SOFT_CAP_PERCENT: u64 = 95;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum BandwidthError {
+    /// Answer with DESTROY (`DestroyReason::ResourceLimit`) and tear the circuit down
+    #[error("Monthly bandwidth cap reached")]
+    Hibernating,
+    #[error("Too many cells queued on circuit {0} (max {MAX_QUEUED_CELLS})")]
+    QueueFull(u32),
+}
+
+/// What a relay operator donates: sustained rates and bursts in bytes, for the whole relay
+/// and for any one circuit, and optionally a cap on the bytes relayed in a calendar month (UTC).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandwidthConfig {
+    pub rate: u64,
+    pub burst: u64,
+    pub circuit_rate: u64,
+    pub circuit_burst: u64,
+    /// Bytes received and sent per month
+    pub monthly_cap: Option<u64>,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1024 * 1024,
+            burst: 2 * 1024 * 1024,
+            circuit_rate: 256 * 1024,
+            circuit_burst: 512 * 1024,
+            monthly_cap: None,
+        }
+    }
+}
+
+/// Bytes that may be sent now, refilled at a rate up to a burst
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenBucket {
+    pub(crate) tokens: f64,
+    updated_ms: u64,
+}
+
+impl TokenBucket {
+    pub(crate) fn full(burst: u64, now_ms: u64) -> Self {
+        Self { tokens: burst as f64, updated_ms: now_ms }
+    }
+
+    pub(crate) fn refill(&mut self, rate: u64, burst: u64, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.updated_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.updated_ms = self.updated_ms.max(now_ms);
+    }
+
+    pub(crate) fn has(&self, bytes: usize) -> bool {
+        self.tokens >= bytes as f64
+    }
+}
+
+struct CircuitQueue {
+    bucket: TokenBucket,
+    cells: VecDeque<Cell>,
+}
+
+/// Bounds the bandwidth a relay donates: a token bucket for the relay and one per circuit,
+/// and byte accounting against an optional monthly cap. Sits in front of `RelayCircuits`:
+/// the host `push`es every cell it receives, feeds the cells `poll` releases to
+/// `RelayCircuits::on_cell`, and reports what it sent with `on_sent`. Cells wait in a queue
+/// per circuit (per direction, as a circuit arrives on a different link from each side),
+/// released in order, circuits taking turns.
+///
+/// Near the monthly cap the relay hibernates: past `SOFT_CAP_PERCENT` of it new circuits are
+/// refused, at the cap every cell is, until the next month starts.
+pub struct BandwidthLimiter {
+    config: BandwidthConfig,
+    global: TokenBucket,
+    circuits: HashMap<CircuitLink, CircuitQueue>,
+    /// Circuits with cells waiting, in turn order
+    turns: VecDeque<CircuitLink>,
+    /// Months since 1970 of the accounting period, and the bytes counted in it
+    month: u64,
+    used: u64,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: BandwidthConfig, now_ms: u64) -> Self {
+        Self {
+            config,
+            global: TokenBucket::full(config.burst, now_ms),
+            circuits: HashMap::new(),
+            turns: VecDeque::new(),
+            month: month_of(now_ms),
+            used: 0,
+        }
+    }
+
+    /// Queues a cell received from `from`, to be released by `poll`.
+    pub fn push(&mut self, from: SocketAddr, cell: Cell, now_ms: u64) -> Result<(), BandwidthError> {
+        self.roll_month(now_ms);
+        if self.is_hibernating(now_ms) || (cell.command == CellCommand::Create && self.over_soft_cap()) {
+            return Err(BandwidthError::Hibernating);
+        }
+
+        let link = CircuitLink::new(from, cell.circuit_id);
+        let burst = self.config.circuit_burst;
+        let queue = self.circuits.entry(link).or_insert_with(|| CircuitQueue { bucket: TokenBucket::full(burst, now_ms), cells: VecDeque::new() });
+        if queue.cells.len() >= MAX_QUEUED_CELLS {
+            return Err(BandwidthError::QueueFull(cell.circuit_id));
+        }
+        if queue.cells.is_empty() {
+            self.turns.push_back(link);
+        }
+        queue.cells.push_back(cell);
+        self.used += CELL_SIZE as u64;
+        Ok(())
+    }
+
+    /// Releases the queued cells the buckets allow by `now_ms`, each with the neighbour it came
+    /// from, one cell per circuit in turn. A circuit out of tokens waits for its next turn.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<(SocketAddr, Cell)> {
+        let config = self.config;
+        self.global.refill(config.rate, config.burst, now_ms);
+        let mut released = Vec::new();
+        let mut waiting = 0;
+        while waiting < self.turns.len() && self.global.has(CELL_SIZE) {
+            let link = self.turns.pop_front().expect("checked above");
+            let queue = self.circuits.get_mut(&link).expect("queued circuits are known");
+            queue.bucket.refill(config.circuit_rate, config.circuit_burst, now_ms);
+            if !queue.bucket.has(CELL_SIZE) {
+                self.turns.push_back(link);
+                waiting += 1;
+                continue;
+            }
+
+            let cell = queue.cells.pop_front().expect("circuits in turn have cells");
+            queue.bucket.tokens -= CELL_SIZE as f64;
+            self.global.tokens -= CELL_SIZE as f64;
+            released.push((link.peer, cell));
+            waiting = 0;
+            if !queue.cells.is_empty() {
+                self.turns.push_back(link);
+            }
+        }
+        released
+    }
+
+    /// The host sent `cells` cells: they count against the monthly cap.
+    pub fn on_sent(&mut self, cells: usize, now_ms: u64) {
+        self.roll_month(now_ms);
+        self.used += (cells * CELL_SIZE) as u64;
+    }
+
+    /// Whether the monthly cap is reached: every cell is refused until the month ends.
+    pub fn is_hibernating(&self, now_ms: u64) -> bool {
+        month_of(now_ms) == self.month && self.config.monthly_cap.is_some_and(|cap| self.used >= cap)
+    }
+
+    /// Bytes received and sent this month
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Cells waiting for bandwidth, all circuits
+    pub fn queued(&self) -> usize {
+        self.circuits.values().map(|q| q.cells.len()).sum()
+    }
+
+    /// Forgets a circuit the relay tore down, with the cells it had waiting.
+    pub fn forget(&mut self, link: CircuitLink) {
+        self.circuits.remove(&link);
+        self.turns.retain(|l| *l != link);
+    }
+
+    /// Drops circuits with nothing queued whose bucket refilled: they start full again anyway.
+    pub fn prune(&mut self, now_ms: u64) {
+        let config = self.config;
+        self.circuits.retain(|_, queue| {
+            queue.bucket.refill(config.circuit_rate, config.circuit_burst, now_ms);
+            !queue.cells.is_empty() || queue.bucket.tokens < config.circuit_burst as f64
+        });
+    }
+
+    fn over_soft_cap(&self) -> bool {
+        self.config.monthly_cap.is_some_and(|cap| self.used >= cap / 100 * SOFT_CAP_PERCENT)
+    }
+
+    fn roll_month(&mut self, now_ms: u64) {
+        let month = month_of(now_ms);
+        if month != self.month {
+            log::info!("Bandwidth accounting: {} bytes relayed last period, starting a new month", self.used);
+            self.month = month;
+            self.used = 0;
+        }
+    }
+}
+
+/// Months since January 1970 (UTC) at `now_ms`, from the civil calendar (Hinnant's algorithm)
+pub(crate) fn month_of(now_ms: u64) -> u64 {
+    let days = now_ms / 86_400_000 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153; // March = 0
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year - 1970) * 12 + (month - 1)
+}
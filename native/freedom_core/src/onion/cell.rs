@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+/// Every cell is exactly this long on the wire, whatever it carries
+pub const CELL_SIZE: usize = 512;
+const CELL_HEADER_SIZE: usize = 5;
+/// Bytes left for a cell's payload (the relay body for RELAY cells)
+pub const CELL_PAYLOAD_SIZE: usize = CELL_SIZE - CELL_HEADER_SIZE;
+
+const RELAY_HEADER_SIZE: usize = 11;
+/// Most data a single relay cell can carry
+pub const RELAY_DATA_SIZE: usize = CELL_PAYLOAD_SIZE - RELAY_HEADER_SIZE;
+
+// Offsets inside the relay body
+const RECOGNIZED_OFFSET: usize = 1;
+pub(crate) const DIGEST_OFFSET: usize = 5;
+pub(crate) const DIGEST_SIZE: usize = 4;
+
+/// Size of the ntor handshake in CREATE / EXTEND: [NodeId (32)] [OnionKey (32)] [ClientKey (32)]
+pub const CREATE_HANDSHAKE_SIZE: usize = 96;
+/// Size of the ntor reply in CREATED / EXTENDED: [ServerKey (32)] [Auth (32)]
+pub const CREATED_HANDSHAKE_SIZE: usize = 64;
+
+/// Payload of a RELAY cell: onion-encrypted, and decrypted one layer per hop
+pub type RelayBody = [u8; CELL_PAYLOAD_SIZE];
+
+/// What a cell asks of the hop it is addressed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CellCommand {
+    /// Opens a circuit on this link: [Handshake (96)]
+    Create = 1,
+    /// Answers CREATE: [Reply (64)]
+    Created = 2,
+    /// Onion-encrypted relay body travelling along the circuit
+    Relay = 3,
+    /// Tears the circuit down: [Reason (1)]
+    Destroy = 4,
+}
+
+impl TryFrom<u8> for CellCommand {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => CellCommand::Create,
+            2 => CellCommand::Created,
+            3 => CellCommand::Relay,
+            4 => CellCommand::Destroy,
+            _ => {
+                return Err(CodecError::InvalidField("cell command"));
+            }
+        })
+    }
+}
+
+/// Why a circuit (or the part of it past some hop) was torn down, carried in DESTROY and
+/// RELAY TRUNCATED. Values follow Tor's; unknown values read as `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DestroyReason {
+    /// No reason given
+    None = 0,
+    /// A peer broke the protocol (e.g. failed handshake)
+    Protocol = 1,
+    Internal = 2,
+    /// The client asked for it (TRUNCATE)
+    Requested = 3,
+    ResourceLimit = 5,
+    /// The next hop could not be reached
+    ConnectFailed = 6,
+    /// The link to the next hop went away
+    ChannelClosed = 8,
+    /// The client is done with the circuit
+    Finished = 9,
+    Timeout = 10,
+    /// Another hop tore the circuit down
+    Destroyed = 11,
+}
+
+impl From<u8> for DestroyReason {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => DestroyReason::Protocol,
+            2 => DestroyReason::Internal,
+            3 => DestroyReason::Requested,
+            5 => DestroyReason::ResourceLimit,
+            6 => DestroyReason::ConnectFailed,
+            8 => DestroyReason::ChannelClosed,
+            9 => DestroyReason::Finished,
+            10 => DestroyReason::Timeout,
+            11 => DestroyReason::Destroyed,
+            _ => DestroyReason::None,
+        }
+    }
+}
+
+/// Unit of circuit traffic between two adjacent nodes, carried in `MessageType::Onion` packets.
+/// Cells are always `CELL_SIZE` bytes, so control and relay traffic look alike on the wire.
+/// Circuit ids are scoped to the link: each hop maps the id it received to the one it uses
+/// towards the next hop.
+/// Format: [CircuitId (4)] [Command (1)] [Payload (507), zero-padded]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub circuit_id: u32,
+    pub command: CellCommand,
+    /// Boxed so cells stay cheap to move through queues
+    pub payload: Box<RelayBody>,
+}
+
+impl Cell {
+    /// Control cell; `data` is zero-padded to the payload size.
+    /// # Panics
+    /// If `data` is longer than `CELL_PAYLOAD_SIZE` (control payloads are fixed-size handshakes).
+    pub fn new(circuit_id: u32, command: CellCommand, data: &[u8]) -> Self {
+        let mut payload = [0u8; CELL_PAYLOAD_SIZE];
+        payload[..data.len()].copy_from_slice(data);
+        Self { circuit_id, command, payload: Box::new(payload) }
+    }
+
+    pub fn destroy(circuit_id: u32, reason: DestroyReason) -> Self {
+        Self::new(circuit_id, CellCommand::Destroy, &[reason as u8])
+    }
+
+    /// Reason of a DESTROY cell
+    pub fn destroy_reason(&self) -> DestroyReason {
+        DestroyReason::from(self.payload[0])
+    }
+
+    pub fn relay(circuit_id: u32, body: RelayBody) -> Self {
+        Self { circuit_id, command: CellCommand::Relay, payload: Box::new(body) }
+    }
+
+    /// The first `N` payload bytes, for fixed-size control payloads
+    pub fn data<const N: usize>(&self) -> [u8; N] {
+        self.payload[..N].try_into().expect("control payloads fit in a cell")
+    }
+
+    pub fn to_bytes(&self) -> [u8; CELL_SIZE] {
+        let mut out = [0u8; CELL_SIZE];
+        out[..4].copy_from_slice(&self.circuit_id.to_be_bytes());
+        out[4] = self.command as u8;
+        out[CELL_HEADER_SIZE..].copy_from_slice(&self.payload[..]);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let circuit_id = reader.u32()?;
+        let command = CellCommand::try_from(reader.u8()?)?;
+        let payload = Box::new(reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { circuit_id, command, payload })
+    }
+
+    pub fn to_packet(&self, request_id: u32) -> NetworkPacket {
+        NetworkPacket::new(MessageType::Onion, request_id, self.to_bytes().to_vec())
+    }
+
+    pub fn from_packet(packet: &NetworkPacket) -> Result<Self, CodecError> {
+        if packet.header.message_type != MessageType::Onion {
+            return Err(CodecError::InvalidField("message type"));
+        }
+        Self::from_bytes(&packet.payload)
+    }
+}
+
+/// What a relay cell asks of the hop that recognizes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RelayCommand {
+    /// Open a stream to a target: data is a `StreamTarget`
+    Begin = 1,
+    /// Application data on a stream
+    Data = 2,
+    /// Close a stream: [Reason (1)]
+    End = 3,
+    /// The exit connected the stream opened by BEGIN
+    Connected = 4,
+    /// The receiver took delivery of more DATA: on stream 0 for the circuit window, else for the stream's
+    Sendme = 5,
+    /// Extend the circuit by one hop: data is an `ExtendRequest`
+    Extend = 6,
+    /// The extension succeeded: [Reply (64)]
+    Extended = 7,
+    /// Tear down the circuit past this hop, keeping the hops up to it
+    Truncate = 8,
+    /// The circuit now ends at this hop: [Reason (1)]
+    Truncated = 9,
+    /// Padding: the hop it is addressed to discards it
+    Drop = 10,
+    /// Open a stream to this hop's directory (no data). Any hop may answer it, not only exits
+    BeginDir = 13,
+    /// (Service) Become our introduction point: data is an `EstablishIntro`
+    EstablishIntro = 32,
+    /// (Client) Wait here for a service to join this circuit: [Cookie (20)]
+    EstablishRendezvous = 33,
+    /// (Client) Pass this to the service: data is an `Introduce`
+    Introduce1 = 34,
+    /// (Introduction point) A client's INTRODUCE1, passed on to the service
+    Introduce2 = 35,
+    /// (Service) Join the client waiting with this cookie: [Cookie (20)] [Reply (64)]
+    Rendezvous1 = 36,
+    /// (Rendezvous point) The service joined: [Reply (64)]
+    Rendezvous2 = 37,
+    /// Answers ESTABLISH_INTRO
+    IntroEstablished = 38,
+    /// Answers ESTABLISH_RENDEZVOUS
+    RendezvousEstablished = 39,
+    /// Answers INTRODUCE1: [Status (1)]
+    IntroduceAck = 40,
+    /// Start or stop padding at this hop: data is a `PaddingNegotiate`
+    PaddingNegotiate = 41,
+    /// Answers PADDING_NEGOTIATE: [Accepted (1)]
+    PaddingNegotiated = 42,
+}
+
+impl TryFrom<u8> for RelayCommand {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => RelayCommand::Begin,
+            2 => RelayCommand::Data,
+            3 => RelayCommand::End,
+            4 => RelayCommand::Connected,
+            5 => RelayCommand::Sendme,
+            6 => RelayCommand::Extend,
+            7 => RelayCommand::Extended,
+            8 => RelayCommand::Truncate,
+            9 => RelayCommand::Truncated,
+            10 => RelayCommand::Drop,
+            13 => RelayCommand::BeginDir,
+            32 => RelayCommand::EstablishIntro,
+            33 => RelayCommand::EstablishRendezvous,
+            34 => RelayCommand::Introduce1,
+            35 => RelayCommand::Introduce2,
+            36 => RelayCommand::Rendezvous1,
+            37 => RelayCommand::Rendezvous2,
+            38 => RelayCommand::IntroEstablished,
+            39 => RelayCommand::RendezvousEstablished,
+            40 => RelayCommand::IntroduceAck,
+            41 => RelayCommand::PaddingNegotiate,
+            42 => RelayCommand::PaddingNegotiated,
+            _ => {
+                return Err(CodecError::InvalidField("relay command"));
+            }
+        })
+    }
+}
+
+/// Plaintext of a relay body, for the hop it is addressed to.
+/// A hop knows a body is for it when, after removing its layer, `Recognized` is zero and the
+/// digest matches its running digest of the circuit; otherwise it passes the body on.
+/// Format: [Command (1)] [Recognized (2)] [StreamId (2)] [Digest (4)] [Length (2)] [Data (496), zero-padded]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayCell {
+    pub command: RelayCommand,
+    /// 0 for circuit-level commands
+    pub stream_id: u16,
+    pub data: Vec<u8>,
+}
+
+impl RelayCell {
+    pub fn new(command: RelayCommand, stream_id: u16, data: Vec<u8>) -> Self {
+        Self { command, stream_id, data }
+    }
+
+    /// Encodes the body with a zero digest; the sender's layer stamps it (see `RelayLayer::seal`).
+    pub fn to_body(&self) -> Result<RelayBody, CodecError> {
+        if self.data.len() > RELAY_DATA_SIZE {
+            return Err(CodecError::InvalidField("relay data length"));
+        }
+
+        let mut body = [0u8; CELL_PAYLOAD_SIZE];
+        body[0] = self.command as u8;
+        body[3..5].copy_from_slice(&self.stream_id.to_be_bytes());
+        body[9..11].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+        body[RELAY_HEADER_SIZE..RELAY_HEADER_SIZE + self.data.len()].copy_from_slice(&self.data);
+        Ok(body)
+    }
+
+    /// Decodes a recognized body.
+    pub fn from_body(body: &RelayBody) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(body);
+        let command = RelayCommand::try_from(reader.u8()?)?;
+        let _recognized = reader.u16()?;
+        let stream_id = reader.u16()?;
+        let _digest = reader.take(DIGEST_SIZE)?;
+        let len = reader.u16()? as usize;
+        if len > RELAY_DATA_SIZE {
+            return Err(CodecError::InvalidField("relay data length"));
+        }
+        Ok(Self { command, stream_id, data: reader.take(len)?.to_vec() })
+    }
+}
+
+/// Whether a decrypted body has a zero `Recognized` field (the cheap check before the digest).
+pub(crate) fn maybe_recognized(body: &RelayBody) -> bool {
+    body[RECOGNIZED_OFFSET..RECOGNIZED_OFFSET + 2] == [0, 0]
+}
+
+/// Data of RELAY EXTEND: who to extend to, and the handshake to send them in CREATE.
+/// Format: [NodeId (32)] [IP_Len (1) | IP | Port (2)] [Handshake (96)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendRequest {
+    pub node_id: NodeId,
+    pub addr: SocketAddr,
+    pub handshake: [u8; CREATE_HANDSHAKE_SIZE],
+}
+
+impl ExtendRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + 19 + CREATE_HANDSHAKE_SIZE);
+        out.extend_from_slice(self.node_id.as_bytes());
+        codec::write_socket_addr(&mut out, &self.addr);
+        out.extend_from_slice(&self.handshake);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let request = Self {
+            node_id: NodeId::from_bytes(reader.take_array()?),
+            addr: codec::read_socket_addr(&mut reader)?,
+            handshake: reader.take_array()?,
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
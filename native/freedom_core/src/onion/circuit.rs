@@ -0,0 +1,878 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::net::SocketAddr;
+
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use rand::{ Rng, RngCore };
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+use super::cell::{ Cell, CellCommand, DestroyReason, ExtendRequest, RelayBody, RelayCell, RelayCommand, CREATED_HANDSHAKE_SIZE };
+use super::ntor::ClientHandshake;
+use super::intro::{ EstablishIntro, Introduce, IntroduceStatus, IntroPointInfo };
+use super::layer::HopCrypto;
+use super::padding::{ PaddingMachine, PaddingNegotiate, PaddingSpec };
+use super::rendezvous::{ AcceptedIntroduction, Introduction, RendezvousCookie, COOKIE_SIZE };
+use super::timeout::BuildTimeEstimator;
+use super::stream::{ EndReason, StreamError, StreamEvent, StreamSet, StreamState, StreamTarget };
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+use crate::protocol::descriptor::NodeDescriptor;
+
+/// Longest path a circuit may take
+pub const MAX_CIRCUIT_HOPS: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CircuitError {
+    #[error("Circuit path is empty")]
+    EmptyPath,
+    #[error("Circuit path too long: {0} hops (max {MAX_CIRCUIT_HOPS})")]
+    PathTooLong(usize),
+    #[error("Unknown circuit {0}")]
+    UnknownCircuit(u32),
+    #[error("Circuit {0} already exists")]
+    DuplicateCircuit(u32),
+    #[error("Unexpected {0:?} cell")]
+    UnexpectedCell(CellCommand),
+    #[error("Unexpected relay {0:?}")]
+    UnexpectedRelay(RelayCommand),
+    #[error("Relay cell not recognized by any hop")]
+    Unrecognized,
+    #[error("Circuit {0} is not open")]
+    NotOpen(u32),
+    #[error("Circuit has no hop {0}")]
+    NoSuchHop(usize),
+    #[error("Circuit {0} is not waiting at a rendezvous point")]
+    NoRendezvous(u32),
+    #[error("Stream error: {0}")]
+    Stream(#[from] StreamError),
+    #[error("Malformed cell: {0}")]
+    Malformed(#[from] CodecError),
+}
+
+/// A relay a circuit goes through, as learned from its descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    pub node_id: NodeId,
+    pub addr: SocketAddr,
+    pub onion_key: X25519PublicKey,
+}
+
+impl Hop {
+    /// Uses the descriptor's first address. The descriptor should be verified by the caller.
+    pub fn from_descriptor(descriptor: &NodeDescriptor) -> Option<Self> {
+        Some(Self {
+            node_id: descriptor.node_id,
+            addr: *descriptor.addresses.first()?,
+            onion_key: descriptor.onion_key,
+        })
+    }
+
+    /// Format: [NodeId (32)] [OnionKey (32)] [Addr (IP_Len | IP | Port)]
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.node_id.as_bytes());
+        out.extend_from_slice(self.onion_key.as_bytes());
+        codec::write_socket_addr(out, &self.addr);
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let node_id = NodeId::from_bytes(reader.take_array()?);
+        let onion_key = X25519PublicKey::from(reader.take_array::<32>()?);
+        let addr = codec::read_socket_addr(reader)?;
+        Ok(Self { node_id, addr, onion_key })
+    }
+}
+
+/// Why a circuit stopped being usable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitFailure {
+    /// Not built within the build timeout
+    Timeout,
+    /// A hop could not prove it holds the onion key from its descriptor
+    HandshakeFailed { hop: usize },
+    /// The first hop tore the circuit down
+    Destroyed(DestroyReason),
+    /// While building, the circuit was cut after hop `hop` (the next one refused or was unreachable)
+    Truncated {
+        hop: usize,
+        reason: DestroyReason,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// `hops` of the path have keys; the next one is being added
+    Building {
+        hops: usize,
+    },
+    Open,
+    Failed(CircuitFailure),
+}
+
+/// What happened to a circuit as a result of a cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitEvent {
+    /// One more hop was added; the circuit keeps building
+    Extended {
+        circuit: CircuitHandle,
+        hops: usize,
+    },
+    /// Every hop of the path was added
+    Opened(CircuitHandle),
+    Failed(CircuitHandle, CircuitFailure),
+    /// A relay cell from hop `hop` (0 is the first hop) of an open circuit
+    Relay {
+        circuit: CircuitHandle,
+        hop: usize,
+        cell: RelayCell,
+    },
+    /// The circuit now ends at its `hops`-th hop, after a TRUNCATE or because the rest of it was
+    /// torn down. Its `streams` ended at the hops cut off and are closed.
+    Truncated {
+        circuit: CircuitHandle,
+        hops: usize,
+        reason: DestroyReason,
+        streams: Vec<StreamHandle>,
+    },
+    /// Something happened on one of the circuit's streams to hop `hop`
+    Stream {
+        circuit: CircuitHandle,
+        hop: usize,
+        event: StreamEvent,
+    },
+    /// The last hop registered us as a service with `establish_intro`
+    IntroEstablished(CircuitHandle),
+    /// (Service) A client reached us through the introduction point at the circuit's last hop
+    Introduced {
+        circuit: CircuitHandle,
+        introduce: Introduce,
+    },
+    /// (Client) The introduction point answered `introduce`
+    IntroduceAck {
+        circuit: CircuitHandle,
+        status: IntroduceStatus,
+    },
+    /// (Client) The last hop keeps the circuit for a service to join; see `establish_rendezvous`
+    RendezvousEstablished(CircuitHandle),
+    /// The other end of a rendezvous joined: it is now the circuit's last hop
+    RendezvousCompleted(CircuitHandle),
+    /// Hop `hop` answered `negotiate_padding`; if it accepted, both ends now pad the circuit
+    PaddingNegotiated {
+        circuit: CircuitHandle,
+        hop: usize,
+        accepted: bool,
+    },
+}
+
+/// Refers to a circuit owned by a `CircuitManager`; also its id on the link to the first hop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CircuitHandle(u32);
+
+impl CircuitHandle {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Refers to a stream opened with `CircuitManager::open_stream` (or `open_stream_at`), or
+/// accepted with `accept_stream`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamHandle {
+    pub circuit: CircuitHandle,
+    /// The hop the stream ends at; stream ids are scoped to it
+    pub hop: usize,
+    pub stream: u16,
+}
+
+/// Padding between the client and one hop of a circuit.
+enum CircuitPadding {
+    /// Asked the hop, waiting for its answer
+    Requested(usize, PaddingSpec),
+    Active(usize, PaddingMachine),
+}
+
+/// A circuit's part in a rendezvous.
+enum Rendezvous {
+    /// (Client) Waiting at the last hop under this cookie
+    Waiting(RendezvousCookie),
+    /// (Service) To join the client once the circuit reaches the rendezvous point
+    Joining(AcceptedIntroduction),
+}
+
+struct Circuit {
+    path: Vec<Hop>,
+    /// Layers of the hops built so far, first hop first. Once a rendezvous completes there is
+    /// one more than `path`: the other end, reached through the rendezvous point.
+    hops: Vec<HopCrypto>,
+    pending: Option<ClientHandshake>,
+    state: CircuitState,
+    deadline: u64,
+    /// Streams by the hop they end at. Each hop that ends streams has its own circuit window.
+    streams: BTreeMap<usize, StreamSet>,
+    padding: Option<CircuitPadding>,
+    /// Real relay cells sent or received since the last `poll_padding`
+    traffic: u64,
+    rendezvous: Option<Rendezvous>,
+}
+
+impl Circuit {
+    /// Stamps a relay cell with the digest of hop `hop`, then adds the layers of that hop and
+    /// every hop before it, innermost first.
+    fn seal_forward(&mut self, hop: usize, cell: &RelayCell) -> Result<RelayBody, CircuitError> {
+        let mut body = cell.to_body()?;
+        self.hops[hop].forward.seal(&mut body);
+        for crypto in self.hops[..=hop].iter_mut().rev() {
+            crypto.forward.apply(&mut body);
+        }
+        Ok(body)
+    }
+
+    /// Hop `hop` answered our padding request; returns false if we had none pending for it.
+    fn padding_negotiated(&mut self, hop: usize, accepted: bool) -> bool {
+        match self.padding {
+            Some(CircuitPadding::Requested(requested, spec)) if requested == hop => {
+                self.padding = accepted.then(|| CircuitPadding::Active(hop, PaddingMachine::new(spec)));
+                true
+            }
+            // Answer to a request we replaced or stopped since
+            _ => false,
+        }
+    }
+
+    /// Removes backward layers from the first hop on until one hop recognizes the body as its own.
+    fn open_backward(&mut self, mut body: RelayBody) -> Result<(usize, RelayCell), CircuitError> {
+        for (hop, crypto) in self.hops.iter_mut().enumerate() {
+            crypto.backward.apply(&mut body);
+            if crypto.backward.recognize(&body) {
+                return Ok((hop, RelayCell::from_body(&body)?));
+            }
+        }
+        Err(CircuitError::Unrecognized)
+    }
+}
+
+/// Builds circuits telescopically from the client side: CREATE to the first hop, then one
+/// EXTEND per further hop, each sent through the part of the circuit already built so that
+/// only the first hop learns who the client is. Sans-IO: cells to send are collected in an
+/// outbox the host drains with `take_outgoing`, and incoming cells are fed to `on_cell`.
+/// Circuits still building when their build timeout passes are abandoned by `expire`; the
+/// timeout adapts to the build times the host reports with `record_build_time`.
+pub struct CircuitManager {
+    circuits: HashMap<u32, Circuit>,
+    outgoing: Vec<(SocketAddr, Cell)>,
+    build_times: BuildTimeEstimator,
+    /// Replaces the adaptive timeout when set (seconds)
+    fixed_timeout: Option<u64>,
+}
+
+impl Default for CircuitManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CircuitManager {
+    pub fn new() -> Self {
+        Self {
+            circuits: HashMap::new(),
+            outgoing: Vec::new(),
+            build_times: BuildTimeEstimator::new(),
+            fixed_timeout: None,
+        }
+    }
+
+    /// Uses a fixed build timeout instead of the adaptive one.
+    pub fn with_build_timeout(mut self, secs: u64) -> Self {
+        self.fixed_timeout = Some(secs);
+        self
+    }
+
+    /// Timeout given to circuits opened now, in seconds
+    pub fn build_timeout(&self) -> u64 {
+        self.fixed_timeout.unwrap_or_else(|| self.build_times.timeout_secs())
+    }
+
+    /// Reports how long a circuit took to open (from `open_circuit` to `CircuitEvent::Opened`),
+    /// as timed by the host.
+    pub fn record_build_time(&mut self, elapsed_ms: u64) {
+        self.build_times.record_build(elapsed_ms);
+    }
+
+    pub fn build_times(&self) -> &BuildTimeEstimator {
+        &self.build_times
+    }
+
+    /// Starts building a circuit along `path` (first hop first) by sending CREATE to its first hop.
+    pub fn open_circuit(&mut self, path: Vec<Hop>, now: u64) -> Result<CircuitHandle, CircuitError> {
+        if path.is_empty() {
+            return Err(CircuitError::EmptyPath);
+        }
+        if path.len() > MAX_CIRCUIT_HOPS {
+            return Err(CircuitError::PathTooLong(path.len()));
+        }
+
+        let id = self.unused_id();
+        let (pending, handshake) = ClientHandshake::start(path[0].node_id, path[0].onion_key);
+        self.outgoing.push((path[0].addr, Cell::new(id, CellCommand::Create, &handshake)));
+        self.circuits.insert(id, Circuit {
+            path,
+            hops: Vec::new(),
+            pending: Some(pending),
+            state: CircuitState::Building { hops: 0 },
+            deadline: now.saturating_add(self.build_timeout()),
+            streams: BTreeMap::new(),
+            padding: None,
+            traffic: 0,
+            rendezvous: None,
+        });
+
+        log::debug!("Building circuit {id}");
+        Ok(CircuitHandle(id))
+    }
+
+    /// Handles a cell received from the first hop of one of our circuits.
+    pub fn on_cell(&mut self, from: SocketAddr, cell: Cell) -> Result<Option<CircuitEvent>, CircuitError> {
+        let handle = CircuitHandle(cell.circuit_id);
+        let circuit = match self.circuits.get_mut(&cell.circuit_id) {
+            Some(circuit) if circuit.path[0].addr == from => circuit,
+            _ => {
+                return Err(CircuitError::UnknownCircuit(cell.circuit_id));
+            }
+        };
+        if matches!(circuit.state, CircuitState::Failed(_)) && cell.command != CellCommand::Destroy {
+            return Err(CircuitError::UnexpectedCell(cell.command));
+        }
+
+        let reply = match cell.command {
+            CellCommand::Created if circuit.hops.is_empty() => cell.data::<CREATED_HANDSHAKE_SIZE>(),
+            CellCommand::Relay if !circuit.hops.is_empty() => {
+                let (hop, relay) = circuit.open_backward(*cell.payload)?;
+                let building = matches!(circuit.state, CircuitState::Building { .. });
+                if relay.command != RelayCommand::Drop {
+                    circuit.traffic += 1;
+                }
+                match relay.command {
+                    RelayCommand::Truncated => {
+                        let reason = DestroyReason::from(relay.data.first().copied().unwrap_or_default());
+                        return Ok(Some(self.truncated(handle, hop, reason)));
+                    }
+                    RelayCommand::Extended if building && hop + 1 == circuit.hops.len() => {
+                        relay.data
+                            .get(..CREATED_HANDSHAKE_SIZE)
+                            .and_then(|reply| reply.try_into().ok())
+                            .ok_or(CodecError::InvalidField("extended"))?
+                    }
+                    RelayCommand::Drop if !building => {
+                        return Ok(None);
+                    }
+                    RelayCommand::PaddingNegotiated if !building => {
+                        let accepted = relay.data.first() == Some(&1);
+                        if !circuit.padding_negotiated(hop, accepted) {
+                            return Ok(None);
+                        }
+                        return Ok(Some(CircuitEvent::PaddingNegotiated { circuit: handle, hop, accepted }));
+                    }
+                    RelayCommand::IntroEstablished if !building && hop + 1 == circuit.hops.len() => {
+                        return Ok(Some(CircuitEvent::IntroEstablished(handle)));
+                    }
+                    RelayCommand::RendezvousEstablished if !building && hop + 1 == circuit.hops.len() => {
+                        return Ok(Some(CircuitEvent::RendezvousEstablished(handle)));
+                    }
+                    // The service's half of the handshake we sent it in `introduce_rendezvous`
+                    RelayCommand::Rendezvous2
+                        if !building &&
+                        hop + 1 == circuit.hops.len() &&
+                        circuit.pending.is_some() &&
+                        matches!(circuit.rendezvous, Some(Rendezvous::Waiting(_))) => {
+                        relay.data
+                            .get(..CREATED_HANDSHAKE_SIZE)
+                            .and_then(|reply| reply.try_into().ok())
+                            .ok_or(CodecError::InvalidField("rendezvous2"))?
+                    }
+                    RelayCommand::Introduce2 if !building && hop + 1 == circuit.hops.len() => {
+                        let introduce = Introduce::from_bytes(&relay.data)?;
+                        return Ok(Some(CircuitEvent::Introduced { circuit: handle, introduce }));
+                    }
+                    RelayCommand::IntroduceAck if !building && hop + 1 == circuit.hops.len() => {
+                        let status = IntroduceStatus::from(relay.data.first().copied().unwrap_or(IntroduceStatus::Malformed as u8));
+                        return Ok(Some(CircuitEvent::IntroduceAck { circuit: handle, status }));
+                    }
+                    // The other end of a rendezvous opening a stream to us
+                    RelayCommand::Begin if !building && hop == circuit.path.len() => {
+                        let event = circuit.streams.entry(hop).or_default().on_cell(relay)?;
+                        return Ok(event.map(|event| CircuitEvent::Stream { circuit: handle, hop, event }));
+                    }
+                    // Circuit SENDMEs and cells for streams we opened go to the stream layer of the hop
+                    // that sent them, anything else to the host
+                    RelayCommand::Connected | RelayCommand::Data | RelayCommand::End | RelayCommand::Sendme
+                        if !building &&
+                        circuit.streams.get(&hop).is_some_and(|streams| {
+                            relay.command == RelayCommand::Sendme && relay.stream_id == 0 ||
+                                streams.state(relay.stream_id).is_some()
+                        }) => {
+                        let event = circuit.streams.get_mut(&hop).expect("checked above").on_cell(relay)?;
+                        self.flush_streams(handle)?;
+                        return Ok(event.map(|event| CircuitEvent::Stream { circuit: handle, hop, event }));
+                    }
+                    _ if !building => {
+                        return Ok(Some(CircuitEvent::Relay { circuit: handle, hop, cell: relay }));
+                    }
+                    command => {
+                        return Err(CircuitError::UnexpectedRelay(command));
+                    }
+                }
+            }
+            CellCommand::Destroy => {
+                let reason = cell.destroy_reason();
+                self.circuits.remove(&cell.circuit_id);
+                log::debug!("Circuit {} destroyed by its first hop ({reason:?})", cell.circuit_id);
+                return Ok(Some(CircuitEvent::Failed(handle, CircuitFailure::Destroyed(reason))));
+            }
+            command => {
+                return Err(CircuitError::UnexpectedCell(command));
+            }
+        };
+
+        let hop = circuit.hops.len();
+        let Some(key) = circuit.pending.take().and_then(|pending| pending.finish(&reply)) else {
+            log::warn!("Hop {hop} of circuit {} failed its handshake", cell.circuit_id);
+            return Ok(Some(self.fail(handle, CircuitFailure::HandshakeFailed { hop })));
+        };
+        circuit.hops.push(HopCrypto::new(&key));
+        if let Some(Rendezvous::Waiting(_)) = circuit.rendezvous {
+            circuit.rendezvous = None;
+            log::debug!("Circuit {} joined by a service at its rendezvous point", cell.circuit_id);
+            return Ok(Some(CircuitEvent::RendezvousCompleted(handle)));
+        }
+
+        let hops = circuit.hops.len();
+        if hops == circuit.path.len() {
+            circuit.state = CircuitState::Open;
+            if let Some(Rendezvous::Joining(accepted)) = circuit.rendezvous.take() {
+                let mut data = accepted.cookie.to_vec();
+                data.extend_from_slice(&accepted.reply);
+                let body = circuit.seal_forward(hops - 1, &RelayCell::new(RelayCommand::Rendezvous1, 0, data))?;
+                circuit.hops.push(HopCrypto::reversed(&accepted.key));
+                self.outgoing.push((circuit.path[0].addr, Cell::relay(cell.circuit_id, body)));
+                log::debug!("Circuit {} joined its client at the rendezvous point", cell.circuit_id);
+                return Ok(Some(CircuitEvent::RendezvousCompleted(handle)));
+            }
+            log::debug!("Circuit {} open ({hops} hops)", cell.circuit_id);
+            return Ok(Some(CircuitEvent::Opened(handle)));
+        }
+
+        let next = circuit.path[hops];
+        let (pending, handshake) = ClientHandshake::start(next.node_id, next.onion_key);
+        let extend = ExtendRequest { node_id: next.node_id, addr: next.addr, handshake };
+        let body = circuit.seal_forward(hops - 1, &RelayCell::new(RelayCommand::Extend, 0, extend.to_bytes()))?;
+        circuit.pending = Some(pending);
+        circuit.state = CircuitState::Building { hops };
+
+        let first = circuit.path[0].addr;
+        self.outgoing.push((first, Cell::relay(cell.circuit_id, body)));
+        Ok(Some(CircuitEvent::Extended { circuit: handle, hops }))
+    }
+
+    /// Sends a relay cell on an open circuit to hop `hop` (0 is the first hop).
+    pub fn send(&mut self, handle: CircuitHandle, hop: usize, cell: RelayCell) -> Result<(), CircuitError> {
+        let circuit = self.circuits.get_mut(&handle.0).ok_or(CircuitError::UnknownCircuit(handle.0))?;
+        if circuit.state != CircuitState::Open {
+            return Err(CircuitError::NotOpen(handle.0));
+        }
+        if hop >= circuit.hops.len() {
+            return Err(CircuitError::NoSuchHop(hop));
+        }
+
+        let body = circuit.seal_forward(hop, &cell)?;
+        circuit.traffic += 1;
+        self.outgoing.push((circuit.path[0].addr, Cell::relay(handle.0, body)));
+        Ok(())
+    }
+
+    /// Asks hop `hop` of an open circuit to tear down the rest of the circuit. Once it confirms,
+    /// `CircuitEvent::Truncated` reports the circuit ending at that hop.
+    pub fn truncate(&mut self, handle: CircuitHandle, hop: usize) -> Result<(), CircuitError> {
+        let hops = self.open_circuit_mut(handle)?.hops.len();
+        if hop + 1 >= hops {
+            return Err(CircuitError::NoSuchHop(hop + 1));
+        }
+        self.send(handle, hop, RelayCell::new(RelayCommand::Truncate, 0, Vec::new()))
+    }
+
+    /// Registers the last hop of an open circuit as an introduction point for the service
+    /// whose introduction key there is `auth`. Clients then reach the service through it with
+    /// `introduce`; see `IntroPoints`.
+    pub fn establish_intro(&mut self, handle: CircuitHandle, auth: &SigningKey) -> Result<(), CircuitError> {
+        let circuit = self.open_circuit_mut(handle)?;
+        let last = circuit.hops.len() - 1;
+        let establish = EstablishIntro::new(auth, &circuit.hops[last].binding);
+        self.send(handle, last, RelayCell::new(RelayCommand::EstablishIntro, 0, establish.to_bytes()))
+    }
+
+    /// Asks the introduction point at the last hop of an open circuit to pass `introduce` on
+    /// to the service; it answers with `CircuitEvent::IntroduceAck`.
+    pub fn introduce(&mut self, handle: CircuitHandle, introduce: &Introduce) -> Result<(), CircuitError> {
+        let last = self.open_circuit_mut(handle)?.hops.len() - 1;
+        self.send(handle, last, RelayCell::new(RelayCommand::Introduce1, 0, introduce.to_bytes()?))
+    }
+
+    /// (Client) Makes the last hop of an open circuit our rendezvous point: it keeps the
+    /// circuit under a fresh cookie for a service to join, and confirms with
+    /// `CircuitEvent::RendezvousEstablished`.
+    pub fn establish_rendezvous(&mut self, handle: CircuitHandle) -> Result<(), CircuitError> {
+        let mut cookie = [0u8; COOKIE_SIZE];
+        OsRng.fill_bytes(&mut cookie);
+        let last = self.open_circuit_mut(handle)?.hops.len() - 1;
+        self.send(handle, last, RelayCell::new(RelayCommand::EstablishRendezvous, 0, cookie.to_vec()))?;
+        self.open_circuit_mut(handle)?.rendezvous = Some(Rendezvous::Waiting(cookie));
+        Ok(())
+    }
+
+    /// (Client) Asks the service behind `point`, through the introduction circuit `intro`, to
+    /// join the established rendezvous circuit `rendezvous`. When it does,
+    /// `CircuitEvent::RendezvousCompleted` reports the service as the rendezvous circuit's last
+    /// hop: relay cells and streams to that hop reach the service end to end.
+    pub fn introduce_rendezvous(
+        &mut self,
+        intro: CircuitHandle,
+        rendezvous: CircuitHandle,
+        point: &IntroPointInfo
+    ) -> Result<(), CircuitError> {
+        let circuit = self.open_circuit_mut(rendezvous)?;
+        let Some(Rendezvous::Waiting(cookie)) = circuit.rendezvous else {
+            return Err(CircuitError::NoRendezvous(rendezvous.0));
+        };
+        let (pending, handshake) = ClientHandshake::start(NodeId::from_identity_key(&point.auth_key), point.enc_key);
+        let introduction = Introduction { cookie, rendezvous: circuit.path[circuit.path.len() - 1], handshake };
+        let introduce = Introduce { auth_key: point.auth_key.to_bytes(), payload: introduction.seal(&point.enc_key) };
+
+        self.introduce(intro, &introduce)?;
+        self.open_circuit_mut(rendezvous)?.pending = Some(pending);
+        Ok(())
+    }
+
+    /// (Service) Builds a circuit along `path` and on to the rendezvous point of `accepted`,
+    /// and joins the client waiting there. `CircuitEvent::RendezvousCompleted` (instead of
+    /// `Opened`) then reports the client as the circuit's last hop.
+    pub fn open_rendezvous(&mut self, mut path: Vec<Hop>, accepted: AcceptedIntroduction, now: u64) -> Result<CircuitHandle, CircuitError> {
+        path.push(accepted.rendezvous);
+        let handle = self.open_circuit(path, now)?;
+        if let Some(circuit) = self.circuits.get_mut(&handle.0) {
+            circuit.rendezvous = Some(Rendezvous::Joining(accepted));
+        }
+        Ok(handle)
+    }
+
+    /// Asks hop `hop` of an open circuit to pad it according to `spec`, replacing any padding
+    /// the circuit had. Once the hop accepts (see `CircuitEvent::PaddingNegotiated`), it pads
+    /// towards us and we pad towards it.
+    pub fn negotiate_padding(&mut self, handle: CircuitHandle, hop: usize, spec: PaddingSpec) -> Result<(), CircuitError> {
+        self.stop_padding(handle)?;
+        self.send(handle, hop, RelayCell::new(RelayCommand::PaddingNegotiate, 0, PaddingNegotiate::Start(spec).to_bytes()))?;
+        self.open_circuit_mut(handle)?.padding = Some(CircuitPadding::Requested(hop, spec));
+        Ok(())
+    }
+
+    /// Stops padding a circuit, at both ends.
+    pub fn stop_padding(&mut self, handle: CircuitHandle) -> Result<(), CircuitError> {
+        let hop = match self.open_circuit_mut(handle)?.padding.take() {
+            Some(CircuitPadding::Requested(hop, _) | CircuitPadding::Active(hop, _)) => hop,
+            None => {
+                return Ok(());
+            }
+        };
+        self.send(handle, hop, RelayCell::new(RelayCommand::PaddingNegotiate, 0, PaddingNegotiate::Stop.to_bytes()))
+    }
+
+    /// Sends the padding cells due by `now_ms` (the host's millisecond clock) on padded circuits.
+    /// Traffic since the last call counts as happening now, so the host should call this more
+    /// often than the shortest padding delay it negotiated. Returns how many cells were sent.
+    pub fn poll_padding<R: Rng + ?Sized>(&mut self, now_ms: u64, rng: &mut R) -> Result<usize, CircuitError> {
+        let mut sent = 0;
+        for (&id, circuit) in &mut self.circuits {
+            let Some(CircuitPadding::Active(hop, machine)) = &mut circuit.padding else {
+                continue;
+            };
+            let hop = *hop;
+            if circuit.traffic > 0 {
+                machine.on_traffic(std::mem::take(&mut circuit.traffic), now_ms, rng);
+            }
+            if machine.poll(now_ms, rng) {
+                let body = circuit.seal_forward(hop, &RelayCell::new(RelayCommand::Drop, 0, Vec::new()))?;
+                self.outgoing.push((circuit.path[0].addr, Cell::relay(id, body)));
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Opens a stream through the last hop of an open circuit to `target`. The stream can be
+    /// written once `StreamEvent::Connected` is reported for it.
+    pub fn open_stream(&mut self, handle: CircuitHandle, target: &StreamTarget) -> Result<StreamHandle, CircuitError> {
+        let last = self.open_circuit_mut(handle)?.hops.len() - 1;
+        self.open_stream_at(handle, last, target)
+    }
+
+    /// Same as `open_stream`, leaving the circuit at hop `hop` instead of the last one: the
+    /// cells stop there, saving the trip through the later hops. The hop must allow `target`
+    /// in its exit policy.
+    pub fn open_stream_at(&mut self, handle: CircuitHandle, hop: usize, target: &StreamTarget) -> Result<StreamHandle, CircuitError> {
+        let stream = self.streams_at(handle, hop)?.begin(target)?;
+        self.flush_streams(handle)?;
+        Ok(StreamHandle { circuit: handle, hop, stream })
+    }
+
+    /// Opens a stream to the directory of hop `hop` (any relay serves one, exit or not), e.g.
+    /// to fetch the consensus from the middle of a circuit without a trip to the exit.
+    pub fn open_directory_stream(&mut self, handle: CircuitHandle, hop: usize) -> Result<StreamHandle, CircuitError> {
+        let stream = self.streams_at(handle, hop)?.begin_dir()?;
+        self.flush_streams(handle)?;
+        Ok(StreamHandle { circuit: handle, hop, stream })
+    }
+
+    /// Accepts a stream the other end of a rendezvous opened to us (reported as
+    /// `StreamEvent::Requested` from the hop past the rendezvous point), answering CONNECTED.
+    pub fn accept_stream(&mut self, handle: StreamHandle) -> Result<(), CircuitError> {
+        self.streams_at(handle.circuit, handle.hop)?.connected(handle.stream)?;
+        self.flush_streams(handle.circuit)
+    }
+
+    /// Queues data on a connected stream; see `StreamSet::write`.
+    pub fn write_stream(&mut self, handle: StreamHandle, data: &[u8]) -> Result<usize, CircuitError> {
+        let accepted = self.streams_at(handle.circuit, handle.hop)?.write(handle.stream, data)?;
+        self.flush_streams(handle.circuit)?;
+        Ok(accepted)
+    }
+
+    /// Reads data received on a stream; see `StreamSet::read`.
+    pub fn read_stream(&mut self, handle: StreamHandle, buf: &mut [u8]) -> Result<usize, CircuitError> {
+        let circuit = self.circuits.get_mut(&handle.circuit.0).ok_or(CircuitError::UnknownCircuit(handle.circuit.0))?;
+        let count = circuit.streams.get_mut(&handle.hop).map_or(0, |streams| streams.read(handle.stream, buf));
+        self.flush_streams(handle.circuit)?;
+        Ok(count)
+    }
+
+    /// Closes a stream from our side, telling the hop once queued data is sent.
+    pub fn close_stream(&mut self, handle: StreamHandle) -> Result<(), CircuitError> {
+        self.streams_at(handle.circuit, handle.hop)?.end(handle.stream, EndReason::Done);
+        self.flush_streams(handle.circuit)
+    }
+
+    /// State of a stream, or None once it ended and its data was read (or its circuit is gone)
+    pub fn stream_state(&self, handle: StreamHandle) -> Option<StreamState> {
+        self.circuits.get(&handle.circuit.0)?.streams.get(&handle.hop)?.state(handle.stream)
+    }
+
+    /// The stream as a `Read + Write` byte stream, for code that expects one.
+    pub fn stream(&mut self, handle: StreamHandle) -> CircuitStream<'_> {
+        CircuitStream { manager: self, handle }
+    }
+
+    /// Fails every circuit through a first hop we lost the link to. There is nobody left to tell,
+    /// so they are dropped at once.
+    pub fn on_link_closed(&mut self, peer: SocketAddr) -> Vec<CircuitEvent> {
+        let lost: Vec<u32> = self.circuits
+            .iter()
+            .filter(|(_, c)| c.path[0].addr == peer && !matches!(c.state, CircuitState::Failed(_)))
+            .map(|(id, _)| *id)
+            .collect();
+
+        lost.into_iter()
+            .map(|id| {
+                self.circuits.remove(&id);
+                CircuitEvent::Failed(CircuitHandle(id), CircuitFailure::Destroyed(DestroyReason::ChannelClosed))
+            })
+            .collect()
+    }
+
+    /// Abandons every circuit still building past its deadline, telling its first hop to tear it
+    /// down; the host builds a replacement along a fresh path.
+    pub fn expire(&mut self, now: u64) -> Vec<CircuitEvent> {
+        let expired: Vec<u32> = self.circuits
+            .iter()
+            .filter(|(_, c)| matches!(c.state, CircuitState::Building { .. }) && now >= c.deadline)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired
+            .into_iter()
+            .map(|id| {
+                log::debug!("Circuit {id} timed out while building");
+                if self.fixed_timeout.is_none() {
+                    self.build_times.record_timeout();
+                }
+                self.fail(CircuitHandle(id), CircuitFailure::Timeout)
+            })
+            .collect()
+    }
+
+    /// Tears a circuit down, or forgets a failed one (failed circuits are kept so their
+    /// failure can be read with `state`).
+    pub fn close(&mut self, handle: CircuitHandle) -> bool {
+        match self.circuits.remove(&handle.0) {
+            Some(circuit) => {
+                if !matches!(circuit.state, CircuitState::Failed(_)) {
+                    self.outgoing.push((circuit.path[0].addr, Cell::destroy(handle.0, DestroyReason::Finished)));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn state(&self, handle: CircuitHandle) -> Option<CircuitState> {
+        self.circuits.get(&handle.0).map(|c| c.state)
+    }
+
+    /// The relays of a circuit, first hop first
+    pub fn path(&self, handle: CircuitHandle) -> Option<&[Hop]> {
+        self.circuits.get(&handle.0).map(|c| c.path.as_slice())
+    }
+
+    /// Cells to send since the last call, with the address of the peer each goes to.
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Cell)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn len(&self) -> usize {
+        self.circuits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+
+    fn open_circuit_mut(&mut self, handle: CircuitHandle) -> Result<&mut Circuit, CircuitError> {
+        let circuit = self.circuits.get_mut(&handle.0).ok_or(CircuitError::UnknownCircuit(handle.0))?;
+        if circuit.state != CircuitState::Open {
+            return Err(CircuitError::NotOpen(handle.0));
+        }
+        Ok(circuit)
+    }
+
+    /// The stream layer of hop `hop` of an open circuit, set up on first use.
+    fn streams_at(&mut self, handle: CircuitHandle, hop: usize) -> Result<&mut StreamSet, CircuitError> {
+        let circuit = self.open_circuit_mut(handle)?;
+        if hop >= circuit.hops.len() {
+            return Err(CircuitError::NoSuchHop(hop));
+        }
+        Ok(circuit.streams.entry(hop).or_default())
+    }
+
+    /// Sends what the circuit's stream layers queued, each to the hop its streams end at.
+    fn flush_streams(&mut self, handle: CircuitHandle) -> Result<(), CircuitError> {
+        let Some(circuit) = self.circuits.get_mut(&handle.0) else {
+            return Ok(());
+        };
+        let queued: Vec<(usize, RelayCell)> = circuit.streams
+            .iter_mut()
+            .flat_map(|(hop, streams)| streams.take_outgoing().into_iter().map(move |cell| (*hop, cell)))
+            .collect();
+        for (hop, cell) in queued {
+            let body = circuit.seal_forward(hop, &cell)?;
+            circuit.traffic += 1;
+            self.outgoing.push((circuit.path[0].addr, Cell::relay(handle.0, body)));
+        }
+        Ok(())
+    }
+
+    /// Hop `hop` reported that the circuit now ends there. A building circuit can't reach the
+    /// rest of its path and fails; an open one carries on with fewer hops.
+    fn truncated(&mut self, handle: CircuitHandle, hop: usize, reason: DestroyReason) -> CircuitEvent {
+        let Some(circuit) = self.circuits.get_mut(&handle.0) else {
+            return CircuitEvent::Failed(handle, CircuitFailure::Truncated { hop, reason });
+        };
+        if circuit.state != CircuitState::Open {
+            log::debug!("Circuit {} truncated after hop {hop} while building ({reason:?})", handle.0);
+            return self.fail(handle, CircuitFailure::Truncated { hop, reason });
+        }
+
+        circuit.hops.truncate(hop + 1);
+        circuit.path.truncate(hop + 1);
+        if let Some(CircuitPadding::Requested(padded, _) | CircuitPadding::Active(padded, _)) = circuit.padding && padded > hop {
+            circuit.padding = None;
+        }
+        let streams = circuit.streams
+            .split_off(&(hop + 1))
+            .into_iter()
+            .flat_map(|(cut, mut streams)| {
+                streams.close_all().into_iter().map(move |stream| StreamHandle { circuit: handle, hop: cut, stream })
+            })
+            .collect();
+        log::debug!("Circuit {} truncated to {} hops ({reason:?})", handle.0, hop + 1);
+        CircuitEvent::Truncated { circuit: handle, hops: hop + 1, reason, streams }
+    }
+
+    /// Marks a circuit failed, drops its keys and streams, and tells its first hop to drop
+    /// whatever was built.
+    fn fail(&mut self, handle: CircuitHandle, failure: CircuitFailure) -> CircuitEvent {
+        if let Some(circuit) = self.circuits.get_mut(&handle.0) {
+            let reason = match failure {
+                CircuitFailure::Timeout => DestroyReason::Timeout,
+                CircuitFailure::HandshakeFailed { .. } => DestroyReason::Protocol,
+                CircuitFailure::Destroyed(_) | CircuitFailure::Truncated { .. } => DestroyReason::Destroyed,
+            };
+            circuit.state = CircuitState::Failed(failure);
+            circuit.pending = None;
+            circuit.hops.clear();
+            circuit.streams.clear();
+            circuit.padding = None;
+            circuit.rendezvous = None;
+            self.outgoing.push((circuit.path[0].addr, Cell::destroy(handle.0, reason)));
+        }
+        CircuitEvent::Failed(handle, failure)
+    }
+
+    fn unused_id(&self) -> u32 {
+        loop {
+            let id = OsRng.next_u32();
+            if id != 0 && !self.circuits.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+}
+
+/// A stream borrowed from its `CircuitManager` as a non-blocking byte stream: reads and writes
+/// fail with `WouldBlock` until the stream is connected, has data, or has room to queue more,
+/// and reads return 0 once the exit ended it. Written data is queued for flow control to
+/// release, so `flush` does nothing.
+pub struct CircuitStream<'a> {
+    manager: &'a mut CircuitManager,
+    handle: StreamHandle,
+}
+
+impl std::io::Read for CircuitStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.manager.read_stream(self.handle, buf).map_err(std::io::Error::other)?;
+        let pending = matches!(
+            self.manager.stream_state(self.handle),
+            Some(StreamState::Connecting | StreamState::Open)
+        );
+        if count == 0 && !buf.is_empty() && pending {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        Ok(count)
+    }
+}
+
+impl std::io::Write for CircuitStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.manager.stream_state(self.handle) {
+            Some(StreamState::Open) => {}
+            Some(StreamState::Connecting) => {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            _ => {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+        }
+        match self.manager.write_stream(self.handle, buf).map_err(std::io::Error::other)? {
+            0 if !buf.is_empty() => Err(std::io::ErrorKind::WouldBlock.into()),
+            accepted => Ok(accepted),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+
+use super::circuit::{ CircuitEvent, CircuitHandle, StreamHandle };
+use super::stream::StreamEvent;
+
+/// A stream not answered within this long counts as a loss
+pub const PROBE_TIMEOUT_MS: u64 = 10_000;
+/// Samples needed before a circuit can be judged degraded
+pub const MIN_RTT_SAMPLES: u32 = 4;
+/// A circuit is degraded once its smoothed RTT is this many times its best RTT
+pub const DEGRADED_RTT_FACTOR: f64 = 3.0;
+/// ... or once this share of its recent round trips were lost
+pub const DEGRADED_LOSS_RATE: f64 = 0.5;
+
+// Weights of a new sample in the smoothed RTT, its variation and the loss rate (RFC 6298's for the RTT)
+const RTT_GAIN: f64 = 1.0 / 8.0;
+const RTTVAR_GAIN: f64 = 1.0 / 4.0;
+const LOSS_GAIN: f64 = 1.0 / 8.0;
+
+/// What we measured of one circuit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitHealth {
+    /// Smoothed round trip time
+    pub srtt_ms: f64,
+    /// Smoothed variation of the round trip time
+    pub rttvar_ms: f64,
+    /// Best round trip seen: the circuit's latency without congestion
+    pub min_rtt_ms: u64,
+    /// Smoothed share of round trips lost
+    pub loss_rate: f64,
+    pub samples: u32,
+}
+
+impl CircuitHealth {
+    fn new(rtt_ms: u64) -> Self {
+        Self { srtt_ms: rtt_ms as f64, rttvar_ms: rtt_ms as f64 / 2.0, min_rtt_ms: rtt_ms, loss_rate: 0.0, samples: 1 }
+    }
+
+    fn record_rtt(&mut self, rtt_ms: u64) {
+        let rtt = rtt_ms as f64;
+        self.rttvar_ms += RTTVAR_GAIN * ((self.srtt_ms - rtt).abs() - self.rttvar_ms);
+        self.srtt_ms += RTT_GAIN * (rtt - self.srtt_ms);
+        self.min_rtt_ms = self.min_rtt_ms.min(rtt_ms);
+        self.loss_rate *= 1.0 - LOSS_GAIN;
+        self.samples += 1;
+    }
+
+    fn record_loss(&mut self) {
+        self.loss_rate += LOSS_GAIN * (1.0 - self.loss_rate);
+    }
+
+    /// Expected time for a round trip, lost ones counting as retried: lower is better
+    pub fn cost(&self) -> f64 {
+        (self.srtt_ms + self.rttvar_ms) / (1.0 - self.loss_rate).max(0.01)
+    }
+
+    /// Much slower than it was at its best, or losing most round trips
+    pub fn is_degraded(&self) -> bool {
+        self.samples >= MIN_RTT_SAMPLES &&
+            (self.srtt_ms >= DEGRADED_RTT_FACTOR * self.min_rtt_ms.max(1) as f64 || self.loss_rate >= DEGRADED_LOSS_RATE)
+    }
+}
+
+/// Per-circuit round trip and loss estimates, to put new streams on the least congested
+/// circuit and move long-lived ones off circuits that got much slower.
+///
+/// Round trips are measured on stream opening: from `on_begin` to the stream's CONNECTED
+/// (or END) reported by `on_event`; streams left unanswered past `PROBE_TIMEOUT_MS` count as
+/// lost. The host may add its own samples (e.g. timing circuit SENDMEs) with `record_rtt`.
+/// Like `BuildTimeEstimator`, the host supplies the millisecond clock.
+#[derive(Debug, Default)]
+pub struct CongestionTracker {
+    circuits: HashMap<CircuitHandle, CircuitHealth>,
+    /// Streams opened and not answered yet, with when they were opened
+    probes: HashMap<StreamHandle, u64>,
+    /// Losses on circuits with no RTT sample yet, applied once they get one
+    early_losses: HashMap<CircuitHandle, u32>,
+    migration: bool,
+}
+
+impl CongestionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets `migration_target` suggest moving streams off degraded circuits; off by default.
+    pub fn with_migration(mut self, enabled: bool) -> Self {
+        self.migration = enabled;
+        self
+    }
+
+    /// A stream was opened at `now_ms`: its answer gives a round trip sample.
+    pub fn on_begin(&mut self, stream: StreamHandle, now_ms: u64) {
+        self.probes.insert(stream, now_ms);
+    }
+
+    /// Follows the circuit manager's events: answered streams give samples, failed circuits
+    /// are forgotten. Returns true if the event was used.
+    pub fn on_event(&mut self, event: &CircuitEvent, now_ms: u64) -> bool {
+        match event {
+            CircuitEvent::Stream { circuit, hop, event: StreamEvent::Connected(stream) | StreamEvent::Ended(stream, _) } => {
+                let handle = StreamHandle { circuit: *circuit, hop: *hop, stream: *stream };
+                let Some(sent_ms) = self.probes.remove(&handle) else {
+                    return false;
+                };
+                self.record_rtt(*circuit, now_ms.saturating_sub(sent_ms));
+                true
+            }
+            CircuitEvent::Failed(circuit, _) => {
+                self.forget(*circuit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a round trip sample for a circuit.
+    pub fn record_rtt(&mut self, circuit: CircuitHandle, rtt_ms: u64) {
+        match self.circuits.get_mut(&circuit) {
+            Some(health) => health.record_rtt(rtt_ms),
+            None => {
+                let mut health = CircuitHealth::new(rtt_ms);
+                for _ in 0..self.early_losses.remove(&circuit).unwrap_or(0) {
+                    health.record_loss();
+                }
+                self.circuits.insert(circuit, health);
+            }
+        }
+    }
+
+    /// Counts a round trip on a circuit as lost.
+    pub fn record_loss(&mut self, circuit: CircuitHandle) {
+        match self.circuits.get_mut(&circuit) {
+            Some(health) => health.record_loss(),
+            None => *self.early_losses.entry(circuit).or_default() += 1,
+        }
+    }
+
+    /// Counts the streams unanswered past `PROBE_TIMEOUT_MS` as losses. Returns how many.
+    pub fn expire(&mut self, now_ms: u64) -> usize {
+        let lost: Vec<StreamHandle> = self.probes
+            .iter()
+            .filter(|(_, sent_ms)| now_ms.saturating_sub(**sent_ms) >= PROBE_TIMEOUT_MS)
+            .map(|(stream, _)| *stream)
+            .collect();
+        for stream in &lost {
+            self.probes.remove(stream);
+            self.record_loss(stream.circuit);
+        }
+        lost.len()
+    }
+
+    pub fn health(&self, circuit: CircuitHandle) -> Option<&CircuitHealth> {
+        self.circuits.get(&circuit)
+    }
+
+    /// The least congested of `candidates` for a new stream. Circuits not measured yet come
+    /// first, so every circuit gets measured; circuits failing half their round trips last.
+    pub fn select(&self, candidates: impl IntoIterator<Item = CircuitHandle>) -> Option<CircuitHandle> {
+        candidates
+            .into_iter()
+            .min_by(|a, b| self.rank(*a).total_cmp(&self.rank(*b)))
+    }
+
+    /// With migration enabled, a circuit to move the long-lived streams of `circuit` to: the
+    /// best measured candidate, if `circuit` is degraded and the candidate is at most half as
+    /// costly. Streams can't change circuits by themselves: carry them in a `MultipathStream`
+    /// and add a subflow on the new circuit before removing the old one.
+    pub fn migration_target(&self, circuit: CircuitHandle, candidates: impl IntoIterator<Item = CircuitHandle>) -> Option<CircuitHandle> {
+        let current = self.circuits.get(&circuit).filter(|h| self.migration && h.is_degraded())?;
+        candidates
+            .into_iter()
+            .filter(|c| *c != circuit)
+            .filter_map(|c| self.circuits.get(&c).filter(|h| !h.is_degraded()).map(|h| (c, h.cost())))
+            .filter(|(_, cost)| *cost * 2.0 <= current.cost())
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(c, _)| c)
+    }
+
+    /// Forgets a circuit that was closed.
+    pub fn forget(&mut self, circuit: CircuitHandle) {
+        self.circuits.remove(&circuit);
+        self.early_losses.remove(&circuit);
+        self.probes.retain(|stream, _| stream.circuit != circuit);
+    }
+
+    /// Circuits measured
+    pub fn len(&self) -> usize {
+        self.circuits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+
+    fn rank(&self, circuit: CircuitHandle) -> f64 {
+        match self.circuits.get(&circuit) {
+            Some(health) if health.loss_rate >= DEGRADED_LOSS_RATE => f64::INFINITY,
+            Some(health) => health.cost(),
+            None if self.early_losses.contains_key(&circuit) => f64::INFINITY,
+            None => 0.0,
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+use ed25519_dalek::{ Signature, Signer, SigningKey, VerifyingKey };
+use rand::rngs::OsRng;
+use x25519_dalek::{ PublicKey as X25519PublicKey, StaticSecret };
+
+use super::address::OnionAddress;
+use crate::crypto::helper::CryptoError;
+use crate::crypto::session::{ Session, SESSION_OVERHEAD };
+
+/// Most plaintext bytes in one record
+pub const MAX_RECORD_SIZE: usize = 16 * 1024;
+
+const KEY_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+/// [Len (2)]
+const RECORD_HEADER_SIZE: usize = 2;
+const TRANSCRIPT_LABEL: &[u8] = b"FreedomNode-E2E-v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum E2eError {
+    #[error("The service's handshake is not signed by the key of its address")]
+    InvalidSignature,
+    #[error("Record of {0} bytes exceeds the maximum ({max})", max = MAX_RECORD_SIZE + SESSION_OVERHEAD)]
+    RecordTooLarge(usize),
+    #[error("Record failed to decrypt: {0}")]
+    Crypto(#[from] CryptoError),
+}
+
+enum Handshake {
+    /// (Client) Sent our ephemeral key, waiting for the service's signed answer
+    Connecting {
+        secret: StaticSecret,
+        service: VerifyingKey,
+    },
+    /// (Service) Waiting for the client's ephemeral key
+    Accepting(SigningKey),
+    Established(Session),
+}
+
+/// End-to-end encryption of one stream between a client and a hidden service, on top of the
+/// layers of the circuit: the rendezvous point and the relays on either side only ever carry
+/// records they can't read, and the client knows it talks to the service of the address it
+/// dialled, whichever introduction point or rendezvous point it went through.
+///
+/// Both ends send an ephemeral X25519 key, the service signing both with the identity key of
+/// its onion address; the keys of a `Session` are derived from the two. Sans-IO, like the
+/// stream layer: bytes read from the stream are fed to `on_data`, and bytes to write on it
+/// collect in an outbox drained with `take_outgoing`.
+/// Handshake: client [EphemeralKey (32)], service [EphemeralKey (32)] [Signature (64)] over
+/// "FreedomNode-E2E-v1" | ClientKey | ServiceKey.
+/// Record format: [Len (2)] [Session message (Len)]
+pub struct FreedomStream {
+    handshake: Handshake,
+    /// Bytes received that don't make a whole handshake or record yet
+    partial: Vec<u8>,
+    inbound: VecDeque<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl FreedomStream {
+    /// (Client) Starts the handshake with the service of `address`, queueing our key.
+    pub fn connect(address: &OnionAddress) -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let outgoing = X25519PublicKey::from(&secret).as_bytes().to_vec();
+        Self::new(Handshake::Connecting { secret, service: *address.identity_key() }, outgoing)
+    }
+
+    /// (Service) Waits for a client's key, to answer signed with `identity`, the key of our
+    /// onion address.
+    pub fn accept(identity: &SigningKey) -> Self {
+        Self::new(Handshake::Accepting(identity.clone()), Vec::new())
+    }
+
+    fn new(handshake: Handshake, outgoing: Vec<u8>) -> Self {
+        Self { handshake, partial: Vec::new(), inbound: VecDeque::new(), outgoing }
+    }
+
+    /// Handles bytes read from the stream: the other end's half of the handshake, then records.
+    /// Returns how many bytes are ready to `read`. Any error is fatal to the stream.
+    pub fn on_data(&mut self, data: &[u8]) -> Result<usize, E2eError> {
+        self.partial.extend_from_slice(data);
+        let mut offset = self.handshake()?;
+
+        if let Handshake::Established(session) = &mut self.handshake {
+            while let Some(header) = self.partial.get(offset..offset + RECORD_HEADER_SIZE) {
+                let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+                if len > MAX_RECORD_SIZE + SESSION_OVERHEAD {
+                    return Err(E2eError::RecordTooLarge(len));
+                }
+                let start = offset + RECORD_HEADER_SIZE;
+                let Some(record) = self.partial.get(start..start + len) else {
+                    break;
+                };
+                self.inbound.extend(session.decrypt(record)?);
+                offset = start + len;
+            }
+        }
+        self.partial.drain(..offset);
+        Ok(self.inbound.len())
+    }
+
+    /// Encrypts data into records. Returns how many bytes were accepted: none until the
+    /// handshake is done.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, E2eError> {
+        let Handshake::Established(session) = &mut self.handshake else {
+            return Ok(0);
+        };
+        for chunk in data.chunks(MAX_RECORD_SIZE) {
+            let record = session.encrypt(chunk)?;
+            self.outgoing.extend_from_slice(&(record.len() as u16).to_be_bytes());
+            self.outgoing.extend_from_slice(&record);
+        }
+        Ok(data.len())
+    }
+
+    /// Moves decrypted bytes into `buf`.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.inbound.len());
+        for (slot, byte) in buf.iter_mut().zip(self.inbound.drain(..count)) {
+            *slot = byte;
+        }
+        count
+    }
+
+    /// Decrypted bytes ready to read
+    pub fn readable(&self) -> usize {
+        self.inbound.len()
+    }
+
+    pub fn is_established(&self) -> bool {
+        matches!(self.handshake, Handshake::Established(_))
+    }
+
+    /// Bytes waiting in the outbox
+    pub fn outgoing_len(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Bytes to write on the stream since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Completes the handshake once the other end's half arrived. Returns how many bytes of
+    /// `partial` it used.
+    fn handshake(&mut self) -> Result<usize, E2eError> {
+        match &self.handshake {
+            Handshake::Established(_) => Ok(0),
+            Handshake::Accepting(identity) => {
+                let Some(client) = self.partial.get(..KEY_SIZE) else {
+                    return Ok(0);
+                };
+                let client = X25519PublicKey::from(<[u8; KEY_SIZE]>::try_from(client).expect("sliced to KEY_SIZE"));
+                let secret = StaticSecret::random_from_rng(OsRng);
+                let ours = X25519PublicKey::from(&secret);
+                let signature = identity.sign(&transcript(&client, &ours));
+
+                self.outgoing.extend_from_slice(ours.as_bytes());
+                self.outgoing.extend_from_slice(&signature.to_bytes());
+                self.handshake = Handshake::Established(Session::from_ephemeral(&secret, &client));
+                Ok(KEY_SIZE)
+            }
+            Handshake::Connecting { secret, service } => {
+                let Some(answer) = self.partial.get(..KEY_SIZE + SIGNATURE_SIZE) else {
+                    return Ok(0);
+                };
+                let theirs = X25519PublicKey::from(<[u8; KEY_SIZE]>::try_from(&answer[..KEY_SIZE]).expect("sliced to KEY_SIZE"));
+                let signature = Signature::from_slice(&answer[KEY_SIZE..]).map_err(|_| E2eError::InvalidSignature)?;
+                service
+                    .verify_strict(&transcript(&X25519PublicKey::from(secret), &theirs), &signature)
+                    .map_err(|_| E2eError::InvalidSignature)?;
+
+                self.handshake = Handshake::Established(Session::from_ephemeral(secret, &theirs));
+                Ok(KEY_SIZE + SIGNATURE_SIZE)
+            }
+        }
+    }
+}
+
+/// What the service signs: both ephemeral keys, so neither can be swapped by someone in between
+fn transcript(client: &X25519PublicKey, service: &X25519PublicKey) -> Vec<u8> {
+    [TRANSCRIPT_LABEL, client.as_bytes(), service.as_bytes()].concat()
+}
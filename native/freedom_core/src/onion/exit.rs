@@ -0,0 +1,214 @@
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::ops::RangeInclusive;
+
+use super::path::prefix_eq;
+use super::stream::StreamTarget;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Most rules a policy may carry in a descriptor
+pub const MAX_EXIT_RULES: usize = 64;
+
+/// Address blocks an exit must not connect to unless its operator says otherwise: private,
+/// loopback, link-local, multicast and reserved networks. Streams there would reach the
+/// exit's own machine or network rather than the Internet.
+const INTERNAL_NETWORKS: [(IpAddr, u8); 14] = [
+    (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), 10),
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(224, 0, 0, 0)), 4),
+    (IpAddr::V4(Ipv4Addr::new(240, 0, 0, 0)), 4),
+    (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+    (IpAddr::V6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0)), 8),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitAction {
+    Reject = 0,
+    Accept = 1,
+}
+
+/// Addresses a rule applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressPattern {
+    Any,
+    /// An address block: network address and prefix length
+    Network(IpAddr, u8),
+}
+
+impl AddressPattern {
+    fn matches(&self, ip: IpAddr) -> bool {
+        match (*self, ip) {
+            (AddressPattern::Any, _) => true,
+            (AddressPattern::Network(IpAddr::V4(net), prefix), IpAddr::V4(ip)) => prefix_eq(&net.octets(), &ip.octets(), prefix),
+            (AddressPattern::Network(IpAddr::V6(net), prefix), IpAddr::V6(ip)) => prefix_eq(&net.octets(), &ip.octets(), prefix),
+            _ => false,
+        }
+    }
+}
+
+/// Accepts or rejects streams to some addresses on some ports.
+/// Format: [Action (1)] [IP_Len (1), 0 for any] [IP (4 or 16)] [Prefix (1)] [MinPort (2)] [MaxPort (2)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitRule {
+    pub action: ExitAction,
+    pub addresses: AddressPattern,
+    pub ports: RangeInclusive<u16>,
+}
+
+impl ExitRule {
+    pub fn accept(addresses: AddressPattern, ports: RangeInclusive<u16>) -> Self {
+        Self { action: ExitAction::Accept, addresses, ports }
+    }
+
+    pub fn reject(addresses: AddressPattern, ports: RangeInclusive<u16>) -> Self {
+        Self { action: ExitAction::Reject, addresses, ports }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.action as u8);
+        match self.addresses {
+            AddressPattern::Any => out.push(0),
+            AddressPattern::Network(IpAddr::V4(ip), prefix) => {
+                out.push(4);
+                out.extend_from_slice(&ip.octets());
+                out.push(prefix);
+            }
+            AddressPattern::Network(IpAddr::V6(ip), prefix) => {
+                out.push(16);
+                out.extend_from_slice(&ip.octets());
+                out.push(prefix);
+            }
+        }
+        out.extend_from_slice(&self.ports.start().to_be_bytes());
+        out.extend_from_slice(&self.ports.end().to_be_bytes());
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let action = match reader.u8()? {
+            0 => ExitAction::Reject,
+            1 => ExitAction::Accept,
+            _ => {
+                return Err(CodecError::InvalidField("exit rule action"));
+            }
+        };
+        let (ip, max_prefix) = match reader.u8()? {
+            0 => (None, 0),
+            4 => (Some(IpAddr::V4(Ipv4Addr::from(reader.take_array::<4>()?))), 32),
+            16 => (Some(IpAddr::V6(Ipv6Addr::from(reader.take_array::<16>()?))), 128),
+            _ => {
+                return Err(CodecError::InvalidField("ip length"));
+            }
+        };
+        let addresses = match ip {
+            Some(ip) => {
+                let prefix = reader.u8()?;
+                if prefix > max_prefix {
+                    return Err(CodecError::InvalidField("exit rule prefix"));
+                }
+                AddressPattern::Network(ip, prefix)
+            }
+            None => AddressPattern::Any,
+        };
+        let (min_port, max_port) = (reader.u16()?, reader.u16()?);
+        Ok(Self { action, addresses, ports: min_port..=max_port })
+    }
+}
+
+/// Which streams an exit opens, advertised in its descriptor so clients pick an exit that
+/// will take their stream. Rules are checked in order and the first one matching decides;
+/// streams no rule matches are rejected.
+/// Format: [RuleCount (1)] + N * [ExitRule]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitPolicy {
+    rules: Vec<ExitRule>,
+}
+
+impl Default for ExitPolicy {
+    /// Any public address on any port
+    fn default() -> Self {
+        Self::new(vec![ExitRule::accept(AddressPattern::Any, 1..=u16::MAX)])
+    }
+}
+
+impl ExitPolicy {
+    /// `rules`, after rules rejecting the internal networks.
+    pub fn new(rules: Vec<ExitRule>) -> Self {
+        let internal = INTERNAL_NETWORKS
+            .iter()
+            .map(|&(ip, prefix)| ExitRule::reject(AddressPattern::Network(ip, prefix), 0..=u16::MAX));
+        Self { rules: internal.chain(rules).collect() }
+    }
+
+    /// Exactly `rules`, internal networks included unless they are rejected explicitly.
+    pub fn from_rules(rules: Vec<ExitRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Opens no stream at all: the policy of relays that are not exits
+    pub fn reject_all() -> Self {
+        Self { rules: Vec::new() }
+    }
+
+    pub fn rules(&self) -> &[ExitRule] {
+        &self.rules
+    }
+
+    /// Whether a stream to `ip`:`port` is allowed. IPv4-mapped IPv6 addresses count as IPv4.
+    pub fn allows(&self, ip: IpAddr, port: u16) -> bool {
+        let ip = ip.to_canonical();
+        self.rules
+            .iter()
+            .find(|rule| rule.ports.contains(&port) && rule.addresses.matches(ip))
+            .is_some_and(|rule| rule.action == ExitAction::Accept)
+    }
+
+    /// Whether a stream to some address on `port` could be allowed, for targets whose address
+    /// is not known yet (host names, or path selection before a target is chosen).
+    pub fn allows_port(&self, port: u16) -> bool {
+        for rule in self.rules.iter().filter(|rule| rule.ports.contains(&port)) {
+            match (rule.action, rule.addresses) {
+                (ExitAction::Accept, _) => {
+                    return true;
+                }
+                (ExitAction::Reject, AddressPattern::Any) => {
+                    return false;
+                }
+                (ExitAction::Reject, AddressPattern::Network(..)) => {}
+            }
+        }
+        false
+    }
+
+    /// Whether a BEGIN to `target` is allowed. For a host name only the port can be checked;
+    /// the exit must check the address it resolves to with `allows` before connecting.
+    pub fn allows_target(&self, target: &StreamTarget) -> bool {
+        let host = target.host.trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.allows(ip, target.port),
+            Err(_) => self.allows_port(target.port),
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.rules.len() as u8);
+        for rule in &self.rules {
+            rule.write(out);
+        }
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let count = reader.u8()? as usize;
+        if count > MAX_EXIT_RULES {
+            return Err(CodecError::InvalidField("exit rule count"));
+        }
+        let rules = (0..count).map(|_| ExitRule::read(reader)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { rules })
+    }
+}
@@ -0,0 +1,244 @@
+use curve25519_dalek::Scalar;
+use ed25519_dalek::hazmat::{ self, ExpandedSecretKey };
+use ed25519_dalek::{ Signature, SigningKey, Verifier, VerifyingKey };
+use hkdf::Hkdf;
+use sha2::{ Digest, Sha256, Sha512 };
+
+use super::intro::IntroPointInfo;
+use crate::crypto::helper;
+use crate::dht::messages::{ FetchRequest, StoreRequest };
+use crate::dht::namespace::{ KeyPath, NamespacedKey };
+use crate::dht::node_id::NodeId;
+use crate::dht::record::{ Record, RecordError };
+use crate::protocol::codec::{ CodecError, Reader };
+
+// Labels of the blinding factor, the blinded nonce prefix, the signed message and the body key,
+// so none of them can be confused with another use of the same keys
+const BLINDING_LABEL: &[u8] = b"FreedomNode-HsBlind-v1";
+const BLINDED_PREFIX_LABEL: &[u8] = b"FreedomNode-HsBlind-v1 prefix";
+const SIGNING_LABEL: &[u8] = b"FreedomNode-HsDescriptor-v1";
+const ENCRYPTION_LABEL: &[u8] = b"FreedomNode-HsDescriptor-v1 encryption";
+
+/// Services publish under a new blinded key every period (seconds)
+pub const HS_PERIOD_SECS: u64 = 24 * 60 * 60;
+/// How long a descriptor stays valid; services republish before it runs out
+pub const HS_DESCRIPTOR_LIFETIME_SECS: u64 = 3 * 60 * 60;
+pub const MAX_HS_INTRO_POINTS: usize = 10;
+/// Largest encoded descriptor a directory accepts (room for `MAX_HS_INTRO_POINTS` IPv6 points)
+pub const MAX_HS_DESCRIPTOR_LEN: usize = 2048;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HsDescriptorError {
+    #[error("Malformed hidden service descriptor: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Too many introduction points: {0} (max {MAX_HS_INTRO_POINTS})")]
+    TooManyIntroPoints(usize),
+    #[error("Signature verification failed")]
+    VerificationFailed,
+    #[error("Descriptor expired at {expires_at} (now {now})")]
+    Expired {
+        expires_at: u64,
+        now: u64,
+    },
+    #[error("Descriptor is not for this service and period")]
+    WrongService,
+    #[error("Descriptor body could not be decrypted")]
+    Undecryptable,
+}
+
+/// The blinding period `now` falls in
+pub fn time_period(now: u64) -> u64 {
+    now / HS_PERIOD_SECS
+}
+
+/// The key the service with `identity` publishes under during `period`: its identity key
+/// times a factor derived from both. Anyone who knows the identity can compute it, but
+/// blinded keys can't be linked to the identity or to each other.
+pub fn blind_public_key(identity: &VerifyingKey, period: u64) -> VerifyingKey {
+    VerifyingKey::from(identity.to_edwards() * blinding_factor(identity, period))
+}
+
+/// The secret half of `blind_public_key`
+fn blind_signing_key(identity: &SigningKey, period: u64) -> ExpandedSecretKey {
+    let expanded = ExpandedSecretKey::from(identity.as_bytes());
+    let prefix = Sha512::new()
+        .chain_update(BLINDED_PREFIX_LABEL)
+        .chain_update(expanded.hash_prefix)
+        .chain_update(period.to_be_bytes())
+        .finalize();
+    let mut hash_prefix = [0u8; 32];
+    hash_prefix.copy_from_slice(&prefix[..32]);
+    ExpandedSecretKey { scalar: expanded.scalar * blinding_factor(&identity.verifying_key(), period), hash_prefix }
+}
+
+fn blinding_factor(identity: &VerifyingKey, period: u64) -> Scalar {
+    let hash = Sha512::new()
+        .chain_update(BLINDING_LABEL)
+        .chain_update(identity.as_bytes())
+        .chain_update(period.to_be_bytes())
+        .finalize();
+    Scalar::from_bytes_mod_order_wide(&hash.into())
+}
+
+/// Key of the encrypted body: only clients who know the identity behind the blinded key can derive it
+fn body_key(identity: &VerifyingKey, blinded_key: &VerifyingKey) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(blinded_key.as_bytes()), identity.as_bytes())
+        .expand(ENCRYPTION_LABEL, &mut key)
+        .expect("32 bytes is a valid length for SHA-256 HKDF");
+    key
+}
+
+/// Where a hidden service can be reached during one period, stored in the DHT under its
+/// blinded key (`NamespacedKey::hidden_service`) and signed with it. The introduction points
+/// are encrypted, so the nodes storing the descriptor learn neither which service it belongs
+/// to nor how to reach it; a client who knows the service's identity key computes the blinded
+/// key, fetches the descriptor and decrypts it with `open`.
+/// Format: [BlindedKey (32)] [Period (8)] [Revision (8)] [ExpiresAt (8)] [BodyLen (2)] [Body] [Signature (64)]
+/// Body: encrypted [Count (1)] + N * IntroPointInfo, as [Nonce (12)] [Ciphertext] [Tag (16)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDescriptor {
+    pub blinded_key: VerifyingKey,
+    pub period: u64,
+    /// Newer descriptors of a period carry higher revisions
+    pub revision: u64,
+    pub expires_at: u64, // Seconds since UNIX epoch
+    body: Vec<u8>,
+    signature: Signature,
+}
+
+impl HsDescriptor {
+    /// Builds and signs the descriptor of the service `identity` for the period `now` falls in,
+    /// valid for `HS_DESCRIPTOR_LIFETIME_SECS`.
+    pub fn new_signed(
+        identity: &SigningKey,
+        intro_points: &[IntroPointInfo],
+        revision: u64,
+        now: u64
+    ) -> Result<Self, HsDescriptorError> {
+        if intro_points.len() > MAX_HS_INTRO_POINTS {
+            return Err(HsDescriptorError::TooManyIntroPoints(intro_points.len()));
+        }
+
+        let period = time_period(now);
+        let blinded = blind_signing_key(identity, period);
+        let blinded_key = VerifyingKey::from(&blinded);
+
+        let mut plaintext = vec![intro_points.len() as u8];
+        for point in intro_points {
+            point.write(&mut plaintext);
+        }
+        let body = helper::encrypt_layer(&body_key(&identity.verifying_key(), &blinded_key), &plaintext)
+            .expect("a descriptor body is far below the AEAD length limit");
+
+        let mut descriptor = Self {
+            blinded_key,
+            period,
+            revision,
+            expires_at: now.saturating_add(HS_DESCRIPTOR_LIFETIME_SECS),
+            body,
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+        descriptor.signature = hazmat::raw_sign::<Sha512>(&blinded, &descriptor.signed_message(), &blinded_key);
+        Ok(descriptor)
+    }
+
+    /// Checks the signature and expiry. Directories can do this without knowing the service.
+    pub fn verify(&self, now: u64) -> Result<(), HsDescriptorError> {
+        self.blinded_key
+            .verify(&self.signed_message(), &self.signature)
+            .map_err(|_| HsDescriptorError::VerificationFailed)?;
+        if now >= self.expires_at {
+            return Err(HsDescriptorError::Expired { expires_at: self.expires_at, now });
+        }
+        Ok(())
+    }
+
+    /// Decrypts the introduction points, checking the descriptor is the one of the service
+    /// `identity` for its period.
+    pub fn decrypt(&self, identity: &VerifyingKey) -> Result<Vec<IntroPointInfo>, HsDescriptorError> {
+        if blind_public_key(identity, self.period) != self.blinded_key {
+            return Err(HsDescriptorError::WrongService);
+        }
+        let plaintext = helper::try_decrypt_layer(&body_key(identity, &self.blinded_key), &self.body)
+            .map_err(|_| HsDescriptorError::Undecryptable)?;
+
+        let mut reader = Reader::new(&plaintext);
+        let count = reader.u8()? as usize;
+        if count > MAX_HS_INTRO_POINTS {
+            return Err(HsDescriptorError::TooManyIntroPoints(count));
+        }
+        let points = (0..count).map(|_| IntroPointInfo::read(&mut reader)).collect::<Result<Vec<_>, _>>()?;
+        reader.finish()?;
+        Ok(points)
+    }
+
+    /// (Client) Reads a descriptor fetched for the service `identity` (see `fetch_request`),
+    /// and returns its introduction points if it is valid now.
+    pub fn open(identity: &VerifyingKey, bytes: &[u8], now: u64) -> Result<Vec<IntroPointInfo>, HsDescriptorError> {
+        let descriptor = Self::from_bytes(bytes)?;
+        if descriptor.period != time_period(now) {
+            return Err(HsDescriptorError::WrongService);
+        }
+        descriptor.verify(now)?;
+        descriptor.decrypt(identity)
+    }
+
+    /// Where the descriptor is stored in the DHT
+    pub fn key(&self) -> NamespacedKey {
+        NamespacedKey::hidden_service(self.blinded_key.as_bytes())
+    }
+
+    /// (Service) A STORE of the descriptor, kept until it expires. The host adds the write
+    /// token of each node it sends it to.
+    pub fn store_request(&self, publisher: NodeId, now: u64) -> Result<StoreRequest, RecordError> {
+        let key = self.key();
+        let ttl = self.expires_at.saturating_sub(now).min(u32::MAX as u64) as u32;
+        let record = Record::new(key.node_id(), self.to_bytes(), publisher, ttl, now)?;
+        Ok(StoreRequest { token: None, path: Some(KeyPath::from(&key)), record })
+    }
+
+    /// (Client) A FETCH of the current descriptor of the service `identity`. Nodes answer with
+    /// the value of the record stored under that key.
+    pub fn fetch_request(identity: &VerifyingKey, now: u64) -> FetchRequest {
+        let key = NamespacedKey::hidden_service(blind_public_key(identity, time_period(now)).as_bytes());
+        FetchRequest { hash: *key.node_id().as_bytes() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.body_bytes();
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Parses a descriptor. Does not check the signature or expiry; call `verify` for that.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HsDescriptorError> {
+        let mut reader = Reader::new(bytes);
+        let blinded_key = VerifyingKey::from_bytes(&reader.take_array()?).map_err(|_| CodecError::InvalidField("blinded key"))?;
+        let period = reader.u64()?;
+        let revision = reader.u64()?;
+        let expires_at = reader.u64()?;
+        let len = reader.u16()? as usize;
+        let body = reader.take(len)?.to_vec();
+        let signature = Signature::from_bytes(&reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { blinded_key, period, revision, expires_at, body, signature })
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        let mut message = SIGNING_LABEL.to_vec();
+        message.extend_from_slice(&self.body_bytes());
+        message
+    }
+
+    fn body_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(58 + self.body.len() + 64);
+        out.extend_from_slice(self.blinded_key.as_bytes());
+        out.extend_from_slice(&self.period.to_be_bytes());
+        out.extend_from_slice(&self.revision.to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out.extend_from_slice(&(self.body.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
@@ -0,0 +1,275 @@
+use ed25519_dalek::{ Signature, Signer, SigningKey, Verifier, VerifyingKey };
+use rand::rngs::OsRng;
+use rand::Rng;
+use x25519_dalek::{ PublicKey as X25519PublicKey, StaticSecret };
+
+use super::cell::RELAY_DATA_SIZE;
+use super::circuit::{ CircuitEvent, CircuitHandle, CircuitManager, Hop };
+use super::path::{ PathRequest, PathSelector };
+use super::rendezvous::{ AcceptedIntroduction, Introduction };
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::protocol::descriptor::Capabilities;
+
+// Prefix of the signed message, so the signature can't be replayed as another object
+const ESTABLISH_INTRO_LABEL: &[u8] = b"FreedomNode-EstablishIntro-v1";
+
+/// Introduction points a service keeps by default
+pub const INTRO_POINTS: usize = 3;
+/// Most a client can pass to a service in INTRODUCE1
+pub const MAX_INTRODUCE_PAYLOAD: usize = RELAY_DATA_SIZE - 32;
+
+/// Data of RELAY ESTABLISH_INTRO: the key clients will name the service by at this relay, and
+/// its signature over the circuit binding of the relay's hop (`HopCrypto::binding`), which
+/// proves the sender holds the key and ties the request to this one circuit.
+/// Format: [AuthKey (32)] [Signature (64)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EstablishIntro {
+    pub auth_key: VerifyingKey,
+    pub signature: Signature,
+}
+
+impl EstablishIntro {
+    pub fn new(auth: &SigningKey, binding: &[u8; 32]) -> Self {
+        let auth_key = auth.verifying_key();
+        Self { auth_key, signature: auth.sign(&Self::signed_message(&auth_key, binding)) }
+    }
+
+    /// Whether the request was signed by its auth key for the circuit with `binding`
+    pub fn verify(&self, binding: &[u8; 32]) -> bool {
+        self.auth_key.verify(&Self::signed_message(&self.auth_key, binding), &self.signature).is_ok()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(96);
+        out.extend_from_slice(self.auth_key.as_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let auth_key = VerifyingKey::from_bytes(&reader.take_array()?).map_err(|_| CodecError::InvalidField("auth key"))?;
+        let signature = Signature::from_bytes(&reader.take_array()?);
+        reader.finish()?;
+        Ok(Self { auth_key, signature })
+    }
+
+    fn signed_message(auth_key: &VerifyingKey, binding: &[u8; 32]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(ESTABLISH_INTRO_LABEL.len() + 64);
+        message.extend_from_slice(ESTABLISH_INTRO_LABEL);
+        message.extend_from_slice(auth_key.as_bytes());
+        message.extend_from_slice(binding);
+        message
+    }
+}
+
+/// Data of RELAY INTRODUCE1 and INTRODUCE2: which service the client wants, by the auth key
+/// it established at this relay, and what to tell it (opaque to the introduction point).
+/// Format: [AuthKey (32)] [Payload (rest)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Introduce {
+    pub auth_key: [u8; 32],
+    pub payload: Vec<u8>,
+}
+
+impl Introduce {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CodecError> {
+        if self.payload.len() > MAX_INTRODUCE_PAYLOAD {
+            return Err(CodecError::InvalidField("introduce payload"));
+        }
+        let mut out = Vec::with_capacity(32 + self.payload.len());
+        out.extend_from_slice(&self.auth_key);
+        out.extend_from_slice(&self.payload);
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let auth_key = reader.take_array()?;
+        Ok(Self { auth_key, payload: reader.rest().to_vec() })
+    }
+}
+
+/// Answer of an introduction point to INTRODUCE1, in INTRODUCE_ACK. Unknown values read as
+/// `Malformed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IntroduceStatus {
+    /// Passed on to the service
+    Success = 0,
+    /// No service established that auth key here
+    UnknownService = 1,
+    Malformed = 2,
+}
+
+impl From<u8> for IntroduceStatus {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => IntroduceStatus::Success,
+            1 => IntroduceStatus::UnknownService,
+            _ => IntroduceStatus::Malformed,
+        }
+    }
+}
+
+/// What clients need to reach a service through one of its introduction points.
+/// Format: [Relay (Hop)] [AuthKey (32)] [EncKey (32)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntroPointInfo {
+    pub relay: Hop,
+    /// The key to name the service by at the relay
+    pub auth_key: VerifyingKey,
+    /// The key to seal `Introduction`s to
+    pub enc_key: X25519PublicKey,
+}
+
+impl IntroPointInfo {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        self.relay.write(out);
+        out.extend_from_slice(self.auth_key.as_bytes());
+        out.extend_from_slice(self.enc_key.as_bytes());
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let relay = Hop::read(reader)?;
+        let auth_key = VerifyingKey::from_bytes(&reader.take_array()?).map_err(|_| CodecError::InvalidField("auth key"))?;
+        let enc_key = X25519PublicKey::from(reader.take_array::<32>()?);
+        Ok(Self { relay, auth_key, enc_key })
+    }
+}
+
+struct IntroPoint {
+    circuit: CircuitHandle,
+    auth: SigningKey,
+    enc: StaticSecret,
+    established: bool,
+}
+
+/// A service's introduction points: circuits to distinct relays offering INTRO_POINT, each
+/// registered with its own auth key, where clients reach the service without learning where
+/// it is. `maintain` builds circuits for missing points, `on_event` establishes them once open
+/// and drops the ones whose circuit failed, and `established` lists what to publish. Clients'
+/// introductions are opened with `accept`.
+pub struct IntroPoints {
+    count: usize,
+    points: Vec<IntroPoint>,
+}
+
+impl Default for IntroPoints {
+    fn default() -> Self {
+        Self::new(INTRO_POINTS)
+    }
+}
+
+impl IntroPoints {
+    pub fn new(count: usize) -> Self {
+        Self { count, points: Vec::new() }
+    }
+
+    /// Opens circuits to new introduction points until there are `count`, never two at the
+    /// same relay. Returns how many circuits were opened.
+    pub fn maintain<R: Rng + ?Sized>(
+        &mut self,
+        manager: &mut CircuitManager,
+        selector: &PathSelector,
+        rng: &mut R,
+        now: u64
+    ) -> usize {
+        let mut opened = 0;
+        while self.points.len() < self.count {
+            let exclude = self.points
+                .iter()
+                .filter_map(|p| manager.path(p.circuit)?.last().map(|hop| hop.node_id))
+                .collect();
+            let request = PathRequest { last_hop: Capabilities::RELAY | Capabilities::INTRO_POINT, exclude, ..Default::default() };
+            let path = match selector.select_path(&request, rng) {
+                Ok(path) => path,
+                Err(e) => {
+                    log::debug!("No path to a new introduction point: {e}");
+                    break;
+                }
+            };
+            match manager.open_circuit(path, now) {
+                Ok(circuit) => {
+                    self.points.push(IntroPoint {
+                        circuit,
+                        auth: SigningKey::generate(&mut OsRng),
+                        enc: StaticSecret::random_from_rng(OsRng),
+                        established: false,
+                    });
+                    opened += 1;
+                }
+                Err(e) => {
+                    log::debug!("Could not open an introduction circuit: {e}");
+                    break;
+                }
+            }
+        }
+        opened
+    }
+
+    /// Follows the circuit manager's events for introduction circuits. Returns true if the
+    /// event was about one of them.
+    pub fn on_event(&mut self, manager: &mut CircuitManager, event: &CircuitEvent) -> bool {
+        let circuit = match event {
+            CircuitEvent::Opened(circuit) |
+            CircuitEvent::IntroEstablished(circuit) |
+            CircuitEvent::Failed(circuit, _) |
+            CircuitEvent::Truncated { circuit, .. } => *circuit,
+            _ => {
+                return false;
+            }
+        };
+        let Some(index) = self.points.iter().position(|p| p.circuit == circuit) else {
+            return false;
+        };
+
+        match event {
+            CircuitEvent::Opened(_) => {
+                if let Err(e) = manager.establish_intro(circuit, &self.points[index].auth) {
+                    log::debug!("Could not establish an introduction point: {e}");
+                    manager.close(circuit);
+                    self.points.swap_remove(index);
+                }
+            }
+            CircuitEvent::IntroEstablished(_) => self.points[index].established = true,
+            // The relay (or the way to it) is gone; `maintain` picks another one
+            _ => {
+                manager.close(circuit);
+                self.points.swap_remove(index);
+            }
+        }
+        true
+    }
+
+    /// Established introduction points
+    pub fn established(&self, manager: &CircuitManager) -> Vec<IntroPointInfo> {
+        self.points
+            .iter()
+            .filter(|p| p.established)
+            .filter_map(|p| {
+                Some(IntroPointInfo {
+                    relay: *manager.path(p.circuit)?.last()?,
+                    auth_key: p.auth.verifying_key(),
+                    enc_key: X25519PublicKey::from(&p.enc),
+                })
+            })
+            .collect()
+    }
+
+    /// Opens a client's introduction (from `CircuitEvent::Introduced`) and answers its
+    /// handshake. None if it names none of our points or was not sealed to that point's key.
+    pub fn accept(&self, introduce: &Introduce) -> Option<AcceptedIntroduction> {
+        let point = self.points.iter().find(|p| p.auth.verifying_key().as_bytes() == &introduce.auth_key)?;
+        let introduction = Introduction::open(&introduce.payload, &point.enc)?;
+        AcceptedIntroduction::accept(introduction, &point.auth.verifying_key(), &point.enc)
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
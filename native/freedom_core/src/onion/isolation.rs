@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+use super::circuit::{ CircuitEvent, CircuitHandle };
+use super::pool::{ CircuitPool, CircuitPurpose };
+use super::stream::StreamTarget;
+
+/// What a stream may share a circuit with: only streams with an equal key. Streams of
+/// different activities (sessions, destinations) then leave through different exits, so the
+/// exit can't link them by the circuit they came on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IsolationKey {
+    pub purpose: CircuitPurpose,
+    /// Chosen by the application, e.g. one per browser tab or per identity
+    pub session: Option<u64>,
+    /// Host the stream goes to, when isolating by destination (lowercase)
+    pub destination: Option<String>,
+}
+
+impl IsolationKey {
+    /// Streams for `purpose`, isolated from nothing else until narrowed down.
+    pub fn new(purpose: CircuitPurpose) -> Self {
+        Self { purpose, session: None, destination: None }
+    }
+
+    pub fn with_session(mut self, session: u64) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Keeps streams to `target`'s host apart from streams to any other host.
+    pub fn with_destination(mut self, target: &StreamTarget) -> Self {
+        self.destination = Some(target.host.to_ascii_lowercase());
+        self
+    }
+}
+
+/// Circuits taken for streams, each bound to the isolation key of its first stream, so later
+/// streams reuse a circuit only if their key is the same. Follows the circuit manager's events
+/// like `CircuitPool`, forgetting circuits that fail.
+#[derive(Debug, Default)]
+pub struct CircuitIsolation {
+    circuits: HashMap<CircuitHandle, IsolationKey>,
+}
+
+impl CircuitIsolation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A circuit for a stream with `key`: one already bound to it, else a ready circuit of the
+    /// key's purpose from `pool`, bound to it from now on. None if the pool has none ready.
+    pub fn circuit(&mut self, key: &IsolationKey, pool: &mut CircuitPool) -> Option<CircuitHandle> {
+        if let Some(handle) = self.circuit_for(key) {
+            return Some(handle);
+        }
+        let handle = pool.take(key.purpose)?;
+        self.circuits.insert(handle, key.clone());
+        Some(handle)
+    }
+
+    /// The circuit already bound to `key`, if any
+    pub fn circuit_for(&self, key: &IsolationKey) -> Option<CircuitHandle> {
+        self.circuits
+            .iter()
+            .find(|(_, bound)| *bound == key)
+            .map(|(handle, _)| *handle)
+    }
+
+    /// Binds a circuit obtained elsewhere to `key`. Returns false if it is bound to another key.
+    pub fn bind(&mut self, handle: CircuitHandle, key: &IsolationKey) -> bool {
+        match self.circuits.get(&handle) {
+            Some(bound) => bound == key,
+            None => {
+                self.circuits.insert(handle, key.clone());
+                true
+            }
+        }
+    }
+
+    /// Whether a stream with `key` may use the circuit: it is unbound or bound to the same key.
+    pub fn allows(&self, handle: CircuitHandle, key: &IsolationKey) -> bool {
+        self.circuits.get(&handle).is_none_or(|bound| bound == key)
+    }
+
+    pub fn key(&self, handle: CircuitHandle) -> Option<&IsolationKey> {
+        self.circuits.get(&handle)
+    }
+
+    /// Forgets a circuit the host closed or retired; its key gets a fresh circuit next time.
+    pub fn release(&mut self, handle: CircuitHandle) -> Option<IsolationKey> {
+        self.circuits.remove(&handle)
+    }
+
+    /// Forgets circuits that failed. Returns true if the event was about one of ours.
+    pub fn on_event(&mut self, event: &CircuitEvent) -> bool {
+        match event {
+            CircuitEvent::Failed(handle, _) => self.circuits.remove(handle).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.circuits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+}
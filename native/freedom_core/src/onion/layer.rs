@@ -0,0 +1,130 @@
+use chacha20::cipher::{ KeyIvInit, StreamCipher };
+use chacha20::ChaCha20;
+use hkdf::Hkdf;
+use sha2::{ Digest, Sha256 };
+
+use super::cell::{ self, RelayBody, DIGEST_OFFSET, DIGEST_SIZE };
+use super::ntor::HopKeyMaterial;
+
+// HKDF info labels, one per direction, so no key or digest seed is shared between them
+const FORWARD_LABEL: &[u8] = b"FreedomNode-Relay-v1 forward";
+const BACKWARD_LABEL: &[u8] = b"FreedomNode-Relay-v1 backward";
+const BINDING_LABEL: &[u8] = b"FreedomNode-Relay-v1 binding";
+
+/// Direction of relay traffic on a circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client towards the last hop
+    Forward,
+    /// From a hop back towards the client
+    Backward,
+}
+
+/// Keys of one hop in one direction, derived from the hop's handshake.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DirectionKeys {
+    pub cipher: [u8; 32],
+    /// Seed of the running digest
+    pub digest: [u8; 32],
+}
+
+impl DirectionKeys {
+    /// Expands the hop's key material under the direction's label:
+    /// [Cipher key (32)] [Digest seed (32)] = HKDF-SHA256(material, label)
+    pub fn derive(material: &HopKeyMaterial, direction: Direction) -> Self {
+        let label = match direction {
+            Direction::Forward => FORWARD_LABEL,
+            Direction::Backward => BACKWARD_LABEL,
+        };
+        let mut okm = [0u8; 64];
+        Hkdf::<Sha256>::new(None, material)
+            .expand(label, &mut okm)
+            .expect("64 bytes is a valid length for SHA-256 HKDF");
+
+        let mut keys = Self { cipher: [0u8; 32], digest: [0u8; 32] };
+        keys.cipher.copy_from_slice(&okm[..32]);
+        keys.digest.copy_from_slice(&okm[32..]);
+        keys
+    }
+}
+
+/// One hop's layer in one direction: a ChaCha20 keystream that runs across every cell of the
+/// circuit (so bodies keep their size), and a running digest of the bodies addressed to or
+/// sent by this hop. Both ends of the hop hold the same state and must process the same
+/// cells in the same order.
+pub struct RelayLayer {
+    cipher: ChaCha20,
+    digest: Sha256,
+}
+
+impl RelayLayer {
+    pub fn new(keys: &DirectionKeys) -> Self {
+        // Every key drives a single keystream, so a fixed nonce is safe
+        let mut digest = Sha256::new();
+        digest.update(keys.digest);
+        Self { cipher: ChaCha20::new(&keys.cipher.into(), &[0u8; 12].into()), digest }
+    }
+
+    /// Adds or removes this layer's encryption (the keystream is its own inverse).
+    pub fn apply(&mut self, body: &mut RelayBody) {
+        self.cipher.apply_keystream(body);
+    }
+
+    /// Stamps a plaintext body (digest field zero) with the running digest, before `apply`.
+    pub fn seal(&mut self, body: &mut RelayBody) {
+        self.digest.update(&body[..]);
+        let digest = self.digest.clone().finalize();
+        body[DIGEST_OFFSET..DIGEST_OFFSET + DIGEST_SIZE].copy_from_slice(&digest[..DIGEST_SIZE]);
+    }
+
+    /// Whether a body just decrypted with `apply` is addressed to this hop. The running digest
+    /// only advances for recognized bodies, so bodies passing through don't disturb it.
+    pub fn recognize(&mut self, body: &RelayBody) -> bool {
+        if !cell::maybe_recognized(body) {
+            return false;
+        }
+
+        let mut zeroed = *body;
+        zeroed[DIGEST_OFFSET..DIGEST_OFFSET + DIGEST_SIZE].fill(0);
+        let mut digest = self.digest.clone();
+        digest.update(&zeroed[..]);
+        if digest.clone().finalize()[..DIGEST_SIZE] != body[DIGEST_OFFSET..DIGEST_OFFSET + DIGEST_SIZE] {
+            return false;
+        }
+
+        self.digest = digest;
+        true
+    }
+}
+
+/// Both directions of one hop, each with its own keys, so a cell can't be decrypted or
+/// recognized in the direction it wasn't sent in.
+pub struct HopCrypto {
+    pub forward: RelayLayer,
+    pub backward: RelayLayer,
+    /// Known only to the client and this hop, and different on every circuit: signing it ties
+    /// a message to the circuit it came on (see `EstablishIntro`)
+    pub binding: [u8; 32],
+}
+
+impl HopCrypto {
+    pub fn new(material: &HopKeyMaterial) -> Self {
+        let mut binding = [0u8; 32];
+        Hkdf::<Sha256>::new(None, material)
+            .expand(BINDING_LABEL, &mut binding)
+            .expect("32 bytes is a valid length for SHA-256 HKDF");
+        Self {
+            forward: RelayLayer::new(&DirectionKeys::derive(material, Direction::Forward)),
+            backward: RelayLayer::new(&DirectionKeys::derive(material, Direction::Backward)),
+            binding,
+        }
+    }
+
+    /// The service's end of a rendezvous: the client's layers with the directions swapped, as
+    /// what the client sends forward reaches the service backward, and the other way round.
+    pub fn reversed(material: &HopKeyMaterial) -> Self {
+        let mut crypto = Self::new(material);
+        std::mem::swap(&mut crypto.forward, &mut crypto.backward);
+        crypto
+    }
+}
@@ -0,0 +1,48 @@
+pub mod address;
+pub mod bandwidth;
+pub mod cell;
+pub mod circuit;
+pub mod congestion;
+pub mod e2e;
+pub mod exit;
+pub mod hs_descriptor;
+pub mod intro;
+pub mod isolation;
+pub mod layer;
+pub mod multipath;
+pub mod node;
+pub mod ntor;
+pub mod padding;
+pub mod path;
+pub mod pool;
+pub mod relay;
+pub mod rendezvous;
+pub mod scheduler;
+pub mod socks;
+pub mod stream;
+pub mod timeout;
+
+pub use address::{ AddressError, OnionAddress };
+pub use bandwidth::{ BandwidthConfig, BandwidthError, BandwidthLimiter };
+pub use cell::{ Cell, CellCommand, DestroyReason, RelayCell, RelayCommand, CELL_SIZE };
+pub use circuit::{ CircuitError, CircuitEvent, CircuitFailure, CircuitHandle, CircuitManager, CircuitState, CircuitStream, Hop, StreamHandle };
+pub use congestion::{ CircuitHealth, CongestionTracker };
+pub use e2e::{ E2eError, FreedomStream };
+pub use exit::{ AddressPattern, ExitAction, ExitPolicy, ExitRule };
+pub use hs_descriptor::{ HsDescriptor, HsDescriptorError };
+pub use intro::{ EstablishIntro, Introduce, IntroduceStatus, IntroPointInfo, IntroPoints };
+pub use isolation::{ CircuitIsolation, IsolationKey };
+pub use multipath::{ MultipathError, MultipathStream };
+pub use node::{ ConnectFailure, ConnectId, Node, NodeError, NodeEvent, NodeStream };
+pub use padding::{ Distribution, PaddingLimits, PaddingMachine, PaddingSpec };
+pub use path::{ PathRequest, PathSelector };
+pub use pool::{ CircuitPool, CircuitPurpose, PoolConfig };
+pub use relay::{ CircuitLink, RelayAction, RelayCircuits };
+pub use rendezvous::{ AcceptedIntroduction, Introduction, RendezvousCookie };
+pub use scheduler::{ CellScheduler, SchedulerConfig, SchedulerError };
+pub use socks::{ SocksClient, SocksCommand, SocksServer, SocksServerConfig };
+pub use stream::{ StreamEvent, StreamSet, StreamTarget };
+pub use timeout::BuildTimeEstimator;
+
+#[cfg(test)]
+mod tests;
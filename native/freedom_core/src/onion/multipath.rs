@@ -0,0 +1,278 @@
+use std::collections::{ BTreeMap, VecDeque };
+
+use super::cell::RELAY_DATA_SIZE;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// [Kind (1)] [Seq (8)] [Len (2)]
+const FRAME_HEADER_SIZE: usize = 11;
+const DATA_FRAME: u8 = 0;
+const ACK_FRAME: u8 = 1;
+
+/// Data bytes per frame, so a frame fits one DATA cell
+pub const MULTIPATH_CHUNK_SIZE: usize = RELAY_DATA_SIZE - FRAME_HEADER_SIZE;
+/// Most data bytes sent and not yet acknowledged, across all subflows. The receiver never has
+/// to hold more than this.
+pub const MULTIPATH_WINDOW: usize = 256 * 1024;
+/// Frames the reader consumes before acknowledging them
+pub const ACK_INTERVAL: u64 = 16;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MultipathError {
+    #[error("Unknown subflow")]
+    UnknownSubflow,
+    #[error("No subflow left to send on")]
+    NoSubflows,
+    #[error("Peer sent more than the multipath window ({MULTIPATH_WINDOW} bytes)")]
+    WindowExceeded,
+    #[error("Malformed multipath frame: {0}")]
+    Malformed(#[from] CodecError),
+}
+
+/// One of the streams carrying a multipath stream.
+struct Subflow<S> {
+    id: S,
+    /// Bytes of a frame not fully received yet
+    partial: Vec<u8>,
+    /// Data bytes sent on it and not yet acknowledged
+    in_flight: usize,
+}
+
+/// A frame sent and not yet acknowledged, kept to resend if its subflow is lost
+struct Unacked<S> {
+    subflow: S,
+    data: Vec<u8>,
+}
+
+/// One logical byte stream split across several streams (subflows), usually on different
+/// circuits, for more throughput than one circuit gives and to survive losing a circuit.
+/// Data is cut into sequence-numbered frames; each goes to the subflow with the least data in
+/// flight, so faster circuits carry more. The other end reorders frames as they arrive and
+/// acknowledges them as they are read, which both bounds what it buffers (`MULTIPATH_WINDOW`)
+/// and tells us which frames to resend when a subflow is lost.
+///
+/// Sans-IO and symmetric: both ends hold one. Frames to write on each subflow collect in an
+/// outbox drained with `take_outgoing`; bytes read from a subflow are fed to `on_data`.
+/// `S` identifies a subflow, e.g. a `StreamHandle`.
+/// Frame format: [Kind (1)] [Seq (8)] [Len (2)] [Data (Len)], where an ACK (kind 1) carries no
+/// data and acknowledges every frame before Seq.
+pub struct MultipathStream<S> {
+    subflows: Vec<Subflow<S>>,
+    outgoing: Vec<(S, Vec<u8>)>,
+    next_seq: u64,
+    unacked: BTreeMap<u64, Unacked<S>>,
+    unacked_bytes: usize,
+    /// Frames received ahead of `next_expected`
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+    next_expected: u64,
+    /// In-order frames waiting to be read, with how much of the first one was read
+    inbound: VecDeque<Vec<u8>>,
+    read_offset: usize,
+    /// Received data bytes not yet read
+    held: usize,
+    /// Frames fully read, and the count last acknowledged
+    consumed: u64,
+    acked: u64,
+}
+
+impl<S: Copy + Eq> Default for MultipathStream<S> {
+    fn default() -> Self {
+        Self {
+            subflows: Vec::new(),
+            outgoing: Vec::new(),
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            unacked_bytes: 0,
+            out_of_order: BTreeMap::new(),
+            next_expected: 0,
+            inbound: VecDeque::new(),
+            read_offset: 0,
+            held: 0,
+            consumed: 0,
+            acked: 0,
+        }
+    }
+}
+
+impl<S: Copy + Eq> MultipathStream<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a subflow to send on. Returns false if it was already one.
+    pub fn add_subflow(&mut self, id: S) -> bool {
+        if self.subflows.iter().any(|s| s.id == id) {
+            return false;
+        }
+        self.subflows.push(Subflow { id, partial: Vec::new(), in_flight: 0 });
+        true
+    }
+
+    /// Drops a subflow whose circuit was lost. The frames it carried that the other end did
+    /// not acknowledge are resent on the remaining subflows; returns how many. The last
+    /// subflow can't be dropped while frames on it are unacknowledged.
+    pub fn remove_subflow(&mut self, id: S) -> Result<usize, MultipathError> {
+        let index = self.subflows.iter().position(|s| s.id == id).ok_or(MultipathError::UnknownSubflow)?;
+        let lost: Vec<u64> = self.unacked
+            .iter()
+            .filter(|(_, frame)| frame.subflow == id)
+            .map(|(seq, _)| *seq)
+            .collect();
+        if !lost.is_empty() && self.subflows.len() == 1 {
+            return Err(MultipathError::NoSubflows);
+        }
+        self.subflows.swap_remove(index);
+        self.outgoing.retain(|(subflow, _)| *subflow != id);
+
+        for &seq in &lost {
+            let index = self.least_loaded().expect("checked above");
+            let frame = self.unacked.get_mut(&seq).expect("listed above");
+            frame.subflow = self.subflows[index].id;
+            self.subflows[index].in_flight += frame.data.len();
+            self.outgoing.push((frame.subflow, encode(DATA_FRAME, seq, &frame.data)));
+        }
+        Ok(lost.len())
+    }
+
+    /// Splits data into frames for the subflows. Returns how many bytes were accepted: fewer
+    /// than `data.len()` once `MULTIPATH_WINDOW` bytes wait for the other end to read them.
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, MultipathError> {
+        if self.subflows.is_empty() {
+            return Err(MultipathError::NoSubflows);
+        }
+        let accepted = data.len().min(MULTIPATH_WINDOW - self.unacked_bytes);
+        for chunk in data[..accepted].chunks(MULTIPATH_CHUNK_SIZE) {
+            let index = self.least_loaded().expect("checked above");
+            let subflow = &mut self.subflows[index];
+            subflow.in_flight += chunk.len();
+            self.outgoing.push((subflow.id, encode(DATA_FRAME, self.next_seq, chunk)));
+            self.unacked.insert(self.next_seq, Unacked { subflow: subflow.id, data: chunk.to_vec() });
+            self.unacked_bytes += chunk.len();
+            self.next_seq += 1;
+        }
+        Ok(accepted)
+    }
+
+    /// Handles bytes read from a subflow. Frames may be split across calls and arrive in any
+    /// order across subflows; frames seen before (resent) are ignored. Returns how many bytes
+    /// are ready to `read`.
+    pub fn on_data(&mut self, from: S, data: &[u8]) -> Result<usize, MultipathError> {
+        let index = self.subflows.iter().position(|s| s.id == from).ok_or(MultipathError::UnknownSubflow)?;
+        let mut partial = std::mem::take(&mut self.subflows[index].partial);
+        partial.extend_from_slice(data);
+
+        let mut offset = 0;
+        while let Some(header) = partial.get(offset..offset + FRAME_HEADER_SIZE) {
+            let mut reader = Reader::new(header);
+            let (kind, seq, len) = (reader.u8()?, reader.u64()?, reader.u16()? as usize);
+            if len > MULTIPATH_CHUNK_SIZE {
+                return Err(CodecError::InvalidField("multipath frame length").into());
+            }
+            let Some(body) = partial.get(offset + FRAME_HEADER_SIZE..offset + FRAME_HEADER_SIZE + len) else {
+                break;
+            };
+            match kind {
+                DATA_FRAME => self.on_frame(seq, body.to_vec())?,
+                ACK_FRAME => self.on_ack(seq),
+                _ => {
+                    return Err(CodecError::InvalidField("multipath frame kind").into());
+                }
+            }
+            offset += FRAME_HEADER_SIZE + len;
+        }
+        partial.drain(..offset);
+        self.subflows[index].partial = partial;
+        Ok(self.readable())
+    }
+
+    /// Moves reassembled bytes into `buf`, acknowledging the frames read every `ACK_INTERVAL`.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() && let Some(frame) = self.inbound.front() {
+            let len = (buf.len() - count).min(frame.len() - self.read_offset);
+            buf[count..count + len].copy_from_slice(&frame[self.read_offset..self.read_offset + len]);
+            count += len;
+            self.read_offset += len;
+            if self.read_offset == frame.len() {
+                self.inbound.pop_front();
+                self.read_offset = 0;
+                self.consumed += 1;
+            }
+        }
+        self.held -= count;
+
+        if self.consumed - self.acked >= ACK_INTERVAL && let Some(index) = self.least_loaded() {
+            self.acked = self.consumed;
+            self.outgoing.push((self.subflows[index].id, encode(ACK_FRAME, self.consumed, &[])));
+        }
+        count
+    }
+
+    /// Bytes ready to read, in order
+    pub fn readable(&self) -> usize {
+        self.inbound.iter().map(Vec::len).sum::<usize>() - self.read_offset
+    }
+
+    /// Data bytes sent and not yet acknowledged
+    pub fn unacked(&self) -> usize {
+        self.unacked_bytes
+    }
+
+    /// Data bytes sent on a subflow and not yet acknowledged
+    pub fn in_flight(&self, id: S) -> Option<usize> {
+        self.subflows.iter().find(|s| s.id == id).map(|s| s.in_flight)
+    }
+
+    pub fn subflows(&self) -> usize {
+        self.subflows.len()
+    }
+
+    /// Frames to write on each subflow since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<(S, Vec<u8>)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn on_frame(&mut self, seq: u64, data: Vec<u8>) -> Result<(), MultipathError> {
+        if seq < self.next_expected || self.out_of_order.contains_key(&seq) {
+            return Ok(());
+        }
+        if self.held + data.len() > MULTIPATH_WINDOW {
+            return Err(MultipathError::WindowExceeded);
+        }
+        self.held += data.len();
+        self.out_of_order.insert(seq, data);
+        while let Some(data) = self.out_of_order.remove(&self.next_expected) {
+            self.inbound.push_back(data);
+            self.next_expected += 1;
+        }
+        Ok(())
+    }
+
+    /// The other end read every frame before `seq`.
+    fn on_ack(&mut self, seq: u64) {
+        let rest = self.unacked.split_off(&seq);
+        let acked = std::mem::replace(&mut self.unacked, rest);
+        for frame in acked.into_values() {
+            self.unacked_bytes -= frame.data.len();
+            if let Some(subflow) = self.subflows.iter_mut().find(|s| s.id == frame.subflow) {
+                subflow.in_flight -= frame.data.len();
+            }
+        }
+    }
+
+    fn least_loaded(&self) -> Option<usize> {
+        self.subflows
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| s.in_flight)
+            .map(|(i, _)| i)
+    }
+}
+
+fn encode(kind: u8, seq: u64, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+    out.push(kind);
+    out.extend_from_slice(&seq.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+    out
+}
@@ -0,0 +1,657 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use ed25519_dalek::SigningKey;
+use rand::Rng;
+
+use super::address::OnionAddress;
+use super::cell::{ Cell, DestroyReason, RELAY_DATA_SIZE };
+use super::circuit::{ CircuitError, CircuitEvent, CircuitFailure, CircuitHandle, CircuitManager, StreamHandle };
+use super::e2e::{ E2eError, FreedomStream };
+use super::hs_descriptor::{ HsDescriptor, HsDescriptorError };
+use super::intro::{ IntroduceStatus, IntroPointInfo };
+use super::path::{ PathError, PathRequest, PathSelector };
+use super::stream::{ EndReason, StreamEvent, StreamState, StreamTarget };
+use crate::dht::messages::FetchRequest;
+
+/// Relays to the rendezvous point, which is the last of them
+pub const RENDEZVOUS_PATH_LENGTH: usize = 3;
+/// Relays before the introduction point
+pub const INTRO_PATH_LENGTH: usize = 2;
+/// A connection not established within this long is abandoned
+pub const CONNECT_TIMEOUT_SECS: u64 = 120;
+/// Most decrypted bytes a stream holds for the application. Past it data stays in the stream
+/// layer, whose flow control then holds the other end back.
+pub const STREAM_READ_BUFFER: usize = 64 * 1024;
+/// Most encrypted bytes a stream holds that the stream layer had no room for yet; past it
+/// `write` accepts nothing.
+pub const STREAM_WRITE_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NodeError {
+    #[error("Unknown connection {0:?}")]
+    UnknownConnect(ConnectId),
+    #[error("Unknown stream")]
+    UnknownStream,
+    #[error("Invalid descriptor: {0}")]
+    Descriptor(#[from] HsDescriptorError),
+    #[error("The service's descriptor lists no introduction point")]
+    NoIntroPoints,
+    #[error("No path: {0}")]
+    Path(#[from] PathError),
+    #[error("Circuit error: {0}")]
+    Circuit(#[from] CircuitError),
+    #[error("End-to-end encryption failed: {0}")]
+    E2e(#[from] E2eError),
+}
+
+/// Refers to a connection started with `Node::connect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectId(u64);
+
+/// Why a connection to a hidden service failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectFailure {
+    /// Not established within `CONNECT_TIMEOUT_SECS`
+    Timeout,
+    /// The introduction point did not pass our introduction on
+    Introduce(IntroduceStatus),
+    Circuit(CircuitFailure),
+    /// The service ended the stream before the handshake completed
+    Ended(EndReason),
+    /// The answer to the end-to-end handshake was not signed by the service
+    Handshake,
+}
+
+/// What the host learns from a `Node`, drained with `take_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeEvent {
+    /// Look the service's descriptor up with `request` and pass it to `on_descriptor`
+    FetchDescriptor {
+        connect: ConnectId,
+        request: FetchRequest,
+    },
+    /// The connection's stream is end-to-end encrypted and ready
+    Connected {
+        connect: ConnectId,
+        stream: StreamHandle,
+    },
+    ConnectFailed(ConnectId, ConnectFailure),
+    /// (Service) A client's stream to `port` is end-to-end encrypted and ready
+    Accepted {
+        stream: StreamHandle,
+        port: u16,
+    },
+    /// Data is waiting to be read
+    Readable(StreamHandle),
+    /// The stream ended, from either end or with its circuit. What was received can still be
+    /// read; `close` then forgets it.
+    Closed(StreamHandle),
+    /// Anything else the circuits reported, for the host's other state machines (e.g.
+    /// `IntroPoints`, or `Introduced` to join a client with `open_rendezvous`)
+    Circuit(CircuitEvent),
+}
+
+enum ConnectStep {
+    /// Waiting for the host to fetch the descriptor
+    Fetching,
+    /// Building circuits to our rendezvous point and to one of the service's introduction points
+    Building {
+        rendezvous: CircuitHandle,
+        intro: CircuitHandle,
+        point: Box<IntroPointInfo>,
+        waiting: bool,
+        intro_open: bool,
+    },
+    /// Introduced; waiting for the service to join at the rendezvous point
+    Introduced {
+        rendezvous: CircuitHandle,
+        intro: CircuitHandle,
+    },
+    /// Opened a stream to the service, handshaking over it
+    Handshaking(StreamHandle),
+}
+
+struct Connect {
+    service: OnionAddress,
+    port: u16,
+    deadline: u64,
+    step: ConnectStep,
+}
+
+impl Connect {
+    fn uses(&self, circuit: CircuitHandle) -> bool {
+        match self.step {
+            ConnectStep::Fetching => false,
+            ConnectStep::Building { rendezvous, intro, .. } | ConnectStep::Introduced { rendezvous, intro } => {
+                circuit == rendezvous || circuit == intro
+            }
+            ConnectStep::Handshaking(stream) => circuit == stream.circuit,
+        }
+    }
+}
+
+/// One end of an end-to-end encrypted stream.
+struct Endpoint {
+    e2e: FreedomStream,
+    /// Encrypted bytes the stream layer had no room for yet
+    unsent: Vec<u8>,
+    /// The connection the stream completes (client), or None (service)
+    connect: Option<ConnectId>,
+    port: u16,
+}
+
+/// Socket-like streams to hidden services, end-to-end encrypted on top of the circuit and
+/// stream layers, which it drives through its own `CircuitManager`.
+///
+/// `connect` takes a service from its onion address to an open stream: it asks the host to
+/// fetch the service's descriptor, builds a circuit to a rendezvous point and one to an
+/// introduction point, introduces itself, and once the service joins, opens a stream to it
+/// and runs the `FreedomStream` handshake. A node serving a hidden service (`with_service`)
+/// accepts the streams clients open to it the same way; the host still runs its
+/// `IntroPoints` and joins introduced clients through `circuits_mut`.
+///
+/// Sans-IO like the layers below: cells go in through `on_cell` and out through
+/// `take_outgoing`, what happened comes out of `take_events`, and streams are read and
+/// written with `read` and `write`, or as `std::io` byte streams through `stream`.
+#[derive(Default)]
+pub struct Node {
+    circuits: CircuitManager,
+    /// Identity of the hidden service we run, to answer handshakes with
+    service: Option<SigningKey>,
+    connects: HashMap<ConnectId, Connect>,
+    streams: HashMap<StreamHandle, Endpoint>,
+    events: Vec<NodeEvent>,
+    next_connect: u64,
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drives circuits with `circuits` (e.g. one with a fixed build timeout) instead of a
+    /// default manager.
+    pub fn with_circuits(mut self, circuits: CircuitManager) -> Self {
+        self.circuits = circuits;
+        self
+    }
+
+    /// Accepts streams clients open to us as the hidden service of `identity`.
+    pub fn with_service(mut self, identity: SigningKey) -> Self {
+        self.service = Some(identity);
+        self
+    }
+
+    /// Starts connecting to port `port` of the service at `address`. Progress is reported by
+    /// `NodeEvent`s, beginning with `FetchDescriptor`.
+    pub fn connect(&mut self, address: &OnionAddress, port: u16, now: u64) -> ConnectId {
+        let connect = ConnectId(self.next_connect);
+        self.next_connect += 1;
+        let request = HsDescriptor::fetch_request(address.identity_key(), now);
+        self.connects.insert(connect, Connect {
+            service: *address,
+            port,
+            deadline: now + CONNECT_TIMEOUT_SECS,
+            step: ConnectStep::Fetching,
+        });
+        self.events.push(NodeEvent::FetchDescriptor { connect, request });
+        connect
+    }
+
+    /// Continues a connection with the descriptor the host fetched: picks one of its
+    /// introduction points and starts the circuits. On error the connection is dropped (unless
+    /// it was not waiting for a descriptor).
+    pub fn on_descriptor<R: Rng + ?Sized>(
+        &mut self,
+        connect: ConnectId,
+        descriptor: &[u8],
+        selector: &PathSelector,
+        rng: &mut R,
+        now: u64
+    ) -> Result<(), NodeError> {
+        if !self.connects.get(&connect).is_some_and(|c| matches!(c.step, ConnectStep::Fetching)) {
+            return Err(NodeError::UnknownConnect(connect));
+        }
+        let result = self.start_circuits(connect, descriptor, selector, rng, now);
+        if result.is_err() {
+            self.connects.remove(&connect);
+        }
+        result
+    }
+
+    /// Handles a cell from the first hop of one of our circuits.
+    pub fn on_cell(&mut self, from: SocketAddr, cell: Cell) -> Result<(), NodeError> {
+        let event = self.circuits.on_cell(from, cell)?;
+        if let Some(event) = event {
+            self.on_event(event);
+        }
+        self.flush_all();
+        Ok(())
+    }
+
+    /// Fails the circuits through a first hop we lost the link to.
+    pub fn on_link_closed(&mut self, peer: SocketAddr) {
+        for event in self.circuits.on_link_closed(peer) {
+            self.on_event(event);
+        }
+    }
+
+    /// Abandons circuits still building past their deadline, and connections not established
+    /// within `CONNECT_TIMEOUT_SECS`.
+    pub fn expire(&mut self, now: u64) {
+        for event in self.circuits.expire(now) {
+            self.on_event(event);
+        }
+        let expired: Vec<ConnectId> = self.connects
+            .iter()
+            .filter(|(_, c)| now >= c.deadline)
+            .map(|(id, _)| *id)
+            .collect();
+        for connect in expired {
+            self.fail(connect, ConnectFailure::Timeout);
+        }
+    }
+
+    /// Moves decrypted bytes into `buf`. Returns 0 when nothing is buffered; see `stream` for
+    /// telling a stream waiting for data from one that ended.
+    pub fn read(&mut self, stream: StreamHandle, buf: &mut [u8]) -> Result<usize, NodeError> {
+        let endpoint = self.streams.get_mut(&stream).ok_or(NodeError::UnknownStream)?;
+        let count = endpoint.e2e.read(buf);
+        self.pump(stream);
+        Ok(count)
+    }
+
+    /// Encrypts data for the other end. Returns how many bytes were accepted: none before the
+    /// handshake completes, fewer than `data.len()` once `STREAM_WRITE_BUFFER` bytes wait.
+    pub fn write(&mut self, stream: StreamHandle, data: &[u8]) -> Result<usize, NodeError> {
+        let endpoint = self.streams.get_mut(&stream).ok_or(NodeError::UnknownStream)?;
+        let room = STREAM_WRITE_BUFFER.saturating_sub(endpoint.unsent.len());
+        let accepted = endpoint.e2e.write(&data[..data.len().min(room)])?;
+        self.flush(stream)?;
+        Ok(accepted)
+    }
+
+    /// Closes a stream, telling the other end once what was written is sent, and forgets it.
+    /// Returns false if it was unknown. Circuits stay up: a client's rendezvous circuit only
+    /// served this connection, and the host closes it through `circuits_mut` once the END left.
+    pub fn close(&mut self, stream: StreamHandle) -> bool {
+        if self.streams.remove(&stream).is_none() {
+            return false;
+        }
+        if let Err(e) = self.circuits.close_stream(stream) {
+            log::debug!("Stream closed after its circuit: {e}");
+        }
+        true
+    }
+
+    /// Whether the stream finished its handshake and was not closed by either end
+    pub fn is_open(&self, stream: StreamHandle) -> bool {
+        self.streams.get(&stream).is_some_and(|e| e.e2e.is_established()) &&
+            self.circuits.stream_state(stream) == Some(StreamState::Open)
+    }
+
+    /// The stream as a `Read + Write` byte stream, for code that expects one.
+    pub fn stream(&mut self, stream: StreamHandle) -> NodeStream<'_> {
+        NodeStream { node: self, stream }
+    }
+
+    /// What happened since the last call, in order.
+    pub fn take_events(&mut self) -> Vec<NodeEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Cells to send since the last call, with the address of the peer each goes to.
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Cell)> {
+        self.circuits.take_outgoing()
+    }
+
+    pub fn circuits(&self) -> &CircuitManager {
+        &self.circuits
+    }
+
+    /// For what `Node` leaves to the host: building circuits of its own, introduction points,
+    /// joining introduced clients.
+    pub fn circuits_mut(&mut self) -> &mut CircuitManager {
+        &mut self.circuits
+    }
+
+    /// Connections not established yet
+    pub fn connecting(&self) -> usize {
+        self.connects.len()
+    }
+
+    fn start_circuits<R: Rng + ?Sized>(
+        &mut self,
+        connect: ConnectId,
+        descriptor: &[u8],
+        selector: &PathSelector,
+        rng: &mut R,
+        now: u64
+    ) -> Result<(), NodeError> {
+        let service = *self.connects[&connect].service.identity_key();
+        let mut points = HsDescriptor::open(&service, descriptor, now)?;
+        if points.is_empty() {
+            return Err(NodeError::NoIntroPoints);
+        }
+        let point = points.swap_remove(rng.gen_range(0..points.len()));
+
+        // Neither circuit goes through the introduction point before its end, so it can't
+        // see both sides of our introduction
+        let exclude = vec![point.relay.node_id];
+        let rendezvous_path = selector.select_path(&PathRequest { length: RENDEZVOUS_PATH_LENGTH, exclude: exclude.clone(), ..Default::default() }, rng)?;
+        let mut intro_path = selector.select_path(&PathRequest { length: INTRO_PATH_LENGTH, exclude, ..Default::default() }, rng)?;
+        intro_path.push(point.relay);
+
+        let rendezvous = self.circuits.open_circuit(rendezvous_path, now)?;
+        let intro = match self.circuits.open_circuit(intro_path, now) {
+            Ok(intro) => intro,
+            Err(e) => {
+                self.circuits.close(rendezvous);
+                return Err(e.into());
+            }
+        };
+        let step = ConnectStep::Building { rendezvous, intro, point: Box::new(point), waiting: false, intro_open: false };
+        self.connects.get_mut(&connect).expect("checked by on_descriptor").step = step;
+        Ok(())
+    }
+
+    fn on_event(&mut self, event: CircuitEvent) {
+        let connect = match &event {
+            CircuitEvent::Extended { circuit, .. } |
+            CircuitEvent::Opened(circuit) |
+            CircuitEvent::Failed(circuit, _) |
+            CircuitEvent::RendezvousEstablished(circuit) |
+            CircuitEvent::RendezvousCompleted(circuit) |
+            CircuitEvent::IntroduceAck { circuit, .. } => self.connect_using(*circuit),
+            _ => None,
+        };
+
+        match (event, connect) {
+            (CircuitEvent::Extended { .. }, Some(_)) => {}
+            (CircuitEvent::Opened(circuit), Some(connect)) => {
+                let Some(Connect { step: ConnectStep::Building { rendezvous, intro_open, .. }, .. }) = self.connects.get_mut(&connect) else {
+                    return;
+                };
+                if circuit != *rendezvous {
+                    *intro_open = true;
+                } else if let Err(e) = self.circuits.establish_rendezvous(circuit) {
+                    log::warn!("Could not establish a rendezvous point: {e}");
+                    return self.fail(connect, ConnectFailure::Circuit(CircuitFailure::Destroyed(DestroyReason::Internal)));
+                }
+                self.introduce(connect);
+            }
+            (CircuitEvent::RendezvousEstablished(_), Some(connect)) => {
+                if let Some(Connect { step: ConnectStep::Building { waiting, .. }, .. }) = self.connects.get_mut(&connect) {
+                    *waiting = true;
+                }
+                self.introduce(connect);
+            }
+            (CircuitEvent::IntroduceAck { circuit, status }, Some(connect)) => {
+                self.circuits.close(circuit);
+                if status != IntroduceStatus::Success {
+                    self.fail(connect, ConnectFailure::Introduce(status));
+                }
+            }
+            (CircuitEvent::RendezvousCompleted(circuit), Some(connect)) => self.open_stream(connect, circuit),
+            (CircuitEvent::Failed(_, failure), Some(connect)) => self.fail(connect, ConnectFailure::Circuit(failure)),
+            (CircuitEvent::Stream { circuit, hop, event }, _) => self.on_stream_event(circuit, hop, event),
+            (CircuitEvent::Failed(circuit, failure), None) => {
+                self.close_all(|stream| stream.circuit == circuit);
+                self.events.push(NodeEvent::Circuit(CircuitEvent::Failed(circuit, failure)));
+            }
+            (CircuitEvent::Truncated { circuit, hops, reason, streams }, _) => {
+                self.close_all(|stream| streams.contains(stream));
+                self.events.push(NodeEvent::Circuit(CircuitEvent::Truncated { circuit, hops, reason, streams }));
+            }
+            (event, _) => self.events.push(NodeEvent::Circuit(event)),
+        }
+    }
+
+    fn on_stream_event(&mut self, circuit: CircuitHandle, hop: usize, event: StreamEvent) {
+        let (StreamEvent::Requested { stream, .. } |
+        StreamEvent::DirectoryRequested(stream) |
+        StreamEvent::Connected(stream) |
+        StreamEvent::Readable(stream) |
+        StreamEvent::Ended(stream, _)) = event;
+        let handle = StreamHandle { circuit, hop, stream };
+        let ours = self.streams.contains_key(&handle);
+
+        match event {
+            // Only the other end of a rendezvous reaches this hop: a client, if we are the service
+            StreamEvent::Requested { target, .. } if self.circuits.path(circuit).is_some_and(|path| path.len() == hop) => {
+                let Some(identity) = &self.service else {
+                    log::debug!("Refused a stream from the other end of rendezvous circuit {}", circuit.id());
+                    self.close_refused(handle);
+                    return;
+                };
+                let e2e = FreedomStream::accept(identity);
+                if let Err(e) = self.circuits.accept_stream(handle) {
+                    log::warn!("Could not accept a stream: {e}");
+                    return;
+                }
+                self.streams.insert(handle, Endpoint { e2e, unsent: Vec::new(), connect: None, port: target.port });
+            }
+            StreamEvent::Connected(_) if ours => {
+                if let Err(e) = self.flush(handle) {
+                    log::warn!("Could not start the end-to-end handshake: {e}");
+                }
+            }
+            StreamEvent::Readable(_) if ours => self.pump(handle),
+            StreamEvent::Ended(_, reason) if ours => {
+                self.pump(handle);
+                match self.streams.get(&handle) {
+                    Some(Endpoint { connect: Some(connect), e2e, .. }) if !e2e.is_established() => {
+                        let connect = *connect;
+                        self.fail(connect, ConnectFailure::Ended(reason));
+                    }
+                    Some(_) => self.events.push(NodeEvent::Closed(handle)),
+                    None => {}
+                }
+            }
+            event => self.events.push(NodeEvent::Circuit(CircuitEvent::Stream { circuit, hop, event })),
+        }
+    }
+
+    /// Introduces us to the service once the rendezvous point waits for it and the circuit to
+    /// the introduction point is open.
+    fn introduce(&mut self, connect: ConnectId) {
+        let Some(Connect { step, .. }) = self.connects.get_mut(&connect) else {
+            return;
+        };
+        let ConnectStep::Building { rendezvous, intro, point, waiting: true, intro_open: true } = step else {
+            return;
+        };
+        let (rendezvous, intro) = (*rendezvous, *intro);
+        if let Err(e) = self.circuits.introduce_rendezvous(intro, rendezvous, point) {
+            log::warn!("Could not introduce us to the service: {e}");
+            return self.fail(connect, ConnectFailure::Introduce(IntroduceStatus::Malformed));
+        }
+        *step = ConnectStep::Introduced { rendezvous, intro };
+    }
+
+    /// The service joined us at the rendezvous point: it is now the circuit's last hop.
+    fn open_stream(&mut self, connect: ConnectId, circuit: CircuitHandle) {
+        let Some(pending) = self.connects.get_mut(&connect) else {
+            return;
+        };
+        let target = StreamTarget::new(pending.service.to_string(), pending.port);
+        let stream = match self.circuits.open_stream(circuit, &target) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Could not open a stream to the service: {e}");
+                return self.fail(connect, ConnectFailure::Ended(EndReason::Misc));
+            }
+        };
+        pending.step = ConnectStep::Handshaking(stream);
+        let e2e = FreedomStream::connect(&pending.service);
+        self.streams.insert(stream, Endpoint { e2e, unsent: Vec::new(), connect: Some(connect), port: pending.port });
+    }
+
+    /// Feeds the stream layer's data to the end-to-end layer until it holds
+    /// `STREAM_READ_BUFFER` bytes, reporting what changed.
+    fn pump(&mut self, handle: StreamHandle) {
+        let Some(endpoint) = self.streams.get_mut(&handle) else {
+            return;
+        };
+        let was_established = endpoint.e2e.is_established();
+        let mut buf = [0u8; RELAY_DATA_SIZE];
+        let mut received = false;
+        while endpoint.e2e.readable() < STREAM_READ_BUFFER {
+            let count = self.circuits.read_stream(handle, &mut buf).unwrap_or(0);
+            if count == 0 {
+                break;
+            }
+            if let Err(e) = endpoint.e2e.on_data(&buf[..count]) {
+                log::warn!("End-to-end stream failed: {e}");
+                return self.abort(handle);
+            }
+            received = true;
+        }
+        let (connect, port, established) = (endpoint.connect, endpoint.port, endpoint.e2e.is_established());
+        let readable = received && endpoint.e2e.readable() > 0;
+
+        if !was_established && established {
+            match connect {
+                Some(connect) => {
+                    self.connects.remove(&connect);
+                    self.events.push(NodeEvent::Connected { connect, stream: handle });
+                }
+                None => self.events.push(NodeEvent::Accepted { stream: handle, port }),
+            }
+        }
+        if readable {
+            self.events.push(NodeEvent::Readable(handle));
+        }
+        if let Err(e) = self.flush(handle) {
+            log::debug!("Could not flush a stream: {e}");
+        }
+    }
+
+    /// Hands a stream's encrypted bytes to the stream layer, as far as it has room.
+    fn flush(&mut self, handle: StreamHandle) -> Result<(), NodeError> {
+        let endpoint = self.streams.get_mut(&handle).ok_or(NodeError::UnknownStream)?;
+        endpoint.unsent.extend(endpoint.e2e.take_outgoing());
+        if endpoint.unsent.is_empty() || self.circuits.stream_state(handle) != Some(StreamState::Open) {
+            return Ok(());
+        }
+        let accepted = self.circuits.write_stream(handle, &endpoint.unsent)?;
+        endpoint.unsent.drain(..accepted);
+        Ok(())
+    }
+
+    /// Retries the streams with bytes the stream layer had no room for.
+    fn flush_all(&mut self) {
+        let waiting: Vec<StreamHandle> = self.streams
+            .iter()
+            .filter(|(_, e)| !e.unsent.is_empty())
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in waiting {
+            if let Err(e) = self.flush(handle) {
+                log::debug!("Could not flush a stream: {e}");
+            }
+        }
+    }
+
+    /// Drops a stream whose end-to-end layer failed.
+    fn abort(&mut self, handle: StreamHandle) {
+        let Some(endpoint) = self.streams.get(&handle) else {
+            return;
+        };
+        match endpoint.connect {
+            Some(connect) if !endpoint.e2e.is_established() => self.fail(connect, ConnectFailure::Handshake),
+            _ => {
+                self.close(handle);
+                self.events.push(NodeEvent::Closed(handle));
+            }
+        }
+    }
+
+    fn close_refused(&mut self, handle: StreamHandle) {
+        if let Err(e) = self.circuits.close_stream(handle) {
+            log::debug!("Could not refuse a stream: {e}");
+        }
+    }
+
+    /// Reports the streams `lost` selects as closed.
+    fn close_all(&mut self, lost: impl Fn(&StreamHandle) -> bool) {
+        let closed: Vec<StreamHandle> = self.streams.keys().filter(|s| lost(s)).copied().collect();
+        for stream in closed {
+            if let Some(Endpoint { connect: Some(connect), .. }) = self.streams.get(&stream) && self.connects.contains_key(connect) {
+                let connect = *connect;
+                self.fail(connect, ConnectFailure::Ended(EndReason::Misc));
+            } else {
+                self.events.push(NodeEvent::Closed(stream));
+            }
+        }
+    }
+
+    /// Drops a connection with its circuits and stream.
+    fn fail(&mut self, connect: ConnectId, failure: ConnectFailure) {
+        let Some(pending) = self.connects.remove(&connect) else {
+            return;
+        };
+        match pending.step {
+            ConnectStep::Fetching => {}
+            ConnectStep::Building { rendezvous, intro, .. } | ConnectStep::Introduced { rendezvous, intro } => {
+                self.circuits.close(rendezvous);
+                self.circuits.close(intro);
+            }
+            ConnectStep::Handshaking(stream) => {
+                self.streams.remove(&stream);
+                self.circuits.close(stream.circuit);
+            }
+        }
+        log::debug!("Connection to {} failed: {failure:?}", pending.service);
+        self.events.push(NodeEvent::ConnectFailed(connect, failure));
+    }
+
+    fn connect_using(&self, circuit: CircuitHandle) -> Option<ConnectId> {
+        self.connects
+            .iter()
+            .find(|(_, c)| c.uses(circuit))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// A stream borrowed from its `Node` as a non-blocking byte stream, like `CircuitStream`:
+/// reads and writes fail with `WouldBlock` until the handshake is done, data arrived, or there
+/// is room to queue more, and reads return 0 once the other end closed it.
+pub struct NodeStream<'a> {
+    node: &'a mut Node,
+    stream: StreamHandle,
+}
+
+impl std::io::Read for NodeStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.node.read(self.stream, buf).map_err(std::io::Error::other)?;
+        let pending = matches!(
+            self.node.circuits.stream_state(self.stream),
+            Some(StreamState::Connecting | StreamState::Open)
+        );
+        if count == 0 && !buf.is_empty() && pending {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        }
+        Ok(count)
+    }
+}
+
+impl std::io::Write for NodeStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.node.circuits.stream_state(self.stream) {
+            Some(StreamState::Open | StreamState::Connecting) => {}
+            _ => {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+        }
+        match self.node.write(self.stream, buf).map_err(std::io::Error::other)? {
+            0 if !buf.is_empty() => Err(std::io::ErrorKind::WouldBlock.into()),
+            accepted => Ok(accepted),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+use hkdf::Hkdf;
+use hmac::{ Hmac, Mac };
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{ PublicKey, StaticSecret };
+
+use super::cell::{ CREATED_HANDSHAKE_SIZE, CREATE_HANDSHAKE_SIZE };
+use crate::dht::node_id::NodeId;
+
+// Protocol id and the tweaks derived from it, as in Tor's ntor (with SHA-256 for H)
+const PROTOID: &[u8] = b"FreedomNode-ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"FreedomNode-ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"FreedomNode-ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"FreedomNode-ntor-curve25519-sha256-1:verify";
+const M_EXPAND: &[u8] = b"FreedomNode-ntor-curve25519-sha256-1:key_expand";
+const SERVER: &[u8] = b"Server";
+
+/// Key material a hop handshake produces, shared by the client and that hop only
+pub type HopKeyMaterial = [u8; 32];
+
+/// Client half of the ntor handshake with one hop.
+///
+/// The client sends an ephemeral key X alongside the relay's NodeId and onion key B (from its
+/// descriptor); the relay answers with its own ephemeral key Y and an AUTH tag that only the
+/// holder of B's secret can compute. Keys mix both DH(X, Y) (forward secrecy) and DH(X, B)
+/// (relay authentication); nothing identifies the client.
+/// CREATE: [NodeId (32)] [OnionKey B (32)] [ClientKey X (32)]
+/// CREATED: [ServerKey Y (32)] [Auth (32)]
+pub struct ClientHandshake {
+    node_id: NodeId,
+    onion_key: PublicKey,
+    ephemeral: StaticSecret,
+}
+
+impl ClientHandshake {
+    /// Starts a handshake with the relay `node_id` whose onion key is `onion_key`.
+    /// Returns the state to keep and the bytes to send in CREATE / EXTEND.
+    pub fn start(node_id: NodeId, onion_key: PublicKey) -> (Self, [u8; CREATE_HANDSHAKE_SIZE]) {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+
+        let mut message = [0u8; CREATE_HANDSHAKE_SIZE];
+        message[..32].copy_from_slice(node_id.as_bytes());
+        message[32..64].copy_from_slice(onion_key.as_bytes());
+        message[64..].copy_from_slice(PublicKey::from(&ephemeral).as_bytes());
+        (Self { node_id, onion_key, ephemeral }, message)
+    }
+
+    /// Checks the relay's reply; returns the hop's key material, or None if the relay could
+    /// not prove it holds the onion key.
+    pub fn finish(self, reply: &[u8; CREATED_HANDSHAKE_SIZE]) -> Option<HopKeyMaterial> {
+        let server_key = PublicKey::from(<[u8; 32]>::try_from(&reply[..32]).expect("32-byte slice"));
+        let auth = &reply[32..];
+
+        let ephemeral_shared = self.ephemeral.diffie_hellman(&server_key);
+        let static_shared = self.ephemeral.diffie_hellman(&self.onion_key);
+        if !ephemeral_shared.was_contributory() || !static_shared.was_contributory() {
+            return None;
+        }
+
+        let client_key = PublicKey::from(&self.ephemeral);
+        let secret_input = secret_input(
+            ephemeral_shared.as_bytes(),
+            static_shared.as_bytes(),
+            &self.node_id,
+            &self.onion_key,
+            &client_key,
+            &server_key
+        );
+        // Constant-time comparison
+        auth_mac(&secret_input, &self.node_id, &self.onion_key, &client_key, &server_key)
+            .verify_slice(auth)
+            .ok()?;
+
+        Some(key_material(&secret_input))
+    }
+}
+
+/// Relay half: answers a CREATE addressed to `node_id` with onion secret `onion_secret`.
+/// Returns the hop's key material and the CREATED reply, or None if the handshake names
+/// another relay or key, or carries a degenerate client key.
+pub fn server_handshake(
+    node_id: &NodeId,
+    onion_secret: &StaticSecret,
+    message: &[u8; CREATE_HANDSHAKE_SIZE]
+) -> Option<(HopKeyMaterial, [u8; CREATED_HANDSHAKE_SIZE])> {
+    let onion_key = PublicKey::from(onion_secret);
+    if message[..32] != node_id.as_bytes()[..] || message[32..64] != onion_key.as_bytes()[..] {
+        return None;
+    }
+    let client_key = PublicKey::from(<[u8; 32]>::try_from(&message[64..]).expect("32-byte slice"));
+
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let server_key = PublicKey::from(&ephemeral);
+    let ephemeral_shared = ephemeral.diffie_hellman(&client_key);
+    let static_shared = onion_secret.diffie_hellman(&client_key);
+    if !ephemeral_shared.was_contributory() || !static_shared.was_contributory() {
+        return None;
+    }
+
+    let secret_input = secret_input(
+        ephemeral_shared.as_bytes(),
+        static_shared.as_bytes(),
+        node_id,
+        &onion_key,
+        &client_key,
+        &server_key
+    );
+
+    let mut reply = [0u8; CREATED_HANDSHAKE_SIZE];
+    reply[..32].copy_from_slice(server_key.as_bytes());
+    let auth = auth_mac(&secret_input, node_id, &onion_key, &client_key, &server_key).finalize();
+    reply[32..].copy_from_slice(&auth.into_bytes());
+    Some((key_material(&secret_input), reply))
+}
+
+/// secret_input = EXP(X, y) | EXP(X, b) | ID | B | X | Y | PROTOID
+fn secret_input(
+    ephemeral_shared: &[u8; 32],
+    static_shared: &[u8; 32],
+    node_id: &NodeId,
+    onion_key: &PublicKey,
+    client_key: &PublicKey,
+    server_key: &PublicKey
+) -> Vec<u8> {
+    let mut input = Vec::with_capacity(32 * 6 + PROTOID.len());
+    input.extend_from_slice(ephemeral_shared);
+    input.extend_from_slice(static_shared);
+    input.extend_from_slice(node_id.as_bytes());
+    input.extend_from_slice(onion_key.as_bytes());
+    input.extend_from_slice(client_key.as_bytes());
+    input.extend_from_slice(server_key.as_bytes());
+    input.extend_from_slice(PROTOID);
+    input
+}
+
+/// AUTH = H(verify | ID | B | Y | X | PROTOID | "Server", t_mac), with verify = H(secret_input, t_verify)
+fn auth_mac(
+    secret_input: &[u8],
+    node_id: &NodeId,
+    onion_key: &PublicKey,
+    client_key: &PublicKey,
+    server_key: &PublicKey
+) -> Hmac<Sha256> {
+    let verify = hmac(T_VERIFY, secret_input);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(T_MAC).expect("HMAC accepts any key length");
+    mac.update(&verify);
+    mac.update(node_id.as_bytes());
+    mac.update(onion_key.as_bytes());
+    mac.update(server_key.as_bytes());
+    mac.update(client_key.as_bytes());
+    mac.update(PROTOID);
+    mac.update(SERVER);
+    mac
+}
+
+/// KEY_SEED = H(secret_input, t_key), expanded with HKDF under m_expand
+fn key_material(secret_input: &[u8]) -> HopKeyMaterial {
+    let seed = hmac(T_KEY, secret_input);
+    let hk = Hkdf::<Sha256>::from_prk(&seed).expect("SHA-256 output is a valid PRK");
+    let mut okm = [0u8; 32];
+    hk.expand(M_EXPAND, &mut okm).expect("32 bytes is a valid length for SHA-256 HKDF");
+    okm
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
@@ -0,0 +1,211 @@
+use rand::Rng;
+
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Cells sent before the overhead limit applies, so short circuits can still be padded
+const OVERHEAD_GRACE_CELLS: u64 = 50;
+
+/// Distribution of the delay before a padding cell, in milliseconds.
+/// Format: [Kind (1)] [A (4)] [B (4)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Fixed(u32),
+    Uniform {
+        min: u32,
+        max: u32,
+    },
+    Exponential {
+        mean: u32,
+    },
+}
+
+impl Distribution {
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        match *self {
+            Distribution::Fixed(ms) => ms as u64,
+            Distribution::Uniform { min, max } => rng.gen_range(min.min(max)..=max.max(min)) as u64,
+            Distribution::Exponential { mean } => {
+                let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+                (-uniform.ln() * mean as f64) as u64
+            }
+        }
+    }
+
+    /// Average delay (ms)
+    pub fn mean(&self) -> u64 {
+        match *self {
+            Distribution::Fixed(ms) => ms as u64,
+            Distribution::Uniform { min, max } => (min as u64 + max as u64) / 2,
+            Distribution::Exponential { mean } => mean as u64,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let (kind, a, b) = match *self {
+            Distribution::Fixed(ms) => (1u8, ms, 0),
+            Distribution::Uniform { min, max } => (2, min, max),
+            Distribution::Exponential { mean } => (3, mean, 0),
+        };
+        out.push(kind);
+        out.extend_from_slice(&a.to_be_bytes());
+        out.extend_from_slice(&b.to_be_bytes());
+    }
+
+    fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let kind = reader.u8()?;
+        let (a, b) = (reader.u32()?, reader.u32()?);
+        Ok(match kind {
+            1 => Distribution::Fixed(a),
+            2 => Distribution::Uniform { min: a, max: b },
+            3 => Distribution::Exponential { mean: a },
+            _ => {
+                return Err(CodecError::InvalidField("padding distribution"));
+            }
+        })
+    }
+}
+
+/// How a padding machine fills idle time: after the last real cell, it sends a padding cell
+/// each time a delay drawn from `delay` passes with no traffic, at most `max_idle_cells` in a
+/// row, and never more padding than `max_overhead_percent` of all cells it has seen.
+/// Format: [Delay (9)] [MaxIdleCells (2)] [MaxOverheadPercent (1)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaddingSpec {
+    pub delay: Distribution,
+    pub max_idle_cells: u16,
+    pub max_overhead_percent: u8,
+}
+
+impl Default for PaddingSpec {
+    fn default() -> Self {
+        Self { delay: Distribution::Exponential { mean: 1_500 }, max_idle_cells: 10, max_overhead_percent: 50 }
+    }
+}
+
+impl PaddingSpec {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        self.delay.write(out);
+        out.extend_from_slice(&self.max_idle_cells.to_be_bytes());
+        out.push(self.max_overhead_percent);
+    }
+
+    pub fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        Ok(Self { delay: Distribution::read(reader)?, max_idle_cells: reader.u16()?, max_overhead_percent: reader.u8()? })
+    }
+}
+
+/// What a relay agrees to pad: a client asks for padding, but the relay pays for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaddingLimits {
+    /// Shortest average delay between padding cells (ms)
+    pub min_mean_delay_ms: u64,
+    pub max_idle_cells: u16,
+    pub max_overhead_percent: u8,
+}
+
+impl Default for PaddingLimits {
+    fn default() -> Self {
+        Self { min_mean_delay_ms: 100, max_idle_cells: 100, max_overhead_percent: 50 }
+    }
+}
+
+impl PaddingLimits {
+    pub fn allows(&self, spec: &PaddingSpec) -> bool {
+        spec.delay.mean() >= self.min_mean_delay_ms &&
+            spec.max_idle_cells <= self.max_idle_cells &&
+            spec.max_overhead_percent <= self.max_overhead_percent
+    }
+}
+
+/// Data of RELAY PADDING_NEGOTIATE: start a machine with this spec, or stop it.
+/// Format: [Command (1): 1 start, 2 stop] [Spec (12), start only]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaddingNegotiate {
+    Start(PaddingSpec),
+    Stop,
+}
+
+impl PaddingNegotiate {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(13);
+        match self {
+            PaddingNegotiate::Start(spec) => {
+                out.push(1);
+                spec.write(&mut out);
+            }
+            PaddingNegotiate::Stop => out.push(2),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let negotiate = match reader.u8()? {
+            1 => PaddingNegotiate::Start(PaddingSpec::read(&mut reader)?),
+            2 => PaddingNegotiate::Stop,
+            _ => {
+                return Err(CodecError::InvalidField("padding command"));
+            }
+        };
+        reader.finish()?;
+        Ok(negotiate)
+    }
+}
+
+/// One end's padding state for one circuit, driven by the host's millisecond clock: report
+/// real traffic with `on_traffic`, and call `poll` regularly to learn when to send padding.
+#[derive(Debug, Clone)]
+pub struct PaddingMachine {
+    spec: PaddingSpec,
+    /// When the next padding cell is due, if one is
+    next_at: Option<u64>,
+    idle_cells: u16,
+    real_cells: u64,
+    padding_cells: u64,
+}
+
+impl PaddingMachine {
+    pub fn new(spec: PaddingSpec) -> Self {
+        Self { spec, next_at: None, idle_cells: 0, real_cells: 0, padding_cells: 0 }
+    }
+
+    /// Real cells went by: the idle period starts over.
+    pub fn on_traffic<R: Rng + ?Sized>(&mut self, cells: u64, now_ms: u64, rng: &mut R) {
+        self.real_cells += cells;
+        self.idle_cells = 0;
+        self.next_at = Some(now_ms + self.spec.delay.sample(rng));
+    }
+
+    /// Whether a padding cell is due now. Schedules the one after it, if any.
+    pub fn poll<R: Rng + ?Sized>(&mut self, now_ms: u64, rng: &mut R) -> bool {
+        match self.next_at {
+            Some(at) if at <= now_ms => {}
+            _ => {
+                return false;
+            }
+        }
+
+        if !self.within_overhead() {
+            self.next_at = None;
+            return false;
+        }
+        self.padding_cells += 1;
+        self.idle_cells += 1;
+        self.next_at = (self.idle_cells < self.spec.max_idle_cells).then(|| now_ms + self.spec.delay.sample(rng));
+        true
+    }
+
+    pub fn spec(&self) -> &PaddingSpec {
+        &self.spec
+    }
+
+    /// Padding cells sent so far
+    pub fn padding_cells(&self) -> u64 {
+        self.padding_cells
+    }
+
+    fn within_overhead(&self) -> bool {
+        let total = self.real_cells + self.padding_cells;
+        total < OVERHEAD_GRACE_CELLS || (self.padding_cells + 1) * 100 <= (self.spec.max_overhead_percent as u64) * (total + 1)
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::net::{ IpAddr, SocketAddr };
+
+use rand::Rng;
+
+use super::circuit::{ Hop, MAX_CIRCUIT_HOPS };
+use super::exit::ExitPolicy;
+use crate::dht::node_id::NodeId;
+use crate::protocol::descriptor::{ Capabilities, NodeDescriptor };
+
+/// Advertised bandwidth is self-reported, so it only counts up to this much (bytes/sec)
+pub const MAX_ADVERTISED_BANDWIDTH: u32 = 10 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum PathError {
+    #[error("Invalid path length {0} (1..={MAX_CIRCUIT_HOPS})")]
+    InvalidLength(usize),
+    #[error("Not enough diverse relays for a {0}-hop path")]
+    NotEnoughRelays(usize),
+}
+
+/// How different the relays of one path must be from each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiversityRules {
+    /// No two relays in the same IPv4 subnet of this prefix length
+    pub ipv4_prefix: u8,
+    /// No two relays in the same IPv6 subnet of this prefix length
+    pub ipv6_prefix: u8,
+    /// No two relays whose NodeIds share this many leading bits (0 disables the rule)
+    pub node_id_prefix_bits: u32,
+}
+
+impl Default for DiversityRules {
+    fn default() -> Self {
+        Self { ipv4_prefix: 16, ipv6_prefix: 32, node_id_prefix_bits: 8 }
+    }
+}
+
+/// What a path is for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathRequest {
+    pub length: usize,
+    /// Capabilities the last hop must offer (e.g. `Capabilities::EXIT`)
+    pub last_hop: Capabilities,
+    /// Relays never to use (ourselves, relays of a circuit this one must not overlap)
+    pub exclude: Vec<NodeId>,
+    /// Port the last hop's exit policy must allow streams to
+    pub exit_port: Option<u16>,
+}
+
+impl Default for PathRequest {
+    fn default() -> Self {
+        Self { length: 3, last_hop: Capabilities::RELAY, exclude: Vec::new(), exit_port: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    hop: Hop,
+    addresses: Vec<SocketAddr>,
+    capabilities: Capabilities,
+    exit_policy: ExitPolicy,
+    advertised: u32,
+    measured: Option<u32>,
+    expires_at: u64,
+}
+
+impl Candidate {
+    /// Measured bandwidth when we have it, capped advertised bandwidth otherwise
+    fn weight(&self) -> u64 {
+        self.measured.unwrap_or(self.advertised.min(MAX_ADVERTISED_BANDWIDTH)) as u64
+    }
+}
+
+/// Picks relays for circuits with probability proportional to their bandwidth, so load
+/// follows capacity, while keeping relays of one path apart: no shared family, subnet, or
+/// NodeId prefix, which makes it harder for one operator to hold several hops of a circuit.
+#[derive(Debug, Default)]
+pub struct PathSelector {
+    /// Ordered, so a seeded RNG picks the same path every run
+    relays: BTreeMap<NodeId, Candidate>,
+    families: HashMap<NodeId, HashSet<NodeId>>,
+    rules: DiversityRules,
+}
+
+impl PathSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rules(mut self, rules: DiversityRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Adds or replaces a relay from its descriptor, which the caller must have verified.
+    /// Descriptors without an address or the RELAY capability are ignored.
+    pub fn insert(&mut self, descriptor: &NodeDescriptor) -> bool {
+        if !descriptor.capabilities.contains(Capabilities::RELAY) {
+            return false;
+        }
+        let Some(hop) = Hop::from_descriptor(descriptor) else {
+            return false;
+        };
+
+        let measured = self.relays.get(&descriptor.node_id).and_then(|c| c.measured);
+        self.relays.insert(descriptor.node_id, Candidate {
+            hop,
+            addresses: descriptor.addresses.clone(),
+            capabilities: descriptor.capabilities,
+            exit_policy: descriptor.exit_policy.clone(),
+            advertised: descriptor.bandwidth,
+            measured,
+            expires_at: descriptor.expires_at,
+        });
+        true
+    }
+
+    pub fn remove(&mut self, node_id: &NodeId) -> bool {
+        self.relays.remove(node_id).is_some()
+    }
+
+    /// Records bandwidth we measured ourselves, which replaces the advertised figure.
+    pub fn set_measured_bandwidth(&mut self, node_id: &NodeId, bandwidth: u32) {
+        if let Some(candidate) = self.relays.get_mut(node_id) {
+            candidate.measured = Some(bandwidth);
+        }
+    }
+
+    /// Records throughput we saw a relay sustain (`ConnectionManager::measured_bandwidth`). It
+    /// only ever raises the relay's weight, past the cap on advertised figures if the relay
+    /// proved that much: we only see our own share of its traffic.
+    pub fn observe_bandwidth(&mut self, node_id: &NodeId, bandwidth: u32) {
+        if let Some(candidate) = self.relays.get_mut(node_id)
+            && bandwidth as u64 > candidate.weight()
+        {
+            candidate.measured = Some(bandwidth);
+        }
+    }
+
+    /// Declares relays run by the same operator; at most one of them goes in a path.
+    pub fn add_family(&mut self, members: &[NodeId]) {
+        for member in members {
+            let family = self.families.entry(*member).or_default();
+            family.extend(members.iter().filter(|m| *m != member));
+        }
+    }
+
+    /// Drops relays whose descriptor expired.
+    pub fn prune(&mut self, now: u64) -> usize {
+        let before = self.relays.len();
+        self.relays.retain(|_, c| now < c.expires_at);
+        before - self.relays.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.relays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.relays.is_empty()
+    }
+
+    /// Chooses a path, first hop first. The last hop is chosen first, among relays offering
+    /// `request.last_hop` (and allowing `request.exit_port`), then the others towards the client.
+    pub fn select_path<R: Rng + ?Sized>(&self, request: &PathRequest, rng: &mut R) -> Result<Vec<Hop>, PathError> {
+        if request.length == 0 || request.length > MAX_CIRCUIT_HOPS {
+            return Err(PathError::InvalidLength(request.length));
+        }
+
+        let mut chosen: Vec<&Candidate> = Vec::with_capacity(request.length);
+        for position in (0..request.length).rev() {
+            let last = position == request.length - 1;
+            let needed = if last { request.last_hop } else { Capabilities::RELAY };
+            let eligible: Vec<&Candidate> = self.relays
+                .values()
+                .filter(|c| c.capabilities.contains(needed) && !request.exclude.contains(&c.hop.node_id))
+                .filter(|c| !last || request.exit_port.is_none_or(|port| c.exit_policy.allows_port(port)))
+                .filter(|c| chosen.iter().all(|other| self.compatible(c, other)))
+                .collect();
+
+            let pick = weighted_choice(&eligible, rng).ok_or(PathError::NotEnoughRelays(request.length))?;
+            chosen.push(pick);
+        }
+
+        chosen.reverse();
+        Ok(chosen.into_iter().map(|c| c.hop).collect())
+    }
+
+    /// Whether two relays may share a path
+    fn compatible(&self, a: &Candidate, b: &Candidate) -> bool {
+        if a.hop.node_id == b.hop.node_id {
+            return false;
+        }
+        if self.families.get(&a.hop.node_id).is_some_and(|f| f.contains(&b.hop.node_id)) {
+            return false;
+        }
+
+        let prefix = self.rules.node_id_prefix_bits;
+        if prefix > 0 && a.hop.node_id.common_prefix_len(&b.hop.node_id) >= prefix {
+            return false;
+        }
+
+        !a.addresses
+            .iter()
+            .any(|x| b.addresses.iter().any(|y| same_subnet(x.ip(), y.ip(), &self.rules)))
+    }
+}
+
+fn same_subnet(a: IpAddr, b: IpAddr, rules: &DiversityRules) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => prefix_eq(&a.octets(), &b.octets(), rules.ipv4_prefix),
+        (IpAddr::V6(a), IpAddr::V6(b)) => prefix_eq(&a.octets(), &b.octets(), rules.ipv6_prefix),
+        _ => false,
+    }
+}
+
+pub(crate) fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bits = (bits as usize).min(a.len() * 8);
+    let (bytes, rest) = (bits / 8, bits % 8);
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    rest == 0 || (a[bytes] ^ b[bytes]) >> (8 - rest) == 0
+}
+
+/// Picks one candidate with probability proportional to its weight (uniformly if all weigh nothing).
+fn weighted_choice<'a, R: Rng + ?Sized>(candidates: &[&'a Candidate], rng: &mut R) -> Option<&'a Candidate> {
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let total: u64 = candidates.iter().map(|c| c.weight()).sum();
+    if total == 0 {
+        return Some(candidates[rng.gen_range(0..candidates.len())]);
+    }
+
+    let mut point = rng.gen_range(0..total);
+    for candidate in candidates {
+        if point < candidate.weight() {
+            return Some(candidate);
+        }
+        point -= candidate.weight();
+    }
+    None
+}
@@ -0,0 +1,159 @@
+use rand::Rng;
+
+use super::circuit::{ CircuitEvent, CircuitHandle, CircuitManager };
+use super::path::{ PathRequest, PathSelector };
+use crate::protocol::descriptor::Capabilities;
+
+/// How long an idle circuit waits in the pool before it is replaced by a fresh one
+pub const MAX_IDLE_SECS: u64 = 600;
+
+/// What a circuit will be used for, which decides its last hop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CircuitPurpose {
+    /// Streams to the outside world: ends at an exit
+    Exit,
+    /// Traffic that stays in the network (e.g. hidden services): ends at any relay
+    Internal,
+}
+
+impl CircuitPurpose {
+    pub fn path_request(self) -> PathRequest {
+        let last_hop = match self {
+            CircuitPurpose::Exit => Capabilities::EXIT,
+            CircuitPurpose::Internal => Capabilities::RELAY,
+        };
+        PathRequest { last_hop, ..Default::default() }
+    }
+}
+
+/// How many ready circuits to keep per purpose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub targets: Vec<(CircuitPurpose, usize)>,
+    pub max_idle_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self { targets: vec![(CircuitPurpose::Exit, 2), (CircuitPurpose::Internal, 1)], max_idle_secs: MAX_IDLE_SECS }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PooledCircuit {
+    handle: CircuitHandle,
+    purpose: CircuitPurpose,
+    ready: bool,
+    built_at: u64,
+}
+
+/// Keeps circuits built ahead of demand so a request can use one at once instead of waiting
+/// several round trips for a build. `maintain` tops each purpose up to its target and retires
+/// circuits idle for too long (so one circuit isn't kept for hours); `on_event` follows the
+/// builds; `take` hands a ready circuit over to the caller, whose it is from then on.
+#[derive(Debug, Default)]
+pub struct CircuitPool {
+    config: PoolConfig,
+    circuits: Vec<PooledCircuit>,
+}
+
+impl CircuitPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self { config, circuits: Vec::new() }
+    }
+
+    /// Opens circuits for every purpose below its target (counting those still building) and
+    /// closes the ones idle past `max_idle_secs`. Returns how many circuits were opened.
+    pub fn maintain<R: Rng + ?Sized>(
+        &mut self,
+        manager: &mut CircuitManager,
+        selector: &PathSelector,
+        rng: &mut R,
+        now: u64
+    ) -> usize {
+        let max_idle = self.config.max_idle_secs;
+        self.circuits.retain(|c| {
+            let stale = c.ready && now.saturating_sub(c.built_at) >= max_idle;
+            if stale {
+                manager.close(c.handle);
+            }
+            !stale
+        });
+
+        let mut opened = 0;
+        for &(purpose, target) in &self.config.targets {
+            let pooled = self.circuits.iter().filter(|c| c.purpose == purpose).count();
+            for _ in pooled..target {
+                let path = match selector.select_path(&purpose.path_request(), rng) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        log::debug!("No path for a pooled {purpose:?} circuit: {e}");
+                        break;
+                    }
+                };
+                match manager.open_circuit(path, now) {
+                    Ok(handle) => {
+                        self.circuits.push(PooledCircuit { handle, purpose, ready: false, built_at: now });
+                        opened += 1;
+                    }
+                    Err(e) => {
+                        log::debug!("Could not open a pooled {purpose:?} circuit: {e}");
+                        break;
+                    }
+                }
+            }
+        }
+        opened
+    }
+
+    /// Follows the circuit manager's events for pooled circuits, forgetting failed ones on both
+    /// sides. Returns true if the event was about one of them.
+    pub fn on_event(&mut self, manager: &mut CircuitManager, event: &CircuitEvent, now: u64) -> bool {
+        let (handle, failed) = match event {
+            CircuitEvent::Opened(handle) => (*handle, false),
+            CircuitEvent::Failed(handle, _) => (*handle, true),
+            _ => {
+                return false;
+            }
+        };
+        let Some(index) = self.circuits.iter().position(|c| c.handle == handle) else {
+            return false;
+        };
+
+        if failed {
+            manager.close(handle);
+            self.circuits.swap_remove(index);
+        } else {
+            self.circuits[index].ready = true;
+            self.circuits[index].built_at = now;
+        }
+        true
+    }
+
+    /// Hands over a ready circuit for `purpose`, the one that waited longest.
+    pub fn take(&mut self, purpose: CircuitPurpose) -> Option<CircuitHandle> {
+        let index = self.circuits
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.ready && c.purpose == purpose)
+            .min_by_key(|(_, c)| c.built_at)
+            .map(|(i, _)| i)?;
+        Some(self.circuits.remove(index).handle)
+    }
+
+    /// Ready circuits for `purpose`
+    pub fn ready(&self, purpose: CircuitPurpose) -> usize {
+        self.circuits
+            .iter()
+            .filter(|c| c.ready && c.purpose == purpose)
+            .count()
+    }
+
+    /// Circuits still building, all purposes
+    pub fn building(&self) -> usize {
+        self.circuits
+            .iter()
+            .filter(|c| !c.ready)
+            .count()
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use rand::rngs::OsRng;
+use rand::{ Rng, RngCore };
+use x25519_dalek::StaticSecret;
+
+use super::cell::{
+    Cell,
+    CellCommand,
+    DestroyReason,
+    ExtendRequest,
+    RelayBody,
+    RelayCell,
+    RelayCommand,
+    CREATED_HANDSHAKE_SIZE,
+    CREATE_HANDSHAKE_SIZE,
+};
+use super::circuit::CircuitError;
+use super::exit::ExitPolicy;
+use super::intro::{ EstablishIntro, Introduce, IntroduceStatus };
+use super::ntor;
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::node_id::NodeId;
+use super::layer::HopCrypto;
+use super::padding::{ PaddingLimits, PaddingMachine, PaddingNegotiate };
+use super::rendezvous::{ RendezvousCookie, COOKIE_SIZE };
+use super::stream::{ EndReason, StreamTarget };
+
+/// One side of a circuit at a relay: the neighbour and the circuit id used on that link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CircuitLink {
+    pub peer: SocketAddr,
+    pub circuit_id: u32,
+}
+
+impl CircuitLink {
+    pub fn new(peer: SocketAddr, circuit_id: u32) -> Self {
+        Self { peer, circuit_id }
+    }
+}
+
+/// What the host must do after a relay handled a cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayAction {
+    Send(SocketAddr, Cell),
+    /// A relay cell the client addressed to this node, on the circuit that came in on the link
+    Deliver(CircuitLink, RelayCell),
+}
+
+/// A circuit as seen by one relay: our layers, and the link it was extended to, if any (or
+/// the circuit it was spliced to, at a rendezvous point).
+struct RelayCircuit {
+    crypto: HopCrypto,
+    next: Option<CircuitLink>,
+    /// Padding towards the client, if it negotiated some with us
+    padding: Option<PaddingMachine>,
+    /// Real relay cells through this circuit since the last `poll_padding`
+    traffic: u64,
+    /// Auth key of the service that made us its introduction point on this circuit
+    intro_key: Option<[u8; 32]>,
+    /// Cookie the client waits under for a service to join this circuit
+    rendezvous_cookie: Option<RendezvousCookie>,
+    /// The other circuit of a completed rendezvous, which cells we don't recognize go on to
+    spliced: Option<CircuitLink>,
+}
+
+impl RelayCircuit {
+    fn new(crypto: HopCrypto) -> Self {
+        Self { crypto, next: None, padding: None, traffic: 0, intro_key: None, rendezvous_cookie: None, spliced: None }
+    }
+
+    /// Encodes a cell for the client and adds our backward layer.
+    fn seal_backward(&mut self, cell: &RelayCell) -> Result<RelayBody, CircuitError> {
+        let mut body = cell.to_body()?;
+        self.crypto.backward.seal(&mut body);
+        self.crypto.backward.apply(&mut body);
+        Ok(body)
+    }
+}
+
+/// Relay side of circuits: answers CREATE, removes our layer from forward relay cells
+/// (acting on the ones we recognize, forwarding the rest) and adds it to backward ones.
+/// Sans-IO like `CircuitManager`: `on_cell` returns what to send or deliver.
+pub struct RelayCircuits {
+    node_id: NodeId,
+    onion_secret: StaticSecret,
+    /// Keyed by the link towards the client
+    circuits: HashMap<CircuitLink, RelayCircuit>,
+    /// Link towards the next hop -> link towards the client
+    backward: HashMap<CircuitLink, CircuitLink>,
+    /// Service auth key -> the service's circuit, for the services we introduce
+    intro_points: HashMap<[u8; 32], CircuitLink>,
+    /// Cookie -> the client's circuit, for the clients waiting here for a service
+    rendezvous: HashMap<RendezvousCookie, CircuitLink>,
+    padding_limits: PaddingLimits,
+    exit_policy: ExitPolicy,
+}
+
+impl RelayCircuits {
+    /// Relays circuits as `identity`, whose onion key is the one advertised in its descriptor.
+    pub fn new(identity: &NodeIdentity) -> Self {
+        Self {
+            node_id: NodeId::from_identity_key(&identity.identity_keypair.verifying_key()),
+            onion_secret: identity.onion_secret.clone(),
+            circuits: HashMap::new(),
+            backward: HashMap::new(),
+            intro_points: HashMap::new(),
+            rendezvous: HashMap::new(),
+            padding_limits: PaddingLimits::default(),
+            exit_policy: ExitPolicy::reject_all(),
+        }
+    }
+
+    /// Opens exit streams as `policy` allows (the one in our descriptor); by default none.
+    pub fn with_exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+
+    /// Padding clients may ask of this relay; requests beyond it are refused.
+    pub fn with_padding_limits(mut self, limits: PaddingLimits) -> Self {
+        self.padding_limits = limits;
+        self
+    }
+
+    /// Handles a cell from a neighbour.
+    pub fn on_cell(&mut self, from: SocketAddr, cell: Cell) -> Result<Vec<RelayAction>, CircuitError> {
+        let link = CircuitLink::new(from, cell.circuit_id);
+        if let Some(&prev) = self.backward.get(&link) {
+            return self.on_backward(link, prev, cell);
+        }
+
+        match cell.command {
+            CellCommand::Create => {
+                // A replayed or confused CREATE must not replace the keys of the circuit in use
+                if self.circuits.contains_key(&link) {
+                    return Err(CircuitError::DuplicateCircuit(cell.circuit_id));
+                }
+                let handshake = cell.data::<CREATE_HANDSHAKE_SIZE>();
+                let Some((key, reply)) = ntor::server_handshake(&self.node_id, &self.onion_secret, &handshake) else {
+                    log::debug!("Refused CREATE for circuit {}: handshake not for this relay", cell.circuit_id);
+                    return Ok(vec![RelayAction::Send(from, Cell::destroy(cell.circuit_id, DestroyReason::Protocol))]);
+                };
+                self.circuits.insert(link, RelayCircuit::new(HopCrypto::new(&key)));
+                Ok(vec![RelayAction::Send(from, Cell::new(cell.circuit_id, CellCommand::Created, &reply))])
+            }
+            CellCommand::Relay => {
+                let circuit = self.circuits.get_mut(&link).ok_or(CircuitError::UnknownCircuit(cell.circuit_id))?;
+                let mut body = *cell.payload;
+                circuit.crypto.forward.apply(&mut body);
+
+                if circuit.crypto.forward.recognize(&body) {
+                    let relay = RelayCell::from_body(&body)?;
+                    if relay.command != RelayCommand::Drop {
+                        circuit.traffic += 1;
+                    }
+                    return self.on_recognized(link, relay);
+                }
+                circuit.traffic += 1;
+                match (circuit.next, circuit.spliced) {
+                    (Some(next), _) => Ok(vec![RelayAction::Send(next.peer, Cell::relay(next.circuit_id, body))]),
+                    // On to the other end of the rendezvous, under our layer of its circuit
+                    (None, Some(other)) => {
+                        let spliced = self.circuits.get_mut(&other).ok_or(CircuitError::UnknownCircuit(other.circuit_id))?;
+                        spliced.crypto.backward.apply(&mut body);
+                        spliced.traffic += 1;
+                        Ok(vec![RelayAction::Send(other.peer, Cell::relay(other.circuit_id, body))])
+                    }
+                    (None, None) => Err(CircuitError::Unrecognized),
+                }
+            }
+            // The client (or the previous hop on its behalf) tore the circuit down: so does every hop after us
+            CellCommand::Destroy => Ok(self.tear_down(link, cell.destroy_reason())),
+            CellCommand::Created => Err(CircuitError::UnexpectedCell(cell.command)),
+        }
+    }
+
+    /// Sends a relay cell back to the client of a circuit that ends here (`link` as given in `Deliver`).
+    pub fn send_backward(&mut self, link: CircuitLink, cell: &RelayCell) -> Result<RelayAction, CircuitError> {
+        let circuit = self.circuits.get_mut(&link).ok_or(CircuitError::UnknownCircuit(link.circuit_id))?;
+        let body = circuit.seal_backward(cell)?;
+        circuit.traffic += 1;
+        Ok(RelayAction::Send(link.peer, Cell::relay(link.circuit_id, body)))
+    }
+
+    /// Sends the padding cells due by `now_ms` (the host's millisecond clock) to the clients
+    /// that negotiated padding; see `CircuitManager::poll_padding`.
+    pub fn poll_padding<R: Rng + ?Sized>(&mut self, now_ms: u64, rng: &mut R) -> Result<Vec<RelayAction>, CircuitError> {
+        let mut actions = Vec::new();
+        for (link, circuit) in &mut self.circuits {
+            let Some(machine) = &mut circuit.padding else {
+                continue;
+            };
+            if circuit.traffic > 0 {
+                machine.on_traffic(std::mem::take(&mut circuit.traffic), now_ms, rng);
+            }
+            if machine.poll(now_ms, rng) {
+                let body = circuit.seal_backward(&RelayCell::new(RelayCommand::Drop, 0, Vec::new()))?;
+                actions.push(RelayAction::Send(link.peer, Cell::relay(link.circuit_id, body)));
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Number of circuits going through this relay
+    pub fn len(&self) -> usize {
+        self.circuits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+
+    /// Services this relay is an introduction point for
+    pub fn intro_points(&self) -> usize {
+        self.intro_points.len()
+    }
+
+    /// Clients waiting at this relay for a service to join them
+    pub fn rendezvous_waiting(&self) -> usize {
+        self.rendezvous.len()
+    }
+
+    /// Drops every circuit that goes through a neighbour we lost the link to. Circuits coming
+    /// from it are destroyed towards their next hop; circuits extended to it are truncated
+    /// back to us, telling their client.
+    pub fn on_link_closed(&mut self, peer: SocketAddr) -> Result<Vec<RelayAction>, CircuitError> {
+        let mut actions = Vec::new();
+        let from_peer: Vec<CircuitLink> = self.circuits.keys().filter(|l| l.peer == peer).copied().collect();
+        for link in from_peer {
+            actions.extend(self.tear_down(link, DestroyReason::ChannelClosed));
+        }
+
+        let to_peer: Vec<(CircuitLink, CircuitLink)> = self.backward
+            .iter()
+            .filter(|(next, _)| next.peer == peer)
+            .map(|(next, prev)| (*next, *prev))
+            .collect();
+        for (next, prev) in to_peer {
+            self.backward.remove(&next);
+            actions.push(self.truncate(prev, DestroyReason::ChannelClosed)?);
+        }
+        Ok(actions)
+    }
+
+    fn on_recognized(&mut self, link: CircuitLink, relay: RelayCell) -> Result<Vec<RelayAction>, CircuitError> {
+        if relay.command == RelayCommand::Truncate {
+            let next = self.circuits.get(&link).and_then(|c| c.next);
+            let mut actions: Vec<RelayAction> = next
+                .map(|next| self.destroy_next(next, DestroyReason::Requested))
+                .into_iter()
+                .collect();
+            actions.push(self.truncate(link, DestroyReason::Requested)?);
+            return Ok(actions);
+        }
+        match relay.command {
+            RelayCommand::Extend => {}
+            RelayCommand::Drop => {
+                return Ok(Vec::new());
+            }
+            RelayCommand::PaddingNegotiate => {
+                return Ok(vec![self.negotiate_padding(link, PaddingNegotiate::from_bytes(&relay.data)?)?]);
+            }
+            RelayCommand::EstablishIntro => {
+                return Ok(self.establish_intro(link, &relay.data));
+            }
+            RelayCommand::Introduce1 => {
+                return self.introduce(link, &relay.data);
+            }
+            RelayCommand::EstablishRendezvous => {
+                return self.establish_rendezvous(link, &relay.data);
+            }
+            RelayCommand::Rendezvous1 => {
+                return self.rendezvous(link, &relay.data);
+            }
+            // Streams our policy rejects are ended here, never reaching the host. BEGIN_DIR opens no
+            // connection out of the network, so it always reaches the host, at any hop
+            RelayCommand::Begin if !self.exit_policy.allows_target(&StreamTarget::from_bytes(&relay.data)?) => {
+                log::debug!("Refused stream {} on circuit {}: exit policy", relay.stream_id, link.circuit_id);
+                let end = RelayCell::new(RelayCommand::End, relay.stream_id, vec![EndReason::ExitPolicy as u8]);
+                return Ok(vec![self.send_backward(link, &end)?]);
+            }
+            _ => {
+                return Ok(vec![RelayAction::Deliver(link, relay)]);
+            }
+        }
+
+        let extend = ExtendRequest::from_bytes(&relay.data)?;
+        let next = CircuitLink::new(extend.addr, self.unused_id(extend.addr));
+        match self.circuits.get_mut(&link) {
+            Some(circuit) if circuit.next.is_none() => circuit.next = Some(next),
+            _ => {
+                return Err(CircuitError::UnexpectedRelay(relay.command));
+            }
+        }
+        self.backward.insert(next, link);
+
+        log::debug!("Extending circuit {} to {}", link.circuit_id, extend.node_id);
+        Ok(vec![RelayAction::Send(next.peer, Cell::new(next.circuit_id, CellCommand::Create, &extend.handshake))])
+    }
+
+    /// A cell from the next hop, travelling back to the client.
+    fn on_backward(&mut self, link: CircuitLink, prev: CircuitLink, cell: Cell) -> Result<Vec<RelayAction>, CircuitError> {
+        match cell.command {
+            CellCommand::Created => {
+                let reply = cell.data::<CREATED_HANDSHAKE_SIZE>().to_vec();
+                Ok(vec![self.send_backward(prev, &RelayCell::new(RelayCommand::Extended, 0, reply))?])
+            }
+            CellCommand::Relay => {
+                let circuit = self.circuits.get_mut(&prev).ok_or(CircuitError::UnknownCircuit(prev.circuit_id))?;
+                let mut body = *cell.payload;
+                circuit.crypto.backward.apply(&mut body);
+                circuit.traffic += 1;
+                Ok(vec![RelayAction::Send(prev.peer, Cell::relay(prev.circuit_id, body))])
+            }
+            // The rest of the circuit is gone; the client keeps the hops up to us and decides what to do
+            CellCommand::Destroy => {
+                self.backward.remove(&link);
+                Ok(vec![self.truncate(prev, cell.destroy_reason())?])
+            }
+            CellCommand::Create => Err(CircuitError::UnexpectedCell(cell.command)),
+        }
+    }
+
+    /// Starts or stops padding towards the client of a circuit, as far as our limits allow,
+    /// and tells the client whether we do.
+    fn negotiate_padding(&mut self, link: CircuitLink, negotiate: PaddingNegotiate) -> Result<RelayAction, CircuitError> {
+        let machine = match negotiate {
+            PaddingNegotiate::Start(spec) if self.padding_limits.allows(&spec) => Some(PaddingMachine::new(spec)),
+            PaddingNegotiate::Start(spec) => {
+                log::debug!("Refused padding on circuit {}: {spec:?} exceeds our limits", link.circuit_id);
+                None
+            }
+            PaddingNegotiate::Stop => None,
+        };
+        let accepted = machine.is_some() || negotiate == PaddingNegotiate::Stop;
+        if let Some(circuit) = self.circuits.get_mut(&link) {
+            circuit.padding = machine;
+        }
+        self.send_backward(link, &RelayCell::new(RelayCommand::PaddingNegotiated, 0, vec![accepted as u8]))
+    }
+
+    /// Makes us an introduction point for the service on `link` if it signed the request for
+    /// this circuit. A request that fails to verify (e.g. replayed from another circuit) tears
+    /// the circuit down.
+    fn establish_intro(&mut self, link: CircuitLink, data: &[u8]) -> Vec<RelayAction> {
+        let valid = match (EstablishIntro::from_bytes(data), self.circuits.get(&link)) {
+            (Ok(establish), Some(circuit)) if circuit.next.is_none() && establish.verify(&circuit.crypto.binding) => Some(establish),
+            _ => None,
+        };
+        let Some(establish) = valid else {
+            log::debug!("Invalid ESTABLISH_INTRO on circuit {}, tearing it down", link.circuit_id);
+            return self.refuse(link);
+        };
+
+        let auth_key = establish.auth_key.to_bytes();
+        // The newest circuit wins: a service re-establishing means its old circuit is gone
+        if let Some(old) = self.intro_points.insert(auth_key, link) &&
+            let Some(circuit) = self.circuits.get_mut(&old)
+        {
+            circuit.intro_key = None;
+        }
+        if let Some(circuit) = self.circuits.get_mut(&link) {
+            circuit.intro_key = Some(auth_key);
+        }
+        log::debug!("Introduction point established on circuit {}", link.circuit_id);
+        self.send_backward(link, &RelayCell::new(RelayCommand::IntroEstablished, 0, Vec::new())).into_iter().collect()
+    }
+
+    /// Passes a client's INTRODUCE1 to the service it names as INTRODUCE2, and tells the
+    /// client whether we could.
+    fn introduce(&mut self, link: CircuitLink, data: &[u8]) -> Result<Vec<RelayAction>, CircuitError> {
+        let mut actions = Vec::new();
+        let status = match Introduce::from_bytes(data) {
+            Ok(introduce) => match self.intro_points.get(&introduce.auth_key).copied() {
+                Some(service) => {
+                    actions.push(self.send_backward(service, &RelayCell::new(RelayCommand::Introduce2, 0, data.to_vec()))?);
+                    IntroduceStatus::Success
+                }
+                None => IntroduceStatus::UnknownService,
+            },
+            Err(_) => IntroduceStatus::Malformed,
+        };
+        actions.push(self.send_backward(link, &RelayCell::new(RelayCommand::IntroduceAck, 0, vec![status as u8]))?);
+        Ok(actions)
+    }
+
+    /// Keeps the client's circuit on `link` under the cookie in `data` until a service joins
+    /// it. A cookie already in use, or a circuit that goes on past us, tears the circuit down.
+    fn establish_rendezvous(&mut self, link: CircuitLink, data: &[u8]) -> Result<Vec<RelayAction>, CircuitError> {
+        let cookie = match (RendezvousCookie::try_from(data), self.circuits.get(&link)) {
+            (Ok(cookie), Some(circuit))
+                if circuit.next.is_none() &&
+                circuit.spliced.is_none() &&
+                circuit.rendezvous_cookie.is_none() &&
+                !self.rendezvous.contains_key(&cookie) => cookie,
+            _ => {
+                log::debug!("Invalid ESTABLISH_RENDEZVOUS on circuit {}, tearing it down", link.circuit_id);
+                return Ok(self.refuse(link));
+            }
+        };
+
+        self.rendezvous.insert(cookie, link);
+        if let Some(circuit) = self.circuits.get_mut(&link) {
+            circuit.rendezvous_cookie = Some(cookie);
+        }
+        Ok(vec![self.send_backward(link, &RelayCell::new(RelayCommand::RendezvousEstablished, 0, Vec::new()))?])
+    }
+
+    /// Joins the service's circuit on `link` to the client's circuit waiting under the cookie
+    /// in `data`, and passes the service's reply on to the client. From then on, cells either
+    /// end doesn't address to us cross over to the other circuit. An unknown cookie tears the
+    /// service's circuit down.
+    fn rendezvous(&mut self, service: CircuitLink, data: &[u8]) -> Result<Vec<RelayAction>, CircuitError> {
+        let client = match (data.get(..COOKIE_SIZE), self.circuits.get(&service)) {
+            (Some(cookie), Some(circuit))
+                if data.len() == COOKIE_SIZE + CREATED_HANDSHAKE_SIZE &&
+                circuit.next.is_none() &&
+                circuit.spliced.is_none() &&
+                circuit.rendezvous_cookie.is_none() => self.rendezvous.remove(cookie),
+            _ => None,
+        };
+        let Some(client) = client else {
+            log::debug!("Invalid RENDEZVOUS1 on circuit {}, tearing it down", service.circuit_id);
+            return Ok(self.refuse(service));
+        };
+
+        let action = self.send_backward(client, &RelayCell::new(RelayCommand::Rendezvous2, 0, data[COOKIE_SIZE..].to_vec()))?;
+        if let Some(circuit) = self.circuits.get_mut(&client) {
+            circuit.rendezvous_cookie = None;
+            circuit.spliced = Some(service);
+        }
+        if let Some(circuit) = self.circuits.get_mut(&service) {
+            circuit.spliced = Some(client);
+        }
+        log::debug!("Rendezvous: circuit {} joined circuit {}", service.circuit_id, client.circuit_id);
+        Ok(vec![action])
+    }
+
+    /// Tears down a circuit whose client broke the protocol, at both ends.
+    fn refuse(&mut self, link: CircuitLink) -> Vec<RelayAction> {
+        let mut actions = vec![RelayAction::Send(link.peer, Cell::destroy(link.circuit_id, DestroyReason::Protocol))];
+        actions.extend(self.tear_down(link, DestroyReason::Protocol));
+        actions
+    }
+
+    /// Forgets the circuit that came in on `link` and destroys what it led to: the rest of
+    /// the circuit, or the circuit it was spliced to.
+    fn tear_down(&mut self, link: CircuitLink, reason: DestroyReason) -> Vec<RelayAction> {
+        let Some(circuit) = self.remove_circuit(link) else {
+            return Vec::new();
+        };
+        let mut actions = Vec::new();
+        if let Some(next) = circuit.next {
+            actions.push(self.destroy_next(next, reason));
+        }
+        if let Some(other) = circuit.spliced && self.remove_circuit(other).is_some() {
+            actions.push(RelayAction::Send(other.peer, Cell::destroy(other.circuit_id, reason)));
+        }
+        actions
+    }
+
+    /// Forgets a circuit, and the introduction point or rendezvous it held if any.
+    fn remove_circuit(&mut self, link: CircuitLink) -> Option<RelayCircuit> {
+        let circuit = self.circuits.remove(&link)?;
+        if let Some(key) = circuit.intro_key {
+            self.intro_points.remove(&key);
+        }
+        if let Some(cookie) = circuit.rendezvous_cookie {
+            self.rendezvous.remove(&cookie);
+        }
+        Some(circuit)
+    }
+
+    /// Forgets the link to the next hop of a circuit and tells that hop to tear its part down.
+    fn destroy_next(&mut self, next: CircuitLink, reason: DestroyReason) -> RelayAction {
+        self.backward.remove(&next);
+        RelayAction::Send(next.peer, Cell::destroy(next.circuit_id, reason))
+    }
+
+    /// Makes us the last hop of the circuit that came in on `link`, telling its client why.
+    fn truncate(&mut self, link: CircuitLink, reason: DestroyReason) -> Result<RelayAction, CircuitError> {
+        if let Some(circuit) = self.circuits.get_mut(&link) {
+            circuit.next = None;
+        }
+        log::debug!("Circuit {} truncated after this hop ({reason:?})", link.circuit_id);
+        self.send_backward(link, &RelayCell::new(RelayCommand::Truncated, 0, vec![reason as u8]))
+    }
+
+    fn unused_id(&self, peer: SocketAddr) -> u32 {
+        loop {
+            let link = CircuitLink::new(peer, OsRng.next_u32());
+            if link.circuit_id != 0 && !self.backward.contains_key(&link) && !self.circuits.contains_key(&link) {
+                return link.circuit_id;
+            }
+        }
+    }
+}
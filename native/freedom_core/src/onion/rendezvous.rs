@@ -0,0 +1,79 @@
+use ed25519_dalek::VerifyingKey;
+use rand::rngs::OsRng;
+use x25519_dalek::{ PublicKey as X25519PublicKey, StaticSecret };
+
+use super::cell::{ CREATED_HANDSHAKE_SIZE, CREATE_HANDSHAKE_SIZE };
+use super::circuit::Hop;
+use super::ntor::{ self, HopKeyMaterial };
+use crate::crypto::helper;
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ CodecError, Reader };
+
+pub const COOKIE_SIZE: usize = 20;
+
+/// Names a client's circuit at its rendezvous point, so the service can join it
+pub type RendezvousCookie = [u8; COOKIE_SIZE];
+
+/// What a client tells a service in the payload of INTRODUCE1: where it waits and its half of
+/// the end-to-end handshake. Sealed to the introduction point's encryption key, so the
+/// introduction point learns neither.
+/// Plaintext: [Cookie (20)] [RP Hop] [Handshake (96)]
+/// Sealed: [EphemeralKey (32)] [Nonce (12)] [Ciphertext] [Tag (16)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Introduction {
+    pub cookie: RendezvousCookie,
+    /// The rendezvous point
+    pub rendezvous: Hop,
+    pub handshake: [u8; CREATE_HANDSHAKE_SIZE],
+}
+
+impl Introduction {
+    pub fn seal(&self, enc_key: &X25519PublicKey) -> Vec<u8> {
+        let mut plaintext = Vec::with_capacity(COOKIE_SIZE + 64 + 19 + CREATE_HANDSHAKE_SIZE);
+        plaintext.extend_from_slice(&self.cookie);
+        self.rendezvous.write(&mut plaintext);
+        plaintext.extend_from_slice(&self.handshake);
+
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let key = helper::create_session_key(&ephemeral, enc_key);
+        let mut sealed = X25519PublicKey::from(&ephemeral).as_bytes().to_vec();
+        sealed.extend(helper::encrypt_layer(&key, &plaintext).expect("an introduction is far below the AEAD length limit"));
+        sealed
+    }
+
+    /// Decrypts an introduction sealed to `enc_secret`'s key; None if it was sealed to another
+    /// key, tampered with or malformed.
+    pub fn open(sealed: &[u8], enc_secret: &StaticSecret) -> Option<Self> {
+        let ephemeral = X25519PublicKey::from(<[u8; 32]>::try_from(sealed.get(..32)?).ok()?);
+        let key = helper::create_session_key(enc_secret, &ephemeral);
+        let plaintext = helper::try_decrypt_layer(&key, &sealed[32..]).ok()?;
+        Self::read(&plaintext).ok()
+    }
+
+    fn read(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let cookie = reader.take_array()?;
+        let rendezvous = Hop::read(&mut reader)?;
+        let handshake = reader.take_array()?;
+        reader.finish()?;
+        Ok(Self { cookie, rendezvous, handshake })
+    }
+}
+
+/// A service's answer to an introduction, ready to join the client with
+/// `CircuitManager::open_rendezvous`.
+pub struct AcceptedIntroduction {
+    pub cookie: RendezvousCookie,
+    pub rendezvous: Hop,
+    pub(super) reply: [u8; CREATED_HANDSHAKE_SIZE],
+    pub(super) key: HopKeyMaterial,
+}
+
+impl AcceptedIntroduction {
+    /// Answers the client's handshake as the service known by `auth_key` at the introduction
+    /// point, holding `enc_secret`. None if the handshake was meant for another key.
+    pub fn accept(introduction: Introduction, auth_key: &VerifyingKey, enc_secret: &StaticSecret) -> Option<Self> {
+        let (key, reply) = ntor::server_handshake(&NodeId::from_identity_key(auth_key), enc_secret, &introduction.handshake)?;
+        Some(Self { cookie: introduction.cookie, rendezvous: introduction.rendezvous, reply, key })
+    }
+}
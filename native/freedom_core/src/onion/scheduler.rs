@@ -0,0 +1,206 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+
+use super::bandwidth::MAX_QUEUED_CELLS;
+use super::cell::Cell;
+use super::relay::CircuitLink;
+
+/// A circuit's EWMA below which it counts as quiet, and `prune` may forget it
+const QUIET_EWMA: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// Cells a peer sends in its turn before the next peer's
+    pub quantum: usize,
+    /// Time for a circuit's count of recently sent cells to halve (Tor's
+    /// `CircuitPriorityHalflife`): shorter forgives a bulk circuit sooner
+    pub halflife_ms: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self { quantum: 8, halflife_ms: 30_000 }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum SchedulerError {
+    #[error("Too many cells queued on circuit {0} (max {MAX_QUEUED_CELLS})")]
+    QueueFull(u32),
+}
+
+struct CircuitQueue {
+    cells: VecDeque<Cell>,
+    /// Cells sent, decaying by half every `halflife_ms`, as of `updated_ms`
+    ewma: f64,
+    updated_ms: u64,
+    /// When the circuit last got cells to send, to break ties first come, first served
+    active_since: u64,
+}
+
+impl CircuitQueue {
+    fn ewma_at(&self, now_ms: u64, halflife_ms: u64) -> f64 {
+        let halvings = now_ms.saturating_sub(self.updated_ms) as f64 / halflife_ms.max(1) as f64;
+        self.ewma * 0.5f64.powf(halvings)
+    }
+}
+
+#[derive(Default)]
+struct PeerQueue {
+    circuits: HashMap<u32, CircuitQueue>,
+    queued: usize,
+    /// Cells left in a turn cut short by the budget
+    deficit: usize,
+}
+
+impl PeerQueue {
+    /// Takes the next cell of the circuit with the fewest cells sent lately.
+    fn pop(&mut self, now_ms: u64, halflife_ms: u64) -> Option<Cell> {
+        let (_, queue) = self.circuits
+            .iter_mut()
+            .filter(|(_, q)| !q.cells.is_empty())
+            .min_by(|(_, a), (_, b)| {
+                a.ewma_at(now_ms, halflife_ms)
+                    .total_cmp(&b.ewma_at(now_ms, halflife_ms))
+                    .then(a.active_since.cmp(&b.active_since))
+            })?;
+        queue.ewma = queue.ewma_at(now_ms, halflife_ms) + 1.0;
+        queue.updated_ms = queue.updated_ms.max(now_ms);
+        self.queued -= 1;
+        queue.cells.pop_front()
+    }
+}
+
+/// Decides which cell goes out next when many circuits have cells to send, so one bulk
+/// transfer can't starve the interactive circuits sharing its links.
+///
+/// Peers take turns by deficit round robin, `quantum` cells each. On a peer, the circuit that
+/// sent the fewest cells lately goes first: every circuit keeps a count of the cells it sent
+/// that decays exponentially (by half every `halflife_ms`), the EWMA of Tor's circuit
+/// scheduler, so a circuit that only sends now and then is always ahead of one that streams.
+/// Cells of one circuit keep their order.
+///
+/// The host `push`es the cells it would send (what `RelayCircuits` or `BandwidthLimiter` let
+/// out, or a `Node`'s outgoing cells) and writes what `poll` returns as the links can take it,
+/// or what `poll_peer` returns when one connection's socket is writable.
+pub struct CellScheduler {
+    config: SchedulerConfig,
+    peers: HashMap<SocketAddr, PeerQueue>,
+    /// Peers with cells waiting, in turn order
+    turns: VecDeque<SocketAddr>,
+    pushes: u64,
+}
+
+impl CellScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self { config, peers: HashMap::new(), turns: VecDeque::new(), pushes: 0 }
+    }
+
+    /// Queues a cell to send to `to`.
+    pub fn push(&mut self, to: SocketAddr, cell: Cell, now_ms: u64) -> Result<(), SchedulerError> {
+        let peer = self.peers.entry(to).or_default();
+        let circuit_id = cell.circuit_id;
+        let queue = peer.circuits.entry(circuit_id).or_insert_with(|| CircuitQueue {
+            cells: VecDeque::new(),
+            ewma: 0.0,
+            updated_ms: now_ms,
+            active_since: 0,
+        });
+        if queue.cells.len() >= MAX_QUEUED_CELLS {
+            return Err(SchedulerError::QueueFull(circuit_id));
+        }
+        if queue.cells.is_empty() {
+            queue.active_since = self.pushes;
+        }
+        self.pushes += 1;
+        queue.cells.push_back(cell);
+        if peer.queued == 0 {
+            self.turns.push_back(to);
+        }
+        peer.queued += 1;
+        Ok(())
+    }
+
+    /// Takes up to `budget` cells to send now, with the peer each goes to, peers in turn.
+    pub fn poll(&mut self, budget: usize, now_ms: u64) -> Vec<(SocketAddr, Cell)> {
+        let config = self.config;
+        let mut sent = Vec::new();
+        while sent.len() < budget && let Some(to) = self.turns.pop_front() {
+            let peer = self.peers.get_mut(&to).expect("peers in turn are known");
+            if peer.deficit == 0 {
+                peer.deficit = config.quantum.max(1);
+            }
+            while peer.deficit > 0 && sent.len() < budget && let Some(cell) = peer.pop(now_ms, config.halflife_ms) {
+                peer.deficit -= 1;
+                sent.push((to, cell));
+            }
+
+            if peer.queued == 0 {
+                peer.deficit = 0;
+            } else if peer.deficit > 0 {
+                // Out of budget mid-turn: the peer finishes its turn next time
+                self.turns.push_front(to);
+            } else {
+                self.turns.push_back(to);
+            }
+        }
+        sent
+    }
+
+    /// Takes up to `budget` cells for `to` alone, e.g. when its connection can take more.
+    /// Doesn't affect whose turn it is.
+    pub fn poll_peer(&mut self, to: SocketAddr, budget: usize, now_ms: u64) -> Vec<Cell> {
+        let Some(peer) = self.peers.get_mut(&to) else {
+            return Vec::new();
+        };
+        let mut sent = Vec::new();
+        while sent.len() < budget && let Some(cell) = peer.pop(now_ms, self.config.halflife_ms) {
+            sent.push(cell);
+        }
+        if peer.queued == 0 {
+            peer.deficit = 0;
+            self.turns.retain(|peer| *peer != to);
+        }
+        sent
+    }
+
+    /// Cells waiting, all peers
+    pub fn queued(&self) -> usize {
+        self.peers.values().map(|p| p.queued).sum()
+    }
+
+    /// Cells waiting for `to`
+    pub fn queued_for(&self, to: SocketAddr) -> usize {
+        self.peers.get(&to).map_or(0, |p| p.queued)
+    }
+
+    /// Forgets a circuit torn down on the link to `link.peer`. Returns the cells it had waiting.
+    pub fn forget(&mut self, link: CircuitLink) -> usize {
+        let Some(peer) = self.peers.get_mut(&link.peer) else {
+            return 0;
+        };
+        let dropped = peer.circuits.remove(&link.circuit_id).map_or(0, |q| q.cells.len());
+        peer.queued -= dropped;
+        if peer.queued == 0 {
+            peer.deficit = 0;
+            self.turns.retain(|peer| *peer != link.peer);
+        }
+        dropped
+    }
+
+    /// Forgets a peer we lost the link to, with all its circuits. Returns the cells dropped.
+    pub fn forget_peer(&mut self, to: SocketAddr) -> usize {
+        self.turns.retain(|peer| *peer != to);
+        self.peers.remove(&to).map_or(0, |p| p.queued)
+    }
+
+    /// Drops circuits with nothing queued that have been quiet long enough to start from
+    /// nothing again anyway.
+    pub fn prune(&mut self, now_ms: u64) {
+        let halflife_ms = self.config.halflife_ms;
+        self.peers.retain(|_, peer| {
+            peer.circuits.retain(|_, q| !q.cells.is_empty() || q.ewma_at(now_ms, halflife_ms) >= QUIET_EWMA);
+            !peer.circuits.is_empty()
+        });
+    }
+}
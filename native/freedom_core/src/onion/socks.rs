@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+use std::hash::{ BuildHasher, RandomState };
+use std::net::{ Ipv4Addr, SocketAddr };
+
+use super::address::{ OnionAddress, ADDRESS_SUFFIX };
+use super::cell::RELAY_DATA_SIZE;
+use super::circuit::{ CircuitEvent, CircuitHandle, StreamHandle };
+use super::isolation::{ CircuitIsolation, IsolationKey };
+use super::node::{ ConnectFailure, ConnectId, Node, NodeEvent, CONNECT_TIMEOUT_SECS };
+use super::pool::{ CircuitPool, CircuitPurpose };
+use super::stream::{ EndReason, StreamEvent, StreamTarget };
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::transport::socks::{
+    self,
+    SocksAddr,
+    SocksReply,
+    AUTH_NONE,
+    AUTH_PASSWORD,
+    AUTH_UNACCEPTABLE,
+    CMD_CONNECT,
+    PASSWORD_VERSION,
+    SOCKS_VERSION,
+};
+
+/// An application connected to the SOCKS port, as numbered by `SocksServer::accept`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocksClient(u64);
+
+/// What the host does on an application's socket, drained with `SocksServer::take_outgoing`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocksCommand {
+    Write {
+        client: SocksClient,
+        bytes: Vec<u8>,
+    },
+    Close(SocksClient),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocksServerConfig {
+    /// Streams of clients that authenticated with different usernames and passwords never share
+    /// a circuit (what the credentials are is not checked), like Tor's `IsolateSOCKSAuth`
+    pub isolate_by_auth: bool,
+    /// Streams to different hosts never share a circuit
+    pub isolate_destinations: bool,
+    /// Targets other than `.freedom` addresses leave the network through an exit; without it
+    /// they are refused
+    pub allow_exit: bool,
+}
+
+impl Default for SocksServerConfig {
+    fn default() -> Self {
+        Self { isolate_by_auth: true, isolate_destinations: false, allow_exit: true }
+    }
+}
+
+/// Where a client's bytes go once it is connected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Upstream {
+    /// An end-to-end encrypted stream to a hidden service, read and written through the `Node`
+    Service(StreamHandle),
+    /// A stream out of an exit, read and written through the node's circuits
+    Exit(StreamHandle),
+}
+
+enum Phase {
+    /// Waiting for the methods the client offers
+    Greeting,
+    /// Waiting for its username and password
+    Password,
+    /// Waiting for its request
+    Request,
+    /// Waiting for the pool to have an exit circuit
+    Circuit {
+        target: StreamTarget,
+        key: IsolationKey,
+    },
+    /// Waiting for `Node::connect` to complete
+    Service,
+    /// Waiting for the exit to connect the stream
+    Exit(StreamHandle),
+    Open(Upstream),
+}
+
+struct Client {
+    phase: Phase,
+    /// What the client sent that is not handled yet: a partial message while negotiating, then
+    /// data for the upstream it had no room for
+    received: Vec<u8>,
+    /// Set from the credentials, with `isolate_by_auth`
+    session: Option<u64>,
+    /// Connected by then, or dropped
+    deadline: u64,
+}
+
+/// A local SOCKS5 proxy (RFC 1928), like Tor's SocksPort, so unmodified applications reach
+/// hidden services and, through exits, the rest of the internet.
+///
+/// CONNECT requests to a `.freedom` host go to that hidden service with `Node::connect`; any
+/// other host (name or IP) is opened as a stream out of an exit circuit from the pool, chosen
+/// by `CircuitIsolation` so that differently authenticated clients don't share one. Only
+/// CONNECT is supported, and only the "no authentication" and username/password methods.
+///
+/// Sans-IO: the host owns the listening socket and the accepted ones, reports them with
+/// `accept`, `on_data` and `on_closed`, and carries out `take_outgoing`. It passes every
+/// `NodeEvent` to `on_node_event` (after the pool saw circuit events, so a client waiting for
+/// a circuit finds the one that just opened), still fetches descriptors for `FetchDescriptor`,
+/// and calls `expire` with `Node::expire`.
+pub struct SocksServer {
+    config: SocksServerConfig,
+    isolation: CircuitIsolation,
+    clients: HashMap<SocksClient, Client>,
+    connects: HashMap<ConnectId, SocksClient>,
+    streams: HashMap<StreamHandle, SocksClient>,
+    /// Turns credentials into isolation sessions without keeping them
+    sessions: RandomState,
+    commands: Vec<SocksCommand>,
+    next_client: u64,
+}
+
+impl SocksServer {
+    pub fn new(config: SocksServerConfig) -> Self {
+        Self {
+            config,
+            isolation: CircuitIsolation::new(),
+            clients: HashMap::new(),
+            connects: HashMap::new(),
+            streams: HashMap::new(),
+            sessions: RandomState::new(),
+            commands: Vec::new(),
+            next_client: 0,
+        }
+    }
+
+    /// Numbers a connection the host accepted on the SOCKS port.
+    pub fn accept(&mut self, now: u64) -> SocksClient {
+        let client = SocksClient(self.next_client);
+        self.next_client += 1;
+        self.clients.insert(client, Client {
+            phase: Phase::Greeting,
+            received: Vec::new(),
+            session: None,
+            deadline: now + CONNECT_TIMEOUT_SECS,
+        });
+        client
+    }
+
+    /// Handles bytes from a client: its side of the negotiation, then data for its stream.
+    pub fn on_data(&mut self, client: SocksClient, data: &[u8], node: &mut Node, pool: &mut CircuitPool, now: u64) {
+        let Some(state) = self.clients.get_mut(&client) else {
+            return;
+        };
+        state.received.extend_from_slice(data);
+        if let Err(e) = self.negotiate(client, node, pool, now) {
+            log::debug!("SOCKS client {client:?} broke the protocol: {e}");
+            self.drop_client(client, node);
+        }
+    }
+
+    /// Forgets a client whose socket closed, closing its stream.
+    pub fn on_closed(&mut self, client: SocksClient, node: &mut Node) {
+        if let Some(state) = self.clients.remove(&client) {
+            self.release(&state, node);
+        }
+    }
+
+    /// Follows the node's events for the clients' streams. Returns true if the event was only
+    /// about them.
+    pub fn on_node_event(&mut self, event: &NodeEvent, node: &mut Node, pool: &mut CircuitPool) -> bool {
+        let ours = match event {
+            NodeEvent::Connected { connect, stream } => match self.connects.remove(connect) {
+                Some(client) if self.clients.contains_key(&client) => {
+                    self.streams.insert(*stream, client);
+                    self.connected(client, Upstream::Service(*stream), node);
+                    true
+                }
+                // The client left while we were connecting
+                Some(_) => {
+                    node.close(*stream);
+                    true
+                }
+                None => false,
+            },
+            NodeEvent::ConnectFailed(connect, failure) => match self.connects.remove(connect) {
+                Some(client) => {
+                    let reply = match failure {
+                        ConnectFailure::Timeout => SocksReply::TtlExpired,
+                        ConnectFailure::Ended(reason) => end_reply(*reason),
+                        _ => SocksReply::HostUnreachable,
+                    };
+                    if self.clients.contains_key(&client) {
+                        self.refuse(client, reply);
+                    }
+                    true
+                }
+                None => false,
+            },
+            NodeEvent::Readable(stream) => match self.streams.get(stream) {
+                Some(&client) => self.relay(client, node),
+                None => false,
+            },
+            NodeEvent::Closed(stream) => match self.streams.remove(stream) {
+                Some(client) => {
+                    self.relay(client, node);
+                    node.close(*stream);
+                    self.close(client);
+                    true
+                }
+                None => false,
+            },
+            NodeEvent::Circuit(CircuitEvent::Stream { circuit, hop, event }) => self.on_stream_event(*circuit, *hop, event, node),
+            NodeEvent::Circuit(CircuitEvent::Opened(_)) => {
+                self.open_waiting(node, pool);
+                false
+            }
+            NodeEvent::Circuit(event @ CircuitEvent::Failed(circuit, _)) => {
+                self.isolation.on_event(event);
+                self.lose_streams(|stream| stream.circuit == *circuit);
+                false
+            }
+            NodeEvent::Circuit(CircuitEvent::Truncated { streams, .. }) => {
+                self.lose_streams(|stream| streams.contains(stream));
+                false
+            }
+            _ => false,
+        };
+        self.flush_all(node);
+        ours
+    }
+
+    /// Drops clients not connected within `CONNECT_TIMEOUT_SECS` of being accepted.
+    pub fn expire(&mut self, now: u64, node: &mut Node) {
+        let expired: Vec<SocksClient> = self.clients
+            .iter()
+            .filter(|(_, c)| !matches!(c.phase, Phase::Open(_)) && now >= c.deadline)
+            .map(|(client, _)| *client)
+            .collect();
+        for client in expired {
+            let requested = !matches!(self.clients[&client].phase, Phase::Greeting | Phase::Password | Phase::Request);
+            if requested {
+                self.reply(client, SocksReply::TtlExpired);
+            }
+            self.drop_client(client, node);
+        }
+    }
+
+    /// Clients connected, negotiating or waiting for their stream
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// What to do on the clients' sockets since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<SocksCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    /// Runs the negotiation as far as what the client sent allows.
+    fn negotiate(&mut self, client: SocksClient, node: &mut Node, pool: &mut CircuitPool, now: u64) -> Result<(), CodecError> {
+        loop {
+            // Refused by what we just handled
+            let Some(state) = self.clients.get_mut(&client) else {
+                return Ok(());
+            };
+            let mut reader = Reader::new(&state.received);
+            let step = match state.phase {
+                Phase::Greeting => read_greeting(&mut reader).map(Step::Methods),
+                Phase::Password => read_password(&mut reader).map(Step::Credentials),
+                Phase::Request => read_request(&mut reader).map(|(command, target)| Step::Request(command, target)),
+                Phase::Open(_) => {
+                    self.flush(client, node);
+                    return Ok(());
+                }
+                // Data sent early waits for the stream
+                Phase::Circuit { .. } | Phase::Service | Phase::Exit(_) => {
+                    return Ok(());
+                }
+            };
+            let step = match step {
+                Ok(step) => step,
+                Err(CodecError::UnexpectedEof { .. }) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let consumed = reader.offset();
+            state.received.drain(..consumed);
+
+            match step {
+                Step::Methods(methods) => {
+                    // Offered credentials are what isolation goes by, so they win when we use them
+                    let method = if methods.contains(&AUTH_PASSWORD) && (self.config.isolate_by_auth || !methods.contains(&AUTH_NONE)) {
+                        AUTH_PASSWORD
+                    } else if methods.contains(&AUTH_NONE) {
+                        AUTH_NONE
+                    } else {
+                        AUTH_UNACCEPTABLE
+                    };
+                    self.commands.push(SocksCommand::Write { client, bytes: vec![SOCKS_VERSION, method] });
+                    state.phase = match method {
+                        AUTH_PASSWORD => Phase::Password,
+                        AUTH_NONE => Phase::Request,
+                        _ => {
+                            self.close(client);
+                            return Ok(());
+                        }
+                    };
+                }
+                Step::Credentials(credentials) => {
+                    if self.config.isolate_by_auth {
+                        state.session = Some(self.sessions.hash_one(credentials));
+                    }
+                    state.phase = Phase::Request;
+                    self.commands.push(SocksCommand::Write { client, bytes: vec![PASSWORD_VERSION, 0] });
+                }
+                Step::Request(CMD_CONNECT, target) => self.start(client, target, node, pool, now),
+                Step::Request(command, _) => {
+                    log::debug!("SOCKS client {client:?} asked for unsupported command {command}");
+                    self.refuse(client, SocksReply::CommandNotSupported);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Starts connecting a client that asked for `target`.
+    fn start(&mut self, client: SocksClient, target: SocksAddr, node: &mut Node, pool: &mut CircuitPool, now: u64) {
+        let (host, port) = match target {
+            SocksAddr::Ip(addr) => (addr.ip().to_string(), addr.port()),
+            SocksAddr::Domain(domain, port) => (domain, port),
+        };
+
+        if host.to_ascii_lowercase().ends_with(ADDRESS_SUFFIX) {
+            let address: OnionAddress = match host.parse() {
+                Ok(address) => address,
+                Err(e) => {
+                    log::debug!("SOCKS client {client:?} asked for invalid address {host}: {e}");
+                    return self.refuse(client, SocksReply::HostUnreachable);
+                }
+            };
+            let connect = node.connect(&address, port, now);
+            self.connects.insert(connect, client);
+            self.clients.get_mut(&client).expect("started").phase = Phase::Service;
+            return;
+        }
+        if !self.config.allow_exit {
+            return self.refuse(client, SocksReply::NotAllowed);
+        }
+
+        let target = StreamTarget::new(host, port);
+        let state = self.clients.get_mut(&client).expect("started");
+        let mut key = IsolationKey::new(CircuitPurpose::Exit);
+        if let Some(session) = state.session {
+            key = key.with_session(session);
+        }
+        if self.config.isolate_destinations {
+            key = key.with_destination(&target);
+        }
+        state.phase = Phase::Circuit { target, key };
+        self.open_exit(client, node, pool);
+    }
+
+    /// Opens the stream of a client waiting for an exit circuit, if the pool has one ready.
+    /// Returns false if it still waits.
+    fn open_exit(&mut self, client: SocksClient, node: &mut Node, pool: &mut CircuitPool) -> bool {
+        let Some(Client { phase: Phase::Circuit { target, key }, .. }) = self.clients.get(&client) else {
+            return false;
+        };
+        let Some(circuit) = self.isolation.circuit(key, pool) else {
+            return false;
+        };
+        match node.circuits_mut().open_stream(circuit, target) {
+            Ok(stream) => {
+                self.streams.insert(stream, client);
+                self.clients.get_mut(&client).expect("waiting").phase = Phase::Exit(stream);
+            }
+            Err(e) => {
+                log::debug!("Could not open a stream on circuit {}: {e}", circuit.id());
+                self.isolation.release(circuit);
+                self.refuse(client, SocksReply::GeneralFailure);
+            }
+        }
+        true
+    }
+
+    /// Gives clients waiting for a circuit the ones that became ready.
+    fn open_waiting(&mut self, node: &mut Node, pool: &mut CircuitPool) {
+        let mut waiting: Vec<SocksClient> = self.clients
+            .iter()
+            .filter(|(_, c)| matches!(c.phase, Phase::Circuit { .. }))
+            .map(|(client, _)| *client)
+            .collect();
+        // First come, first served
+        waiting.sort_by_key(|client| client.0);
+        for client in waiting {
+            self.open_exit(client, node, pool);
+        }
+    }
+
+    fn on_stream_event(&mut self, circuit: CircuitHandle, hop: usize, event: &StreamEvent, node: &mut Node) -> bool {
+        let (StreamEvent::Connected(stream) | StreamEvent::Readable(stream) | StreamEvent::Ended(stream, _)) = *event else {
+            return false;
+        };
+        let handle = StreamHandle { circuit, hop, stream };
+        let Some(&client) = self.streams.get(&handle) else {
+            return false;
+        };
+        match event {
+            StreamEvent::Connected(_) => self.connected(client, Upstream::Exit(handle), node),
+            StreamEvent::Readable(_) => {
+                self.relay(client, node);
+            }
+            StreamEvent::Ended(_, reason) => {
+                self.streams.remove(&handle);
+                if matches!(self.clients[&client].phase, Phase::Open(_)) {
+                    self.relay(client, node);
+                    self.close(client);
+                } else {
+                    self.refuse(client, end_reply(*reason));
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Tells the client its stream is up and sends what it wrote early.
+    fn connected(&mut self, client: SocksClient, upstream: Upstream, node: &mut Node) {
+        let Some(state) = self.clients.get_mut(&client) else {
+            return;
+        };
+        state.phase = Phase::Open(upstream);
+        self.reply(client, SocksReply::Succeeded);
+        self.flush(client, node);
+    }
+
+    /// Passes what the client's stream received on to it. Returns false if it has none open.
+    fn relay(&mut self, client: SocksClient, node: &mut Node) -> bool {
+        let Some(Client { phase: Phase::Open(upstream), .. }) = self.clients.get(&client) else {
+            return false;
+        };
+        let mut bytes = Vec::new();
+        let mut buf = [0u8; RELAY_DATA_SIZE * 8];
+        loop {
+            let read = match *upstream {
+                Upstream::Service(stream) => node.read(stream, &mut buf).map_err(|e| e.to_string()),
+                Upstream::Exit(stream) => node.circuits_mut().read_stream(stream, &mut buf).map_err(|e| e.to_string()),
+            };
+            match read {
+                Ok(0) => break,
+                Ok(count) => bytes.extend_from_slice(&buf[..count]),
+                Err(e) => {
+                    log::debug!("Could not read the stream of SOCKS client {client:?}: {e}");
+                    break;
+                }
+            }
+        }
+        if !bytes.is_empty() {
+            self.commands.push(SocksCommand::Write { client, bytes });
+        }
+        true
+    }
+
+    /// Writes what the client sent to its stream, as much as the stream takes now.
+    fn flush(&mut self, client: SocksClient, node: &mut Node) {
+        let Some(Client { phase: Phase::Open(upstream), received, .. }) = self.clients.get_mut(&client) else {
+            return;
+        };
+        if received.is_empty() {
+            return;
+        }
+        let written = match *upstream {
+            Upstream::Service(stream) => node.write(stream, received).map_err(|e| e.to_string()),
+            Upstream::Exit(stream) => node.circuits_mut().write_stream(stream, received).map_err(|e| e.to_string()),
+        };
+        match written {
+            Ok(count) => {
+                received.drain(..count);
+            }
+            Err(e) => log::debug!("Could not write the stream of SOCKS client {client:?}: {e}"),
+        }
+    }
+
+    /// Retries the writes streams had no room for; there is no event for when they do.
+    fn flush_all(&mut self, node: &mut Node) {
+        let clients: Vec<SocksClient> = self.clients
+            .iter()
+            .filter(|(_, c)| matches!(c.phase, Phase::Open(_)) && !c.received.is_empty())
+            .map(|(client, _)| *client)
+            .collect();
+        for client in clients {
+            self.flush(client, node);
+        }
+    }
+
+    /// Ends the clients whose streams went down with their circuit.
+    fn lose_streams(&mut self, lost: impl Fn(&StreamHandle) -> bool) {
+        let streams: Vec<StreamHandle> = self.streams.keys().filter(|stream| lost(stream)).copied().collect();
+        for stream in streams {
+            let client = self.streams.remove(&stream).expect("listed");
+            if matches!(self.clients[&client].phase, Phase::Open(_)) {
+                self.close(client);
+            } else {
+                self.refuse(client, SocksReply::GeneralFailure);
+            }
+        }
+    }
+
+    fn reply(&mut self, client: SocksClient, reply: SocksReply) {
+        let bound = SocksAddr::Ip(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+        self.commands.push(SocksCommand::Write { client, bytes: socks::reply(reply, &bound) });
+    }
+
+    /// Answers a request with a failure and closes the client.
+    fn refuse(&mut self, client: SocksClient, reply: SocksReply) {
+        self.reply(client, reply);
+        self.close(client);
+    }
+
+    /// Closes a client whose stream, if any, is already gone.
+    fn close(&mut self, client: SocksClient) {
+        self.clients.remove(&client);
+        self.commands.push(SocksCommand::Close(client));
+    }
+
+    /// Closes a client and its stream.
+    fn drop_client(&mut self, client: SocksClient, node: &mut Node) {
+        if let Some(state) = self.clients.remove(&client) {
+            self.release(&state, node);
+            self.commands.push(SocksCommand::Close(client));
+        }
+    }
+
+    /// Lets go of what a forgotten client was using.
+    fn release(&mut self, state: &Client, node: &mut Node) {
+        match state.phase {
+            // The connection goes on regardless; its stream is closed once it completes
+            Phase::Service => {}
+            Phase::Open(Upstream::Service(stream)) => {
+                self.streams.remove(&stream);
+                node.close(stream);
+            }
+            Phase::Exit(stream) | Phase::Open(Upstream::Exit(stream)) => {
+                self.streams.remove(&stream);
+                if let Err(e) = node.circuits_mut().close_stream(stream) {
+                    log::debug!("Stream closed after its circuit: {e}");
+                }
+            }
+            Phase::Greeting | Phase::Password | Phase::Request | Phase::Circuit { .. } => {}
+        }
+    }
+}
+
+/// A complete client message
+enum Step {
+    Methods(Vec<u8>),
+    Credentials((Vec<u8>, Vec<u8>)),
+    Request(u8, SocksAddr),
+}
+
+/// Format: [Version 5 (1)] [Count (1)] [Methods (Count)]
+fn read_greeting(reader: &mut Reader<'_>) -> Result<Vec<u8>, CodecError> {
+    if reader.u8()? != SOCKS_VERSION {
+        return Err(CodecError::InvalidField("version"));
+    }
+    let count = reader.u8()? as usize;
+    Ok(reader.take(count)?.to_vec())
+}
+
+/// Format: [Version 1 (1)] [Len (1)] [Username (Len)] [Len (1)] [Password (Len)]
+fn read_password(reader: &mut Reader<'_>) -> Result<(Vec<u8>, Vec<u8>), CodecError> {
+    if reader.u8()? != PASSWORD_VERSION {
+        return Err(CodecError::InvalidField("password version"));
+    }
+    let len = reader.u8()? as usize;
+    let username = reader.take(len)?.to_vec();
+    let len = reader.u8()? as usize;
+    Ok((username, reader.take(len)?.to_vec()))
+}
+
+/// Format: [Version 5 (1)] [Command (1)] [0 (1)] [Address]
+fn read_request(reader: &mut Reader<'_>) -> Result<(u8, SocksAddr), CodecError> {
+    if reader.u8()? != SOCKS_VERSION {
+        return Err(CodecError::InvalidField("version"));
+    }
+    let command = reader.u8()?;
+    let _reserved = reader.u8()?;
+    Ok((command, SocksAddr::read(reader)?))
+}
+
+fn end_reply(reason: EndReason) -> SocksReply {
+    match reason {
+        EndReason::Refused => SocksReply::ConnectionRefused,
+        EndReason::ExitPolicy => SocksReply::NotAllowed,
+        EndReason::Done | EndReason::Misc => SocksReply::HostUnreachable,
+    }
+}
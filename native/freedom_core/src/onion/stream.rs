@@ -0,0 +1,462 @@
+use std::collections::{ BTreeMap, VecDeque };
+
+use super::cell::{ RelayCell, RelayCommand, RELAY_DATA_SIZE };
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Most streams one circuit carries at once
+pub const MAX_STREAMS_PER_CIRCUIT: usize = 256;
+/// Most bytes a stream queues for sending before `write` stops accepting more
+pub const MAX_STREAM_BUFFER: usize = 256 * 1024;
+
+/// DATA cells an end may send on a circuit before the other end acknowledges them
+pub const CIRCUIT_WINDOW: u16 = 1000;
+/// DATA cells acknowledged by one circuit-level SENDME
+pub const CIRCUIT_SENDME_INCREMENT: u16 = 100;
+/// DATA cells an end may send on one stream before the other end acknowledges them
+pub const STREAM_WINDOW: u16 = 500;
+/// DATA cells acknowledged by one stream-level SENDME
+pub const STREAM_SENDME_INCREMENT: u16 = 50;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum StreamError {
+    #[error("Unknown stream {0}")]
+    UnknownStream(u16),
+    #[error("Stream {0} already exists")]
+    DuplicateStream(u16),
+    #[error("Too many streams on this circuit (max {MAX_STREAMS_PER_CIRCUIT})")]
+    TooManyStreams,
+    #[error("Stream {0} is not open")]
+    NotOpen(u16),
+    #[error("Flow control violated on stream {0} (0 is the circuit)")]
+    FlowControl(u16),
+    #[error("Malformed stream cell: {0}")]
+    Malformed(#[from] CodecError),
+}
+
+/// Where the exit should connect a stream.
+/// Format: [HostLen (1)] [Host (UTF-8)] [Port (2)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl StreamTarget {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self { host: host.into(), port }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CodecError> {
+        if self.host.len() > u8::MAX as usize {
+            return Err(CodecError::InvalidField("stream host"));
+        }
+        let mut out = Vec::with_capacity(3 + self.host.len());
+        out.push(self.host.len() as u8);
+        out.extend_from_slice(self.host.as_bytes());
+        out.extend_from_slice(&self.port.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let len = reader.u8()? as usize;
+        let host = std::str::from_utf8(reader.take(len)?).map_err(|_| CodecError::InvalidField("stream host"))?;
+        let port = reader.u16()?;
+        reader.finish()?;
+        Ok(Self { host: host.to_owned(), port })
+    }
+}
+
+/// Why a stream ended, carried in END: [Reason (1)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EndReason {
+    /// Closed normally by either side
+    Done = 1,
+    /// The exit could not (or would not) connect to the target
+    Refused = 2,
+    Misc = 3,
+    /// The exit's policy does not allow the target
+    ExitPolicy = 4,
+}
+
+impl From<u8> for EndReason {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => EndReason::Done,
+            2 => EndReason::Refused,
+            4 => EndReason::ExitPolicy,
+            _ => EndReason::Misc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// BEGIN sent, waiting for CONNECTED
+    Connecting,
+    Open,
+    /// END sent or received; buffered data can still be read
+    Closed(EndReason),
+}
+
+/// What a stream cell did, for the host to act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    /// (Exit side) The client asks for a connection; answer with `connected` or `end`
+    Requested {
+        stream: u16,
+        target: StreamTarget,
+    },
+    /// (Relay side) The client asks for our directory, e.g. the consensus; answer with
+    /// `connected` or `end`
+    DirectoryRequested(u16),
+    /// (Client side) The exit connected the stream
+    Connected(u16),
+    /// Data is waiting to be read
+    Readable(u16),
+    Ended(u16, EndReason),
+}
+
+struct Stream {
+    state: StreamState,
+    inbound: VecDeque<u8>,
+    outbound: VecDeque<u8>,
+    /// DATA cells we may still send before a SENDME from the other end
+    package_window: u16,
+    /// DATA cells the other end may still send before we owe it a SENDME
+    deliver_window: u16,
+    /// END to send once `outbound` is drained
+    ending: Option<EndReason>,
+}
+
+impl Stream {
+    fn new(state: StreamState) -> Self {
+        Self {
+            state,
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+            package_window: STREAM_WINDOW,
+            deliver_window: STREAM_WINDOW,
+            ending: None,
+        }
+    }
+
+    /// Nothing left to read or send
+    fn finished(&self) -> bool {
+        matches!(self.state, StreamState::Closed(_)) && self.ending.is_none() && self.inbound.is_empty()
+    }
+}
+
+/// The streams multiplexed over one circuit, at either end: ids, state, buffers, and flow control.
+///
+/// DATA is flow-controlled with Tor-style windows at two levels: each stream, and the circuit
+/// as a whole. A sender stops when either window is used up and resumes on the SENDME the
+/// receiver returns once it has taken delivery, so a slow reader holds back its sender instead
+/// of filling buffers along the path. When windows open, queued data is sent one cell per
+/// stream in turn, so a bulk transfer can't starve the other streams. Cells to send (DATA,
+/// SENDME, and the control cells of `begin`, `connected`, `end`) collect in an outbox the
+/// caller drains with `take_outgoing` and sends on the circuit.
+pub struct StreamSet {
+    streams: BTreeMap<u16, Stream>,
+    next_id: u16,
+    outgoing: Vec<RelayCell>,
+    package_window: u16,
+    deliver_window: u16,
+    /// Where the next round of sending starts
+    next_turn: u16,
+}
+
+impl Default for StreamSet {
+    fn default() -> Self {
+        Self {
+            streams: BTreeMap::new(),
+            next_id: 1,
+            outgoing: Vec::new(),
+            package_window: CIRCUIT_WINDOW,
+            deliver_window: CIRCUIT_WINDOW,
+            next_turn: 0,
+        }
+    }
+}
+
+impl StreamSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// (Client side) Opens a stream to `target`, queueing BEGIN. Returns the stream id.
+    pub fn begin(&mut self, target: &StreamTarget) -> Result<u16, StreamError> {
+        let begin = target.to_bytes()?;
+        self.open(RelayCell::new(RelayCommand::Begin, 0, begin))
+    }
+
+    /// (Client side) Opens a stream to the directory of the hop at this end, queueing BEGIN_DIR.
+    /// Returns the stream id.
+    pub fn begin_dir(&mut self) -> Result<u16, StreamError> {
+        self.open(RelayCell::new(RelayCommand::BeginDir, 0, Vec::new()))
+    }
+
+    /// Queues `begin` on a new stream, connecting until the other end answers.
+    fn open(&mut self, mut begin: RelayCell) -> Result<u16, StreamError> {
+        if self.streams.len() >= MAX_STREAMS_PER_CIRCUIT {
+            return Err(StreamError::TooManyStreams);
+        }
+
+        // Stream 0 is reserved for circuit-level relay commands
+        let mut id = self.next_id;
+        while id == 0 || self.streams.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        self.next_id = id.wrapping_add(1);
+
+        self.streams.insert(id, Stream::new(StreamState::Connecting));
+        begin.stream_id = id;
+        self.outgoing.push(begin);
+        Ok(id)
+    }
+
+    /// (Exit side) Accepts a stream the client asked for, queueing CONNECTED.
+    pub fn connected(&mut self, id: u16) -> Result<(), StreamError> {
+        let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+        if stream.state != StreamState::Connecting {
+            return Err(StreamError::NotOpen(id));
+        }
+        stream.state = StreamState::Open;
+        self.outgoing.push(RelayCell::new(RelayCommand::Connected, id, Vec::new()));
+        Ok(())
+    }
+
+    /// Handles a stream cell (or a circuit-level SENDME) from the other end of the circuit.
+    pub fn on_cell(&mut self, cell: RelayCell) -> Result<Option<StreamEvent>, StreamError> {
+        let id = cell.stream_id;
+        match cell.command {
+            RelayCommand::Sendme if id == 0 => {
+                self.package_window = acknowledge(self.package_window, CIRCUIT_SENDME_INCREMENT, CIRCUIT_WINDOW)
+                    .ok_or(StreamError::FlowControl(0))?;
+                self.pump();
+                Ok(None)
+            }
+            RelayCommand::Sendme => {
+                let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+                stream.package_window = acknowledge(stream.package_window, STREAM_SENDME_INCREMENT, STREAM_WINDOW)
+                    .ok_or(StreamError::FlowControl(id))?;
+                self.pump();
+                Ok(None)
+            }
+            RelayCommand::Begin | RelayCommand::BeginDir => {
+                let target = match cell.command {
+                    RelayCommand::Begin => Some(StreamTarget::from_bytes(&cell.data)?),
+                    _ => None,
+                };
+                if id == 0 || self.streams.contains_key(&id) {
+                    return Err(StreamError::DuplicateStream(id));
+                }
+                if self.streams.len() >= MAX_STREAMS_PER_CIRCUIT {
+                    return Err(StreamError::TooManyStreams);
+                }
+                self.streams.insert(id, Stream::new(StreamState::Connecting));
+                Ok(Some(match target {
+                    Some(target) => StreamEvent::Requested { stream: id, target },
+                    None => StreamEvent::DirectoryRequested(id),
+                }))
+            }
+            RelayCommand::Connected => {
+                let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+                if stream.state != StreamState::Connecting {
+                    return Err(StreamError::NotOpen(id));
+                }
+                stream.state = StreamState::Open;
+                Ok(Some(StreamEvent::Connected(id)))
+            }
+            RelayCommand::Data => {
+                let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+                if stream.state == StreamState::Connecting {
+                    return Err(StreamError::NotOpen(id));
+                }
+                if self.deliver_window == 0 {
+                    return Err(StreamError::FlowControl(0));
+                }
+
+                // The circuit window is replenished on receipt; stream windows as the reader catches up
+                self.deliver_window -= 1;
+                if self.deliver_window <= CIRCUIT_WINDOW - CIRCUIT_SENDME_INCREMENT {
+                    self.deliver_window += CIRCUIT_SENDME_INCREMENT;
+                    self.outgoing.push(RelayCell::new(RelayCommand::Sendme, 0, Vec::new()));
+                }
+
+                // Data crossing our END is dropped
+                if stream.state != StreamState::Open {
+                    return Ok(None);
+                }
+                if stream.deliver_window == 0 {
+                    return Err(StreamError::FlowControl(id));
+                }
+                stream.deliver_window -= 1;
+                stream.inbound.extend(&cell.data);
+                Ok(Some(StreamEvent::Readable(id)))
+            }
+            RelayCommand::End => {
+                let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+                let reason = EndReason::from(cell.data.first().copied().unwrap_or(EndReason::Misc as u8));
+                // The other end is gone: nothing more to send it
+                stream.state = StreamState::Closed(reason);
+                stream.outbound.clear();
+                stream.ending = None;
+                if stream.finished() {
+                    self.streams.remove(&id);
+                }
+                Ok(Some(StreamEvent::Ended(id, reason)))
+            }
+            RelayCommand::Extend |
+            RelayCommand::Extended |
+            RelayCommand::Truncate |
+            RelayCommand::Truncated |
+            RelayCommand::Drop |
+            RelayCommand::EstablishIntro |
+            RelayCommand::EstablishRendezvous |
+            RelayCommand::Introduce1 |
+            RelayCommand::Introduce2 |
+            RelayCommand::Rendezvous1 |
+            RelayCommand::Rendezvous2 |
+            RelayCommand::IntroEstablished |
+            RelayCommand::RendezvousEstablished |
+            RelayCommand::IntroduceAck |
+            RelayCommand::PaddingNegotiate |
+            RelayCommand::PaddingNegotiated => {
+                Err(CodecError::InvalidField("stream command").into())
+            }
+        }
+    }
+
+    /// Queues data on an open stream, sending what the windows allow right away. Returns how
+    /// many bytes were accepted: fewer than `data.len()` once `MAX_STREAM_BUFFER` bytes are
+    /// waiting for the other end to catch up.
+    pub fn write(&mut self, id: u16, data: &[u8]) -> Result<usize, StreamError> {
+        let stream = self.streams.get_mut(&id).ok_or(StreamError::UnknownStream(id))?;
+        if stream.state != StreamState::Open {
+            return Err(StreamError::NotOpen(id));
+        }
+
+        let accepted = data.len().min(MAX_STREAM_BUFFER - stream.outbound.len());
+        stream.outbound.extend(&data[..accepted]);
+        self.pump();
+        Ok(accepted)
+    }
+
+    /// Moves buffered data into `buf`. Returns 0 when nothing is buffered: `state` tells whether
+    /// more may come (it is None once the stream ended and was drained).
+    pub fn read(&mut self, id: u16, buf: &mut [u8]) -> usize {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return 0;
+        };
+        let count = buf.len().min(stream.inbound.len());
+        for (slot, byte) in buf.iter_mut().zip(stream.inbound.drain(..count)) {
+            *slot = byte;
+        }
+
+        // Let the sender go on once little is left unread
+        let caught_up = stream.inbound.len() <= (STREAM_SENDME_INCREMENT as usize) * RELAY_DATA_SIZE;
+        while stream.state == StreamState::Open && caught_up && stream.deliver_window <= STREAM_WINDOW - STREAM_SENDME_INCREMENT {
+            stream.deliver_window += STREAM_SENDME_INCREMENT;
+            self.outgoing.push(RelayCell::new(RelayCommand::Sendme, id, Vec::new()));
+        }
+
+        if stream.finished() {
+            self.streams.remove(&id);
+        }
+        count
+    }
+
+    /// Closes a stream from this side; END is sent after the data already queued. Returns
+    /// false if the stream was unknown or already closed.
+    pub fn end(&mut self, id: u16, reason: EndReason) -> bool {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return false;
+        };
+        if matches!(stream.state, StreamState::Closed(_)) {
+            return false;
+        }
+
+        stream.state = StreamState::Closed(reason);
+        stream.ending = Some(reason);
+        self.pump();
+        true
+    }
+
+    /// Drops every stream (their circuit no longer reaches the other end), returning their ids.
+    /// Flow control starts over, as on a new circuit.
+    pub fn close_all(&mut self) -> Vec<u16> {
+        std::mem::take(self).streams.into_keys().collect()
+    }
+
+    /// Cells to send on the circuit since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<RelayCell> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// State of a stream, or None once it is closed and drained
+    pub fn state(&self, id: u16) -> Option<StreamState> {
+        self.streams.get(&id).map(|s| s.state)
+    }
+
+    /// Bytes waiting to be read on a stream
+    pub fn buffered(&self, id: u16) -> usize {
+        self.streams.get(&id).map_or(0, |s| s.inbound.len())
+    }
+
+    /// Bytes of a stream waiting for a window to open
+    pub fn unsent(&self, id: u16) -> usize {
+        self.streams.get(&id).map_or(0, |s| s.outbound.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// Sends queued data while windows allow, one cell per stream per round, then the END of
+    /// streams closed with nothing left to send.
+    fn pump(&mut self) {
+        loop {
+            let mut sent = false;
+            let order: Vec<u16> = self.streams
+                .range(self.next_turn..)
+                .chain(self.streams.range(..self.next_turn))
+                .map(|(id, _)| *id)
+                .collect();
+
+            for id in order {
+                let stream = self.streams.get_mut(&id).expect("listed above");
+                if self.package_window > 0 && stream.package_window > 0 && !stream.outbound.is_empty() {
+                    let len = stream.outbound.len().min(RELAY_DATA_SIZE);
+                    let data: Vec<u8> = stream.outbound.drain(..len).collect();
+                    self.outgoing.push(RelayCell::new(RelayCommand::Data, id, data));
+                    stream.package_window -= 1;
+                    self.package_window -= 1;
+                    self.next_turn = id.wrapping_add(1);
+                    sent = true;
+                }
+
+                if stream.outbound.is_empty() && let Some(reason) = stream.ending.take() {
+                    self.outgoing.push(RelayCell::new(RelayCommand::End, id, vec![reason as u8]));
+                    if stream.finished() {
+                        self.streams.remove(&id);
+                    }
+                }
+            }
+
+            if !sent {
+                return;
+            }
+        }
+    }
+}
+
+/// Grows a package window by a SENDME's increment; None if the SENDME acknowledges cells never sent.
+fn acknowledge(window: u16, increment: u16, max: u16) -> Option<u16> {
+    window.checked_add(increment).filter(|w| *w <= max)
+}
@@ -0,0 +1,1908 @@
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ ErrorKind, Read, Write };
+use std::net::SocketAddr;
+
+use rand::rngs::StdRng;
+use rand::{ Rng, SeedableRng };
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+use crate::crypto::helper::CryptoError;
+use crate::crypto::identity::NodeIdentity;
+use crate::crypto::session::SESSION_OVERHEAD;
+use crate::dht::messages::StoreRequest;
+use crate::dht::node_id::NodeId;
+use crate::dht::record::Record;
+use crate::dht::validate::{ ValidationError, ValidatorRegistry };
+use crate::onion::address::{ AddressError, OnionAddress };
+use crate::onion::bandwidth::{ BandwidthConfig, BandwidthError, BandwidthLimiter, MAX_QUEUED_CELLS };
+use crate::onion::cell::{ Cell, CellCommand, DestroyReason, ExtendRequest, RelayCell, RelayCommand, CELL_PAYLOAD_SIZE, CELL_SIZE, RELAY_DATA_SIZE };
+use crate::onion::circuit::{ CircuitError, CircuitEvent, CircuitFailure, CircuitManager, CircuitState, Hop, StreamHandle };
+use crate::onion::e2e::{ E2eError, FreedomStream, MAX_RECORD_SIZE };
+use crate::onion::congestion::{ CongestionTracker, PROBE_TIMEOUT_MS };
+use crate::onion::exit::{ AddressPattern, ExitPolicy, ExitRule };
+use crate::onion::hs_descriptor::{ self, HsDescriptor, HsDescriptorError, HS_PERIOD_SECS, MAX_HS_INTRO_POINTS };
+use crate::onion::intro::{ EstablishIntro, Introduce, IntroduceStatus, IntroPointInfo, IntroPoints };
+use crate::onion::isolation::{ CircuitIsolation, IsolationKey };
+use crate::onion::layer::{ Direction, DirectionKeys, HopCrypto };
+use crate::onion::multipath::{ MultipathError, MultipathStream, MULTIPATH_CHUNK_SIZE, MULTIPATH_WINDOW };
+use crate::onion::node::{ ConnectFailure, Node, NodeError, NodeEvent, CONNECT_TIMEOUT_SECS };
+use crate::onion::ntor;
+use crate::onion::padding::{ Distribution, PaddingMachine, PaddingNegotiate, PaddingSpec };
+use crate::onion::timeout::{ BuildTimeEstimator, INITIAL_BUILD_TIMEOUT_MS, MAX_BUILD_SAMPLES, MIN_BUILD_SAMPLES };
+use crate::onion::pool::{ CircuitPool, CircuitPurpose, PoolConfig };
+use crate::onion::path::{ DiversityRules, PathError, PathRequest, PathSelector };
+use crate::onion::relay::{ CircuitLink, RelayAction, RelayCircuits };
+use crate::onion::rendezvous::Introduction;
+use crate::onion::scheduler::{ CellScheduler, SchedulerConfig, SchedulerError };
+use crate::onion::socks::{ SocksCommand, SocksServer, SocksServerConfig };
+use crate::onion::stream::{
+    EndReason,
+    StreamError,
+    StreamEvent,
+    StreamSet,
+    StreamState,
+    StreamTarget,
+    CIRCUIT_SENDME_INCREMENT,
+    CIRCUIT_WINDOW,
+    MAX_STREAM_BUFFER,
+    STREAM_SENDME_INCREMENT,
+    STREAM_WINDOW,
+};
+use crate::protocol::descriptor::{ Capabilities, DescriptorError, NodeDescriptor };
+use crate::transport::socks::SocksReply;
+
+fn client_addr() -> SocketAddr {
+    "10.0.0.1:5000".parse().unwrap()
+}
+
+/// Relays listening on 10.0.1.{n}:5000 that open streams under the default exit policy, with the
+/// hops a client would learn from their descriptors
+fn relays(count: u8) -> (HashMap<SocketAddr, RelayCircuits>, Vec<Hop>) {
+    let mut relays = HashMap::new();
+    let mut hops = Vec::new();
+    for n in 1..=count {
+        let identity = NodeIdentity::generate();
+        let addr: SocketAddr = format!("10.0.1.{n}:5000").parse().unwrap();
+        let descriptor = NodeDescriptor::new_signed(&identity, vec![addr], Capabilities::RELAY, 0, 1_000, 3_600).unwrap();
+        hops.push(Hop::from_descriptor(&descriptor).unwrap());
+        relays.insert(addr, RelayCircuits::new(&identity).with_exit_policy(ExitPolicy::default()));
+    }
+    (relays, hops)
+}
+
+type Delivered = Vec<(SocketAddr, CircuitLink, RelayCell)>;
+
+/// Delivers cells (the client's, then whatever they cause) until the network is quiet, returning
+/// the client's circuit events and what relays delivered (with the relay's address)
+fn run(manager: &mut CircuitManager, relays: &mut HashMap<SocketAddr, RelayCircuits>) -> (Vec<CircuitEvent>, Delivered) {
+    run_from(manager, relays, Vec::new())
+}
+
+/// Same as `run`, starting with cells in flight as (from, to, cell)
+fn run_from(
+    manager: &mut CircuitManager,
+    relays: &mut HashMap<SocketAddr, RelayCircuits>,
+    in_flight: Vec<(SocketAddr, SocketAddr, Cell)>
+) -> (Vec<CircuitEvent>, Delivered) {
+    let mut queue = VecDeque::from(in_flight);
+    let mut events = Vec::new();
+    let mut delivered = Vec::new();
+    loop {
+        queue.extend(manager.take_outgoing().into_iter().map(|(to, cell)| (client_addr(), to, cell)));
+        let Some((from, to, cell)) = queue.pop_front() else {
+            return (events, delivered);
+        };
+
+        // Every cell has the same size on the wire
+        let packet = cell.to_packet(0);
+        assert_eq!(packet.payload.len(), CELL_SIZE);
+        let cell = Cell::from_packet(&packet).unwrap();
+
+        if to == client_addr() {
+            events.extend(manager.on_cell(from, cell).unwrap());
+            continue;
+        }
+        for action in relays.get_mut(&to).unwrap().on_cell(from, cell).unwrap() {
+            match action {
+                RelayAction::Send(next, cell) => queue.push_back((to, next, cell)),
+                RelayAction::Deliver(link, cell) => delivered.push((to, link, cell)),
+            }
+        }
+    }
+}
+
+/// Integration test: A three-hop circuit is built hop by hop, then carries fixed-size relay cells both ways
+#[test]
+fn test_circuit_telescoping_build() {
+    let (mut relays, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+
+    let handle = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    assert_eq!(manager.state(handle), Some(CircuitState::Building { hops: 0 }));
+
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![
+        CircuitEvent::Extended { circuit: handle, hops: 1 },
+        CircuitEvent::Extended { circuit: handle, hops: 2 },
+        CircuitEvent::Opened(handle)
+    ]);
+    assert_eq!(manager.state(handle), Some(CircuitState::Open));
+    assert!(manager.expire(10_000).is_empty());
+    assert!(relays.values().all(|r| r.len() == 1));
+
+    // Relay cells are recognized by the hop they are addressed to, and only by it
+    for hop in [2, 0] {
+        let data = RelayCell::new(RelayCommand::Data, 9, format!("to hop {hop}").into_bytes());
+        manager.send(handle, hop, data.clone()).unwrap();
+        let (_, delivered) = run(&mut manager, &mut relays);
+        assert_eq!(delivered.len(), 1);
+        let (relay, link, cell) = &delivered[0];
+        assert_eq!((*relay, cell), (hops[hop].addr, &data));
+
+        let reply = RelayCell::new(RelayCommand::Data, 9, vec![hop as u8; RELAY_DATA_SIZE]);
+        let RelayAction::Send(to, cell) = relays.get_mut(relay).unwrap().send_backward(*link, &reply).unwrap() else {
+            panic!("Expected a cell to send");
+        };
+        let (events, _) = run_from(&mut manager, &mut relays, vec![(*relay, to, cell)]);
+        assert_eq!(events, vec![CircuitEvent::Relay { circuit: handle, hop, cell: reply }]);
+    }
+    let oversized = RelayCell::new(RelayCommand::Data, 9, vec![0u8; RELAY_DATA_SIZE + 1]);
+    assert!(manager.send(handle, 0, oversized).is_err());
+    assert!(matches!(manager.send(handle, 3, RelayCell::new(RelayCommand::Data, 1, vec![])), Err(CircuitError::NoSuchHop(3))));
+
+    // A tampered cell is recognized by no hop
+    manager.send(handle, 2, RelayCell::new(RelayCommand::Data, 9, b"payload".to_vec())).unwrap();
+    let (first, mut cell) = manager.take_outgoing().pop().unwrap();
+    cell.payload[100] ^= 1;
+    let RelayAction::Send(second, cell) = relays.get_mut(&first).unwrap().on_cell(client_addr(), cell).unwrap().remove(0) else {
+        panic!("The first hop forwards what it does not recognize");
+    };
+    let RelayAction::Send(third, cell) = relays.get_mut(&second).unwrap().on_cell(first, cell).unwrap().remove(0) else {
+        panic!("The second hop forwards what it does not recognize");
+    };
+    assert!(matches!(relays.get_mut(&third).unwrap().on_cell(second, cell), Err(CircuitError::Unrecognized)));
+
+    // Closing tears the circuit down along the path
+    assert!(manager.close(handle));
+    run(&mut manager, &mut relays);
+    assert!(relays.values().all(|r| r.is_empty()));
+}
+
+/// Unit test: Build failures (wrong onion key, timeout) and invalid paths
+#[test]
+fn test_circuit_build_failures() {
+    let (mut relays, mut hops) = relays(2);
+    let mut manager = CircuitManager::new().with_build_timeout(30);
+
+    // The second relay's descriptor was swapped for another key: it refuses the handshake, and
+    // the first relay reports the circuit truncated back to it
+    hops[1].onion_key = X25519PublicKey::from(&NodeIdentity::generate().onion_secret);
+    let handle = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    let failure = CircuitFailure::Truncated { hop: 0, reason: DestroyReason::Protocol };
+    assert_eq!(events.last(), Some(&CircuitEvent::Failed(handle, failure)));
+    assert_eq!(manager.state(handle), Some(CircuitState::Failed(failure)));
+    assert!(relays.values().all(|r| r.is_empty()), "Failed circuits are torn down");
+    assert!(manager.close(handle));
+    assert!(manager.take_outgoing().is_empty());
+
+    // A first hop whose reply does not authenticate
+    let handle = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    let (first, create) = manager.take_outgoing().remove(0);
+    let Some(RelayAction::Send(_, mut created)) = relays.get_mut(&first).unwrap().on_cell(client_addr(), create).unwrap().pop() else {
+        panic!("Expected CREATED");
+    };
+    created.payload[40] ^= 1;
+    let event = manager.on_cell(first, created).unwrap();
+    assert_eq!(event, Some(CircuitEvent::Failed(handle, CircuitFailure::HandshakeFailed { hop: 0 })));
+    assert_eq!(manager.state(handle), Some(CircuitState::Failed(CircuitFailure::HandshakeFailed { hop: 0 })));
+    assert!(manager.close(handle));
+    run(&mut manager, &mut relays);
+    assert!(relays.values().all(|r| r.is_empty()));
+
+    // A first hop that never answers
+    let silent = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    manager.take_outgoing();
+    assert!(manager.expire(1_029).is_empty());
+    assert_eq!(manager.expire(1_030), vec![CircuitEvent::Failed(silent, CircuitFailure::Timeout)]);
+
+    assert!(matches!(manager.open_circuit(Vec::new(), 1_000), Err(CircuitError::EmptyPath)));
+    assert!(matches!(manager.open_circuit(vec![hops[0]; 9], 1_000), Err(CircuitError::PathTooLong(9))));
+    let stray = Cell::new(7, CellCommand::Created, &[0u8; 64]);
+    assert!(matches!(manager.on_cell(hops[0].addr, stray), Err(CircuitError::UnknownCircuit(7))));
+
+    let extend = ExtendRequest { node_id: NodeId::hash_of(b"x"), addr: hops[0].addr, handshake: [1u8; 96] };
+    assert_eq!(ExtendRequest::from_bytes(&extend.to_bytes()).unwrap(), extend);
+}
+
+/// Unit test: A relay refuses a second CREATE for a circuit it already has, which keeps its keys
+#[test]
+fn test_relay_refuses_duplicate_create() {
+    let (mut relays, hops) = relays(1);
+    let mut manager = CircuitManager::new();
+    let handle = manager.open_circuit(hops, 1_000).unwrap();
+    let (first, create) = manager.take_outgoing().remove(0);
+
+    let relay = relays.get_mut(&first).unwrap();
+    let created = relay.on_cell(client_addr(), create.clone()).unwrap();
+    let circuit_id = create.circuit_id;
+    assert!(matches!(relay.on_cell(client_addr(), create), Err(CircuitError::DuplicateCircuit(id)) if id == circuit_id));
+    assert_eq!(relay.len(), 1);
+
+    let (events, _) = run_from(&mut manager, &mut relays, sends(first, created));
+    assert_eq!(events, vec![CircuitEvent::Opened(handle)]);
+    let data = RelayCell::new(RelayCommand::Data, 1, b"still keyed".to_vec());
+    manager.send(handle, 0, data.clone()).unwrap();
+    let (_, delivered) = run(&mut manager, &mut relays);
+    assert!(matches!(&delivered[..], [(_, _, cell)] if *cell == data));
+}
+
+/// Unit test: ntor agrees on key material only with the relay holding the onion key, and CREATE reveals nothing about the client
+#[test]
+fn test_ntor_handshake() {
+    let relay = NodeIdentity::generate();
+    let relay_id = NodeId::from_identity_key(&relay.identity_keypair.verifying_key());
+    let onion_key = X25519PublicKey::from(&relay.onion_secret);
+
+    let (client, create) = ntor::ClientHandshake::start(relay_id, onion_key);
+    assert_eq!(&create[..32], relay_id.as_bytes());
+    assert_eq!(&create[32..64], onion_key.as_bytes());
+
+    let (server_keys, reply) = ntor::server_handshake(&relay_id, &relay.onion_secret, &create).unwrap();
+    assert_eq!(client.finish(&reply), Some(server_keys));
+
+    // Fresh ephemeral keys every time
+    let (_, second) = ntor::ClientHandshake::start(relay_id, onion_key);
+    assert_ne!(create[64..], second[64..]);
+    let (again, _) = ntor::server_handshake(&relay_id, &relay.onion_secret, &create).unwrap();
+    assert_ne!(again, server_keys);
+
+    // Another relay can't answer in its place, nor accept a handshake for someone else
+    let impostor = NodeIdentity::generate();
+    assert!(ntor::server_handshake(&relay_id, &impostor.onion_secret, &create).is_none());
+    let mut forged = create;
+    forged[32..64].copy_from_slice(X25519PublicKey::from(&impostor.onion_secret).as_bytes());
+    let (client, _) = ntor::ClientHandshake::start(relay_id, onion_key);
+    let (_, forged_reply) = ntor::server_handshake(&relay_id, &impostor.onion_secret, &forged).unwrap();
+    assert_eq!(client.finish(&forged_reply), None);
+
+    // A low-order client key is refused
+    let mut degenerate = create;
+    degenerate[64..].fill(0);
+    assert!(ntor::server_handshake(&relay_id, &relay.onion_secret, &degenerate).is_none());
+}
+
+/// Hands every cell `from` queued to `to`
+fn deliver_streams(from: &mut StreamSet, to: &mut StreamSet) {
+    for cell in from.take_outgoing() {
+        to.on_cell(cell).unwrap();
+    }
+}
+
+/// Build times (ms) drawn from a Pareto distribution with scale `scale` and shape 2
+fn pareto_build_times(rng: &mut StdRng, scale: f64, count: usize) -> Vec<u64> {
+    (0..count).map(|_| (scale / rng.gen_range(0.0001f64..1.0).sqrt()) as u64).collect()
+}
+
+/// Unit test: The build timeout follows observed build times and falls back to the initial timeout when most builds time out
+#[test]
+fn test_adaptive_build_timeout() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let mut estimator = BuildTimeEstimator::new();
+    assert_eq!(estimator.timeout_ms(), INITIAL_BUILD_TIMEOUT_MS);
+
+    // Too few samples to adapt
+    for ms in pareto_build_times(&mut rng, 300.0, MIN_BUILD_SAMPLES - 1) {
+        estimator.record_build(ms);
+    }
+    assert_eq!(estimator.timeout_ms(), INITIAL_BUILD_TIMEOUT_MS);
+
+    // Builds typically take 300ms+: 80% finish within ~670ms
+    for ms in pareto_build_times(&mut rng, 300.0, 500) {
+        estimator.record_build(ms);
+    }
+    let fast = estimator.timeout_ms();
+    assert!((450..1_000).contains(&fast), "timeout {fast}ms");
+    assert_eq!(estimator.timeout_secs(), fast.div_ceil(1000));
+
+    // The network slows down: old samples age out and the timeout follows
+    for ms in pareto_build_times(&mut rng, 2_000.0, MAX_BUILD_SAMPLES) {
+        estimator.record_build(ms);
+    }
+    let slow = estimator.timeout_ms();
+    assert!((3_000..7_000).contains(&slow), "timeout {slow}ms");
+
+    // Abandoned builds count as taking at least the timeout, pushing it up
+    for _ in 0..5 {
+        estimator.record_timeout();
+    }
+    assert!(estimator.timeout_ms() > slow);
+
+    // Nearly every build timing out means the estimate no longer fits: start over
+    for _ in 0..10 {
+        estimator.record_timeout();
+    }
+    assert_eq!(estimator.timeout_ms(), INITIAL_BUILD_TIMEOUT_MS);
+    assert_eq!(estimator.samples(), 0);
+
+    // The circuit manager gives new circuits the adaptive timeout and abandons slow builds
+    let (_, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+    assert_eq!(manager.build_timeout(), INITIAL_BUILD_TIMEOUT_MS / 1000);
+    for ms in pareto_build_times(&mut rng, 300.0, 200) {
+        manager.record_build_time(ms);
+    }
+    assert_eq!(manager.build_timeout(), 1);
+
+    let slow = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    assert!(manager.expire(1_000).is_empty());
+    assert_eq!(manager.expire(1_001), vec![CircuitEvent::Failed(slow, CircuitFailure::Timeout)]);
+    assert_eq!(manager.build_times().samples(), 200);
+}
+
+/// Relays in distinct subnets (10.{n}.0.1:5000), all introduction points and the first `exits` of them exits, with a path
+/// selector knowing them all
+fn relay_network(count: u8, exits: u8) -> (HashMap<SocketAddr, RelayCircuits>, PathSelector) {
+    let mut relays = HashMap::new();
+    let mut selector = PathSelector::new().with_rules(DiversityRules { node_id_prefix_bits: 0, ..Default::default() });
+    for n in 1..=count {
+        let identity = NodeIdentity::generate();
+        let addr: SocketAddr = format!("10.{n}.0.1:5000").parse().unwrap();
+        let relay = Capabilities::RELAY | Capabilities::INTRO_POINT;
+        let capabilities = if n <= exits { relay | Capabilities::EXIT } else { relay };
+        let descriptor = NodeDescriptor::new_signed(&identity, vec![addr], capabilities, 1_000, 1_000, 3_600).unwrap();
+        selector.insert(&descriptor);
+        relays.insert(addr, RelayCircuits::new(&identity));
+    }
+    (relays, selector)
+}
+
+/// Integration test: The pool keeps ready circuits per purpose, replaces the ones taken, failed or idle too long
+#[test]
+fn test_circuit_pool() {
+    let (mut relays, selector) = relay_network(6, 2);
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut manager = CircuitManager::new();
+    let config = PoolConfig { targets: vec![(CircuitPurpose::Exit, 2), (CircuitPurpose::Internal, 1)], max_idle_secs: 600 };
+    let mut pool = CircuitPool::new(config);
+
+    let pump = |pool: &mut CircuitPool, manager: &mut CircuitManager, relays: &mut HashMap<SocketAddr, RelayCircuits>, now| {
+        let (events, _) = run(manager, relays);
+        for event in &events {
+            pool.on_event(manager, event, now);
+        }
+    };
+
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_000), 3);
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_000), 0, "Builds in progress count");
+    assert_eq!((pool.building(), pool.take(CircuitPurpose::Exit)), (3, None));
+    pump(&mut pool, &mut manager, &mut relays, 1_001);
+    assert_eq!((pool.ready(CircuitPurpose::Exit), pool.ready(CircuitPurpose::Internal), pool.building()), (2, 1, 0));
+
+    // A request gets an open circuit at once, ending at an exit; the pool builds a replacement
+    let circuit = pool.take(CircuitPurpose::Exit).unwrap();
+    assert_eq!(manager.state(circuit), Some(CircuitState::Open));
+    let exit = manager.path(circuit).unwrap()[2].addr;
+    assert!(["10.1.0.1:5000", "10.2.0.1:5000"].contains(&exit.to_string().as_str()));
+    assert_eq!(pool.ready(CircuitPurpose::Exit), 1);
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_010), 1);
+
+    // A build that fails is forgotten, here and by the manager, and rebuilt
+    manager.take_outgoing();
+    let events = manager.expire(1_010 + manager.build_timeout());
+    let [CircuitEvent::Failed(lost, CircuitFailure::Timeout)] = events.as_slice() else {
+        panic!("Expected the replacement to time out, got {events:?}");
+    };
+    assert!(pool.on_event(&mut manager, &events[0], 1_070));
+    assert_eq!(manager.state(*lost), None);
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_070), 1);
+    pump(&mut pool, &mut manager, &mut relays, 1_071);
+    assert_eq!(pool.ready(CircuitPurpose::Exit), 2);
+
+    // Idle circuits are closed and replaced once they get old
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_500), 0);
+    assert_eq!(pool.maintain(&mut manager, &selector, &mut rng, 1_601), 2);
+    pump(&mut pool, &mut manager, &mut relays, 1_602);
+    assert_eq!((pool.ready(CircuitPurpose::Exit), pool.ready(CircuitPurpose::Internal)), (2, 1));
+    // The pool's 3 circuits and the one taken are all that is left at the relays
+    assert_eq!(manager.len(), 4);
+    assert_eq!(relays.values().map(|r| r.len()).sum::<usize>(), 4 * 3);
+
+    // No exits known: the exit target can't be met, the rest still is
+    let (_, internal_only) = relay_network(4, 0);
+    let mut pool = CircuitPool::new(PoolConfig::default());
+    assert_eq!(pool.maintain(&mut CircuitManager::new(), &internal_only, &mut rng, 1_000), 1);
+}
+
+/// Integration test: Streams share a circuit only with streams of the same purpose, session and destination
+#[test]
+fn test_circuit_isolation() {
+    let (mut relays, selector) = relay_network(6, 2);
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut manager = CircuitManager::new();
+    let mut pool = CircuitPool::new(PoolConfig { targets: vec![(CircuitPurpose::Exit, 3), (CircuitPurpose::Internal, 1)], ..Default::default() });
+    pool.maintain(&mut manager, &selector, &mut rng, 1_000);
+    let (events, _) = run(&mut manager, &mut relays);
+    for event in &events {
+        pool.on_event(&mut manager, event, 1_001);
+    }
+
+    let web = StreamTarget::new("example.org", 443);
+    let tab = IsolationKey::new(CircuitPurpose::Exit).with_session(1).with_destination(&web);
+    let other_tab = IsolationKey::new(CircuitPurpose::Exit).with_session(2).with_destination(&web);
+    let other_site = IsolationKey::new(CircuitPurpose::Exit).with_session(1).with_destination(&StreamTarget::new("mail.example.org", 443));
+    let mut isolation = CircuitIsolation::new();
+
+    // The same key reuses its circuit (destinations compare without case); any other key gets its own
+    let first = isolation.circuit(&tab, &mut pool).unwrap();
+    let again = IsolationKey::new(CircuitPurpose::Exit).with_session(1).with_destination(&StreamTarget::new("Example.ORG", 80));
+    assert_eq!(isolation.circuit(&again, &mut pool), Some(first));
+    let second = isolation.circuit(&other_tab, &mut pool).unwrap();
+    let third = isolation.circuit(&other_site, &mut pool).unwrap();
+    assert!(first != second && second != third && first != third);
+    assert_eq!((isolation.len(), pool.ready(CircuitPurpose::Exit)), (3, 0));
+
+    // Purposes draw from their own circuits; a key nobody has a circuit for waits for the pool
+    let internal = IsolationKey::new(CircuitPurpose::Internal);
+    assert!(isolation.circuit(&internal, &mut pool).is_some());
+    assert_eq!(isolation.circuit(&IsolationKey::new(CircuitPurpose::Exit), &mut pool), None);
+
+    // Circuits bound elsewhere refuse other keys
+    assert!(!isolation.allows(first, &other_tab));
+    assert!(!isolation.bind(first, &other_tab));
+    assert!(isolation.allows(first, &tab));
+    assert_eq!(isolation.key(second), Some(&other_tab));
+
+    // A failed circuit is forgotten, and its key gets a fresh one from the pool
+    assert!(isolation.on_event(&CircuitEvent::Failed(first, CircuitFailure::Timeout)));
+    assert!(!isolation.on_event(&CircuitEvent::Opened(second)));
+    assert_eq!(isolation.circuit_for(&tab), None);
+    pool.maintain(&mut manager, &selector, &mut rng, 1_010);
+    let (events, _) = run(&mut manager, &mut relays);
+    for event in &events {
+        pool.on_event(&mut manager, event, 1_011);
+    }
+    let replacement = isolation.circuit(&tab, &mut pool).unwrap();
+    assert!(![first, second, third].contains(&replacement));
+    assert_eq!(isolation.release(replacement), Some(tab));
+}
+
+/// A client and an exit stream layer with `count` connected streams between them
+fn connected_streams(count: usize) -> (StreamSet, StreamSet, Vec<u16>) {
+    let (mut client, mut exit) = (StreamSet::new(), StreamSet::new());
+    let ids: Vec<u16> = (0..count).map(|_| client.begin(&StreamTarget::new("example.org", 443)).unwrap()).collect();
+    deliver_streams(&mut client, &mut exit);
+    for id in &ids {
+        exit.connected(*id).unwrap();
+    }
+    deliver_streams(&mut exit, &mut client);
+    (client, exit, ids)
+}
+
+/// Unit test: Stream and circuit windows hold a sender back until SENDMEs return, and reopened windows are shared round-robin
+#[test]
+fn test_stream_flow_control() {
+    let (mut client, mut exit, ids) = connected_streams(1);
+    let bulk = ids[0];
+    let window_bytes = STREAM_WINDOW as usize * RELAY_DATA_SIZE;
+
+    // A bulk sender stops after a stream window of cells, and queues a bounded amount
+    let data = vec![7u8; 2 * MAX_STREAM_BUFFER];
+    assert_eq!(exit.write(bulk, &data).unwrap(), MAX_STREAM_BUFFER);
+    let sent = exit.take_outgoing();
+    assert_eq!(sent.len(), STREAM_WINDOW as usize);
+    assert_eq!(exit.unsent(bulk), MAX_STREAM_BUFFER - window_bytes);
+    assert_eq!(exit.write(bulk, &data).unwrap(), window_bytes);
+    assert_eq!(exit.write(bulk, &data).unwrap(), 0);
+    assert!(exit.take_outgoing().is_empty());
+
+    // Circuit SENDMEs go back on receipt; stream SENDMEs only as the reader catches up
+    for cell in sent {
+        client.on_cell(cell).unwrap();
+    }
+    let acks = client.take_outgoing();
+    assert_eq!(acks.len(), (STREAM_WINDOW / CIRCUIT_SENDME_INCREMENT) as usize);
+    assert!(acks.iter().all(|c| c.command == RelayCommand::Sendme && c.stream_id == 0));
+    for cell in acks {
+        exit.on_cell(cell).unwrap();
+    }
+    assert!(exit.take_outgoing().is_empty());
+
+    let mut buf = vec![0u8; MAX_STREAM_BUFFER];
+    assert_eq!(client.read(bulk, &mut buf[..1_000]), 1_000);
+    assert!(client.take_outgoing().is_empty(), "Still far behind");
+    assert_eq!(client.read(bulk, &mut buf), window_bytes - 1_000);
+    let acks = client.take_outgoing();
+    assert_eq!(acks.len(), (STREAM_WINDOW / STREAM_SENDME_INCREMENT) as usize);
+    assert!(acks.iter().all(|c| c.command == RelayCommand::Sendme && c.stream_id == bulk));
+    for cell in acks {
+        exit.on_cell(cell).unwrap();
+    }
+    let resumed = exit.take_outgoing();
+    assert_eq!(resumed.len(), STREAM_WINDOW as usize);
+    for cell in resumed {
+        client.on_cell(cell).unwrap();
+    }
+
+    // A sender ignoring its window, or acknowledging what was never sent, breaks the protocol
+    let flood = RelayCell::new(RelayCommand::Data, bulk, vec![1]);
+    assert_eq!(client.on_cell(flood), Err(StreamError::FlowControl(bulk)));
+    let bogus = RelayCell::new(RelayCommand::Sendme, 0, Vec::new());
+    assert_eq!(StreamSet::new().on_cell(bogus), Err(StreamError::FlowControl(0)));
+
+    // Two streams use up the circuit window; a third queues behind it along with the second
+    let (mut client, mut exit, ids) = connected_streams(3);
+    let (first, second, third) = (ids[0], ids[1], ids[2]);
+    exit.write(first, &data).unwrap();
+    exit.write(second, &data).unwrap();
+    exit.write(third, &vec![1u8; 10 * RELAY_DATA_SIZE]).unwrap();
+    let sent = exit.take_outgoing();
+    assert_eq!(sent.len(), CIRCUIT_WINDOW as usize);
+    assert!(sent.iter().all(|c| c.stream_id != third));
+    for cell in sent {
+        client.on_cell(cell).unwrap();
+    }
+    client.read(second, &mut buf);
+    let acks = client.take_outgoing();
+    let (circuit_acks, stream_acks): (Vec<RelayCell>, Vec<RelayCell>) = acks.into_iter().partition(|c| c.stream_id == 0);
+    for cell in stream_acks {
+        exit.on_cell(cell).unwrap();
+    }
+    assert!(exit.take_outgoing().is_empty(), "The circuit window is still closed");
+
+    // Once it reopens, the waiting streams take turns
+    exit.on_cell(circuit_acks[0].clone()).unwrap();
+    let resumed: Vec<u16> = exit.take_outgoing().iter().map(|c| c.stream_id).collect();
+    assert_eq!(&resumed[..20], &[third, second].repeat(10)[..]);
+    assert!(resumed[20..].iter().all(|id| *id == second));
+    assert!(!resumed.contains(&first));
+}
+
+/// Unit test: Each direction of a hop has its own keys; a body sent one way neither decrypts nor is recognized the other way
+#[test]
+fn test_hop_direction_keys() {
+    let material = [7u8; 32];
+    let forward = DirectionKeys::derive(&material, Direction::Forward);
+    let backward = DirectionKeys::derive(&material, Direction::Backward);
+    assert!(forward.cipher != backward.cipher && forward.digest != backward.digest);
+    assert!(forward.cipher != forward.digest && forward.cipher != material);
+    assert!(DirectionKeys::derive(&material, Direction::Forward) == forward);
+
+    let plain = RelayCell::new(RelayCommand::Data, 1, b"hello relay".to_vec()).to_body().unwrap();
+    let sealed = |crypto: &mut HopCrypto, direction: Direction| {
+        let layer = if direction == Direction::Forward { &mut crypto.forward } else { &mut crypto.backward };
+        let mut body = plain;
+        layer.seal(&mut body);
+        layer.apply(&mut body);
+        body
+    };
+
+    // Same direction: both ends agree
+    let (mut client, mut relay) = (HopCrypto::new(&material), HopCrypto::new(&material));
+    let mut body = sealed(&mut client, Direction::Forward);
+    relay.forward.apply(&mut body);
+    assert!(relay.forward.recognize(&body));
+    assert_eq!(RelayCell::from_body(&body).unwrap().data, b"hello relay");
+
+    // A forward body run through the backward layer (e.g. reflected back at the client) is garbage
+    let (mut client, mut relay) = (HopCrypto::new(&material), HopCrypto::new(&material));
+    let mut body = sealed(&mut client, Direction::Forward);
+    relay.backward.apply(&mut body);
+    assert!(!relay.backward.recognize(&body));
+    assert_ne!(body, plain);
+    client.backward.apply(&mut body);
+    assert!(!client.backward.recognize(&body));
+
+    // Likewise a backward body fed to the forward layer
+    let (mut client, mut relay) = (HopCrypto::new(&material), HopCrypto::new(&material));
+    let mut body = sealed(&mut relay, Direction::Backward);
+    client.forward.apply(&mut body);
+    assert!(!client.forward.recognize(&body));
+
+    // Even with the right keystream, a body stamped with the other direction's digest is refused
+    let (mut client, mut relay) = (HopCrypto::new(&material), HopCrypto::new(&material));
+    let mut body = plain;
+    client.backward.seal(&mut body);
+    client.forward.apply(&mut body);
+    relay.forward.apply(&mut body);
+    assert!(!relay.forward.recognize(&body));
+}
+
+/// Cells a relay's actions send, as in flight from `relay`
+fn sends(relay: SocketAddr, actions: Vec<RelayAction>) -> Vec<(SocketAddr, SocketAddr, Cell)> {
+    actions
+        .into_iter()
+        .map(|action| {
+            let RelayAction::Send(to, cell) = action else {
+                panic!("Expected a cell to send");
+            };
+            (relay, to, cell)
+        })
+        .collect()
+}
+
+/// Integration test: TRUNCATE, lost links and DESTROY tear circuits down hop by hop with a reason, leaving no state behind
+#[test]
+fn test_circuit_teardown() {
+    let (mut relays, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+    let circuit = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    run(&mut manager, &mut relays);
+
+    let stream = manager.open_stream(circuit, &StreamTarget::new("example.org", 80)).unwrap();
+    run(&mut manager, &mut relays);
+    assert_eq!(manager.stream_state(stream), Some(StreamState::Connecting));
+
+    // The middle hop drops the exit on request; the stream through the exit goes with it
+    manager.truncate(circuit, 1).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Truncated {
+        circuit,
+        hops: 2,
+        reason: DestroyReason::Requested,
+        streams: vec![stream],
+    }]);
+    assert_eq!(manager.state(circuit), Some(CircuitState::Open));
+    assert_eq!(manager.path(circuit).unwrap().len(), 2);
+    assert_eq!(manager.stream_state(stream), None);
+    assert!(relays[&hops[2].addr].is_empty());
+    assert!(matches!(manager.truncate(circuit, 1), Err(CircuitError::NoSuchHop(2))));
+
+    // The first hop loses its link to the second: it truncates back to itself, and the second
+    // hop, which lost the same link, forgets the circuit
+    let in_flight = sends(hops[0].addr, relays.get_mut(&hops[0].addr).unwrap().on_link_closed(hops[1].addr).unwrap());
+    let (events, _) = run_from(&mut manager, &mut relays, in_flight);
+    assert_eq!(events, vec![CircuitEvent::Truncated {
+        circuit,
+        hops: 1,
+        reason: DestroyReason::ChannelClosed,
+        streams: vec![],
+    }]);
+    assert!(relays.get_mut(&hops[1].addr).unwrap().on_link_closed(hops[0].addr).unwrap().is_empty());
+    assert!(relays[&hops[1].addr].is_empty());
+
+    // Closing says why
+    assert!(manager.close(circuit));
+    let outgoing = manager.take_outgoing();
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].1.destroy_reason(), DestroyReason::Finished);
+    run_from(&mut manager, &mut relays, vec![(client_addr(), outgoing[0].0, outgoing[0].1.clone())]);
+    assert!(relays.values().all(|r| r.is_empty()));
+
+    // Losing the link to the first hop fails its circuits at once, on both ends
+    let circuit = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    run(&mut manager, &mut relays);
+    assert_eq!(manager.on_link_closed(hops[0].addr), vec![CircuitEvent::Failed(
+        circuit,
+        CircuitFailure::Destroyed(DestroyReason::ChannelClosed)
+    )]);
+    assert!(manager.is_empty());
+    let in_flight = sends(hops[0].addr, relays.get_mut(&hops[0].addr).unwrap().on_link_closed(client_addr()).unwrap());
+    assert!(in_flight.iter().all(|(_, _, cell)| cell.destroy_reason() == DestroyReason::ChannelClosed));
+    run_from(&mut manager, &mut relays, in_flight);
+    assert!(relays.values().all(|r| r.is_empty()));
+}
+
+fn relay_descriptor(addr: &str, capabilities: Capabilities, bandwidth: u32) -> NodeDescriptor {
+    let addr = addr.parse().unwrap();
+    NodeDescriptor::new_signed(&NodeIdentity::generate(), vec![addr], capabilities, bandwidth, 1_000, 3_600).unwrap()
+}
+
+/// Unit test: Paths follow bandwidth, end at a capable relay, and never pair relays of one family, subnet or NodeId prefix
+#[test]
+fn test_path_selection() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let no_prefix_rule = DiversityRules { node_id_prefix_bits: 0, ..Default::default() };
+
+    // Weighted by bandwidth; advertised figures are capped, measured ones are trusted
+    let fast = relay_descriptor("10.1.0.1:5000", Capabilities::RELAY, 9_000);
+    let slow = relay_descriptor("10.2.0.1:5000", Capabilities::RELAY, 1_000);
+    let liar = relay_descriptor("10.3.0.1:5000", Capabilities::RELAY, u32::MAX);
+    let mut selector = PathSelector::new().with_rules(no_prefix_rule);
+    for descriptor in [&fast, &slow, &liar] {
+        assert!(selector.insert(descriptor));
+    }
+    selector.set_measured_bandwidth(&liar.node_id, 0);
+
+    let single = PathRequest { length: 1, ..Default::default() };
+    let mut fast_count = 0;
+    for _ in 0..1_000 {
+        let path = selector.select_path(&single, &mut rng).unwrap();
+        assert_ne!(path[0].node_id, liar.node_id, "Measured bandwidth overrides the advertised one");
+        fast_count += (path[0].node_id == fast.node_id) as usize;
+    }
+    assert!((850..=950).contains(&fast_count), "Fast relay chosen {fast_count}/1000 times");
+
+    // Diversity: one family, one /16 subnet
+    let mut selector = PathSelector::new().with_rules(no_prefix_rule);
+    let exit = relay_descriptor("10.9.0.1:5000", Capabilities::RELAY | Capabilities::EXIT, 1_000);
+    let sibling = relay_descriptor("10.8.0.1:5000", Capabilities::RELAY, 1_000);
+    let neighbour = relay_descriptor("10.9.200.7:5000", Capabilities::RELAY, 1_000);
+    let others = [relay_descriptor("10.5.0.1:5000", Capabilities::RELAY, 1_000), relay_descriptor("10.6.0.1:5000", Capabilities::RELAY, 1_000)];
+    for descriptor in [&exit, &sibling, &neighbour].into_iter().chain(&others) {
+        selector.insert(descriptor);
+    }
+    assert!(!selector.insert(&relay_descriptor("10.7.0.1:5000", Capabilities::STORAGE, 1_000)));
+    selector.add_family(&[exit.node_id, sibling.node_id]);
+
+    let request = PathRequest { last_hop: Capabilities::EXIT, ..Default::default() };
+    for _ in 0..100 {
+        let path = selector.select_path(&request, &mut rng).unwrap();
+        let ids: Vec<NodeId> = path.iter().map(|h| h.node_id).collect();
+        assert_eq!(ids[2], exit.node_id);
+        assert!(!ids.contains(&sibling.node_id) && !ids.contains(&neighbour.node_id));
+    }
+
+    let excluded = PathRequest { exclude: vec![others[0].node_id], ..request.clone() };
+    assert_eq!(selector.select_path(&excluded, &mut rng), Err(PathError::NotEnoughRelays(3)));
+    assert_eq!(selector.select_path(&PathRequest { length: 0, ..Default::default() }, &mut rng), Err(PathError::InvalidLength(0)));
+
+    // NodeIds sharing a prefix don't share a path
+    let mut selector = PathSelector::new();
+    let mut twins = [relay_descriptor("10.1.0.1:5000", Capabilities::RELAY, 1_000), relay_descriptor("10.2.0.1:5000", Capabilities::RELAY, 1_000)];
+    for (i, twin) in twins.iter_mut().enumerate() {
+        let mut id = [0xabu8; 32];
+        id[31] = i as u8;
+        twin.node_id = NodeId::from_bytes(id);
+        selector.insert(twin);
+    }
+    let pair = PathRequest { length: 2, ..Default::default() };
+    assert_eq!(selector.select_path(&pair, &mut rng), Err(PathError::NotEnoughRelays(2)));
+
+    assert_eq!(selector.prune(4_600), 2);
+    assert!(selector.is_empty());
+}
+
+/// Sends what the exit's stream layer queued back along the circuit, returning the client's events
+fn exit_reply(
+    manager: &mut CircuitManager,
+    relays: &mut HashMap<SocketAddr, RelayCircuits>,
+    exit: SocketAddr,
+    link: CircuitLink,
+    exit_streams: &mut StreamSet
+) -> Vec<CircuitEvent> {
+    let in_flight = exit_streams
+        .take_outgoing()
+        .iter()
+        .map(|cell| {
+            let RelayAction::Send(to, cell) = relays.get_mut(&exit).unwrap().send_backward(link, cell).unwrap() else {
+                panic!("Expected a cell to send");
+            };
+            (exit, to, cell)
+        })
+        .collect();
+    run_from(manager, relays, in_flight).0
+}
+
+/// Integration test: Several streams share one circuit, each with its own state and buffer
+#[test]
+fn test_stream_multiplexing() {
+    let (mut relays, hops) = relays(3);
+    let exit = hops[2].addr;
+    let mut manager = CircuitManager::new();
+    let circuit = manager.open_circuit(hops, 1_000).unwrap();
+    run(&mut manager, &mut relays);
+
+    let web = manager.open_stream(circuit, &StreamTarget::new("example.org", 80)).unwrap();
+    let mail = manager.open_stream(circuit, &StreamTarget::new("mail.example.org", 25)).unwrap();
+    assert_ne!(web.stream, mail.stream);
+    assert_eq!(manager.stream_state(web), Some(StreamState::Connecting));
+    assert_eq!(manager.stream(web).write(b"early").unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    // The exit keeps its own stream set for the circuit, accepts one stream and refuses the other
+    let (_, delivered) = run(&mut manager, &mut relays);
+    let link = delivered[0].1;
+    let mut exit_streams = StreamSet::new();
+    let requests: Vec<StreamEvent> = delivered
+        .into_iter()
+        .map(|(relay, _, cell)| {
+            assert_eq!(relay, exit);
+            exit_streams.on_cell(cell).unwrap().unwrap()
+        })
+        .collect();
+    assert_eq!(requests, vec![
+        StreamEvent::Requested { stream: web.stream, target: StreamTarget::new("example.org", 80) },
+        StreamEvent::Requested { stream: mail.stream, target: StreamTarget::new("mail.example.org", 25) }
+    ]);
+    exit_streams.connected(web.stream).unwrap();
+    assert!(exit_streams.end(mail.stream, EndReason::Refused));
+    let events = exit_reply(&mut manager, &mut relays, exit, link, &mut exit_streams);
+    assert_eq!(events, vec![
+        CircuitEvent::Stream { circuit, hop: 2, event: StreamEvent::Connected(web.stream) },
+        CircuitEvent::Stream { circuit, hop: 2, event: StreamEvent::Ended(mail.stream, EndReason::Refused) }
+    ]);
+    assert_eq!(manager.stream_state(mail), None);
+    assert!(matches!(manager.write_stream(mail, b"x"), Err(CircuitError::Stream(StreamError::UnknownStream(_)))));
+
+    // Writes larger than a cell are split, and the exit reassembles them
+    let request: Vec<u8> = (0..1_200u32).map(|i| i as u8).collect();
+    manager.stream(web).write_all(&request).unwrap();
+    let (_, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(delivered.len(), 3);
+    for (_, _, cell) in delivered {
+        assert_eq!(exit_streams.on_cell(cell).unwrap(), Some(StreamEvent::Readable(web.stream)));
+    }
+    let mut received = vec![0u8; 2_000];
+    assert_eq!(exit_streams.read(web.stream, &mut received), request.len());
+    assert_eq!(&received[..request.len()], &request[..]);
+
+    // The exit echoes it back and closes; the client reads everything, then end of stream
+    assert_eq!(exit_streams.write(web.stream, &request).unwrap(), request.len());
+    assert!(exit_streams.end(web.stream, EndReason::Done));
+    let events = exit_reply(&mut manager, &mut relays, exit, link, &mut exit_streams);
+    assert_eq!(events.last(), Some(&CircuitEvent::Stream { circuit, hop: 2, event: StreamEvent::Ended(web.stream, EndReason::Done) }));
+    assert!(exit_streams.is_empty());
+
+    let mut echoed = Vec::new();
+    let mut buf = [0u8; 500];
+    loop {
+        match manager.stream(web).read(&mut buf).unwrap() {
+            0 => break,
+            n => echoed.extend_from_slice(&buf[..n]),
+        }
+    }
+    assert_eq!(echoed, request);
+    assert_eq!(manager.stream_state(web), None);
+    assert_eq!(manager.stream(web).write(b"late").unwrap_err().kind(), ErrorKind::BrokenPipe);
+}
+
+/// Integration test: Streams leave a circuit at any hop: a directory fetch from the middle hop never reaches the exit,
+/// and outlives the exit being truncated away
+#[test]
+fn test_leaky_pipe() {
+    let (mut relays, hops) = relays(3);
+    let (middle, exit) = (hops[1].addr, hops[2].addr);
+    let mut manager = CircuitManager::new();
+    let circuit = manager.open_circuit(hops, 1_000).unwrap();
+    run(&mut manager, &mut relays);
+
+    // Each hop recognizes only the cells sealed for it; stream ids are scoped to the hop
+    let dir = manager.open_directory_stream(circuit, 1).unwrap();
+    let web = manager.open_stream(circuit, &StreamTarget::new("example.org", 80)).unwrap();
+    assert_eq!((dir.hop, web.hop), (1, 2));
+    assert_eq!(dir.stream, web.stream);
+    assert!(matches!(manager.open_directory_stream(circuit, 3), Err(CircuitError::NoSuchHop(3))));
+    let (_, delivered) = run(&mut manager, &mut relays);
+    let received: Vec<(SocketAddr, RelayCommand)> = delivered.iter().map(|(relay, _, cell)| (*relay, cell.command)).collect();
+    assert_eq!(received, vec![(middle, RelayCommand::BeginDir), (exit, RelayCommand::Begin)]);
+
+    // The middle hop answers from its own stream set for the circuit
+    let (_, link, begin_dir) = delivered[0].clone();
+    let mut dir_streams = StreamSet::new();
+    assert_eq!(dir_streams.on_cell(begin_dir).unwrap(), Some(StreamEvent::DirectoryRequested(dir.stream)));
+    dir_streams.connected(dir.stream).unwrap();
+    dir_streams.write(dir.stream, b"consensus").unwrap();
+    let events = exit_reply(&mut manager, &mut relays, middle, link, &mut dir_streams);
+    assert_eq!(events, vec![
+        CircuitEvent::Stream { circuit, hop: 1, event: StreamEvent::Connected(dir.stream) },
+        CircuitEvent::Stream { circuit, hop: 1, event: StreamEvent::Readable(dir.stream) }
+    ]);
+    let mut buf = [0u8; 64];
+    assert_eq!(manager.read_stream(dir, &mut buf).unwrap(), 9);
+    assert_eq!(&buf[..9], b"consensus");
+    assert_eq!(manager.stream_state(web), Some(StreamState::Connecting));
+
+    // Cutting the exit off closes only the streams that ended there
+    manager.truncate(circuit, 1).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Truncated { circuit, hops: 2, reason: DestroyReason::Requested, streams: vec![web] }]);
+    assert_eq!(manager.stream_state(web), None);
+    assert_eq!(manager.stream_state(dir), Some(StreamState::Open));
+    manager.write_stream(dir, b"more").unwrap();
+    let (_, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(delivered.len(), 1);
+    assert_eq!((delivered[0].0, delivered[0].2.command, delivered[0].2.stream_id), (middle, RelayCommand::Data, dir.stream));
+}
+
+/// Unit test: New streams go to the least congested circuit, measured or lossy circuits after unmeasured ones, and
+/// streams on a circuit whose latency degraded are moved to a much better one only when migration is enabled
+#[test]
+fn test_congestion_aware_selection() {
+    let (_, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+    let [fast, slow, fresh] = [(); 3].map(|_| manager.open_circuit(hops.clone(), 1_000).unwrap());
+    let mut plain = CongestionTracker::new();
+    let mut migrating = CongestionTracker::new().with_migration(true);
+
+    for tracker in [&mut plain, &mut migrating] {
+        // Round trips are timed from BEGIN to the stream's answer
+        for n in 0..4 {
+            let stream = StreamHandle { circuit: fast, hop: 2, stream: n };
+            tracker.on_begin(stream, 1_000);
+            let connected = CircuitEvent::Stream { circuit: fast, hop: 2, event: StreamEvent::Connected(n) };
+            assert!(tracker.on_event(&connected, 1_100));
+            assert!(!tracker.on_event(&connected, 1_200), "answered already");
+            tracker.record_rtt(slow, 300);
+        }
+        assert_eq!(tracker.health(fast).unwrap().min_rtt_ms, 100);
+        assert_eq!(tracker.select([slow, fast]), Some(fast));
+        assert_eq!(tracker.select([slow, fast, fresh]), Some(fresh), "unmeasured circuits are tried first");
+
+        // A stream left unanswered is a loss; a circuit with only losses goes last
+        tracker.on_begin(StreamHandle { circuit: fresh, hop: 2, stream: 1 }, 2_000);
+        assert_eq!(tracker.expire(2_000 + PROBE_TIMEOUT_MS - 1), 0);
+        assert_eq!(tracker.expire(2_000 + PROBE_TIMEOUT_MS), 1);
+        assert_eq!(tracker.select([fresh, slow]), Some(slow));
+        assert!(tracker.migration_target(fast, [slow]).is_none(), "fast is not degraded");
+
+        // The fast circuit gets congested
+        for _ in 0..20 {
+            tracker.record_rtt(fast, 900);
+        }
+        assert!(tracker.health(fast).unwrap().is_degraded());
+        assert!(!tracker.health(slow).unwrap().is_degraded());
+        assert_eq!(tracker.select([slow, fast]), Some(slow));
+    }
+    assert_eq!(plain.migration_target(fast, [fast, slow, fresh]), None);
+    assert_eq!(migrating.migration_target(fast, [fast, slow, fresh]), Some(slow));
+    assert_eq!(migrating.migration_target(slow, [fast, slow]), None);
+
+    // Failed circuits are forgotten
+    assert!(migrating.on_event(&CircuitEvent::Failed(slow, CircuitFailure::Timeout), 3_000));
+    assert!(migrating.health(slow).is_none());
+    assert_eq!(migrating.migration_target(fast, [slow]), None);
+    assert_eq!(migrating.len(), 1);
+}
+
+/// A relay cell marked with `n` in its first byte
+fn marked_cell(circuit_id: u32, n: u8) -> Cell {
+    let mut body = [0u8; CELL_PAYLOAD_SIZE];
+    body[0] = n;
+    Cell::relay(circuit_id, body)
+}
+
+/// Unit test: Relays release cells within the global and per-circuit token buckets, in order per circuit and taking
+/// turns across circuits, and hibernate at their monthly cap until the next month
+#[test]
+fn test_relay_bandwidth_limits() {
+    let (p, q): (SocketAddr, SocketAddr) = ("10.0.2.1:5000".parse().unwrap(), "10.0.2.2:5000".parse().unwrap());
+    let cells = |n: u64| n * CELL_SIZE as u64;
+    let config = BandwidthConfig { rate: cells(6), burst: cells(4), circuit_rate: cells(2), circuit_burst: cells(3), monthly_cap: None };
+    let mut limiter = BandwidthLimiter::new(config, 0);
+    for n in 0..5 {
+        for (from, id) in [(p, 1), (p, 2), (q, 1)] {
+            limiter.push(from, marked_cell(id, n), 0).unwrap();
+        }
+    }
+    let released = |cells: Vec<(SocketAddr, Cell)>| -> Vec<(SocketAddr, u32, u8)> {
+        cells.into_iter().map(|(from, cell)| (from, cell.circuit_id, cell.payload[0])).collect()
+    };
+
+    // The global burst goes round the circuits; then the rates set the pace
+    assert_eq!(released(limiter.poll(0)), vec![(p, 1, 0), (p, 2, 0), (q, 1, 0), (p, 1, 1)]);
+    assert!(limiter.poll(0).is_empty());
+    assert_eq!(released(limiter.poll(500)), vec![(p, 2, 1), (q, 1, 1), (p, 1, 2)]);
+    assert_eq!(limiter.queued(), 8);
+
+    // One busy circuit alone is held to its own rate
+    limiter.forget(CircuitLink::new(p, 2));
+    limiter.forget(CircuitLink::new(q, 1));
+    assert_eq!(limiter.queued(), 2);
+    assert_eq!(released(limiter.poll(10_000)), vec![(p, 1, 3), (p, 1, 4)]);
+    let mut busy = BandwidthLimiter::new(BandwidthConfig { rate: cells(100), burst: cells(100), ..config }, 0);
+    for n in 0..10 {
+        busy.push(p, marked_cell(7, n), 0).unwrap();
+    }
+    assert_eq!(busy.poll(0).len(), 3);
+    assert_eq!(released(busy.poll(1_000)), vec![(p, 7, 3), (p, 7, 4)]);
+
+    // A circuit can't queue without bound
+    let mut flooded = BandwidthLimiter::new(BandwidthConfig::default(), 0);
+    for _ in 0..MAX_QUEUED_CELLS {
+        flooded.push(p, marked_cell(1, 0), 0).unwrap();
+    }
+    assert_eq!(flooded.push(p, marked_cell(1, 0), 0), Err(BandwidthError::QueueFull(1)));
+
+    // Past 95% of the monthly cap new circuits are refused; at the cap, everything until February
+    let (january, february) = (1_768_435_200_000, 1_769_904_000_000);
+    let capped = BandwidthConfig { monthly_cap: Some(cells(100)), ..BandwidthConfig::default() };
+    let mut limiter = BandwidthLimiter::new(capped, january);
+    let create = || Cell::new(9, CellCommand::Create, &[0u8; 96]);
+    for n in 0..90 {
+        limiter.push(p, marked_cell(1, n), january).unwrap();
+    }
+    limiter.on_sent(5, january);
+    assert_eq!(limiter.used(), cells(95));
+    assert_eq!(limiter.push(q, create(), january), Err(BandwidthError::Hibernating));
+    limiter.push(p, marked_cell(1, 90), january).unwrap();
+    limiter.on_sent(4, january);
+    assert!(limiter.is_hibernating(january));
+    assert_eq!(limiter.push(p, marked_cell(1, 91), february - 1), Err(BandwidthError::Hibernating));
+    assert!(!limiter.is_hibernating(february));
+    limiter.push(q, create(), february).unwrap();
+    assert_eq!(limiter.used(), cells(1));
+}
+/// Unit test: The cell scheduler gives peers turns of `quantum` cells and, on each peer, sends first for the circuit
+/// that sent least lately, so an interactive circuit gets ahead of a bulk one that has been streaming
+#[test]
+fn test_cell_scheduler() {
+    let (p, q): (SocketAddr, SocketAddr) = ("10.0.2.1:5000".parse().unwrap(), "10.0.2.2:5000".parse().unwrap());
+    let sent = |cells: Vec<(SocketAddr, Cell)>| -> Vec<(SocketAddr, u32, u8)> {
+        cells.into_iter().map(|(to, cell)| (to, cell.circuit_id, cell.payload[0])).collect()
+    };
+    let mut scheduler = CellScheduler::new(SchedulerConfig { quantum: 2, halflife_ms: 1_000 });
+
+    // A bulk transfer alone has the link to itself...
+    for n in 0..20 {
+        scheduler.push(p, marked_cell(1, n), 0).unwrap();
+    }
+    assert_eq!(scheduler.poll(10, 0).len(), 10);
+    // ...until an interactive circuit has something to send: it goes ahead, in order
+    scheduler.push(p, marked_cell(2, 0), 0).unwrap();
+    scheduler.push(p, marked_cell(2, 1), 0).unwrap();
+    assert_eq!(sent(scheduler.poll(3, 0)), vec![(p, 2, 0), (p, 2, 1), (p, 1, 10)]);
+
+    // Peers take turns, one cut short by the budget finishing its turn first next time
+    for n in 0..3 {
+        scheduler.push(q, marked_cell(1, n), 0).unwrap();
+    }
+    assert_eq!(sent(scheduler.poll(5, 0)), vec![(p, 1, 11), (q, 1, 0), (q, 1, 1), (p, 1, 12), (p, 1, 13)]);
+    assert_eq!(sent(scheduler.poll(3, 0)), vec![(q, 1, 2), (p, 1, 14), (p, 1, 15)]);
+    assert_eq!((scheduler.queued(), scheduler.queued_for(q)), (4, 0));
+
+    // Counts decay: long after its burst, the bulk circuit is ahead of one that just sent a cell
+    scheduler.push(p, marked_cell(3, 0), 5_000).unwrap();
+    assert_eq!(scheduler.poll_peer(p, 1, 5_000)[0].circuit_id, 3);
+    scheduler.push(p, marked_cell(3, 1), 5_000).unwrap();
+    assert_eq!(scheduler.poll_peer(p, 1, 5_000)[0].circuit_id, 1);
+    assert!(scheduler.poll_peer(q, 2, 5_000).is_empty());
+
+    // Torn down circuits and lost links drop what they had waiting; quiet circuits are pruned
+    assert_eq!(scheduler.forget(CircuitLink::new(p, 1)), 3);
+    assert_eq!(scheduler.forget_peer(p), 1);
+    assert!(scheduler.poll(10, 5_000).is_empty());
+    scheduler.prune(60_000);
+    assert_eq!(scheduler.queued(), 0);
+
+    // A circuit can't queue without bound
+    for _ in 0..MAX_QUEUED_CELLS {
+        scheduler.push(q, marked_cell(1, 0), 60_000).unwrap();
+    }
+    assert_eq!(scheduler.push(q, marked_cell(1, 0), 60_000), Err(SchedulerError::QueueFull(1)));
+}
+
+
+/// Moves the frames `from` queued to `to`, each in two reads, holding back those of subflow `late` until the end
+fn deliver_frames(from: &mut MultipathStream<u8>, to: &mut MultipathStream<u8>, late: Option<u8>) {
+    let (held, frames): (Vec<_>, Vec<_>) = from.take_outgoing().into_iter().partition(|(subflow, _)| Some(*subflow) == late);
+    for (subflow, frame) in frames.into_iter().chain(held) {
+        let (head, tail) = frame.split_at(frame.len() / 2);
+        to.on_data(subflow, head).unwrap();
+        to.on_data(subflow, tail).unwrap();
+    }
+}
+
+/// Unit test: A stream split across subflows is reassembled in order whatever order its frames arrive in, stays within
+/// its window, and survives losing a subflow
+#[test]
+fn test_multipath() {
+    let (mut client, mut server) = (MultipathStream::new(), MultipathStream::new());
+    for subflow in [0u8, 1] {
+        assert!(client.add_subflow(subflow));
+        assert!(server.add_subflow(subflow));
+    }
+    assert!(!client.add_subflow(0));
+
+    // Frames go to the subflow with the least in flight; subflow 0's arrive last and order is restored
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    assert_eq!(client.write(&data).unwrap(), data.len());
+    assert!(client.in_flight(0).unwrap().abs_diff(client.in_flight(1).unwrap()) <= MULTIPATH_CHUNK_SIZE);
+    deliver_frames(&mut client, &mut server, Some(0));
+    assert_eq!(server.readable(), data.len());
+    let mut received = vec![0u8; 30_000];
+    assert_eq!(server.read(&mut received), data.len());
+    assert_eq!(&received[..data.len()], &data[..]);
+
+    // Reading acknowledges; the sender forgets what was read
+    assert_eq!(client.unacked(), data.len());
+    deliver_frames(&mut server, &mut client, None);
+    assert_eq!(client.unacked(), 0);
+    assert_eq!((client.in_flight(0), client.in_flight(1)), (Some(0), Some(0)));
+
+    // The window caps what is unread at the other end, and opens as it is read
+    let bulk = vec![7u8; MULTIPATH_WINDOW + 1_000];
+    assert_eq!(client.write(&bulk).unwrap(), MULTIPATH_WINDOW);
+    assert_eq!(client.write(&bulk).unwrap(), 0);
+    deliver_frames(&mut client, &mut server, None);
+    let mut buf = vec![0u8; 100_000];
+    assert_eq!(server.read(&mut buf), buf.len());
+    deliver_frames(&mut server, &mut client, None);
+    assert_eq!(client.write(&bulk).unwrap(), (100_000 / MULTIPATH_CHUNK_SIZE) * MULTIPATH_CHUNK_SIZE);
+
+    // Frames on a lost subflow are resent on the others; resent frames that arrive twice count once
+    let (mut client, mut server) = (MultipathStream::new(), MultipathStream::new());
+    for subflow in [0u8, 1] {
+        client.add_subflow(subflow);
+        server.add_subflow(subflow);
+    }
+    assert_eq!(client.write(&data[..5_000]).unwrap(), 5_000);
+    let (lost, frames): (Vec<_>, Vec<_>) = client.take_outgoing().into_iter().partition(|(subflow, _)| *subflow == 0);
+    for (subflow, frame) in &frames {
+        server.on_data(*subflow, frame).unwrap();
+    }
+    assert_eq!(server.readable(), 0, "the first frame went on the lost subflow");
+    assert_eq!(client.remove_subflow(0).unwrap(), lost.len());
+    assert_eq!(server.remove_subflow(0).unwrap(), 0);
+    deliver_frames(&mut client, &mut server, None);
+    assert_eq!(server.on_data(1, &frames[0].1).unwrap(), 5_000);
+    assert_eq!(server.read(&mut received), 5_000);
+    assert_eq!(&received[..5_000], &data[..5_000]);
+
+    assert_eq!(client.remove_subflow(1), Err(MultipathError::NoSubflows));
+    assert_eq!(client.subflows(), 1);
+    assert_eq!(MultipathStream::<u8>::new().write(b"x"), Err(MultipathError::NoSubflows));
+    assert_eq!(server.on_data(0, b"x"), Err(MultipathError::UnknownSubflow));
+    assert!(matches!(server.on_data(1, &[9; 16]), Err(MultipathError::Malformed(_))));
+}
+
+/// Integration test: Padding is negotiated with a hop within its limits, fills idle time at both ends and stops on request
+#[test]
+fn test_circuit_padding() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let (mut relays, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+    let circuit = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    run(&mut manager, &mut relays);
+
+    // Padding more often than the relay allows is refused
+    let eager = PaddingSpec { delay: Distribution::Fixed(10), ..Default::default() };
+    manager.negotiate_padding(circuit, 1, eager).unwrap();
+    let (events, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::PaddingNegotiated { circuit, hop: 1, accepted: false }]);
+    assert!(delivered.is_empty());
+    assert_eq!(manager.poll_padding(1_000_000, &mut rng).unwrap(), 0);
+
+    let spec = PaddingSpec { delay: Distribution::Fixed(200), max_idle_cells: 3, max_overhead_percent: 50 };
+    manager.negotiate_padding(circuit, 1, spec).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::PaddingNegotiated { circuit, hop: 1, accepted: true }]);
+
+    // The client pads after each delay without traffic, up to `max_idle_cells` in a row; the
+    // hop discards the cells without delivering them
+    let sent: Vec<usize> = [0, 100, 200, 400, 600, 800, 5_000]
+        .into_iter()
+        .map(|now| manager.poll_padding(now, &mut rng).unwrap())
+        .collect();
+    assert_eq!(sent, vec![0, 0, 1, 1, 1, 0, 0]);
+    let (events, delivered) = run(&mut manager, &mut relays);
+    assert!(events.is_empty() && delivered.is_empty());
+
+    // Real traffic starts a new idle period
+    manager.send(circuit, 2, RelayCell::new(RelayCommand::Data, 9, b"real".to_vec())).unwrap();
+    run(&mut manager, &mut relays);
+    assert_eq!(manager.poll_padding(6_000, &mut rng).unwrap(), 0);
+    assert_eq!(manager.poll_padding(6_200, &mut rng).unwrap(), 1);
+    run(&mut manager, &mut relays);
+
+    // The hop pads towards the client, who discards the cells
+    let middle = relays.get_mut(&hops[1].addr).unwrap();
+    assert!(middle.poll_padding(6_300, &mut rng).unwrap().is_empty());
+    let padding = sends(hops[1].addr, middle.poll_padding(6_500, &mut rng).unwrap());
+    assert_eq!(padding.len(), 1);
+    let (events, _) = run_from(&mut manager, &mut relays, padding);
+    assert!(events.is_empty());
+    assert!(relays.values_mut().all(|r| r.poll_padding(6_500, &mut rng).unwrap().is_empty()));
+
+    // Stopping ends padding at both ends
+    manager.stop_padding(circuit).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert!(events.is_empty());
+    assert_eq!(manager.poll_padding(100_000, &mut rng).unwrap(), 0);
+    assert!(relays.values_mut().all(|r| r.poll_padding(100_000, &mut rng).unwrap().is_empty()));
+}
+
+/// Unit test: A padding machine keeps padding within its overhead limit, and specs and delays survive the wire
+#[test]
+fn test_padding_machine() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let spec = PaddingSpec { delay: Distribution::Fixed(10), max_idle_cells: u16::MAX, max_overhead_percent: 50 };
+    let mut machine = PaddingMachine::new(spec);
+
+    // The first cells are free of the limit; after that, padding never outgrows real traffic
+    let mut now = 0;
+    for (real, padded) in [(1, 49), (100, 101)] {
+        machine.on_traffic(real, now, &mut rng);
+        now += 10;
+        while machine.poll(now, &mut rng) {
+            now += 10;
+        }
+        assert_eq!(machine.padding_cells(), padded);
+    }
+
+    let uniform = Distribution::Uniform { min: 50, max: 150 };
+    assert!((0..1_000).all(|_| (50..=150).contains(&uniform.sample(&mut rng))));
+    let exponential = Distribution::Exponential { mean: 400 };
+    let mean = (0..10_000).map(|_| exponential.sample(&mut rng)).sum::<u64>() / 10_000;
+    assert!((360..440).contains(&mean), "mean delay {mean}");
+
+    for negotiate in [PaddingNegotiate::Start(PaddingSpec::default()), PaddingNegotiate::Start(spec), PaddingNegotiate::Stop] {
+        assert_eq!(PaddingNegotiate::from_bytes(&negotiate.to_bytes()).unwrap(), negotiate);
+    }
+    assert!(PaddingNegotiate::from_bytes(&[3]).is_err());
+    assert!(PaddingNegotiate::from_bytes(&[1, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 50]).is_err());
+}
+
+/// Integration test: Exit policies reject internal networks by default, travel in descriptors, steer path selection and end refused BEGINs at the exit
+#[test]
+fn test_exit_policy() {
+    let default = ExitPolicy::default();
+    for (ip, port) in [("10.0.0.7", 80), ("127.0.0.1", 8080), ("192.168.1.1", 443), ("::1", 80), ("fe80::1", 22), ("::ffff:172.16.0.1", 80)] {
+        assert!(!default.allows(ip.parse().unwrap(), port), "{ip}:{port} is internal");
+    }
+    assert!(default.allows("93.184.216.34".parse().unwrap(), 443));
+    assert!(default.allows("2001:db8::1".parse().unwrap(), 22));
+    assert!(!ExitPolicy::reject_all().allows_port(80));
+
+    // First match wins; a host name is only checked by port
+    let web = ExitPolicy::new(vec![
+        ExitRule::reject(AddressPattern::Network("203.0.113.0".parse().unwrap(), 24), 0..=u16::MAX),
+        ExitRule::accept(AddressPattern::Any, 80..=80),
+        ExitRule::accept(AddressPattern::Any, 443..=443)
+    ]);
+    assert!(web.allows("198.51.100.1".parse().unwrap(), 443));
+    assert!(!web.allows("198.51.100.1".parse().unwrap(), 22));
+    assert!(!web.allows("203.0.113.9".parse().unwrap(), 80));
+    assert!(web.allows_port(80) && !web.allows_port(22));
+    assert!(web.allows_target(&StreamTarget::new("example.org", 443)));
+    assert!(!web.allows_target(&StreamTarget::new("example.org", 25)));
+    assert!(!web.allows_target(&StreamTarget::new("[::ffff:10.1.2.3]", 80)));
+
+    // The policy is part of the signed descriptor, and clients pick exits that allow their port
+    let mut rng = StdRng::seed_from_u64(14);
+    let exit = |addr: &str, policy: ExitPolicy| {
+        let capabilities = Capabilities::RELAY | Capabilities::EXIT;
+        NodeDescriptor::new_signed_with_exit_policy(&NodeIdentity::generate(), vec![addr.parse().unwrap()], capabilities, policy, 1_000, 1_000, 3_600)
+    };
+    let web_exit = exit("10.1.0.1:5000", web.clone()).unwrap();
+    let open_exit = exit("10.2.0.1:5000", default.clone()).unwrap();
+    let decoded = NodeDescriptor::from_bytes(&web_exit.to_bytes()).unwrap();
+    decoded.verify(1_100).unwrap();
+    assert_eq!(decoded.exit_policy, web);
+    let too_many = ExitPolicy::from_rules(vec![ExitRule::accept(AddressPattern::Any, 80..=80); 65]);
+    assert!(matches!(exit("10.3.0.1:5000", too_many), Err(DescriptorError::TooManyExitRules(65))));
+
+    let mut selector = PathSelector::new();
+    selector.insert(&web_exit);
+    selector.insert(&open_exit);
+    let ssh = PathRequest { length: 1, last_hop: Capabilities::EXIT, exit_port: Some(22), ..Default::default() };
+    assert!((0..20).all(|_| selector.select_path(&ssh, &mut rng).unwrap()[0].node_id == open_exit.node_id));
+
+    // The exit ends streams its policy rejects itself; the others reach its host
+    let (mut relays, hops) = relays(3);
+    let mut manager = CircuitManager::new();
+    let circuit = manager.open_circuit(hops.clone(), 1_000).unwrap();
+    run(&mut manager, &mut relays);
+
+    let internal = manager.open_stream(circuit, &StreamTarget::new("192.168.1.1", 80)).unwrap();
+    let (events, delivered) = run(&mut manager, &mut relays);
+    assert!(delivered.is_empty());
+    assert_eq!(events, vec![CircuitEvent::Stream { circuit, hop: 2, event: StreamEvent::Ended(internal.stream, EndReason::ExitPolicy) }]);
+    assert_eq!(manager.stream_state(internal), None);
+
+    let public = manager.open_stream(circuit, &StreamTarget::new("example.org", 80)).unwrap();
+    let (_, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(delivered.len(), 1);
+    assert_eq!((delivered[0].0, delivered[0].2.command, delivered[0].2.stream_id), (hops[2].addr, RelayCommand::Begin, public.stream));
+}
+
+/// Integration test: A service establishes introduction points at distinct relays, clients reach it through them, and
+/// registrations can't be replayed on another circuit or outlive their circuit
+#[test]
+fn test_intro_points() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let (mut relays, selector) = relay_network(6, 0);
+    let mut manager = CircuitManager::new();
+    let mut service = IntroPoints::new(2);
+    assert_eq!(service.maintain(&mut manager, &selector, &mut rng, 1_000), 2);
+    let (mut events, _) = run(&mut manager, &mut relays);
+    while !events.is_empty() {
+        for event in &events {
+            service.on_event(&mut manager, event);
+        }
+        events = run(&mut manager, &mut relays).0;
+    }
+    let points = service.established(&manager);
+    assert_eq!(points.len(), 2);
+    assert_ne!(points[0].relay.node_id, points[1].relay.node_id);
+    assert_eq!(relays.values().map(|r| r.intro_points()).sum::<usize>(), 2);
+
+    // A client reaches the service through the first point, naming it by its auth key there
+    let (intro_hop, auth_key) = (points[0].relay, points[0].auth_key);
+    let open_client = |manager: &mut CircuitManager, relays: &mut HashMap<SocketAddr, RelayCircuits>, rng: &mut StdRng| {
+        let request = PathRequest { length: 1, exclude: vec![intro_hop.node_id], ..Default::default() };
+        let first = selector.select_path(&request, rng).unwrap()[0];
+        let client = manager.open_circuit(vec![first, intro_hop], 1_000).unwrap();
+        run(manager, relays);
+        client
+    };
+    let client = open_client(&mut manager, &mut relays, &mut rng);
+
+    let introduce = Introduce { auth_key: auth_key.to_bytes(), payload: b"meet me at the rendezvous point".to_vec() };
+    manager.introduce(client, &introduce).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events.len(), 2);
+    assert!(events.contains(&CircuitEvent::IntroduceAck { circuit: client, status: IntroduceStatus::Success }));
+    let service_circuit = events
+        .iter()
+        .find_map(|e| match e {
+            CircuitEvent::Introduced { circuit, introduce: received } if *received == introduce => Some(*circuit),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(manager.path(service_circuit).unwrap().last(), Some(&intro_hop));
+
+    let unknown = Introduce { auth_key: [7u8; 32], payload: Vec::new() };
+    manager.introduce(client, &unknown).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::IntroduceAck { circuit: client, status: IntroduceStatus::UnknownService }]);
+
+    // ESTABLISH_INTRO signed for another circuit is refused, and the introduction point drops the circuit
+    let replayed = EstablishIntro::new(&ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]), &[0u8; 32]);
+    manager.send(client, 1, RelayCell::new(RelayCommand::EstablishIntro, 0, replayed.to_bytes())).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Truncated { circuit: client, hops: 1, reason: DestroyReason::Protocol, streams: vec![] }]);
+    assert_eq!(relays[&intro_hop.addr].intro_points(), 1);
+
+    // A lost introduction circuit is closed, which unregisters it at the relay; the service replaces it elsewhere
+    let lost = CircuitEvent::Failed(service_circuit, CircuitFailure::Destroyed(DestroyReason::Internal));
+    assert!(service.on_event(&mut manager, &lost));
+    run(&mut manager, &mut relays);
+    assert_eq!(relays[&intro_hop.addr].intro_points(), 0);
+    assert_eq!(service.len(), 1);
+    assert_eq!(service.maintain(&mut manager, &selector, &mut rng, 2_000), 1);
+
+    let client = open_client(&mut manager, &mut relays, &mut rng);
+    manager.introduce(client, &introduce).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert!(events.contains(&CircuitEvent::IntroduceAck { circuit: client, status: IntroduceStatus::UnknownService }));
+}
+
+/// Integration test: A client and a service meet at a rendezvous point the client picked, through an introduction point,
+/// and exchange cells end to end that neither relay can read; either end leaving tears down the other's circuit
+#[test]
+fn test_rendezvous() {
+    let mut rng = StdRng::seed_from_u64(16);
+    let (mut relays, selector) = relay_network(8, 0);
+    let mut manager = CircuitManager::new();
+    let mut service = IntroPoints::new(1);
+    service.maintain(&mut manager, &selector, &mut rng, 1_000);
+    let (mut events, _) = run(&mut manager, &mut relays);
+    while !events.is_empty() {
+        for event in &events {
+            service.on_event(&mut manager, event);
+        }
+        events = run(&mut manager, &mut relays).0;
+    }
+    let point = service.established(&manager).remove(0);
+
+    // The client waits at its rendezvous point, then introduces itself
+    let request = PathRequest { length: 2, exclude: vec![point.relay.node_id], ..Default::default() };
+    let rendezvous = manager.open_circuit(selector.select_path(&request, &mut rng).unwrap(), 1_000).unwrap();
+    let request = PathRequest { length: 1, exclude: vec![point.relay.node_id], ..Default::default() };
+    let mut intro_path = selector.select_path(&request, &mut rng).unwrap();
+    intro_path.push(point.relay);
+    let intro = manager.open_circuit(intro_path, 1_000).unwrap();
+    run(&mut manager, &mut relays);
+    let rp = *manager.path(rendezvous).unwrap().last().unwrap();
+
+    assert!(matches!(manager.introduce_rendezvous(intro, rendezvous, &point), Err(CircuitError::NoRendezvous(_))));
+    manager.establish_rendezvous(rendezvous).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::RendezvousEstablished(rendezvous)]);
+    assert_eq!(relays[&rp.addr].rendezvous_waiting(), 1);
+
+    manager.introduce_rendezvous(intro, rendezvous, &point).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert!(events.contains(&CircuitEvent::IntroduceAck { circuit: intro, status: IntroduceStatus::Success }));
+    let introduce = events
+        .into_iter()
+        .find_map(|e| match e {
+            CircuitEvent::Introduced { introduce, .. } => Some(introduce),
+            _ => None,
+        })
+        .unwrap();
+
+    // Only the service can open the introduction
+    assert!(Introduction::open(&introduce.payload, &x25519_dalek::StaticSecret::from([3u8; 32])).is_none());
+    let tampered = Introduce { payload: introduce.payload[..introduce.payload.len() - 1].to_vec(), ..introduce.clone() };
+    assert!(service.accept(&tampered).is_none());
+    let accepted = service.accept(&introduce).unwrap();
+    assert_eq!(accepted.rendezvous, rp);
+
+    let request = PathRequest { length: 1, exclude: vec![rp.node_id], ..Default::default() };
+    let joined = manager.open_rendezvous(selector.select_path(&request, &mut rng).unwrap(), accepted, 1_000).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events.len(), 3);
+    assert!(events.contains(&CircuitEvent::Extended { circuit: joined, hops: 1 }));
+    assert!(events.contains(&CircuitEvent::RendezvousCompleted(joined)));
+    assert!(events.contains(&CircuitEvent::RendezvousCompleted(rendezvous)));
+    assert_eq!(relays[&rp.addr].rendezvous_waiting(), 0);
+
+    // Each end's last hop is now the other end; no relay recognizes what they send each other
+    let hello = RelayCell::new(RelayCommand::Data, 1, b"hello service".to_vec());
+    manager.send(rendezvous, 2, hello.clone()).unwrap();
+    let (events, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Relay { circuit: joined, hop: 2, cell: hello }]);
+    assert!(delivered.is_empty());
+
+    let reply = RelayCell::new(RelayCommand::Data, 1, b"hello client".to_vec());
+    manager.send(joined, 2, reply.clone()).unwrap();
+    let (events, delivered) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Relay { circuit: rendezvous, hop: 2, cell: reply }]);
+    assert!(delivered.is_empty());
+
+    // A cookie is used once: joining it again gets the service's circuit destroyed
+    let again = RelayCell::new(RelayCommand::Rendezvous1, 0, vec![0u8; 84]);
+    let probe = manager.open_circuit(vec![rp], 1_000).unwrap();
+    run(&mut manager, &mut relays);
+    manager.send(probe, 0, again).unwrap();
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Failed(probe, CircuitFailure::Destroyed(DestroyReason::Protocol))]);
+
+    // The service leaving cuts the client's circuit back before the rendezvous point
+    let circuits = relays[&rp.addr].len();
+    manager.close(joined);
+    let (events, _) = run(&mut manager, &mut relays);
+    assert_eq!(events, vec![CircuitEvent::Truncated { circuit: rendezvous, hops: 1, reason: DestroyReason::Finished, streams: vec![] }]);
+    assert_eq!(relays[&rp.addr].len(), circuits - 2);
+}
+
+/// Unit test: Hidden service descriptors are stored under a blinded key that changes every period, pass directory
+/// validation without revealing their content, and open only for clients who know the service's identity
+#[test]
+fn test_hs_descriptor() {
+    let now = 10 * HS_PERIOD_SECS + 100;
+    let identity = ed25519_dalek::SigningKey::from_bytes(&[21u8; 32]);
+    let public = identity.verifying_key();
+    let (_, hops) = relays(2);
+    let points: Vec<IntroPointInfo> = hops
+        .iter()
+        .enumerate()
+        .map(|(i, hop)| IntroPointInfo {
+            relay: *hop,
+            auth_key: ed25519_dalek::SigningKey::from_bytes(&[i as u8; 32]).verifying_key(),
+            enc_key: X25519PublicKey::from([i as u8 + 1; 32]),
+        })
+        .collect();
+
+    let descriptor = HsDescriptor::new_signed(&identity, &points, 1, now).unwrap();
+    assert_eq!(HsDescriptor::from_bytes(&descriptor.to_bytes()).unwrap(), descriptor);
+    descriptor.verify(now).unwrap();
+    assert_eq!(descriptor.decrypt(&public).unwrap(), points);
+
+    // The blinded key is neither the identity nor stable across periods, yet clients derive it from the identity
+    let period = hs_descriptor::time_period(now);
+    assert_eq!(descriptor.blinded_key, hs_descriptor::blind_public_key(&public, period));
+    assert_ne!(descriptor.blinded_key, public);
+    assert_ne!(hs_descriptor::blind_public_key(&public, period + 1), descriptor.blinded_key);
+    let other = ed25519_dalek::SigningKey::from_bytes(&[22u8; 32]).verifying_key();
+    assert!(matches!(descriptor.decrypt(&other), Err(HsDescriptorError::WrongService)));
+
+    // Published with STORE and validated by the directory; fetched with FETCH under the same key
+    let store = descriptor.store_request(NodeId::from_bytes([1u8; 32]), now).unwrap();
+    let registry = ValidatorRegistry::default();
+    registry.validate_store(&store, now).unwrap();
+    assert_eq!(store.record.expires_at(), descriptor.expires_at);
+    assert_eq!(HsDescriptor::fetch_request(&public, now).hash, *store.record.key.as_bytes());
+    assert_eq!(HsDescriptor::open(&public, &store.record.value, now).unwrap(), points);
+    assert!(matches!(HsDescriptor::open(&public, &store.record.value, now + HS_PERIOD_SECS), Err(HsDescriptorError::WrongService)));
+
+    let mut tampered = store.clone();
+    let last = tampered.record.value.len() - 70;
+    tampered.record.value[last] ^= 1;
+    assert!(matches!(registry.validate_store(&tampered, now), Err(ValidationError::Invalid(_))));
+    assert!(matches!(registry.validate_store(&store, descriptor.expires_at), Err(ValidationError::Invalid(_))));
+    let elsewhere = HsDescriptor::new_signed(&identity, &points, 2, now + HS_PERIOD_SECS).unwrap();
+    let misplaced = StoreRequest { record: Record { value: elsewhere.to_bytes(), ..store.record.clone() }, ..store };
+    assert_eq!(registry.validate_store(&misplaced, now), Err(ValidationError::KeyMismatch));
+
+    let too_many = vec![points[0].clone(); MAX_HS_INTRO_POINTS + 1];
+    assert!(matches!(HsDescriptor::new_signed(&identity, &too_many, 1, now), Err(HsDescriptorError::TooManyIntroPoints(11))));
+}
+
+/// Unit test: Onion addresses round-trip through their canonical text form and reject typos, other versions and
+/// anything but base32 with the .freedom suffix
+#[test]
+fn test_onion_address() {
+    let identity = ed25519_dalek::SigningKey::from_bytes(&[23u8; 32]).verifying_key();
+    let address = OnionAddress::new(identity);
+    let text = address.to_string();
+    assert_eq!(text.len(), 56 + ".freedom".len());
+    assert!(text.ends_with(".freedom"));
+    assert!(text.chars().all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c) || c == '.'));
+    assert_eq!(text.parse::<OnionAddress>(), Ok(address));
+    assert_eq!(text.to_uppercase().parse::<OnionAddress>(), Ok(address));
+    assert_eq!(address.identity_key(), &identity);
+
+    // One mistyped character is caught by the checksum; the last one carries the version
+    let (encoded, _) = text.split_at(56);
+    let typo = format!("{}{}.freedom", if encoded.starts_with('a') { 'b' } else { 'a' }, &encoded[1..]);
+    assert_eq!(typo.parse::<OnionAddress>(), Err(AddressError::BadChecksum));
+    let version_2 = format!("{}c.freedom", &encoded[..55]);
+    assert_eq!(version_2.parse::<OnionAddress>(), Err(AddressError::UnsupportedVersion(2)));
+
+    assert_eq!(encoded.parse::<OnionAddress>(), Err(AddressError::MissingSuffix));
+    assert_eq!(format!("{encoded}.onion").parse::<OnionAddress>(), Err(AddressError::MissingSuffix));
+    assert_eq!(format!("www.{text}").parse::<OnionAddress>(), Err(AddressError::InvalidLength(60)));
+    assert_eq!(format!("{}.freedom", &encoded[1..]).parse::<OnionAddress>(), Err(AddressError::InvalidLength(55)));
+    assert_eq!(format!("1{}.freedom", &encoded[1..]).parse::<OnionAddress>(), Err(AddressError::InvalidCharacter('1')));
+    assert_eq!(format!("={}.freedom", &encoded[1..]).parse::<OnionAddress>(), Err(AddressError::InvalidCharacter('=')));
+    assert_eq!(format!("é{}.freedom", &encoded[1..]).parse::<OnionAddress>(), Err(AddressError::InvalidCharacter('é')));
+}
+
+fn service_addr() -> SocketAddr {
+    "10.200.0.1:5000".parse().unwrap()
+}
+
+/// Delivers cells between a client node, a service node (at `service_addr()`) and the relays until the network is
+/// quiet, returning the events of each node and what relays delivered
+fn run_nodes(client: &mut Node, service: &mut Node, relays: &mut HashMap<SocketAddr, RelayCircuits>) -> (Vec<NodeEvent>, Vec<NodeEvent>, Delivered) {
+    let mut queue = VecDeque::new();
+    let mut delivered = Vec::new();
+    loop {
+        queue.extend(client.take_outgoing().into_iter().map(|(to, cell)| (client_addr(), to, cell)));
+        queue.extend(service.take_outgoing().into_iter().map(|(to, cell)| (service_addr(), to, cell)));
+        let Some((from, to, cell)) = queue.pop_front() else {
+            return (client.take_events(), service.take_events(), delivered);
+        };
+        if to == client_addr() {
+            client.on_cell(from, cell).unwrap();
+            continue;
+        }
+        if to == service_addr() {
+            service.on_cell(from, cell).unwrap();
+            continue;
+        }
+        for action in relays.get_mut(&to).unwrap().on_cell(from, cell).unwrap() {
+            match action {
+                RelayAction::Send(next, cell) => queue.push_back((to, next, cell)),
+                RelayAction::Deliver(link, cell) => delivered.push((to, link, cell)),
+            }
+        }
+    }
+}
+
+/// Runs `run_nodes` until both nodes are quiet, playing the service's host: it keeps its introduction points and joins
+/// the clients introduced to it. Returns the other events of each node, and whether any relay delivered a cell.
+fn run_service(
+    client: &mut Node,
+    service: &mut Node,
+    points: &mut IntroPoints,
+    relays: &mut HashMap<SocketAddr, RelayCircuits>,
+    selector: &PathSelector,
+    rng: &mut StdRng
+) -> (Vec<NodeEvent>, Vec<NodeEvent>, bool) {
+    let (mut client_events, mut service_events, mut delivered) = (Vec::new(), Vec::new(), false);
+    loop {
+        let (from_client, from_service, cells) = run_nodes(client, service, relays);
+        if from_client.is_empty() && from_service.is_empty() {
+            return (client_events, service_events, delivered);
+        }
+        client_events.extend(from_client);
+        delivered |= !cells.is_empty();
+        for event in from_service {
+            match event {
+                NodeEvent::Circuit(CircuitEvent::Introduced { introduce, .. }) => {
+                    let accepted = points.accept(&introduce).unwrap();
+                    let request = PathRequest { length: 1, exclude: vec![accepted.rendezvous.node_id], ..Default::default() };
+                    let path = selector.select_path(&request, rng).unwrap();
+                    service.circuits_mut().open_rendezvous(path, accepted, 1_000).unwrap();
+                }
+                NodeEvent::Circuit(event) => {
+                    points.on_event(service.circuits_mut(), &event);
+                }
+                event => service_events.push(event),
+            }
+        }
+    }
+}
+
+/// Integration test: A client reaches a hidden service from its onion address alone and exchanges data with it both ways
+/// over an end-to-end encrypted stream, which no relay gets to deliver, until either end closes it
+#[test]
+fn test_node_connect() {
+    let now = 1_000;
+    let mut rng = StdRng::seed_from_u64(17);
+    let (mut relays, selector) = relay_network(8, 0);
+    let identity = ed25519_dalek::SigningKey::from_bytes(&[24u8; 32]);
+    let address = OnionAddress::new(identity.verifying_key());
+    let mut client = Node::new();
+    let mut service = Node::new().with_service(identity.clone());
+
+    // The service sets up its introduction points and publishes them
+    let mut points = IntroPoints::new(2);
+    points.maintain(service.circuits_mut(), &selector, &mut rng, now);
+    run_service(&mut client, &mut service, &mut points, &mut relays, &selector, &mut rng);
+    let descriptor = HsDescriptor::new_signed(&identity, &points.established(service.circuits()), 1, now).unwrap().to_bytes();
+
+    // The client only knows the address: its host fetches the descriptor
+    let connect = client.connect(&address, 80, now);
+    let request = HsDescriptor::fetch_request(&identity.verifying_key(), now);
+    assert_eq!(client.take_events(), vec![NodeEvent::FetchDescriptor { connect, request }]);
+    client.on_descriptor(connect, &descriptor, &selector, &mut rng, now).unwrap();
+    assert!(matches!(client.on_descriptor(connect, &descriptor, &selector, &mut rng, now), Err(NodeError::UnknownConnect(_))));
+
+    let (client_events, service_events, delivered) = run_service(&mut client, &mut service, &mut points, &mut relays, &selector, &mut rng);
+    let [NodeEvent::Connected { connect: connected, stream }] = client_events[..] else {
+        panic!("expected the connection to complete, got {client_events:?}");
+    };
+    let [NodeEvent::Accepted { stream: accepted, port: 80 }] = service_events[..] else {
+        panic!("expected the service to accept a stream, got {service_events:?}");
+    };
+    assert_eq!(connected, connect);
+    assert!(client.is_open(stream) && service.is_open(accepted));
+    assert_eq!(client.connecting(), 0);
+
+    // A request, then a reply larger than the windows and buffers of every layer
+    client.stream(stream).write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let (_, service_events, _) = run_service(&mut client, &mut service, &mut points, &mut relays, &selector, &mut rng);
+    assert_eq!(service_events, vec![NodeEvent::Readable(accepted)]);
+    let mut buf = [0u8; 64];
+    let count = service.stream(accepted).read(&mut buf).unwrap();
+    assert_eq!(&buf[..count], b"GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(service.stream(accepted).read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    let reply: Vec<u8> = (0..400_000u32).map(|i| (i % 251) as u8).collect();
+    let (mut sent, mut received) = (0, Vec::new());
+    let mut buf = vec![0u8; 32 * 1024];
+    let mut relayed = delivered;
+    while received.len() < reply.len() {
+        sent += service.write(accepted, &reply[sent..]).unwrap();
+        let (_, _, delivered) = run_service(&mut client, &mut service, &mut points, &mut relays, &selector, &mut rng);
+        relayed |= delivered;
+        loop {
+            match client.stream(stream).read(&mut buf) {
+                Ok(count) => received.extend_from_slice(&buf[..count]),
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::WouldBlock);
+                    break;
+                }
+            }
+        }
+    }
+    assert!(received == reply);
+    assert!(!relayed, "every cell is for the other end");
+
+    // The client closing the stream closes the service's end
+    assert!(client.close(stream));
+    let (_, service_events, _) = run_service(&mut client, &mut service, &mut points, &mut relays, &selector, &mut rng);
+    assert_eq!(service_events, vec![NodeEvent::Closed(accepted)]);
+    assert_eq!(service.stream(accepted).read(&mut buf).unwrap(), 0);
+    assert!(!service.is_open(accepted));
+    assert_eq!(client.write(stream, b"late").unwrap_err().to_string(), "Unknown stream");
+
+    // A service whose descriptor never comes is given up on
+    let unknown = OnionAddress::new(ed25519_dalek::SigningKey::from_bytes(&[25u8; 32]).verifying_key());
+    let lost = client.connect(&unknown, 80, now);
+    client.take_events();
+    client.expire(now + CONNECT_TIMEOUT_SECS - 1);
+    assert!(client.take_events().is_empty());
+    client.expire(now + CONNECT_TIMEOUT_SECS);
+    assert_eq!(client.take_events(), vec![NodeEvent::ConnectFailed(lost, ConnectFailure::Timeout)]);
+    assert_eq!(client.connecting(), 0);
+}
+
+/// A SOCKS5 reply with the unspecified bound address the server gives
+fn socks_reply(reply: SocksReply) -> Vec<u8> {
+    vec![5, reply as u8, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+/// A SOCKS5 CONNECT request for a host name
+fn socks_connect(host: &str, port: u16) -> Vec<u8> {
+    [&[5, 1, 0, 3, host.len() as u8][..], host.as_bytes(), &port.to_be_bytes()].concat()
+}
+
+/// Unit test: The SOCKS server negotiates methods and credentials in fragments, sends `.freedom` hosts to `Node::connect`
+/// and refuses what it can't serve with the matching reply
+#[test]
+fn test_socks_negotiation() {
+    let now = 1_000;
+    let mut node = Node::new();
+    let mut pool = CircuitPool::new(PoolConfig::default());
+    let mut server = SocksServer::new(SocksServerConfig::default());
+    let address = OnionAddress::new(ed25519_dalek::SigningKey::from_bytes(&[25u8; 32]).verifying_key());
+
+    // Offered credentials are taken (they isolate), however the messages are split
+    let client = server.accept(now);
+    server.on_data(client, &[5], &mut node, &mut pool, now);
+    assert!(server.take_outgoing().is_empty());
+    server.on_data(client, &[2, 0, 2, 1, 5], &mut node, &mut pool, now);
+    server.on_data(client, b"alice\x06secret", &mut node, &mut pool, now);
+    assert_eq!(server.take_outgoing(), vec![
+        SocksCommand::Write { client, bytes: vec![5, 2] },
+        SocksCommand::Write { client, bytes: vec![1, 0] }
+    ]);
+
+    // A hidden service is reached by the node, whose host fetches the descriptor; its failure is the client's
+    server.on_data(client, &socks_connect(&address.to_string().to_uppercase(), 80), &mut node, &mut pool, now);
+    assert!(matches!(node.take_events()[..], [NodeEvent::FetchDescriptor { .. }]));
+    assert!(server.take_outgoing().is_empty());
+    node.expire(now + CONNECT_TIMEOUT_SECS);
+    let events = node.take_events();
+    let [NodeEvent::ConnectFailed(_, ConnectFailure::Timeout)] = events[..] else {
+        panic!("Expected the connection to time out, got {events:?}");
+    };
+    assert!(server.on_node_event(&events[0], &mut node, &mut pool));
+    assert_eq!(server.take_outgoing(), vec![
+        SocksCommand::Write { client, bytes: socks_reply(SocksReply::TtlExpired) },
+        SocksCommand::Close(client)
+    ]);
+    assert!(server.is_empty());
+
+    // Only CONNECT, only valid onion addresses, only methods we know
+    let refused = |server: &mut SocksServer, node: &mut Node, pool: &mut CircuitPool, request: Vec<u8>| {
+        let client = server.accept(now);
+        server.on_data(client, &[&[5, 1, 0][..], &request].concat(), node, pool, now);
+        let outgoing = server.take_outgoing();
+        assert_eq!(outgoing[0], SocksCommand::Write { client, bytes: vec![5, 0] });
+        assert_eq!(outgoing[2..], [SocksCommand::Close(client)]);
+        let SocksCommand::Write { ref bytes, .. } = outgoing[1] else {
+            panic!("Expected a reply, got {outgoing:?}");
+        };
+        SocksReply::try_from(bytes[1]).unwrap()
+    };
+    let bind = [&[5, 2, 0, 1, 10, 0, 0, 1][..], &80u16.to_be_bytes()].concat();
+    assert_eq!(refused(&mut server, &mut node, &mut pool, bind), SocksReply::CommandNotSupported);
+    assert_eq!(refused(&mut server, &mut node, &mut pool, socks_connect("a.freedom", 80)), SocksReply::HostUnreachable);
+    let client = server.accept(now);
+    server.on_data(client, &[5, 1, 1], &mut node, &mut pool, now);
+    assert_eq!(server.take_outgoing(), vec![SocksCommand::Write { client, bytes: vec![5, 0xFF] }, SocksCommand::Close(client)]);
+
+    // Without exits, other hosts are not allowed
+    let mut onion_only = SocksServer::new(SocksServerConfig { allow_exit: false, ..Default::default() });
+    assert_eq!(refused(&mut onion_only, &mut node, &mut pool, socks_connect("example.org", 443)), SocksReply::NotAllowed);
+    assert!(server.is_empty() && onion_only.is_empty());
+
+    // A client stuck negotiating is dropped, one waiting for its stream told why
+    let (idle, waiting) = (server.accept(now), server.accept(now));
+    server.on_data(waiting, &[&[5, 1, 0][..], &socks_connect("example.org", 443)].concat(), &mut node, &mut pool, now);
+    server.take_outgoing();
+    server.expire(now + CONNECT_TIMEOUT_SECS - 1, &mut node);
+    assert_eq!(server.len(), 2);
+    server.expire(now + CONNECT_TIMEOUT_SECS, &mut node);
+    let mut outgoing = server.take_outgoing();
+    outgoing.sort_by_key(|command| matches!(command, SocksCommand::Close(client) if *client == idle));
+    assert_eq!(outgoing, vec![
+        SocksCommand::Write { client: waiting, bytes: socks_reply(SocksReply::TtlExpired) },
+        SocksCommand::Close(waiting),
+        SocksCommand::Close(idle)
+    ]);
+    assert!(server.is_empty());
+}
+
+/// Integration test: Applications reach hosts out of an exit through the SOCKS port, waiting for a pooled circuit if none
+/// is ready; clients with the same credentials share a circuit, others never do
+#[test]
+fn test_socks_exit_streams() {
+    let (relays, selector) = relay_network(6, 2);
+    let mut relays: HashMap<SocketAddr, RelayCircuits> = relays
+        .into_iter()
+        .map(|(addr, relay)| (addr, relay.with_exit_policy(ExitPolicy::default())))
+        .collect();
+    let mut rng = StdRng::seed_from_u64(21);
+    let mut node = Node::new();
+    let mut pool = CircuitPool::new(PoolConfig { targets: vec![(CircuitPurpose::Exit, 2)], ..Default::default() });
+    let mut server = SocksServer::new(SocksServerConfig::default());
+
+    // Asked before any circuit is ready, the request waits, and so does the data sent along
+    let web = server.accept(1_000);
+    let request = [&[5, 1, 0][..], &socks_connect("example.org", 80), b"GET /"].concat();
+    server.on_data(web, &request, &mut node, &mut pool, 1_000);
+    assert_eq!(server.take_outgoing(), vec![SocksCommand::Write { client: web, bytes: vec![5, 0] }]);
+    pool.maintain(node.circuits_mut(), &selector, &mut rng, 1_000);
+    let (events, _) = run(node.circuits_mut(), &mut relays);
+    for event in events {
+        pool.on_event(node.circuits_mut(), &event, 1_001);
+        assert!(!server.on_node_event(&NodeEvent::Circuit(event), &mut node, &mut pool));
+    }
+    assert_eq!(pool.ready(CircuitPurpose::Exit), 1);
+
+    // The exit connects the stream: the client is told, and what it sent goes out
+    let (_, delivered) = run(node.circuits_mut(), &mut relays);
+    let [(exit, link, ref begin)] = delivered[..] else {
+        panic!("Expected a stream request at the exit, got {delivered:?}");
+    };
+    let mut exit_streams = StreamSet::new();
+    let Some(StreamEvent::Requested { stream, target }) = exit_streams.on_cell(begin.clone()).unwrap() else {
+        panic!("Expected a stream request");
+    };
+    assert_eq!(target, StreamTarget::new("example.org", 80));
+    exit_streams.connected(stream).unwrap();
+    for event in exit_reply(node.circuits_mut(), &mut relays, exit, link, &mut exit_streams) {
+        assert!(server.on_node_event(&NodeEvent::Circuit(event), &mut node, &mut pool));
+    }
+    assert_eq!(server.take_outgoing(), vec![SocksCommand::Write { client: web, bytes: socks_reply(SocksReply::Succeeded) }]);
+    let (_, delivered) = run(node.circuits_mut(), &mut relays);
+    for (_, _, cell) in delivered {
+        exit_streams.on_cell(cell).unwrap();
+    }
+    let mut buf = [0u8; 64];
+    let count = exit_streams.read(stream, &mut buf);
+    assert_eq!(&buf[..count], b"GET /");
+
+    // The answer reaches the client, and the exit closing the stream closes it
+    exit_streams.write(stream, b"200 OK").unwrap();
+    exit_streams.end(stream, EndReason::Done);
+    for event in exit_reply(node.circuits_mut(), &mut relays, exit, link, &mut exit_streams) {
+        assert!(server.on_node_event(&NodeEvent::Circuit(event), &mut node, &mut pool));
+    }
+    assert_eq!(server.take_outgoing(), vec![SocksCommand::Write { client: web, bytes: b"200 OK".to_vec() }, SocksCommand::Close(web)]);
+
+    // The same credentials share the other circuit; new ones wait for a third
+    let mut links = Vec::new();
+    for password in [&b"one"[..], b"one", b"two"] {
+        let client = server.accept(1_010);
+        let auth = [&[5, 1, 2, 1, 5][..], b"alice", &[password.len() as u8], password, &socks_connect("example.org", 80)].concat();
+        server.on_data(client, &auth, &mut node, &mut pool, 1_010);
+        let (_, delivered) = run(node.circuits_mut(), &mut relays);
+        links.extend(delivered.into_iter().map(|(_, link, _)| link));
+    }
+    assert_eq!(links.len(), 2);
+    assert_eq!(links[0], links[1]);
+    assert_ne!(links[0], link);
+    assert_eq!(server.len(), 3);
+}
+
+/// A client and a service `FreedomStream` that completed their handshake
+fn handshaken(identity: &ed25519_dalek::SigningKey) -> (FreedomStream, FreedomStream) {
+    let mut client = FreedomStream::connect(&OnionAddress::new(identity.verifying_key()));
+    let mut service = FreedomStream::accept(identity);
+    service.on_data(&client.take_outgoing()).unwrap();
+    client.on_data(&service.take_outgoing()).unwrap();
+    (client, service)
+}
+
+/// Unit test: The end-to-end handshake completes only with the holder of the address's key, and records tampered with,
+/// replayed or oversized are refused
+#[test]
+fn test_freedom_stream() {
+    let identity = ed25519_dalek::SigningKey::from_bytes(&[26u8; 32]);
+    let address = OnionAddress::new(identity.verifying_key());
+    let mut client = FreedomStream::connect(&address);
+    let mut service = FreedomStream::accept(&identity);
+    assert_eq!(client.write(b"early").unwrap(), 0);
+
+    // Halves of the handshake may arrive in pieces, and records right behind them
+    for byte in client.take_outgoing() {
+        assert!(!service.is_established());
+        service.on_data(&[byte]).unwrap();
+    }
+    assert!(service.is_established());
+    let answer = service.take_outgoing();
+    assert_eq!(answer.len(), 32 + 64);
+    assert_eq!(service.write(b"hello").unwrap(), 5);
+    assert_eq!(client.on_data(&[answer, service.take_outgoing()].concat()).unwrap(), 5);
+    let mut buf = [0u8; 16];
+    let count = client.read(&mut buf);
+    assert_eq!(&buf[..count], b"hello");
+
+    // Long writes are split into records
+    let long = vec![7u8; MAX_RECORD_SIZE + 1];
+    assert_eq!(client.write(&long).unwrap(), long.len());
+    let bytes = client.take_outgoing();
+    assert_eq!(bytes.len(), long.len() + 2 * (2 + SESSION_OVERHEAD));
+    assert_eq!(service.on_data(&bytes[..100]).unwrap(), 0);
+    assert_eq!(service.on_data(&bytes[100..]).unwrap(), long.len());
+
+    // A record can't be replayed or altered
+    let (mut client, mut service) = handshaken(&identity);
+    client.write(b"once").unwrap();
+    let record = client.take_outgoing();
+    service.on_data(&record).unwrap();
+    assert!(matches!(service.on_data(&record), Err(E2eError::Crypto(CryptoError::ReplayedMessage))));
+    let (mut client, mut service) = handshaken(&identity);
+    client.write(b"altered").unwrap();
+    let mut record = client.take_outgoing();
+    *record.last_mut().unwrap() ^= 1;
+    assert!(matches!(service.on_data(&record), Err(E2eError::Crypto(CryptoError::DecryptionError))));
+    let (_, mut service) = handshaken(&identity);
+    assert!(matches!(service.on_data(&[0xff, 0xff]), Err(E2eError::RecordTooLarge(0xffff))));
+
+    // Anyone else answering for the address is caught
+    let impostor = ed25519_dalek::SigningKey::from_bytes(&[27u8; 32]);
+    let mut client = FreedomStream::connect(&address);
+    let mut service = FreedomStream::accept(&impostor);
+    service.on_data(&client.take_outgoing()).unwrap();
+    assert!(matches!(client.on_data(&service.take_outgoing()), Err(E2eError::InvalidSignature)));
+    assert!(!client.is_established());
+}
@@ -0,0 +1,152 @@
+use std::collections::{ HashMap, VecDeque };
+
+/// Timeout used until enough builds were observed (and after the network seems to have changed)
+pub const INITIAL_BUILD_TIMEOUT_MS: u64 = 60_000;
+/// The estimate never goes below this
+pub const MIN_BUILD_TIMEOUT_MS: u64 = 500;
+/// Completed builds needed before the timeout adapts
+pub const MIN_BUILD_SAMPLES: usize = 20;
+/// Builds remembered; older ones are forgotten so the estimate follows the network
+pub const MAX_BUILD_SAMPLES: usize = 1000;
+/// Share of builds expected to complete within the timeout
+pub const BUILD_TIMEOUT_QUANTILE: f64 = 0.8;
+
+/// Histogram bin width for finding the most common build times
+const BIN_WIDTH_MS: u64 = 10;
+/// Most populated bins averaged into the Pareto scale parameter
+const MODE_BINS: usize = 10;
+/// Recent builds checked for a sudden run of timeouts
+const RECENT_BUILDS: usize = 20;
+/// Timeouts among the recent builds that mean the estimate no longer fits the network
+const RECENT_TIMEOUT_LIMIT: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuildOutcome {
+    Completed(u64),
+    /// Abandoned at the timeout in effect then: it would have taken at least that long
+    TimedOut(u64),
+}
+
+/// Adaptive circuit build timeout, after Tor's circuit build time estimation: build times
+/// follow a Pareto distribution, so the timeout is set at the quantile of the fitted
+/// distribution where most builds have completed. Slow builds past it are more likely to go
+/// through a congested relay than to finish soon, and are better abandoned and rebuilt.
+///
+/// Build times are measured by the host (the core has no millisecond clock) and reported with
+/// `record_build`; builds abandoned at the timeout with `record_timeout`.
+#[derive(Debug, Clone)]
+pub struct BuildTimeEstimator {
+    outcomes: VecDeque<BuildOutcome>,
+    timeout_ms: u64,
+}
+
+impl Default for BuildTimeEstimator {
+    fn default() -> Self {
+        Self { outcomes: VecDeque::new(), timeout_ms: INITIAL_BUILD_TIMEOUT_MS }
+    }
+}
+
+impl BuildTimeEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A circuit was built in `elapsed_ms`.
+    pub fn record_build(&mut self, elapsed_ms: u64) {
+        self.push(BuildOutcome::Completed(elapsed_ms.max(1)));
+    }
+
+    /// A circuit was abandoned at the current timeout.
+    pub fn record_timeout(&mut self) {
+        self.push(BuildOutcome::TimedOut(self.timeout_ms));
+
+        let recent = self.outcomes
+            .iter()
+            .rev()
+            .take(RECENT_BUILDS)
+            .filter(|o| matches!(o, BuildOutcome::TimedOut(_)))
+            .count();
+        if recent >= RECENT_TIMEOUT_LIMIT {
+            // Most builds time out: the network changed (or we lost connectivity); start over
+            log::info!("{recent} of the last {RECENT_BUILDS} circuit builds timed out, resetting the build timeout");
+            self.outcomes.clear();
+            self.timeout_ms = INITIAL_BUILD_TIMEOUT_MS;
+        }
+    }
+
+    /// Current build timeout in milliseconds
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms
+    }
+
+    /// Current build timeout in whole seconds, rounded up, for deadlines on the seconds clock
+    pub fn timeout_secs(&self) -> u64 {
+        self.timeout_ms.div_ceil(1000)
+    }
+
+    /// Completed builds currently informing the estimate
+    pub fn samples(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|o| matches!(o, BuildOutcome::Completed(_)))
+            .count()
+    }
+
+    fn push(&mut self, outcome: BuildOutcome) {
+        if self.outcomes.len() == MAX_BUILD_SAMPLES {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(outcome);
+
+        if let Some(timeout) = self.estimate() {
+            self.timeout_ms = timeout;
+        }
+    }
+
+    /// Fits a Pareto distribution to the outcomes and returns its `BUILD_TIMEOUT_QUANTILE`
+    /// quantile, or None while there are too few completed builds.
+    fn estimate(&self) -> Option<u64> {
+        let completed: Vec<u64> = self.outcomes
+            .iter()
+            .filter_map(|o| match o {
+                BuildOutcome::Completed(ms) => Some(*ms),
+                BuildOutcome::TimedOut(_) => None,
+            })
+            .collect();
+        if completed.len() < MIN_BUILD_SAMPLES {
+            return None;
+        }
+
+        // Scale (Xm): the mode of the build times, averaged over the busiest bins
+        let mut bins: HashMap<u64, u64> = HashMap::new();
+        for ms in &completed {
+            *bins.entry(ms / BIN_WIDTH_MS).or_default() += 1;
+        }
+        let mut busiest: Vec<(u64, u64)> = bins.into_iter().collect();
+        busiest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        busiest.truncate(MODE_BINS);
+        let weight: u64 = busiest.iter().map(|(_, count)| count).sum();
+        let scale = busiest
+            .iter()
+            .map(|(bin, count)| ((bin * BIN_WIDTH_MS + BIN_WIDTH_MS / 2) * count) as f64)
+            .sum::<f64>() / weight as f64;
+
+        // Shape (alpha), by maximum likelihood; timed-out builds are censored at their timeout
+        let log_sum: f64 = self.outcomes
+            .iter()
+            .map(|o| {
+                let ms = match o {
+                    BuildOutcome::Completed(ms) | BuildOutcome::TimedOut(ms) => *ms,
+                };
+                (ms as f64).max(scale).ln() - scale.ln()
+            })
+            .sum();
+        if log_sum <= 0.0 {
+            return None;
+        }
+        let shape = completed.len() as f64 / log_sum;
+
+        let timeout = scale / (1.0 - BUILD_TIMEOUT_QUANTILE).powf(1.0 / shape);
+        Some((timeout.ceil() as u64).clamp(MIN_BUILD_TIMEOUT_MS, INITIAL_BUILD_TIMEOUT_MS))
+    }
+}
@@ -0,0 +1,108 @@
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+/// Errors shared by the binary payload decoders.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CodecError {
+    #[error("Unexpected end of buffer: needed {needed} bytes at offset {offset}")]
+    UnexpectedEof {
+        offset: usize,
+        needed: usize,
+    },
+    #[error("Invalid field: {0}")]
+    InvalidField(&'static str),
+    #[error("Trailing bytes after payload: {0}")]
+    TrailingBytes(usize),
+}
+
+/// Bounds-checked cursor over a received payload.
+/// All multi-byte integers are read Big-Endian, matching the C# BinaryPrimitives usage.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.offset
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        let eof = CodecError::UnexpectedEof { offset: self.offset, needed: len };
+        let end = self.offset.checked_add(len).ok_or(eof.clone())?;
+        let slice = self.bytes.get(self.offset..end).ok_or(eof)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    pub fn take_array<const N: usize>(&mut self) -> Result<[u8; N], CodecError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, CodecError> {
+        Ok(u16::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CodecError> {
+        Ok(u64::from_be_bytes(self.take_array()?))
+    }
+
+    /// Returns everything that has not been consumed yet
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        rest
+    }
+
+    /// Fails if the payload has unread bytes (strict decoding)
+    pub fn finish(&self) -> Result<(), CodecError> {
+        match self.remaining() {
+            0 => Ok(()),
+            n => Err(CodecError::TrailingBytes(n)),
+        }
+    }
+}
+
+/// Writes an endpoint in the contact format used by the C# DHT messages.
+/// Format: [IP_Len (1 byte)] [IP (4 or 16 bytes)] [Port (2 bytes)]
+pub fn write_socket_addr(out: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(16);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Reads an endpoint written by `write_socket_addr`
+pub fn read_socket_addr(reader: &mut Reader<'_>) -> Result<SocketAddr, CodecError> {
+    let ip = match reader.u8()? {
+        4 => IpAddr::V4(Ipv4Addr::from(reader.take_array::<4>()?)),
+        16 => IpAddr::V6(Ipv6Addr::from(reader.take_array::<16>()?)),
+        _ => {
+            return Err(CodecError::InvalidField("ip length"));
+        }
+    };
+    let port = reader.u16()?;
+    Ok(SocketAddr::new(ip, port))
+}
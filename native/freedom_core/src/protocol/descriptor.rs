@@ -0,0 +1,227 @@
+use ed25519_dalek::{ Signature, VerifyingKey };
+use std::net::SocketAddr;
+use x25519_dalek::PublicKey as X25519PublicKey;
+
+use super::codec::{ self, CodecError, Reader };
+use crate::clock::unix_now;
+use crate::crypto::identity::{ self, NodeIdentity };
+use crate::dht::node_id::NodeId;
+use crate::onion::exit::{ ExitPolicy, MAX_EXIT_RULES };
+
+// Prefix of the signed message, so a descriptor signature can't be replayed as another object
+const DESCRIPTOR_SIGNING_LABEL: &[u8] = b"FreedomNode-Descriptor-v1";
+const MAX_ADDRESSES: usize = 16;
+
+/// Roles a node offers to the network, advertised in its descriptor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const RELAY: Capabilities = Capabilities(1 << 0);
+    pub const EXIT: Capabilities = Capabilities(1 << 1);
+    pub const DIRECTORY: Capabilities = Capabilities(1 << 2);
+    pub const STORAGE: Capabilities = Capabilities(1 << 3);
+    pub const INTRO_POINT: Capabilities = Capabilities(1 << 4);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DescriptorError {
+    #[error("Malformed descriptor: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Too many addresses: {0} (max {MAX_ADDRESSES})")]
+    TooManyAddresses(usize),
+    #[error("Too many exit policy rules: {0} (max {MAX_EXIT_RULES})")]
+    TooManyExitRules(usize),
+    #[error("Invalid identity key bytes")]
+    InvalidIdentityKey,
+    #[error("NodeId does not match the identity key")]
+    NodeIdMismatch,
+    #[error("Signature verification failed")]
+    VerificationFailed,
+    #[error("Descriptor expired at {expires_at} (now {now})")]
+    Expired {
+        expires_at: u64,
+        now: u64,
+    },
+}
+
+/// Self-certifying description of a node: how to reach it, what it offers, and until when.
+/// Published by the DHT and directory layers, signed by the node's identity key.
+#[derive(Debug, Clone)]
+pub struct NodeDescriptor {
+    pub node_id: NodeId,
+    pub identity_key: VerifyingKey,
+    pub onion_key: X25519PublicKey,
+    pub addresses: Vec<SocketAddr>,
+    pub capabilities: Capabilities,
+    /// Streams the node opens as an exit; rejects everything unless it offers EXIT
+    pub exit_policy: ExitPolicy,
+    pub bandwidth: u32, // Advertised bandwidth in bytes/sec
+    pub published_at: u64, // Seconds since UNIX epoch
+    pub expires_at: u64, // Seconds since UNIX epoch
+    pub signature: Signature,
+}
+
+impl NodeDescriptor {
+    /// Builds and signs a descriptor for the local node, valid for `ttl_secs` from `published_at`.
+    /// Exits advertise the default exit policy; see `new_signed_with_exit_policy`.
+    pub fn new_signed(
+        identity: &NodeIdentity,
+        addresses: Vec<SocketAddr>,
+        capabilities: Capabilities,
+        bandwidth: u32,
+        published_at: u64,
+        ttl_secs: u64
+    ) -> Result<Self, DescriptorError> {
+        let exit_policy = if capabilities.contains(Capabilities::EXIT) { ExitPolicy::default() } else { ExitPolicy::reject_all() };
+        Self::new_signed_with_exit_policy(identity, addresses, capabilities, exit_policy, bandwidth, published_at, ttl_secs)
+    }
+
+    /// Same as `new_signed`, advertising `exit_policy`.
+    pub fn new_signed_with_exit_policy(
+        identity: &NodeIdentity,
+        addresses: Vec<SocketAddr>,
+        capabilities: Capabilities,
+        exit_policy: ExitPolicy,
+        bandwidth: u32,
+        published_at: u64,
+        ttl_secs: u64
+    ) -> Result<Self, DescriptorError> {
+        if addresses.len() > MAX_ADDRESSES {
+            return Err(DescriptorError::TooManyAddresses(addresses.len()));
+        }
+        if exit_policy.rules().len() > MAX_EXIT_RULES {
+            return Err(DescriptorError::TooManyExitRules(exit_policy.rules().len()));
+        }
+
+        let identity_key = identity.identity_keypair.verifying_key();
+        let mut descriptor = Self {
+            node_id: NodeId::from_identity_key(&identity_key),
+            identity_key,
+            onion_key: X25519PublicKey::from(&identity.onion_secret),
+            addresses,
+            capabilities,
+            exit_policy,
+            bandwidth,
+            published_at,
+            expires_at: published_at.saturating_add(ttl_secs),
+            signature: Signature::from_bytes(&[0u8; 64]),
+        };
+
+        descriptor.signature = identity.sign(&descriptor.signed_message());
+        Ok(descriptor)
+    }
+
+    /// Serializes the descriptor.
+    /// Format: [NodeId (32)] [IdentityKey (32)] [OnionKey (32)] [Capabilities (4)] [Bandwidth (4)]
+    ///         [PublishedAt (8)] [ExpiresAt (8)] [AddrCount (1)] + N * [IP_Len (1) | IP | Port (2)]
+    ///         [ExitPolicy] [Signature (64)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.body_bytes();
+        out.extend_from_slice(&self.signature.to_bytes());
+        out
+    }
+
+    /// Parses a descriptor. Does not check the signature or expiry; call `verify` for that.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DescriptorError> {
+        let mut reader = Reader::new(bytes);
+
+        let node_id = NodeId::from_bytes(reader.take_array()?);
+        let identity_key = VerifyingKey::from_bytes(&reader.take_array()?).map_err(
+            |_| DescriptorError::InvalidIdentityKey
+        )?;
+        let onion_key = X25519PublicKey::from(reader.take_array::<32>()?);
+        let capabilities = Capabilities(reader.u32()?);
+        let bandwidth = reader.u32()?;
+        let published_at = reader.u64()?;
+        let expires_at = reader.u64()?;
+
+        let count = reader.u8()? as usize;
+        if count > MAX_ADDRESSES {
+            return Err(DescriptorError::TooManyAddresses(count));
+        }
+        let addresses = (0..count)
+            .map(|_| codec::read_socket_addr(&mut reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        let exit_policy = ExitPolicy::read(&mut reader)?;
+
+        let signature = Signature::from_bytes(&reader.take_array()?);
+        reader.finish()?;
+
+        Ok(Self {
+            node_id,
+            identity_key,
+            onion_key,
+            addresses,
+            capabilities,
+            exit_policy,
+            bandwidth,
+            published_at,
+            expires_at,
+            signature,
+        })
+    }
+
+    /// Checks the NodeId binding, the signature, and that the descriptor has not expired at `now`.
+    pub fn verify(&self, now: u64) -> Result<(), DescriptorError> {
+        if NodeId::from_identity_key(&self.identity_key) != self.node_id {
+            return Err(DescriptorError::NodeIdMismatch);
+        }
+
+        identity
+            ::verify(&self.identity_key, &self.signed_message(), &self.signature)
+            .map_err(|_| DescriptorError::VerificationFailed)?;
+
+        if self.is_expired(now) {
+            return Err(DescriptorError::Expired { expires_at: self.expires_at, now });
+        }
+
+        Ok(())
+    }
+
+    /// Same as `verify`, using the system clock
+    pub fn verify_now(&self) -> Result<(), DescriptorError> {
+        self.verify(unix_now())
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    fn signed_message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(DESCRIPTOR_SIGNING_LABEL.len() + 160);
+        message.extend_from_slice(DESCRIPTOR_SIGNING_LABEL);
+        message.extend_from_slice(&self.body_bytes());
+        message
+    }
+
+    fn body_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(160);
+        out.extend_from_slice(self.node_id.as_bytes());
+        out.extend_from_slice(self.identity_key.as_bytes());
+        out.extend_from_slice(self.onion_key.as_bytes());
+        out.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        out.extend_from_slice(&self.bandwidth.to_be_bytes());
+        out.extend_from_slice(&self.published_at.to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+
+        out.push(self.addresses.len() as u8);
+        for addr in &self.addresses {
+            codec::write_socket_addr(&mut out, addr);
+        }
+        self.exit_policy.write(&mut out);
+        out
+    }
+}
@@ -1,8 +1,8 @@
 use crc32fast::Hasher;
-use std::convert::TryFrom;
 
 // Protocol Constants
 pub const HEADER_SIZE: usize = 16;
+pub const PROTOCOL_VERSION: u8 = 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -18,6 +18,9 @@ pub enum MessageType {
     Put = 0x0A,
     GetValueReq = 0x0B,
     GetValueRes = 0x0C,
+    Error = 0x0D,
+    HolePunch = 0x0E,
+    Relay = 0x0F,
     // Add a fallback for unknown types to handle forward compatibility safely
     Unknown = 0xFF,
 }
@@ -36,6 +39,9 @@ impl From<u8> for MessageType {
             0x0A => MessageType::Put,
             0x0B => MessageType::GetValueReq,
             0x0C => MessageType::GetValueRes,
+            0x0D => MessageType::Error,
+            0x0E => MessageType::HolePunch,
+            0x0F => MessageType::Relay,
             _ => MessageType::Unknown,
         }
     }
@@ -91,7 +97,7 @@ impl FixedHeader {
         let checksum = hasher.finalize();
 
         Self::new(
-            1, // version
+            PROTOCOL_VERSION,
             0, // flags
             message_type,
             request_id,
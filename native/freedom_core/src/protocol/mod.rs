@@ -1,3 +1,7 @@
+pub mod codec;
+pub mod descriptor;
 pub mod header;
 pub mod packet;
+// The tests keep their own nested `mod tests` with a glob import of the parent
+#[allow(clippy::module_inception, unused_imports)]
 pub mod tests;
\ No newline at end of file
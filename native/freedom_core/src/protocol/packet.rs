@@ -1,6 +1,9 @@
 use crc32fast::Hasher;
 use super::header::{ MessageType, FixedHeader, HeaderError, HEADER_SIZE };
 
+/// Largest payload accepted unless the host configures another limit (matches the C# listener)
+pub const DEFAULT_MAX_PAYLOAD_LEN: usize = 5 * 1024 * 1024;
+
 #[derive(Debug)]
 pub struct NetworkPacket {
     pub header: FixedHeader,
@@ -14,6 +17,10 @@ pub enum PacketError {
         expected: u32,
         got: usize,
     },
+    #[error("Payload of {len} bytes exceeds the {max} byte limit")] PayloadTooLarge {
+        len: u32,
+        max: usize,
+    },
     #[error(
         "Checksum mismatch: expected {expected:#x}, calculated {calculated:#x}"
     )] ChecksumMismatch {
@@ -45,13 +52,22 @@ impl NetworkPacket {
     /// Parses a packet from raw bytes.
     /// Validates the checksum for CRC32.
     pub fn from_bytes(data: &[u8]) -> Result<Self, PacketError> {
+        Self::from_bytes_limited(data, usize::MAX)
+    }
+
+    /// Same as `from_bytes`, but rejects a header announcing more than `max_payload_len` bytes
+    /// before looking at the payload.
+    pub fn from_bytes_limited(data: &[u8], max_payload_len: usize) -> Result<Self, PacketError> {
         if data.len() < HEADER_SIZE {
-            return Err(PacketError::HeaderError(HeaderError::BufferTooSmall.into()));
+            return Err(PacketError::HeaderError(HeaderError::BufferTooSmall));
         }
 
         // 1. Parse Header
         let header_bytes = &data[0..HEADER_SIZE];
         let header = FixedHeader::from_bytes(header_bytes)?;
+        if header.payload_length as usize > max_payload_len {
+            return Err(PacketError::PayloadTooLarge { len: header.payload_length, max: max_payload_len });
+        }
 
         // 2. Validate payload length
         let payload_len = header.payload_length as usize;
@@ -4,6 +4,8 @@ mod tests {
     use crate::protocol::header::{FixedHeader, MessageType, HEADER_SIZE};
     use crate::protocol::packet::NetworkPacket;
     use crate::crypto::identity::NodeIdentity;
+    use crate::onion::exit::ExitPolicy;
+    use crate::protocol::descriptor::{ Capabilities, DescriptorError, NodeDescriptor };
 
     use super::*;
     use crc32fast::Hasher;
@@ -94,4 +96,36 @@ mod tests {
         assert_eq!(received_handshake.timestamp, timestamp);
     }
 
+    // Integration test: Node descriptor signing, encoding and expiry
+    #[test]
+    fn test_node_descriptor_roundtrip_and_expiry() {
+        let node = NodeIdentity::generate();
+        let addresses = vec!["203.0.113.7:4433".parse().unwrap(), "[2001:db8::1]:4433".parse().unwrap()];
+
+        let descriptor = NodeDescriptor::new_signed(
+            &node,
+            addresses.clone(),
+            Capabilities::RELAY | Capabilities::DIRECTORY,
+            1_000_000,
+            1700000000,
+            3600
+        ).unwrap();
+
+        let decoded = NodeDescriptor::from_bytes(&descriptor.to_bytes()).expect("Failed to parse descriptor");
+        decoded.verify(1700000100).expect("Valid descriptor rejected");
+
+        assert_eq!(decoded.addresses, addresses);
+        assert!(decoded.capabilities.contains(Capabilities::RELAY));
+        assert!(!decoded.capabilities.contains(Capabilities::EXIT));
+        assert_eq!(decoded.exit_policy, ExitPolicy::reject_all());
+
+        // Expired
+        assert!(matches!(decoded.verify(1700003600), Err(DescriptorError::Expired { .. })));
+
+        // Tampered bandwidth breaks the signature
+        let mut tampered = decoded.clone();
+        tampered.bandwidth = 9_999_999;
+        assert!(matches!(tampered.verify(1700000100), Err(DescriptorError::VerificationFailed)));
+    }
+
 }
\ No newline at end of file
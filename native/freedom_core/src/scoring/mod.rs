@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::dht::node_id::NodeId;
+
+/// Something a peer did wrong. Reported by whichever layer noticed it (DHT, transport, onion).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    InvalidSignature,
+    MalformedPacket,
+    Timeout,
+    /// Valid bytes, wrong behaviour (unsolicited responses, bad write tokens, ...)
+    ProtocolViolation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreConfig {
+    pub invalid_signature_penalty: i32,
+    pub malformed_packet_penalty: i32,
+    pub timeout_penalty: i32,
+    pub protocol_violation_penalty: i32,
+    pub success_reward: i32,
+    /// Scores are clamped to [-max_score, max_score], so good behaviour can't bank unlimited credit
+    pub max_score: i32,
+    /// At or below this score a peer is greylisted: kept, but not preferred for routing or circuits
+    pub greylist_threshold: i32,
+    /// At or below this score a peer is banned for `ban_secs`
+    pub ban_threshold: i32,
+    pub ban_secs: u64,
+    /// Greylist peers whose share of timed-out requests exceeds this, once `min_requests` were made
+    pub max_timeout_ratio: f64,
+    pub min_requests: u32,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        Self {
+            invalid_signature_penalty: 50,
+            malformed_packet_penalty: 20,
+            timeout_penalty: 2,
+            protocol_violation_penalty: 10,
+            success_reward: 1,
+            max_score: 100,
+            greylist_threshold: -30,
+            ban_threshold: -100,
+            ban_secs: 60 * 60,
+            max_timeout_ratio: 0.5,
+            min_requests: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Standing {
+    Good,
+    /// Usable as a last resort only
+    Greylisted,
+    /// Refuse connections and drop messages until `until`
+    Banned {
+        until: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerScore {
+    score: i32,
+    successes: u32,
+    timeouts: u32,
+    banned_until: u64,
+}
+
+/// Reputation of every peer we interacted with, keyed by NodeId. One instance is shared by the
+/// DHT, transport and onion layers (behind the host's lock), so misbehaviour seen by one
+/// layer demotes the peer everywhere.
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    config: ScoreConfig,
+    peers: HashMap<NodeId, PeerScore>,
+}
+
+impl PeerScores {
+    pub fn new(config: ScoreConfig) -> Self {
+        Self { config, peers: HashMap::new() }
+    }
+
+    /// The peer answered correctly.
+    pub fn record_success(&mut self, id: &NodeId) {
+        let config = self.config;
+        let peer = self.peers.entry(*id).or_default();
+        peer.successes = peer.successes.saturating_add(1);
+        peer.score = peer.score.saturating_add(config.success_reward).min(config.max_score);
+    }
+
+    /// Penalizes the peer. Returns its standing afterwards.
+    pub fn record(&mut self, id: &NodeId, offense: Offense, now: u64) -> Standing {
+        let config = self.config;
+        let penalty = match offense {
+            Offense::InvalidSignature => config.invalid_signature_penalty,
+            Offense::MalformedPacket => config.malformed_packet_penalty,
+            Offense::Timeout => config.timeout_penalty,
+            Offense::ProtocolViolation => config.protocol_violation_penalty,
+        };
+
+        let peer = self.peers.entry(*id).or_default();
+        if offense == Offense::Timeout {
+            peer.timeouts = peer.timeouts.saturating_add(1);
+        }
+        peer.score = peer.score.saturating_sub(penalty).max(config.max_score.saturating_neg());
+
+        if peer.score <= config.ban_threshold && peer.banned_until <= now {
+            peer.banned_until = now.saturating_add(config.ban_secs);
+            log::warn!("Banned peer {id} until {} ({offense:?})", peer.banned_until);
+        }
+
+        self.standing(id, now)
+    }
+
+    pub fn standing(&self, id: &NodeId, now: u64) -> Standing {
+        let Some(peer) = self.peers.get(id) else {
+            return Standing::Good;
+        };
+
+        if now < peer.banned_until {
+            return Standing::Banned { until: peer.banned_until };
+        }
+
+        let requests = peer.successes.saturating_add(peer.timeouts);
+        let timeout_ratio = if requests == 0 { 0.0 } else { peer.timeouts as f64 / requests as f64 };
+        if
+            peer.score <= self.config.greylist_threshold ||
+            (requests >= self.config.min_requests && timeout_ratio > self.config.max_timeout_ratio)
+        {
+            return Standing::Greylisted;
+        }
+
+        Standing::Good
+    }
+
+    pub fn is_banned(&self, id: &NodeId, now: u64) -> bool {
+        matches!(self.standing(id, now), Standing::Banned { .. })
+    }
+
+    pub fn score(&self, id: &NodeId) -> i32 {
+        self.peers.get(id).map_or(0, |p| p.score)
+    }
+
+    /// Moves every score one step back towards zero and forgets peers with nothing left to
+    /// remember, so old offenses fade. Call on a slow timer (e.g. every few minutes).
+    pub fn decay(&mut self, now: u64) {
+        self.peers.retain(|_, peer| {
+            peer.score = peer.score.saturating_sub(peer.score.signum());
+            peer.score != 0 || now < peer.banned_until
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,56 @@
+use crate::dht::node_id::NodeId;
+use crate::scoring::{ Offense, PeerScores, ScoreConfig, Standing };
+
+fn id_with_first_byte(byte: u8) -> NodeId {
+    let mut bytes = [0u8; 32];
+    bytes[0] = byte;
+    NodeId::from_bytes(bytes)
+}
+
+/// Unit test: Peer scores greylist flaky peers and ban forgers, and offenses fade with decay
+#[test]
+fn test_peer_scoring_standings() {
+    let config = ScoreConfig { min_requests: 4, ..ScoreConfig::default() };
+    let mut scores = PeerScores::new(config);
+    let (flaky, forger) = (id_with_first_byte(1), id_with_first_byte(2));
+
+    scores.record_success(&flaky);
+    for _ in 0..3 {
+        scores.record(&flaky, Offense::Timeout, 1_000);
+    }
+    assert_eq!(scores.standing(&flaky, 1_000), Standing::Greylisted, "3 of 4 requests timed out");
+
+    assert_eq!(scores.record(&forger, Offense::InvalidSignature, 1_000), Standing::Greylisted);
+    assert_eq!(scores.record(&forger, Offense::InvalidSignature, 1_000), Standing::Banned { until: 1_000 + config.ban_secs });
+    assert!(scores.is_banned(&forger, 1_000 + config.ban_secs - 1));
+    assert!(!scores.is_banned(&forger, 1_000 + config.ban_secs));
+
+    let unknown = id_with_first_byte(3);
+    assert_eq!(scores.standing(&unknown, 1_000), Standing::Good);
+
+    scores.decay(1_000);
+    assert_eq!(scores.score(&flaky), -4);
+}
+
+/// Unit test: Extreme rewards and penalties saturate at the clamp instead of overflowing
+#[test]
+fn test_peer_scoring_saturates() {
+    let config = ScoreConfig {
+        success_reward: i32::MAX,
+        invalid_signature_penalty: i32::MAX,
+        max_score: i32::MAX,
+        ..ScoreConfig::default()
+    };
+    let mut scores = PeerScores::new(config);
+    let peer = id_with_first_byte(1);
+
+    scores.record_success(&peer);
+    scores.record_success(&peer);
+    assert_eq!(scores.score(&peer), i32::MAX);
+
+    for _ in 0..3 {
+        scores.record(&peer, Offense::InvalidSignature, 1_000);
+    }
+    assert_eq!(scores.score(&peer), -i32::MAX);
+    assert!(scores.is_banned(&peer, 1_000));
+}
@@ -0,0 +1,216 @@
+use std::fs::{ self, File };
+use std::io::{ self, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::atomic::{ AtomicU64, Ordering };
+
+use super::chunk::Chunking;
+use super::content_hash;
+use super::manifest::{ Manifest, ManifestError };
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::messages::{ FetchRequest, FetchResponse };
+
+/// Suffix of blobs being written; anything left with it was cut short by a crash
+const PARTIAL_SUFFIX: &str = ".partial";
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlobError {
+    #[error("Blob store I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The bytes on disk no longer hash to the blob's name. The file is removed when found.
+    #[error("Blob {} is corrupt", hex(.0))]
+    Corrupt([u8; 32]),
+    #[error("Data doesn't hash to {}", hex(.0))]
+    HashMismatch([u8; 32]),
+    #[error("Invalid manifest: {0}")]
+    Manifest(#[from] ManifestError),
+}
+
+fn hex(hash: &[u8; 32]) -> String {
+    blake3::Hash::from_bytes(*hash).to_hex().to_string()
+}
+
+/// Blobs on disk, each in a file named by its content hash under a directory named by the
+/// hash's first byte (`ab/abcd…`), so a node can serve what it holds to `Fetch` requests
+/// across restarts.
+///
+/// A blob is written to a temporary file that is synced before it is renamed into place, and
+/// the directory is synced after, so a crash leaves either the whole blob or none of it. Every
+/// read hashes the bytes again: a blob the disk damaged is reported as `Corrupt` and removed,
+/// never served.
+#[derive(Debug)]
+pub struct BlobStore {
+    root: PathBuf,
+    /// Tells apart the temporary files of concurrent writes
+    writes: AtomicU64,
+}
+
+impl BlobStore {
+    /// Opens the store in `root`, creating the directory if needed and removing writes a
+    /// crash cut short.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, BlobError> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        for shard in fs::read_dir(&root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let path = entry?.path();
+                if path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(Self { root, writes: AtomicU64::new(0) })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Stores `data`, returning its hash. Storing a blob already held is a no-op.
+    pub fn put(&self, data: &[u8]) -> Result<[u8; 32], BlobError> {
+        let hash = content_hash(data);
+        let path = self.path(&hash);
+        if path.exists() {
+            return Ok(hash);
+        }
+
+        let dir = path.parent().expect("blob paths have a shard directory");
+        fs::create_dir_all(dir)?;
+        let write = self.writes.fetch_add(1, Ordering::Relaxed);
+        let tmp = dir.join(format!("{}.{}.{write}{PARTIAL_SUFFIX}", hex(&hash), std::process::id()));
+        if let Err(e) = write_durably(&tmp, &path, data) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(hash)
+    }
+
+    /// Stores `data` received for `hash`, e.g. in answer to a `Fetch`, refusing it unless it
+    /// hashes to what was asked for.
+    pub fn put_verified(&self, hash: &[u8; 32], data: &[u8]) -> Result<(), BlobError> {
+        if content_hash(data) != *hash {
+            return Err(BlobError::HashMismatch(*hash));
+        }
+        self.put(data)?;
+        Ok(())
+    }
+
+    /// The blob stored under `hash`, if any.
+    pub fn get(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, BlobError> {
+        let path = self.path(hash);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if content_hash(&data) != *hash {
+            let _ = fs::remove_file(&path);
+            return Err(BlobError::Corrupt(*hash));
+        }
+        Ok(Some(data))
+    }
+
+    pub fn has(&self, hash: &[u8; 32]) -> bool {
+        self.path(hash).is_file()
+    }
+
+    /// Removes the blob stored under `hash`. Returns false if there was none.
+    pub fn delete(&self, hash: &[u8; 32]) -> Result<bool, BlobError> {
+        let path = self.path(hash);
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        sync_dir(path.parent().expect("blob paths have a shard directory"))?;
+        Ok(true)
+    }
+
+    /// Cuts `data` into chunks and stores them with their signed manifest, whose hash the
+    /// content can be fetched and provided under.
+    pub fn put_content(&self, identity: &NodeIdentity, data: &[u8], chunking: Chunking) -> Result<Manifest, BlobError> {
+        let (manifest, chunks) = Manifest::build(identity, data, chunking)?;
+        for chunk in chunks {
+            self.put(chunk)?;
+        }
+        self.put(&manifest.to_bytes())?;
+        Ok(manifest)
+    }
+
+    /// The content whose manifest is stored under `hash`, if the manifest and every chunk
+    /// are held.
+    pub fn get_content(&self, hash: &[u8; 32]) -> Result<Option<Vec<u8>>, BlobError> {
+        let Some(bytes) = self.get(hash)? else {
+            return Ok(None);
+        };
+        let manifest = Manifest::from_bytes(&bytes)?;
+        manifest.verify()?;
+
+        // Grown as chunks are read: the lengths the manifest claims may be anything up to its limits
+        let mut content = Vec::new();
+        for chunk in &manifest.chunks {
+            let Some(data) = self.get(&chunk.hash)? else {
+                return Ok(None);
+            };
+            content.extend_from_slice(&data);
+        }
+        Ok(Some(content))
+    }
+
+    /// Hashes of every blob stored, e.g. to announce them as a provider. Not checked for
+    /// corruption; `get` is.
+    pub fn hashes(&self) -> Result<Vec<[u8; 32]>, BlobError> {
+        let mut hashes = Vec::new();
+        for shard in fs::read_dir(&self.root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let name = entry?.file_name();
+                if let Some(hash) = name.to_str().and_then(|name| blake3::Hash::from_hex(name).ok()) {
+                    hashes.push(*hash.as_bytes());
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Answers a peer's `Fetch`: the blob, or None if we don't hold it (or held it corrupt).
+    pub fn fetch(&self, request: &FetchRequest) -> Result<Option<FetchResponse>, BlobError> {
+        match self.get(&request.hash) {
+            Ok(data) => Ok(data.map(|data| FetchResponse { data })),
+            Err(BlobError::Corrupt(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn path(&self, hash: &[u8; 32]) -> PathBuf {
+        let name = hex(hash);
+        self.root.join(&name[..2]).join(name)
+    }
+}
+
+/// Writes `data` to `tmp`, syncs it and renames it to `path`.
+fn write_durably(tmp: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    sync_dir(path.parent().expect("blob paths have a shard directory"))
+}
+
+/// Makes a rename or removal in `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened (and synced) as files on Windows; NTFS journals renames.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
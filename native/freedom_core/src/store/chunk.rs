@@ -0,0 +1,86 @@
+/// Largest chunk a manifest may list
+pub const MAX_CHUNK_LEN: usize = 1024 * 1024;
+
+/// How large content is cut into chunks, each stored and fetched as a blob of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of this many bytes, the last one shorter
+    Fixed(usize),
+    /// Cuts where a rolling hash of the last bytes hits a pattern (FastCDC's gear hash), so
+    /// chunks end at the same content whatever comes before it: an edit only changes the
+    /// chunks around it, and the rest dedupe against what is already stored.
+    /// Chunks are `min` to `max` bytes, `avg` on average.
+    ContentDefined {
+        min: usize,
+        avg: usize,
+        max: usize,
+    },
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Self::ContentDefined { min: 16 * 1024, avg: 64 * 1024, max: 256 * 1024 }
+    }
+}
+
+/// Cuts `data` into chunks, in order. Empty data has no chunks.
+pub fn split(data: &[u8], chunking: Chunking) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = match chunking {
+            Chunking::Fixed(len) => len.max(1).min(rest.len()),
+            Chunking::ContentDefined { min, avg, max } => cut_point(rest, min, avg, max),
+        };
+        let (chunk, tail) = rest.split_at(len);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Length of the next content-defined chunk at the start of `data`. Past `min`, a cut needs
+/// one more bit of the hash to match until `avg` and one fewer after (FastCDC's normalized
+/// chunking), which keeps chunk sizes close to `avg`.
+fn cut_point(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+    let min = min.max(1);
+    let max = max.max(min);
+    let avg = avg.clamp(min, max).max(4);
+    if data.len() <= min {
+        return data.len();
+    }
+
+    let bits = avg.ilog2();
+    let strict = u64::MAX << (64 - (bits + 1));
+    let loose = u64::MAX << (64 - (bits - 1));
+    let end = data.len().min(max);
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(min) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < avg { strict } else { loose };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// The gear hash's random value per byte. Where content is cut depends on it: nodes that
+/// chunk with another table don't dedupe against ours.
+static GEAR: [u64; 256] = gear_table();
+
+/// SplitMix64 from a fixed seed, so every build has the same table
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x4672_6565_646F_6D4Eu64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+use super::content_hash;
+use super::manifest::Manifest;
+use crate::dht::contact::Contact;
+use crate::dht::messages::FetchRequest;
+use crate::dht::node_id::NodeId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchConfig {
+    /// Chunk requests in flight at once, all providers
+    pub parallel: usize,
+    /// Chunk requests in flight at once to one provider
+    pub per_provider: usize,
+    /// Time a provider has to send a chunk before it is asked of another
+    pub timeout_ms: u64,
+    /// Failures (timeouts, wrong data, "not found") after which a provider isn't asked again
+    pub max_provider_failures: u32,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self { parallel: 8, per_provider: 2, timeout_ms: 15_000, max_provider_failures: 3 }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum FetchError {
+    #[error("{0} chunks are still missing")]
+    Incomplete(usize),
+}
+
+#[derive(Debug)]
+enum ChunkState {
+    Wanted,
+    InFlight {
+        provider: NodeId,
+        since_ms: u64,
+    },
+    Done(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Chunk {
+    state: ChunkState,
+    /// Providers that failed to send this chunk
+    tried: Vec<NodeId>,
+}
+
+#[derive(Debug)]
+struct Provider {
+    contact: Contact,
+    in_flight: usize,
+    failures: u32,
+}
+
+/// Downloads the content a manifest describes from the peers that provide it, many chunks at
+/// once and from several providers, then puts it back together. Sans-IO: the host sends the
+/// `Fetch` requests `poll` hands out and feeds back the chunks (`on_chunk`) or the failures
+/// (`on_failure`, `on_timeout`) they end in.
+///
+/// Each chunk goes to the least busy provider that hasn't failed it yet. Chunks are checked
+/// against their hash on arrival, so a provider can't slip in other data; one that fails
+/// too often isn't asked again. The manifest is trusted as given: fetch it by its hash and
+/// `verify` it first.
+#[derive(Debug)]
+pub struct ChunkFetcher {
+    config: FetchConfig,
+    manifest: Manifest,
+    /// Chunk hashes in manifest order, each once
+    order: Vec<[u8; 32]>,
+    chunks: HashMap<[u8; 32], Chunk>,
+    providers: Vec<Provider>,
+}
+
+impl ChunkFetcher {
+    pub fn new(manifest: Manifest, providers: impl IntoIterator<Item = Contact>, config: FetchConfig) -> Self {
+        let mut order = Vec::new();
+        let mut chunks = HashMap::new();
+        for chunk in &manifest.chunks {
+            if let Entry::Vacant(entry) = chunks.entry(chunk.hash) {
+                order.push(chunk.hash);
+                entry.insert(Chunk { state: ChunkState::Wanted, tried: Vec::new() });
+            }
+        }
+
+        let mut fetcher = Self { config, manifest, order, chunks, providers: Vec::new() };
+        for provider in providers {
+            fetcher.add_provider(provider);
+        }
+        fetcher
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Another peer found to provide the content.
+    pub fn add_provider(&mut self, contact: Contact) {
+        if !self.providers.iter().any(|p| p.contact.id == contact.id) {
+            self.providers.push(Provider { contact, in_flight: 0, failures: 0 });
+        }
+    }
+
+    /// Hands out the chunk requests to send now, with the provider each goes to.
+    pub fn poll(&mut self, now_ms: u64) -> Vec<(Contact, FetchRequest)> {
+        let mut requests = Vec::new();
+        let mut in_flight = self.in_flight();
+        for hash in &self.order {
+            if in_flight >= self.config.parallel {
+                break;
+            }
+            let chunk = self.chunks.get_mut(hash).expect("ordered chunks are known");
+            if !matches!(chunk.state, ChunkState::Wanted) {
+                continue;
+            }
+            let Some(provider) = self.providers
+                .iter_mut()
+                .filter(|p| {
+                    p.failures < self.config.max_provider_failures &&
+                        p.in_flight < self.config.per_provider &&
+                        !chunk.tried.contains(&p.contact.id)
+                })
+                .min_by_key(|p| (p.in_flight, p.failures))
+            else {
+                continue;
+            };
+
+            provider.in_flight += 1;
+            in_flight += 1;
+            chunk.state = ChunkState::InFlight { provider: provider.contact.id, since_ms: now_ms };
+            requests.push((provider.contact, FetchRequest { hash: *hash }));
+        }
+        requests
+    }
+
+    /// A provider sent a chunk. Data that isn't the chunk counts as a failure of the provider.
+    /// Returns whether the chunk was taken.
+    pub fn on_chunk(&mut self, from: &NodeId, hash: &[u8; 32], data: Vec<u8>) -> bool {
+        if !self.chunks.contains_key(hash) {
+            return false;
+        }
+        if content_hash(&data) != *hash {
+            log::warn!("Provider {from} sent data that isn't the chunk it was asked for");
+            self.on_failure(from, hash);
+            return false;
+        }
+        self.insert(hash, data)
+    }
+
+    /// Takes a chunk got some other way, e.g. already held in a `BlobStore`. Returns whether
+    /// it was wanted and matches its hash.
+    pub fn insert(&mut self, hash: &[u8; 32], data: Vec<u8>) -> bool {
+        let Some(chunk) = self.chunks.get_mut(hash) else {
+            return false;
+        };
+        if matches!(chunk.state, ChunkState::Done(_)) || content_hash(&data) != *hash {
+            return false;
+        }
+        if let ChunkState::InFlight { provider, .. } = chunk.state
+            && let Some(provider) = self.providers.iter_mut().find(|p| p.contact.id == provider)
+        {
+            provider.in_flight -= 1;
+        }
+        chunk.state = ChunkState::Done(data);
+        true
+    }
+
+    /// A provider didn't send a chunk it was asked for: it answered without it, the request
+    /// failed, or it timed out. The chunk is asked of another provider.
+    pub fn on_failure(&mut self, from: &NodeId, hash: &[u8; 32]) {
+        let Some(chunk) = self.chunks.get_mut(hash) else {
+            return;
+        };
+        let Some(provider) = self.providers.iter_mut().find(|p| p.contact.id == *from) else {
+            return;
+        };
+        if let ChunkState::InFlight { provider: asked, .. } = chunk.state
+            && asked == *from
+        {
+            provider.in_flight -= 1;
+            chunk.state = ChunkState::Wanted;
+        }
+        if !chunk.tried.contains(from) {
+            chunk.tried.push(*from);
+            provider.failures += 1;
+        }
+    }
+
+    /// When the oldest request in flight times out
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.chunks
+            .values()
+            .filter_map(|c| match c.state {
+                ChunkState::InFlight { since_ms, .. } => Some(since_ms.saturating_add(self.config.timeout_ms)),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Fails the requests that timed out.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let expired: Vec<_> = self.chunks
+            .iter()
+            .filter_map(|(hash, c)| match c.state {
+                ChunkState::InFlight { provider, since_ms } if now_ms >= since_ms.saturating_add(self.config.timeout_ms) =>
+                    Some((provider, *hash)),
+                _ => None,
+            })
+            .collect();
+        for (provider, hash) in expired {
+            log::debug!("Provider {provider} timed out sending a chunk");
+            self.on_failure(&provider, &hash);
+        }
+    }
+
+    /// Chunks not received yet
+    pub fn remaining(&self) -> usize {
+        self.chunks.values().filter(|c| !matches!(c.state, ChunkState::Done(_))).count()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Whether some chunk can't be fetched from any provider known: every one failed it, or
+    /// failed too often to be asked. Adding providers may get it going again.
+    pub fn is_stuck(&self) -> bool {
+        self.chunks.values().any(|c| {
+            matches!(c.state, ChunkState::Wanted) &&
+                !self.providers
+                    .iter()
+                    .any(|p| p.failures < self.config.max_provider_failures && !c.tried.contains(&p.contact.id))
+        })
+    }
+
+    /// Puts the content back together.
+    pub fn finish(self) -> Result<Vec<u8>, FetchError> {
+        let remaining = self.remaining();
+        if remaining > 0 {
+            return Err(FetchError::Incomplete(remaining));
+        }
+        // Grown as chunks are appended, not sized by the lengths the manifest claims
+        let mut content = Vec::new();
+        for chunk in &self.manifest.chunks {
+            if let Some(Chunk { state: ChunkState::Done(data), .. }) = self.chunks.get(&chunk.hash) {
+                content.extend_from_slice(data);
+            }
+        }
+        Ok(content)
+    }
+
+    fn in_flight(&self) -> usize {
+        self.providers.iter().map(|p| p.in_flight).sum()
+    }
+}
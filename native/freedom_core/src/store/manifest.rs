@@ -0,0 +1,149 @@
+use ed25519_dalek::{ Signature, VerifyingKey };
+
+use super::chunk::{ self, Chunking, MAX_CHUNK_LEN };
+use super::content_hash;
+use crate::crypto::identity::{ self, NodeIdentity };
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Most chunks one manifest lists
+pub const MAX_MANIFEST_CHUNKS: usize = 65_536;
+
+/// Signatures over manifests start with this, so a manifest can't pass for anything else the
+/// same key signs, such as a `MutableRecord`
+const MANIFEST_CONTEXT: &[u8] = b"FreedomNode-manifest-v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManifestError {
+    #[error("Malformed manifest: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Invalid owner key bytes")]
+    InvalidOwnerKey,
+    #[error("Signature verification failed")]
+    VerificationFailed,
+    #[error("Manifest lists too many chunks: {0} (max {MAX_MANIFEST_CHUNKS})")]
+    TooManyChunks(usize),
+    #[error("Chunk too large: {0} bytes (max {MAX_CHUNK_LEN})")]
+    ChunkTooLarge(usize),
+}
+
+/// One chunk of the content a manifest describes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: [u8; 32],
+    pub len: u32,
+}
+
+/// Describes content too large for one blob: the hashes and sizes of the chunks it was cut
+/// into, in order, signed by whoever published it. The manifest is stored and fetched as a
+/// blob itself, and its hash is what the content is known and announced by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub owner: VerifyingKey,
+    pub chunks: Vec<ChunkRef>,
+    pub signature: Signature,
+}
+
+impl Manifest {
+    /// Cuts `data` with `chunking` and signs the manifest of the chunks. Returns the chunks
+    /// too, for the caller to store.
+    pub fn build<'a>(
+        identity: &NodeIdentity,
+        data: &'a [u8],
+        chunking: Chunking
+    ) -> Result<(Self, Vec<&'a [u8]>), ManifestError> {
+        let chunks = chunk::split(data, chunking);
+        let refs = chunks
+            .iter()
+            .map(|chunk| ChunkRef { hash: content_hash(chunk), len: chunk.len() as u32 })
+            .collect();
+        Ok((Self::new_signed(identity, refs)?, chunks))
+    }
+
+    pub fn new_signed(identity: &NodeIdentity, chunks: Vec<ChunkRef>) -> Result<Self, ManifestError> {
+        check_chunks(&chunks)?;
+        let signature = identity.sign(&signed_message(&chunks));
+        Ok(Self { owner: identity.identity_keypair.verifying_key(), chunks, signature })
+    }
+
+    /// Checks the owner's signature over the chunk list.
+    pub fn verify(&self) -> Result<(), ManifestError> {
+        identity
+            ::verify(&self.owner, &signed_message(&self.chunks), &self.signature)
+            .map_err(|_| ManifestError::VerificationFailed)
+    }
+
+    /// Hash of the encoded manifest, which it is stored, fetched and provided under
+    pub fn hash(&self) -> [u8; 32] {
+        content_hash(&self.to_bytes())
+    }
+
+    /// DHT key providers of the content announce themselves under
+    pub fn provider_key(&self) -> NodeId {
+        NodeId::from_bytes(self.hash())
+    }
+
+    /// Length of the content
+    pub fn len(&self) -> u64 {
+        self.chunks.iter().map(|c| c.len as u64).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Serializes the manifest.
+    /// Format: [Owner (32)] [Signature (64)] [Count (4)] + N * [Hash (32) | Len (4)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(100 + self.chunks.len() * 36);
+        out.extend_from_slice(self.owner.as_bytes());
+        out.extend_from_slice(&self.signature.to_bytes());
+        write_chunks(&mut out, &self.chunks);
+        out
+    }
+
+    /// Parses a manifest. Does not check the signature; call `verify` for that.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ManifestError> {
+        let mut reader = Reader::new(bytes);
+
+        let owner = VerifyingKey::from_bytes(&reader.take_array()?).map_err(|_| ManifestError::InvalidOwnerKey)?;
+        let signature = Signature::from_bytes(&reader.take_array()?);
+        let count = reader.u32()? as usize;
+        if count > MAX_MANIFEST_CHUNKS {
+            return Err(ManifestError::TooManyChunks(count));
+        }
+        let mut chunks = Vec::with_capacity(count);
+        for _ in 0..count {
+            chunks.push(ChunkRef { hash: reader.take_array()?, len: reader.u32()? });
+        }
+        reader.finish()?;
+        check_chunks(&chunks)?;
+
+        Ok(Self { owner, chunks, signature })
+    }
+}
+
+fn check_chunks(chunks: &[ChunkRef]) -> Result<(), ManifestError> {
+    if chunks.len() > MAX_MANIFEST_CHUNKS {
+        return Err(ManifestError::TooManyChunks(chunks.len()));
+    }
+    match chunks.iter().find(|c| c.len as usize > MAX_CHUNK_LEN) {
+        Some(chunk) => Err(ManifestError::ChunkTooLarge(chunk.len as usize)),
+        None => Ok(()),
+    }
+}
+
+fn write_chunks(out: &mut Vec<u8>, chunks: &[ChunkRef]) {
+    out.extend_from_slice(&(chunks.len() as u32).to_be_bytes());
+    for chunk in chunks {
+        out.extend_from_slice(&chunk.hash);
+        out.extend_from_slice(&chunk.len.to_be_bytes());
+    }
+}
+
+/// [Context] [Count (4)] + N * [Hash (32) | Len (4)]
+fn signed_message(chunks: &[ChunkRef]) -> Vec<u8> {
+    let mut message = MANIFEST_CONTEXT.to_vec();
+    write_chunks(&mut message, chunks);
+    message
+}
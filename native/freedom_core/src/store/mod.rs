@@ -0,0 +1,20 @@
+// Blobs live in files; the browser build has no filesystem
+#[cfg(not(target_arch = "wasm32"))]
+pub mod blob;
+pub mod chunk;
+pub mod fetch;
+pub mod manifest;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use blob::{ BlobError, BlobStore };
+pub use chunk::Chunking;
+pub use fetch::{ ChunkFetcher, FetchConfig, FetchError };
+pub use manifest::{ ChunkRef, Manifest, ManifestError };
+
+/// The BLAKE3 hash content is stored and fetched under
+pub fn content_hash(data: &[u8]) -> [u8; 32] {
+    *blake3::hash(data).as_bytes()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,252 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{ AtomicU32, Ordering };
+
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::contact::Contact;
+use crate::dht::messages::FetchRequest;
+use crate::dht::node_id::NodeId;
+use crate::store::blob::{ BlobError, BlobStore };
+use crate::store::chunk::{ self, Chunking, MAX_CHUNK_LEN };
+use crate::store::content_hash;
+use crate::store::fetch::{ ChunkFetcher, FetchConfig, FetchError };
+use crate::store::manifest::{ ChunkRef, Manifest, ManifestError };
+
+/// A fresh directory for one test's store
+fn temp_root() -> PathBuf {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "freedom_core-blobs-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Bytes that look random, the same every run
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Unit test: blobs are stored under their BLAKE3 hash and read back; storing one twice is a
+/// no-op and deleting it forgets it.
+#[test]
+fn test_blob_store_roundtrip() {
+    let root = temp_root();
+    let store = BlobStore::open(&root).unwrap();
+
+    let empty = store.put(b"").unwrap();
+    assert_eq!(
+        blake3::Hash::from_bytes(empty).to_hex().as_str(),
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+    );
+
+    let data = vec![7u8; 100_000];
+    let hash = store.put(&data).unwrap();
+    assert_eq!(hash, content_hash(&data));
+    assert_eq!(store.put(&data).unwrap(), hash);
+    assert!(store.has(&hash));
+    assert_eq!(store.get(&hash).unwrap(), Some(data.clone()));
+    assert_eq!(store.get(&[1; 32]).unwrap(), None);
+
+    let mut hashes = store.hashes().unwrap();
+    hashes.sort();
+    let mut expected = vec![empty, hash];
+    expected.sort();
+    assert_eq!(hashes, expected);
+
+    // What the node serves survives a restart
+    drop(store);
+    let store = BlobStore::open(&root).unwrap();
+    assert_eq!(store.fetch(&FetchRequest { hash }).unwrap().map(|r| r.data), Some(data));
+    assert_eq!(store.fetch(&FetchRequest { hash: [1; 32] }).unwrap(), None);
+
+    assert!(store.delete(&hash).unwrap());
+    assert!(!store.delete(&hash).unwrap());
+    assert!(!store.has(&hash));
+    assert_eq!(store.get(&hash).unwrap(), None);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Unit test: a blob damaged on disk is reported as corrupt and removed, never served; data
+/// received for a hash it doesn't match is refused; writes a crash cut short are cleaned up.
+#[test]
+fn test_blob_store_corruption() {
+    let root = temp_root();
+    let store = BlobStore::open(&root).unwrap();
+
+    let hash = store.put(b"some blob").unwrap();
+    let hex = blake3::Hash::from_bytes(hash).to_hex();
+    let path = root.join(&hex[..2]).join(hex.as_str());
+    fs::write(&path, b"some blub").unwrap();
+
+    assert!(matches!(store.get(&hash), Err(BlobError::Corrupt(h)) if h == hash));
+    assert!(!store.has(&hash));
+    assert_eq!(store.get(&hash).unwrap(), None);
+
+    fs::write(&path, b"some blub").unwrap();
+    assert_eq!(store.fetch(&FetchRequest { hash }).unwrap(), None);
+
+    assert!(matches!(store.put_verified(&hash, b"not it"), Err(BlobError::HashMismatch(_))));
+    assert!(!store.has(&hash));
+    store.put_verified(&hash, b"some blob").unwrap();
+    assert_eq!(store.get(&hash).unwrap().as_deref(), Some(&b"some blob"[..]));
+
+    let partial = path.with_file_name(format!("{hex}.1.0.partial"));
+    fs::write(&partial, b"some b").unwrap();
+    drop(store);
+    let store = BlobStore::open(&root).unwrap();
+    assert!(!partial.exists());
+    assert_eq!(store.hashes().unwrap(), vec![hash]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Unit test: fixed chunking cuts every `len` bytes; content-defined chunks stay within their
+/// bounds, and an insertion near the start only changes the chunks around it.
+#[test]
+fn test_chunking() {
+    let data = noise(1_000, 1);
+    let chunks = chunk::split(&data, Chunking::Fixed(300));
+    assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), vec![300, 300, 300, 100]);
+    assert!(chunk::split(&[], Chunking::default()).is_empty());
+
+    let cdc = Chunking::ContentDefined { min: 2 * 1024, avg: 8 * 1024, max: 32 * 1024 };
+    let data = noise(1024 * 1024, 2);
+    let chunks = chunk::split(&data, cdc);
+    assert_eq!(chunks.concat(), data);
+    assert!(chunks[..chunks.len() - 1].iter().all(|c| (2 * 1024..=32 * 1024).contains(&c.len())));
+    let avg = data.len() / chunks.len();
+    assert!((4 * 1024..=16 * 1024).contains(&avg), "average chunk {avg} bytes");
+    assert_eq!(chunk::split(&data, cdc), chunks);
+
+    let mut edited = data[..1000].to_vec();
+    edited.extend_from_slice(b"inserted");
+    edited.extend_from_slice(&data[1000..]);
+    let before: Vec<_> = chunks.iter().map(|c| content_hash(c)).collect();
+    let after: Vec<_> = chunk::split(&edited, cdc).iter().map(|c| content_hash(c)).collect();
+    let changed = after.iter().filter(|h| !before.contains(h)).count();
+    assert!(changed <= 2, "{changed} chunks changed");
+}
+
+/// Unit test: manifests list their chunks in order, survive encoding, and are only valid with
+/// the owner's signature over exactly that list.
+#[test]
+fn test_manifest_roundtrip_and_signature() {
+    let identity = NodeIdentity::generate();
+    let data = noise(100_000, 3);
+    let (manifest, chunks) = Manifest::build(&identity, &data, Chunking::Fixed(30_000)).unwrap();
+    assert_eq!(manifest.chunks.len(), 4);
+    assert_eq!(manifest.len(), 100_000);
+    assert_eq!(manifest.chunks[3], ChunkRef { hash: content_hash(chunks[3]), len: 10_000 });
+    manifest.verify().unwrap();
+
+    let parsed = Manifest::from_bytes(&manifest.to_bytes()).unwrap();
+    assert_eq!(parsed, manifest);
+    assert_eq!(parsed.hash(), content_hash(&manifest.to_bytes()));
+    assert_eq!(parsed.provider_key(), NodeId::from_bytes(manifest.hash()));
+
+    let mut tampered = manifest.clone();
+    tampered.chunks.swap(0, 1);
+    assert!(matches!(tampered.verify(), Err(ManifestError::VerificationFailed)));
+
+    let mut bytes = manifest.to_bytes();
+    bytes.pop();
+    assert!(matches!(Manifest::from_bytes(&bytes), Err(ManifestError::Malformed(_))));
+    assert!(matches!(
+        Manifest::build(&identity, &noise(MAX_CHUNK_LEN + 1, 4), Chunking::Fixed(MAX_CHUNK_LEN + 1)),
+        Err(ManifestError::ChunkTooLarge(_))
+    ));
+}
+
+/// Unit test: chunks are fetched in parallel from several providers, within the limits; wrong
+/// data and timeouts send a chunk to another provider, and the content comes back whole.
+#[test]
+fn test_chunk_fetcher() {
+    let identity = NodeIdentity::generate();
+    let mut data = noise(50_000, 5);
+    data.extend_from_within(..10_000);
+    let (manifest, chunks) = Manifest::build(&identity, &data, Chunking::Fixed(10_000)).unwrap();
+    assert_eq!(manifest.chunks.len(), 6);
+    let blobs: Vec<_> = chunks.iter().map(|c| (content_hash(c), c.to_vec())).collect();
+    let blob = |hash: &[u8; 32]| blobs.iter().find(|(h, _)| h == hash).unwrap().1.clone();
+
+    let providers: Vec<_> = (1..=3)
+        .map(|n| Contact::new(NodeId::random(), format!("10.0.0.{n}:4433").parse().unwrap()))
+        .collect();
+    // The first provider sends what it's asked for, the second garbage, the third nothing
+    let (honest, liar) = (providers[0].id, providers[1].id);
+    let config = FetchConfig { parallel: 4, per_provider: 2, timeout_ms: 1_000, max_provider_failures: 1 };
+    let mut fetcher = ChunkFetcher::new(manifest.clone(), providers.clone(), config);
+
+    // The repeated first chunk is only fetched once
+    let requests = fetcher.poll(0);
+    assert_eq!(requests.len(), 4);
+    for provider in &providers {
+        assert!(requests.iter().filter(|(c, _)| c.id == provider.id).count() <= 2);
+    }
+    assert!(fetcher.poll(0).is_empty());
+    assert_eq!(fetcher.next_timeout(), Some(1_000));
+
+    for (contact, request) in requests {
+        if contact.id == honest {
+            assert!(fetcher.on_chunk(&honest, &request.hash, blob(&request.hash)));
+        } else if contact.id == liar {
+            assert!(!fetcher.on_chunk(&liar, &request.hash, b"something else".to_vec()));
+        }
+    }
+    fetcher.on_timeout(1_000);
+    assert!(!fetcher.is_finished());
+    assert!(!fetcher.is_stuck());
+
+    // Everything left goes to whoever can still be asked, and arrives
+    for now in 1_000..1_010 {
+        for (contact, request) in fetcher.poll(now) {
+            assert_eq!(contact.id, honest);
+            fetcher.on_chunk(&honest, &request.hash, blob(&request.hash));
+        }
+    }
+    assert!(fetcher.is_finished());
+    assert_eq!(fetcher.finish().unwrap(), data);
+
+    // With every provider failing a chunk, the fetch is stuck until another one turns up
+    let mut fetcher = ChunkFetcher::new(manifest.clone(), [providers[1]], config);
+    let (_, request) = fetcher.poll(0).remove(0);
+    fetcher.on_failure(&liar, &request.hash);
+    assert!(fetcher.is_stuck());
+    assert!(fetcher.insert(&request.hash, blob(&request.hash)));
+    fetcher.add_provider(providers[0]);
+    assert!(!fetcher.is_stuck());
+    assert_eq!(fetcher.finish(), Err(FetchError::Incomplete(4)));
+}
+
+/// Unit test: content stored chunked comes back whole by its manifest's hash, and only while
+/// every chunk is held.
+#[test]
+fn test_blob_store_content() {
+    let root = temp_root();
+    let store = BlobStore::open(&root).unwrap();
+    let identity = NodeIdentity::generate();
+
+    let data = noise(200_000, 6);
+    let manifest = store.put_content(&identity, &data, Chunking::Fixed(64 * 1024)).unwrap();
+    assert_eq!(manifest.chunks.len(), 4);
+    assert!(manifest.chunks.iter().all(|c| store.has(&c.hash)));
+    assert_eq!(store.get_content(&manifest.hash()).unwrap(), Some(data));
+
+    store.delete(&manifest.chunks[1].hash).unwrap();
+    assert_eq!(store.get_content(&manifest.hash()).unwrap(), None);
+    assert!(matches!(store.get_content(&manifest.chunks[0].hash), Err(BlobError::Manifest(_))));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use quinn_proto::ConnectionError;
+
+use super::quic::{ QuicEndpoint, QuicError };
+use super::tcp::{ TcpCommand, TcpError, TcpInput, TcpTransport };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::HandshakePayload;
+use crate::protocol::packet::NetworkPacket;
+
+/// How long a QUIC dial may take before TCP is tried instead
+pub const QUIC_FALLBACK_MS: u64 = 5_000;
+/// How long an address QUIC couldn't reach is dialled over TCP straight away
+pub const QUIC_BLOCKED_TTL_MS: u64 = 10 * 60 * 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FallbackError {
+    #[error("Unknown connection {0:?}")]
+    UnknownConnection(ConnectionId),
+    #[error("QUIC: {0}")]
+    Quic(#[from] QuicError),
+    #[error("TCP: {0}")]
+    Tcp(#[from] TcpError),
+}
+
+pub type FallbackEvent = TransportEvent<FallbackError>;
+
+/// What the host's sockets did, for `Transport::handle`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackInput {
+    Datagram(SocketAddr, Vec<u8>),
+    Tcp(TcpInput),
+}
+
+/// What the host is to do with its sockets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackOutput {
+    Datagram(SocketAddr, Vec<u8>),
+    Tcp(TcpCommand),
+}
+
+/// Where one of our connections runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Route {
+    Quic(ConnectionId),
+    Tcp(ConnectionId),
+}
+
+/// A QUIC dial not connected yet
+struct PendingDial {
+    addr: SocketAddr,
+    deadline_ms: u64,
+}
+
+/// Dials over QUIC, and over TCP when QUIC doesn't get through: a QUIC dial that fails to
+/// reach the peer (or takes longer than `QUIC_FALLBACK_MS`) is retried over TCP under the same
+/// `ConnectionId`, and the address is dialled over TCP directly for `QUIC_BLOCKED_TTL_MS`.
+/// A peer that answers over QUIC but fails its handshake is not retried.
+///
+/// The host runs the I/O of both transports through it: datagrams with `on_datagram` and
+/// `take_datagrams`, TCP sockets with `on_tcp_accepted`, `on_tcp_data`, `on_tcp_closed` and
+/// `take_tcp_commands`. Sockets are named by the TCP transport's own ids, the ones its
+/// `TcpCommand`s carry; connections (events, `send`, `close`) by ours.
+pub struct FallbackTransport {
+    quic: QuicEndpoint,
+    tcp: TcpTransport,
+    routes: HashMap<ConnectionId, Route>,
+    owners: HashMap<Route, ConnectionId>,
+    pending: HashMap<ConnectionId, PendingDial>,
+    /// Addresses QUIC couldn't reach, until when they are dialled over TCP
+    blocked: HashMap<SocketAddr, u64>,
+    next_id: u64,
+    events: Vec<FallbackEvent>,
+}
+
+impl FallbackTransport {
+    pub fn new(quic: QuicEndpoint, tcp: TcpTransport) -> Self {
+        Self {
+            quic,
+            tcp,
+            routes: HashMap::new(),
+            owners: HashMap::new(),
+            pending: HashMap::new(),
+            blocked: HashMap::new(),
+            next_id: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// Starts a connection to `addr`, over TCP if QUIC recently failed to reach it.
+    pub fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.allocate();
+        if self.blocked.get(&addr).is_some_and(|until| *until > now_ms) {
+            let socket = self.tcp.dial(addr, now_ms);
+            self.route(connection, Route::Tcp(socket));
+        } else {
+            match self.quic.dial(addr, now_ms) {
+                Ok(quic) => {
+                    self.route(connection, Route::Quic(quic));
+                    self.pending.insert(connection, PendingDial { addr, deadline_ms: now_ms + QUIC_FALLBACK_MS });
+                }
+                Err(e) => {
+                    log::debug!("QUIC dial to {addr} failed ({e}), using TCP");
+                    let socket = self.tcp.dial(addr, now_ms);
+                    self.route(connection, Route::Tcp(socket));
+                }
+            }
+        }
+        self.pump(now_ms);
+        connection
+    }
+
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8], now_ms: u64) {
+        self.quic.on_datagram(from, data, now_ms);
+        self.pump(now_ms);
+    }
+
+    /// Takes over a TCP socket the host accepted. Returns its socket id.
+    pub fn on_tcp_accepted(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let socket = self.tcp.accept(addr, now_ms);
+        self.pump(now_ms);
+        socket
+    }
+
+    pub fn on_tcp_data(&mut self, socket: ConnectionId, data: &[u8], now_ms: u64) {
+        self.tcp.on_data(socket, data, now_ms);
+        self.pump(now_ms);
+    }
+
+    pub fn on_tcp_closed(&mut self, socket: ConnectionId, now_ms: u64) {
+        self.tcp.on_closed(socket);
+        self.pump(now_ms);
+    }
+
+    /// The UDP socket moved to another local address: see `QuicEndpoint::rebind`. TCP
+    /// connections don't survive it, the host closes their sockets.
+    pub fn rebind(&mut self, now_ms: u64) {
+        self.quic.rebind(now_ms);
+        self.pump(now_ms);
+    }
+
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), FallbackError> {
+        match self.routes.get(&connection) {
+            Some(Route::Quic(quic)) => self.quic.send(*quic, packet, now_ms)?,
+            Some(Route::Tcp(socket)) => self.tcp.send(*socket, packet)?,
+            None => {
+                return Err(FallbackError::UnknownConnection(connection));
+            }
+        }
+        self.pump(now_ms);
+        Ok(())
+    }
+
+    /// Closes a connection. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        let Some(route) = self.unroute(connection) else {
+            return false;
+        };
+        match route {
+            Route::Quic(quic) => self.quic.close(quic, now_ms),
+            Route::Tcp(socket) => self.tcp.close(socket),
+        };
+        self.pump(now_ms);
+        true
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&mut self) -> Option<u64> {
+        [self.quic.next_timeout(), self.tcp.next_timeout(), self.pending.values().map(|d| d.deadline_ms).min()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// Runs the timers of both transports, and moves QUIC dials that took too long to TCP.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let late: Vec<ConnectionId> = self.pending
+            .iter()
+            .filter(|(_, dial)| dial.deadline_ms <= now_ms)
+            .map(|(connection, _)| *connection)
+            .collect();
+        for connection in late {
+            if let Some(Route::Quic(quic)) = self.routes.get(&connection).copied() {
+                self.quic.close(quic, now_ms);
+                self.fall_back(connection, now_ms);
+            }
+        }
+        self.quic.on_timeout(now_ms);
+        self.tcp.on_timeout(now_ms);
+        self.pump(now_ms);
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        match self.routes.get(&connection)? {
+            Route::Quic(quic) => self.quic.peer(*quic),
+            Route::Tcp(socket) => self.tcp.peer(*socket),
+        }
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        match self.routes.get(&connection)? {
+            Route::Quic(quic) => self.quic.remote_address(*quic),
+            Route::Tcp(socket) => self.tcp.remote_address(*socket),
+        }
+    }
+
+    /// What a connection carried so far, over whichever transport it ended up on
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        match self.routes.get(&connection)? {
+            Route::Quic(quic) => self.quic.stats(*quic),
+            Route::Tcp(socket) => self.tcp.stats(*socket),
+        }
+    }
+
+    /// Whether a connection runs over TCP
+    pub fn is_tcp(&self, connection: ConnectionId) -> bool {
+        matches!(self.routes.get(&connection), Some(Route::Tcp(_)))
+    }
+
+    pub fn take_datagrams(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.quic.take_outgoing()
+    }
+
+    pub fn take_tcp_commands(&mut self) -> Vec<TcpCommand> {
+        self.tcp.take_outgoing()
+    }
+
+    pub fn take_events(&mut self) -> Vec<FallbackEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn allocate(&mut self) -> ConnectionId {
+        self.next_id += 1;
+        ConnectionId(self.next_id - 1)
+    }
+
+    fn route(&mut self, connection: ConnectionId, route: Route) {
+        self.routes.insert(connection, route);
+        self.owners.insert(route, connection);
+    }
+
+    fn unroute(&mut self, connection: ConnectionId) -> Option<Route> {
+        let route = self.routes.remove(&connection)?;
+        self.owners.remove(&route);
+        self.pending.remove(&connection);
+        Some(route)
+    }
+
+    /// Redials a pending QUIC connection over TCP.
+    fn fall_back(&mut self, connection: ConnectionId, now_ms: u64) {
+        let Some(dial) = self.pending.remove(&connection) else {
+            return;
+        };
+        log::info!("QUIC did not reach {}, falling back to TCP", dial.addr);
+        if let Some(route) = self.routes.remove(&connection) {
+            self.owners.remove(&route);
+        }
+        self.blocked.insert(dial.addr, now_ms + QUIC_BLOCKED_TTL_MS);
+        let socket = self.tcp.dial(dial.addr, now_ms);
+        self.route(connection, Route::Tcp(socket));
+    }
+
+    /// Follows the events of both transports, reporting them under our connection ids.
+    fn pump(&mut self, now_ms: u64) {
+        self.blocked.retain(|_, until| *until > now_ms);
+
+        for event in self.quic.take_events() {
+            if let TransportEvent::Closed { connection: quic, error: QuicError::Lost(ConnectionError::TimedOut) } = &event
+                && let Some(&connection) = self.owners.get(&Route::Quic(*quic))
+                && self.pending.contains_key(&connection)
+            {
+                self.fall_back(connection, now_ms);
+                continue;
+            }
+            self.forward(event, Route::Quic);
+        }
+        for event in self.tcp.take_events() {
+            self.forward(event, Route::Tcp);
+        }
+    }
+
+    fn forward<E>(&mut self, event: TransportEvent<E>, route: fn(ConnectionId) -> Route) where FallbackError: From<E> {
+        match event {
+            TransportEvent::Connected { connection, addr, peer } => {
+                // Connections the peers dialled are ours from their first event
+                let connection = match self.owners.get(&route(connection)) {
+                    Some(owner) => *owner,
+                    None => {
+                        let owner = self.allocate();
+                        self.route(owner, route(connection));
+                        owner
+                    }
+                };
+                self.pending.remove(&connection);
+                self.events.push(FallbackEvent::Connected { connection, addr, peer });
+            }
+            TransportEvent::Packet { connection, packet } => {
+                if let Some(&connection) = self.owners.get(&route(connection)) {
+                    self.events.push(FallbackEvent::Packet { connection, packet });
+                }
+            }
+            TransportEvent::Migrated { connection, addr } => {
+                if let Some(&connection) = self.owners.get(&route(connection)) {
+                    self.events.push(FallbackEvent::Migrated { connection, addr });
+                }
+            }
+            TransportEvent::Closed { connection, error } => {
+                // Incoming connections that never authenticated were never reported
+                if let Some(&connection) = self.owners.get(&route(connection)) {
+                    self.unroute(connection);
+                    self.events.push(FallbackEvent::Closed { connection, error: error.into() });
+                }
+            }
+        }
+    }
+}
+
+impl Transport for FallbackTransport {
+    type Input = FallbackInput;
+    /// Datagrams first, then socket commands
+    type Output = FallbackOutput;
+    type Error = FallbackError;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, FallbackError> {
+        Ok(FallbackTransport::dial(self, addr, now_ms))
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        Transport::listen(&mut self.quic, enabled);
+        Transport::listen(&mut self.tcp, enabled);
+    }
+
+    fn handle(&mut self, input: FallbackInput, now_ms: u64) -> Option<ConnectionId> {
+        match input {
+            FallbackInput::Datagram(from, datagram) => self.on_datagram(from, &datagram, now_ms),
+            FallbackInput::Tcp(TcpInput::Accepted(addr)) => {
+                return Some(self.on_tcp_accepted(addr, now_ms));
+            }
+            FallbackInput::Tcp(TcpInput::Data { connection, bytes }) => self.on_tcp_data(connection, &bytes, now_ms),
+            FallbackInput::Tcp(TcpInput::Closed(connection)) => self.on_tcp_closed(connection, now_ms),
+        }
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), FallbackError> {
+        FallbackTransport::send(self, connection, packet, now_ms)
+    }
+
+    fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        FallbackTransport::close(self, connection, now_ms)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        FallbackTransport::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        FallbackTransport::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        FallbackTransport::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        FallbackTransport::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        FallbackTransport::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<FallbackOutput> {
+        let datagrams = self.take_datagrams().into_iter().map(|(to, datagram)| FallbackOutput::Datagram(to, datagram));
+        datagrams.chain(self.take_tcp_commands().into_iter().map(FallbackOutput::Tcp)).collect()
+    }
+
+    fn take_events(&mut self) -> Vec<FallbackEvent> {
+        FallbackTransport::take_events(self)
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{ IpAddr, Ipv6Addr, SocketAddr };
+use std::str::FromStr;
+
+use crate::onion::path::prefix_eq;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum FilterError {
+    #[error("{0} is on the deny list")]
+    Denied(IpAddr),
+    #[error("{0} is not on the allow list")]
+    NotAllowed(IpAddr),
+    #[error("{0} already has {1} connections open")]
+    TooManyConnections(IpAddr, usize),
+    #[error("{0} opens connections too fast")]
+    RateLimited(IpAddr),
+    #[error("Invalid network {0:?}")]
+    InvalidNetwork(String),
+}
+
+/// An address block, written as in "203.0.113.0/24", or a bare address for that address alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, FilterError> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(FilterError::InvalidNetwork(format!("{addr}/{prefix}")));
+        }
+        Ok(Self { addr: addr.to_canonical(), prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_eq(&net.octets(), &ip.octets(), self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, FilterError> {
+        let invalid = || FilterError::InvalidNetwork(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix = prefix.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Self::new(addr, prefix).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptConfig {
+    /// Only addresses in these blocks are accepted; anyone not denied if empty
+    pub allow: Vec<IpNetwork>,
+    /// Addresses in these blocks are refused, allowed or not
+    pub deny: Vec<IpNetwork>,
+    /// Connections one source may have open at once
+    pub max_per_source: Option<usize>,
+    /// New connections one source may open per second, sustained
+    pub attempts_per_sec: f64,
+    /// New connections one source may open in a burst above the sustained rate
+    pub attempt_burst: u32,
+    /// IPv6 addresses count as one source per block of this prefix length: a host usually has
+    /// a whole /64 to pick addresses from
+    pub ipv6_prefix: u8,
+}
+
+impl Default for AcceptConfig {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_per_source: Some(32),
+            attempts_per_sec: 2.0,
+            attempt_burst: 20,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+struct Source {
+    open: usize,
+    /// Connections the source may open right now, as of `updated_ms`
+    tokens: f64,
+    updated_ms: u64,
+}
+
+/// Decides whether to take a connection peers open to us, on their address alone and before
+/// any of the handshake is processed, so a relay's operator can shut out abusive sources
+/// cheaply: CIDR allow and deny lists, a cap on the connections one source keeps open, and a
+/// token bucket on how fast it opens new ones. Nothing here looks at where an address is.
+///
+/// Transports check every connection they accept with `admit` and `release` it once it is
+/// gone; `prune` forgets sources with nothing open that are back to a full bucket.
+pub struct AcceptFilter {
+    config: AcceptConfig,
+    sources: HashMap<IpAddr, Source>,
+}
+
+impl AcceptFilter {
+    pub fn new(config: AcceptConfig) -> Self {
+        Self { config, sources: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &AcceptConfig {
+        &self.config
+    }
+
+    /// Replaces the rules. Connections already open stay, and count towards the new limits.
+    pub fn set_config(&mut self, config: AcceptConfig) {
+        self.config = config;
+    }
+
+    /// Takes a connection from `addr` if the rules allow it, counting it as open.
+    pub fn admit(&mut self, addr: SocketAddr, now_ms: u64) -> Result<(), FilterError> {
+        let ip = addr.ip().to_canonical();
+        if self.config.deny.iter().any(|net| net.contains(ip)) {
+            return Err(FilterError::Denied(ip));
+        }
+        if !self.config.allow.is_empty() && !self.config.allow.iter().any(|net| net.contains(ip)) {
+            return Err(FilterError::NotAllowed(ip));
+        }
+
+        let burst = self.config.attempt_burst as f64;
+        let source = self.sources
+            .entry(self.source(ip))
+            .or_insert(Source { open: 0, tokens: burst, updated_ms: now_ms });
+        let elapsed = now_ms.saturating_sub(source.updated_ms) as f64 / 1000.0;
+        source.tokens = (source.tokens + elapsed * self.config.attempts_per_sec).min(burst);
+        source.updated_ms = source.updated_ms.max(now_ms);
+        if source.tokens < 1.0 {
+            return Err(FilterError::RateLimited(ip));
+        }
+        source.tokens -= 1.0;
+        if let Some(max) = self.config.max_per_source
+            && source.open >= max
+        {
+            return Err(FilterError::TooManyConnections(ip, source.open));
+        }
+        source.open += 1;
+        Ok(())
+    }
+
+    /// A connection `admit` took from `addr` is gone.
+    pub fn release(&mut self, addr: SocketAddr) {
+        let key = self.source(addr.ip().to_canonical());
+        if let Some(source) = self.sources.get_mut(&key) {
+            source.open = source.open.saturating_sub(1);
+        }
+    }
+
+    /// Connections open from the source `ip` is part of
+    pub fn open_from(&self, ip: IpAddr) -> usize {
+        self.sources.get(&self.source(ip.to_canonical())).map_or(0, |s| s.open)
+    }
+
+    /// Forgets sources with nothing open whose bucket is full again.
+    pub fn prune(&mut self, now_ms: u64) {
+        let (rate, burst) = (self.config.attempts_per_sec, self.config.attempt_burst as f64);
+        self.sources.retain(|_, s| {
+            let elapsed = now_ms.saturating_sub(s.updated_ms) as f64 / 1000.0;
+            s.open > 0 || s.tokens + elapsed * rate < burst
+        });
+    }
+
+    /// What limits count by: the address, or its block for IPv6
+    fn source(&self, ip: IpAddr) -> IpAddr {
+        let IpAddr::V6(v6) = ip else {
+            return ip;
+        };
+        let bits = self.config.ipv6_prefix.min(128) as u32;
+        let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+        IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+    }
+}
+
+impl Default for AcceptFilter {
+    fn default() -> Self {
+        Self::new(AcceptConfig::default())
+    }
+}
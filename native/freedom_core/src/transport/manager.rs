@@ -0,0 +1,450 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+
+use super::{ ConnectionId, Throughput, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::HandshakePayload;
+use crate::dht::node_id::NodeId;
+use crate::dht::peer_store::PeerStore;
+use crate::protocol::packet::NetworkPacket;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManagerError<E> {
+    #[error("Unknown connection {0:?}")]
+    UnknownConnection(ConnectionId),
+    #[error("No address to dial")]
+    NoAddress,
+    #[error("Connection limit of {0} reached")]
+    TooManyConnections(usize),
+    #[error("Peer already has {0} connections")]
+    TooManyForPeer(usize),
+    #[error("Dialled {expected:?} but reached {got:?}")]
+    WrongPeer {
+        expected: NodeId,
+        got: NodeId,
+    },
+    #[error("Idle for too long")]
+    Idle,
+    #[error("Transport: {0}")]
+    Transport(E),
+}
+
+pub type ManagerEvent<E> = TransportEvent<ManagerError<E>>;
+
+/// Throughput is sampled from the transport's counters at most this often
+const THROUGHPUT_SAMPLE_MS: u64 = 1000;
+/// Time for a throughput sample's weight in the average to halve
+const THROUGHPUT_HALFLIFE_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManagerConfig {
+    /// Connections open or being dialled, all peers together
+    pub max_connections: usize,
+    /// Authenticated connections to one node
+    pub max_per_peer: usize,
+    /// Connections without a packet either way for this long are closed
+    pub idle_timeout_ms: u64,
+    /// How long a dial gets before the next address of the node is tried alongside it
+    pub attempt_delay_ms: u64,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        Self { max_connections: 256, max_per_peer: 2, idle_timeout_ms: 5 * 60 * 1000, attempt_delay_ms: 250 }
+    }
+}
+
+struct Pooled {
+    node: NodeId,
+    last_active_ms: u64,
+    meter: Meter,
+}
+
+/// Averages a connection's byte counters into rates, recent samples weighing most.
+struct Meter {
+    sampled_ms: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    sent: f64,
+    received: f64,
+    /// The highest average either way so far
+    peak: f64,
+}
+
+impl Meter {
+    fn new(now_ms: u64, stats: Option<TransportStats>) -> Self {
+        let stats = stats.unwrap_or_default();
+        Self { sampled_ms: now_ms, bytes_sent: stats.bytes_sent, bytes_received: stats.bytes_received, sent: 0.0, received: 0.0, peak: 0.0 }
+    }
+
+    fn sample(&mut self, stats: &TransportStats, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.sampled_ms);
+        let weight = 1.0 - 0.5f64.powf(elapsed as f64 / THROUGHPUT_HALFLIFE_MS as f64);
+        let rate = |bytes: u64, before: u64| bytes.saturating_sub(before) as f64 * 1000.0 / elapsed as f64;
+        self.sent += weight * (rate(stats.bytes_sent, self.bytes_sent) - self.sent);
+        self.received += weight * (rate(stats.bytes_received, self.bytes_received) - self.received);
+        self.peak = self.peak.max(self.sent).max(self.received);
+        (self.sampled_ms, self.bytes_sent, self.bytes_received) = (now_ms, stats.bytes_sent, stats.bytes_received);
+    }
+}
+
+/// The dials racing to one node
+struct Race {
+    /// The first attempt, which names the race until one wins
+    first: ConnectionId,
+    attempts: Vec<ConnectionId>,
+    /// Addresses not dialled yet, in the order they will be
+    remaining: VecDeque<SocketAddr>,
+    next_attempt_ms: u64,
+}
+
+/// Keeps the connections of a node to its peers, by `NodeId`, over any `Transport`.
+///
+/// `connect` hands back a connection the peer already has, or the dial already under way to
+/// it, before dialling anew; dials that reach another node than the one asked for are
+/// closed. Nodes with several addresses are dialled Happy Eyeballs style (RFC 8305) by
+/// `connect_any`: IPv6 and IPv4 addresses alternate, a new attempt starts every
+/// `attempt_delay_ms` (or as soon as one fails), and the first to authenticate wins while the
+/// others are closed. Connections beyond `max_connections`, or beyond `max_per_peer` to one node, are
+/// refused, and those idle for `idle_timeout_ms` are closed and reported as `Closed` with
+/// `ManagerError::Idle`.
+///
+/// The transport's `TransportStats` of each connection are averaged into its `throughput`
+/// every `THROUGHPUT_SAMPLE_MS` the host calls in, and the best a node has sustained is its
+/// `measured_bandwidth`, for path selection to weigh it by.
+///
+/// The host drives it as it would the transport: `handle`, `take_outgoing`, `on_timeout`.
+pub struct ConnectionManager<T: Transport> {
+    transport: T,
+    config: ManagerConfig,
+    /// Dials not authenticated yet: the race to each node, and the node of each attempt
+    races: HashMap<NodeId, Race>,
+    dialled: HashMap<ConnectionId, NodeId>,
+    connections: HashMap<ConnectionId, Pooled>,
+    peers: HashMap<NodeId, Vec<ConnectionId>>,
+    events: Vec<ManagerEvent<T::Error>>,
+}
+
+impl<T: Transport> ConnectionManager<T> {
+    pub fn new(transport: T, config: ManagerConfig) -> Self {
+        Self {
+            transport,
+            config,
+            races: HashMap::new(),
+            dialled: HashMap::new(),
+            connections: HashMap::new(),
+            peers: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// A connection to `node`: the most recently active one open, the dial under way, or a
+    /// new dial to `addr`.
+    pub fn connect(&mut self, node: NodeId, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, ManagerError<T::Error>> {
+        self.connect_any(node, &[addr], now_ms)
+    }
+
+    /// As `connect`, racing dials to each of `addrs` (the node's, most preferred first).
+    ///
+    /// Returns the first attempt, which stands for the race: if every attempt fails it is
+    /// reported `Closed`, but the attempt that wins is reported `Connected` under its own id.
+    pub fn connect_any(&mut self, node: NodeId, addrs: &[SocketAddr], now_ms: u64) -> Result<ConnectionId, ManagerError<T::Error>> {
+        if let Some(connection) = self.connection(&node).or_else(|| self.races.get(&node).map(|race| race.first)) {
+            return Ok(connection);
+        }
+        if self.len() >= self.config.max_connections {
+            return Err(ManagerError::TooManyConnections(self.config.max_connections));
+        }
+        let mut remaining = interleave(addrs);
+        let addr = remaining.pop_front().ok_or(ManagerError::NoAddress)?;
+        let connection = self.transport.dial(addr, now_ms).map_err(ManagerError::Transport)?;
+        self.dialled.insert(connection, node);
+        let race = Race { first: connection, attempts: vec![connection], remaining, next_attempt_ms: now_ms + self.config.attempt_delay_ms };
+        self.races.insert(node, race);
+        self.pump(now_ms);
+        Ok(connection)
+    }
+
+    /// The most recently active authenticated connection to `node`
+    pub fn connection(&self, node: &NodeId) -> Option<ConnectionId> {
+        self.peers.get(node)?.iter().copied().max_by_key(|connection| self.connections[connection].last_active_ms)
+    }
+
+    /// The node at the other end of an authenticated connection
+    pub fn node(&self, connection: ConnectionId) -> Option<NodeId> {
+        Some(self.connections.get(&connection)?.node)
+    }
+
+    /// Handles what the host's sockets did, as `Transport::handle`.
+    pub fn handle(&mut self, input: T::Input, now_ms: u64) -> Option<ConnectionId> {
+        let accepted = self.transport.handle(input, now_ms);
+        self.pump(now_ms);
+        accepted
+    }
+
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), ManagerError<T::Error>> {
+        let pooled = self.connections.get_mut(&connection).ok_or(ManagerError::UnknownConnection(connection))?;
+        pooled.last_active_ms = now_ms;
+        self.transport.send(connection, packet, now_ms).map_err(ManagerError::Transport)?;
+        self.pump(now_ms);
+        Ok(())
+    }
+
+    /// Closes a connection, or gives up a dial (all of its race). Returns false if it was
+    /// unknown.
+    pub fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        let racing = self.races.iter().find(|(_, race)| race.first == connection || race.attempts.contains(&connection));
+        if let Some(node) = racing.map(|(node, _)| *node) {
+            self.abandon(node, now_ms);
+        } else if self.forget(connection).is_some() {
+            self.transport.close(connection, now_ms);
+        } else {
+            return false;
+        }
+        self.pump(now_ms);
+        true
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&mut self) -> Option<u64> {
+        let idle = self.connections.values().map(|c| c.last_active_ms + self.config.idle_timeout_ms).min();
+        let attempt = self.races.values().filter(|race| !race.remaining.is_empty()).map(|race| race.next_attempt_ms).min();
+        [self.transport.next_timeout(), idle, attempt].into_iter().flatten().min()
+    }
+
+    /// Runs the transport's timers, starts the next attempt of races that waited long enough
+    /// and closes the connections that went idle.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let due: Vec<NodeId> = self.races
+            .iter()
+            .filter(|(_, race)| !race.remaining.is_empty() && race.next_attempt_ms <= now_ms)
+            .map(|(node, _)| *node)
+            .collect();
+        for node in due {
+            self.next_attempt(node, now_ms);
+        }
+
+        let idle: Vec<ConnectionId> = self.connections
+            .iter()
+            .filter(|(_, c)| c.last_active_ms + self.config.idle_timeout_ms <= now_ms)
+            .map(|(connection, _)| *connection)
+            .collect();
+        for connection in idle {
+            log::debug!("Closing idle connection {connection:?}");
+            self.forget(connection);
+            self.transport.close(connection, now_ms);
+            self.events.push(ManagerEvent::Closed { connection, error: ManagerError::Idle });
+        }
+        self.transport.on_timeout(now_ms);
+        self.pump(now_ms);
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.transport.peer(connection)
+    }
+
+    /// What an authenticated connection carried so far: round trips, losses, bytes and packets
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        self.connections.get(&connection)?;
+        self.transport.stats(connection)
+    }
+
+    /// How fast an authenticated connection moves bytes lately, as of the last sample
+    pub fn throughput(&self, connection: ConnectionId) -> Option<Throughput> {
+        let meter = &self.connections.get(&connection)?.meter;
+        Some(Throughput { sent: meter.sent as u32, received: meter.received as u32 })
+    }
+
+    /// The most bytes per second one of our connections to `node` has averaged either way:
+    /// what the node has shown it can carry, at least.
+    pub fn measured_bandwidth(&self, node: &NodeId) -> Option<u32> {
+        let peak = self.peers.get(node)?.iter().map(|c| self.connections[c].meter.peak).fold(0.0, f64::max);
+        Some(peak as u32)
+    }
+
+    /// Where an authenticated connection's peer is now, after any migration
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        self.connections.get(&connection)?;
+        self.transport.remote_address(connection)
+    }
+
+    /// Records in `store` where each node we are connected to is reached now, first among
+    /// its addresses: those that migrated keep their connections, and are dialled at the new
+    /// address next time. `now` is in seconds, as the store keeps it.
+    pub fn record_addresses(&self, store: &mut PeerStore, now: u64) {
+        for (connection, pooled) in &self.connections {
+            if let Some(addr) = self.transport.remote_address(*connection) {
+                store.record_seen(pooled.node, addr, now);
+            }
+        }
+    }
+
+    /// Connections open or being dialled
+    pub fn len(&self) -> usize {
+        self.connections.len() + self.dialled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<T::Output> {
+        self.transport.take_outgoing()
+    }
+
+    pub fn take_events(&mut self) -> Vec<ManagerEvent<T::Error>> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Starts the next attempt of the race to `node`, if it has addresses left and the
+    /// connection limit allows. Attempts the transport refuses outright count as failed.
+    fn next_attempt(&mut self, node: NodeId, now_ms: u64) {
+        while self.len() < self.config.max_connections
+            && let Some(race) = self.races.get_mut(&node)
+            && let Some(addr) = race.remaining.pop_front()
+        {
+            race.next_attempt_ms = now_ms + self.config.attempt_delay_ms;
+            match self.transport.dial(addr, now_ms) {
+                Ok(connection) => {
+                    log::debug!("Racing a dial to {addr} for {node:?}");
+                    race.attempts.push(connection);
+                    self.dialled.insert(connection, node);
+                    return;
+                }
+                Err(e) => log::debug!("Dial to {addr} failed: {e}"),
+            }
+        }
+    }
+
+    /// Takes a failed attempt out of its race, starting the next one straight away. Reports
+    /// the race `Closed` with `error` once nothing is left to try.
+    fn attempt_failed(&mut self, connection: ConnectionId, error: ManagerError<T::Error>, now_ms: u64) {
+        let Some(node) = self.dialled.remove(&connection) else {
+            return;
+        };
+        let Some(race) = self.races.get_mut(&node) else {
+            return;
+        };
+        race.attempts.retain(|c| *c != connection);
+        self.next_attempt(node, now_ms);
+        if self.races.get(&node).is_some_and(|race| race.attempts.is_empty()) {
+            let race = self.races.remove(&node).expect("checked above");
+            self.events.push(ManagerEvent::Closed { connection: race.first, error });
+        }
+    }
+
+    /// Gives up the race to `node`, closing its attempts.
+    fn abandon(&mut self, node: NodeId, now_ms: u64) {
+        for connection in self.races.remove(&node).map(|race| race.attempts).unwrap_or_default() {
+            self.dialled.remove(&connection);
+            self.transport.close(connection, now_ms);
+        }
+    }
+
+    fn forget(&mut self, connection: ConnectionId) -> Option<NodeId> {
+        let node = self.connections.remove(&connection)?.node;
+        if let Some(connections) = self.peers.get_mut(&node) {
+            connections.retain(|c| *c != connection);
+            if connections.is_empty() {
+                self.peers.remove(&node);
+            }
+        }
+        Some(node)
+    }
+
+    /// Takes the transport's events in: admits or refuses new connections and keeps the
+    /// pool up to date. Then samples the throughput of connections due for it.
+    fn pump(&mut self, now_ms: u64) {
+        for event in self.transport.take_events() {
+            match event {
+                TransportEvent::Connected { connection, addr, peer } => {
+                    let node = NodeId::from_identity_key(&peer.identity_key);
+                    let expected = self.dialled.get(&connection).copied();
+                    let refused = match expected {
+                        Some(expected) if expected != node => Some(ManagerError::WrongPeer { expected, got: node }),
+                        // Our own dials were counted when made
+                        None if self.len() >= self.config.max_connections => {
+                            Some(ManagerError::TooManyConnections(self.config.max_connections))
+                        }
+                        _ if self.peers.get(&node).is_some_and(|c| c.len() >= self.config.max_per_peer) => {
+                            Some(ManagerError::TooManyForPeer(self.config.max_per_peer))
+                        }
+                        _ => None,
+                    };
+                    if let Some(error) = refused {
+                        log::debug!("Refusing connection {connection:?} from {addr}: {error}");
+                        self.transport.close(connection, now_ms);
+                        // Peers that dialled us were never reported; a dial of ours leaves its race
+                        self.attempt_failed(connection, error, now_ms);
+                        continue;
+                    }
+                    if let Some(expected) = expected {
+                        // The race is won: the other attempts are closed
+                        self.dialled.remove(&connection);
+                        if let Some(race) = self.races.get_mut(&expected) {
+                            race.attempts.retain(|c| *c != connection);
+                        }
+                        self.abandon(expected, now_ms);
+                    }
+                    let meter = Meter::new(now_ms, self.transport.stats(connection));
+                    self.connections.insert(connection, Pooled { node, last_active_ms: now_ms, meter });
+                    self.peers.entry(node).or_default().push(connection);
+                    self.events.push(ManagerEvent::Connected { connection, addr, peer });
+                }
+                TransportEvent::Packet { connection, packet } => {
+                    if let Some(pooled) = self.connections.get_mut(&connection) {
+                        pooled.last_active_ms = now_ms;
+                        self.events.push(ManagerEvent::Packet { connection, packet });
+                    }
+                }
+                TransportEvent::Migrated { connection, addr } => {
+                    if let Some(pooled) = self.connections.get_mut(&connection) {
+                        pooled.last_active_ms = now_ms;
+                        log::debug!("{:?} is now at {addr}", pooled.node);
+                        self.events.push(ManagerEvent::Migrated { connection, addr });
+                    }
+                }
+                TransportEvent::Closed { connection, error } => {
+                    if self.dialled.contains_key(&connection) {
+                        self.attempt_failed(connection, ManagerError::Transport(error), now_ms);
+                    } else if self.forget(connection).is_some() {
+                        self.events.push(ManagerEvent::Closed { connection, error: ManagerError::Transport(error) });
+                    }
+                }
+            }
+        }
+        for (connection, pooled) in &mut self.connections {
+            if now_ms >= pooled.meter.sampled_ms + THROUGHPUT_SAMPLE_MS
+                && let Some(stats) = self.transport.stats(*connection)
+            {
+                pooled.meter.sample(&stats, now_ms);
+            }
+        }
+    }
+}
+
+/// Orders addresses for a race: IPv6 and IPv4 alternating, IPv6 first, each family in the
+/// order given.
+fn interleave(addrs: &[SocketAddr]) -> VecDeque<SocketAddr> {
+    let mut unique: Vec<SocketAddr> = Vec::new();
+    for addr in addrs {
+        if !unique.contains(addr) {
+            unique.push(*addr);
+        }
+    }
+    let (mut v6, mut v4): (VecDeque<SocketAddr>, VecDeque<SocketAddr>) = unique.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ordered = VecDeque::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop_front());
+        ordered.extend(v4.pop_front());
+    }
+    ordered
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use crate::dht::node_id::{ NodeId, NODE_ID_SIZE };
+use crate::dht::peer_store::PeerStore;
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// The mDNS multicast groups (RFC 6762)
+pub const MDNS_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+pub const MDNS_V6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb)), 5353);
+/// The DNS-SD service nodes register under
+pub const SERVICE_NAME: &str = "_freedomnode._udp.local";
+/// Nodes remembered from the LAN; announcements of more are ignored
+pub const MAX_LOCAL_PEERS: usize = 256;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the records only we hold, so caches replace theirs instead of adding to them
+const CACHE_FLUSH: u16 = 0x8000;
+/// Response, authoritative answer
+const FLAGS_RESPONSE: u16 = 0x8400;
+/// Least time between two of our multicast answers (RFC 6762 §6)
+const MIN_ANSWER_INTERVAL_MS: u64 = 1_000;
+/// Queries start a second apart and back off to this
+const MAX_QUERY_INTERVAL_MS: u64 = 60 * 60 * 1000;
+/// Compression pointers followed in one name, against loops
+const MAX_NAME_JUMPS: usize = 16;
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsConfig {
+    /// The port our node listens on
+    pub port: u16,
+    /// LAN addresses to announce; without any, peers use the one announcements come from
+    pub addresses: Vec<IpAddr>,
+    /// How long peers may cache our records. We announce again halfway through.
+    pub ttl_secs: u32,
+    /// Also announce and query on the IPv6 group
+    pub ipv6: bool,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self { port: 0, addresses: Vec::new(), ttl_secs: 120, ipv6: false }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MdnsEvent {
+    /// A node on the LAN announced itself, or moved
+    Discovered {
+        node: NodeId,
+        addrs: Vec<SocketAddr>,
+    },
+    /// A node said goodbye, or stopped announcing itself
+    Departed(NodeId),
+}
+
+/// A record's data, as far as discovery reads it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RecordData {
+    Ptr(String),
+    Srv {
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<Vec<u8>>),
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    ttl: u32,
+    data: RecordData,
+}
+
+/// A DNS message: its questions as (name, type), and the records of all its sections. Names
+/// are lowercase.
+struct Message {
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+impl Message {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let _id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+
+        let mut message = Message { response: flags & 0x8000 != 0, questions: Vec::new(), records: Vec::new() };
+        for _ in 0..questions {
+            let name = read_name(bytes, &mut reader)?;
+            let kind = reader.u16()?;
+            let _class = reader.u16()?;
+            message.questions.push((name, kind));
+        }
+        for _ in 0..records {
+            let name = read_name(bytes, &mut reader)?;
+            let kind = reader.u16()?;
+            let _class = reader.u16()?;
+            let ttl = reader.u32()?;
+            let len = reader.u16()? as usize;
+            let start = reader.offset();
+            let rdata = reader.take(len)?;
+            let data = match kind {
+                TYPE_PTR => RecordData::Ptr(parse_name(bytes, start)?.0),
+                TYPE_SRV => {
+                    let mut srv = Reader::new(rdata);
+                    let _priority_weight = srv.u32()?;
+                    RecordData::Srv { port: srv.u16()?, target: parse_name(bytes, start + 6)?.0 }
+                }
+                TYPE_TXT => {
+                    let mut txt = Reader::new(rdata);
+                    let mut strings = Vec::new();
+                    while txt.remaining() > 0 {
+                        let len = txt.u8()? as usize;
+                        strings.push(txt.take(len)?.to_vec());
+                    }
+                    RecordData::Txt(strings)
+                }
+                TYPE_A => RecordData::A(Ipv4Addr::from(<[u8; 4]>::try_from(rdata).map_err(|_| CodecError::InvalidField("A record"))?)),
+                TYPE_AAAA => RecordData::Aaaa(Ipv6Addr::from(<[u8; 16]>::try_from(rdata).map_err(|_| CodecError::InvalidField("AAAA record"))?)),
+                _ => RecordData::Other,
+            };
+            message.records.push(Record { name, ttl, data });
+        }
+        Ok(message)
+    }
+
+    /// The first record named `name` with data that `matches`
+    fn find(&self, name: &str, matches: impl Fn(&RecordData) -> bool) -> Option<&Record> {
+        self.records.iter().find(|r| r.name == name && matches(&r.data))
+    }
+}
+
+/// Announces this node on the LAN over mDNS (as a DNS-SD service, `SERVICE_NAME`) and finds
+/// the others doing so, adding them to the peer store: nodes on one network, or on a mesh
+/// cut off from the Internet, find each other without seed nodes.
+///
+/// Announcements are unauthenticated, so a node found this way is only as trusted as any
+/// address: the handshake proves who answers when it is dialled (`ConnectionManager` closes
+/// dials that reach another node). Like the other components it is sans-IO: the host joins
+/// `MDNS_V4` (and `MDNS_V6`) on port 5353, feeds what arrives to `on_datagram` and sends
+/// what `take_outgoing` returns.
+pub struct LocalDiscovery {
+    node: NodeId,
+    config: MdnsConfig,
+    /// Our instance and host names
+    instance: String,
+    host: String,
+    announcements: u32,
+    next_announce_ms: Option<u64>,
+    next_query_ms: Option<u64>,
+    query_interval_ms: u64,
+    last_answer_ms: Option<u64>,
+    /// Nodes found, at which addresses, until when
+    peers: HashMap<NodeId, (Vec<SocketAddr>, u64)>,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+    events: Vec<MdnsEvent>,
+}
+
+impl LocalDiscovery {
+    pub fn new(node: NodeId, config: MdnsConfig) -> Self {
+        let label: String = node.as_bytes()[..8].iter().map(|b| format!("{b:02x}")).collect();
+        Self {
+            node,
+            config,
+            instance: format!("{label}.{SERVICE_NAME}"),
+            host: format!("{label}.local"),
+            announcements: 0,
+            next_announce_ms: None,
+            next_query_ms: None,
+            query_interval_ms: MIN_ANSWER_INTERVAL_MS,
+            last_answer_ms: None,
+            peers: HashMap::new(),
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Announces this node and asks who else is there.
+    pub fn start(&mut self, now_ms: u64) {
+        self.next_announce_ms = Some(now_ms);
+        self.next_query_ms = Some(now_ms);
+        self.on_timeout(now_ms);
+    }
+
+    /// Handles a datagram from the mDNS group, answering queries for our service and adding
+    /// the nodes announced to `store`. Returns false if it wasn't an mDNS message.
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8], now_ms: u64, store: &mut PeerStore) -> bool {
+        let message = match Message::from_bytes(data) {
+            Ok(message) => message,
+            Err(e) => {
+                log::debug!("Malformed mDNS message from {from}: {e}");
+                return false;
+            }
+        };
+        if !message.response {
+            let asked = message.questions.iter().any(|(name, kind)| {
+                (*name == SERVICE_NAME && matches!(*kind, TYPE_PTR | TYPE_ANY)) || *name == self.instance || *name == self.host
+            });
+            if asked && self.next_announce_ms.is_some() && self.last_answer_ms.is_none_or(|at| at + MIN_ANSWER_INTERVAL_MS <= now_ms) {
+                self.announce(self.config.ttl_secs, now_ms);
+            }
+            return true;
+        }
+
+        for record in message.records.iter().filter(|r| r.name == SERVICE_NAME) {
+            if let RecordData::Ptr(instance) = &record.data
+                && *instance != self.instance
+            {
+                self.on_instance(&message, instance, record.ttl, from, now_ms, store);
+            }
+        }
+        true
+    }
+
+    /// Says goodbye, so peers forget us at once rather than when our records expire.
+    pub fn shutdown(&mut self) {
+        if self.next_announce_ms.take().is_some() {
+            self.next_query_ms = None;
+            self.send(self.records(0));
+        }
+    }
+
+    /// Nodes found on the LAN, and where
+    pub fn peers(&self) -> impl Iterator<Item = (&NodeId, &[SocketAddr])> {
+        self.peers.iter().map(|(node, (addrs, _))| (node, addrs.as_slice()))
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&self) -> Option<u64> {
+        let expiry = self.peers.values().map(|(_, until)| *until).min();
+        [self.next_announce_ms, self.next_query_ms, expiry].into_iter().flatten().min()
+    }
+
+    /// Announces and queries again when due, and forgets nodes whose records expired.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        if self.next_announce_ms.is_some_and(|at| at <= now_ms) {
+            self.announce(self.config.ttl_secs, now_ms);
+            // Twice a second apart at first (RFC 6762 §8.3), then before the records expire
+            self.announcements += 1;
+            let wait = if self.announcements < 2 { MIN_ANSWER_INTERVAL_MS } else { self.config.ttl_secs as u64 * 500 };
+            self.next_announce_ms = Some(now_ms + wait.max(MIN_ANSWER_INTERVAL_MS));
+        }
+        if self.next_query_ms.is_some_and(|at| at <= now_ms) {
+            self.query();
+            self.next_query_ms = Some(now_ms + self.query_interval_ms);
+            self.query_interval_ms = (self.query_interval_ms * 2).min(MAX_QUERY_INTERVAL_MS);
+        }
+
+        let expired: Vec<NodeId> = self.peers.iter().filter(|(_, (_, until))| *until <= now_ms).map(|(node, _)| *node).collect();
+        for node in expired {
+            self.peers.remove(&node);
+            self.events.push(MdnsEvent::Departed(node));
+        }
+    }
+
+    /// Datagrams to send, to `MDNS_V4` or `MDNS_V6`
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_events(&mut self) -> Vec<MdnsEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Takes in an instance of our service a response pointed to.
+    fn on_instance(&mut self, message: &Message, instance: &str, ttl: u32, from: SocketAddr, now_ms: u64, store: &mut PeerStore) {
+        let node = message.find(instance, |d| matches!(d, RecordData::Txt(_))).and_then(|txt| match &txt.data {
+            RecordData::Txt(strings) => strings.iter().find_map(|s| parse_node_id(s)),
+            _ => None,
+        });
+        let Some(node) = node.filter(|node| *node != self.node) else {
+            return;
+        };
+        if ttl == 0 {
+            if self.peers.remove(&node).is_some() {
+                self.events.push(MdnsEvent::Departed(node));
+            }
+            return;
+        }
+        let Some(RecordData::Srv { port, target }) = message.find(instance, |d| matches!(d, RecordData::Srv { .. })).map(|r| &r.data) else {
+            return;
+        };
+
+        let mut ips: Vec<IpAddr> = message.records
+            .iter()
+            .filter(|r| r.name == *target)
+            .filter_map(|r| match r.data {
+                RecordData::A(ip) => Some(IpAddr::V4(ip)),
+                RecordData::Aaaa(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect();
+        if ips.is_empty() {
+            ips.push(from.ip());
+        }
+        let addrs: Vec<SocketAddr> = ips.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect();
+
+        if !self.peers.contains_key(&node) && self.peers.len() >= MAX_LOCAL_PEERS {
+            return;
+        }
+        // Recorded last to first, so the store prefers the first
+        for addr in addrs.iter().rev() {
+            store.record_seen(node, *addr, now_ms / 1000);
+        }
+        let until = now_ms + ttl as u64 * 1000;
+        match self.peers.insert(node, (addrs.clone(), until)) {
+            Some((known, _)) if known == addrs => {}
+            _ => {
+                log::debug!("Found {node:?} on the LAN at {addrs:?}");
+                self.events.push(MdnsEvent::Discovered { node, addrs });
+            }
+        }
+    }
+
+    fn announce(&mut self, ttl: u32, now_ms: u64) {
+        self.last_answer_ms = Some(now_ms);
+        let records = self.records(ttl);
+        self.send(records);
+    }
+
+    /// A response with our records: the service pointer, our instance's SRV and TXT, and the
+    /// addresses of our host.
+    /// Format: [Id 0 (2)] [Flags (2)] [Questions 0 (2)] [Answers (2)] [0 (2)] [0 (2)] + Answers
+    fn records(&self, ttl: u32) -> Vec<u8> {
+        let mut records = Vec::new();
+        let mut count = 0u16;
+        let mut record = |name: &str, kind: u16, flush: bool, rdata: &[u8]| {
+            write_name(&mut records, name);
+            records.extend_from_slice(&kind.to_be_bytes());
+            records.extend_from_slice(&(CLASS_IN | if flush { CACHE_FLUSH } else { 0 }).to_be_bytes());
+            records.extend_from_slice(&ttl.to_be_bytes());
+            records.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            records.extend_from_slice(rdata);
+            count += 1;
+        };
+
+        let mut ptr = Vec::new();
+        write_name(&mut ptr, &self.instance);
+        record(SERVICE_NAME, TYPE_PTR, false, &ptr);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&self.config.port.to_be_bytes());
+        write_name(&mut srv, &self.host);
+        record(&self.instance, TYPE_SRV, true, &srv);
+        let id: String = self.node.as_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        let txt = format!("id={id}");
+        let mut rdata = vec![txt.len() as u8];
+        rdata.extend_from_slice(txt.as_bytes());
+        record(&self.instance, TYPE_TXT, true, &rdata);
+        for ip in &self.config.addresses {
+            match ip {
+                IpAddr::V4(ip) => record(&self.host, TYPE_A, true, &ip.octets()),
+                IpAddr::V6(ip) => record(&self.host, TYPE_AAAA, true, &ip.octets()),
+            }
+        }
+
+        let mut out = Vec::with_capacity(12 + records.len());
+        out.extend_from_slice(&0u16.to_be_bytes());
+        out.extend_from_slice(&FLAGS_RESPONSE.to_be_bytes());
+        out.extend_from_slice(&0u16.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&records);
+        out
+    }
+
+    /// Asks for the instances of our service.
+    fn query(&mut self) {
+        let mut out = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut out, SERVICE_NAME);
+        out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        self.send(out);
+    }
+
+    fn send(&mut self, message: Vec<u8>) {
+        if self.config.ipv6 {
+            self.outgoing.push((MDNS_V6, message.clone()));
+        }
+        self.outgoing.push((MDNS_V4, message));
+    }
+}
+
+/// Reads the node id of a TXT string `id=<64 hex digits>`.
+fn parse_node_id(txt: &[u8]) -> Option<NodeId> {
+    let hex = txt.strip_prefix(b"id=").filter(|hex| hex.len() == NODE_ID_SIZE * 2)?;
+    let mut bytes = [0u8; NODE_ID_SIZE];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(NodeId::from_bytes(bytes))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Reads a name at the reader's position, which must be over the whole message.
+fn read_name(message: &[u8], reader: &mut Reader<'_>) -> Result<String, CodecError> {
+    let (name, end) = parse_name(message, reader.offset())?;
+    reader.take(end - reader.offset())?;
+    Ok(name)
+}
+
+/// Reads the (possibly compressed) name at `at`. Returns it lowercased, and where it ends.
+fn parse_name(message: &[u8], mut at: usize) -> Result<(String, usize), CodecError> {
+    let malformed = CodecError::InvalidField("name");
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *message.get(at).ok_or(malformed.clone())? as usize;
+        match len {
+            0 => break,
+            len if len & 0xC0 == 0xC0 => {
+                let low = *message.get(at + 1).ok_or(malformed.clone())? as usize;
+                end.get_or_insert(at + 2);
+                jumps += 1;
+                if jumps > MAX_NAME_JUMPS {
+                    return Err(malformed);
+                }
+                at = (len & 0x3F) << 8 | low;
+            }
+            len if len <= 63 => {
+                let label = message.get(at + 1..at + 1 + len).ok_or(malformed.clone())?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                if name.len() > MAX_NAME_LEN {
+                    return Err(malformed);
+                }
+                at += 1 + len;
+            }
+            _ => {
+                return Err(malformed);
+            }
+        }
+    }
+    Ok((name, end.unwrap_or(at + 1)))
+}
@@ -0,0 +1,161 @@
+// Transports carry `NetworkPacket`s between nodes. Like the protocol state machines they are
+// sans-IO: the host owns the sockets, feeds what it receives in and sends what comes out.
+// UDP (and so QUIC) is unavailable in the browser.
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fallback;
+pub mod filter;
+pub mod manager;
+pub mod mdns;
+pub mod nat;
+pub mod obfs;
+pub mod portmap;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod quic;
+pub mod queue;
+pub mod relay;
+pub mod socks;
+pub mod tcp;
+pub mod websocket;
+
+use std::net::SocketAddr;
+
+use crate::crypto::handshake::HandshakePayload;
+use crate::protocol::packet::NetworkPacket;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use fallback::{ FallbackError, FallbackEvent, FallbackInput, FallbackOutput, FallbackTransport };
+pub use filter::{ AcceptConfig, AcceptFilter, FilterError, IpNetwork };
+pub use manager::{ ConnectionManager, ManagerConfig, ManagerError, ManagerEvent };
+pub use mdns::{ LocalDiscovery, MdnsConfig, MdnsEvent };
+pub use nat::{ NatBehavior, NatConfig, NatError, NatEvent, NatTraversal, PunchMessage };
+pub use obfs::{ BridgeCert, ObfsConfig, ObfsError, ObfsEvent, ObfsTransport };
+pub use portmap::{ MappingProtocol, PortMapCommand, PortMapConfig, PortMapEvent, PortMapMethod, PortMapper, PortMapping };
+#[cfg(not(target_arch = "wasm32"))]
+pub use quic::{ QuicConfig, QuicEndpoint, QuicError, QuicEvent };
+pub use queue::{ QueueConfig, QueueError, QueueEvent, SendPriority, SendQueues };
+pub use relay::{ RelayClient, RelayError, RelayEvent, RelayMessage, RelayReason, RelayService, RelayServiceConfig };
+pub use socks::{ Socks5Config, Socks5Error, Socks5Event, Socks5Transport, SocksAddr, SocksReply };
+pub use tcp::{ TcpCommand, TcpConfig, TcpError, TcpEvent, TcpInput, TcpTransport };
+pub use websocket::{ WebSocketServer, WebSocketTransport, WsCommand, WsConfig, WsError, WsEvent, WsInput };
+
+/// A connection of one transport, never reused while the transport lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// The number behind the id, for hosts and bindings that name connections themselves
+    pub fn to_raw(self) -> u64 {
+        self.0
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
+}
+
+/// What a transport reports about its connections, `E` being its error type.
+#[derive(Debug)]
+pub enum TransportEvent<E> {
+    /// The peer proved its identity with a fresh `HandshakePayload`: packets can be sent
+    Connected {
+        connection: ConnectionId,
+        addr: SocketAddr,
+        peer: Box<HandshakePayload>,
+    },
+    Packet {
+        connection: ConnectionId,
+        packet: NetworkPacket,
+    },
+    /// The peer moved to `addr` (another network, a new NAT mapping) and answered there: the
+    /// connection carries on, and `addr` is where the peer is now reached
+    Migrated {
+        connection: ConnectionId,
+        addr: SocketAddr,
+    },
+    /// The connection is gone, closed by the peer, lost, or refused by us for breaking the
+    /// protocol. Not reported for connections closed with `close`.
+    Closed {
+        connection: ConnectionId,
+        error: E,
+    },
+}
+
+/// What a connection carried so far, and how well, for the operator and for weighing peers
+/// against each other. Counters start at the dial (or accept) and only grow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportStats {
+    /// Smoothed round-trip time, for transports that measure it (QUIC does, TCP leaves it to
+    /// the host's kernel)
+    pub rtt_ms: Option<u32>,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Bytes on the wire, overhead included, as far as the transport sees it: transports
+    /// wrapping another count what that one does
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Datagrams declared lost and sent again; streams over TCP never see theirs
+    pub lost_packets: u64,
+}
+
+/// How fast a connection moves bytes lately, in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throughput {
+    pub sent: u32,
+    pub received: u32,
+}
+
+/// What every transport offers, so the layers above can run over any of them (or several)
+/// without knowing which.
+///
+/// The socket I/O differs from one wire to the next and is left to the transport's own
+/// `Input` and `Output`: the host hands over what its sockets did with `handle` and carries
+/// out `take_outgoing`. Everything else is in `ConnectionId`s, `NetworkPacket`s and
+/// `TransportEvent`s, the same for all.
+///
+/// Every `now_ms` is wall-clock time in milliseconds since the UNIX epoch, not a monotonic
+/// clock: handshakes stamp and check their timestamps against it (`now_ms / 1000`), so a
+/// clock counting from boot would fail every one of them as stale.
+pub trait Transport {
+    /// What the host's sockets did: datagrams received, sockets accepted, bytes read...
+    type Input;
+    /// What the host is to do with its sockets, in order
+    type Output;
+    type Error: std::error::Error;
+
+    /// Starts a connection to the node listening at `addr`. `Connected` follows once the
+    /// peer is authenticated.
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, Self::Error>;
+
+    /// Whether to accept the connections peers dial
+    fn listen(&mut self, enabled: bool);
+
+    /// Handles one thing the host's sockets did. Returns the connection an accepted socket
+    /// was given, when the host is the one accepting them.
+    fn handle(&mut self, input: Self::Input, now_ms: u64) -> Option<ConnectionId>;
+
+    /// Sends a packet to an authenticated peer.
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), Self::Error>;
+
+    /// Closes a connection. Returns false if it was unknown.
+    fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool;
+
+    /// When `on_timeout` is due next
+    fn next_timeout(&mut self) -> Option<u64>;
+
+    fn on_timeout(&mut self, now_ms: u64);
+
+    /// The authenticated identity of a connection's peer
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload>;
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr>;
+
+    /// What a connection carried so far
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats>;
+
+    fn take_outgoing(&mut self) -> Vec<Self::Output>;
+
+    fn take_events(&mut self) -> Vec<TransportEvent<Self::Error>>;
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use crate::dht::contact::{ AddressKind, ContactInfo, Reachability, TransportAddress };
+use crate::dht::node_id::NodeId;
+use crate::protocol::codec::{ self, CodecError, Reader };
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+/// RFC 5389: present in every STUN message, which tells them apart from QUIC on the same socket
+pub const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+/// [Type (2)] [Length (2)] [Cookie (4)] [Transaction (12)]
+const STUN_HEADER_SIZE: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+const PUNCH_REQUEST: u8 = 1;
+const PUNCH_OFFER: u8 = 2;
+const PUNCH_ANSWER: u8 = 3;
+const PUNCH_GO: u8 = 4;
+
+pub type TransactionId = [u8; 12];
+
+#[derive(Debug, thiserror::Error)]
+pub enum NatError {
+    #[error("Not a STUN message")]
+    NotStun,
+    #[error("Malformed message: {0}")]
+    Malformed(#[from] CodecError),
+    #[error("Our address as seen from outside is not known yet")]
+    NoReflexiveAddress,
+    #[error("The NAT maps each destination to another port: a hole can't be punched")]
+    SymmetricNat,
+    #[error("Message type {0:?} is not a hole punching message")]
+    NotHolePunch(MessageType),
+}
+
+/// Whether `datagram` is STUN rather than QUIC: STUN starts with two zero bits (QUIC sets the
+/// second) and carries the magic cookie.
+pub fn is_stun(datagram: &[u8]) -> bool {
+    datagram.len() >= STUN_HEADER_SIZE
+        && datagram[0] & 0xC0 == 0
+        && datagram[4..8] == STUN_MAGIC_COOKIE.to_be_bytes()
+}
+
+/// The two STUN messages of address discovery (RFC 5389 Binding), which also serve as the
+/// probes of hole punching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StunMessage {
+    BindingRequest {
+        transaction: TransactionId,
+    },
+    /// `mapped` is where the request came from, as the responder saw it
+    BindingSuccess {
+        transaction: TransactionId,
+        mapped: SocketAddr,
+    },
+}
+
+impl StunMessage {
+    /// Responses carry the address as XOR-MAPPED-ADDRESS, which NATs rewriting addresses
+    /// they find in payloads leave alone.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (message_type, transaction, attributes) = match self {
+            StunMessage::BindingRequest { transaction } => (BINDING_REQUEST, transaction, Vec::new()),
+            StunMessage::BindingSuccess { transaction, mapped } => {
+                let value = xor_address(mapped, transaction);
+                let mut attribute = Vec::with_capacity(4 + value.len());
+                attribute.extend_from_slice(&ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+                attribute.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                attribute.extend_from_slice(&value);
+                (BINDING_SUCCESS, transaction, attribute)
+            }
+        };
+        let mut out = Vec::with_capacity(STUN_HEADER_SIZE + attributes.len());
+        out.extend_from_slice(&message_type.to_be_bytes());
+        out.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        out.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        out.extend_from_slice(transaction);
+        out.extend_from_slice(&attributes);
+        out
+    }
+
+    /// Reads a Binding request or success response, skipping the attributes it doesn't use
+    /// (SOFTWARE, FINGERPRINT...).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NatError> {
+        if !is_stun(bytes) {
+            return Err(NatError::NotStun);
+        }
+        let mut reader = Reader::new(bytes);
+        let message_type = reader.u16()?;
+        let length = reader.u16()? as usize;
+        reader.u32()?;
+        let transaction: TransactionId = reader.take_array()?;
+        let mut attributes = Reader::new(reader.take(length)?);
+        reader.finish()?;
+
+        match message_type {
+            BINDING_REQUEST => Ok(StunMessage::BindingRequest { transaction }),
+            BINDING_SUCCESS => {
+                let mut mapped = None;
+                while attributes.remaining() > 0 {
+                    let kind = attributes.u16()?;
+                    let len = attributes.u16()? as usize;
+                    let value = attributes.take(len)?;
+                    // Values are padded to 4 bytes
+                    attributes.take(len.next_multiple_of(4) - len)?;
+                    match kind {
+                        ATTR_XOR_MAPPED_ADDRESS => mapped = Some(read_address(value, Some(&transaction))?),
+                        ATTR_MAPPED_ADDRESS if mapped.is_none() => mapped = Some(read_address(value, None)?),
+                        _ => {}
+                    }
+                }
+                let mapped = mapped.ok_or(CodecError::InvalidField("mapped address"))?;
+                Ok(StunMessage::BindingSuccess { transaction, mapped })
+            }
+            _ => Err(CodecError::InvalidField("STUN message type").into()),
+        }
+    }
+}
+
+/// Format: [0] [Family (1)] [Port (2)] [IP (4 or 16)], XORed with the cookie and transaction
+fn xor_address(addr: &SocketAddr, transaction: &TransactionId) -> Vec<u8> {
+    let mut mask = STUN_MAGIC_COOKIE.to_be_bytes().to_vec();
+    mask.extend_from_slice(transaction);
+    let mut out = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(FAMILY_IPV4);
+            out.extend_from_slice(&(addr.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+            out.extend(ip.octets().iter().zip(&mask).map(|(b, m)| b ^ m));
+        }
+        IpAddr::V6(ip) => {
+            out.push(FAMILY_IPV6);
+            out.extend_from_slice(&(addr.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+            out.extend(ip.octets().iter().zip(&mask).map(|(b, m)| b ^ m));
+        }
+    }
+    out
+}
+
+/// Reads a MAPPED-ADDRESS, or an XOR-MAPPED-ADDRESS if given the transaction.
+fn read_address(value: &[u8], transaction: Option<&TransactionId>) -> Result<SocketAddr, CodecError> {
+    let mut mask = [0u8; 16];
+    if let Some(transaction) = transaction {
+        mask[..4].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction);
+    }
+    let mut reader = Reader::new(value);
+    reader.u8()?;
+    let family = reader.u8()?;
+    let port = reader.u16()? ^ u16::from_be_bytes([mask[0], mask[1]]);
+    let ip = match family {
+        FAMILY_IPV4 => {
+            let octets: [u8; 4] = reader.take_array()?;
+            IpAddr::V4(Ipv4Addr::from(std::array::from_fn::<u8, 4, _>(|i| octets[i] ^ mask[i])))
+        }
+        FAMILY_IPV6 => {
+            let octets: [u8; 16] = reader.take_array()?;
+            IpAddr::V6(Ipv6Addr::from(std::array::from_fn::<u8, 16, _>(|i| octets[i] ^ mask[i])))
+        }
+        _ => {
+            return Err(CodecError::InvalidField("address family"));
+        }
+    };
+    reader.finish()?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Sets up a hole punch between two nodes through a relay both are connected to: the
+/// initiator sends `Request`, the relay passes it on as `Offer`, the peer answers with
+/// `Answer`, which reaches the initiator as `Go`. Both then probe each other's address.
+/// Format: [Kind (1)] [NodeID (32)] [IP_Len (1) | IP | Port (2)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PunchMessage {
+    /// To the relay: punch a hole to `peer`; we are at `addr`
+    Request {
+        peer: NodeId,
+        addr: SocketAddr,
+    },
+    /// From the relay: `peer`, at `addr`, wants a hole punched to us
+    Offer {
+        peer: NodeId,
+        addr: SocketAddr,
+    },
+    /// To the relay: we take `peer`'s offer, and are at `addr`
+    Answer {
+        peer: NodeId,
+        addr: SocketAddr,
+    },
+    /// From the relay: `peer` answered from `addr`: start probing
+    Go {
+        peer: NodeId,
+        addr: SocketAddr,
+    },
+}
+
+impl PunchMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (kind, peer, addr) = match self {
+            PunchMessage::Request { peer, addr } => (PUNCH_REQUEST, peer, addr),
+            PunchMessage::Offer { peer, addr } => (PUNCH_OFFER, peer, addr),
+            PunchMessage::Answer { peer, addr } => (PUNCH_ANSWER, peer, addr),
+            PunchMessage::Go { peer, addr } => (PUNCH_GO, peer, addr),
+        };
+        let mut out = vec![kind];
+        out.extend_from_slice(peer.as_bytes());
+        codec::write_socket_addr(&mut out, addr);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let kind = reader.u8()?;
+        let peer = NodeId::from_bytes(reader.take_array()?);
+        let addr = codec::read_socket_addr(&mut reader)?;
+        reader.finish()?;
+        Ok(match kind {
+            PUNCH_REQUEST => PunchMessage::Request { peer, addr },
+            PUNCH_OFFER => PunchMessage::Offer { peer, addr },
+            PUNCH_ANSWER => PunchMessage::Answer { peer, addr },
+            PUNCH_GO => PunchMessage::Go { peer, addr },
+            _ => {
+                return Err(CodecError::InvalidField("punch message kind"));
+            }
+        })
+    }
+
+    pub fn from_packet(packet: &NetworkPacket) -> Result<Self, NatError> {
+        match packet.header.message_type {
+            MessageType::HolePunch => Ok(Self::from_bytes(&packet.payload)?),
+            other => Err(NatError::NotHolePunch(other)),
+        }
+    }
+
+    pub fn to_packet(&self, request_id: u32) -> NetworkPacket {
+        NetworkPacket::new(MessageType::HolePunch, request_id, self.to_bytes())
+    }
+}
+
+/// What a relay does with a punch message from `from`: the message to pass on, and to whom.
+/// None for the messages relays send rather than take.
+pub fn relay_punch(from: NodeId, message: PunchMessage) -> Option<(NodeId, PunchMessage)> {
+    match message {
+        PunchMessage::Request { peer, addr } => Some((peer, PunchMessage::Offer { peer: from, addr })),
+        PunchMessage::Answer { peer, addr } => Some((peer, PunchMessage::Go { peer: from, addr })),
+        PunchMessage::Offer { .. } | PunchMessage::Go { .. } => None,
+    }
+}
+
+/// How the NAT in front of us maps our socket, as far as STUN servers can tell.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NatBehavior {
+    #[default]
+    Unknown,
+    /// Seen at our own address: there is no NAT
+    Open,
+    /// Seen at one address by every server: holes can be punched
+    EndpointIndependent,
+    /// Seen at another address by each server (symmetric NAT): only relays get through
+    EndpointDependent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatConfig {
+    /// Servers asked for our address; two or more also tell a symmetric NAT apart
+    pub stun_servers: Vec<SocketAddr>,
+    /// STUN requests unanswered for this long are sent again...
+    pub retransmit_ms: u64,
+    /// ...this many times at most
+    pub max_retransmits: u32,
+    /// How often to ask again, NAT mappings being changeable
+    pub refresh_ms: u64,
+    /// Probes to the peer of a hole punch, one per interval
+    pub punch_interval_ms: u64,
+    pub punch_probes: u32,
+    /// How long the relay may take to pass a punch request on and bring the answer back
+    pub punch_setup_ms: u64,
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            stun_servers: Vec::new(),
+            retransmit_ms: 500,
+            max_retransmits: 3,
+            refresh_ms: 10 * 60 * 1000,
+            punch_interval_ms: 100,
+            punch_probes: 30,
+            punch_setup_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatEvent {
+    /// STUN servers see us at a new address, or the NAT turned out to behave differently
+    AddressChanged {
+        addr: SocketAddr,
+        behavior: NatBehavior,
+    },
+    /// `peer` answered a probe from `addr`: the hole is open. The initiator dials it now
+    Punched {
+        peer: NodeId,
+        addr: SocketAddr,
+        initiator: bool,
+    },
+    /// No probe was answered: `peer` can only be reached through a relay
+    PunchFailed {
+        peer: NodeId,
+    },
+}
+
+struct Query {
+    server: SocketAddr,
+    resend_ms: u64,
+    retransmits: u32,
+}
+
+struct Punch {
+    initiator: bool,
+    /// The peer's address, once the relay told us
+    addr: Option<SocketAddr>,
+    probes_sent: u32,
+    /// When to probe next, or give up waiting for the relay
+    next_ms: u64,
+}
+
+/// NAT traversal, on the UDP socket QUIC uses: learns our reflexive address (the one the
+/// outside sees) from STUN servers, to advertise as an `Observed` address, and punches holes
+/// to NATed peers by simultaneous open, coordinated through a relay.
+///
+/// Once both sides probe each other's reflexive address with STUN Binding requests, each
+/// NAT has a mapping to the other, and a probe answered means the hole is open both ways.
+/// The initiator then dials the peer over QUIC from the same socket; the peer only answers.
+/// Nodes answer every Binding request they get, so peers can serve as STUN servers as well.
+///
+/// Sans-IO: the host hands it the datagrams `is_stun` picks out (`on_datagram`), sends what
+/// `take_outgoing` returns from the QUIC socket, and carries `PunchMessage`s to and from
+/// the relay.
+pub struct NatTraversal {
+    config: NatConfig,
+    /// Where our socket is bound
+    local: SocketAddr,
+    queries: HashMap<TransactionId, Query>,
+    /// Our address as each server answered this round
+    answers: Vec<(SocketAddr, SocketAddr)>,
+    reflexive: Option<SocketAddr>,
+    behavior: NatBehavior,
+    refresh_at_ms: Option<u64>,
+    punches: HashMap<NodeId, Punch>,
+    probes: HashMap<TransactionId, NodeId>,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+    events: Vec<NatEvent>,
+}
+
+impl NatTraversal {
+    pub fn new(config: NatConfig, local: SocketAddr) -> Self {
+        Self {
+            config,
+            local,
+            queries: HashMap::new(),
+            answers: Vec::new(),
+            reflexive: None,
+            behavior: NatBehavior::Unknown,
+            refresh_at_ms: None,
+            punches: HashMap::new(),
+            probes: HashMap::new(),
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Asks every STUN server for our address, then again every `refresh_ms`.
+    pub fn discover(&mut self, now_ms: u64) {
+        self.answers.clear();
+        self.refresh_at_ms = None;
+        for server in self.config.stun_servers.clone() {
+            let transaction = new_transaction();
+            self.outgoing.push((server, StunMessage::BindingRequest { transaction }.to_bytes()));
+            self.queries.insert(transaction, Query { server, resend_ms: now_ms + self.config.retransmit_ms, retransmits: 0 });
+        }
+        if self.queries.is_empty() {
+            self.conclude(now_ms);
+        }
+    }
+
+    /// Handles a STUN datagram. Returns false if it wasn't one.
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8], now_ms: u64) -> bool {
+        let message = match StunMessage::from_bytes(data) {
+            Ok(message) => message,
+            Err(NatError::NotStun) => {
+                return false;
+            }
+            Err(e) => {
+                log::debug!("Dropping STUN message from {from}: {e}");
+                return true;
+            }
+        };
+        match message {
+            StunMessage::BindingRequest { transaction } => {
+                self.outgoing.push((from, StunMessage::BindingSuccess { transaction, mapped: from }.to_bytes()));
+            }
+            StunMessage::BindingSuccess { transaction, mapped } => {
+                if let Some(query) = self.queries.remove(&transaction) {
+                    self.answers.push((query.server, mapped));
+                    if self.queries.is_empty() {
+                        self.conclude(now_ms);
+                    }
+                } else if let Some(peer) = self.probes.remove(&transaction)
+                    && let Some(punch) = self.punches.remove(&peer)
+                {
+                    self.probes.retain(|_, p| *p != peer);
+                    self.events.push(NatEvent::Punched { peer, addr: from, initiator: punch.initiator });
+                }
+            }
+        }
+        true
+    }
+
+    /// Starts a hole punch to `peer`. Returns the request to send to a relay connected to both.
+    pub fn punch(&mut self, peer: NodeId, now_ms: u64) -> Result<PunchMessage, NatError> {
+        let addr = self.punchable_address()?;
+        self.punches.insert(peer, Punch { initiator: true, addr: None, probes_sent: 0, next_ms: now_ms + self.config.punch_setup_ms });
+        Ok(PunchMessage::Request { peer, addr })
+    }
+
+    /// Handles a punch message a relay passed on. Returns the reply for the relay, if any.
+    pub fn on_punch(&mut self, message: PunchMessage, now_ms: u64) -> Result<Option<PunchMessage>, NatError> {
+        match message {
+            PunchMessage::Offer { peer, addr: theirs } => {
+                let addr = self.punchable_address()?;
+                self.punches.insert(peer, Punch { initiator: false, addr: Some(theirs), probes_sent: 0, next_ms: now_ms });
+                self.probe(now_ms);
+                Ok(Some(PunchMessage::Answer { peer, addr }))
+            }
+            PunchMessage::Go { peer, addr } => {
+                if let Some(punch) = self.punches.get_mut(&peer).filter(|p| p.initiator && p.addr.is_none()) {
+                    punch.addr = Some(addr);
+                    punch.next_ms = now_ms;
+                    self.probe(now_ms);
+                }
+                Ok(None)
+            }
+            PunchMessage::Request { .. } | PunchMessage::Answer { .. } => Ok(None),
+        }
+    }
+
+    /// Our address as the outside sees it
+    pub fn reflexive_address(&self) -> Option<SocketAddr> {
+        self.reflexive
+    }
+
+    pub fn behavior(&self) -> NatBehavior {
+        self.behavior
+    }
+
+    /// What to advertise in our contact info
+    pub fn transport_address(&self) -> Option<TransportAddress> {
+        let kind = match self.behavior {
+            NatBehavior::Open => AddressKind::Public,
+            _ => AddressKind::Observed,
+        };
+        Some(TransportAddress { addr: self.reflexive?, kind })
+    }
+
+    /// Our contact info: `addresses` (those bound, relays) with our reflexive address first
+    pub fn contact_info(&self, id: NodeId, mut addresses: Vec<TransportAddress>) -> ContactInfo {
+        if let Some(ours) = self.transport_address() {
+            addresses.retain(|a| a.addr != ours.addr);
+            addresses.insert(0, ours);
+        }
+        ContactInfo::new(id, self.reachability(), addresses)
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        match self.behavior {
+            NatBehavior::Unknown => Reachability::Unknown,
+            NatBehavior::Open => Reachability::Direct,
+            NatBehavior::EndpointIndependent | NatBehavior::EndpointDependent => Reachability::BehindNat,
+        }
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&self) -> Option<u64> {
+        let queries = self.queries.values().map(|q| q.resend_ms);
+        let punches = self.punches.values().map(|p| p.next_ms);
+        queries.chain(punches).chain(self.refresh_at_ms).min()
+    }
+
+    /// Resends unanswered STUN requests, probes, gives up on punches, and refreshes.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let late: Vec<TransactionId> = self.queries
+            .iter()
+            .filter(|(_, q)| q.resend_ms <= now_ms)
+            .map(|(transaction, _)| *transaction)
+            .collect();
+        for transaction in late {
+            let query = self.queries.get_mut(&transaction).expect("collected above");
+            if query.retransmits < self.config.max_retransmits {
+                query.retransmits += 1;
+                query.resend_ms = now_ms + self.config.retransmit_ms;
+                self.outgoing.push((query.server, StunMessage::BindingRequest { transaction }.to_bytes()));
+            } else {
+                log::debug!("STUN server {} did not answer", query.server);
+                self.queries.remove(&transaction);
+                if self.queries.is_empty() {
+                    self.conclude(now_ms);
+                }
+            }
+        }
+        self.probe(now_ms);
+        if self.refresh_at_ms.is_some_and(|at| at <= now_ms) {
+            self.discover(now_ms);
+        }
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_events(&mut self) -> Vec<NatEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn punchable_address(&self) -> Result<SocketAddr, NatError> {
+        let addr = self.reflexive.ok_or(NatError::NoReflexiveAddress)?;
+        if self.behavior == NatBehavior::EndpointDependent {
+            return Err(NatError::SymmetricNat);
+        }
+        Ok(addr)
+    }
+
+    /// Sends the probes that are due, and fails the punches out of probes or never answered
+    /// by the relay.
+    fn probe(&mut self, now_ms: u64) {
+        let mut failed = Vec::new();
+        for (peer, punch) in &mut self.punches {
+            if punch.next_ms > now_ms {
+                continue;
+            }
+            match punch.addr {
+                Some(addr) if punch.probes_sent < self.config.punch_probes => {
+                    let transaction = new_transaction();
+                    self.outgoing.push((addr, StunMessage::BindingRequest { transaction }.to_bytes()));
+                    self.probes.insert(transaction, *peer);
+                    punch.probes_sent += 1;
+                    punch.next_ms = now_ms + self.config.punch_interval_ms;
+                }
+                _ => failed.push(*peer),
+            }
+        }
+        for peer in failed {
+            log::debug!("Hole punch to {peer:?} failed");
+            self.punches.remove(&peer);
+            self.probes.retain(|_, p| *p != peer);
+            self.events.push(NatEvent::PunchFailed { peer });
+        }
+    }
+
+    /// Draws our address and the NAT's behavior from this round's answers.
+    fn conclude(&mut self, now_ms: u64) {
+        self.refresh_at_ms = Some(now_ms + self.config.refresh_ms);
+        let Some(&(_, first)) = self.answers.first() else {
+            return;
+        };
+        let behavior = if self.answers.iter().any(|(_, mapped)| *mapped != first) {
+            NatBehavior::EndpointDependent
+        } else if first == self.local {
+            NatBehavior::Open
+        } else {
+            NatBehavior::EndpointIndependent
+        };
+        if self.reflexive != Some(first) || self.behavior != behavior {
+            log::info!("Reachable at {first} ({behavior:?})");
+            self.reflexive = Some(first);
+            self.behavior = behavior;
+            self.events.push(NatEvent::AddressChanged { addr: first, behavior });
+        }
+    }
+}
+
+fn new_transaction() -> TransactionId {
+    let mut transaction = [0u8; 12];
+    OsRng.fill_bytes(&mut transaction);
+    transaction
+}
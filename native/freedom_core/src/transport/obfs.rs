@@ -0,0 +1,661 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+
+use chacha20::ChaCha20;
+use chacha20::cipher::{ KeyIvInit, StreamCipher };
+use chacha20poly1305::{ aead::{ Aead, KeyInit }, ChaCha20Poly1305, Nonce };
+use hkdf::Hkdf;
+use hmac::{ Hmac, Mac };
+use rand::rngs::OsRng;
+use rand::{ Rng, RngCore };
+use sha2::Sha256;
+use x25519_dalek::{ PublicKey, StaticSecret };
+
+use super::tcp::{ TcpCommand, TcpConfig, TcpError, TcpInput, TcpTransport };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::identity::NodeIdentity;
+use crate::protocol::packet::NetworkPacket;
+
+const SEED_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const MARK_SIZE: usize = 16;
+const MAC_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const MAX_HELLO_PADDING: usize = 1024;
+/// Longest hello: anything past this without a valid mark is not a client
+const MAX_HELLO: usize = SEED_SIZE + KEY_SIZE + MAX_HELLO_PADDING + MARK_SIZE + MAC_SIZE;
+
+/// Bytes carried in one frame, so frames fit in a typical TCP segment
+pub const MAX_FRAME_PAYLOAD: usize = 1428;
+/// Sealed part of the largest frame: [PayloadLen (2)] [Payload] [Padding] + Tag
+const MAX_SEALED: usize = 2 + MAX_FRAME_PAYLOAD + TAG_SIZE;
+/// Most a frame is held back in `iat_mode`
+const MAX_IAT_DELAY_MS: u64 = 10;
+/// Most a bridge reads from a client that failed the handshake before closing
+const MAX_PROBE_DISCARD: usize = 8 * 1024;
+const HOUR_MS: u64 = 60 * 60 * 1000;
+/// Hellos are accepted one hour either side, so their MACs are remembered for three
+const REPLAY_TTL_MS: u64 = 3 * HOUR_MS;
+
+const LABEL_HELLO_KEY: &[u8] = b"FreedomNode-Obfs-v1 hello key";
+const LABEL_HELLO_MAC: &[u8] = b"FreedomNode-Obfs-v1 hello mac";
+const LABEL_SHAPING: &[u8] = b"FreedomNode-Obfs-v1 shaping";
+const LABEL_CLIENT_LENGTH: &[u8] = b"FreedomNode-Obfs-v1 client length";
+const LABEL_CLIENT_FRAME: &[u8] = b"FreedomNode-Obfs-v1 client frame";
+const LABEL_SERVER_LENGTH: &[u8] = b"FreedomNode-Obfs-v1 server length";
+const LABEL_SERVER_FRAME: &[u8] = b"FreedomNode-Obfs-v1 server frame";
+
+/// The secret a bridge hands out with its address, out of band, to the clients it serves.
+/// Without it nothing on the wire can be told apart from random bytes, and the bridge
+/// doesn't answer.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BridgeCert([u8; 32]);
+
+impl BridgeCert {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for BridgeCert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("BridgeCert(..)")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ObfsConfig {
+    pub tcp: TcpConfig,
+    /// Spaces frames out by random delays (and so writes no more than a frame at a time), to
+    /// hide timing as well as sizes, at the cost of throughput
+    pub iat_mode: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ObfsError {
+    #[error("No bridge certificate for {0}")]
+    NoCert(SocketAddr),
+    #[error("Obfuscated handshake failed")]
+    BadHandshake,
+    #[error("Invalid obfuscated frame")]
+    BadFrame,
+    #[error("TCP: {0}")]
+    Tcp(#[from] TcpError),
+}
+
+pub type ObfsEvent = TransportEvent<ObfsError>;
+
+/// Frame padding and delays, drawn from a table derived from the bridge's certificate: the
+/// connections of a bridge look alike, those of two bridges don't.
+#[derive(Clone, Copy)]
+struct Shaping {
+    lengths: [u16; 8],
+    delays_ms: [u8; 8],
+}
+
+impl Shaping {
+    fn new(cert: &BridgeCert) -> Self {
+        let mut table = [0u8; 24];
+        Hkdf::<Sha256>::new(None, cert.as_bytes())
+            .expand(LABEL_SHAPING, &mut table)
+            .expect("24 bytes is a valid length for SHA-256 HKDF");
+        Self {
+            lengths: std::array::from_fn(|i| u16::from_be_bytes([table[2 * i], table[2 * i + 1]]) % (MAX_FRAME_PAYLOAD as u16 + 1)),
+            delays_ms: std::array::from_fn(|i| table[16 + i] % (MAX_IAT_DELAY_MS as u8 + 1)),
+        }
+    }
+
+    /// Size to pad a frame's payload to
+    fn length(&self) -> usize {
+        self.lengths[OsRng.gen_range(0..self.lengths.len())] as usize
+    }
+
+    fn delay_ms(&self) -> u64 {
+        self.delays_ms[OsRng.gen_range(0..self.delays_ms.len())] as u64
+    }
+}
+
+/// One direction of an open connection: AEAD-sealed frames whose length is masked by a
+/// keystream, so not even frame boundaries show.
+struct FrameCipher {
+    mask: ChaCha20,
+    aead: ChaCha20Poly1305,
+    counter: u64,
+    /// Length of the frame being received, once unmasked
+    pending: Option<usize>,
+}
+
+impl FrameCipher {
+    fn new(hk: &Hkdf<Sha256>, length_label: &[u8], frame_label: &[u8]) -> Self {
+        let (mut mask_key, mut frame_key) = ([0u8; 32], [0u8; 32]);
+        hk.expand(length_label, &mut mask_key).expect("32 bytes is a valid length for SHA-256 HKDF");
+        hk.expand(frame_label, &mut frame_key).expect("32 bytes is a valid length for SHA-256 HKDF");
+        Self {
+            mask: ChaCha20::new(&mask_key.into(), &[0u8; 12].into()),
+            aead: ChaCha20Poly1305::new(&frame_key.into()),
+            counter: 0,
+            pending: None,
+        }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Nonce::from(nonce)
+    }
+
+    /// Format: [Len (2), masked] [Sealed: [PayloadLen (2)] [Payload] [Zeros (padding)] + Tag]
+    fn seal(&mut self, payload: &[u8], padding: usize) -> Vec<u8> {
+        let mut body = Vec::with_capacity(2 + payload.len() + padding);
+        body.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        body.extend_from_slice(payload);
+        body.resize(2 + payload.len() + padding, 0);
+        let nonce = self.nonce();
+        let sealed = self.aead.encrypt(&nonce, body.as_slice()).expect("ChaCha20-Poly1305 seals any frame size");
+
+        let mut len = (sealed.len() as u16).to_be_bytes();
+        self.mask.apply_keystream(&mut len);
+        [&len[..], &sealed].concat()
+    }
+
+    /// Takes the next whole frame off `received`. Returns its payload.
+    fn open(&mut self, received: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ObfsError> {
+        let len = match self.pending {
+            Some(len) => len,
+            None => {
+                let Some(header) = received.get(..2) else {
+                    return Ok(None);
+                };
+                let mut len = [header[0], header[1]];
+                self.mask.apply_keystream(&mut len);
+                let len = u16::from_be_bytes(len) as usize;
+                if !(2 + TAG_SIZE..=MAX_SEALED).contains(&len) {
+                    return Err(ObfsError::BadFrame);
+                }
+                received.drain(..2);
+                *self.pending.insert(len)
+            }
+        };
+        if received.len() < len {
+            return Ok(None);
+        }
+        self.pending = None;
+        let nonce = self.nonce();
+        let body = self.aead.decrypt(&nonce, &received[..len]).map_err(|_| ObfsError::BadFrame)?;
+        received.drain(..len);
+        let payload_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        body.get(2..2 + payload_len).map(|p| Some(p.to_vec())).ok_or(ObfsError::BadFrame)
+    }
+}
+
+/// Hello keys for a seed: one to encrypt the ephemeral key, one for the mark and MAC.
+fn hello_keys(cert: &BridgeCert, seed: &[u8]) -> ([u8; 32], Hmac<Sha256>) {
+    let hk = Hkdf::<Sha256>::new(Some(seed), cert.as_bytes());
+    let (mut key, mut mac_key) = ([0u8; 32], [0u8; 32]);
+    hk.expand(LABEL_HELLO_KEY, &mut key).expect("32 bytes is a valid length for SHA-256 HKDF");
+    hk.expand(LABEL_HELLO_MAC, &mut mac_key).expect("32 bytes is a valid length for SHA-256 HKDF");
+    (key, <Hmac<Sha256> as Mac>::new_from_slice(&mac_key).expect("HMAC accepts any key length"))
+}
+
+fn truncated(mac: Hmac<Sha256>) -> [u8; 16] {
+    mac.finalize().into_bytes()[..16].try_into().expect("SHA-256 is longer than 16 bytes")
+}
+
+/// A hello: an ephemeral key made to look random, random padding, a mark to find its end
+/// by, and a MAC over all of it, the hour and `bound` (the client's MAC, in the reply).
+/// Format: [Seed (16)] [EncryptedKey (32)] [Padding (0-1024)] [Mark (16)] [Mac (16)]
+fn hello(cert: &BridgeCert, public: &PublicKey, bound: &[u8], hour: u64) -> (Vec<u8>, [u8; MAC_SIZE]) {
+    let mut bytes = vec![0u8; SEED_SIZE + KEY_SIZE + OsRng.gen_range(0..=MAX_HELLO_PADDING)];
+    OsRng.fill_bytes(&mut bytes);
+    let (key, mac) = hello_keys(cert, &bytes[..SEED_SIZE]);
+    bytes[SEED_SIZE..SEED_SIZE + KEY_SIZE].copy_from_slice(public.as_bytes());
+    ChaCha20::new(&key.into(), &[0u8; 12].into()).apply_keystream(&mut bytes[SEED_SIZE..SEED_SIZE + KEY_SIZE]);
+
+    let mark = truncated(mac.clone().chain_update(&bytes[..SEED_SIZE + KEY_SIZE]));
+    bytes.extend_from_slice(&mark);
+    let tag = truncated(mac.chain_update(&bytes).chain_update(bound).chain_update(hour.to_be_bytes()));
+    bytes.extend_from_slice(&tag);
+    (bytes, tag)
+}
+
+/// Reads a hello off the front of `received`: None until it is all there, an error if it
+/// can't be one. Returns the peer's ephemeral key, the hello's MAC and its length.
+fn read_hello(received: &[u8], cert: &BridgeCert, bound: &[u8], hour: u64) -> Result<Option<(PublicKey, [u8; MAC_SIZE], usize)>, ObfsError> {
+    let head = SEED_SIZE + KEY_SIZE;
+    if received.len() < head + MARK_SIZE {
+        return Ok(None);
+    }
+    let (key, mac) = hello_keys(cert, &received[..SEED_SIZE]);
+    let mark = truncated(mac.clone().chain_update(&received[..head]));
+    let searched = &received[head..received.len().min(MAX_HELLO - MAC_SIZE)];
+    let Some(at) = searched.windows(MARK_SIZE).position(|window| window == mark).map(|i| head + i + MARK_SIZE) else {
+        return if received.len() >= MAX_HELLO { Err(ObfsError::BadHandshake) } else { Ok(None) };
+    };
+    let Some(tag) = received.get(at..at + MAC_SIZE) else {
+        return Ok(None);
+    };
+    let hours = [hour.saturating_sub(1), hour, hour + 1];
+    let valid = hours.iter().any(|h| truncated(mac.clone().chain_update(&received[..at]).chain_update(bound).chain_update(h.to_be_bytes())) == tag);
+    if !valid {
+        return Err(ObfsError::BadHandshake);
+    }
+
+    let mut public = <[u8; KEY_SIZE]>::try_from(&received[SEED_SIZE..head]).expect("sliced to KEY_SIZE");
+    ChaCha20::new(&key.into(), &[0u8; 12].into()).apply_keystream(&mut public);
+    Ok(Some((PublicKey::from(public), tag.try_into().expect("sliced to MAC_SIZE"), at + MAC_SIZE)))
+}
+
+/// The two directions' ciphers, from the ephemeral keys and both hellos' MACs.
+fn frame_ciphers(secret: &StaticSecret, theirs: &PublicKey, cert: &BridgeCert, client_mac: &[u8], server_mac: &[u8], client: bool) -> Result<(FrameCipher, FrameCipher), ObfsError> {
+    let shared = secret.diffie_hellman(theirs);
+    if !shared.was_contributory() {
+        return Err(ObfsError::BadHandshake);
+    }
+    let salt = [cert.as_bytes(), client_mac, server_mac].concat();
+    let hk = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+    let to_server = FrameCipher::new(&hk, LABEL_CLIENT_LENGTH, LABEL_CLIENT_FRAME);
+    let to_client = FrameCipher::new(&hk, LABEL_SERVER_LENGTH, LABEL_SERVER_FRAME);
+    Ok(if client { (to_server, to_client) } else { (to_client, to_server) })
+}
+
+enum Phase {
+    /// Client: our hello is out, waiting for the bridge's
+    AwaitingReply {
+        secret: StaticSecret,
+        mac: [u8; MAC_SIZE],
+    },
+    /// Bridge: waiting for the client's hello
+    AwaitingHello,
+    /// Bridge: the hello didn't check out; this many more bytes are read before closing, so
+    /// probes learn nothing from when we hang up
+    Discarding(usize),
+    Open {
+        tx: Box<FrameCipher>,
+        rx: Box<FrameCipher>,
+    },
+}
+
+struct ObfsConnection {
+    cert: BridgeCert,
+    shaping: Shaping,
+    phase: Phase,
+    /// Our hello, written right after the host opens the socket
+    hello: Option<Vec<u8>>,
+    /// Raw bytes received that don't make a hello or frame yet
+    received: Vec<u8>,
+    /// What the TCP transport wrote before the connection was open
+    held: Vec<u8>,
+    /// Frames waiting out their delay in `iat_mode`, in order
+    delayed: VecDeque<(u64, Vec<u8>)>,
+}
+
+/// An obfs4-style pluggable transport for censored networks: the TCP transport, wrapped so
+/// nothing on the wire can be fingerprinted.
+///
+/// Bridges hand out a `BridgeCert` with their address. Both sides open with a hello whose
+/// ephemeral X25519 key is encrypted under the certificate, followed by random padding and
+/// a MAC over the hello and the hour, found by a mark; a bridge answers only hellos with a
+/// valid and unseen MAC and otherwise reads a random amount and hangs up, giving active
+/// probes nothing. The ephemeral keys then key frames sealed with ChaCha20-Poly1305 behind
+/// a masked length, padded to sizes (and, in `iat_mode`, delayed by times) from a table
+/// derived from the certificate. The TCP transport's own handshake runs inside, so peers
+/// are authenticated as usual.
+///
+/// Driven as the TCP transport is, with `TcpInput` and `TcpCommand`.
+pub struct ObfsTransport {
+    tcp: TcpTransport,
+    config: ObfsConfig,
+    /// Ours, for the clients that dial us
+    cert: BridgeCert,
+    bridges: HashMap<SocketAddr, BridgeCert>,
+    connections: HashMap<ConnectionId, ObfsConnection>,
+    /// MACs of the hellos accepted, until they expire
+    seen: HashMap<[u8; MAC_SIZE], u64>,
+    commands: Vec<TcpCommand>,
+    events: Vec<ObfsEvent>,
+}
+
+impl ObfsTransport {
+    pub fn new(identity: NodeIdentity, config: ObfsConfig, cert: BridgeCert) -> Self {
+        Self {
+            tcp: TcpTransport::new(identity, config.tcp.clone()),
+            config,
+            cert,
+            bridges: HashMap::new(),
+            connections: HashMap::new(),
+            seen: HashMap::new(),
+            commands: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn cert(&self) -> &BridgeCert {
+        &self.cert
+    }
+
+    /// Remembers the certificate of the bridge at `addr`, needed to dial it.
+    pub fn add_bridge(&mut self, addr: SocketAddr, cert: BridgeCert) {
+        self.bridges.insert(addr, cert);
+    }
+
+    /// Starts a connection to the bridge at `addr`. `Connected` follows once both hellos
+    /// and the TCP handshake inside are through.
+    pub fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, ObfsError> {
+        let cert = *self.bridges.get(&addr).ok_or(ObfsError::NoCert(addr))?;
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let (bytes, mac) = hello(&cert, &PublicKey::from(&secret), &[], now_ms / HOUR_MS);
+        let connection = self.tcp.dial(addr, now_ms);
+        let mut conn = ObfsConnection::new(cert, Phase::AwaitingReply { secret, mac });
+        conn.hello = Some(bytes);
+        self.connections.insert(connection, conn);
+        self.pump(now_ms);
+        Ok(connection)
+    }
+
+    /// Takes over a socket the host accepted from `addr`, or has it closed if we don't listen.
+    pub fn accept(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.tcp.accept(addr, now_ms);
+        if self.tcp.remote_address(connection).is_some() {
+            self.connections.insert(connection, ObfsConnection::new(self.cert, Phase::AwaitingHello));
+        }
+        self.pump(now_ms);
+        connection
+    }
+
+    /// Handles bytes read from a connection's socket.
+    pub fn on_data(&mut self, connection: ConnectionId, data: &[u8], now_ms: u64) {
+        if let Some(conn) = self.connections.get_mut(&connection) {
+            conn.received.extend_from_slice(data);
+            if let Err(error) = self.process(connection, now_ms) {
+                log::warn!("Closing obfuscated connection {connection:?}: {error}");
+                self.connections.remove(&connection);
+                self.tcp.close(connection);
+                self.events.push(ObfsEvent::Closed { connection, error });
+            }
+        }
+        self.pump(now_ms);
+    }
+
+    /// The host's socket closed or failed.
+    pub fn on_closed(&mut self, connection: ConnectionId, now_ms: u64) {
+        self.connections.remove(&connection);
+        self.tcp.on_closed(connection);
+        self.pump(now_ms);
+    }
+
+    /// Sends a packet to an authenticated peer.
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), ObfsError> {
+        self.tcp.send(connection, packet)?;
+        self.pump(now_ms);
+        Ok(())
+    }
+
+    /// Closes a connection, after the frames still delayed. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        let known = self.tcp.close(connection);
+        self.pump(now_ms);
+        known
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&self) -> Option<u64> {
+        let delayed = self.connections.values().filter_map(|c| c.delayed.front().map(|(at, _)| *at));
+        delayed.chain(self.tcp.next_timeout()).min()
+    }
+
+    /// Writes the delayed frames that are due and times out handshakes.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        for (connection, conn) in &mut self.connections {
+            while let Some((_, bytes)) = conn.delayed.pop_front_if(|(at, _)| *at <= now_ms) {
+                self.commands.push(TcpCommand::Write { connection: *connection, bytes });
+            }
+        }
+        self.tcp.on_timeout(now_ms);
+        self.pump(now_ms);
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.tcp.peer(connection)
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        self.tcp.remote_address(connection)
+    }
+
+    /// What a connection carried so far, counted by the TCP transport inside
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        self.tcp.stats(connection)
+    }
+
+    /// What to do with the sockets since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn take_events(&mut self) -> Vec<ObfsEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Consumes what `received` holds: the peer's hello, then frames for the TCP transport.
+    fn process(&mut self, connection: ConnectionId, now_ms: u64) -> Result<(), ObfsError> {
+        let conn = self.connections.get_mut(&connection).expect("checked by on_data");
+        let hour = now_ms / HOUR_MS;
+        match &conn.phase {
+            Phase::AwaitingHello => match read_hello(&conn.received, &conn.cert, &[], hour) {
+                Ok(Some((theirs, client_mac, used))) => {
+                    self.seen.retain(|_, expires_ms| *expires_ms > now_ms);
+                    if self.seen.insert(client_mac, now_ms + REPLAY_TTL_MS).is_some() {
+                        log::info!("Replayed obfuscated hello on {connection:?}");
+                        conn.phase = Phase::Discarding(OsRng.gen_range(0..=MAX_PROBE_DISCARD));
+                        return self.process(connection, now_ms);
+                    }
+                    let secret = StaticSecret::random_from_rng(OsRng);
+                    let (reply, server_mac) = hello(&conn.cert, &PublicKey::from(&secret), &client_mac, hour);
+                    let (tx, rx) = frame_ciphers(&secret, &theirs, &conn.cert, &client_mac, &server_mac, false)?;
+                    conn.received.drain(..used);
+                    self.commands.push(TcpCommand::Write { connection, bytes: reply });
+                    self.open(connection, tx, rx, now_ms)?;
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    log::info!("Obfuscated hello on {connection:?} failed: {error}");
+                    conn.phase = Phase::Discarding(OsRng.gen_range(0..=MAX_PROBE_DISCARD));
+                    return self.process(connection, now_ms);
+                }
+            },
+            Phase::AwaitingReply { secret, mac } => {
+                if let Some((theirs, server_mac, used)) = read_hello(&conn.received, &conn.cert, mac, hour)? {
+                    let (tx, rx) = frame_ciphers(secret, &theirs, &conn.cert, mac, &server_mac, true)?;
+                    conn.received.drain(..used);
+                    self.open(connection, tx, rx, now_ms)?;
+                }
+            }
+            Phase::Discarding(remaining) => {
+                if conn.received.len() >= *remaining {
+                    return Err(ObfsError::BadHandshake);
+                }
+                conn.phase = Phase::Discarding(remaining - conn.received.len());
+                conn.received.clear();
+            }
+            Phase::Open { .. } => self.deliver(connection, now_ms)?,
+        }
+        Ok(())
+    }
+
+    /// Opens a connection once the hellos are through: what the TCP transport wrote so far
+    /// goes out, and what came after the peer's hello is read.
+    fn open(&mut self, connection: ConnectionId, tx: FrameCipher, rx: FrameCipher, now_ms: u64) -> Result<(), ObfsError> {
+        let conn = self.connections.get_mut(&connection).expect("opened by process");
+        conn.phase = Phase::Open { tx: Box::new(tx), rx: Box::new(rx) };
+        let held = std::mem::take(&mut conn.held);
+        if !held.is_empty() {
+            self.write(connection, &held, now_ms);
+        }
+        self.deliver(connection, now_ms)
+    }
+
+    /// Hands the payloads of the whole frames received to the TCP transport.
+    fn deliver(&mut self, connection: ConnectionId, now_ms: u64) -> Result<(), ObfsError> {
+        let conn = self.connections.get_mut(&connection).expect("opened by process");
+        let Phase::Open { rx, .. } = &mut conn.phase else {
+            return Ok(());
+        };
+        let mut bytes = Vec::new();
+        while let Some(payload) = rx.open(&mut conn.received)? {
+            bytes.extend_from_slice(&payload);
+        }
+        if !bytes.is_empty() {
+            self.tcp.on_data(connection, &bytes, now_ms);
+        }
+        Ok(())
+    }
+
+    /// Frames bytes of the TCP transport for the wire, or holds them until the connection
+    /// is open.
+    fn write(&mut self, connection: ConnectionId, bytes: &[u8], now_ms: u64) {
+        let Some(conn) = self.connections.get_mut(&connection) else {
+            return;
+        };
+        let Phase::Open { tx, .. } = &mut conn.phase else {
+            conn.held.extend_from_slice(bytes);
+            return;
+        };
+        let mut wire = Vec::new();
+        for chunk in bytes.chunks(MAX_FRAME_PAYLOAD) {
+            let frame = tx.seal(chunk, conn.shaping.length().saturating_sub(chunk.len()));
+            if self.config.iat_mode {
+                let after = conn.delayed.back().map_or(now_ms, |(at, _)| now_ms.max(*at));
+                conn.delayed.push_back((after + conn.shaping.delay_ms(), frame));
+            } else {
+                wire.extend_from_slice(&frame);
+            }
+        }
+        if !wire.is_empty() {
+            self.commands.push(TcpCommand::Write { connection, bytes: wire });
+        }
+    }
+
+    /// Puts what the TCP transport would write through the obfuscation, and passes its
+    /// events on.
+    fn pump(&mut self, now_ms: u64) {
+        for command in self.tcp.take_outgoing() {
+            match command {
+                TcpCommand::Connect { connection, addr } => {
+                    self.commands.push(TcpCommand::Connect { connection, addr });
+                    if let Some(bytes) = self.connections.get_mut(&connection).and_then(|c| c.hello.take()) {
+                        self.commands.push(TcpCommand::Write { connection, bytes });
+                    }
+                }
+                TcpCommand::Write { connection, bytes } => self.write(connection, &bytes, now_ms),
+                TcpCommand::Close(connection) => {
+                    if let Some(conn) = self.connections.remove(&connection) {
+                        let bytes: Vec<u8> = conn.delayed.into_iter().flat_map(|(_, frame)| frame).collect();
+                        if !bytes.is_empty() {
+                            self.commands.push(TcpCommand::Write { connection, bytes });
+                        }
+                    }
+                    self.commands.push(TcpCommand::Close(connection));
+                }
+            }
+        }
+        for event in self.tcp.take_events() {
+            self.events.push(match event {
+                TransportEvent::Connected { connection, addr, peer } => ObfsEvent::Connected { connection, addr, peer },
+                TransportEvent::Packet { connection, packet } => ObfsEvent::Packet { connection, packet },
+                TransportEvent::Migrated { connection, addr } => ObfsEvent::Migrated { connection, addr },
+                TransportEvent::Closed { connection, error } => {
+                    self.connections.remove(&connection);
+                    ObfsEvent::Closed { connection, error: error.into() }
+                }
+            });
+        }
+    }
+}
+
+impl ObfsConnection {
+    fn new(cert: BridgeCert, phase: Phase) -> Self {
+        Self {
+            cert,
+            shaping: Shaping::new(&cert),
+            phase,
+            hello: None,
+            received: Vec::new(),
+            held: Vec::new(),
+            delayed: VecDeque::new(),
+        }
+    }
+}
+
+impl Transport for ObfsTransport {
+    type Input = TcpInput;
+    type Output = TcpCommand;
+    type Error = ObfsError;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, ObfsError> {
+        ObfsTransport::dial(self, addr, now_ms)
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        self.tcp.listen(enabled);
+    }
+
+    fn handle(&mut self, input: TcpInput, now_ms: u64) -> Option<ConnectionId> {
+        match input {
+            TcpInput::Accepted(addr) => {
+                return Some(self.accept(addr, now_ms));
+            }
+            TcpInput::Data { connection, bytes } => self.on_data(connection, &bytes, now_ms),
+            TcpInput::Closed(connection) => self.on_closed(connection, now_ms),
+        }
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), ObfsError> {
+        ObfsTransport::send(self, connection, packet, now_ms)
+    }
+
+    fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        ObfsTransport::close(self, connection, now_ms)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        ObfsTransport::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        ObfsTransport::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        ObfsTransport::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        ObfsTransport::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        ObfsTransport::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        ObfsTransport::take_outgoing(self)
+    }
+
+    fn take_events(&mut self) -> Vec<ObfsEvent> {
+        ObfsTransport::take_events(self)
+    }
+}
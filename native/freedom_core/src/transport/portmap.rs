@@ -0,0 +1,645 @@
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+use crate::protocol::codec::{ CodecError, Reader };
+
+/// Where NAT-PMP and PCP gateways listen
+pub const GATEWAY_PORT: u16 = 5351;
+/// Where UPnP gateways answer SSDP searches
+pub const SSDP_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+const PCP_VERSION: u8 = 2;
+const PCP_OPCODE_MAP: u8 = 1;
+const PCP_RESPONSE: u8 = 0x80;
+/// [Version (1)] [Opcode (1)] [Reserved (2)] [Lifetime (4)] [ClientIP (16)]
+const PCP_HEADER_SIZE: usize = 24;
+/// [Nonce (12)] [Protocol (1)] [Reserved (3)] [InternalPort (2)] [ExternalPort (2)] [ExternalIP (16)]
+const PCP_MAP_SIZE: usize = 36;
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_OPCODE_ADDRESS: u8 = 0;
+/// Result code of both protocols for a version the gateway doesn't speak
+const UNSUPPORTED_VERSION: u16 = 1;
+
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const UPNP_DESCRIPTION: &str = "FreedomNode";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MappingProtocol {
+    Udp,
+    Tcp,
+}
+
+impl MappingProtocol {
+    /// IANA protocol number, as PCP carries it
+    fn number(self) -> u8 {
+        match self {
+            MappingProtocol::Udp => 17,
+            MappingProtocol::Tcp => 6,
+        }
+    }
+
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            MappingProtocol::Udp => 1,
+            MappingProtocol::Tcp => 2,
+        }
+    }
+
+    fn upnp_name(self) -> &'static str {
+        match self {
+            MappingProtocol::Udp => "UDP",
+            MappingProtocol::Tcp => "TCP",
+        }
+    }
+}
+
+/// How the router was asked for the mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMapMethod {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapConfig {
+    /// The router, for PCP and NAT-PMP; UPnP finds it by itself
+    pub gateway: Option<IpAddr>,
+    /// The listening socket to forward to, at our LAN address
+    pub local: SocketAddr,
+    pub protocol: MappingProtocol,
+    /// Lease asked for; mappings are renewed halfway through
+    pub lifetime_secs: u32,
+    /// First wait for a PCP or NAT-PMP answer, doubled on each retransmit
+    pub initial_timeout_ms: u64,
+    pub max_retransmits: u32,
+    /// How long UPnP gateways have to answer a search, and each HTTP request
+    pub upnp_timeout_ms: u64,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        Self {
+            gateway: None,
+            local: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            protocol: MappingProtocol::Udp,
+            lifetime_secs: 3600,
+            initial_timeout_ms: 250,
+            max_retransmits: 3,
+            upnp_timeout_ms: 3000,
+        }
+    }
+}
+
+/// A port forwarded by the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub method: PortMapMethod,
+    /// Where peers reach us; the IP is unspecified if the router didn't tell
+    pub external: SocketAddr,
+    pub expires_at_ms: u64,
+    renew_at_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortMapCommand {
+    Datagram {
+        to: SocketAddr,
+        bytes: Vec<u8>,
+    },
+    /// Send `bytes` over a new TCP connection to `addr` and hand the whole response (read
+    /// until the router closes) to `on_http_response`, or report `on_http_failed`
+    Http {
+        request: u32,
+        addr: SocketAddr,
+        bytes: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMapEvent {
+    /// The port is forwarded, or the lease was renewed
+    Mapped(PortMapping),
+    /// No method got the router to forward the port
+    Unavailable,
+    /// The lease ran out without being renewed
+    Expired,
+    /// The mapping was removed at shutdown
+    Removed,
+}
+
+/// A UPnP WAN connection service and where to control it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UpnpService {
+    addr: SocketAddr,
+    control_path: String,
+    service_type: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Idle,
+    Pcp {
+        attempt: u32,
+        resend_ms: u64,
+    },
+    NatPmp {
+        attempt: u32,
+        resend_ms: u64,
+        external_ip: Option<Ipv4Addr>,
+    },
+    UpnpSearch {
+        until_ms: u64,
+    },
+    UpnpDescribe {
+        request: u32,
+        addr: SocketAddr,
+        until_ms: u64,
+    },
+    UpnpAdd {
+        request: u32,
+        until_ms: u64,
+    },
+    UpnpExternalIp {
+        request: u32,
+        until_ms: u64,
+    },
+}
+
+/// Forwards the listening port on the home router, so a node behind it can be dialled
+/// directly and serve as a relay. Optional: the host runs it only if the user allows it.
+///
+/// PCP is tried first, then NAT-PMP if the gateway only speaks that, then UPnP IGD (SSDP
+/// search, device description, SOAP `AddPortMapping`). The lease is renewed halfway
+/// through, with the method that got it, and `shutdown` removes the mapping.
+///
+/// Sans-IO: the host sends the `PortMapCommand`s (datagrams from the socket being mapped,
+/// HTTP requests over short TCP connections) and hands the replies back.
+pub struct PortMapper {
+    config: PortMapConfig,
+    step: Step,
+    /// Names our PCP mapping to the gateway, the same on every renewal
+    nonce: [u8; 12],
+    mapping: Option<PortMapping>,
+    upnp: Option<UpnpService>,
+    next_request: u32,
+    outgoing: Vec<PortMapCommand>,
+    events: Vec<PortMapEvent>,
+}
+
+impl PortMapper {
+    pub fn new(config: PortMapConfig) -> Self {
+        Self {
+            config,
+            step: Step::Idle,
+            nonce: new_nonce(),
+            mapping: None,
+            upnp: None,
+            next_request: 0,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Asks the router to forward the port.
+    pub fn start(&mut self, now_ms: u64) {
+        match self.config.gateway {
+            Some(_) => self.send_pcp(0, now_ms),
+            None => self.search_upnp(now_ms),
+        }
+    }
+
+    /// The port forwarded, while the lease lasts
+    pub fn mapping(&self) -> Option<&PortMapping> {
+        self.mapping.as_ref()
+    }
+
+    /// Handles a datagram from the gateway or a UPnP device. Returns false if it wasn't ours.
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8], now_ms: u64) -> bool {
+        if self.config.gateway == Some(from.ip()) && from.port() == GATEWAY_PORT {
+            if let Err(e) = self.on_gateway_reply(data, now_ms) {
+                log::debug!("Dropping malformed reply from gateway {from}: {e}");
+            }
+            return true;
+        }
+        let Step::UpnpSearch { .. } = self.step else {
+            return false;
+        };
+        let Some(location) = ssdp_location(data) else {
+            return false;
+        };
+        let Some((addr, path)) = parse_http_url(&location) else {
+            log::debug!("Unusable UPnP description location {location}");
+            return true;
+        };
+        let request = self.http(addr, http_get(addr, &path));
+        self.step = Step::UpnpDescribe { request, addr, until_ms: now_ms + self.config.upnp_timeout_ms };
+        true
+    }
+
+    /// Handles the response to an `Http` command.
+    pub fn on_http_response(&mut self, request: u32, response: &[u8], now_ms: u64) {
+        let (status, body) = parse_http_response(response).unwrap_or((0, ""));
+        match self.step.clone() {
+            Step::UpnpDescribe { request: expected, addr, .. } if expected == request => {
+                let Some((service_type, control)) = find_wan_service(body) else {
+                    log::debug!("UPnP gateway {addr} offers no WAN connection service");
+                    self.fail(now_ms);
+                    return;
+                };
+                let control_path = match parse_http_url(control) {
+                    Some((_, path)) => path,
+                    None if control.starts_with('/') => control.to_string(),
+                    None => format!("/{control}"),
+                };
+                self.upnp = Some(UpnpService { addr, control_path, service_type });
+                self.send_upnp_add(self.config.lifetime_secs, now_ms);
+            }
+            Step::UpnpAdd { request: expected, .. } if expected == request => {
+                if status != 200 {
+                    log::debug!("UPnP AddPortMapping refused with status {status}");
+                    self.fail(now_ms);
+                    return;
+                }
+                let Some(service) = &self.upnp else {
+                    return;
+                };
+                let bytes = soap_request(service, "GetExternalIPAddress", "");
+                let request = self.http(service.addr, bytes);
+                self.step = Step::UpnpExternalIp { request, until_ms: now_ms + self.config.upnp_timeout_ms };
+            }
+            Step::UpnpExternalIp { request: expected, .. } if expected == request => {
+                let ip = xml_value(body, "NewExternalIPAddress").and_then(|ip| ip.trim().parse().ok());
+                self.mapped(PortMapMethod::Upnp, ip, self.config.local.port(), self.config.lifetime_secs, now_ms);
+            }
+            _ => {}
+        }
+    }
+
+    /// An `Http` command couldn't be carried out.
+    pub fn on_http_failed(&mut self, request: u32, now_ms: u64) {
+        let pending = match &self.step {
+            Step::UpnpDescribe { request: r, .. } | Step::UpnpAdd { request: r, .. } => Some(*r),
+            Step::UpnpExternalIp { request: r, .. } => {
+                // Mapped all the same, only the address is unknown
+                if *r == request {
+                    self.mapped(PortMapMethod::Upnp, None, self.config.local.port(), self.config.lifetime_secs, now_ms);
+                }
+                None
+            }
+            _ => None,
+        };
+        if pending == Some(request) {
+            self.fail(now_ms);
+        }
+    }
+
+    /// Removes the mapping, for when the node shuts down.
+    pub fn shutdown(&mut self) {
+        let Some(mapping) = self.mapping.take() else {
+            self.step = Step::Idle;
+            return;
+        };
+        match mapping.method {
+            PortMapMethod::Pcp => {
+                self.outgoing.push(PortMapCommand::Datagram { to: self.gateway_addr(), bytes: self.pcp_request(0) });
+            }
+            PortMapMethod::NatPmp => {
+                self.outgoing.push(PortMapCommand::Datagram { to: self.gateway_addr(), bytes: self.natpmp_request(0) });
+            }
+            PortMapMethod::Upnp => {
+                if let Some(service) = &self.upnp {
+                    let arguments = format!(
+                        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol>",
+                        mapping.external.port(),
+                        self.config.protocol.upnp_name(),
+                    );
+                    let bytes = soap_request(service, "DeletePortMapping", &arguments);
+                    self.http(service.addr, bytes);
+                }
+            }
+        }
+        log::info!("Removing port mapping {}", mapping.external);
+        self.step = Step::Idle;
+        self.events.push(PortMapEvent::Removed);
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&self) -> Option<u64> {
+        let step = match &self.step {
+            Step::Idle => None,
+            Step::Pcp { resend_ms, .. } | Step::NatPmp { resend_ms, .. } => Some(*resend_ms),
+            Step::UpnpSearch { until_ms }
+            | Step::UpnpDescribe { until_ms, .. }
+            | Step::UpnpAdd { until_ms, .. }
+            | Step::UpnpExternalIp { until_ms, .. } => Some(*until_ms),
+        };
+        let lease = self.mapping.map(|m| if self.step == Step::Idle { m.renew_at_ms } else { m.expires_at_ms });
+        step.into_iter().chain(lease).min()
+    }
+
+    /// Retransmits, moves on to the next method when one gets no answer, renews leases.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        match self.step.clone() {
+            Step::Pcp { attempt, resend_ms } if resend_ms <= now_ms => {
+                if attempt < self.config.max_retransmits {
+                    self.send_pcp(attempt + 1, now_ms);
+                } else if self.mapping.is_none() {
+                    // Gateways speaking only NAT-PMP answer PCP: no answer means neither
+                    self.search_upnp(now_ms);
+                } else {
+                    self.step = Step::Idle;
+                }
+            }
+            Step::NatPmp { attempt, resend_ms, external_ip } if resend_ms <= now_ms => {
+                if attempt < self.config.max_retransmits {
+                    self.send_natpmp(attempt + 1, external_ip, now_ms);
+                } else {
+                    self.fail(now_ms);
+                }
+            }
+            Step::UpnpSearch { until_ms }
+            | Step::UpnpDescribe { until_ms, .. }
+            | Step::UpnpAdd { until_ms, .. }
+            | Step::UpnpExternalIp { until_ms, .. } if until_ms <= now_ms => self.fail(now_ms),
+            _ => {}
+        }
+
+        let Some(mapping) = self.mapping else {
+            return;
+        };
+        if mapping.expires_at_ms <= now_ms {
+            log::info!("Port mapping {} expired", mapping.external);
+            self.mapping = None;
+            self.step = Step::Idle;
+            self.events.push(PortMapEvent::Expired);
+            self.start(now_ms);
+        } else if mapping.renew_at_ms <= now_ms && self.step == Step::Idle {
+            match mapping.method {
+                PortMapMethod::Pcp => self.send_pcp(0, now_ms),
+                PortMapMethod::NatPmp => self.send_natpmp(0, None, now_ms),
+                PortMapMethod::Upnp => self.send_upnp_add(self.config.lifetime_secs, now_ms),
+            }
+        }
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<PortMapCommand> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_events(&mut self) -> Vec<PortMapEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn gateway_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.config.gateway.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), GATEWAY_PORT)
+    }
+
+    fn http(&mut self, addr: SocketAddr, bytes: Vec<u8>) -> u32 {
+        self.next_request += 1;
+        self.outgoing.push(PortMapCommand::Http { request: self.next_request, addr, bytes });
+        self.next_request
+    }
+
+    fn retransmit_ms(&self, attempt: u32, now_ms: u64) -> u64 {
+        now_ms + (self.config.initial_timeout_ms << attempt.min(16))
+    }
+
+    fn send_pcp(&mut self, attempt: u32, now_ms: u64) {
+        let bytes = self.pcp_request(self.config.lifetime_secs);
+        self.outgoing.push(PortMapCommand::Datagram { to: self.gateway_addr(), bytes });
+        self.step = Step::Pcp { attempt, resend_ms: self.retransmit_ms(attempt, now_ms) };
+    }
+
+    fn send_natpmp(&mut self, attempt: u32, external_ip: Option<Ipv4Addr>, now_ms: u64) {
+        if external_ip.is_none() {
+            self.outgoing.push(PortMapCommand::Datagram { to: self.gateway_addr(), bytes: vec![NATPMP_VERSION, NATPMP_OPCODE_ADDRESS] });
+        }
+        let bytes = self.natpmp_request(self.config.lifetime_secs);
+        self.outgoing.push(PortMapCommand::Datagram { to: self.gateway_addr(), bytes });
+        self.step = Step::NatPmp { attempt, resend_ms: self.retransmit_ms(attempt, now_ms), external_ip };
+    }
+
+    fn search_upnp(&mut self, now_ms: u64) {
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDR}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n"
+        );
+        self.outgoing.push(PortMapCommand::Datagram { to: SSDP_ADDR, bytes: search.into_bytes() });
+        self.step = Step::UpnpSearch { until_ms: now_ms + self.config.upnp_timeout_ms };
+    }
+
+    fn send_upnp_add(&mut self, lifetime_secs: u32, now_ms: u64) {
+        let Some(service) = &self.upnp else {
+            self.fail(now_ms);
+            return;
+        };
+        let port = self.config.local.port();
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort><NewProtocol>{}</NewProtocol>\
+             <NewInternalPort>{port}</NewInternalPort><NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>{UPNP_DESCRIPTION}</NewPortMappingDescription><NewLeaseDuration>{lifetime_secs}</NewLeaseDuration>",
+            self.config.protocol.upnp_name(),
+            self.config.local.ip(),
+        );
+        let bytes = soap_request(service, "AddPortMapping", &arguments);
+        let request = self.http(service.addr, bytes);
+        self.step = Step::UpnpAdd { request, until_ms: now_ms + self.config.upnp_timeout_ms };
+    }
+
+    /// Format: PCP header, then the MAP opcode data
+    fn pcp_request(&self, lifetime_secs: u32) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PCP_HEADER_SIZE + PCP_MAP_SIZE);
+        out.extend_from_slice(&[PCP_VERSION, PCP_OPCODE_MAP, 0, 0]);
+        out.extend_from_slice(&lifetime_secs.to_be_bytes());
+        out.extend_from_slice(&mapped_ipv6(self.config.local.ip()).octets());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(&[self.config.protocol.number(), 0, 0, 0]);
+        out.extend_from_slice(&self.config.local.port().to_be_bytes());
+        // Suggest the same port outside, any address
+        out.extend_from_slice(&self.config.local.port().to_be_bytes());
+        out.extend_from_slice(&mapped_ipv6(IpAddr::V4(Ipv4Addr::UNSPECIFIED)).octets());
+        out
+    }
+
+    /// Format: [Version (1)] [Opcode (1)] [Reserved (2)] [InternalPort (2)] [ExternalPort (2)] [Lifetime (4)]
+    fn natpmp_request(&self, lifetime_secs: u32) -> Vec<u8> {
+        let mut out = vec![NATPMP_VERSION, self.config.protocol.natpmp_opcode(), 0, 0];
+        out.extend_from_slice(&self.config.local.port().to_be_bytes());
+        out.extend_from_slice(&self.config.local.port().to_be_bytes());
+        out.extend_from_slice(&lifetime_secs.to_be_bytes());
+        out
+    }
+
+    fn on_gateway_reply(&mut self, data: &[u8], now_ms: u64) -> Result<(), CodecError> {
+        let mut reader = Reader::new(data);
+        let version = reader.u8()?;
+        let opcode = reader.u8()?;
+        match (version, self.step.clone()) {
+            (PCP_VERSION, Step::Pcp { .. }) if opcode == PCP_OPCODE_MAP | PCP_RESPONSE => {
+                reader.u8()?;
+                let result = reader.u8()?;
+                let lifetime = reader.u32()?;
+                reader.take(4 + 12)?;
+                if reader.take_array::<12>()? != self.nonce {
+                    return Ok(());
+                }
+                reader.take(4)?;
+                reader.u16()?;
+                let port = reader.u16()?;
+                let ip = Ipv6Addr::from(reader.take_array::<16>()?);
+                if result != 0 {
+                    log::debug!("PCP gateway refused the mapping with result {result}");
+                    self.fail(now_ms);
+                } else {
+                    let ip = ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip));
+                    self.mapped(PortMapMethod::Pcp, Some(ip), port, lifetime, now_ms);
+                }
+            }
+            // A NAT-PMP gateway answering PCP
+            (NATPMP_VERSION, Step::Pcp { .. }) => {
+                let result = reader.u16()?;
+                if result == UNSUPPORTED_VERSION {
+                    self.send_natpmp(0, None, now_ms);
+                }
+            }
+            (NATPMP_VERSION, Step::NatPmp { attempt, resend_ms, external_ip }) => {
+                let result = reader.u16()?;
+                reader.u32()?;
+                if result != 0 {
+                    log::debug!("NAT-PMP gateway refused opcode {opcode} with result {result}");
+                    self.fail(now_ms);
+                    return Ok(());
+                }
+                if opcode == NATPMP_OPCODE_ADDRESS | PCP_RESPONSE {
+                    let ip = Ipv4Addr::from(reader.take_array::<4>()?);
+                    self.step = Step::NatPmp { attempt, resend_ms, external_ip: Some(ip) };
+                } else if opcode == self.config.protocol.natpmp_opcode() | PCP_RESPONSE {
+                    reader.u16()?;
+                    let port = reader.u16()?;
+                    let lifetime = reader.u32()?;
+                    self.mapped(PortMapMethod::NatPmp, external_ip.map(IpAddr::V4), port, lifetime, now_ms);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn mapped(&mut self, method: PortMapMethod, ip: Option<IpAddr>, port: u16, lifetime_secs: u32, now_ms: u64) {
+        let lifetime_ms = u64::from(lifetime_secs) * 1000;
+        let mapping = PortMapping {
+            method,
+            external: SocketAddr::new(ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port),
+            expires_at_ms: now_ms + lifetime_ms,
+            renew_at_ms: now_ms + lifetime_ms / 2,
+        };
+        if self.mapping.is_none() {
+            log::info!("Port {} forwarded as {} by {method:?}", self.config.local.port(), mapping.external);
+        }
+        self.mapping = Some(mapping);
+        self.step = Step::Idle;
+        self.events.push(PortMapEvent::Mapped(mapping));
+    }
+
+    /// The current method failed: the next one is tried, unless renewing a lease.
+    fn fail(&mut self, now_ms: u64) {
+        let renewing = self.mapping.is_some();
+        if !renewing && matches!(self.step, Step::Pcp { .. } | Step::NatPmp { .. }) {
+            self.search_upnp(now_ms);
+            return;
+        }
+        self.step = Step::Idle;
+        if !renewing {
+            log::info!("The router can't forward ports");
+            self.events.push(PortMapEvent::Unavailable);
+        }
+    }
+}
+
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// PCP carries IPv4 addresses IPv4-mapped
+fn mapped_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// The LOCATION header of an SSDP response
+fn ssdp_location(data: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(data).ok()?;
+    if !text.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    text.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("location").then(|| value.trim().to_string())
+    })
+}
+
+/// Splits "http://host:port/path" into the address and path; hosts must be IP addresses.
+fn parse_http_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], rest[at..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let addr = authority.parse().ok().or_else(|| Some(SocketAddr::new(authority.parse().ok()?, 80)))?;
+    Some((addr, path))
+}
+
+/// HTTP/1.0, so the response is never chunked
+fn http_get(addr: SocketAddr, path: &str) -> Vec<u8> {
+    format!("GET {path} HTTP/1.0\r\nHost: {addr}\r\n\r\n").into_bytes()
+}
+
+fn soap_request(service: &UpnpService, action: &str, arguments: &str) -> Vec<u8> {
+    let body = format!(
+        "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}>\
+         </s:Body></s:Envelope>",
+        service.service_type,
+    );
+    format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{action}\"\r\nContent-Length: {}\r\n\r\n{body}",
+        service.control_path,
+        service.addr,
+        service.service_type,
+        body.len(),
+    )
+    .into_bytes()
+}
+
+/// The status code and body of an HTTP response
+fn parse_http_response(response: &[u8]) -> Option<(u16, &str)> {
+    let text = std::str::from_utf8(response).ok()?;
+    let status = text.split_whitespace().nth(1)?.parse().ok()?;
+    let body = text.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    Some((status, body))
+}
+
+/// The first WAN connection service of a device description, and its control URL
+fn find_wan_service(description: &str) -> Option<(&'static str, &str)> {
+    UPNP_SERVICES.iter().find_map(|service| {
+        let at = description.find(&format!("<serviceType>{service}</serviceType>"))?;
+        Some((*service, xml_value(&description[at..], "controlURL")?.trim()))
+    })
+}
+
+/// The text of the first `<tag>` element
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{tag}>"))? + start;
+    Some(&xml[start..end])
+}
@@ -0,0 +1,186 @@
+use std::collections::{ HashMap, VecDeque };
+
+use super::ConnectionId;
+use crate::protocol::header::HEADER_SIZE;
+use crate::protocol::packet::NetworkPacket;
+
+/// What may become of a queued packet when its connection backs up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendPriority {
+    /// Cover traffic (the cells `poll_padding` makes): dropped first to make room, dropped
+    /// outright on a blocked connection, and never the cause of backpressure
+    Padding,
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Bytes queued on a connection (padding aside) at which upper layers are told to hold off
+    pub high_water: usize,
+    /// ...and below which they are told to resume
+    pub low_water: usize,
+    /// Bytes a connection may have queued at all; pushes past it fail
+    pub max_bytes: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { high_water: 128 * 1024, low_water: 32 * 1024, max_bytes: 256 * 1024 }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum QueueError {
+    #[error("Send queue of {connection:?} is full ({queued} bytes)")]
+    Full {
+        connection: ConnectionId,
+        queued: usize,
+    },
+}
+
+/// Backpressure for the layers that push packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueEvent {
+    /// The connection's queue passed `high_water`: stop reading from whatever feeds it (the
+    /// circuits sending to this peer, say) until `Unblocked`
+    Blocked(ConnectionId),
+    /// Back under `low_water`
+    Unblocked(ConnectionId),
+}
+
+#[derive(Default)]
+struct SendQueue {
+    packets: VecDeque<(SendPriority, NetworkPacket)>,
+    /// All bytes queued, and those of padding
+    bytes: usize,
+    padding_bytes: usize,
+    blocked: bool,
+}
+
+impl SendQueue {
+    /// Bytes that count towards backpressure
+    fn normal_bytes(&self) -> usize {
+        self.bytes - self.padding_bytes
+    }
+
+    /// Drops the oldest padding until `needed` more bytes fit under `max_bytes`. Returns the
+    /// packets dropped.
+    fn make_room(&mut self, needed: usize, max_bytes: usize) -> u64 {
+        let mut dropped = 0;
+        while self.bytes + needed > max_bytes && self.padding_bytes > 0 {
+            let at = self.packets.iter().position(|(priority, _)| *priority == SendPriority::Padding).expect("padding is queued");
+            let (_, packet) = self.packets.remove(at).expect("found above");
+            self.bytes -= wire_len(&packet);
+            self.padding_bytes -= wire_len(&packet);
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+/// Bounded send queues, one per connection, between the layers that make packets and the
+/// transport, so a slow peer costs a bounded amount of memory however fast we produce for it.
+///
+/// Upper layers `push`; the host `take`s as much as a connection's socket can take now and
+/// hands it to the transport's `send`. A queue past `high_water` reports `Blocked`, so its
+/// producers pause, and `Unblocked` once drained below `low_water`; at `max_bytes` pushes
+/// fail, after queued padding was dropped to make room.
+pub struct SendQueues {
+    config: QueueConfig,
+    queues: HashMap<ConnectionId, SendQueue>,
+    dropped_padding: u64,
+    events: Vec<QueueEvent>,
+}
+
+impl SendQueues {
+    pub fn new(config: QueueConfig) -> Self {
+        Self { config, queues: HashMap::new(), dropped_padding: 0, events: Vec::new() }
+    }
+
+    /// Queues a packet for `connection`. Padding that doesn't fit, or is for a blocked
+    /// connection, is dropped rather than refused.
+    pub fn push(&mut self, connection: ConnectionId, packet: NetworkPacket, priority: SendPriority) -> Result<(), QueueError> {
+        let len = wire_len(&packet);
+        let queue = self.queues.entry(connection).or_default();
+        if priority == SendPriority::Padding {
+            if queue.blocked || queue.bytes + len > self.config.max_bytes {
+                self.dropped_padding += 1;
+                return Ok(());
+            }
+            queue.padding_bytes += len;
+        } else {
+            self.dropped_padding += queue.make_room(len, self.config.max_bytes);
+            if queue.bytes + len > self.config.max_bytes {
+                return Err(QueueError::Full { connection, queued: queue.bytes });
+            }
+        }
+
+        queue.bytes += len;
+        queue.packets.push_back((priority, packet));
+        if !queue.blocked && queue.normal_bytes() >= self.config.high_water {
+            log::debug!("Send queue of {connection:?} blocked at {} bytes", queue.bytes);
+            queue.blocked = true;
+            self.events.push(QueueEvent::Blocked(connection));
+        }
+        Ok(())
+    }
+
+    /// Takes the packets to send on `connection` now, in order, for about `budget` bytes: the
+    /// last one may go over.
+    pub fn take(&mut self, connection: ConnectionId, budget: usize) -> Vec<NetworkPacket> {
+        let Some(queue) = self.queues.get_mut(&connection) else {
+            return Vec::new();
+        };
+        let mut taken = Vec::new();
+        let mut spent = 0;
+        while spent < budget && let Some((priority, packet)) = queue.packets.pop_front() {
+            let len = wire_len(&packet);
+            spent += len;
+            queue.bytes -= len;
+            if priority == SendPriority::Padding {
+                queue.padding_bytes -= len;
+            }
+            taken.push(packet);
+        }
+        if queue.blocked && queue.normal_bytes() < self.config.low_water {
+            queue.blocked = false;
+            self.events.push(QueueEvent::Unblocked(connection));
+        }
+        if queue.packets.is_empty() && !queue.blocked {
+            self.queues.remove(&connection);
+        }
+        taken
+    }
+
+    /// Connections with packets waiting
+    pub fn pending(&self) -> Vec<ConnectionId> {
+        self.queues.iter().filter(|(_, q)| !q.packets.is_empty()).map(|(connection, _)| *connection).collect()
+    }
+
+    /// Bytes queued on a connection
+    pub fn queued(&self, connection: ConnectionId) -> usize {
+        self.queues.get(&connection).map_or(0, |q| q.bytes)
+    }
+
+    pub fn is_blocked(&self, connection: ConnectionId) -> bool {
+        self.queues.get(&connection).is_some_and(|q| q.blocked)
+    }
+
+    /// Drops the queue of a connection that closed. Returns the packets it held.
+    pub fn forget(&mut self, connection: ConnectionId) -> usize {
+        self.queues.remove(&connection).map_or(0, |q| q.packets.len())
+    }
+
+    /// Padding packets dropped so far
+    pub fn dropped_padding(&self) -> u64 {
+        self.dropped_padding
+    }
+
+    pub fn take_events(&mut self) -> Vec<QueueEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+fn wire_len(packet: &NetworkPacket) -> usize {
+    HEADER_SIZE + packet.payload.len()
+}
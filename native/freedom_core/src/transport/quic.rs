@@ -0,0 +1,893 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+use bytes::{ Bytes, BytesMut };
+use ed25519_dalek::Signature;
+use quinn_proto::crypto::rustls::{ NoInitialCipherSuite, QuicClientConfig, QuicServerConfig };
+use quinn_proto::{
+    ClientConfig,
+    ConnectError,
+    Connection,
+    ConnectionError,
+    ConnectionHandle,
+    DatagramEvent,
+    Dir,
+    Endpoint,
+    EndpointConfig,
+    Event,
+    IdleTimeout,
+    ReadError,
+    ServerConfig,
+    StreamEvent,
+    StreamId,
+    TransportConfig,
+    VarInt,
+    WriteError,
+};
+use rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime };
+use rustls::{ DigitallySignedStruct, SignatureScheme };
+
+use super::filter::{ AcceptConfig, AcceptFilter };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::{ HandshakeError, HandshakePayload, HANDSHAKE_PAYLOAD_SIZE, HANDSHAKE_WINDOW_SECS, SIGNATURE_SIZE };
+use crate::crypto::identity::{ self, NodeIdentity };
+use crate::protocol::header::{ FixedHeader, MessageType, HEADER_SIZE };
+use crate::protocol::packet::{ NetworkPacket, PacketError, DEFAULT_MAX_PAYLOAD_LEN };
+
+/// Application protocol negotiated in TLS, the one the C# listener announces
+pub const ALPN: &[u8] = b"freedom-v1";
+/// Name in our certificates: they are self-signed, nothing checks it
+const SERVER_NAME: &str = "freedom";
+/// Label of the TLS exporter each side signs with its identity key, binding its
+/// `HandshakePayload` to the connection. The signed message starts with it too, so the
+/// signature can't pass for anything else the key signs.
+const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-freedom-v1-channel-binding";
+/// Length of the channel binding the TLS exporter derives
+const CHANNEL_BINDING_SIZE: usize = 32;
+
+// Application error codes we close connections with
+const CLOSE_NORMAL: u32 = 0;
+const CLOSE_PROTOCOL_ERROR: u32 = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuicError {
+    #[error("TLS configuration failed: {0}")]
+    Tls(#[from] rustls::Error),
+    #[error("Could not create the certificate: {0}")]
+    Certificate(#[from] rcgen::Error),
+    #[error("TLS configuration has no cipher suite usable by QUIC: {0}")]
+    CipherSuite(#[from] NoInitialCipherSuite),
+    #[error("Could not dial: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("Unknown connection {0:?}")]
+    UnknownConnection(ConnectionId),
+    #[error("Connection {0:?} is not authenticated yet")]
+    NotConnected(ConnectionId),
+    #[error("Connection lost: {0}")]
+    Lost(#[from] ConnectionError),
+    #[error("Stream write failed: {0}")]
+    Write(#[from] WriteError),
+    #[error("Peer did not start with a handshake")]
+    MissingHandshake,
+    #[error("Peer handshake rejected: {0}")]
+    Handshake(#[from] HandshakeError),
+    /// The handshake is valid but was signed for another TLS session: replayed, or relayed by
+    /// someone in the middle
+    #[error("Peer handshake is not bound to this connection")]
+    ChannelBinding,
+    #[error("Malformed packet: {0}")]
+    Packet(#[from] PacketError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuicConfig {
+    /// Accept connections (the host binds a reachable port); dial only otherwise
+    pub listen: bool,
+    /// Connections silent for this long are dropped
+    pub idle_timeout_ms: u32,
+    /// Ping interval keeping quiet connections (and NAT mappings) alive
+    pub keep_alive_ms: Option<u32>,
+    pub max_payload_len: usize,
+    pub handshake_window_secs: u64,
+    /// Packets with a payload at least this long go on a stream of their own (say, the
+    /// answer to a large Fetch), so the small packets behind them don't wait for them
+    pub substream_min_len: usize,
+    /// Which connections peers open are taken, before their first datagram is processed
+    pub accept: AcceptConfig,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            listen: false,
+            idle_timeout_ms: 30_000,
+            keep_alive_ms: Some(10_000),
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            handshake_window_secs: HANDSHAKE_WINDOW_SECS,
+            substream_min_len: 16 * 1024,
+            accept: AcceptConfig::default(),
+        }
+    }
+}
+
+pub type QuicEvent = TransportEvent<QuicError>;
+
+struct QuicConnection {
+    handle: ConnectionHandle,
+    connection: Connection,
+    /// Our stream for packets, opened once the QUIC handshake is done
+    stream: Option<StreamId>,
+    /// Packet bytes waiting for room on `stream`, starting with our handshake once TLS completes
+    unsent: Vec<u8>,
+    /// Streams of their own carrying large packets, by request id
+    substreams: HashMap<u32, Substream>,
+    /// Bytes read from each of the peer's streams that don't make a whole packet yet
+    partial: HashMap<StreamId, Vec<u8>>,
+    peer: Option<HandshakePayload>,
+    /// What the peer sent before the TLS handshake completed: its handshake can't be checked
+    /// against the connection until then
+    held: Vec<NetworkPacket>,
+    /// The peer's address as last reported
+    addr: SocketAddr,
+    /// Where the last PATH_RESPONSE came from: a new path is valid once the peer answers on it
+    answered: Option<SocketAddr>,
+    /// Packets written and read; quinn counts the rest
+    packets_sent: u64,
+    packets_received: u64,
+    /// (Server) The address the accept filter counts the connection against
+    admitted: Option<SocketAddr>,
+    /// Closed or lost: kept only until quinn is done draining it
+    closed: bool,
+}
+
+struct Substream {
+    stream: StreamId,
+    /// Bytes left to write. Once all are written the stream is finished, and packets of the
+    /// exchange queued until the peer acknowledged it all go on a new stream after it.
+    unsent: Vec<u8>,
+    finished: bool,
+}
+
+impl QuicConnection {
+    /// Queues a packet to an authenticated peer: behind the packets of its request id on their
+    /// substream if they have one still open or unacknowledged, on a new one if the packet is
+    /// large, on the shared stream otherwise (or when the peer allows no more streams).
+    fn queue(&mut self, packet: &NetworkPacket, substream_min_len: usize) {
+        let request_id = packet.header.request_id;
+        if let Some(substream) = self.substreams.get_mut(&request_id) {
+            substream.unsent.extend_from_slice(&packet.to_bytes());
+            return;
+        }
+        if packet.payload.len() >= substream_min_len
+            && !self.connection.is_handshaking()
+            && let Some(stream) = self.connection.streams().open(Dir::Uni)
+        {
+            self.substreams.insert(request_id, Substream { stream, unsent: packet.to_bytes(), finished: false });
+            return;
+        }
+        self.unsent.extend_from_slice(&packet.to_bytes());
+    }
+
+    /// Writes what `unsent` and the substreams hold, as far as flow control lets us.
+    fn flush(&mut self) -> Result<(), WriteError> {
+        if !self.closed && !self.connection.is_handshaking() {
+            self.flush_substreams();
+        }
+        self.flush_shared()
+    }
+
+    fn flush_substreams(&mut self) {
+        let Self { connection, substreams, .. } = self;
+        substreams.retain(|request_id, substream| {
+            if substream.finished {
+                return true;
+            }
+            let mut send = connection.send_stream(substream.stream);
+            match send.write(&substream.unsent) {
+                Ok(written) => {
+                    substream.unsent.drain(..written);
+                }
+                Err(WriteError::Blocked) => {}
+                Err(e) => {
+                    // The peer stopped reading it: only this exchange is lost
+                    log::debug!("Dropping the substream of request {request_id}: {e}");
+                    return false;
+                }
+            }
+            if substream.unsent.is_empty() {
+                substream.finished = send.finish().is_ok();
+            }
+            true
+        });
+    }
+
+    /// A substream was all acknowledged, or stopped by the peer: what its exchange queued since
+    /// goes on a new one.
+    fn on_substream_finished(&mut self, stream: StreamId) {
+        let Some((&request_id, _)) = self.substreams.iter().find(|(_, s)| s.stream == stream) else {
+            return;
+        };
+        let substream = self.substreams.remove(&request_id).expect("found above");
+        if substream.unsent.is_empty() {
+            return;
+        }
+        match self.connection.streams().open(Dir::Uni) {
+            Some(stream) => {
+                self.substreams.insert(request_id, Substream { stream, unsent: substream.unsent, finished: false });
+            }
+            None => self.unsent.extend_from_slice(&substream.unsent),
+        }
+    }
+
+    fn flush_shared(&mut self) -> Result<(), WriteError> {
+        if self.closed || self.unsent.is_empty() || self.connection.is_handshaking() {
+            return Ok(());
+        }
+        let stream = match self.stream {
+            Some(stream) => stream,
+            None => {
+                // None until the peer allows another stream (`StreamEvent::Available`)
+                let Some(stream) = self.connection.streams().open(Dir::Bi) else {
+                    return Ok(());
+                };
+                self.stream = Some(stream);
+                stream
+            }
+        };
+        match self.connection.send_stream(stream).write(&self.unsent) {
+            Ok(written) => {
+                self.unsent.drain(..written);
+                Ok(())
+            }
+            Err(WriteError::Blocked) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads what arrived on one of the peer's streams. Returns the whole packets it completed.
+    fn read(&mut self, stream: StreamId, max_payload_len: usize) -> Result<Vec<NetworkPacket>, PacketError> {
+        let mut recv = self.connection.recv_stream(stream);
+        let Ok(mut chunks) = recv.read(true) else {
+            return Ok(Vec::new()); // Already finished
+        };
+        let buffer = self.partial.entry(stream).or_default();
+        let finished = loop {
+            match chunks.next(usize::MAX) {
+                Ok(Some(chunk)) => buffer.extend_from_slice(&chunk.bytes),
+                Ok(None) | Err(ReadError::Reset(_)) => break true,
+                Err(ReadError::Blocked) => break false,
+            }
+        };
+        // Any flow control credit it returns goes out with the next `poll_transmit`
+        let _ = chunks.finalize();
+
+        let packets = split_packets(buffer, max_payload_len);
+        if finished {
+            self.partial.remove(&stream);
+        }
+        packets
+    }
+
+    /// What both ends of this TLS session, and no other, derive from it. Known once the TLS
+    /// handshake completes.
+    fn channel_binding(&self) -> Result<[u8; CHANNEL_BINDING_SIZE], QuicError> {
+        let mut binding = [0u8; CHANNEL_BINDING_SIZE];
+        self.connection
+            .crypto_session()
+            .export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, &[])
+            .map_err(|_| QuicError::ChannelBinding)?;
+        Ok(binding)
+    }
+
+    /// Queues our handshake, bound to the TLS session that just completed, ahead of anything
+    /// else.
+    fn on_handshake_complete(&mut self, identity: &NodeIdentity, now_ms: u64) -> Result<(), QuicError> {
+        let hello = bound_handshake(identity, &self.channel_binding()?, now_ms / 1000);
+        let mut unsent = NetworkPacket::new(MessageType::Handshake, 0, hello).to_bytes();
+        unsent.append(&mut self.unsent);
+        self.unsent = unsent;
+        Ok(())
+    }
+
+    /// Follows the peer moving to another address: quinn switches to the new path as soon as
+    /// packets come from it, and back if the peer doesn't answer the PATH_CHALLENGE there.
+    /// Reported once the answer came (or silently, before the peer is authenticated).
+    fn track_path(&mut self, id: ConnectionId, events: &mut Vec<QuicEvent>) {
+        let remote = self.connection.remote_address();
+        if remote == self.addr {
+            self.answered = None;
+            return;
+        }
+        if self.peer.is_some() && self.answered != Some(remote) {
+            return;
+        }
+        self.answered = None;
+        self.addr = remote;
+        if self.peer.is_some() && !self.closed {
+            log::debug!("QUIC connection {id:?} migrated to {remote}");
+            events.push(QuicEvent::Migrated { connection: id, addr: remote });
+        }
+    }
+
+    fn fail(&mut self, now: Instant) {
+        self.connection.close(now, VarInt::from_u32(CLOSE_PROTOCOL_ERROR), Bytes::from_static(b"protocol error"));
+        self.closed = true;
+    }
+}
+
+/// The QUIC wire of the network over a UDP socket the host owns: connections dialled and
+/// accepted, each authenticated by a `HandshakePayload` before it carries packets.
+///
+/// TLS runs with throwaway self-signed certificates, which the dialer accepts whatever they
+/// are (as the C# host does), so once TLS completes the first packet each side sends on a
+/// connection is a `Handshake` with its signed `HandshakePayload`, and a signature with the
+/// same identity key over what the TLS exporter derives for the session (see
+/// `bound_handshake`). The latter ties the handshake to the connection: one recorded from
+/// another connection, or relayed by whoever sits in the middle of two, doesn't verify.
+/// Packets from a peer that doesn't start with a valid, fresh, bound one are refused and the
+/// connection closed. Packets are written back to back on one stream per direction, in the
+/// `NetworkPacket` framing (header, then payload); packets the peer sends on any of its
+/// streams are read.
+///
+/// A packet of `substream_min_len` or more goes on a unidirectional stream of its own instead,
+/// one per request id, which the packets of the same exchange follow until the peer has it
+/// all: a large transfer then doesn't hold up the small control messages on the shared stream,
+/// nor other transfers, when its datagrams are lost. Packets of one exchange keep their order.
+///
+/// Reconnecting to a node dialled before resumes the TLS session. 0-RTT data isn't sent or
+/// accepted: nothing is bound to the session before the TLS handshake completes, and anyone
+/// who recorded the early data could replay it.
+///
+/// Connections peers open go through `QuicConfig::accept` first: those it refuses are dropped
+/// unanswered, before quinn does any work for them.
+///
+/// Connections survive the peer changing networks (Wi-Fi to cellular, a NAT rebinding): its
+/// packets from a new address move the connection there, and once the peer answers a
+/// PATH_CHALLENGE on the new path it is reported `Migrated`. When our own network changes,
+/// the host calls `rebind` for the peers to do the same.
+///
+/// Sans-IO on top of quinn-proto: datagrams received go in through `on_datagram`, datagrams
+/// to send come out of `take_outgoing`, and the host calls `on_timeout` by `next_timeout`.
+/// Time is the host's wall clock, in milliseconds since the UNIX epoch (see `Transport`).
+pub struct QuicEndpoint {
+    endpoint: Endpoint,
+    client: ClientConfig,
+    server: Arc<ServerConfig>,
+    identity: NodeIdentity,
+    config: QuicConfig,
+    filter: AcceptFilter,
+    connections: HashMap<ConnectionId, QuicConnection>,
+    handles: HashMap<ConnectionHandle, ConnectionId>,
+    next_id: u64,
+    /// quinn keeps time in `Instant`s: the host's `now_ms` maps onto them from here
+    epoch: Instant,
+    epoch_ms: u64,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+    events: Vec<QuicEvent>,
+}
+
+impl QuicEndpoint {
+    pub fn new(identity: NodeIdentity, config: QuicConfig, now_ms: u64) -> Result<Self, QuicError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(config.idle_timeout_ms))))
+            .keep_alive_interval(config.keep_alive_ms.map(|ms| Duration::from_millis(ms.into())));
+        let transport = Arc::new(transport);
+
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+        let mut server_tls = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)?;
+        server_tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut server = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_tls)?));
+        server.transport_config(transport.clone());
+        let server = Arc::new(server);
+
+        let mut client_tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+            .with_no_client_auth();
+        client_tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut client = ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_tls)?));
+        client.transport_config(transport);
+
+        Ok(Self {
+            endpoint: Endpoint::new(Arc::new(EndpointConfig::default()), config.listen.then(|| server.clone()), false, None),
+            client,
+            server,
+            identity,
+            filter: AcceptFilter::new(config.accept.clone()),
+            config,
+            connections: HashMap::new(),
+            handles: HashMap::new(),
+            next_id: 0,
+            epoch: Instant::now(),
+            epoch_ms: now_ms,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        })
+    }
+
+    /// Starts a connection to the node listening at `addr`. `Connected` follows once both
+    /// handshakes are through.
+    pub fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, QuicError> {
+        let (handle, connection) = self.endpoint.connect(self.instant(now_ms), self.client.clone(), addr, &addr.ip().to_string())?;
+        let id = self.insert(handle, connection);
+        self.drive(id, now_ms);
+        Ok(id)
+    }
+
+    /// Handles a datagram received on the socket.
+    pub fn on_datagram(&mut self, from: SocketAddr, data: &[u8], now_ms: u64) {
+        let now = self.instant(now_ms);
+        let mut buf = Vec::new();
+        match self.endpoint.handle(now, from, None, None, BytesMut::from(data), &mut buf) {
+            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                let Some(&id) = self.handles.get(&handle) else {
+                    return;
+                };
+                if let Some(conn) = self.connections.get_mut(&id) {
+                    let responses = conn.connection.stats().frame_rx.path_response;
+                    conn.connection.handle_event(event);
+                    if conn.connection.stats().frame_rx.path_response > responses {
+                        conn.answered = Some(from);
+                    }
+                }
+                self.drive(id, now_ms);
+            }
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                if let Err(e) = self.filter.admit(from, now_ms) {
+                    // Dropped without an answer, as if no one listened
+                    log::debug!("Refusing QUIC connection from {from}: {e}");
+                    self.endpoint.ignore(incoming);
+                    return;
+                }
+                match self.endpoint.accept(incoming, now, &mut buf, None) {
+                    Ok((handle, connection)) => {
+                        let id = self.insert(handle, connection);
+                        if let Some(conn) = self.connections.get_mut(&id) {
+                            conn.admitted = Some(from);
+                        }
+                        self.drive(id, now_ms);
+                    }
+                    Err(e) => {
+                        self.filter.release(from);
+                        log::debug!("Refused QUIC connection from {from}: {}", e.cause);
+                        if let Some(transmit) = e.response {
+                            self.outgoing.push((transmit.destination, buf[..transmit.size].to_vec()));
+                        }
+                    }
+                }
+            }
+            Some(DatagramEvent::Response(transmit)) => {
+                self.outgoing.push((transmit.destination, buf[..transmit.size].to_vec()));
+            }
+            None => {}
+        }
+    }
+
+    /// Sends a packet to an authenticated peer.
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), QuicError> {
+        let conn = self.connections
+            .get_mut(&connection)
+            .filter(|c| !c.closed)
+            .ok_or(QuicError::UnknownConnection(connection))?;
+        if conn.peer.is_none() {
+            return Err(QuicError::NotConnected(connection));
+        }
+        conn.queue(packet, self.config.substream_min_len);
+        conn.packets_sent += 1;
+        self.drive(connection, now_ms);
+        Ok(())
+    }
+
+    /// Closes a connection. Returns false if it was unknown or already closed.
+    pub fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        let now = self.instant(now_ms);
+        let Some(conn) = self.connections.get_mut(&connection).filter(|c| !c.closed) else {
+            return false;
+        };
+        conn.connection.close(now, VarInt::from_u32(CLOSE_NORMAL), Bytes::new());
+        conn.closed = true;
+        self.drive(connection, now_ms);
+        true
+    }
+
+    /// Replaces the rules connections peers open are held to.
+    pub fn set_accept_config(&mut self, config: AcceptConfig) {
+        self.filter.set_config(config.clone());
+        self.config.accept = config;
+    }
+
+    /// Tells every connection the host's socket now sends from another local address (the
+    /// device changed networks): each switches to a fresh connection id and pings the peer,
+    /// which validates the new path instead of letting the connection time out.
+    pub fn rebind(&mut self, now_ms: u64) {
+        let ids: Vec<ConnectionId> = self.connections.keys().copied().collect();
+        for id in ids {
+            if let Some(conn) = self.connections.get_mut(&id).filter(|c| !c.closed) {
+                conn.connection.local_address_changed();
+            }
+            self.drive(id, now_ms);
+        }
+    }
+
+    /// When `on_timeout` is due next, if any connection waits for a timer
+    pub fn next_timeout(&mut self) -> Option<u64> {
+        let next = self.connections.values_mut().filter_map(|c| c.connection.poll_timeout()).min()?;
+        Some(self.epoch_ms + next.saturating_duration_since(self.epoch).as_millis() as u64)
+    }
+
+    /// Runs the timers due by `now_ms`: retransmissions, keep-alives, idle timeouts.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let now = self.instant(now_ms);
+        let ids: Vec<ConnectionId> = self.connections.keys().copied().collect();
+        for id in ids {
+            if let Some(conn) = self.connections.get_mut(&id)
+                && conn.connection.poll_timeout().is_some_and(|at| at <= now)
+            {
+                conn.connection.handle_timeout(now);
+            }
+            self.drive(id, now_ms);
+        }
+        self.filter.prune(now_ms);
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.connections.get(&connection)?.peer.as_ref()
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        Some(self.connections.get(&connection)?.connection.remote_address())
+    }
+
+    /// What a connection carried so far, with quinn's round-trip estimate and the datagrams
+    /// it found lost. Bytes are those of the UDP datagrams.
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        let conn = self.connections.get(&connection)?;
+        let stats = conn.connection.stats();
+        Some(TransportStats {
+            rtt_ms: Some(stats.path.rtt.as_millis().try_into().unwrap_or(u32::MAX)),
+            packets_sent: conn.packets_sent,
+            packets_received: conn.packets_received,
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+            lost_packets: stats.path.lost_packets,
+        })
+    }
+
+    /// Connections open or being set up
+    pub fn len(&self) -> usize {
+        self.connections.values().filter(|c| !c.closed).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Datagrams to send since the last call, each with its destination.
+    pub fn take_outgoing(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_events(&mut self) -> Vec<QuicEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn instant(&self, now_ms: u64) -> Instant {
+        self.epoch + Duration::from_millis(now_ms.saturating_sub(self.epoch_ms))
+    }
+
+    fn insert(&mut self, handle: ConnectionHandle, connection: Connection) -> ConnectionId {
+        let id = ConnectionId(self.next_id);
+        self.next_id += 1;
+        let addr = connection.remote_address();
+        self.connections.insert(id, QuicConnection {
+            handle,
+            connection,
+            stream: None,
+            unsent: Vec::new(),
+            substreams: HashMap::new(),
+            partial: HashMap::new(),
+            peer: None,
+            held: Vec::new(),
+            addr,
+            answered: None,
+            packets_sent: 0,
+            packets_received: 0,
+            admitted: None,
+            closed: false,
+        });
+        self.handles.insert(handle, id);
+        id
+    }
+
+    /// Lets a connection act on what it was given: reads and writes its streams, reports what
+    /// happened, queues its datagrams and forgets it once drained.
+    fn drive(&mut self, id: ConnectionId, now_ms: u64) {
+        let now = self.instant(now_ms);
+        let Some(conn) = self.connections.get_mut(&id) else {
+            return;
+        };
+
+        let mut failure = None;
+        while let Some(event) = conn.connection.poll() {
+            let result = match event {
+                Event::Connected => {
+                    let held = std::mem::take(&mut conn.held);
+                    conn.on_handshake_complete(&self.identity, now_ms)
+                        .and_then(|()| handle_packets(conn, id, held, &self.config, now_ms, &mut self.events))
+                        .and_then(|()| conn.flush().map_err(QuicError::from))
+                }
+                Event::Stream(StreamEvent::Writable { .. } | StreamEvent::Available { .. }) => {
+                    conn.flush().map_err(QuicError::from)
+                }
+                Event::Stream(StreamEvent::Finished { id: stream } | StreamEvent::Stopped { id: stream, .. }) => {
+                    conn.on_substream_finished(stream);
+                    conn.flush().map_err(QuicError::from)
+                }
+                Event::Stream(StreamEvent::Opened { dir }) => {
+                    let mut result = Ok(());
+                    while let Some(stream) = conn.connection.streams().accept(dir) {
+                        result = result.and(read_packets(conn, id, stream, &self.config, now_ms, &mut self.events));
+                    }
+                    result
+                }
+                Event::Stream(StreamEvent::Readable { id: stream }) => {
+                    read_packets(conn, id, stream, &self.config, now_ms, &mut self.events)
+                }
+                Event::ConnectionLost { reason } => {
+                    if !conn.closed {
+                        conn.closed = true;
+                        self.events.push(QuicEvent::Closed { connection: id, error: QuicError::Lost(reason) });
+                    }
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                failure.get_or_insert(e);
+            }
+        }
+        conn.track_path(id, &mut self.events);
+        if let Some(error) = failure
+            && !conn.closed
+        {
+            log::warn!("Closing QUIC connection to {}: {error}", conn.connection.remote_address());
+            conn.fail(now);
+            self.events.push(QuicEvent::Closed { connection: id, error });
+        } else if let Err(e) = conn.flush() {
+            conn.fail(now);
+            self.events.push(QuicEvent::Closed { connection: id, error: QuicError::Write(e) });
+        }
+
+        let mut buf = Vec::new();
+        while let Some(transmit) = conn.connection.poll_transmit(now, 1, &mut buf) {
+            self.outgoing.push((transmit.destination, buf[..transmit.size].to_vec()));
+            buf.clear();
+        }
+        // New connection ids and the like go through the endpoint, which may answer
+        while let Some(event) = conn.connection.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(conn.handle, event) {
+                conn.connection.handle_event(event);
+            }
+        }
+        if conn.connection.is_drained() {
+            if let Some(addr) = conn.admitted {
+                self.filter.release(addr);
+            }
+            self.handles.remove(&conn.handle);
+            self.connections.remove(&id);
+        }
+    }
+}
+
+impl Transport for QuicEndpoint {
+    /// A datagram received and who from
+    type Input = (SocketAddr, Vec<u8>);
+    /// A datagram to send and where to
+    type Output = (SocketAddr, Vec<u8>);
+    type Error = QuicError;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, QuicError> {
+        QuicEndpoint::dial(self, addr, now_ms)
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        self.config.listen = enabled;
+        self.endpoint.set_server_config(enabled.then(|| self.server.clone()));
+    }
+
+    fn handle(&mut self, (from, datagram): (SocketAddr, Vec<u8>), now_ms: u64) -> Option<ConnectionId> {
+        self.on_datagram(from, &datagram, now_ms);
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), QuicError> {
+        QuicEndpoint::send(self, connection, packet, now_ms)
+    }
+
+    fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        QuicEndpoint::close(self, connection, now_ms)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        QuicEndpoint::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        QuicEndpoint::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        QuicEndpoint::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        QuicEndpoint::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        QuicEndpoint::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        QuicEndpoint::take_outgoing(self)
+    }
+
+    fn take_events(&mut self) -> Vec<QuicEvent> {
+        QuicEndpoint::take_events(self)
+    }
+}
+
+
+/// Reads a stream of the peer and handles the packets it completed.
+fn read_packets(
+    conn: &mut QuicConnection,
+    id: ConnectionId,
+    stream: StreamId,
+    config: &QuicConfig,
+    now_ms: u64,
+    events: &mut Vec<QuicEvent>,
+) -> Result<(), QuicError> {
+    let packets = conn.read(stream, config.max_payload_len)?;
+    handle_packets(conn, id, packets, config, now_ms, events)
+}
+
+/// Handles packets of the peer: the first must be its handshake, bound to the connection.
+/// Those that come before the TLS handshake completes are held until it has.
+fn handle_packets(
+    conn: &mut QuicConnection,
+    id: ConnectionId,
+    packets: Vec<NetworkPacket>,
+    config: &QuicConfig,
+    now_ms: u64,
+    events: &mut Vec<QuicEvent>,
+) -> Result<(), QuicError> {
+    for packet in packets {
+        if conn.connection.is_handshaking() {
+            conn.held.push(packet);
+            continue;
+        }
+        if conn.peer.is_some() {
+            conn.packets_received += 1;
+            events.push(QuicEvent::Packet { connection: id, packet });
+            continue;
+        }
+        if packet.header.message_type != MessageType::Handshake {
+            return Err(QuicError::MissingHandshake);
+        }
+        let peer = verify_bound_handshake(&packet.payload, &conn.channel_binding()?, now_ms / 1000, config.handshake_window_secs)?;
+
+        conn.peer = Some(peer.clone());
+        events.push(QuicEvent::Connected { connection: id, addr: conn.connection.remote_address(), peer: Box::new(peer) });
+    }
+    Ok(())
+}
+
+/// Our handshake, bound to the TLS session `binding` comes from.
+/// Format: [HandshakePayload (136)] [Signature over label + binding (64)]
+pub(crate) fn bound_handshake(identity: &NodeIdentity, binding: &[u8; CHANNEL_BINDING_SIZE], now_secs: u64) -> Vec<u8> {
+    let mut hello = identity.sign_handshake(now_secs).to_bytes().to_vec();
+    hello.extend_from_slice(&identity.sign(&binding_message(binding)).to_bytes());
+    hello
+}
+
+/// Checks a peer's handshake, bound to the TLS session `binding` comes from: signed, fresh,
+/// and signed for this session by the same identity key.
+pub(crate) fn verify_bound_handshake(
+    bytes: &[u8],
+    binding: &[u8; CHANNEL_BINDING_SIZE],
+    now_secs: u64,
+    window_secs: u64,
+) -> Result<HandshakePayload, QuicError> {
+    if bytes.len() != HANDSHAKE_PAYLOAD_SIZE + SIGNATURE_SIZE {
+        return Err(HandshakeError::InvalidSize { expected: HANDSHAKE_PAYLOAD_SIZE + SIGNATURE_SIZE, got: bytes.len() }.into());
+    }
+    let (payload, signature) = bytes.split_at(HANDSHAKE_PAYLOAD_SIZE);
+    let peer = HandshakePayload::from_bytes(payload)?;
+    peer.verify()?;
+    peer.check_freshness(now_secs, window_secs)?;
+
+    let signature = Signature::from_bytes(signature.try_into().expect("split at the payload size"));
+    identity
+        ::verify(&peer.identity_key, &binding_message(binding), &signature)
+        .map_err(|_| QuicError::ChannelBinding)?;
+    Ok(peer)
+}
+
+/// [Label] [Binding (32)]
+fn binding_message(binding: &[u8; CHANNEL_BINDING_SIZE]) -> Vec<u8> {
+    [CHANNEL_BINDING_LABEL, binding].concat()
+}
+
+/// Takes the whole packets off the front of `buffer`, leaving a packet still arriving.
+fn split_packets(buffer: &mut Vec<u8>, max_payload_len: usize) -> Result<Vec<NetworkPacket>, PacketError> {
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    while let Some(header) = buffer.get(offset..offset + HEADER_SIZE) {
+        let header = FixedHeader::from_bytes(header)?;
+        // Checked before waiting for the payload, so a huge announced length can't make us buffer it
+        if header.payload_length as usize > max_payload_len {
+            return Err(PacketError::PayloadTooLarge { len: header.payload_length, max: max_payload_len });
+        }
+        let end = offset + HEADER_SIZE + header.payload_length as usize;
+        let Some(bytes) = buffer.get(offset..end) else {
+            break;
+        };
+        packets.push(NetworkPacket::from_bytes_limited(bytes, max_payload_len)?);
+        offset = end;
+    }
+    buffer.drain(..offset);
+    Ok(packets)
+}
+
+/// Accepts any server certificate: nodes use throwaway self-signed ones, and prove who they
+/// are with their `HandshakePayload` instead. TLS signatures are still checked against the
+/// certificate's key.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,660 @@
+use std::collections::{ HashMap, VecDeque };
+use std::net::SocketAddr;
+
+use super::tcp::{ TcpCommand, TcpConfig, TcpError, TcpTransport };
+use super::{ ConnectionId, TransportEvent };
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::contact::{ AddressKind, TransportAddress };
+use crate::dht::node_id::NodeId;
+use crate::onion::bandwidth::{ self, BandwidthConfig, TokenBucket, SOFT_CAP_PERCENT };
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+
+/// Largest chunk of a relayed byte stream in one message
+pub const MAX_RELAY_DATA: usize = 16 * 1024;
+/// Bytes a relay holds for one direction of a circuit before closing it
+const MAX_QUEUED_BYTES: usize = 16 * MAX_RELAY_DATA;
+
+const KIND_RESERVE: u8 = 1;
+const KIND_RESERVED: u8 = 2;
+const KIND_REFUSED: u8 = 3;
+const KIND_CONNECT: u8 = 4;
+const KIND_INCOMING: u8 = 5;
+const KIND_DATA: u8 = 6;
+const KIND_CLOSE: u8 = 7;
+
+/// Why a relay refused a reservation or closed a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RelayReason {
+    Normal = 0,
+    /// The target holds no reservation with the relay
+    NoReservation = 1,
+    /// Out of reservations, circuits or bandwidth
+    ResourceLimit = 2,
+    UnknownCircuit = 3,
+    /// Data larger than `MAX_RELAY_DATA`, or more queued than allowed
+    Protocol = 4,
+    /// The node at the other end went away
+    PeerGone = 5,
+}
+
+impl TryFrom<u8> for RelayReason {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, CodecError> {
+        match value {
+            0 => Ok(RelayReason::Normal),
+            1 => Ok(RelayReason::NoReservation),
+            2 => Ok(RelayReason::ResourceLimit),
+            3 => Ok(RelayReason::UnknownCircuit),
+            4 => Ok(RelayReason::Protocol),
+            5 => Ok(RelayReason::PeerGone),
+            _ => Err(CodecError::InvalidField("relay reason")),
+        }
+    }
+}
+
+/// Relayed connections, TURN-like. A NATed node reserves a slot with a volunteer relay and
+/// advertises the relay's address; a peer then connects to the relay and asks for a circuit
+/// to it. Circuits are named by a number chosen by the side that opens them (the dialer
+/// towards the relay, the relay towards the reserved node) and carry a byte stream.
+/// Format: [Kind (1)], then
+///   Reserve / Reserved: [TtlSecs (4)]   (Reserve with 0 releases the reservation)
+///   Refused: [Reason (1)]
+///   Connect: [Circuit (4)] [TargetID (32)]
+///   Incoming: [Circuit (4)] [FromID (32)]
+///   Data: [Circuit (4)] [Bytes]
+///   Close: [Circuit (4)] [Reason (1)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayMessage {
+    Reserve {
+        ttl_secs: u32,
+    },
+    Reserved {
+        ttl_secs: u32,
+    },
+    Refused(RelayReason),
+    Connect {
+        circuit: u32,
+        target: NodeId,
+    },
+    /// To the reserved node: `from` opened a circuit to it
+    Incoming {
+        circuit: u32,
+        from: NodeId,
+    },
+    Data {
+        circuit: u32,
+        bytes: Vec<u8>,
+    },
+    Close {
+        circuit: u32,
+        reason: RelayReason,
+    },
+}
+
+impl RelayMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            RelayMessage::Reserve { ttl_secs } | RelayMessage::Reserved { ttl_secs } => {
+                out.push(if matches!(self, RelayMessage::Reserve { .. }) { KIND_RESERVE } else { KIND_RESERVED });
+                out.extend_from_slice(&ttl_secs.to_be_bytes());
+            }
+            RelayMessage::Refused(reason) => out.extend_from_slice(&[KIND_REFUSED, *reason as u8]),
+            RelayMessage::Connect { circuit, target: node } | RelayMessage::Incoming { circuit, from: node } => {
+                out.push(if matches!(self, RelayMessage::Connect { .. }) { KIND_CONNECT } else { KIND_INCOMING });
+                out.extend_from_slice(&circuit.to_be_bytes());
+                out.extend_from_slice(node.as_bytes());
+            }
+            RelayMessage::Data { circuit, bytes } => {
+                out.push(KIND_DATA);
+                out.extend_from_slice(&circuit.to_be_bytes());
+                out.extend_from_slice(bytes);
+            }
+            RelayMessage::Close { circuit, reason } => {
+                out.push(KIND_CLOSE);
+                out.extend_from_slice(&circuit.to_be_bytes());
+                out.push(*reason as u8);
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CodecError> {
+        let mut reader = Reader::new(bytes);
+        let message = match reader.u8()? {
+            KIND_RESERVE => RelayMessage::Reserve { ttl_secs: reader.u32()? },
+            KIND_RESERVED => RelayMessage::Reserved { ttl_secs: reader.u32()? },
+            KIND_REFUSED => RelayMessage::Refused(RelayReason::try_from(reader.u8()?)?),
+            KIND_CONNECT => RelayMessage::Connect { circuit: reader.u32()?, target: NodeId::from_bytes(reader.take_array()?) },
+            KIND_INCOMING => RelayMessage::Incoming { circuit: reader.u32()?, from: NodeId::from_bytes(reader.take_array()?) },
+            KIND_DATA => {
+                let circuit = reader.u32()?;
+                let bytes = reader.rest();
+                if bytes.len() > MAX_RELAY_DATA {
+                    return Err(CodecError::InvalidField("relay data length"));
+                }
+                RelayMessage::Data { circuit, bytes: bytes.to_vec() }
+            }
+            KIND_CLOSE => RelayMessage::Close { circuit: reader.u32()?, reason: RelayReason::try_from(reader.u8()?)? },
+            _ => {
+                return Err(CodecError::InvalidField("relay message kind"));
+            }
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+
+    /// None for packets of other types
+    pub fn from_packet(packet: &NetworkPacket) -> Option<Result<Self, CodecError>> {
+        (packet.header.message_type == MessageType::Relay).then(|| Self::from_bytes(&packet.payload))
+    }
+
+    pub fn to_packet(&self, request_id: u32) -> NetworkPacket {
+        NetworkPacket::new(MessageType::Relay, request_id, self.to_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayServiceConfig {
+    pub max_reservations: usize,
+    /// Longest reservation granted; nodes renew before it runs out
+    pub max_ttl_secs: u32,
+    /// Circuits open at once through the relay, all nodes together
+    pub max_circuits: usize,
+    /// Rates for the relay and each circuit direction, and the monthly cap, as for onion relaying
+    pub bandwidth: BandwidthConfig,
+}
+
+impl Default for RelayServiceConfig {
+    fn default() -> Self {
+        Self { max_reservations: 128, max_ttl_secs: 3600, max_circuits: 512, bandwidth: BandwidthConfig::default() }
+    }
+}
+
+/// One end of a circuit through the relay, as its node names it
+type CircuitEnd = (NodeId, u32);
+
+struct Hop {
+    /// Where what arrives on this end goes
+    to: CircuitEnd,
+    bucket: TokenBucket,
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+}
+
+/// The volunteer side of relayed connections: keeps the reservations of NATed nodes and
+/// forwards the circuits peers open to them, within the bandwidth it donates.
+///
+/// Data goes through the same limits as onion cells: a token bucket for the relay and one
+/// per circuit direction, fed by `poll`, and bytes counted against the monthly cap. Past
+/// `SOFT_CAP_PERCENT` of the cap no reservation or circuit is taken; at the cap, circuits
+/// with data to carry are closed. The host passes `RelayMessage`s from its connections to
+/// `on_message`, sends what `take_outgoing` returns, and reports nodes it lost.
+pub struct RelayService {
+    config: RelayServiceConfig,
+    reservations: HashMap<NodeId, u64>,
+    hops: HashMap<CircuitEnd, Hop>,
+    /// Hops with data queued, in turn order
+    turns: VecDeque<CircuitEnd>,
+    global: TokenBucket,
+    month: u64,
+    used: u64,
+    next_circuit: u32,
+    outgoing: Vec<(NodeId, RelayMessage)>,
+}
+
+impl RelayService {
+    pub fn new(config: RelayServiceConfig, now_ms: u64) -> Self {
+        Self {
+            config,
+            reservations: HashMap::new(),
+            hops: HashMap::new(),
+            turns: VecDeque::new(),
+            global: TokenBucket::full(config.bandwidth.burst, now_ms),
+            month: bandwidth::month_of(now_ms),
+            used: 0,
+            next_circuit: 0,
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Handles a message from `from`, an authenticated neighbour.
+    pub fn on_message(&mut self, from: NodeId, message: RelayMessage, now_ms: u64) {
+        self.roll_month(now_ms);
+        match message {
+            RelayMessage::Reserve { ttl_secs: 0 } => {
+                self.reservations.remove(&from);
+            }
+            RelayMessage::Reserve { ttl_secs } => {
+                self.reservations.retain(|_, expires_ms| *expires_ms > now_ms);
+                let full = self.reservations.len() >= self.config.max_reservations && !self.reservations.contains_key(&from);
+                if full || self.over_soft_cap() {
+                    self.outgoing.push((from, RelayMessage::Refused(RelayReason::ResourceLimit)));
+                    return;
+                }
+                let ttl_secs = ttl_secs.min(self.config.max_ttl_secs);
+                self.reservations.insert(from, now_ms + u64::from(ttl_secs) * 1000);
+                self.outgoing.push((from, RelayMessage::Reserved { ttl_secs }));
+            }
+            RelayMessage::Connect { circuit, target } => {
+                let reserved = self.reservations.get(&target).is_some_and(|expires_ms| *expires_ms > now_ms);
+                let refusal = if !reserved {
+                    Some(RelayReason::NoReservation)
+                } else if self.hops.len() / 2 >= self.config.max_circuits || self.over_soft_cap() || self.hops.contains_key(&(from, circuit)) {
+                    Some(RelayReason::ResourceLimit)
+                } else {
+                    None
+                };
+                if let Some(reason) = refusal {
+                    self.outgoing.push((from, RelayMessage::Close { circuit, reason }));
+                    return;
+                }
+                self.next_circuit = self.next_circuit.wrapping_add(1);
+                let theirs = (target, self.next_circuit);
+                self.open_hop((from, circuit), theirs, now_ms);
+                self.open_hop(theirs, (from, circuit), now_ms);
+                self.outgoing.push((target, RelayMessage::Incoming { circuit: self.next_circuit, from }));
+            }
+            RelayMessage::Data { circuit, bytes } => {
+                let Some(hop) = self.hops.get_mut(&(from, circuit)) else {
+                    self.outgoing.push((from, RelayMessage::Close { circuit, reason: RelayReason::UnknownCircuit }));
+                    return;
+                };
+                if hop.queued + bytes.len() > MAX_QUEUED_BYTES {
+                    self.close((from, circuit), RelayReason::Protocol);
+                    return;
+                }
+                if hop.queue.is_empty() {
+                    self.turns.push_back((from, circuit));
+                }
+                hop.queued += bytes.len();
+                hop.queue.push_back(bytes);
+            }
+            RelayMessage::Close { circuit, .. } => {
+                if let Some(hop) = self.forget((from, circuit)) {
+                    self.outgoing.push((hop.to.0, RelayMessage::Close { circuit: hop.to.1, reason: RelayReason::Normal }));
+                }
+            }
+            RelayMessage::Reserved { .. } | RelayMessage::Refused(_) | RelayMessage::Incoming { .. } => {}
+        }
+    }
+
+    /// Forwards the queued data the buckets allow by `now_ms`, one chunk per circuit in turn.
+    pub fn poll(&mut self, now_ms: u64) {
+        self.roll_month(now_ms);
+        let config = self.config.bandwidth;
+        self.global.refill(config.rate, config.burst, now_ms);
+        let mut waiting = 0;
+        while waiting < self.turns.len() {
+            let end = self.turns.pop_front().expect("checked above");
+            let Some(hop) = self.hops.get_mut(&end) else {
+                continue;
+            };
+            let len = hop.queue.front().map_or(0, Vec::len);
+            hop.bucket.refill(config.circuit_rate, config.circuit_burst, now_ms);
+            if !hop.bucket.has(len) || !self.global.has(len) {
+                self.turns.push_back(end);
+                waiting += 1;
+                continue;
+            }
+            // Received and sent both count
+            if config.monthly_cap.is_some_and(|cap| self.used + 2 * len as u64 > cap) {
+                self.close(end, RelayReason::ResourceLimit);
+                continue;
+            }
+
+            let bytes = hop.queue.pop_front().expect("hops in turn have data");
+            hop.queued -= len;
+            hop.bucket.tokens -= len as f64;
+            self.global.tokens -= len as f64;
+            self.used += 2 * len as u64;
+            self.outgoing.push((hop.to.0, RelayMessage::Data { circuit: hop.to.1, bytes }));
+            if !hop.queue.is_empty() {
+                self.turns.push_back(end);
+            }
+            waiting = 0;
+        }
+    }
+
+    /// A node's connection to us is gone: its reservation and circuits with it.
+    pub fn on_disconnected(&mut self, node: NodeId) {
+        self.reservations.remove(&node);
+        let ends: Vec<CircuitEnd> = self.hops.keys().filter(|(owner, _)| *owner == node).copied().collect();
+        for end in ends {
+            if let Some(hop) = self.forget(end) {
+                self.outgoing.push((hop.to.0, RelayMessage::Close { circuit: hop.to.1, reason: RelayReason::PeerGone }));
+            }
+        }
+    }
+
+    pub fn is_reserved(&self, node: &NodeId, now_ms: u64) -> bool {
+        self.reservations.get(node).is_some_and(|expires_ms| *expires_ms > now_ms)
+    }
+
+    /// Circuits open through the relay
+    pub fn circuits(&self) -> usize {
+        self.hops.len() / 2
+    }
+
+    /// Bytes received and sent this month
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn take_outgoing(&mut self) -> Vec<(NodeId, RelayMessage)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn open_hop(&mut self, end: CircuitEnd, to: CircuitEnd, now_ms: u64) {
+        let bucket = TokenBucket::full(self.config.bandwidth.circuit_burst, now_ms);
+        self.hops.insert(end, Hop { to, bucket, queue: VecDeque::new(), queued: 0 });
+    }
+
+    /// Removes both directions of the circuit `end` belongs to. Returns its hop.
+    fn forget(&mut self, end: CircuitEnd) -> Option<Hop> {
+        let hop = self.hops.remove(&end)?;
+        self.hops.remove(&hop.to);
+        self.turns.retain(|e| *e != end && *e != hop.to);
+        Some(hop)
+    }
+
+    /// Closes a circuit on both its nodes.
+    fn close(&mut self, end: CircuitEnd, reason: RelayReason) {
+        log::debug!("Closing relayed circuit {} of {:?}: {reason:?}", end.1, end.0);
+        if let Some(hop) = self.forget(end) {
+            self.outgoing.push((end.0, RelayMessage::Close { circuit: end.1, reason }));
+            self.outgoing.push((hop.to.0, RelayMessage::Close { circuit: hop.to.1, reason }));
+        }
+    }
+
+    fn over_soft_cap(&self) -> bool {
+        self.config.bandwidth.monthly_cap.is_some_and(|cap| self.used >= cap / 100 * SOFT_CAP_PERCENT)
+    }
+
+    fn roll_month(&mut self, now_ms: u64) {
+        let month = bandwidth::month_of(now_ms);
+        if month != self.month {
+            self.month = month;
+            self.used = 0;
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RelayError {
+    #[error("Unknown connection {0:?}")]
+    UnknownConnection(ConnectionId),
+    #[error("Relay closed the circuit: {0:?}")]
+    Closed(RelayReason),
+    #[error("Lost the connection to the relay")]
+    RelayLost,
+    #[error("Reached {got:?} instead of {expected:?}")]
+    WrongPeer {
+        expected: NodeId,
+        got: NodeId,
+    },
+    #[error("TCP: {0}")]
+    Tcp(#[from] TcpError),
+}
+
+pub type RelayEvent = TransportEvent<RelayError>;
+
+struct Reservation {
+    addr: SocketAddr,
+    /// When to ask again: before it runs out, or after a refusal
+    renew_ms: Option<u64>,
+    expires_ms: Option<u64>,
+}
+
+/// The node side of relayed connections: reserves slots with relays so peers can reach us
+/// through them, and dials peers through the relays they advertise.
+///
+/// Relays only see ciphertext: a relayed circuit is a byte stream, and both ends run the TCP
+/// transport's handshake and encrypted session over it, so the peer is authenticated end to
+/// end (and must be the node the circuit was opened to). It is the last resort after direct
+/// and hole-punched connections, slower for the extra hop and the relay's limits.
+///
+/// The host carries `RelayMessage`s between it and the relays over its connections to them.
+pub struct RelayClient {
+    tcp: TcpTransport,
+    ttl_secs: u32,
+    reservations: HashMap<NodeId, Reservation>,
+    /// Dials whose circuit isn't open yet: through which relay, to whom
+    dials: HashMap<ConnectionId, (NodeId, NodeId)>,
+    /// Who each connection should reach
+    expected: HashMap<ConnectionId, NodeId>,
+    circuits: HashMap<ConnectionId, CircuitEnd>,
+    connections: HashMap<CircuitEnd, ConnectionId>,
+    next_circuit: u32,
+    outgoing: Vec<(NodeId, RelayMessage)>,
+    events: Vec<RelayEvent>,
+}
+
+impl RelayClient {
+    pub fn new(identity: NodeIdentity, config: TcpConfig, ttl_secs: u32) -> Self {
+        Self {
+            tcp: TcpTransport::new(identity, config),
+            ttl_secs,
+            reservations: HashMap::new(),
+            dials: HashMap::new(),
+            expected: HashMap::new(),
+            circuits: HashMap::new(),
+            connections: HashMap::new(),
+            next_circuit: 0,
+            outgoing: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Asks `relay`, at `addr`, to keep a slot for us, renewed until `release`.
+    pub fn reserve(&mut self, relay: NodeId, addr: SocketAddr) {
+        self.reservations.insert(relay, Reservation { addr, renew_ms: None, expires_ms: None });
+        self.outgoing.push((relay, RelayMessage::Reserve { ttl_secs: self.ttl_secs }));
+    }
+
+    pub fn release(&mut self, relay: NodeId) {
+        if self.reservations.remove(&relay).is_some() {
+            self.outgoing.push((relay, RelayMessage::Reserve { ttl_secs: 0 }));
+        }
+    }
+
+    /// The relays holding a slot for us, to advertise in our contact info
+    pub fn relay_addresses(&self, now_ms: u64) -> Vec<TransportAddress> {
+        self.reservations
+            .values()
+            .filter(|r| r.expires_ms.is_some_and(|expires_ms| expires_ms > now_ms))
+            .map(|r| TransportAddress { addr: r.addr, kind: AddressKind::Relay })
+            .collect()
+    }
+
+    /// Opens a connection to `target` through `relay` (at `relay_addr`), which we must be
+    /// connected to. `Connected` follows once `target` is authenticated.
+    pub fn dial(&mut self, relay: NodeId, relay_addr: SocketAddr, target: NodeId, now_ms: u64) -> ConnectionId {
+        let connection = self.tcp.dial(relay_addr, now_ms);
+        self.dials.insert(connection, (relay, target));
+        self.expected.insert(connection, target);
+        self.pump();
+        connection
+    }
+
+    /// Handles a message from `relay`.
+    pub fn on_message(&mut self, relay: NodeId, message: RelayMessage, now_ms: u64) {
+        match message {
+            RelayMessage::Reserved { ttl_secs } => {
+                if let Some(reservation) = self.reservations.get_mut(&relay) {
+                    let ttl_ms = u64::from(ttl_secs) * 1000;
+                    reservation.expires_ms = Some(now_ms + ttl_ms);
+                    reservation.renew_ms = Some(now_ms + ttl_ms / 2);
+                }
+            }
+            RelayMessage::Refused(reason) => {
+                if let Some(reservation) = self.reservations.get_mut(&relay) {
+                    log::info!("Relay {relay:?} refused a reservation: {reason:?}");
+                    reservation.renew_ms = Some(now_ms + u64::from(self.ttl_secs) * 1000 / 2);
+                }
+            }
+            RelayMessage::Incoming { circuit, from } => {
+                let Some(reservation) = self.reservations.get(&relay) else {
+                    self.outgoing.push((relay, RelayMessage::Close { circuit, reason: RelayReason::NoReservation }));
+                    return;
+                };
+                let connection = self.tcp.accept(reservation.addr, now_ms);
+                self.expected.insert(connection, from);
+                self.circuits.insert(connection, (relay, circuit));
+                self.connections.insert((relay, circuit), connection);
+            }
+            RelayMessage::Data { circuit, bytes } => match self.connections.get(&(relay, circuit)) {
+                Some(connection) => self.tcp.on_data(*connection, &bytes, now_ms),
+                None => self.outgoing.push((relay, RelayMessage::Close { circuit, reason: RelayReason::UnknownCircuit })),
+            },
+            RelayMessage::Close { circuit, reason } => {
+                if let Some(connection) = self.connections.get(&(relay, circuit)).copied() {
+                    self.unroute(connection);
+                    self.tcp.close(connection);
+                    self.events.push(RelayEvent::Closed { connection, error: RelayError::Closed(reason) });
+                }
+            }
+            RelayMessage::Reserve { .. } | RelayMessage::Connect { .. } => {}
+        }
+        self.pump();
+    }
+
+    /// Our connection to `relay` is gone, and every circuit through it. Its reservation is
+    /// kept but no longer renewed nor advertised until `reserve` is called again.
+    pub fn on_relay_lost(&mut self, relay: NodeId) {
+        if let Some(reservation) = self.reservations.get_mut(&relay) {
+            reservation.renew_ms = None;
+            reservation.expires_ms = None;
+        }
+        let lost: Vec<ConnectionId> = self.circuits.iter().filter(|(_, end)| end.0 == relay).map(|(c, _)| *c).collect();
+        for connection in lost {
+            self.unroute(connection);
+            self.tcp.close(connection);
+            self.events.push(RelayEvent::Closed { connection, error: RelayError::RelayLost });
+        }
+        self.pump();
+    }
+
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket) -> Result<(), RelayError> {
+        self.tcp.send(connection, packet)?;
+        self.pump();
+        Ok(())
+    }
+
+    /// Closes a connection. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId) -> bool {
+        let known = self.tcp.close(connection);
+        self.pump();
+        known
+    }
+
+    /// When `on_timeout` is due next
+    pub fn next_timeout(&self) -> Option<u64> {
+        let renewals = self.reservations.values().filter_map(|r| r.renew_ms);
+        renewals.chain(self.tcp.next_timeout()).min()
+    }
+
+    /// Renews reservations and times out handshakes.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        for (relay, reservation) in &mut self.reservations {
+            if reservation.renew_ms.is_some_and(|at| at <= now_ms) {
+                reservation.renew_ms = None;
+                self.outgoing.push((*relay, RelayMessage::Reserve { ttl_secs: self.ttl_secs }));
+            }
+        }
+        self.tcp.on_timeout(now_ms);
+        self.pump();
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.tcp.peer(connection)
+    }
+
+    /// The relay a connection goes through
+    pub fn relay(&self, connection: ConnectionId) -> Option<NodeId> {
+        Some(self.circuits.get(&connection)?.0)
+    }
+
+    /// Messages for relays, in order
+    pub fn take_outgoing(&mut self) -> Vec<(NodeId, RelayMessage)> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    pub fn take_events(&mut self) -> Vec<RelayEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn unroute(&mut self, connection: ConnectionId) {
+        self.dials.remove(&connection);
+        self.expected.remove(&connection);
+        if let Some(end) = self.circuits.remove(&connection) {
+            self.connections.remove(&end);
+        }
+    }
+
+    /// Turns what the TCP transport would do with sockets into circuit messages, and passes
+    /// its events on.
+    fn pump(&mut self) {
+        for command in self.tcp.take_outgoing() {
+            match command {
+                TcpCommand::Connect { connection, .. } => {
+                    let Some((relay, target)) = self.dials.remove(&connection) else {
+                        continue;
+                    };
+                    self.next_circuit = self.next_circuit.wrapping_add(1);
+                    let end = (relay, self.next_circuit);
+                    self.circuits.insert(connection, end);
+                    self.connections.insert(end, connection);
+                    self.outgoing.push((relay, RelayMessage::Connect { circuit: end.1, target }));
+                }
+                TcpCommand::Write { connection, bytes } => {
+                    if let Some(&(relay, circuit)) = self.circuits.get(&connection) {
+                        for chunk in bytes.chunks(MAX_RELAY_DATA) {
+                            self.outgoing.push((relay, RelayMessage::Data { circuit, bytes: chunk.to_vec() }));
+                        }
+                    }
+                }
+                TcpCommand::Close(connection) => {
+                    if let Some((relay, circuit)) = self.circuits.get(&connection).copied() {
+                        self.unroute(connection);
+                        self.outgoing.push((relay, RelayMessage::Close { circuit, reason: RelayReason::Normal }));
+                    }
+                }
+            }
+        }
+
+        let mut wrong = Vec::new();
+        for event in self.tcp.take_events() {
+            match event {
+                TransportEvent::Connected { connection, addr, peer } => {
+                    let got = NodeId::from_identity_key(&peer.identity_key);
+                    match self.expected.get(&connection) {
+                        Some(expected) if *expected != got => wrong.push((connection, *expected, got)),
+                        _ => self.events.push(RelayEvent::Connected { connection, addr, peer }),
+                    }
+                }
+                TransportEvent::Packet { connection, packet } => self.events.push(RelayEvent::Packet { connection, packet }),
+                TransportEvent::Migrated { connection, addr } => self.events.push(RelayEvent::Migrated { connection, addr }),
+                TransportEvent::Closed { connection, error } => {
+                    self.unroute(connection);
+                    self.events.push(RelayEvent::Closed { connection, error: error.into() });
+                }
+            }
+        }
+        for &(connection, expected, got) in &wrong {
+            log::warn!("Relayed connection {connection:?} reached {got:?} instead of {expected:?}");
+            self.tcp.close(connection);
+            self.events.push(RelayEvent::Closed { connection, error: RelayError::WrongPeer { expected, got } });
+        }
+        // Closing on a wrong peer queued a Close of its own
+        if !wrong.is_empty() {
+            self.pump();
+        }
+    }
+}
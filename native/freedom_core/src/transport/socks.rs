@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use super::tcp::{ TcpCommand, TcpInput };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::HandshakePayload;
+use crate::protocol::codec::{ CodecError, Reader };
+use crate::protocol::packet::NetworkPacket;
+
+pub(crate) const SOCKS_VERSION: u8 = 5;
+pub(crate) const AUTH_NONE: u8 = 0x00;
+pub(crate) const AUTH_PASSWORD: u8 = 0x02;
+pub(crate) const AUTH_UNACCEPTABLE: u8 = 0xFF;
+/// Version of the username/password subnegotiation (RFC 1929)
+pub(crate) const PASSWORD_VERSION: u8 = 1;
+pub(crate) const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// The outcome of a SOCKS5 request (RFC 1928 §6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SocksReply {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    NetworkUnreachable = 3,
+    HostUnreachable = 4,
+    ConnectionRefused = 5,
+    TtlExpired = 6,
+    CommandNotSupported = 7,
+    AddressNotSupported = 8,
+}
+
+impl TryFrom<u8> for SocksReply {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, CodecError> {
+        match value {
+            0 => Ok(SocksReply::Succeeded),
+            1 => Ok(SocksReply::GeneralFailure),
+            2 => Ok(SocksReply::NotAllowed),
+            3 => Ok(SocksReply::NetworkUnreachable),
+            4 => Ok(SocksReply::HostUnreachable),
+            5 => Ok(SocksReply::ConnectionRefused),
+            6 => Ok(SocksReply::TtlExpired),
+            7 => Ok(SocksReply::CommandNotSupported),
+            8 => Ok(SocksReply::AddressNotSupported),
+            _ => Err(CodecError::InvalidField("reply")),
+        }
+    }
+}
+
+/// Where a SOCKS5 request goes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SocksAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl SocksAddr {
+    /// Format: [AddrType (1)] [IPv4 (4) | Len (1) Domain (Len) | IPv6 (16)] [Port (2)]
+    pub(crate) fn write(&self, out: &mut Vec<u8>) {
+        let port = match self {
+            SocksAddr::Ip(SocketAddr::V4(addr)) => {
+                out.push(ATYP_IPV4);
+                out.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            SocksAddr::Ip(SocketAddr::V6(addr)) => {
+                out.push(ATYP_IPV6);
+                out.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            SocksAddr::Domain(domain, port) => {
+                let domain = &domain.as_bytes()[..domain.len().min(255)];
+                out.push(ATYP_DOMAIN);
+                out.push(domain.len() as u8);
+                out.extend_from_slice(domain);
+                *port
+            }
+        };
+        out.extend_from_slice(&port.to_be_bytes());
+    }
+
+    pub(crate) fn read(reader: &mut Reader<'_>) -> Result<Self, CodecError> {
+        let addr = match reader.u8()? {
+            ATYP_IPV4 => SocksAddr::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(reader.take_array::<4>()?)), 0)),
+            ATYP_IPV6 => SocksAddr::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(reader.take_array::<16>()?)), 0)),
+            ATYP_DOMAIN => {
+                let len = reader.u8()? as usize;
+                let domain = std::str::from_utf8(reader.take(len)?).map_err(|_| CodecError::InvalidField("domain"))?;
+                SocksAddr::Domain(domain.to_string(), 0)
+            }
+            _ => {
+                return Err(CodecError::InvalidField("address type"));
+            }
+        };
+        let port = reader.u16()?;
+        Ok(match addr {
+            SocksAddr::Ip(addr) => SocksAddr::Ip(SocketAddr::new(addr.ip(), port)),
+            SocksAddr::Domain(domain, _) => SocksAddr::Domain(domain, port),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Config {
+    pub proxy: SocketAddr,
+    /// Username and password (RFC 1929), each at most 255 bytes; without them we ask for
+    /// no authentication
+    pub credentials: Option<(String, String)>,
+}
+
+impl Default for Socks5Config {
+    fn default() -> Self {
+        Self { proxy: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1080), credentials: None }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Socks5Error<E> {
+    #[error("Proxy credentials are longer than 255 bytes")]
+    CredentialsTooLong,
+    #[error("Proxy accepts none of our authentication methods")]
+    NoAcceptableAuth,
+    #[error("Proxy rejected our credentials")]
+    AuthFailed,
+    #[error("Proxy could not connect: {0:?}")]
+    Refused(SocksReply),
+    #[error("Malformed proxy reply")]
+    Protocol,
+    #[error("Transport: {0}")]
+    Transport(E),
+}
+
+pub type Socks5Event<E> = TransportEvent<Socks5Error<E>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Method,
+    Password,
+    Connect,
+}
+
+/// A dial whose socket is still being set up by the proxy
+struct Negotiation {
+    target: SocketAddr,
+    step: Step,
+    received: Vec<u8>,
+    /// What the transport wrote meanwhile
+    held: Vec<Vec<u8>>,
+}
+
+/// Routes the dials of a TCP-based transport (`TcpTransport`, `ObfsTransport`) through a
+/// SOCKS5 proxy: to chain through another anonymity network, or out of a network that only
+/// lets a proxy out. Wrapping is per transport, so each may use its own proxy, or none.
+///
+/// The socket each dial opens goes to the proxy, which is asked to CONNECT to the peer;
+/// what the transport writes is held until the proxy has, and everything after its reply is
+/// the transport's as usual. Sockets accepted are not touched. Only TCP dials can be proxied:
+/// a `FallbackTransport` would dial QUIC directly, so a node that must not be seen dialling
+/// peers runs TCP only.
+pub struct Socks5Transport<T: Transport> {
+    inner: T,
+    config: Socks5Config,
+    negotiating: HashMap<ConnectionId, Negotiation>,
+    /// Why the proxy failed dials, until the transport reports them closed
+    failed: HashMap<ConnectionId, Socks5Error<T::Error>>,
+    commands: Vec<TcpCommand>,
+    events: Vec<Socks5Event<T::Error>>,
+}
+
+impl<T: Transport<Input = TcpInput, Output = TcpCommand>> Socks5Transport<T> {
+    pub fn new(inner: T, config: Socks5Config) -> Self {
+        Self {
+            inner,
+            config,
+            negotiating: HashMap::new(),
+            failed: HashMap::new(),
+            commands: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Advances the negotiation of `connection` with what the proxy sent.
+    fn negotiate(&mut self, connection: ConnectionId, data: &[u8], now_ms: u64) -> Result<(), Socks5Error<T::Error>> {
+        let Self { inner, config, negotiating, commands, .. } = self;
+        let negotiation = negotiating.get_mut(&connection).expect("checked by handle");
+        negotiation.received.extend_from_slice(data);
+        loop {
+            let received = &negotiation.received;
+            match negotiation.step {
+                Step::Method => {
+                    let [version, method, ..] = received[..] else {
+                        return Ok(());
+                    };
+                    if version != SOCKS_VERSION {
+                        return Err(Socks5Error::Protocol);
+                    }
+                    negotiation.step = match (method, &config.credentials) {
+                        (AUTH_NONE, _) => Step::Connect,
+                        (AUTH_PASSWORD, Some((username, password))) => {
+                            let mut bytes = vec![PASSWORD_VERSION, username.len() as u8];
+                            bytes.extend_from_slice(username.as_bytes());
+                            bytes.push(password.len() as u8);
+                            bytes.extend_from_slice(password.as_bytes());
+                            commands.push(TcpCommand::Write { connection, bytes });
+                            Step::Password
+                        }
+                        (AUTH_UNACCEPTABLE, _) => {
+                            return Err(Socks5Error::NoAcceptableAuth);
+                        }
+                        _ => {
+                            return Err(Socks5Error::Protocol);
+                        }
+                    };
+                    negotiation.received.drain(..2);
+                    if negotiation.step == Step::Connect {
+                        commands.push(TcpCommand::Write { connection, bytes: connect_request(&SocksAddr::Ip(negotiation.target)) });
+                    }
+                }
+                Step::Password => {
+                    let [version, status, ..] = received[..] else {
+                        return Ok(());
+                    };
+                    if version != PASSWORD_VERSION {
+                        return Err(Socks5Error::Protocol);
+                    }
+                    if status != 0 {
+                        return Err(Socks5Error::AuthFailed);
+                    }
+                    negotiation.received.drain(..2);
+                    negotiation.step = Step::Connect;
+                    commands.push(TcpCommand::Write { connection, bytes: connect_request(&SocksAddr::Ip(negotiation.target)) });
+                }
+                Step::Connect => {
+                    let mut reader = Reader::new(received);
+                    let reply = match read_reply(&mut reader) {
+                        Ok(reply) => reply,
+                        Err(CodecError::UnexpectedEof { .. }) => return Ok(()),
+                        Err(_) => return Err(Socks5Error::Protocol),
+                    };
+                    if reply != SocksReply::Succeeded {
+                        return Err(Socks5Error::Refused(reply));
+                    }
+                    let rest = reader.rest().to_vec();
+                    let negotiation = negotiating.remove(&connection).expect("negotiating");
+                    log::debug!("Proxy connected {connection:?} to {}", negotiation.target);
+                    commands.extend(negotiation.held.into_iter().map(|bytes| TcpCommand::Write { connection, bytes }));
+                    if !rest.is_empty() {
+                        inner.handle(TcpInput::Data { connection, bytes: rest }, now_ms);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sends the transport's socket commands through the proxy, and passes its events on.
+    fn pump(&mut self) {
+        for command in self.inner.take_outgoing() {
+            match command {
+                TcpCommand::Connect { connection, addr } => {
+                    let methods: &[u8] = if self.config.credentials.is_some() { &[AUTH_NONE, AUTH_PASSWORD] } else { &[AUTH_NONE] };
+                    let greeting = [&[SOCKS_VERSION, methods.len() as u8][..], methods].concat();
+                    self.commands.push(TcpCommand::Connect { connection, addr: self.config.proxy });
+                    self.commands.push(TcpCommand::Write { connection, bytes: greeting });
+                    self.negotiating.insert(connection, Negotiation { target: addr, step: Step::Method, received: Vec::new(), held: Vec::new() });
+                }
+                TcpCommand::Write { connection, bytes } => match self.negotiating.get_mut(&connection) {
+                    Some(negotiation) => negotiation.held.push(bytes),
+                    None => self.commands.push(TcpCommand::Write { connection, bytes }),
+                },
+                TcpCommand::Close(connection) => {
+                    self.negotiating.remove(&connection);
+                    self.commands.push(TcpCommand::Close(connection));
+                }
+            }
+        }
+        for event in self.inner.take_events() {
+            self.events.push(match event {
+                TransportEvent::Connected { connection, addr, peer } => Socks5Event::Connected { connection, addr, peer },
+                TransportEvent::Packet { connection, packet } => Socks5Event::Packet { connection, packet },
+                TransportEvent::Migrated { connection, addr } => Socks5Event::Migrated { connection, addr },
+                TransportEvent::Closed { connection, error } => {
+                    let error = match self.failed.remove(&connection) {
+                        Some(failure) => failure,
+                        None => Socks5Error::Transport(error),
+                    };
+                    Socks5Event::Closed { connection, error }
+                }
+            });
+        }
+    }
+}
+
+impl<T: Transport<Input = TcpInput, Output = TcpCommand>> Transport for Socks5Transport<T> {
+    type Input = TcpInput;
+    type Output = TcpCommand;
+    type Error = Socks5Error<T::Error>;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, Self::Error> {
+        if let Some((username, password)) = &self.config.credentials
+            && (username.len() > 255 || password.len() > 255)
+        {
+            return Err(Socks5Error::CredentialsTooLong);
+        }
+        let connection = self.inner.dial(addr, now_ms).map_err(Socks5Error::Transport)?;
+        self.pump();
+        Ok(connection)
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        self.inner.listen(enabled);
+    }
+
+    fn handle(&mut self, input: TcpInput, now_ms: u64) -> Option<ConnectionId> {
+        let accepted = match input {
+            TcpInput::Data { connection, bytes } if self.negotiating.contains_key(&connection) => {
+                if let Err(error) = self.negotiate(connection, &bytes, now_ms) {
+                    log::info!("Proxied dial {connection:?} failed: {error}");
+                    self.negotiating.remove(&connection);
+                    self.failed.insert(connection, error);
+                    self.commands.push(TcpCommand::Close(connection));
+                    self.inner.handle(TcpInput::Closed(connection), now_ms);
+                }
+                None
+            }
+            TcpInput::Closed(connection) => {
+                self.negotiating.remove(&connection);
+                self.inner.handle(TcpInput::Closed(connection), now_ms)
+            }
+            input => self.inner.handle(input, now_ms),
+        };
+        self.pump();
+        accepted
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, now_ms: u64) -> Result<(), Self::Error> {
+        self.inner.send(connection, packet, now_ms).map_err(Socks5Error::Transport)?;
+        self.pump();
+        Ok(())
+    }
+
+    fn close(&mut self, connection: ConnectionId, now_ms: u64) -> bool {
+        let known = self.inner.close(connection, now_ms);
+        self.pump();
+        known
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        self.inner.next_timeout()
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        self.inner.on_timeout(now_ms);
+        self.pump();
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.inner.peer(connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        self.inner.remote_address(connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        self.inner.stats(connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn take_events(&mut self) -> Vec<Socks5Event<T::Error>> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Format: [Version 5 (1)] [Command (1)] [0 (1)] [Address]
+pub(crate) fn connect_request(target: &SocksAddr) -> Vec<u8> {
+    let mut bytes = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    target.write(&mut bytes);
+    bytes
+}
+
+/// Format: [Version 5 (1)] [Reply (1)] [0 (1)] [Bound address]
+pub(crate) fn reply(reply: SocksReply, bound: &SocksAddr) -> Vec<u8> {
+    let mut bytes = vec![SOCKS_VERSION, reply as u8, 0];
+    bound.write(&mut bytes);
+    bytes
+}
+
+fn read_reply(reader: &mut Reader<'_>) -> Result<SocksReply, CodecError> {
+    if reader.u8()? != SOCKS_VERSION {
+        return Err(CodecError::InvalidField("version"));
+    }
+    let reply = SocksReply::try_from(reader.u8()?)?;
+    let _reserved = reader.u8()?;
+    SocksAddr::read(reader)?;
+    Ok(reply)
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use ed25519_dalek::Signature;
+use rand::rngs::OsRng;
+use x25519_dalek::{ PublicKey as X25519PublicKey, StaticSecret };
+
+use super::filter::{ AcceptConfig, AcceptFilter };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::{ HandshakeError, HandshakePayload, HANDSHAKE_PAYLOAD_SIZE, HANDSHAKE_WINDOW_SECS };
+use crate::crypto::helper::CryptoError;
+use crate::crypto::identity::NodeIdentity;
+use crate::crypto::session::{ Session, SESSION_OVERHEAD };
+use crate::protocol::header::HEADER_SIZE;
+use crate::protocol::packet::{ NetworkPacket, PacketError, DEFAULT_MAX_PAYLOAD_LEN };
+
+const KEY_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+/// [Len (4)]
+const FRAME_HEADER_SIZE: usize = 4;
+const TRANSCRIPT_LABEL: &[u8] = b"FreedomNode-TCP-v1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TcpError {
+    #[error("Unknown connection {0:?}")]
+    UnknownConnection(ConnectionId),
+    #[error("Connection {0:?} is not authenticated yet")]
+    NotConnected(ConnectionId),
+    #[error("Socket closed")]
+    Disconnected,
+    #[error("Peer did not complete the handshake in time")]
+    HandshakeTimeout,
+    #[error("Peer handshake rejected: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error("Session keys are not signed by the peer's identity")]
+    InvalidBinding,
+    #[error("Frame of {0} bytes exceeds the limit")]
+    FrameTooLarge(usize),
+    #[error("Frame failed to decrypt: {0}")]
+    Crypto(#[from] CryptoError),
+    #[error("Malformed packet: {0}")]
+    Packet(#[from] PacketError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpConfig {
+    pub max_payload_len: usize,
+    pub handshake_window_secs: u64,
+    /// Connections not authenticated by then are closed
+    pub handshake_timeout_ms: u64,
+    /// Which sockets the host accepts are taken, before any of the handshake
+    pub accept: AcceptConfig,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            max_payload_len: DEFAULT_MAX_PAYLOAD_LEN,
+            handshake_window_secs: HANDSHAKE_WINDOW_SECS,
+            handshake_timeout_ms: 10_000,
+            accept: AcceptConfig::default(),
+        }
+    }
+}
+
+pub type TcpEvent = TransportEvent<TcpError>;
+
+/// What the host does with its sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpCommand {
+    /// Open a socket to `addr` for `connection`; the `Write`s for it follow
+    Connect {
+        connection: ConnectionId,
+        addr: SocketAddr,
+    },
+    Write {
+        connection: ConnectionId,
+        bytes: Vec<u8>,
+    },
+    Close(ConnectionId),
+}
+
+/// What the host's sockets did, for `Transport::handle`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpInput {
+    /// A socket was accepted from `addr`: `handle` returns the connection it is now
+    Accepted(SocketAddr),
+    Data {
+        connection: ConnectionId,
+        bytes: Vec<u8>,
+    },
+    Closed(ConnectionId),
+}
+
+struct TcpConnection {
+    addr: SocketAddr,
+    /// Our ephemeral key, until the peer's arrives
+    secret: Option<StaticSecret>,
+    ours: X25519PublicKey,
+    dialer: bool,
+    session: Option<Session>,
+    /// What both sides sign once the keys are exchanged
+    transcript: Vec<u8>,
+    peer: Option<HandshakePayload>,
+    /// Bytes received that don't make a whole key or frame yet
+    partial: Vec<u8>,
+    /// When the handshake must be done by
+    deadline_ms: u64,
+    stats: TransportStats,
+}
+
+/// The TCP wire of the network, for where UDP (and so QUIC) is blocked: packets in
+/// length-framed records of an encrypted `Session`, without TLS.
+///
+/// Both sides open with an ephemeral X25519 key; the `Session` is keyed from the two. The
+/// first record each side sends is its `HandshakePayload` followed by a signature, with the
+/// same identity key, over "FreedomNode-TCP-v1" | DialerKey | AcceptorKey, which ties the
+/// session to the identity so no one in between can run two sessions and relay. Every record
+/// after that holds one `NetworkPacket`.
+/// Format: [EphemeralKey (32)], then records [Len (4)] [Session message (Len)]
+///
+/// Sans-IO: the host opens, writes and closes sockets as `TcpCommand`s say, and hands over
+/// sockets it accepted with `accept`, bytes it read with `on_data`, and sockets that closed
+/// with `on_closed`. Time is the host's wall clock, in milliseconds since the UNIX epoch.
+pub struct TcpTransport {
+    identity: NodeIdentity,
+    config: TcpConfig,
+    /// Whether sockets the host accepts are taken; they are closed straight away if not
+    listening: bool,
+    filter: AcceptFilter,
+    connections: HashMap<ConnectionId, TcpConnection>,
+    next_id: u64,
+    commands: Vec<TcpCommand>,
+    events: Vec<TcpEvent>,
+}
+
+impl TcpTransport {
+    pub fn new(identity: NodeIdentity, config: TcpConfig) -> Self {
+        Self {
+            identity,
+            filter: AcceptFilter::new(config.accept.clone()),
+            config,
+            listening: true,
+            connections: HashMap::new(),
+            next_id: 0,
+            commands: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Starts a connection to the node listening at `addr`. `Connected` follows once both
+    /// handshakes are through.
+    pub fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.allocate();
+        self.commands.push(TcpCommand::Connect { connection, addr });
+        self.open(connection, addr, true, now_ms);
+        connection
+    }
+
+    /// Takes over a socket the host accepted from `addr`, or has it closed if we don't listen
+    /// or the accept filter refuses `addr`.
+    pub fn accept(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.allocate();
+        if !self.listening {
+            self.commands.push(TcpCommand::Close(connection));
+        } else if let Err(e) = self.filter.admit(addr, now_ms) {
+            log::debug!("Refusing TCP connection from {addr}: {e}");
+            self.commands.push(TcpCommand::Close(connection));
+        } else {
+            self.open(connection, addr, false, now_ms);
+        }
+        connection
+    }
+
+    /// Replaces the rules sockets the host accepts are held to.
+    pub fn set_accept_config(&mut self, config: AcceptConfig) {
+        self.filter.set_config(config.clone());
+        self.config.accept = config;
+    }
+
+    /// Handles bytes read from a connection's socket.
+    pub fn on_data(&mut self, connection: ConnectionId, data: &[u8], now_ms: u64) {
+        let Some(conn) = self.connections.get_mut(&connection) else {
+            return;
+        };
+        conn.partial.extend_from_slice(data);
+        conn.stats.bytes_received += data.len() as u64;
+        if let Err(error) = self.process(connection, now_ms) {
+            log::warn!("Closing TCP connection {connection:?}: {error}");
+            self.remove(connection);
+            self.commands.push(TcpCommand::Close(connection));
+            self.events.push(TcpEvent::Closed { connection, error });
+        }
+    }
+
+    /// The host's socket closed or failed.
+    pub fn on_closed(&mut self, connection: ConnectionId) {
+        if self.remove(connection) {
+            self.events.push(TcpEvent::Closed { connection, error: TcpError::Disconnected });
+        }
+    }
+
+    /// Sends a packet to an authenticated peer.
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket) -> Result<(), TcpError> {
+        let conn = self.connections.get_mut(&connection).ok_or(TcpError::UnknownConnection(connection))?;
+        let (Some(session), Some(_)) = (&mut conn.session, &conn.peer) else {
+            return Err(TcpError::NotConnected(connection));
+        };
+        let bytes = frame(session.encrypt(&packet.to_bytes())?);
+        conn.stats.packets_sent += 1;
+        conn.stats.bytes_sent += bytes.len() as u64;
+        self.commands.push(TcpCommand::Write { connection, bytes });
+        Ok(())
+    }
+
+    /// Closes a connection. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId) -> bool {
+        let known = self.remove(connection);
+        if known {
+            self.commands.push(TcpCommand::Close(connection));
+        }
+        known
+    }
+
+    /// When `on_timeout` is due next, if a handshake is under way
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.connections.values().filter(|c| c.peer.is_none()).map(|c| c.deadline_ms).min()
+    }
+
+    /// Closes the connections whose handshake didn't finish in time, and forgets sources the
+    /// accept filter no longer needs to remember.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        let expired: Vec<ConnectionId> = self.connections
+            .iter()
+            .filter(|(_, c)| c.peer.is_none() && c.deadline_ms <= now_ms)
+            .map(|(id, _)| *id)
+            .collect();
+        for connection in expired {
+            self.remove(connection);
+            self.commands.push(TcpCommand::Close(connection));
+            self.events.push(TcpEvent::Closed { connection, error: TcpError::HandshakeTimeout });
+        }
+        self.filter.prune(now_ms);
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.connections.get(&connection)?.peer.as_ref()
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        Some(self.connections.get(&connection)?.addr)
+    }
+
+    /// What a connection carried so far; TCP measures neither round trips nor losses
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        Some(self.connections.get(&connection)?.stats)
+    }
+
+    /// Connections open or being set up
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// What to do with the sockets since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn take_events(&mut self) -> Vec<TcpEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Forgets a connection, which no longer counts against its peer's address if it was
+    /// accepted. Returns false if it was unknown.
+    fn remove(&mut self, connection: ConnectionId) -> bool {
+        let Some(conn) = self.connections.remove(&connection) else {
+            return false;
+        };
+        if !conn.dialer {
+            self.filter.release(conn.addr);
+        }
+        true
+    }
+
+    fn allocate(&mut self) -> ConnectionId {
+        self.next_id += 1;
+        ConnectionId(self.next_id - 1)
+    }
+
+    /// Starts the handshake of a new connection by sending our key.
+    fn open(&mut self, connection: ConnectionId, addr: SocketAddr, dialer: bool, now_ms: u64) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let ours = X25519PublicKey::from(&secret);
+        self.commands.push(TcpCommand::Write { connection, bytes: ours.as_bytes().to_vec() });
+        self.connections.insert(connection, TcpConnection {
+            addr,
+            secret: Some(secret),
+            ours,
+            dialer,
+            session: None,
+            transcript: Vec::new(),
+            peer: None,
+            partial: Vec::new(),
+            deadline_ms: now_ms + self.config.handshake_timeout_ms,
+            stats: TransportStats { bytes_sent: KEY_SIZE as u64, ..TransportStats::default() },
+        });
+    }
+
+    /// Consumes what `partial` holds: the peer's key, its handshake, then packets.
+    fn process(&mut self, connection: ConnectionId, now_ms: u64) -> Result<(), TcpError> {
+        let Self { identity, config, connections, commands, events, .. } = self;
+        let conn = connections.get_mut(&connection).expect("checked by on_data");
+        let mut offset = 0;
+
+        if let Some(secret) = conn.secret.take_if(|_| conn.partial.len() >= KEY_SIZE) {
+            let theirs = X25519PublicKey::from(<[u8; KEY_SIZE]>::try_from(&conn.partial[..KEY_SIZE]).expect("sliced to KEY_SIZE"));
+            let (dialer, acceptor) = if conn.dialer { (conn.ours, theirs) } else { (theirs, conn.ours) };
+            conn.transcript = [TRANSCRIPT_LABEL, dialer.as_bytes(), acceptor.as_bytes()].concat();
+
+            let mut session = Session::from_ephemeral(&secret, &theirs);
+            let mut hello = identity.sign_handshake(now_ms / 1000).to_bytes().to_vec();
+            hello.extend_from_slice(&identity.sign(&conn.transcript).to_bytes());
+            let bytes = frame(session.encrypt(&hello)?);
+            conn.stats.bytes_sent += bytes.len() as u64;
+            commands.push(TcpCommand::Write { connection, bytes });
+            conn.session = Some(session);
+            offset = KEY_SIZE;
+        }
+        let Some(session) = &mut conn.session else {
+            return Ok(());
+        };
+
+        let max_frame = HEADER_SIZE + config.max_payload_len + SESSION_OVERHEAD;
+        while let Some(header) = conn.partial.get(offset..offset + FRAME_HEADER_SIZE) {
+            let len = u32::from_be_bytes(header.try_into().expect("sliced to FRAME_HEADER_SIZE")) as usize;
+            if len > max_frame {
+                return Err(TcpError::FrameTooLarge(len));
+            }
+            let start = offset + FRAME_HEADER_SIZE;
+            let Some(record) = conn.partial.get(start..start + len) else {
+                break;
+            };
+            let plaintext = session.decrypt(record)?;
+            offset = start + len;
+
+            if conn.peer.is_some() {
+                let packet = NetworkPacket::from_bytes_limited(&plaintext, config.max_payload_len)?;
+                conn.stats.packets_received += 1;
+                events.push(TcpEvent::Packet { connection, packet });
+                continue;
+            }
+            if plaintext.len() != HANDSHAKE_PAYLOAD_SIZE + SIGNATURE_SIZE {
+                return Err(HandshakeError::InvalidSize { expected: HANDSHAKE_PAYLOAD_SIZE + SIGNATURE_SIZE, got: plaintext.len() }.into());
+            }
+            let (payload, binding) = plaintext.split_at(HANDSHAKE_PAYLOAD_SIZE);
+            let peer = HandshakePayload::from_bytes(payload)?;
+            peer.verify()?;
+            peer.check_freshness(now_ms / 1000, config.handshake_window_secs)?;
+            let binding = Signature::from_slice(binding).map_err(|_| TcpError::InvalidBinding)?;
+            peer.identity_key.verify_strict(&conn.transcript, &binding).map_err(|_| TcpError::InvalidBinding)?;
+
+            conn.peer = Some(peer.clone());
+            events.push(TcpEvent::Connected { connection, addr: conn.addr, peer: Box::new(peer) });
+        }
+        conn.partial.drain(..offset);
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    type Input = TcpInput;
+    type Output = TcpCommand;
+    type Error = TcpError;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, TcpError> {
+        Ok(TcpTransport::dial(self, addr, now_ms))
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        self.listening = enabled;
+    }
+
+    fn handle(&mut self, input: TcpInput, now_ms: u64) -> Option<ConnectionId> {
+        match input {
+            TcpInput::Accepted(addr) => {
+                return Some(self.accept(addr, now_ms));
+            }
+            TcpInput::Data { connection, bytes } => self.on_data(connection, &bytes, now_ms),
+            TcpInput::Closed(connection) => self.on_closed(connection),
+        }
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, _now_ms: u64) -> Result<(), TcpError> {
+        TcpTransport::send(self, connection, packet)
+    }
+
+    fn close(&mut self, connection: ConnectionId, _now_ms: u64) -> bool {
+        TcpTransport::close(self, connection)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        TcpTransport::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        TcpTransport::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        TcpTransport::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        TcpTransport::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        TcpTransport::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        TcpTransport::take_outgoing(self)
+    }
+
+    fn take_events(&mut self) -> Vec<TcpEvent> {
+        TcpTransport::take_events(self)
+    }
+}
+
+fn frame(record: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(FRAME_HEADER_SIZE + record.len());
+    bytes.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&record);
+    bytes
+}
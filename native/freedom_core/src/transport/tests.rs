@@ -0,0 +1,1826 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::crypto::handshake::{ HandshakeError, HANDSHAKE_PAYLOAD_SIZE };
+use crate::crypto::identity::NodeIdentity;
+use crate::dht::node_id::NodeId;
+use crate::dht::peer_store::PeerStore;
+use crate::onion::bandwidth::BandwidthConfig;
+use crate::protocol::header::MessageType;
+use crate::protocol::packet::NetworkPacket;
+use crate::transport::{ ConnectionId, Transport };
+use crate::dht::contact::{ AddressKind, Reachability, TransportAddress };
+use crate::transport::mdns::{ self, LocalDiscovery, MdnsConfig, MdnsEvent, MDNS_V4, MDNS_V6 };
+use crate::transport::nat::{ self, NatBehavior, NatConfig, NatError, NatEvent, NatTraversal, PunchMessage, StunMessage };
+use crate::transport::obfs::{ BridgeCert, ObfsConfig, ObfsError, ObfsEvent, ObfsTransport };
+use crate::transport::portmap::{ PortMapCommand, PortMapConfig, PortMapEvent, PortMapMethod, PortMapper, GATEWAY_PORT, SSDP_ADDR };
+use crate::transport::queue::{ QueueConfig, QueueError, QueueEvent, SendPriority, SendQueues };
+use crate::transport::relay::{ RelayClient, RelayError, RelayEvent, RelayMessage, RelayReason, RelayService, RelayServiceConfig, MAX_RELAY_DATA };
+use crate::transport::manager::{ ConnectionManager, ManagerConfig, ManagerError, ManagerEvent };
+use crate::transport::filter::{ AcceptConfig, AcceptFilter, FilterError, IpNetwork };
+use crate::transport::fallback::{ FallbackError, FallbackEvent, FallbackTransport, QUIC_FALLBACK_MS };
+use crate::transport::quic::{ self, QuicConfig, QuicEndpoint, QuicError, QuicEvent };
+use crate::transport::websocket::{ self, WebSocketServer, WebSocketTransport, WsCommand, WsConfig, WsError, WsEvent };
+use crate::transport::socks::{ Socks5Config, Socks5Error, Socks5Event, Socks5Transport, SocksReply };
+use crate::transport::tcp::{ TcpCommand, TcpConfig, TcpError, TcpEvent, TcpInput, TcpTransport };
+
+const NOW_MS: u64 = 1_700_000_000_000;
+
+fn quic_addr(host: u8) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, host], 4433))
+}
+
+/// Delivers the datagrams of every endpoint to the one they are addressed to, running timers,
+/// until the network is quiet. Returns the events of each endpoint, in order.
+fn run_quic(endpoints: &mut [(SocketAddr, &mut QuicEndpoint)], now_ms: &mut u64) -> Vec<Vec<QuicEvent>> {
+    let mut events: Vec<Vec<QuicEvent>> = endpoints.iter().map(|_| Vec::new()).collect();
+    for _ in 0..1000 {
+        let mut in_flight = Vec::new();
+        for (from, endpoint) in endpoints.iter_mut() {
+            in_flight.extend(endpoint.take_outgoing().into_iter().map(|(to, datagram)| (*from, to, datagram)));
+        }
+        for (i, (_, endpoint)) in endpoints.iter_mut().enumerate() {
+            events[i].extend(endpoint.take_events());
+        }
+        if in_flight.is_empty() {
+            return events;
+        }
+
+        *now_ms += 1;
+        for (from, to, datagram) in in_flight {
+            if let Some((_, endpoint)) = endpoints.iter_mut().find(|(addr, _)| *addr == to) {
+                endpoint.on_datagram(from, &datagram, *now_ms);
+            }
+        }
+        for (_, endpoint) in endpoints.iter_mut() {
+            if endpoint.next_timeout().is_some_and(|at| at <= *now_ms) {
+                endpoint.on_timeout(*now_ms);
+            }
+        }
+    }
+    panic!("QUIC endpoints never went quiet");
+}
+
+fn connected_peer(events: &[QuicEvent]) -> Option<(ConnectionId, [u8; 32])> {
+    events.iter().find_map(|event| match event {
+        QuicEvent::Connected { connection, peer, .. } => Some((*connection, peer.identity_key.to_bytes())),
+        _ => None,
+    })
+}
+
+/// Integration test: A dialled QUIC connection authenticates both nodes, then carries packets
+/// both ways, split into several datagrams when large
+#[test]
+fn test_quic_dial_handshake_and_packets() {
+    let (alice_identity, bob_identity) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let alice_key = alice_identity.identity_keypair.verifying_key().to_bytes();
+    let bob_key = bob_identity.identity_keypair.verifying_key().to_bytes();
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(alice_identity, QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(bob_identity, QuicConfig { listen: true, ..QuicConfig::default() }, now_ms).unwrap();
+
+    // Alice doesn't listen: nothing comes of dialling her
+    let unanswered = bob.dial(quic_addr(1), now_ms).unwrap();
+    assert!(matches!(bob.send(unanswered, &NetworkPacket::new(MessageType::Store, 1, vec![1]), now_ms), Err(QuicError::NotConnected(_))));
+    assert!(bob.close(unanswered, now_ms));
+
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert_eq!(connected_peer(&events[0]), Some((to_bob, bob_key)));
+    let (to_alice, key) = connected_peer(&events[1]).expect("bob authenticated alice");
+    assert_eq!(key, alice_key);
+    assert_eq!(bob.remote_address(to_alice), Some(quic_addr(1)));
+    assert_eq!(bob.peer(to_alice).map(|p| p.identity_key.to_bytes()), Some(alice_key));
+
+    let large: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 7, large.clone()), now_ms).unwrap();
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Fetch, 8, b"key".to_vec()), now_ms).unwrap();
+    bob.send(to_alice, &NetworkPacket::new(MessageType::StoreRes, 7, vec![0]), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+
+    let received: Vec<(MessageType, u32, Vec<u8>)> = events[1]
+        .iter()
+        .filter_map(|event| match event {
+            QuicEvent::Packet { connection, packet } if *connection == to_alice => {
+                Some((packet.header.message_type, packet.header.request_id, packet.payload.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    // The large Store goes on a substream of its own: the Fetch behind it doesn't wait for it
+    assert_eq!(received, vec![(MessageType::Fetch, 8, b"key".to_vec()), (MessageType::Store, 7, large)]);
+    assert!(matches!(&events[0][..], [QuicEvent::Packet { packet, .. }] if packet.header.request_id == 7));
+    let (sent, received) = (alice.stats(to_bob).unwrap(), bob.stats(to_alice).unwrap());
+    assert_eq!((sent.packets_sent, sent.packets_received, received.packets_received), (2, 1, 2));
+    assert!(sent.bytes_sent > 20_000 && received.bytes_sent == sent.bytes_received);
+    assert!(sent.rtt_ms.is_some_and(|rtt| rtt <= 10), "{sent:?}");
+
+    // Closing is seen by the peer
+    assert!(alice.close(to_bob, now_ms));
+    assert!(!alice.close(to_bob, now_ms));
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert!(events[0].is_empty());
+    assert!(matches!(&events[1][..], [QuicEvent::Closed { connection, error: QuicError::Lost(_) }] if *connection == to_alice));
+    assert!(alice.is_empty() && bob.is_empty());
+}
+
+/// Unit test: A peer whose handshake is stale is refused before any packet is accepted
+#[test]
+fn test_quic_rejects_stale_handshake() {
+    // Alice's clock is an hour behind: her handshake is outside bob's window
+    let alice_ms = NOW_MS - 3_600_000;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), alice_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig { listen: true, ..QuicConfig::default() }, NOW_MS).unwrap();
+
+    let to_bob = alice.dial(quic_addr(2), alice_ms).unwrap();
+    for step in 0..100 {
+        for (to, datagram) in alice.take_outgoing() {
+            assert_eq!(to, quic_addr(2));
+            bob.on_datagram(quic_addr(1), &datagram, NOW_MS + step);
+        }
+        for (_, datagram) in bob.take_outgoing() {
+            alice.on_datagram(quic_addr(2), &datagram, alice_ms + step);
+        }
+    }
+
+    let bob_events = bob.take_events();
+    assert!(matches!(
+        &bob_events[..],
+        [QuicEvent::Closed { error: QuicError::Handshake(HandshakeError::StaleTimestamp { .. }), .. }]
+    ));
+    // To alice, bob's handshake is an hour ahead: she refuses him just the same
+    let alice_events = alice.take_events();
+    assert!(matches!(&alice_events[..], [QuicEvent::Closed { connection, .. }] if *connection == to_bob));
+}
+/// Delivers the datagrams `from` has queued to `to`, once, returning `to`'s events.
+fn quic_flight(from: (SocketAddr, &mut QuicEndpoint), to: &mut QuicEndpoint, now_ms: u64) -> Vec<QuicEvent> {
+    for (_, datagram) in from.1.take_outgoing() {
+        to.on_datagram(from.0, &datagram, now_ms);
+    }
+    to.take_events()
+}
+
+/// Integration test: Redialling a node resumes the TLS session, and the peers authenticate each other again before
+/// any packet goes either way
+#[test]
+fn test_quic_resumption() {
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig { listen: true, ..QuicConfig::default() }, now_ms).unwrap();
+    let first = alice.dial(quic_addr(2), now_ms).unwrap();
+    run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    alice.close(first, now_ms);
+    run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    assert!(matches!(alice.send(to_bob, &NetworkPacket::new(MessageType::Onion, 1, vec![1; 64]), now_ms), Err(QuicError::NotConnected(_))));
+    // Nothing reaches bob with the first flight: alice's handshake waits for TLS to complete
+    assert!(quic_flight((quic_addr(1), &mut alice), &mut bob, now_ms + 1).is_empty());
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert_eq!(connected_peer(&events[0]).map(|(connection, _)| connection), Some(to_bob));
+    let (to_alice, _) = connected_peer(&events[1]).expect("bob authenticated alice");
+    bob.send(to_alice, &NetworkPacket::new(MessageType::Onion, 1, vec![3; 64]), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert!(matches!(&events[0][..], [QuicEvent::Packet { packet, .. }] if packet.payload == vec![3; 64]));
+}
+
+/// Unit test: A QUIC handshake only verifies against the TLS session it was signed for, so one replayed on, or relayed
+/// to, another connection is refused, as is a bare `HandshakePayload`
+#[test]
+fn test_quic_handshake_channel_binding() {
+    let identity = NodeIdentity::generate();
+    let now_secs = NOW_MS / 1000;
+    let hello = quic::bound_handshake(&identity, &[1; 32], now_secs);
+
+    let peer = quic::verify_bound_handshake(&hello, &[1; 32], now_secs, 300).unwrap();
+    assert_eq!(peer.identity_key, identity.identity_keypair.verifying_key());
+    assert!(matches!(quic::verify_bound_handshake(&hello, &[2; 32], now_secs, 300), Err(QuicError::ChannelBinding)));
+
+    // Another key's signature over the binding doesn't vouch for this payload
+    let mut spliced = hello.clone();
+    let impostor = quic::bound_handshake(&NodeIdentity::generate(), &[1; 32], now_secs);
+    spliced[HANDSHAKE_PAYLOAD_SIZE..].copy_from_slice(&impostor[HANDSHAKE_PAYLOAD_SIZE..]);
+    assert!(matches!(quic::verify_bound_handshake(&spliced, &[1; 32], now_secs, 300), Err(QuicError::ChannelBinding)));
+
+    let bare = identity.sign_handshake(now_secs).to_bytes();
+    assert!(matches!(
+        quic::verify_bound_handshake(&bare, &[1; 32], now_secs, 300),
+        Err(QuicError::Handshake(HandshakeError::InvalidSize { .. }))
+    ));
+}
+
+/// Integration test: A large packet goes on a substream of its own, so small packets sent after it arrive first, while
+/// the packets of its own exchange still follow it
+#[test]
+fn test_quic_substreams() {
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig { listen: true, ..QuicConfig::default() }, now_ms).unwrap();
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+
+    let large = NetworkPacket::new(MessageType::FetchRes, 1, vec![7; 400_000]);
+    alice.send(to_bob, &large, now_ms).unwrap();
+    alice.send(to_bob, &NetworkPacket::new(MessageType::FetchRes, 1, b"end".to_vec()), now_ms).unwrap();
+    for request_id in 2..5 {
+        alice.send(to_bob, &NetworkPacket::new(MessageType::Fetch, request_id, vec![0; 32]), now_ms).unwrap();
+    }
+    let mut order = Vec::new();
+    for _ in 0..1000 {
+        now_ms += 1;
+        for event in quic_flight((quic_addr(1), &mut alice), &mut bob, now_ms) {
+            if let QuicEvent::Packet { packet, .. } = event {
+                order.push((packet.header.request_id, packet.payload.len()));
+            }
+        }
+        quic_flight((quic_addr(2), &mut bob), &mut alice, now_ms);
+        if alice.next_timeout().is_some_and(|at| at <= now_ms) {
+            alice.on_timeout(now_ms);
+        }
+    }
+    assert_eq!(order, vec![(2, 32), (3, 32), (4, 32), (1, 400_000), (1, 3)]);
+}
+
+/// Integration test: A node that changes networks keeps its connection: the peer validates the new path before
+/// reporting the move, and a packet merely seen from elsewhere (a spoofed or dead path) moves nothing for good
+#[test]
+fn test_quic_connection_migration() {
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig { listen: true, ..QuicConfig::default() }, now_ms).unwrap();
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    let (to_alice, _) = connected_peer(&events[1]).unwrap();
+
+    // Alice's socket now sends from another address
+    alice.rebind(now_ms);
+    let events = run_quic(&mut [(quic_addr(3), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert!(matches!(&events[1][..], [QuicEvent::Migrated { connection, addr }] if *connection == to_alice && *addr == quic_addr(3)));
+    assert_eq!(bob.remote_address(to_alice), Some(quic_addr(3)));
+    bob.send(to_alice, &NetworkPacket::new(MessageType::Onion, 1, vec![1; 64]), now_ms).unwrap();
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Onion, 1, vec![2; 64]), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(3), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert!(matches!(&events[0][..], [QuicEvent::Packet { packet, .. }] if packet.payload == vec![1; 64]));
+    assert!(matches!(&events[1][..], [QuicEvent::Packet { packet, .. }] if packet.payload == vec![2; 64]));
+
+    // One of alice's packets reaches bob from an address that never answers
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Onion, 2, vec![3; 64]), now_ms).unwrap();
+    for (_, datagram) in alice.take_outgoing() {
+        bob.on_datagram(quic_addr(4), &datagram, now_ms);
+    }
+    assert_eq!(bob.remote_address(to_alice), Some(quic_addr(4)));
+    let mut events = bob.take_events();
+    for _ in 0..20 {
+        if let Some(at) = [alice.next_timeout(), bob.next_timeout()].into_iter().flatten().min() {
+            now_ms = now_ms.max(at);
+            alice.on_timeout(now_ms);
+            bob.on_timeout(now_ms);
+        }
+        events.extend(run_quic(&mut [(quic_addr(3), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms).remove(1));
+    }
+    assert!(events.iter().all(|event| !matches!(event, QuicEvent::Migrated { .. } | QuicEvent::Closed { .. })), "{events:?}");
+    assert_eq!(bob.remote_address(to_alice), Some(quic_addr(3)));
+}
+
+
+/// Carries the bytes `commands` write on `from` to `to` on the other side, a byte at a time
+/// if `trickle`. Returns whether the socket was closed.
+fn pipe_tcp(commands: Vec<TcpCommand>, from: ConnectionId, to: ConnectionId, trickle: bool, mut deliver: impl FnMut(ConnectionId, &[u8])) -> bool {
+    let mut closed = false;
+    for command in commands {
+        match command {
+            TcpCommand::Write { connection, bytes } if connection == from => {
+                if trickle {
+                    bytes.chunks(1).for_each(|byte| deliver(to, byte));
+                } else {
+                    deliver(to, &bytes);
+                }
+            }
+            TcpCommand::Close(connection) if connection == from => closed = true,
+            _ => {}
+        }
+    }
+    closed
+}
+
+/// Integration test: Two TCP transports exchange keys and handshakes over bytes split
+/// arbitrarily, then carry packets; a tampered record closes the connection
+#[test]
+fn test_tcp_handshake_and_packets() {
+    let (alice_identity, bob_identity) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let bob_key = bob_identity.identity_keypair.verifying_key().to_bytes();
+    let mut alice = TcpTransport::new(alice_identity, TcpConfig::default());
+    let mut bob = TcpTransport::new(bob_identity, TcpConfig::default());
+
+    let to_bob = alice.dial(quic_addr(2), NOW_MS);
+    let commands = alice.take_outgoing();
+    assert_eq!(commands[0], TcpCommand::Connect { connection: to_bob, addr: quic_addr(2) });
+    assert!(matches!(alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 1, vec![1])), Err(TcpError::NotConnected(_))));
+    let to_alice = bob.accept(quic_addr(1), NOW_MS);
+
+    let mut commands = (commands, bob.take_outgoing());
+    for _ in 0..3 {
+        pipe_tcp(commands.0, to_bob, to_alice, true, |c, b| bob.on_data(c, b, NOW_MS));
+        pipe_tcp(commands.1, to_alice, to_bob, true, |c, b| alice.on_data(c, b, NOW_MS));
+        commands = (alice.take_outgoing(), bob.take_outgoing());
+    }
+    assert!(matches!(&alice.take_events()[..], [TcpEvent::Connected { connection, peer, .. }]
+        if *connection == to_bob && peer.identity_key.to_bytes() == bob_key));
+    assert!(matches!(&bob.take_events()[..], [TcpEvent::Connected { connection, .. }] if *connection == to_alice));
+    assert_eq!(alice.next_timeout(), None);
+
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 7, vec![7; 3000])).unwrap();
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Fetch, 8, b"key".to_vec())).unwrap();
+    pipe_tcp(alice.take_outgoing(), to_bob, to_alice, false, |c, b| bob.on_data(c, b, NOW_MS));
+    let received: Vec<(u32, usize)> = bob
+        .take_events()
+        .into_iter()
+        .filter_map(|event| match event {
+            TcpEvent::Packet { packet, .. } => Some((packet.header.request_id, packet.payload.len())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(received, vec![(7, 3000), (8, 3)]);
+
+    bob.send(to_alice, &NetworkPacket::new(MessageType::StoreRes, 7, vec![0])).unwrap();
+    let mut tampered = match &bob.take_outgoing()[..] {
+        [TcpCommand::Write { bytes, .. }] => bytes.clone(),
+        other => panic!("unexpected commands {other:?}"),
+    };
+    *tampered.last_mut().unwrap() ^= 1;
+    alice.on_data(to_bob, &tampered, NOW_MS);
+    assert!(matches!(&alice.take_events()[..], [TcpEvent::Closed { error: TcpError::Crypto(_), .. }]));
+    assert_eq!(alice.take_outgoing(), vec![TcpCommand::Close(to_bob)]);
+    assert!(alice.is_empty());
+}
+
+/// Unit test: A socket that never completes the handshake is closed at the deadline
+#[test]
+fn test_tcp_handshake_timeout() {
+    let config = TcpConfig::default();
+    let mut bob = TcpTransport::new(NodeIdentity::generate(), config.clone());
+    let silent = bob.accept(quic_addr(1), NOW_MS);
+    bob.on_data(silent, &[0; 10], NOW_MS);
+    bob.take_outgoing();
+
+    assert_eq!(bob.next_timeout(), Some(NOW_MS + config.handshake_timeout_ms));
+    bob.on_timeout(NOW_MS + config.handshake_timeout_ms - 1);
+    assert!(bob.take_events().is_empty());
+    bob.on_timeout(NOW_MS + config.handshake_timeout_ms);
+    assert!(matches!(&bob.take_events()[..], [TcpEvent::Closed { error: TcpError::HandshakeTimeout, .. }]));
+    assert_eq!(bob.take_outgoing(), vec![TcpCommand::Close(silent)]);
+}
+
+/// Integration test: When QUIC datagrams don't get through, the dial moves to TCP after
+/// `QUIC_FALLBACK_MS` under the same connection, and later dials go to TCP straight away
+#[test]
+fn test_fallback_to_tcp_when_udp_is_blocked() {
+    let bob_identity = NodeIdentity::generate();
+    let bob_key = bob_identity.identity_keypair.verifying_key().to_bytes();
+    let mut now_ms = NOW_MS;
+    let fallback = |identity: NodeIdentity, listen: bool| {
+        let quic = QuicEndpoint::new(NodeIdentity::from_secret_bytes(&identity.to_secret_bytes()), QuicConfig { listen, ..QuicConfig::default() }, NOW_MS).unwrap();
+        FallbackTransport::new(quic, TcpTransport::new(identity, TcpConfig::default()))
+    };
+    let mut alice = fallback(NodeIdentity::generate(), false);
+    let mut bob = fallback(bob_identity, true);
+
+    let to_bob = alice.dial(quic_addr(2), now_ms);
+    assert!(!alice.is_tcp(to_bob));
+    // The firewall drops every datagram
+    while let Some(at) = alice.next_timeout().filter(|at| *at <= NOW_MS + QUIC_FALLBACK_MS) {
+        now_ms = at;
+        alice.take_datagrams();
+        alice.on_timeout(now_ms);
+    }
+    assert!(alice.is_tcp(to_bob));
+    assert!(alice.take_events().is_empty());
+
+    let commands = alice.take_tcp_commands();
+    let Some(&TcpCommand::Connect { connection: socket, addr }) = commands.first() else {
+        panic!("expected a TCP connect, got {commands:?}");
+    };
+    assert_eq!(addr, quic_addr(2));
+    let accepted = bob.on_tcp_accepted(quic_addr(1), now_ms);
+    let mut commands = (commands, bob.take_tcp_commands());
+    for _ in 0..3 {
+        pipe_tcp(commands.0, socket, accepted, false, |c, b| bob.on_tcp_data(c, b, now_ms));
+        pipe_tcp(commands.1, accepted, socket, false, |c, b| alice.on_tcp_data(c, b, now_ms));
+        commands = (alice.take_tcp_commands(), bob.take_tcp_commands());
+    }
+    assert!(matches!(&alice.take_events()[..], [FallbackEvent::Connected { connection, peer, .. }]
+        if *connection == to_bob && peer.identity_key.to_bytes() == bob_key));
+    let to_alice = match &bob.take_events()[..] {
+        [FallbackEvent::Connected { connection, .. }] => *connection,
+        other => panic!("unexpected events {other:?}"),
+    };
+    assert_eq!(alice.peer(to_bob).map(|p| p.identity_key.to_bytes()), Some(bob_key));
+
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 9, vec![9]), now_ms).unwrap();
+    pipe_tcp(alice.take_tcp_commands(), socket, accepted, false, |c, b| bob.on_tcp_data(c, b, now_ms));
+    assert!(matches!(&bob.take_events()[..], [FallbackEvent::Packet { connection, packet }]
+        if *connection == to_alice && packet.header.request_id == 9));
+
+    // The peer's socket going away is reported on our connection
+    bob.on_tcp_closed(accepted, now_ms);
+    assert!(matches!(&bob.take_events()[..], [FallbackEvent::Closed { connection, error: FallbackError::Tcp(TcpError::Disconnected) }]
+        if *connection == to_alice));
+
+    // Bob's address is known to block UDP now
+    alice.take_datagrams();
+    let again = alice.dial(quic_addr(2), now_ms);
+    assert!(alice.is_tcp(again));
+    assert!(alice.take_datagrams().is_empty());
+    assert!(matches!(&alice.take_tcp_commands()[..], [TcpCommand::Connect { addr, .. }, ..] if *addr == quic_addr(2)));
+    assert!(matches!(alice.send(ConnectionId(99), &NetworkPacket::new(MessageType::Store, 1, vec![]), now_ms), Err(FallbackError::UnknownConnection(_))));
+}
+
+/// Checks what any transport does with connections it doesn't have.
+fn assert_ignores_unknown<T: Transport>(transport: &mut T) {
+    let unknown = ConnectionId(42);
+    assert!(transport.send(unknown, &NetworkPacket::new(MessageType::Store, 1, vec![1]), NOW_MS).is_err());
+    assert!(!transport.close(unknown, NOW_MS));
+    assert!(transport.peer(unknown).is_none() && transport.remote_address(unknown).is_none());
+}
+
+/// Unit test: Through the `Transport` trait, listening can be switched on and off: peers
+/// are refused while it is off
+#[test]
+fn test_transport_listen_toggle() {
+    let mut tcp = TcpTransport::new(NodeIdentity::generate(), TcpConfig::default());
+    assert_ignores_unknown(&mut tcp);
+    tcp.listen(false);
+    let refused = tcp.handle(TcpInput::Accepted(quic_addr(1)), NOW_MS).expect("accepted sockets get a connection");
+    assert_eq!(Transport::take_outgoing(&mut tcp), vec![TcpCommand::Close(refused)]);
+    tcp.listen(true);
+    let accepted = tcp.handle(TcpInput::Accepted(quic_addr(1)), NOW_MS).unwrap();
+    assert!(matches!(&Transport::take_outgoing(&mut tcp)[..], [TcpCommand::Write { connection, .. }] if *connection == accepted));
+    assert_eq!(tcp.handle(TcpInput::Closed(accepted), NOW_MS), None);
+    assert!(matches!(&Transport::take_events(&mut tcp)[..], [TcpEvent::Closed { error: TcpError::Disconnected, .. }]));
+
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    assert_ignores_unknown(&mut bob);
+    bob.listen(true);
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert_eq!(connected_peer(&events[0]).map(|(connection, _)| connection), Some(to_bob));
+
+    // Bob drops new dials unanswered; the connection he has stays up
+    bob.listen(false);
+    let unanswered = alice.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert!(events.iter().all(Vec::is_empty));
+    assert!(alice.peer(unanswered).is_none());
+    assert_eq!(bob.len(), 1);
+}
+
+/// Unit test: CIDR blocks parse and match; the accept filter applies its lists before its
+/// limits, counts IPv6 sources by block, and forgets sources once they are quiet
+#[test]
+fn test_accept_filter_rules() {
+    let net: IpNetwork = "203.0.113.0/24".parse().unwrap();
+    assert!(net.contains("203.0.113.200".parse().unwrap()) && !net.contains("203.0.114.1".parse().unwrap()));
+    assert!(net.contains("::ffff:203.0.113.9".parse().unwrap()));
+    assert_eq!("2001:db8::1".parse::<IpNetwork>().unwrap().to_string(), "2001:db8::1/128");
+    for invalid in ["10.0.0.0/33", "10.0.0/8", "fe80::/129", "10.0.0.0/x"] {
+        assert_eq!(invalid.parse::<IpNetwork>(), Err(FilterError::InvalidNetwork(invalid.to_string())));
+    }
+
+    let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+    let config = AcceptConfig {
+        allow: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+        deny: vec!["10.6.6.0/24".parse().unwrap()],
+        max_per_source: Some(2),
+        attempts_per_sec: 1.0,
+        attempt_burst: 3,
+        ..AcceptConfig::default()
+    };
+    let mut filter = AcceptFilter::new(config);
+    assert_eq!(filter.admit(addr("10.6.6.6:1"), NOW_MS), Err(FilterError::Denied("10.6.6.6".parse().unwrap())));
+    assert_eq!(filter.admit(addr("192.0.2.1:1"), NOW_MS), Err(FilterError::NotAllowed("192.0.2.1".parse().unwrap())));
+
+    // Two open at once; the third attempt uses up the burst
+    let source = addr("10.0.0.1:1000");
+    assert!(filter.admit(source, NOW_MS).is_ok() && filter.admit(addr("10.0.0.1:1001"), NOW_MS).is_ok());
+    assert_eq!(filter.admit(source, NOW_MS), Err(FilterError::TooManyConnections(source.ip(), 2)));
+    filter.release(source);
+    assert_eq!(filter.admit(source, NOW_MS), Err(FilterError::RateLimited(source.ip())));
+    assert!(filter.admit(source, NOW_MS + 1000).is_ok());
+    assert_eq!(filter.open_from(source.ip()), 2);
+
+    // Addresses of one /64 are one source
+    assert!(filter.admit(addr("[2001:db8::1]:1"), NOW_MS).is_ok() && filter.admit(addr("[2001:db8::2]:1"), NOW_MS).is_ok());
+    assert!(matches!(filter.admit(addr("[2001:db8::3]:1"), NOW_MS), Err(FilterError::TooManyConnections(_, 2))));
+    assert!(filter.admit(addr("[2001:db8:0:1::1]:1"), NOW_MS).is_ok());
+
+    filter.release(source);
+    filter.release(source);
+    filter.prune(NOW_MS + 2000);
+    assert_eq!(filter.open_from("2001:db8::ffff".parse().unwrap()), 2);
+    filter.prune(NOW_MS + 10_000);
+    assert_eq!(filter.open_from(source.ip()), 0);
+}
+
+/// Integration test: Connections from a denied source are refused before any handshake:
+/// TCP sockets are closed without our key being written, QUIC dials go unanswered
+#[test]
+fn test_transports_refuse_filtered_sources() {
+    let accept = AcceptConfig { deny: vec!["10.0.0.1/32".parse().unwrap()], ..AcceptConfig::default() };
+    let mut tcp = TcpTransport::new(NodeIdentity::generate(), TcpConfig { accept: accept.clone(), ..TcpConfig::default() });
+    let refused = tcp.accept(quic_addr(1), NOW_MS);
+    assert_eq!(tcp.take_outgoing(), vec![TcpCommand::Close(refused)]);
+    assert!(tcp.is_empty());
+    let accepted = tcp.accept(quic_addr(3), NOW_MS);
+    assert!(matches!(&tcp.take_outgoing()[..], [TcpCommand::Write { connection, .. }] if *connection == accepted));
+
+    let mut now_ms = NOW_MS;
+    let mut alice = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut carol = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig::default(), now_ms).unwrap();
+    let mut bob = QuicEndpoint::new(NodeIdentity::generate(), QuicConfig { listen: true, accept, ..QuicConfig::default() }, now_ms).unwrap();
+    alice.dial(quic_addr(2), now_ms).unwrap();
+    carol.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob), (quic_addr(3), &mut carol)], &mut now_ms);
+    assert!(events[0].is_empty());
+    assert!(connected_peer(&events[2]).is_some());
+    assert_eq!(bob.len(), 1);
+
+    // Lifting the rule lets alice in
+    bob.set_accept_config(AcceptConfig::default());
+    let to_bob = alice.dial(quic_addr(2), now_ms).unwrap();
+    let events = run_quic(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], &mut now_ms);
+    assert_eq!(connected_peer(&events[0]).map(|(connection, _)| connection), Some(to_bob));
+}
+
+type TcpManager = ConnectionManager<TcpTransport>;
+
+fn tcp_manager(identity: &NodeIdentity, config: ManagerConfig) -> TcpManager {
+    let identity = NodeIdentity::from_secret_bytes(&identity.to_secret_bytes());
+    ConnectionManager::new(TcpTransport::new(identity, TcpConfig::default()), config)
+}
+
+fn node_id(identity: &NodeIdentity) -> NodeId {
+    NodeId::from_identity_key(&identity.identity_keypair.verifying_key())
+}
+
+/// The sockets between TCP nodes: a `Connect` is accepted by the node at that address, and
+/// what one end writes or closes reaches the other.
+#[derive(Default)]
+struct TcpWire {
+    /// Each socket end, by node address and connection, and the end across
+    links: HashMap<(SocketAddr, ConnectionId), (SocketAddr, ConnectionId)>,
+}
+
+impl TcpWire {
+    /// Runs the wire until no node has anything left to do. Returns the events of each node.
+    fn run(&mut self, nodes: &mut [(SocketAddr, &mut TcpManager)], now_ms: u64) -> Vec<Vec<ManagerEvent<TcpError>>> {
+        let mut events: Vec<Vec<ManagerEvent<TcpError>>> = nodes.iter().map(|_| Vec::new()).collect();
+        for _ in 0..100 {
+            let mut commands = Vec::new();
+            for (i, (addr, node)) in nodes.iter_mut().enumerate() {
+                commands.extend(node.take_outgoing().into_iter().map(|command| (*addr, command)));
+                events[i].extend(node.take_events());
+            }
+            if commands.is_empty() {
+                return events;
+            }
+            for (from, command) in commands {
+                let (to, input) = match command {
+                    TcpCommand::Connect { connection, addr } => {
+                        let Some((_, node)) = nodes.iter_mut().find(|(at, _)| *at == addr) else {
+                            continue;
+                        };
+                        let accepted = node.handle(TcpInput::Accepted(from), now_ms).unwrap();
+                        self.links.insert((from, connection), (addr, accepted));
+                        self.links.insert((addr, accepted), (from, connection));
+                        continue;
+                    }
+                    TcpCommand::Write { connection, bytes } => match self.links.get(&(from, connection)) {
+                        Some(&(to, other)) => (to, TcpInput::Data { connection: other, bytes }),
+                        None => continue,
+                    },
+                    TcpCommand::Close(connection) => match self.links.remove(&(from, connection)) {
+                        Some((to, other)) => {
+                            self.links.remove(&(to, other));
+                            (to, TcpInput::Closed(other))
+                        }
+                        None => continue,
+                    },
+                };
+                if let Some((_, node)) = nodes.iter_mut().find(|(at, _)| *at == to) {
+                    node.handle(input, now_ms);
+                }
+            }
+        }
+        panic!("TCP nodes never went quiet");
+    }
+}
+
+/// Integration test: Dials to one node are made once and the connection is reused; a dial
+/// that reaches the wrong node is closed
+#[test]
+fn test_manager_dedups_dials_and_pools_connections() {
+    let (alice_identity, bob_identity, carol_identity) = (NodeIdentity::generate(), NodeIdentity::generate(), NodeIdentity::generate());
+    let (alice_id, bob_id) = (node_id(&alice_identity), node_id(&bob_identity));
+    let mut alice = tcp_manager(&alice_identity, ManagerConfig::default());
+    let mut bob = tcp_manager(&bob_identity, ManagerConfig::default());
+    let mut carol = tcp_manager(&carol_identity, ManagerConfig::default());
+    let mut wire = TcpWire::default();
+
+    let to_bob = alice.connect(bob_id, quic_addr(2), NOW_MS).unwrap();
+    assert_eq!(alice.connect(bob_id, quic_addr(2), NOW_MS).unwrap(), to_bob);
+    assert_eq!(alice.len(), 1);
+    assert_eq!(alice.connection(&bob_id), None);
+
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], NOW_MS);
+    assert!(matches!(&events[0][..], [ManagerEvent::Connected { connection, .. }] if *connection == to_bob));
+    assert_eq!(alice.connection(&bob_id), Some(to_bob));
+    assert_eq!(alice.node(to_bob), Some(bob_id));
+    assert_eq!(alice.connect(bob_id, quic_addr(2), NOW_MS).unwrap(), to_bob);
+    let to_alice = bob.connection(&alice_id).expect("bob pooled alice's connection");
+
+    bob.send(to_alice, &NetworkPacket::new(MessageType::Store, 3, vec![3]), NOW_MS).unwrap();
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], NOW_MS);
+    assert!(matches!(&events[0][..], [ManagerEvent::Packet { connection, .. }] if *connection == to_bob));
+
+    // Carol is at the address alice thinks belongs to someone else
+    let expected = NodeId::hash_of(b"someone else");
+    let to_carol = alice.connect(expected, quic_addr(3), NOW_MS).unwrap();
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(3), &mut carol)], NOW_MS);
+    assert!(matches!(&events[0][..], [ManagerEvent::Closed { connection, error: ManagerError::WrongPeer { expected: e, got } }]
+        if *connection == to_carol && *e == expected && *got == node_id(&carol_identity)));
+    assert_eq!(alice.len(), 1);
+    assert!(carol.is_empty());
+}
+
+/// Integration test: Connections beyond the global or per-peer limit are refused, and
+/// connections left idle are evicted
+#[test]
+fn test_manager_limits_and_idle_eviction() {
+    let (alice_identity, bob_identity) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let bob_id = node_id(&bob_identity);
+    let config = ManagerConfig { max_connections: 1, max_per_peer: 1, idle_timeout_ms: 60_000, ..ManagerConfig::default() };
+    let mut alice = tcp_manager(&alice_identity, config);
+    let mut bob = tcp_manager(&bob_identity, config);
+    // A second node with alice's identity, beyond bob's one connection per peer
+    let mut alice_again = tcp_manager(&alice_identity, ManagerConfig::default());
+    let mut wire = TcpWire::default();
+
+    let to_bob = alice.connect(bob_id, quic_addr(2), NOW_MS).unwrap();
+    assert!(matches!(alice.connect(NodeId::hash_of(b"carol"), quic_addr(3), NOW_MS), Err(ManagerError::TooManyConnections(1))));
+    wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], NOW_MS);
+    assert_eq!(bob.len(), 1);
+
+    alice_again.connect(bob_id, quic_addr(2), NOW_MS).unwrap();
+    let events = wire.run(&mut [(quic_addr(2), &mut bob), (quic_addr(4), &mut alice_again)], NOW_MS);
+    assert!(events[0].is_empty());
+    assert_eq!(bob.len(), 1);
+    assert!(matches!(events[1].last(), Some(ManagerEvent::Closed { error: ManagerError::Transport(TcpError::Disconnected), .. })));
+
+    // Traffic keeps a connection alive; a minute without any ends it
+    let later = NOW_MS + 30_000;
+    alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 1, vec![1]), later).unwrap();
+    wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], later);
+    assert_eq!(bob.next_timeout(), Some(later + 60_000));
+    bob.on_timeout(NOW_MS + 60_000);
+    assert!(bob.take_events().is_empty());
+
+    bob.on_timeout(later + 60_000);
+    assert!(matches!(&bob.take_events()[..], [ManagerEvent::Closed { error: ManagerError::Idle, .. }]));
+    assert!(bob.is_empty());
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], later + 60_000);
+    assert!(matches!(&events[0][..], [ManagerEvent::Closed { connection, error: ManagerError::Transport(TcpError::Disconnected) }]
+        if *connection == to_bob));
+}
+
+/// Integration test: The manager reports each connection's counters, averages them into a
+/// throughput that follows the traffic, and keeps the best a node sustained
+#[test]
+fn test_manager_transport_stats() {
+    let (alice_identity, bob_identity) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let bob_id = node_id(&bob_identity);
+    let mut alice = tcp_manager(&alice_identity, ManagerConfig::default());
+    let mut bob = tcp_manager(&bob_identity, ManagerConfig::default());
+    let mut wire = TcpWire::default();
+    let to_bob = alice.connect(bob_id, quic_addr(2), NOW_MS).unwrap();
+    wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], NOW_MS);
+    let to_alice = bob.connection(&node_id(&alice_identity)).unwrap();
+
+    // 10 kB a second for 20 seconds
+    for second in 1..=20 {
+        let now_ms = NOW_MS + second * 1000;
+        alice.send(to_bob, &NetworkPacket::new(MessageType::Store, 1, vec![0; 10_000]), now_ms).unwrap();
+        wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], now_ms);
+    }
+    let (sent, received) = (alice.stats(to_bob).unwrap(), bob.stats(to_alice).unwrap());
+    assert_eq!((sent.packets_sent, received.packets_received), (20, 20));
+    assert_eq!(sent.bytes_sent, received.bytes_received);
+    assert_eq!((sent.rtt_ms, sent.lost_packets), (None, 0));
+    let throughput = alice.throughput(to_bob).unwrap();
+    assert!((8_000..=10_500).contains(&throughput.sent) && throughput.received < 1_000, "{throughput:?}");
+    assert!(bob.throughput(to_alice).unwrap().received >= 8_000);
+
+    // Quiet again: the throughput falls, what bob carried stays on record
+    let later = NOW_MS + 60_000;
+    alice.on_timeout(later);
+    assert!(alice.throughput(to_bob).unwrap().sent < 500);
+    assert!(alice.measured_bandwidth(&bob_id).is_some_and(|peak| peak >= throughput.sent));
+    assert_eq!(alice.measured_bandwidth(&NodeId::hash_of(b"carol")), None);
+}
+
+/// Unit test: STUN Binding messages round-trip, and the RFC 5769 sample response decodes
+/// with its other attributes skipped
+#[test]
+fn test_stun_binding_messages() {
+    let transaction = [7u8; 12];
+    for mapped in ["203.0.113.9:40000".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap()] {
+        let response = StunMessage::BindingSuccess { transaction, mapped };
+        let bytes = response.to_bytes();
+        assert!(nat::is_stun(&bytes));
+        assert_eq!(StunMessage::from_bytes(&bytes).unwrap(), response);
+    }
+    let request = StunMessage::BindingRequest { transaction }.to_bytes();
+    assert_eq!(StunMessage::from_bytes(&request).unwrap(), StunMessage::BindingRequest { transaction });
+
+    let sample: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20,
+        0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+        0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53,
+        0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+    assert!(matches!(StunMessage::from_bytes(&sample), Ok(StunMessage::BindingSuccess { mapped, .. }) if mapped == "192.0.2.1:32853".parse().unwrap()));
+
+    // QUIC long and short headers are never taken for STUN
+    assert!(!nat::is_stun(&[0xc0; 40]) && !nat::is_stun(&[0x40; 40]));
+    assert!(matches!(StunMessage::from_bytes(&sample[..60]), Err(NatError::Malformed(_))));
+}
+
+/// Answers the STUN requests `nat` sent, each server seeing it at `mapped(server)`.
+fn answer_stun(nat: &mut NatTraversal, mapped: impl Fn(SocketAddr) -> SocketAddr, now_ms: u64) {
+    for (server, datagram) in nat.take_outgoing() {
+        let Ok(StunMessage::BindingRequest { transaction }) = StunMessage::from_bytes(&datagram) else {
+            panic!("expected a binding request");
+        };
+        let response = StunMessage::BindingSuccess { transaction, mapped: mapped(server) }.to_bytes();
+        assert!(nat.on_datagram(server, &response, now_ms));
+    }
+}
+
+/// Integration test: The reflexive address is learned from STUN servers, retried when they
+/// are slow, and a NAT mapping per destination is recognised as unpunchable
+#[test]
+fn test_nat_address_discovery() {
+    let local = quic_addr(1);
+    let reflexive: SocketAddr = "198.51.100.7:61000".parse().unwrap();
+    let servers = vec!["192.0.2.10:3478".parse().unwrap(), "192.0.2.20:3478".parse().unwrap()];
+    let config = NatConfig { stun_servers: servers.clone(), ..NatConfig::default() };
+    let mut nat = NatTraversal::new(config.clone(), local);
+    assert!(matches!(nat.punch(NodeId::hash_of(b"peer"), NOW_MS), Err(NatError::NoReflexiveAddress)));
+
+    // The first requests are lost: they are sent again
+    nat.discover(NOW_MS);
+    assert_eq!(nat.take_outgoing().len(), 2);
+    assert_eq!(nat.next_timeout(), Some(NOW_MS + config.retransmit_ms));
+    nat.on_timeout(NOW_MS + config.retransmit_ms);
+    answer_stun(&mut nat, |_| reflexive, NOW_MS + 600);
+    assert_eq!(nat.take_events(), vec![NatEvent::AddressChanged { addr: reflexive, behavior: NatBehavior::EndpointIndependent }]);
+    assert_eq!(nat.reachability(), Reachability::BehindNat);
+
+    let bound = TransportAddress { addr: local, kind: AddressKind::Public };
+    let info = nat.contact_info(NodeId::hash_of(b"us"), vec![bound]);
+    assert_eq!(info.addresses, vec![TransportAddress { addr: reflexive, kind: AddressKind::Observed }, bound]);
+    assert_eq!(info.reachability, Reachability::BehindNat);
+
+    // Refreshed later, the NAT now maps each server to another port
+    let refresh_ms = nat.next_timeout().unwrap();
+    assert_eq!(refresh_ms, NOW_MS + 600 + config.refresh_ms);
+    nat.on_timeout(refresh_ms);
+    answer_stun(&mut nat, |server| SocketAddr::new(reflexive.ip(), 60_000 + server.port() + u16::from(server.ip() == servers[1].ip())), refresh_ms);
+    assert!(matches!(&nat.take_events()[..], [NatEvent::AddressChanged { behavior: NatBehavior::EndpointDependent, .. }]));
+    assert!(matches!(nat.punch(NodeId::hash_of(b"peer"), refresh_ms), Err(NatError::SymmetricNat)));
+
+    // A node bound on a public address is reachable directly
+    let mut public = NatTraversal::new(config, local);
+    public.discover(NOW_MS);
+    answer_stun(&mut public, |_| local, NOW_MS);
+    assert_eq!(public.behavior(), NatBehavior::Open);
+    assert_eq!(public.transport_address(), Some(TransportAddress { addr: local, kind: AddressKind::Public }));
+}
+
+/// Integration test: Two NATed nodes punch a hole through each other's NAT, coordinated by
+/// a relay, despite their NATs dropping what arrives before they sent anything out
+#[test]
+fn test_nat_hole_punch_through_relay() {
+    let (alice_id, bob_id) = (NodeId::hash_of(b"alice"), NodeId::hash_of(b"bob"));
+    let (alice_public, bob_public): (SocketAddr, SocketAddr) = ("198.51.100.1:50001".parse().unwrap(), "203.0.113.2:50002".parse().unwrap());
+    let server: SocketAddr = "192.0.2.10:3478".parse().unwrap();
+    let config = NatConfig { stun_servers: vec![server], ..NatConfig::default() };
+    let mut alice = NatTraversal::new(config.clone(), quic_addr(1));
+    let mut bob = NatTraversal::new(config.clone(), quic_addr(2));
+    alice.discover(NOW_MS);
+    answer_stun(&mut alice, |_| alice_public, NOW_MS);
+    bob.discover(NOW_MS);
+    answer_stun(&mut bob, |_| bob_public, NOW_MS);
+    alice.take_events();
+    bob.take_events();
+
+    let request = alice.punch(bob_id, NOW_MS).unwrap();
+    let request = PunchMessage::from_packet(&request.to_packet(1)).unwrap();
+    let (to, offer) = nat::relay_punch(alice_id, request).unwrap();
+    assert_eq!((to, offer), (bob_id, PunchMessage::Offer { peer: alice_id, addr: alice_public }));
+    let answer = bob.on_punch(offer, NOW_MS).unwrap().expect("bob answers the offer");
+    let (to, go) = nat::relay_punch(bob_id, answer).unwrap();
+    assert_eq!((to, go), (alice_id, PunchMessage::Go { peer: bob_id, addr: bob_public }));
+    assert_eq!(nat::relay_punch(alice_id, go), None);
+
+    // Each NAT lets in only what comes from where its node has sent to
+    let mut alice_sent_to = std::collections::HashSet::new();
+    let mut bob_sent_to = std::collections::HashSet::new();
+    let mut now_ms = NOW_MS + 50;
+    assert_eq!(alice.on_punch(go, now_ms).unwrap(), None);
+    let mut events = (Vec::new(), Vec::new());
+    for _ in 0..20 {
+        for (to, datagram) in alice.take_outgoing() {
+            alice_sent_to.insert(to);
+            if to == bob_public && bob_sent_to.contains(&alice_public) {
+                bob.on_datagram(alice_public, &datagram, now_ms);
+            }
+        }
+        for (to, datagram) in bob.take_outgoing() {
+            bob_sent_to.insert(to);
+            if to == alice_public && alice_sent_to.contains(&bob_public) {
+                alice.on_datagram(bob_public, &datagram, now_ms);
+            }
+        }
+        events.0.extend(alice.take_events());
+        events.1.extend(bob.take_events());
+        now_ms += config.punch_interval_ms;
+        alice.on_timeout(now_ms);
+        bob.on_timeout(now_ms);
+    }
+    assert_eq!(events.0, vec![NatEvent::Punched { peer: bob_id, addr: bob_public, initiator: true }]);
+    assert_eq!(events.1, vec![NatEvent::Punched { peer: alice_id, addr: alice_public, initiator: false }]);
+    // Nothing is left to probe: only the STUN refresh is due
+    assert_eq!(alice.next_timeout(), Some(NOW_MS + config.refresh_ms));
+
+    // A punch whose relay never answers fails once the setup time is up
+    let carol_id = NodeId::hash_of(b"carol");
+    alice.punch(carol_id, now_ms).unwrap();
+    alice.on_timeout(now_ms + config.punch_setup_ms);
+    assert_eq!(alice.take_events(), vec![NatEvent::PunchFailed { peer: carol_id }]);
+}
+
+const GATEWAY: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 1)), GATEWAY_PORT);
+
+fn portmap_config(gateway: bool) -> PortMapConfig {
+    PortMapConfig {
+        gateway: gateway.then_some(GATEWAY.ip()),
+        local: "192.168.1.20:4433".parse().unwrap(),
+        ..PortMapConfig::default()
+    }
+}
+
+/// The datagrams among `commands`
+fn datagrams(commands: Vec<PortMapCommand>) -> Vec<(SocketAddr, Vec<u8>)> {
+    commands
+        .into_iter()
+        .filter_map(|command| match command {
+            PortMapCommand::Datagram { to, bytes } => Some((to, bytes)),
+            PortMapCommand::Http { .. } => None,
+        })
+        .collect()
+}
+
+/// The one HTTP request among `commands`
+fn http_request(commands: Vec<PortMapCommand>) -> (u32, SocketAddr, String) {
+    match &commands[..] {
+        [PortMapCommand::Http { request, addr, bytes }] => (*request, *addr, String::from_utf8(bytes.clone()).unwrap()),
+        other => panic!("expected one HTTP request, got {other:?}"),
+    }
+}
+
+/// Unit test: PCP maps the port and renews it with the same nonce; a gateway that only
+/// speaks NAT-PMP is asked again in NAT-PMP
+#[test]
+fn test_portmap_pcp_and_natpmp() {
+    let mut mapper = PortMapper::new(portmap_config(true));
+    mapper.start(NOW_MS);
+    let [(to, request)] = &datagrams(mapper.take_outgoing())[..] else {
+        panic!("expected one PCP request");
+    };
+    assert_eq!((*to, request.len(), request[0], request[1]), (GATEWAY, 60, 2, 1));
+    let nonce = request[24..36].to_vec();
+
+    let pcp_response = |lifetime: u32| {
+        let mut response = vec![2, 0x81, 0, 0];
+        response.extend_from_slice(&lifetime.to_be_bytes());
+        response.extend_from_slice(&[0; 16]);
+        response.extend_from_slice(&nonce);
+        response.extend_from_slice(&[17, 0, 0, 0]);
+        response.extend_from_slice(&4433u16.to_be_bytes());
+        response.extend_from_slice(&40_000u16.to_be_bytes());
+        response.extend_from_slice(&std::net::Ipv4Addr::new(198, 51, 100, 7).to_ipv6_mapped().octets());
+        response
+    };
+    assert!(mapper.on_datagram(GATEWAY, &pcp_response(7200), NOW_MS));
+    let external: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+    assert!(matches!(mapper.take_events()[..], [PortMapEvent::Mapped(m)] if m.method == PortMapMethod::Pcp && m.external == external));
+    assert_eq!(mapper.mapping().unwrap().expires_at_ms, NOW_MS + 7_200_000);
+
+    // Renewed halfway through the lease granted, under the same nonce
+    assert_eq!(mapper.next_timeout(), Some(NOW_MS + 3_600_000));
+    mapper.on_timeout(NOW_MS + 3_600_000);
+    let [(_, renewal)] = &datagrams(mapper.take_outgoing())[..] else {
+        panic!("expected a PCP renewal");
+    };
+    assert_eq!(renewal[24..36], nonce[..]);
+    mapper.on_datagram(GATEWAY, &pcp_response(3600), NOW_MS + 3_600_000);
+    assert!(matches!(mapper.take_events()[..], [PortMapEvent::Mapped(_)]));
+
+    // Removed at shutdown with a zero lifetime
+    mapper.shutdown();
+    let [(_, removal)] = &datagrams(mapper.take_outgoing())[..] else {
+        panic!("expected a PCP removal");
+    };
+    assert_eq!(removal[4..8], [0; 4]);
+    assert_eq!(mapper.take_events(), vec![PortMapEvent::Removed]);
+    assert!(mapper.mapping().is_none());
+
+    let mut mapper = PortMapper::new(portmap_config(true));
+    mapper.start(NOW_MS);
+    mapper.take_outgoing();
+    assert!(mapper.on_datagram(GATEWAY, &[0, 0x81, 0, 1, 0, 0, 0, 9], NOW_MS));
+    let requests = datagrams(mapper.take_outgoing());
+    assert_eq!(requests.iter().map(|(_, r)| r[..2].to_vec()).collect::<Vec<_>>(), vec![vec![0, 0], vec![0, 1]]);
+    mapper.on_datagram(GATEWAY, &[0, 128, 0, 0, 0, 0, 0, 9, 203, 0, 113, 5], NOW_MS);
+    let mut response = vec![0, 129, 0, 0, 0, 0, 0, 9];
+    response.extend_from_slice(&4433u16.to_be_bytes());
+    response.extend_from_slice(&4433u16.to_be_bytes());
+    response.extend_from_slice(&3600u32.to_be_bytes());
+    mapper.on_datagram(GATEWAY, &response, NOW_MS);
+    let external: SocketAddr = "203.0.113.5:4433".parse().unwrap();
+    assert!(matches!(mapper.take_events()[..], [PortMapEvent::Mapped(m)] if m.method == PortMapMethod::NatPmp && m.external == external));
+}
+
+/// Integration test: UPnP finds the gateway by SSDP and maps the port over SOAP; without
+/// any method answering, the port is reported unavailable
+#[test]
+fn test_portmap_upnp_and_unavailable() {
+    let mut mapper = PortMapper::new(portmap_config(false));
+    mapper.start(NOW_MS);
+    assert_eq!(datagrams(mapper.take_outgoing())[0].0, SSDP_ADDR);
+    assert!(!mapper.on_datagram(quic_addr(9), b"not ssdp", NOW_MS));
+
+    let ssdp = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+    assert!(mapper.on_datagram("192.168.1.1:1900".parse().unwrap(), ssdp, NOW_MS));
+    let (request, addr, get) = http_request(mapper.take_outgoing());
+    assert_eq!(addr, "192.168.1.1:5000".parse().unwrap());
+    assert!(get.starts_with("GET /rootDesc.xml HTTP/1.0"));
+
+    let description = "HTTP/1.1 200 OK\r\n\r\n<root><device><serviceList><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType><controlURL>/ctl/CmnIfCfg</controlURL></service><service>\
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL>\
+        </service></serviceList></device></root>";
+    mapper.on_http_response(request, description.as_bytes(), NOW_MS);
+    let (request, _, add) = http_request(mapper.take_outgoing());
+    assert!(add.starts_with("POST /ctl/IPConn HTTP/1.0"));
+    assert!(add.contains("SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\""));
+    assert!(add.contains("<NewInternalClient>192.168.1.20</NewInternalClient>") && add.contains("<NewLeaseDuration>3600</NewLeaseDuration>"));
+
+    mapper.on_http_response(request, b"HTTP/1.1 200 OK\r\n\r\n<s:Envelope/>", NOW_MS);
+    let (request, _, get_ip) = http_request(mapper.take_outgoing());
+    assert!(get_ip.contains("#GetExternalIPAddress"));
+    mapper.on_http_response(request, b"HTTP/1.1 200 OK\r\n\r\n<NewExternalIPAddress>203.0.113.5</NewExternalIPAddress>", NOW_MS);
+    let external: SocketAddr = "203.0.113.5:4433".parse().unwrap();
+    assert!(matches!(mapper.take_events()[..], [PortMapEvent::Mapped(m)] if m.method == PortMapMethod::Upnp && m.external == external));
+
+    mapper.shutdown();
+    let (_, _, delete) = http_request(mapper.take_outgoing());
+    assert!(delete.contains("#DeletePortMapping") && delete.contains("<NewExternalPort>4433</NewExternalPort>"));
+
+    // A gateway that answers nothing: PCP is retried, then UPnP searched, then given up
+    let mut mapper = PortMapper::new(portmap_config(true));
+    mapper.start(NOW_MS);
+    let mut now_ms = NOW_MS;
+    let mut sent = Vec::new();
+    while let Some(at) = mapper.next_timeout() {
+        sent.extend(datagrams(mapper.take_outgoing()).into_iter().map(|(to, _)| to));
+        now_ms = at;
+        mapper.on_timeout(now_ms);
+    }
+    assert_eq!(sent, vec![GATEWAY, GATEWAY, GATEWAY, GATEWAY, SSDP_ADDR]);
+    assert_eq!(now_ms, NOW_MS + 250 + 500 + 1000 + 2000 + 3000);
+    assert_eq!(mapper.take_events(), vec![PortMapEvent::Unavailable]);
+}
+
+fn relay_client(identity: &NodeIdentity) -> RelayClient {
+    RelayClient::new(NodeIdentity::from_secret_bytes(&identity.to_secret_bytes()), TcpConfig::default(), 3600)
+}
+
+/// Carries the messages between the relay `relay` and its clients, forwarding data as its
+/// bandwidth allows, until nothing moves. Returns the events of each client, in order.
+fn run_relay(relay: NodeId, service: &mut RelayService, clients: &mut [(NodeId, &mut RelayClient)], now_ms: u64) -> Vec<Vec<RelayEvent>> {
+    let mut events: Vec<Vec<RelayEvent>> = clients.iter().map(|_| Vec::new()).collect();
+    loop {
+        service.poll(now_ms);
+        let mut moved = false;
+        for (to, message) in service.take_outgoing() {
+            let (_, client) = clients.iter_mut().find(|(id, _)| *id == to).expect("relay writes to its clients");
+            client.on_message(relay, message, now_ms);
+            moved = true;
+        }
+        for (i, (id, client)) in clients.iter_mut().enumerate() {
+            for (to, message) in client.take_outgoing() {
+                assert_eq!(to, relay);
+                // Every message goes over the wire
+                service.on_message(*id, RelayMessage::from_bytes(&message.to_bytes()).unwrap(), now_ms);
+                moved = true;
+            }
+            events[i].extend(client.take_events());
+        }
+        if !moved {
+            return events;
+        }
+    }
+}
+
+/// Integration test: A NATed node reserves a slot with a relay and is reached through it,
+/// end to end encrypted, with packets larger than one relay message; nodes without a
+/// reservation can't be reached, and reservations are renewed
+#[test]
+fn test_relay_reserve_and_connect() {
+    let identities = [NodeIdentity::generate(), NodeIdentity::generate(), NodeIdentity::generate()];
+    let [alice_id, bob_id, carol_id] = [0, 1, 2].map(|i| node_id(&identities[i]));
+    let relay_id = NodeId::from_bytes([7; 32]);
+    let relay_addr = quic_addr(7);
+    let mut service = RelayService::new(RelayServiceConfig::default(), NOW_MS);
+    let (mut alice, mut bob) = (relay_client(&identities[0]), relay_client(&identities[1]));
+
+    alice.reserve(relay_id, relay_addr);
+    assert!(alice.relay_addresses(NOW_MS).is_empty());
+    run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (bob_id, &mut bob)], NOW_MS);
+    assert!(service.is_reserved(&alice_id, NOW_MS));
+    assert_eq!(alice.relay_addresses(NOW_MS), vec![TransportAddress { addr: relay_addr, kind: AddressKind::Relay }]);
+
+    let to_alice = bob.dial(relay_id, relay_addr, alice_id, NOW_MS);
+    let events = run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (bob_id, &mut bob)], NOW_MS);
+    let from_bob = match &events[0][..] {
+        [RelayEvent::Connected { connection, peer, .. }] => {
+            assert_eq!(peer.identity_key, identities[1].identity_keypair.verifying_key());
+            *connection
+        }
+        other => panic!("unexpected events {other:?}"),
+    };
+    assert!(matches!(&events[1][..], [RelayEvent::Connected { connection, .. }] if *connection == to_alice));
+    assert_eq!(service.circuits(), 1);
+    assert_eq!(bob.relay(to_alice), Some(relay_id));
+
+    let packet = NetworkPacket::new(MessageType::Store, 9, vec![0xAB; 3 * MAX_RELAY_DATA]);
+    bob.send(to_alice, &packet).unwrap();
+    let events = run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (bob_id, &mut bob)], NOW_MS);
+    assert!(matches!(&events[0][..], [RelayEvent::Packet { connection, packet: p }] if *connection == from_bob && p.payload == packet.payload));
+
+    // Closing one end closes the other through the relay
+    assert!(bob.close(to_alice));
+    let events = run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (bob_id, &mut bob)], NOW_MS);
+    assert!(matches!(&events[0][..], [RelayEvent::Closed { error: RelayError::Closed(RelayReason::Normal), .. }]));
+    assert_eq!(service.circuits(), 0);
+
+    // Carol holds no reservation
+    let mut carol = relay_client(&identities[2]);
+    let to_carol = alice.dial(relay_id, relay_addr, carol_id, NOW_MS);
+    let events = run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (carol_id, &mut carol)], NOW_MS);
+    assert!(matches!(&events[0][..], [RelayEvent::Closed { connection, error: RelayError::Closed(RelayReason::NoReservation) }] if *connection == to_carol));
+
+    // Renewed at half the granted time
+    let renew_ms = NOW_MS + 1800 * 1000;
+    assert_eq!(alice.next_timeout(), Some(renew_ms));
+    alice.on_timeout(renew_ms);
+    assert_eq!(alice.take_outgoing(), vec![(relay_id, RelayMessage::Reserve { ttl_secs: 3600 })]);
+
+    // Losing the relay loses its circuits and stops advertising it
+    let to_alice = bob.dial(relay_id, relay_addr, alice_id, NOW_MS);
+    run_relay(relay_id, &mut service, &mut [(alice_id, &mut alice), (bob_id, &mut bob)], NOW_MS);
+    bob.on_relay_lost(relay_id);
+    assert!(matches!(&bob.take_events()[..], [RelayEvent::Closed { connection, error: RelayError::RelayLost }] if *connection == to_alice));
+    service.on_disconnected(alice_id);
+    assert!(!service.is_reserved(&alice_id, NOW_MS));
+    assert!(matches!(&service.take_outgoing()[..], [(to, RelayMessage::Close { reason: RelayReason::PeerGone, .. })] if *to == bob_id));
+}
+
+/// Unit test: A relay forwards circuits within its rates, closes circuits that queue too
+/// much or would go over the monthly cap, and takes no reservations near the cap
+#[test]
+fn test_relay_bandwidth_accounting() {
+    let (alice, bob, carol) = (NodeId::from_bytes([1; 32]), NodeId::from_bytes([2; 32]), NodeId::from_bytes([3; 32]));
+    let bandwidth = BandwidthConfig { circuit_rate: 10_000, circuit_burst: 20_000, monthly_cap: Some(100_000), ..BandwidthConfig::default() };
+    let mut service = RelayService::new(RelayServiceConfig { bandwidth, ..RelayServiceConfig::default() }, NOW_MS);
+    let chunk = vec![0; MAX_RELAY_DATA];
+
+    service.on_message(alice, RelayMessage::Reserve { ttl_secs: 1_000_000 }, NOW_MS);
+    assert_eq!(service.take_outgoing(), vec![(alice, RelayMessage::Reserved { ttl_secs: 3600 })]);
+    service.on_message(bob, RelayMessage::Connect { circuit: 5, target: alice }, NOW_MS);
+    let circuit = match &service.take_outgoing()[..] {
+        [(to, RelayMessage::Incoming { circuit, from })] if *to == alice && *from == bob => *circuit,
+        other => panic!("unexpected messages {other:?}"),
+    };
+
+    // The circuit's burst covers one chunk, its rate another two seconds later
+    service.on_message(bob, RelayMessage::Data { circuit: 5, bytes: chunk.clone() }, NOW_MS);
+    service.on_message(bob, RelayMessage::Data { circuit: 5, bytes: chunk.clone() }, NOW_MS);
+    service.poll(NOW_MS);
+    assert_eq!(service.take_outgoing(), vec![(alice, RelayMessage::Data { circuit, bytes: chunk.clone() })]);
+    service.poll(NOW_MS + 1000);
+    assert!(service.take_outgoing().is_empty());
+    service.poll(NOW_MS + 2000);
+    assert_eq!(service.take_outgoing().len(), 1);
+    // Both directions of both chunks
+    assert_eq!(service.used(), 4 * MAX_RELAY_DATA as u64);
+
+    // Replies go the other way, under the circuit number bob chose
+    service.on_message(alice, RelayMessage::Data { circuit, bytes: vec![1, 2, 3] }, NOW_MS + 2000);
+    service.poll(NOW_MS + 2000);
+    assert_eq!(service.take_outgoing(), vec![(bob, RelayMessage::Data { circuit: 5, bytes: vec![1, 2, 3] })]);
+
+    // Queueing more than the relay holds closes the circuit on both ends
+    for _ in 0..17 {
+        service.on_message(bob, RelayMessage::Data { circuit: 5, bytes: chunk.clone() }, NOW_MS + 2000);
+    }
+    let closed = [(bob, RelayMessage::Close { circuit: 5, reason: RelayReason::Protocol }), (alice, RelayMessage::Close { circuit, reason: RelayReason::Protocol })];
+    assert_eq!(service.take_outgoing(), closed);
+    assert_eq!(service.circuits(), 0);
+    service.on_message(bob, RelayMessage::Data { circuit: 5, bytes: vec![1] }, NOW_MS + 2000);
+    assert_eq!(service.take_outgoing(), vec![(bob, RelayMessage::Close { circuit: 5, reason: RelayReason::UnknownCircuit })]);
+
+    // A chunk that would go over the monthly cap closes its circuit instead
+    service.on_message(bob, RelayMessage::Connect { circuit: 6, target: alice }, NOW_MS + 2000);
+    service.take_outgoing();
+    service.on_message(bob, RelayMessage::Data { circuit: 6, bytes: vec![0; 15_000] }, NOW_MS + 2000);
+    service.poll(NOW_MS + 2000);
+    service.take_outgoing();
+    service.on_message(bob, RelayMessage::Data { circuit: 6, bytes: chunk.clone() }, NOW_MS + 2000);
+    service.poll(NOW_MS + 10_000);
+    assert!(matches!(&service.take_outgoing()[..], [(_, RelayMessage::Close { reason: RelayReason::ResourceLimit, .. }), _]));
+
+    // Past the soft cap, nothing new is taken on
+    assert!(service.used() >= 95_000);
+    service.on_message(carol, RelayMessage::Reserve { ttl_secs: 60 }, NOW_MS + 10_000);
+    service.on_message(bob, RelayMessage::Connect { circuit: 7, target: alice }, NOW_MS + 10_000);
+    let refused = vec![(carol, RelayMessage::Refused(RelayReason::ResourceLimit)), (bob, RelayMessage::Close { circuit: 7, reason: RelayReason::ResourceLimit })];
+    assert_eq!(service.take_outgoing(), refused);
+}
+
+/// Carries what the client at `to_bridge` and the bridge at `to_client` write to each other
+/// until both are quiet, a byte at a time if `trickle`. Returns the client's writes.
+fn pipe_obfs(client: &mut ObfsTransport, bridge: &mut ObfsTransport, to_bridge: ConnectionId, to_client: ConnectionId, trickle: bool, now_ms: u64) -> Vec<Vec<u8>> {
+    let mut written = Vec::new();
+    loop {
+        let (from_client, from_bridge) = (client.take_outgoing(), bridge.take_outgoing());
+        if from_client.is_empty() && from_bridge.is_empty() {
+            return written;
+        }
+        written.extend(from_client.iter().filter_map(|command| match command {
+            TcpCommand::Write { bytes, .. } => Some(bytes.clone()),
+            _ => None,
+        }));
+        pipe_tcp(from_client, to_bridge, to_client, trickle, |c, b| bridge.on_data(c, b, now_ms));
+        pipe_tcp(from_bridge, to_client, to_bridge, trickle, |c, b| client.on_data(c, b, now_ms));
+    }
+}
+
+/// Integration test: A client with the bridge's certificate connects through hellos and
+/// frames that differ on every connection, split arbitrarily, and the TCP handshake inside
+/// authenticates the bridge; without the certificate it can't dial at all
+#[test]
+fn test_obfs_handshake_and_packets() {
+    let bridge_identity = NodeIdentity::generate();
+    let bridge_key = bridge_identity.identity_keypair.verifying_key().to_bytes();
+    let mut bridge = ObfsTransport::new(bridge_identity, ObfsConfig::default(), BridgeCert::generate());
+    let mut client = ObfsTransport::new(NodeIdentity::generate(), ObfsConfig::default(), BridgeCert::generate());
+    assert!(matches!(client.dial(quic_addr(2), NOW_MS), Err(ObfsError::NoCert(_))));
+    client.add_bridge(quic_addr(2), *bridge.cert());
+
+    let mut hellos = Vec::new();
+    for trickle in [true, false] {
+        let to_bridge = client.dial(quic_addr(2), NOW_MS).unwrap();
+        let commands = client.take_outgoing();
+        match &commands[..] {
+            [TcpCommand::Connect { connection, .. }, TcpCommand::Write { bytes, .. }] if *connection == to_bridge => hellos.push(bytes.clone()),
+            other => panic!("unexpected commands {other:?}"),
+        }
+        let to_client = bridge.accept(quic_addr(1), NOW_MS);
+        // The bridge says nothing before the client's hello
+        assert!(bridge.take_outgoing().is_empty());
+        pipe_tcp(commands, to_bridge, to_client, trickle, |c, b| bridge.on_data(c, b, NOW_MS));
+        pipe_obfs(&mut client, &mut bridge, to_bridge, to_client, trickle, NOW_MS);
+        assert!(matches!(&client.take_events()[..], [ObfsEvent::Connected { connection, peer, .. }]
+            if *connection == to_bridge && peer.identity_key.to_bytes() == bridge_key));
+        assert!(matches!(&bridge.take_events()[..], [ObfsEvent::Connected { connection, .. }] if *connection == to_client));
+
+        client.send(to_bridge, &NetworkPacket::new(MessageType::Store, 7, vec![7; 5000]), NOW_MS).unwrap();
+        bridge.send(to_client, &NetworkPacket::new(MessageType::StoreRes, 7, vec![1]), NOW_MS).unwrap();
+        let written = pipe_obfs(&mut client, &mut bridge, to_bridge, to_client, false, NOW_MS);
+        // Whole frames, the last padded
+        assert!(written[0].len() > 5000 && written[0].len() <= 4 * (2 + 1428 + 2 + 16));
+        assert!(matches!(&bridge.take_events()[..], [ObfsEvent::Packet { packet, .. }] if packet.payload == vec![7; 5000]));
+        assert!(matches!(&client.take_events()[..], [ObfsEvent::Packet { packet, .. }] if packet.header.request_id == 7));
+
+        assert!(client.close(to_bridge, NOW_MS));
+        assert_eq!(client.take_outgoing(), vec![TcpCommand::Close(to_bridge)]);
+        bridge.on_closed(to_client, NOW_MS);
+        bridge.take_events();
+    }
+    assert_ne!(hellos[0][..48], hellos[1][..48]);
+    assert!(hellos.iter().all(|hello| hello.len() >= 80));
+}
+
+/// Unit test: A bridge answers nothing to probes, replayed hellos or hellos under another
+/// certificate: it reads a while and hangs up
+#[test]
+fn test_obfs_resists_probing() {
+    let mut bridge = ObfsTransport::new(NodeIdentity::generate(), ObfsConfig::default(), BridgeCert::generate());
+    let mut client = ObfsTransport::new(NodeIdentity::generate(), ObfsConfig::default(), BridgeCert::generate());
+    client.add_bridge(quic_addr(2), *bridge.cert());
+    client.dial(quic_addr(2), NOW_MS).unwrap();
+    let hello = match &client.take_outgoing()[..] {
+        [TcpCommand::Connect { .. }, TcpCommand::Write { bytes, .. }] => bytes.clone(),
+        other => panic!("unexpected commands {other:?}"),
+    };
+    let mut impostor = ObfsTransport::new(NodeIdentity::generate(), ObfsConfig::default(), BridgeCert::generate());
+    impostor.add_bridge(quic_addr(2), BridgeCert::generate());
+    impostor.dial(quic_addr(2), NOW_MS).unwrap();
+    let wrong_cert = match &impostor.take_outgoing()[..] {
+        [_, TcpCommand::Write { bytes, .. }] => bytes.clone(),
+        other => panic!("unexpected commands {other:?}"),
+    };
+
+    let first = bridge.accept(quic_addr(1), NOW_MS);
+    bridge.on_data(first, &hello, NOW_MS);
+    assert!(matches!(bridge.take_outgoing().first(), Some(TcpCommand::Write { .. })));
+
+    for probe in [hello, wrong_cert, vec![0x16, 0x03, 0x01, 0x02, 0x00, 0x01]] {
+        let connection = bridge.accept(quic_addr(3), NOW_MS);
+        bridge.on_data(connection, &probe, NOW_MS);
+        let mut sent = probe.len();
+        while bridge.take_outgoing().is_empty() {
+            bridge.on_data(connection, &[0xAA; 512], NOW_MS);
+            sent += 512;
+        }
+        assert!(sent <= 2 * 1024 + 8 * 1024 + 512, "read {sent} bytes before closing");
+        assert!(matches!(&bridge.take_events()[..], [ObfsEvent::Closed { error: ObfsError::BadHandshake, .. }]));
+    }
+    // Only the close went out
+    let connection = bridge.accept(quic_addr(3), NOW_MS);
+    bridge.on_data(connection, &[0; 100], NOW_MS);
+    assert!(bridge.take_outgoing().is_empty());
+}
+
+/// Unit test: In `iat_mode` frames go out one at a time, spaced by the delays of the
+/// bridge's table, and those still waiting go out before a close
+#[test]
+fn test_obfs_iat_mode() {
+    let config = ObfsConfig { iat_mode: true, ..ObfsConfig::default() };
+    let mut bridge = ObfsTransport::new(NodeIdentity::generate(), config.clone(), BridgeCert::generate());
+    let mut client = ObfsTransport::new(NodeIdentity::generate(), config, BridgeCert::generate());
+    client.add_bridge(quic_addr(2), *bridge.cert());
+    let to_bridge = client.dial(quic_addr(2), NOW_MS).unwrap();
+    let to_client = bridge.accept(quic_addr(1), NOW_MS);
+
+    let mut now_ms = NOW_MS;
+    let mut connected = 0;
+    loop {
+        pipe_obfs(&mut client, &mut bridge, to_bridge, to_client, false, now_ms);
+        let events = client.take_events().into_iter().chain(bridge.take_events());
+        connected += events.filter(|e| matches!(e, ObfsEvent::Connected { .. })).count();
+        let Some(at) = [client.next_timeout(), bridge.next_timeout()].into_iter().flatten().min() else {
+            break;
+        };
+        now_ms = at;
+        client.on_timeout(now_ms);
+        bridge.on_timeout(now_ms);
+    }
+    assert_eq!(connected, 2);
+    assert!(now_ms > NOW_MS && now_ms < NOW_MS + 100);
+
+    client.send(to_bridge, &NetworkPacket::new(MessageType::Store, 1, vec![1; 4000]), now_ms).unwrap();
+    assert!(client.take_outgoing().is_empty());
+    let mut frames = 0;
+    while let Some(at) = client.next_timeout() {
+        assert!(at <= now_ms + 10);
+        now_ms = at;
+        client.on_timeout(now_ms);
+        let commands = client.take_outgoing();
+        frames += commands.len();
+        pipe_tcp(commands, to_bridge, to_client, false, |c, b| bridge.on_data(c, b, now_ms));
+    }
+    // 4000 bytes of packet, in frames of at most 1428
+    assert_eq!(frames, 3);
+    assert!(matches!(&bridge.take_events()[..], [ObfsEvent::Packet { packet, .. }] if packet.payload.len() == 4000));
+
+    client.send(to_bridge, &NetworkPacket::new(MessageType::Store, 2, vec![2; 10]), now_ms).unwrap();
+    client.close(to_bridge, now_ms);
+    assert!(matches!(&client.take_outgoing()[..], [TcpCommand::Write { .. }, TcpCommand::Close(_)]));
+    assert_eq!(client.next_timeout(), None);
+}
+
+/// The sample key of RFC 6455
+const WS_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+fn upgrade_request(path: &str, version: &str) -> Vec<u8> {
+    format!("GET {path} HTTP/1.1\r\nHost: gateway\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: {WS_KEY}\r\nSec-WebSocket-Version: {version}\r\n\r\n").into_bytes()
+}
+
+/// A frame as browsers send it: masked, and final unless `more` follow.
+fn browser_frame(opcode: u8, more: bool, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xFA, 0x21, 0x3D];
+    let mut out = vec![if more { opcode } else { 0x80 | opcode }];
+    if payload.len() < 126 {
+        out.push(0x80 | payload.len() as u8);
+    } else {
+        out.push(0x80 | 126);
+        out.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    out.extend_from_slice(&mask);
+    out.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+    out
+}
+
+/// The frames a gateway wrote, as (opcode, payload)
+fn gateway_frames(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut frames = Vec::new();
+    while let [first, second, ref rest @ ..] = *bytes {
+        assert_eq!(first & 0xF0, 0x80, "gateways send final frames only");
+        assert_eq!(second & 0x80, 0, "gateways never mask");
+        let (len, rest) = match second {
+            126 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+            127 => (u64::from_be_bytes(rest[..8].try_into().unwrap()) as usize, &rest[8..]),
+            len => (len as usize, rest),
+        };
+        frames.push((first & 0x0F, rest[..len].to_vec()));
+        bytes = &rest[len..];
+    }
+    frames
+}
+
+/// What a gateway wrote to `connection`, and whether it closed it
+fn gateway_writes(gateway: &mut WebSocketServer, connection: ConnectionId) -> (Vec<u8>, bool) {
+    let mut written = Vec::new();
+    let closed = pipe_tcp(gateway.take_outgoing(), connection, connection, false, |_, bytes| written.extend_from_slice(bytes));
+    (written, closed)
+}
+
+/// Integration test: A browser node reaches a gateway over a WebSocket: the upgrade is
+/// answered, the TCP handshake runs over the messages, and packets pass both ways whatever
+/// the fragmentation; pings are answered and a close frame echoed
+#[test]
+fn test_websocket_browser_to_gateway() {
+    let gateway_identity = NodeIdentity::generate();
+    let gateway_key = gateway_identity.identity_keypair.verifying_key().to_bytes();
+    let mut gateway = WebSocketServer::new(gateway_identity, WsConfig::default());
+    let mut browser = WebSocketTransport::new(NodeIdentity::generate(), WsConfig::default());
+
+    let to_gateway = browser.dial(quic_addr(2), NOW_MS);
+    assert_eq!(browser.take_outgoing(), vec![WsCommand::Open { connection: to_gateway, url: "wss://10.0.0.2:4433/freedom".to_string() }]);
+    let to_browser = gateway.accept(quic_addr(1), NOW_MS);
+    let request = upgrade_request("/freedom?v=1", "13");
+    gateway.on_data(to_browser, &request[..20], NOW_MS);
+    assert!(gateway.take_outgoing().is_empty());
+    gateway.on_data(to_browser, &request[20..], NOW_MS);
+    let (written, _) = gateway_writes(&mut gateway, to_browser);
+    let end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let response = String::from_utf8(written[..end].to_vec()).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    let mut from_gateway = gateway_frames(&written[end..]);
+    browser.on_opened(to_gateway);
+
+    for _ in 0..3 {
+        for command in browser.take_outgoing() {
+            if let WsCommand::Send { bytes, .. } = command {
+                // Split in a binary frame and a continuation
+                let (head, tail) = bytes.split_at(bytes.len() / 2);
+                gateway.on_data(to_browser, &[browser_frame(0x2, true, head), browser_frame(0x0, false, tail)].concat(), NOW_MS);
+            }
+        }
+        for (opcode, payload) in from_gateway.drain(..) {
+            assert_eq!(opcode, 0x2);
+            browser.on_message(to_gateway, &payload, NOW_MS);
+        }
+        from_gateway = gateway_frames(&gateway_writes(&mut gateway, to_browser).0);
+    }
+    assert!(matches!(&browser.take_events()[..], [WsEvent::Connected { connection, peer, .. }]
+        if *connection == to_gateway && peer.identity_key.to_bytes() == gateway_key));
+    assert!(matches!(&gateway.take_events()[..], [WsEvent::Connected { connection, .. }] if *connection == to_browser));
+
+    browser.send(to_gateway, &NetworkPacket::new(MessageType::Store, 3, vec![3; 70_000])).unwrap();
+    for command in browser.take_outgoing() {
+        if let WsCommand::Send { bytes, .. } = command {
+            for chunk in bytes.chunks(60_000) {
+                gateway.on_data(to_browser, &browser_frame(0x2, false, chunk), NOW_MS);
+            }
+        }
+    }
+    assert!(matches!(&gateway.take_events()[..], [WsEvent::Packet { packet, .. }] if packet.payload.len() == 70_000));
+
+    gateway.on_data(to_browser, &browser_frame(0x9, false, b"ping"), NOW_MS);
+    assert_eq!(gateway_frames(&gateway_writes(&mut gateway, to_browser).0), vec![(0xA, b"ping".to_vec())]);
+
+    gateway.on_data(to_browser, &browser_frame(0x8, false, &1000u16.to_be_bytes()), NOW_MS);
+    let (written, closed) = gateway_writes(&mut gateway, to_browser);
+    assert!(closed);
+    assert_eq!(gateway_frames(&written), vec![(0x8, 1000u16.to_be_bytes().to_vec())]);
+    assert!(matches!(&gateway.take_events()[..], [WsEvent::Closed { error: WsError::Tcp(TcpError::Disconnected), .. }]));
+}
+
+/// Unit test: A gateway refuses upgrades for other paths or versions with an HTTP status,
+/// and closes with the matching code on text, unmasked or oversized frames
+#[test]
+fn test_websocket_rejects_bad_upgrades_and_frames() {
+    assert_eq!(websocket::accept_key(WS_KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    let config = WsConfig { max_frame_len: 1000, ..WsConfig::default() };
+    let mut gateway = WebSocketServer::new(NodeIdentity::generate(), config);
+
+    for (request, status) in [(upgrade_request("/other", "13"), "404"), (upgrade_request("/freedom", "8"), "426")] {
+        let connection = gateway.accept(quic_addr(1), NOW_MS);
+        gateway.on_data(connection, &request, NOW_MS);
+        let (written, closed) = gateway_writes(&mut gateway, connection);
+        assert!(closed && written.starts_with(format!("HTTP/1.1 {status}").as_bytes()));
+        assert!(matches!(&gateway.take_events()[..], [WsEvent::Closed { error: WsError::BadUpgrade(_), .. }]));
+    }
+
+    let mut unmasked = browser_frame(0x2, false, b"data");
+    unmasked[1] &= 0x7F;
+    let oversized = browser_frame(0x2, false, &[0; 1001]);
+    for (frame, code) in [(browser_frame(0x1, false, b"text"), 1003u16), (unmasked, 1002), (oversized, 1009)] {
+        let connection = gateway.accept(quic_addr(1), NOW_MS);
+        gateway.on_data(connection, &upgrade_request("/freedom", "13"), NOW_MS);
+        gateway.take_outgoing();
+        gateway.on_data(connection, &frame, NOW_MS);
+        let (written, closed) = gateway_writes(&mut gateway, connection);
+        assert!(closed);
+        assert_eq!(gateway_frames(&written), vec![(0x8, code.to_be_bytes().to_vec())]);
+        gateway.take_events();
+    }
+    assert!(matches!(Transport::dial(&mut gateway, quic_addr(3), NOW_MS), Err(WsError::DialUnsupported)));
+}
+
+/// Integration test: Dials to a dual-stack node race its addresses, IPv6 first, with staggered
+/// starts; the first to connect wins, and a race fails only once every address did
+#[test]
+fn test_manager_happy_eyeballs() {
+    let (alice_identity, bob_identity) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let bob_id = node_id(&bob_identity);
+    let mut alice = tcp_manager(&alice_identity, ManagerConfig::default());
+    let mut bob = tcp_manager(&bob_identity, ManagerConfig::default());
+    let mut wire = TcpWire::default();
+    let v6 = |port| SocketAddr::new(std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST), port);
+
+    // Bob's IPv6 address goes nowhere
+    let first = alice.connect_any(bob_id, &[quic_addr(2), v6(7000), v6(7000)], NOW_MS).unwrap();
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], NOW_MS);
+    assert!(events.iter().all(Vec::is_empty));
+    assert_eq!(alice.transport().remote_address(first), Some(v6(7000)));
+    assert_eq!(alice.next_timeout(), Some(NOW_MS + 250));
+
+    let later = NOW_MS + 250;
+    alice.on_timeout(later);
+    let events = wire.run(&mut [(quic_addr(1), &mut alice), (quic_addr(2), &mut bob)], later);
+    let [ManagerEvent::Connected { connection: winner, addr, .. }] = &events[0][..] else {
+        panic!("alice did not connect: {:?}", events[0].len());
+    };
+    assert_ne!(*winner, first);
+    assert_eq!(*addr, quic_addr(2));
+    assert_eq!(alice.len(), 1);
+    assert_eq!(alice.connection(&bob_id), Some(*winner));
+    assert_eq!(alice.connect_any(bob_id, &[v6(7000)], later).unwrap(), *winner);
+    assert!(!alice.close(first, later));
+
+    // A refused attempt makes way for the next at once
+    let carol = NodeId::hash_of(b"carol");
+    let first = alice.connect_any(carol, &[quic_addr(3), v6(7001)], later).unwrap();
+    alice.take_outgoing();
+    alice.handle(TcpInput::Closed(first), later);
+    let outgoing = alice.take_outgoing();
+    let [TcpCommand::Connect { connection: second, addr }, ..] = &outgoing[..] else {
+        panic!("no second attempt");
+    };
+    assert_eq!(*addr, quic_addr(3));
+    assert!(alice.take_events().is_empty());
+    alice.handle(TcpInput::Closed(*second), later);
+    assert!(matches!(&alice.take_events()[..], [ManagerEvent::Closed { connection, error: ManagerError::Transport(TcpError::Disconnected) }]
+        if *connection == first));
+    assert_eq!(alice.len(), 1);
+}
+
+/// 100 bytes on the wire
+fn queued_packet(request_id: u32, message_type: MessageType) -> NetworkPacket {
+    NetworkPacket::new(message_type, request_id, vec![0; 100 - crate::protocol::header::HEADER_SIZE])
+}
+
+/// Unit test: a connection's queue blocks past the high-water mark, refuses pushes at its bound, and
+/// unblocks once drained below the low-water mark, in order
+#[test]
+fn test_send_queue_backpressure() {
+    let mut queues = SendQueues::new(QueueConfig { high_water: 300, low_water: 200, max_bytes: 500 });
+    let (slow, other) = (ConnectionId(1), ConnectionId(2));
+    for id in 0..2 {
+        queues.push(slow, queued_packet(id, MessageType::Store), SendPriority::Normal).unwrap();
+    }
+    assert!(queues.take_events().is_empty());
+    queues.push(slow, queued_packet(2, MessageType::Store), SendPriority::Normal).unwrap();
+    assert_eq!(queues.take_events(), vec![QueueEvent::Blocked(slow)]);
+    assert!(queues.is_blocked(slow));
+
+    for id in 3..5 {
+        queues.push(slow, queued_packet(id, MessageType::Store), SendPriority::Normal).unwrap();
+    }
+    assert_eq!(queues.push(slow, queued_packet(5, MessageType::Store), SendPriority::Normal), Err(QueueError::Full { connection: slow, queued: 500 }));
+    // Other connections are unaffected
+    queues.push(other, queued_packet(9, MessageType::Fetch), SendPriority::Normal).unwrap();
+    assert!(!queues.is_blocked(other));
+    assert_eq!(queues.pending().len(), 2);
+
+    let taken = queues.take(slow, 150);
+    assert_eq!(taken.iter().map(|p| p.header.request_id).collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(queues.queued(slow), 300);
+    assert!(queues.take_events().is_empty());
+    let taken = queues.take(slow, 200);
+    assert_eq!(taken.iter().map(|p| p.header.request_id).collect::<Vec<_>>(), vec![2, 3]);
+    assert_eq!(queues.take_events(), vec![QueueEvent::Unblocked(slow)]);
+
+    assert_eq!(queues.forget(slow), 1);
+    assert_eq!(queues.queued(slow), 0);
+    assert_eq!(queues.take(other, usize::MAX).len(), 1);
+    assert!(queues.pending().is_empty());
+}
+
+/// Unit test: padding never blocks a connection and is what gets dropped when its queue fills
+#[test]
+fn test_send_queue_drops_padding_first() {
+    let mut queues = SendQueues::new(QueueConfig { high_water: 300, low_water: 100, max_bytes: 400 });
+    let connection = ConnectionId(1);
+    for id in 0..4 {
+        queues.push(connection, queued_packet(id, MessageType::Onion), SendPriority::Padding).unwrap();
+    }
+    assert!(!queues.is_blocked(connection));
+    assert_eq!(queues.queued(connection), 400);
+
+    // Real traffic evicts the oldest padding
+    for id in 10..13 {
+        queues.push(connection, queued_packet(id, MessageType::Store), SendPriority::Normal).unwrap();
+    }
+    assert_eq!(queues.dropped_padding(), 3);
+    assert_eq!(queues.take_events(), vec![QueueEvent::Blocked(connection)]);
+    // ...and padding for a blocked connection is dropped outright
+    queues.push(connection, queued_packet(4, MessageType::Onion), SendPriority::Padding).unwrap();
+    assert_eq!(queues.dropped_padding(), 4);
+
+    let taken = queues.take(connection, usize::MAX);
+    let ids = taken.iter().map(|p| p.header.request_id).collect::<Vec<_>>();
+    assert_eq!(ids, vec![3, 10, 11, 12]);
+    assert_eq!(queues.take_events(), vec![QueueEvent::Unblocked(connection)]);
+}
+
+/// Delivers what each LAN node multicasts to the others, until they go quiet. Returns the events of each.
+fn run_mdns(nodes: &mut [(SocketAddr, &mut LocalDiscovery, &mut PeerStore)], now_ms: u64) -> Vec<Vec<MdnsEvent>> {
+    let mut events: Vec<Vec<MdnsEvent>> = nodes.iter().map(|_| Vec::new()).collect();
+    for _ in 0..10 {
+        let mut sent = Vec::new();
+        for (i, (addr, discovery, _)) in nodes.iter_mut().enumerate() {
+            for (to, datagram) in discovery.take_outgoing() {
+                assert!(to == MDNS_V4 || to == MDNS_V6);
+                sent.push((i, *addr, datagram));
+            }
+            events[i].extend(discovery.take_events());
+        }
+        if sent.is_empty() {
+            return events;
+        }
+        for (sender, from, datagram) in sent {
+            for (i, (_, discovery, store)) in nodes.iter_mut().enumerate() {
+                if i != sender {
+                    assert!(discovery.on_datagram(from, &datagram, now_ms, store));
+                }
+            }
+        }
+    }
+    panic!("LAN nodes never went quiet");
+}
+
+/// Integration test: Nodes on a LAN find each other over mDNS, land in each other's peer store,
+/// and are forgotten when they leave or stop announcing
+#[test]
+fn test_mdns_local_discovery() {
+    let (alice_id, bob_id, carol_id) = (NodeId::hash_of(b"alice"), NodeId::hash_of(b"bob"), NodeId::hash_of(b"carol"));
+    let lan = |host: u8| SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, host)), 5353);
+    let alice_config = MdnsConfig { port: 7000, addresses: vec![lan(10).ip(), quic_addr(1).ip()], ..MdnsConfig::default() };
+    let mut alice = LocalDiscovery::new(alice_id, alice_config);
+    let mut bob = LocalDiscovery::new(bob_id, MdnsConfig { port: 7001, ..MdnsConfig::default() });
+    let mut carol = LocalDiscovery::new(carol_id, MdnsConfig { port: 7002, ipv6: true, ..MdnsConfig::default() });
+    let (mut alice_store, mut bob_store, mut carol_store) = (PeerStore::new(), PeerStore::new(), PeerStore::new());
+
+    alice.start(NOW_MS);
+    bob.start(NOW_MS);
+    carol.start(NOW_MS);
+    let events = run_mdns(&mut [
+        (lan(10), &mut alice, &mut alice_store),
+        (lan(11), &mut bob, &mut bob_store),
+        (lan(12), &mut carol, &mut carol_store),
+    ], NOW_MS);
+    assert!(events[1].contains(&MdnsEvent::Discovered { node: alice_id, addrs: vec![SocketAddr::new(lan(10).ip(), 7000), SocketAddr::new(quic_addr(1).ip(), 7000)] }));
+    assert!(events[0].contains(&MdnsEvent::Discovered { node: bob_id, addrs: vec![SocketAddr::new(lan(11).ip(), 7001)] }));
+    assert_eq!(events[0].len(), 2);
+    assert_eq!(bob_store.get(&alice_id).unwrap().addresses[0], SocketAddr::new(lan(10).ip(), 7000));
+    assert_eq!(alice_store.get(&carol_id).unwrap().addresses, vec![SocketAddr::new(lan(12).ip(), 7002)]);
+    assert_eq!(alice_store.len(), 2);
+    assert_eq!(alice.peers().count(), 2);
+
+    // The second announcement, and answers to repeated queries, change nothing
+    let later = NOW_MS + 1_000;
+    for node in [&mut alice, &mut bob, &mut carol] {
+        assert_eq!(node.next_timeout(), Some(later));
+        node.on_timeout(later);
+    }
+    let events = run_mdns(&mut [
+        (lan(10), &mut alice, &mut alice_store),
+        (lan(11), &mut bob, &mut bob_store),
+        (lan(12), &mut carol, &mut carol_store),
+    ], later);
+    assert!(events.iter().all(Vec::is_empty));
+
+    // Bob leaves politely; carol goes silent and expires after her TTL
+    bob.shutdown();
+    let events = run_mdns(&mut [(lan(10), &mut alice, &mut alice_store), (lan(11), &mut bob, &mut bob_store)], later);
+    assert_eq!(events[0], vec![MdnsEvent::Departed(bob_id)]);
+    assert!(bob.next_timeout().is_some_and(|at| at >= later + 120_000));
+    alice.on_timeout(later + 120_000);
+    assert_eq!(alice.take_events(), vec![MdnsEvent::Departed(carol_id)]);
+    assert_eq!(alice.peers().count(), 0);
+    // The peer store keeps them, for next time
+    assert_eq!(alice_store.len(), 2);
+}
+
+/// Unit test: Responses from other responders, with compressed names and unrelated records, are
+/// read; our own announcements and malformed messages are not
+#[test]
+fn test_mdns_parses_compressed_responses() {
+    let node = NodeId::hash_of(b"dave");
+    let mut discovery = LocalDiscovery::new(NodeId::hash_of(b"alice"), MdnsConfig::default());
+    let mut store = PeerStore::new();
+    let id: String = node.as_bytes().iter().map(|b| format!("{b:02x}")).collect();
+
+    let labels = |out: &mut Vec<u8>, name: &str| name.split('.').for_each(|label| {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    });
+    // Appends a record named by `name` (raw, maybe a pointer). Returns where its data starts.
+    let record = |out: &mut Vec<u8>, name: &[u8], kind: u16, rdata: &[u8]| {
+        out.extend_from_slice(name);
+        out.extend_from_slice(&kind.to_be_bytes());
+        out.extend_from_slice(&[0x80, 1, 0, 0, 0, 120]);
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.len()
+    };
+    let mut response = vec![0, 0, 0x84, 0, 0, 0, 0, 5, 0, 0, 0, 0];
+    // An unrelated record first, pointing at its own name
+    let mut printer = Vec::new();
+    labels(&mut printer, "printer._ipp._tcp.local");
+    printer.push(0);
+    record(&mut response, &printer, 12, &[0xC0, 12]);
+    response.extend_from_slice(&[0xC0, 12]);
+    // The service name written out once, mixed case; everything after points into it
+    let service = response.len() as u8;
+    let mut name = Vec::new();
+    labels(&mut name, "_FreedomNode._udp.local");
+    name.push(0);
+    let ptr = [&[4][..], b"dave", &[0xC0, service]].concat();
+    let instance = record(&mut response, &name, 12, &ptr) as u8;
+    response.extend_from_slice(&ptr);
+    let srv = [&[0, 0, 0, 0, 0x1F, 0x40, 4][..], b"dave", &[5], b"local", &[0]].concat();
+    let host = record(&mut response, &[0xC0, instance], 33, &srv) as u8 + 6;
+    response.extend_from_slice(&srv);
+    let txt = [&[67][..], format!("id={id}").as_bytes()].concat();
+    record(&mut response, &[0xC0, instance], 16, &txt);
+    response.extend_from_slice(&txt);
+    record(&mut response, &[0xC0, host], 1, &[10, 0, 0, 7]);
+    response.extend_from_slice(&[10, 0, 0, 7]);
+
+    let from = quic_addr(9);
+    assert!(discovery.on_datagram(from, &response, NOW_MS, &mut store));
+    let dave = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 7)), 8000);
+    assert_eq!(discovery.take_events(), vec![MdnsEvent::Discovered { node, addrs: vec![dave] }]);
+    assert_eq!(store.get(&node).unwrap().addresses, vec![dave]);
+
+    // A name pointing at itself, and a truncated header
+    let mut looping = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    looping.extend_from_slice(&[0xC0, 12]);
+    assert!(!discovery.on_datagram(from, &looping, NOW_MS, &mut store));
+    assert!(!discovery.on_datagram(from, &[0; 5], NOW_MS, &mut store));
+
+    // Queries are answered only once started, and at most once a second
+    let mut query = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    labels(&mut query, mdns::SERVICE_NAME);
+    query.extend_from_slice(&[0, 0, 12, 0, 1]);
+    assert!(discovery.on_datagram(from, &query, NOW_MS, &mut store));
+    assert!(discovery.take_outgoing().is_empty());
+    discovery.start(NOW_MS);
+    discovery.take_outgoing();
+    assert!(discovery.on_datagram(from, &query, NOW_MS + 500, &mut store));
+    assert!(discovery.take_outgoing().is_empty());
+    assert!(discovery.on_datagram(from, &query, NOW_MS + 1_000, &mut store));
+    assert_eq!(discovery.take_outgoing().len(), 1);
+    // Our own announcement, looped back, is not a peer
+    discovery.shutdown();
+    let (_, goodbye) = discovery.take_outgoing().remove(0);
+    let mut own = LocalDiscovery::new(NodeId::hash_of(b"alice"), MdnsConfig::default());
+    assert!(own.on_datagram(from, &goodbye, NOW_MS, &mut store));
+    assert!(own.take_events().is_empty());
+}
+
+/// The bytes a transport wrote on `connection`, and whether it closed it
+fn written(commands: &[TcpCommand], connection: ConnectionId) -> (Vec<u8>, bool) {
+    let mut bytes = Vec::new();
+    let mut closed = false;
+    for command in commands {
+        match command {
+            TcpCommand::Write { connection: c, bytes: b } if *c == connection => bytes.extend_from_slice(b),
+            TcpCommand::Close(c) if *c == connection => closed = true,
+            _ => {}
+        }
+    }
+    (bytes, closed)
+}
+
+/// Integration test: A dial through a SOCKS5 proxy authenticates, asks the proxy to connect,
+/// holds the handshake until it has, then runs as usual over the proxied socket
+#[test]
+fn test_socks5_proxied_dial() {
+    let proxy = quic_addr(9);
+    let config = Socks5Config { proxy, credentials: Some(("alice".into(), "secret".into())) };
+    let mut alice = Socks5Transport::new(TcpTransport::new(NodeIdentity::generate(), TcpConfig::default()), config);
+    let mut bob = TcpTransport::new(NodeIdentity::generate(), TcpConfig::default());
+
+    let to_bob = Transport::dial(&mut alice, quic_addr(2), NOW_MS).unwrap();
+    let commands = alice.take_outgoing();
+    assert_eq!(commands[0], TcpCommand::Connect { connection: to_bob, addr: proxy });
+    assert_eq!(written(&commands, to_bob).0, vec![5, 2, 0, 2]);
+    assert_eq!(alice.remote_address(to_bob), Some(quic_addr(2)));
+
+    // The proxy picks password authentication, a byte at a time
+    alice.handle(TcpInput::Data { connection: to_bob, bytes: vec![5] }, NOW_MS);
+    alice.handle(TcpInput::Data { connection: to_bob, bytes: vec![2] }, NOW_MS);
+    assert_eq!(written(&alice.take_outgoing(), to_bob).0, b"\x01\x05alice\x06secret");
+    alice.handle(TcpInput::Data { connection: to_bob, bytes: vec![1, 0] }, NOW_MS);
+    assert_eq!(written(&alice.take_outgoing(), to_bob).0, [&[5, 1, 0, 1, 10, 0, 0, 2][..], &4433u16.to_be_bytes()].concat());
+
+    // It connects to bob, whose key follows its reply in the same read
+    let to_alice = bob.accept(proxy, NOW_MS);
+    let (bob_key, _) = written(&bob.take_outgoing(), to_alice);
+    let mut reply = vec![5, 0, 0, 1, 192, 0, 2, 1, 0x30, 0x39];
+    reply.extend_from_slice(&bob_key);
+    alice.handle(TcpInput::Data { connection: to_bob, bytes: reply }, NOW_MS);
+    let mut commands = (alice.take_outgoing(), bob.take_outgoing());
+    for _ in 0..3 {
+        pipe_tcp(commands.0, to_bob, to_alice, false, |c, b| bob.on_data(c, b, NOW_MS));
+        pipe_tcp(commands.1, to_alice, to_bob, false, |c, b| {
+            alice.handle(TcpInput::Data { connection: c, bytes: b.to_vec() }, NOW_MS);
+        });
+        commands = (alice.take_outgoing(), bob.take_outgoing());
+    }
+    assert!(matches!(&alice.take_events()[..], [Socks5Event::Connected { connection, addr, .. }] if *connection == to_bob && *addr == quic_addr(2)));
+    assert!(matches!(&bob.take_events()[..], [TcpEvent::Connected { .. }]));
+
+    Transport::send(&mut alice, to_bob, &NetworkPacket::new(MessageType::Store, 4, vec![4]), NOW_MS).unwrap();
+    pipe_tcp(alice.take_outgoing(), to_bob, to_alice, false, |c, b| bob.on_data(c, b, NOW_MS));
+    assert!(matches!(&bob.take_events()[..], [TcpEvent::Packet { packet, .. }] if packet.payload == [4]));
+}
+
+/// Unit test: Dials the proxy refuses, or can't authenticate, are closed with the reason
+#[test]
+fn test_socks5_proxy_failures() {
+    let mut alice = Socks5Transport::new(TcpTransport::new(NodeIdentity::generate(), TcpConfig::default()), Socks5Config::default());
+    let refused = Transport::dial(&mut alice, quic_addr(2), NOW_MS).unwrap();
+    assert_eq!(written(&alice.take_outgoing(), refused).0, vec![5, 1, 0]);
+    alice.handle(TcpInput::Data { connection: refused, bytes: vec![5, 0] }, NOW_MS);
+    alice.take_outgoing();
+    alice.handle(TcpInput::Data { connection: refused, bytes: vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0] }, NOW_MS);
+    assert!(written(&alice.take_outgoing(), refused).1);
+    assert!(matches!(&alice.take_events()[..], [Socks5Event::Closed { connection, error: Socks5Error::Refused(SocksReply::ConnectionRefused) }]
+        if *connection == refused));
+
+    // We offered no authentication, so the proxy can't insist on a password
+    let unauthenticated = Transport::dial(&mut alice, quic_addr(3), NOW_MS).unwrap();
+    alice.handle(TcpInput::Data { connection: unauthenticated, bytes: vec![5, 0xFF] }, NOW_MS);
+    assert!(matches!(&alice.take_events()[..], [Socks5Event::Closed { error: Socks5Error::NoAcceptableAuth, .. }]));
+    let garbled = Transport::dial(&mut alice, quic_addr(4), NOW_MS).unwrap();
+    alice.handle(TcpInput::Data { connection: garbled, bytes: b"HTTP/1.1 400".to_vec() }, NOW_MS);
+    assert!(matches!(&alice.take_events()[..], [Socks5Event::Closed { error: Socks5Error::Protocol, .. }]));
+    assert!(alice.inner().is_empty());
+
+    let long = Socks5Config { credentials: Some(("a".repeat(256), String::new())), ..Socks5Config::default() };
+    let mut alice = Socks5Transport::new(TcpTransport::new(NodeIdentity::generate(), TcpConfig::default()), long);
+    assert!(matches!(Transport::dial(&mut alice, quic_addr(2), NOW_MS), Err(Socks5Error::CredentialsTooLong)));
+}
@@ -0,0 +1,687 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use sha1::{ Digest, Sha1 };
+
+use super::tcp::{ TcpCommand, TcpConfig, TcpError, TcpInput, TcpTransport };
+use super::{ ConnectionId, Transport, TransportEvent, TransportStats };
+use crate::crypto::handshake::HandshakePayload;
+use crate::crypto::identity::NodeIdentity;
+use crate::protocol::packet::NetworkPacket;
+
+/// Appended to the client's key before hashing, per RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Longest upgrade request a gateway reads
+const MAX_REQUEST: usize = 8 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsConfig {
+    pub tcp: TcpConfig,
+    /// Where gateways serve the network, as in "wss://203.0.113.5:443/freedom"
+    pub path: String,
+    /// Whether browsers dial wss:// (pages served over https can't open ws://)
+    pub secure: bool,
+    /// Largest frame a gateway accepts
+    pub max_frame_len: usize,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self { tcp: TcpConfig::default(), path: "/freedom".to_string(), secure: true, max_frame_len: 1024 * 1024 }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WsError {
+    #[error("Invalid upgrade request: {0}")]
+    BadUpgrade(&'static str),
+    #[error("WebSocket protocol violation: {0}")]
+    Protocol(&'static str),
+    #[error("Frame of {0} bytes is too large")]
+    FrameTooLarge(u64),
+    #[error("Gateways only accept connections")]
+    DialUnsupported,
+    #[error("TCP: {0}")]
+    Tcp(#[from] TcpError),
+}
+
+pub type WsEvent = TransportEvent<WsError>;
+
+/// What the browser's WebSocket API did, for `Transport::handle`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsInput {
+    /// The socket's `open` event
+    Opened(ConnectionId),
+    /// A binary `message`
+    Message {
+        connection: ConnectionId,
+        bytes: Vec<u8>,
+    },
+    /// The socket's `close` or `error` event
+    Closed(ConnectionId),
+}
+
+/// What the host does with the browser's WebSockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WsCommand {
+    /// `new WebSocket(url)` with `binaryType = "arraybuffer"`, for `connection`
+    Open {
+        connection: ConnectionId,
+        url: String,
+    },
+    /// `send` a binary message
+    Send {
+        connection: ConnectionId,
+        bytes: Vec<u8>,
+    },
+    Close(ConnectionId),
+}
+
+/// The socket for `connection`: sends wait for its `open` event
+#[derive(Default)]
+struct Socket {
+    open: bool,
+    held: Vec<Vec<u8>>,
+}
+
+/// The transport of browser nodes, which have neither UDP nor raw sockets: WebSockets to
+/// gateways, nodes that also run a `WebSocketServer` next to their QUIC endpoint and so
+/// join browsers to the rest of the network.
+///
+/// The TCP transport runs over the WebSocket's messages, so the gateway is authenticated
+/// end to end whatever TLS terminates the wss:// connection. Being a browser, it dials
+/// only; `listen` does nothing.
+///
+/// Sans-IO like the others: the host carries out `WsCommand`s with the WebSocket API and
+/// hands its events over with `handle`.
+pub struct WebSocketTransport {
+    tcp: TcpTransport,
+    config: WsConfig,
+    sockets: HashMap<ConnectionId, Socket>,
+    commands: Vec<WsCommand>,
+    events: Vec<WsEvent>,
+}
+
+impl WebSocketTransport {
+    pub fn new(identity: NodeIdentity, config: WsConfig) -> Self {
+        Self {
+            tcp: TcpTransport::new(identity, config.tcp.clone()),
+            config,
+            sockets: HashMap::new(),
+            commands: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// The URL of the gateway at `addr`
+    pub fn url(&self, addr: SocketAddr) -> String {
+        let scheme = if self.config.secure { "wss" } else { "ws" };
+        format!("{scheme}://{addr}{}", self.config.path)
+    }
+
+    /// Starts a connection to the gateway at `addr`. `Connected` follows once the socket is
+    /// open and the handshake through.
+    pub fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.tcp.dial(addr, now_ms);
+        self.sockets.insert(connection, Socket::default());
+        self.pump();
+        connection
+    }
+
+    /// The socket of a connection opened: what was sent before goes out.
+    pub fn on_opened(&mut self, connection: ConnectionId) {
+        if let Some(socket) = self.sockets.get_mut(&connection) {
+            socket.open = true;
+            let held = std::mem::take(&mut socket.held);
+            self.commands.extend(held.into_iter().map(|bytes| WsCommand::Send { connection, bytes }));
+        }
+    }
+
+    /// Handles a message received on a connection's socket.
+    pub fn on_message(&mut self, connection: ConnectionId, bytes: &[u8], now_ms: u64) {
+        self.tcp.on_data(connection, bytes, now_ms);
+        self.pump();
+    }
+
+    /// A connection's socket closed or failed.
+    pub fn on_closed(&mut self, connection: ConnectionId) {
+        self.sockets.remove(&connection);
+        self.tcp.on_closed(connection);
+        self.pump();
+    }
+
+    /// Sends a packet to an authenticated peer.
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket) -> Result<(), WsError> {
+        self.tcp.send(connection, packet)?;
+        self.pump();
+        Ok(())
+    }
+
+    /// Closes a connection. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId) -> bool {
+        let known = self.tcp.close(connection);
+        self.pump();
+        known
+    }
+
+    /// When `on_timeout` is due next, if a handshake is under way
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.tcp.next_timeout()
+    }
+
+    /// Closes the connections whose socket or handshake didn't finish in time.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        self.tcp.on_timeout(now_ms);
+        self.pump();
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.tcp.peer(connection)
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        self.tcp.remote_address(connection)
+    }
+
+    /// What a connection carried so far, counted by the TCP transport inside
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        self.tcp.stats(connection)
+    }
+
+    /// What to do with the WebSockets since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<WsCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn take_events(&mut self) -> Vec<WsEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn pump(&mut self) {
+        for command in self.tcp.take_outgoing() {
+            match command {
+                TcpCommand::Connect { connection, addr } => {
+                    self.commands.push(WsCommand::Open { connection, url: self.url(addr) });
+                }
+                TcpCommand::Write { connection, bytes } => match self.sockets.get_mut(&connection) {
+                    Some(socket) if !socket.open => socket.held.push(bytes),
+                    Some(_) => self.commands.push(WsCommand::Send { connection, bytes }),
+                    None => {}
+                },
+                TcpCommand::Close(connection) => {
+                    self.sockets.remove(&connection);
+                    self.commands.push(WsCommand::Close(connection));
+                }
+            }
+        }
+        for event in self.tcp.take_events() {
+            self.events.push(match event {
+                TransportEvent::Connected { connection, addr, peer } => WsEvent::Connected { connection, addr, peer },
+                TransportEvent::Packet { connection, packet } => WsEvent::Packet { connection, packet },
+                TransportEvent::Migrated { connection, addr } => WsEvent::Migrated { connection, addr },
+                TransportEvent::Closed { connection, error } => {
+                    self.sockets.remove(&connection);
+                    WsEvent::Closed { connection, error: error.into() }
+                }
+            });
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    type Input = WsInput;
+    type Output = WsCommand;
+    type Error = WsError;
+
+    fn dial(&mut self, addr: SocketAddr, now_ms: u64) -> Result<ConnectionId, WsError> {
+        Ok(WebSocketTransport::dial(self, addr, now_ms))
+    }
+
+    fn listen(&mut self, _enabled: bool) {}
+
+    fn handle(&mut self, input: WsInput, now_ms: u64) -> Option<ConnectionId> {
+        match input {
+            WsInput::Opened(connection) => self.on_opened(connection),
+            WsInput::Message { connection, bytes } => self.on_message(connection, &bytes, now_ms),
+            WsInput::Closed(connection) => self.on_closed(connection),
+        }
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, _now_ms: u64) -> Result<(), WsError> {
+        WebSocketTransport::send(self, connection, packet)
+    }
+
+    fn close(&mut self, connection: ConnectionId, _now_ms: u64) -> bool {
+        WebSocketTransport::close(self, connection)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        WebSocketTransport::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        WebSocketTransport::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        WebSocketTransport::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        WebSocketTransport::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        WebSocketTransport::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<WsCommand> {
+        WebSocketTransport::take_outgoing(self)
+    }
+
+    fn take_events(&mut self) -> Vec<WsEvent> {
+        WebSocketTransport::take_events(self)
+    }
+}
+
+struct Upgradable {
+    upgraded: bool,
+    /// Raw bytes received that don't make a request or frame yet
+    received: Vec<u8>,
+    /// What the TCP transport wrote before the upgrade
+    held: Vec<u8>,
+}
+
+/// The gateway side of `WebSocketTransport`: an RFC 6455 server over the host's TCP
+/// sockets, usually behind a TLS-terminating proxy for wss://.
+///
+/// It answers the HTTP upgrade for `WsConfig::path`, then takes the bytes of binary
+/// messages, whatever their fragmentation, for the TCP transport running over them, and
+/// sends its writes as binary frames. Pings are answered; text frames and frames over
+/// `max_frame_len` close the connection. It only accepts: `dial` fails.
+///
+/// Driven as the TCP transport is, with `TcpInput` and `TcpCommand`.
+pub struct WebSocketServer {
+    tcp: TcpTransport,
+    config: WsConfig,
+    connections: HashMap<ConnectionId, Upgradable>,
+    commands: Vec<TcpCommand>,
+    events: Vec<WsEvent>,
+}
+
+impl WebSocketServer {
+    pub fn new(identity: NodeIdentity, config: WsConfig) -> Self {
+        Self {
+            tcp: TcpTransport::new(identity, config.tcp.clone()),
+            config,
+            connections: HashMap::new(),
+            commands: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Takes over a socket the host accepted from `addr`, or has it closed if we don't listen.
+    pub fn accept(&mut self, addr: SocketAddr, now_ms: u64) -> ConnectionId {
+        let connection = self.tcp.accept(addr, now_ms);
+        if self.tcp.remote_address(connection).is_some() {
+            self.connections.insert(connection, Upgradable { upgraded: false, received: Vec::new(), held: Vec::new() });
+        }
+        self.pump();
+        connection
+    }
+
+    /// Handles bytes read from a connection's socket.
+    pub fn on_data(&mut self, connection: ConnectionId, data: &[u8], now_ms: u64) {
+        let Some(conn) = self.connections.get_mut(&connection) else {
+            return;
+        };
+        conn.received.extend_from_slice(data);
+        match self.process(connection, now_ms) {
+            Ok(true) => {}
+            // The browser closed: echo its close frame, as the protocol asks
+            Ok(false) => {
+                self.connections.remove(&connection);
+                self.commands.push(TcpCommand::Write { connection, bytes: close_frame(CLOSE_NORMAL) });
+                self.tcp.on_closed(connection);
+                self.commands.push(TcpCommand::Close(connection));
+            }
+            Err(error) => {
+                log::warn!("Closing WebSocket connection {connection:?}: {error}");
+                if self.connections.remove(&connection).is_some_and(|conn| conn.upgraded) {
+                    let code = match error {
+                        WsError::FrameTooLarge(_) => CLOSE_TOO_BIG,
+                        WsError::Protocol("text frame") => CLOSE_UNSUPPORTED,
+                        _ => CLOSE_PROTOCOL,
+                    };
+                    self.commands.push(TcpCommand::Write { connection, bytes: close_frame(code) });
+                }
+                self.tcp.close(connection);
+                self.events.push(WsEvent::Closed { connection, error });
+            }
+        }
+        self.pump();
+    }
+
+    /// The host's socket closed or failed.
+    pub fn on_closed(&mut self, connection: ConnectionId) {
+        self.connections.remove(&connection);
+        self.tcp.on_closed(connection);
+        self.pump();
+    }
+
+    /// Sends a packet to an authenticated peer.
+    pub fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket) -> Result<(), WsError> {
+        self.tcp.send(connection, packet)?;
+        self.pump();
+        Ok(())
+    }
+
+    /// Closes a connection with a close frame. Returns false if it was unknown.
+    pub fn close(&mut self, connection: ConnectionId) -> bool {
+        let known = self.tcp.close(connection);
+        self.pump();
+        known
+    }
+
+    /// When `on_timeout` is due next, if an upgrade or handshake is under way
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.tcp.next_timeout()
+    }
+
+    /// Closes the connections whose upgrade or handshake didn't finish in time.
+    pub fn on_timeout(&mut self, now_ms: u64) {
+        self.tcp.on_timeout(now_ms);
+        self.pump();
+    }
+
+    /// The authenticated identity of a connection's peer
+    pub fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        self.tcp.peer(connection)
+    }
+
+    pub fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        self.tcp.remote_address(connection)
+    }
+
+    /// What a connection carried so far, counted by the TCP transport inside
+    pub fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        self.tcp.stats(connection)
+    }
+
+    /// What to do with the sockets since the last call, in order.
+    pub fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        std::mem::take(&mut self.commands)
+    }
+
+    pub fn take_events(&mut self) -> Vec<WsEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Consumes what `received` holds: the upgrade request, then frames. Returns false once
+    /// the browser sent a close frame.
+    fn process(&mut self, connection: ConnectionId, now_ms: u64) -> Result<bool, WsError> {
+        let conn = self.connections.get_mut(&connection).expect("checked by on_data");
+        if !conn.upgraded {
+            let Some(end) = conn.received.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4) else {
+                if conn.received.len() > MAX_REQUEST {
+                    self.commands.push(TcpCommand::Write { connection, bytes: http_status("431 Request Header Fields Too Large") });
+                    return Err(WsError::BadUpgrade("request too long"));
+                }
+                return Ok(true);
+            };
+            let request = String::from_utf8_lossy(&conn.received[..end]).into_owned();
+            let key = match upgrade_key(&request, &self.config.path) {
+                Ok(key) => key,
+                Err((status, error)) => {
+                    self.commands.push(TcpCommand::Write { connection, bytes: http_status(status) });
+                    return Err(error);
+                }
+            };
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(key)
+            );
+            self.commands.push(TcpCommand::Write { connection, bytes: response.into_bytes() });
+            conn.received.drain(..end);
+            conn.upgraded = true;
+            let held = std::mem::take(&mut conn.held);
+            if !held.is_empty() {
+                self.commands.push(TcpCommand::Write { connection, bytes: frame(OP_BINARY, &held) });
+            }
+        }
+
+        let mut bytes = Vec::new();
+        let mut open = true;
+        while let Some((opcode, payload)) = read_frame(&mut conn.received, self.config.max_frame_len)? {
+            match opcode {
+                OP_BINARY | OP_CONTINUATION => bytes.extend_from_slice(&payload),
+                OP_TEXT => return Err(WsError::Protocol("text frame")),
+                OP_PING => self.commands.push(TcpCommand::Write { connection, bytes: frame(OP_PONG, &payload) }),
+                OP_PONG => {}
+                OP_CLOSE => {
+                    open = false;
+                    break;
+                }
+                _ => return Err(WsError::Protocol("unknown opcode")),
+            }
+        }
+        if !bytes.is_empty() {
+            self.tcp.on_data(connection, &bytes, now_ms);
+        }
+        Ok(open)
+    }
+
+    fn pump(&mut self) {
+        for command in self.tcp.take_outgoing() {
+            match command {
+                TcpCommand::Write { connection, bytes } => match self.connections.get_mut(&connection) {
+                    Some(conn) if !conn.upgraded => conn.held.extend_from_slice(&bytes),
+                    Some(_) => self.commands.push(TcpCommand::Write { connection, bytes: frame(OP_BINARY, &bytes) }),
+                    None => {}
+                },
+                TcpCommand::Close(connection) => {
+                    if self.connections.remove(&connection).is_some_and(|conn| conn.upgraded) {
+                        self.commands.push(TcpCommand::Write { connection, bytes: close_frame(CLOSE_NORMAL) });
+                    }
+                    self.commands.push(TcpCommand::Close(connection));
+                }
+                TcpCommand::Connect { .. } => {}
+            }
+        }
+        for event in self.tcp.take_events() {
+            self.events.push(match event {
+                TransportEvent::Connected { connection, addr, peer } => WsEvent::Connected { connection, addr, peer },
+                TransportEvent::Packet { connection, packet } => WsEvent::Packet { connection, packet },
+                TransportEvent::Migrated { connection, addr } => WsEvent::Migrated { connection, addr },
+                TransportEvent::Closed { connection, error } => {
+                    self.connections.remove(&connection);
+                    WsEvent::Closed { connection, error: error.into() }
+                }
+            });
+        }
+    }
+}
+
+impl Transport for WebSocketServer {
+    type Input = TcpInput;
+    type Output = TcpCommand;
+    type Error = WsError;
+
+    fn dial(&mut self, _addr: SocketAddr, _now_ms: u64) -> Result<ConnectionId, WsError> {
+        Err(WsError::DialUnsupported)
+    }
+
+    fn listen(&mut self, enabled: bool) {
+        self.tcp.listen(enabled);
+    }
+
+    fn handle(&mut self, input: TcpInput, now_ms: u64) -> Option<ConnectionId> {
+        match input {
+            TcpInput::Accepted(addr) => {
+                return Some(self.accept(addr, now_ms));
+            }
+            TcpInput::Data { connection, bytes } => self.on_data(connection, &bytes, now_ms),
+            TcpInput::Closed(connection) => self.on_closed(connection),
+        }
+        None
+    }
+
+    fn send(&mut self, connection: ConnectionId, packet: &NetworkPacket, _now_ms: u64) -> Result<(), WsError> {
+        WebSocketServer::send(self, connection, packet)
+    }
+
+    fn close(&mut self, connection: ConnectionId, _now_ms: u64) -> bool {
+        WebSocketServer::close(self, connection)
+    }
+
+    fn next_timeout(&mut self) -> Option<u64> {
+        WebSocketServer::next_timeout(self)
+    }
+
+    fn on_timeout(&mut self, now_ms: u64) {
+        WebSocketServer::on_timeout(self, now_ms);
+    }
+
+    fn peer(&self, connection: ConnectionId) -> Option<&HandshakePayload> {
+        WebSocketServer::peer(self, connection)
+    }
+
+    fn remote_address(&self, connection: ConnectionId) -> Option<SocketAddr> {
+        WebSocketServer::remote_address(self, connection)
+    }
+
+    fn stats(&self, connection: ConnectionId) -> Option<TransportStats> {
+        WebSocketServer::stats(self, connection)
+    }
+
+    fn take_outgoing(&mut self) -> Vec<TcpCommand> {
+        WebSocketServer::take_outgoing(self)
+    }
+
+    fn take_events(&mut self) -> Vec<WsEvent> {
+        WebSocketServer::take_events(self)
+    }
+}
+
+/// Checks an upgrade request for `path`. Returns its Sec-WebSocket-Key, or the status to
+/// answer with.
+fn upgrade_key<'a>(request: &'a str, path: &str) -> Result<&'a str, (&'static str, WsError)> {
+    let start: Vec<&str> = request.split("\r\n").next().unwrap_or_default().split(' ').collect();
+    let ["GET", target, "HTTP/1.1"] = start[..] else {
+        return Err(("400 Bad Request", WsError::BadUpgrade("not a GET over HTTP/1.1")));
+    };
+    if target.split('?').next() != Some(path) {
+        return Err(("404 Not Found", WsError::BadUpgrade("wrong path")));
+    }
+
+    let header = |name: &str| {
+        request
+            .split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    };
+    let has_token = |name: &str, token: &str| header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(("400 Bad Request", WsError::BadUpgrade("not a WebSocket upgrade")));
+    }
+    if header("Sec-WebSocket-Version") != Some("13") {
+        return Err(("426 Upgrade Required\r\nSec-WebSocket-Version: 13", WsError::BadUpgrade("unsupported version")));
+    }
+    // 16 random bytes in base64
+    match header("Sec-WebSocket-Key") {
+        Some(key) if key.len() == 24 => Ok(key),
+        _ => Err(("400 Bad Request", WsError::BadUpgrade("missing key"))),
+    }
+}
+
+fn http_status(status: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").into_bytes()
+}
+
+/// Sec-WebSocket-Accept for a Sec-WebSocket-Key: Base64(SHA-1(Key | GUID))
+pub fn accept_key(key: &str) -> String {
+    BASE64_STANDARD.encode(Sha1::digest(format!("{key}{ACCEPT_GUID}").as_bytes()))
+}
+
+/// A final, unmasked frame: servers never mask.
+/// Format: [Fin | Opcode (1)] [Len (1), 126 + Len (2) or 127 + Len (8)] [Payload]
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xFFFF => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+fn close_frame(code: u16) -> Vec<u8> {
+    frame(OP_CLOSE, &code.to_be_bytes())
+}
+
+/// Takes the next whole frame from a browser off `received`: they are always masked.
+/// Returns its opcode and unmasked payload.
+fn read_frame(received: &mut Vec<u8>, max_len: usize) -> Result<Option<(u8, Vec<u8>)>, WsError> {
+    let [first, second, ..] = received[..] else {
+        return Ok(None);
+    };
+    if first & 0x70 != 0 {
+        return Err(WsError::Protocol("reserved bits set"));
+    }
+    if second & 0x80 == 0 {
+        return Err(WsError::Protocol("unmasked frame"));
+    }
+    let opcode = first & 0x0F;
+    let (len, mut offset) = match second & 0x7F {
+        126 => match received.get(2..4) {
+            Some(len) => (u16::from_be_bytes(len.try_into().expect("sliced to 2")) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match received.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().expect("sliced to 8")), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if opcode >= OP_CLOSE && (len > 125 || first & 0x80 == 0) {
+        return Err(WsError::Protocol("fragmented or long control frame"));
+    }
+    if len > max_len as u64 {
+        return Err(WsError::FrameTooLarge(len));
+    }
+    let Some(mask) = received.get(offset..offset + 4).map(|m| <[u8; 4]>::try_from(m).expect("sliced to 4")) else {
+        return Ok(None);
+    };
+    offset += 4;
+    let Some(payload) = received.get(offset..offset + len as usize) else {
+        return Ok(None);
+    };
+    let payload = payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+    received.drain(..offset + len as usize);
+    Ok(Some((opcode, payload)))
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::panic::{ self, AssertUnwindSafe };
+use std::sync::{ mpsc, Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of threads running CPU-bound jobs (crypto batches, lookups) off the caller's thread.
+/// Dropping the pool lets queued jobs finish, then joins the threads.
+pub struct WorkerPool {
+    sender: Option<mpsc::Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("freedom-worker-{i}"))
+                    .spawn(move || {
+                        loop {
+                            // The lock is released before running the job
+                            let job = match receiver.lock() {
+                                Ok(receiver) => receiver.recv(),
+                                Err(_) => break,
+                            };
+                            let Ok(job) = job else {
+                                break; // Pool dropped
+                            };
+
+                            // A panicking job must not take the worker down with it
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                log::error!("Worker job panicked");
+                            }
+                        }
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(Self { sender: Some(sender), workers })
+    }
+
+    /// One thread per available core
+    pub fn with_default_size() -> io::Result<Self> {
+        Self::new(thread::available_parallelism().map_or(2, |n| n.get()))
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Whether the calling thread is one of this pool's workers, which must not drop the pool:
+    /// it would wait on its own join.
+    pub fn is_worker_thread(&self) -> bool {
+        let current = thread::current().id();
+        self.workers.iter().any(|worker| worker.thread().id() == current)
+    }
+
+    /// Queues a job. Returns false if the pool is shutting down.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> bool {
+        match &self.sender {
+            Some(sender) => sender.send(Box::new(job)).is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
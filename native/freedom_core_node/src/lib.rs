@@ -0,0 +1,99 @@
+use napi::bindgen_prelude::*;
+use napi_derive::napi;
+
+use freedom_core::clock::unix_now;
+use freedom_core::crypto::handshake::{ HandshakePayload, HANDSHAKE_WINDOW_SECS };
+use freedom_core::crypto::helper;
+use freedom_core::crypto::identity::{ NodeIdentity, IDENTITY_SECRET_SIZE };
+use freedom_core::ffi::status::FfiStatus;
+
+// N-API surface for tooling and an Electron client: identity, handshake validation and layer encryption.
+// Build with `npm run build` (napi-rs CLI), which writes `freedom_core_node.<platform>.node` + `index.js`.
+
+fn to_array<const N: usize>(bytes: &[u8], name: &str) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| {
+        Error::new(Status::InvalidArg, format!("`{name}` must be {N} bytes, got {}", bytes.len()))
+    })
+}
+
+fn js_error(error: impl ToString) -> Error {
+    Error::new(Status::GenericFailure, error.to_string())
+}
+
+/// A node identity (Ed25519 identity key + X25519 onion key).
+#[napi]
+pub struct Identity {
+    inner: NodeIdentity,
+}
+
+#[napi]
+impl Identity {
+    #[napi(factory)]
+    pub fn generate() -> Self {
+        Self { inner: NodeIdentity::generate() }
+    }
+
+    /// Restores an identity from the 64 bytes returned by `secretBytes`.
+    #[napi(factory)]
+    pub fn from_secret_bytes(secret: Buffer) -> Result<Self> {
+        let secret = to_array::<IDENTITY_SECRET_SIZE>(&secret, "secret")?;
+        Ok(Self { inner: NodeIdentity::from_secret_bytes(&secret) })
+    }
+
+    #[napi]
+    pub fn secret_bytes(&self) -> Buffer {
+        self.inner.to_secret_bytes().to_vec().into()
+    }
+
+    #[napi]
+    pub fn identity_key(&self) -> Buffer {
+        self.inner.identity_keypair.verifying_key().to_bytes().to_vec().into()
+    }
+
+    #[napi]
+    pub fn onion_key(&self) -> Buffer {
+        x25519_dalek::PublicKey::from(&self.inner.onion_secret).to_bytes().to_vec().into()
+    }
+
+    #[napi]
+    pub fn fingerprint(&self) -> String {
+        self.inner.fingerprint().to_string()
+    }
+
+    /// Builds a signed 136-byte handshake payload. `timestamp` is in seconds since UNIX epoch.
+    #[napi]
+    pub fn sign_handshake(&self, timestamp: i64) -> Result<Buffer> {
+        let timestamp = u64::try_from(timestamp).map_err(|_| Error::new(Status::InvalidArg, "Negative timestamp"))?;
+        Ok(self.inner.sign_handshake(timestamp).to_bytes().to_vec().into())
+    }
+}
+
+/// Validates a handshake payload like `ffi_validate_handshake`: size, key and signature encoding,
+/// Ed25519 signature, and a timestamp within `HANDSHAKE_WINDOW_SECS` of the local clock.
+/// Returns 0 if valid, or the same status code as the C ABI for the first failed check
+/// (InvalidSize -9, InvalidKey -10, InvalidSignature -11, VerificationFailed -5, StaleTimestamp -12).
+#[napi]
+pub fn validate_handshake(payload: Buffer) -> i32 {
+    let result = HandshakePayload::from_bytes(&payload).and_then(|payload| {
+        payload.verify()?;
+        payload.check_freshness(unix_now(), HANDSHAKE_WINDOW_SECS)
+    });
+
+    match result {
+        Ok(()) => FfiStatus::Ok.code(),
+        Err(e) => FfiStatus::from(&e).code(),
+    }
+}
+
+/// Encrypts with ChaCha20-Poly1305. Output: [Nonce (12)] + [Ciphertext]
+#[napi]
+pub fn encrypt_layer(key: Buffer, plaintext: Buffer) -> Result<Buffer> {
+    let key = to_array::<32>(&key, "key")?;
+    Ok(helper::encrypt_layer(&key, &plaintext).map_err(js_error)?.into())
+}
+
+#[napi]
+pub fn decrypt_layer(key: Buffer, ciphertext: Buffer) -> Result<Buffer> {
+    let key = to_array::<32>(&key, "key")?;
+    Ok(helper::try_decrypt_layer(&key, &ciphertext).map_err(js_error)?.into())
+}
@@ -1,6 +1,7 @@
 using System.Collections.Concurrent;
 using System.Net;
 using System.Numerics;
+using FalconNode.Core.Interop;
 using FalconNode.Core.State;
 
 namespace FalconNode.Core.Dht;
@@ -68,30 +69,12 @@ public class RoutingTable
         }
     }
 
-    private int GetBucketIndex(NodeId otherId)
-    {
-        var distance = NodeId.Distance(_localNodeId, otherId);
-
-        int zeros = 0;
-        foreach (var b in distance)
-        {
-            if (b == 0)
-            {
-                zeros += 8;
-            }
-            else
-            {
-                int temp = b;
-                while ((temp & 0x80) == 0)
-                {
-                    zeros++;
-                    temp <<= 1;
-                }
-            }
-        }
-
-        return Math.Min(255, zeros);
-    }
+    // Computed natively so the bucket layout matches the Rust core: the number of leading
+    // zero bits of the distance, capped at 255. The managed version this replaced counted
+    // the zero bits of every byte, past the first set bit too, and so put contacts in other
+    // (usually higher) buckets; nothing persists bucket indexes, so tables rebuilt after the
+    // change are simply laid out the new way.
+    private int GetBucketIndex(NodeId otherId) => RustDht.BucketIndex(_localNodeId, otherId);
 
     public void AddContact(Contact contact)
     {
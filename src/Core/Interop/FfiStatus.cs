@@ -0,0 +1,59 @@
+namespace FalconNode.Core.Interop;
+
+/// <summary>
+/// Status codes returned by every export of the native <c>freedom_core</c> library.
+/// </summary>
+/// <remarks>
+/// Must stay in sync with <c>FfiStatus</c> in <c>native/freedom_core/src/ffi/status.rs</c>.
+/// Exports returning a length or count use non-negative values for success and these codes for failure.
+/// </remarks>
+public enum FfiStatus
+{
+    /// <summary>The call succeeded.</summary>
+    Ok = 0,
+
+    /// <summary>A pointer was null or an argument had the wrong size.</summary>
+    InvalidArgument = -1,
+
+    /// <summary>The output buffer is smaller than the result.</summary>
+    BufferTooSmall = -2,
+
+    /// <summary>An encryption or decryption operation failed.</summary>
+    CryptoError = -3,
+
+    /// <summary>Input bytes could not be parsed.</summary>
+    ParseError = -4,
+
+    /// <summary>A signature or authentication check failed.</summary>
+    VerificationFailed = -5,
+
+    /// <summary>A peer's identity key differs from the one recorded in the trust store.</summary>
+    KeyChanged = -6,
+
+    /// <summary>A peer's identity key is on the denylist.</summary>
+    Denied = -7,
+
+    /// <summary>A file could not be read or written.</summary>
+    IoError = -8,
+
+    /// <summary>A fixed-size input (e.g. a handshake payload) had the wrong length.</summary>
+    InvalidSize = -9,
+
+    /// <summary>Key bytes do not encode a valid public key.</summary>
+    InvalidKey = -10,
+
+    /// <summary>Signature bytes are not a canonical Ed25519 signature encoding.</summary>
+    InvalidSignature = -11,
+
+    /// <summary>A handshake timestamp is too far from the local clock (replayed or skewed peer).</summary>
+    StaleTimestamp = -12,
+
+    /// <summary>A long-running operation stopped because its cancellation token was cancelled.</summary>
+    Cancelled = -13,
+
+    /// <summary>A frame header names a message type this library does not know.</summary>
+    UnknownMessageType = -14,
+
+    /// <summary>The native code panicked; the call had no effect.</summary>
+    Panic = -99,
+}
@@ -15,7 +15,7 @@ public static class RustCrypto
     /// <param name="myPrivateKey">The private key of the local node (32 bytes).</param>
     /// <param name="otherPublicKey">The public key of the other node (32 bytes).</param>
     /// <param name="output">The output buffer where the derived session key will be stored (32 bytes).</param>
-    /// <returns>Returns 1 on success, negative value on failure.</returns>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     private static extern unsafe int ffi_create_session_key(
         byte* myPrivateKey,
@@ -28,9 +28,9 @@ public static class RustCrypto
     /// </summary>
     /// <param name="data">The handshake payload data.</param>
     /// <param name="len">The length of the handshake payload data.</param>
-    /// <returns>Returns 1 if the handshake is valid, otherwise returns a negative value.</returns>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> if the handshake is valid, otherwise a negative <see cref="FfiStatus"/>.</returns>
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
-    private static extern unsafe int ffi_verify_handshake(byte* data, nuint len);
+    private static extern unsafe int ffi_validate_handshake(byte* data, nuint len);
 
     /// <summary>
     /// Encrypts a data layer using the provided key.
@@ -38,9 +38,9 @@ public static class RustCrypto
     /// <param name="key">The encryption key.</param>
     /// <param name="plain">The plaintext data to encrypt.</param>
     /// <param name="plainLen">The length of the plaintext data.</param>
-    /// <param name="output">The buffer where the encrypted data will be stored.</param>
+    /// <param name="output">The buffer where the encrypted data will be stored, or null to query the required length.</param>
     /// <param name="outCap">The capacity of the output buffer.</param>
-    /// <returns>Returns 1 on success, negative value on failure.</returns>
+    /// <returns>Returns the number of bytes written (or required), or a negative <see cref="FfiStatus"/> on failure.</returns>
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     private static extern unsafe int ffi_encrypt_layer(
         byte* key,
@@ -56,9 +56,9 @@ public static class RustCrypto
     /// <param name="key">The decryption key.</param>
     /// <param name="cipher">The ciphertext data to decrypt.</param>
     /// <param name="cipherLen">The length of the ciphertext data.</param>
-    /// <param name="output">The buffer where the decrypted data will be stored.</param>
+    /// <param name="output">The buffer where the decrypted data will be stored, or null to query the required length.</param>
     /// <param name="outCap">The capacity of the output buffer.</param>
-    /// <returns>Returns 1 on success, negative value on failure.</returns>
+    /// <returns>Returns the number of bytes written (or required), or a negative <see cref="FfiStatus"/> on failure.</returns>
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     private static extern unsafe int ffi_decrypt_layer(
         byte* key,
@@ -68,6 +68,92 @@ public static class RustCrypto
         nuint outCap
     );
 
+    /// <summary>
+    /// Encrypts a data layer into a buffer allocated by the native library.
+    /// </summary>
+    /// <param name="key">The encryption key.</param>
+    /// <param name="plain">The plaintext data to encrypt.</param>
+    /// <param name="plainLen">The length of the plaintext data.</param>
+    /// <param name="output">Receives the native buffer; must be released with <see cref="ffi_free"/>.</param>
+    /// <param name="outLen">Receives the length of the native buffer.</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_encrypt_layer_alloc(
+        byte* key,
+        byte* plain,
+        nuint plainLen,
+        byte** output,
+        nuint* outLen
+    );
+
+    /// <summary>
+    /// Releases a buffer allocated by the native library.
+    /// </summary>
+    /// <param name="ptr">The buffer returned by an <c>_alloc</c> export.</param>
+    /// <param name="len">The length returned alongside it.</param>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe void ffi_free(byte* ptr, nuint len);
+
+    /// <summary>
+    /// Encrypts several independent plaintexts, each with its own key, in one native call.
+    /// </summary>
+    /// <param name="keys">The keys, 32 bytes each, back to back.</param>
+    /// <param name="plains">The plaintext pointers.</param>
+    /// <param name="plainLens">The plaintext lengths.</param>
+    /// <param name="count">The number of items.</param>
+    /// <param name="outputs">The output buffer pointers.</param>
+    /// <param name="outputCaps">The output buffer capacities.</param>
+    /// <param name="outputLens">Receives the (required) length of each output.</param>
+    /// <returns>Returns <paramref name="count"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_encrypt_layer_batch(
+        byte* keys,
+        byte** plains,
+        nuint* plainLens,
+        nuint count,
+        byte** outputs,
+        nuint* outputCaps,
+        nuint* outputLens
+    );
+
+    /// <summary>
+    /// Wraps a payload in one encryption layer per hop (the last hop's layer is the innermost).
+    /// </summary>
+    /// <param name="keys">The hop keys, 32 bytes each, back to back and ordered from the first hop.</param>
+    /// <param name="numKeys">The number of hop keys.</param>
+    /// <param name="payload">The payload to wrap.</param>
+    /// <param name="payloadLen">The length of the payload.</param>
+    /// <param name="output">The buffer where the cell will be stored, or null to query the required length.</param>
+    /// <param name="outCap">The capacity of the output buffer.</param>
+    /// <returns>Returns the number of bytes written (or required), or a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_onion_wrap(
+        byte* keys,
+        nuint numKeys,
+        byte* payload,
+        nuint payloadLen,
+        byte* output,
+        nuint outCap
+    );
+
+    /// <summary>
+    /// Removes one encryption layer from a cell.
+    /// </summary>
+    /// <param name="key">The hop key (32 bytes).</param>
+    /// <param name="cell">The cell to peel.</param>
+    /// <param name="cellLen">The length of the cell.</param>
+    /// <param name="output">The buffer where the inner cell will be stored, or null to query the required length.</param>
+    /// <param name="outCap">The capacity of the output buffer.</param>
+    /// <returns>Returns the number of bytes written (or required), or a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_onion_peel(
+        byte* key,
+        byte* cell,
+        nuint cellLen,
+        byte* output,
+        nuint outCap
+    );
+
     /// <summary>
     /// Calculates the CRC32 checksum of the given data.
     /// </summary>
@@ -77,8 +163,62 @@ public static class RustCrypto
     [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
     private static extern unsafe uint ffi_calculate_crc32(byte* data, nuint len);
 
+    /// <summary>
+    /// Gets the error code of the last failed native call on the current thread.
+    /// </summary>
+    /// <returns>The <see cref="FfiStatus"/> code, or 0 if the last call succeeded.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern int ffi_last_error_code();
+
+    /// <summary>
+    /// Copies the UTF-8 message of the last failed native call on the current thread.
+    /// </summary>
+    /// <param name="output">The buffer where the message will be stored, or null to query the required length.</param>
+    /// <param name="outCap">The capacity of the output buffer.</param>
+    /// <returns>The number of bytes written (or required), 0 if there is no error, or a negative value if the buffer is too small.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_last_error_message(byte* output, nuint outCap);
+
     // --- SAFE WRAPPERS ---
 
+    /// <summary>
+    /// Gets the error code of the last failed native call on the current thread.
+    /// </summary>
+    /// <remarks>
+    /// Must be called on the same thread, right after the failing call; every native call resets it.
+    /// </remarks>
+    /// <returns>The status of the last failed call, or <see cref="FfiStatus.Ok"/> if it succeeded.</returns>
+    public static FfiStatus GetLastErrorCode() => (FfiStatus)ffi_last_error_code();
+
+    /// <summary>
+    /// Gets the description of the last failed native call on the current thread.
+    /// </summary>
+    /// <remarks>
+    /// Must be called on the same thread, right after the failing call; every native call resets it.
+    /// </remarks>
+    /// <returns>The error message, or an empty string if the last call succeeded.</returns>
+    public static string GetLastErrorMessage()
+    {
+        unsafe
+        {
+            // First call sizes the buffer, second call fills it
+            int required = ffi_last_error_message(null, 0);
+            if (required <= 0)
+            {
+                return string.Empty;
+            }
+
+            byte[] buffer = new byte[required];
+            fixed (byte* bufferPtr = buffer)
+            {
+                int written = ffi_last_error_message(bufferPtr, (nuint)buffer.Length);
+                return written > 0
+                    ? System.Text.Encoding.UTF8.GetString(buffer, 0, written)
+                    : string.Empty;
+            }
+        }
+    }
+
     /// <summary>
     /// Derives a shared session key using X25519 key agreement.
     /// </summary>
@@ -105,31 +245,44 @@ public static class RustCrypto
             fixed (byte* outPtr = output)
             {
                 int result = ffi_create_session_key(myPrivPtr, otherPubPtr, outPtr);
-                if (result != 1)
+                if (result != (int)FfiStatus.Ok)
                 {
-                    throw new InvalidOperationException("Rust key derivation failed.");
+                    throw new InvalidOperationException(
+                        $"Rust key derivation failed: {GetLastErrorMessage()}"
+                    );
                 }
             }
         }
     }
 
     /// <summary>
-    /// Verifies the integrity and authenticity of a handshake payload.
+    /// Validates a handshake payload and reports why it was rejected.
     /// </summary>
-    /// <param name="handshakePayload">The handshake payload to verify.</param>
-    /// <returns>True if the handshake is valid; otherwise, false.</returns>
-    public static bool VerifyHandshake(ReadOnlySpan<byte> handshakePayload)
+    /// <param name="handshakePayload">The handshake payload to validate.</param>
+    /// <returns>
+    /// <see cref="FfiStatus.Ok"/> if the handshake is valid; otherwise <see cref="FfiStatus.InvalidSize"/>,
+    /// <see cref="FfiStatus.InvalidKey"/>, <see cref="FfiStatus.InvalidSignature"/>,
+    /// <see cref="FfiStatus.VerificationFailed"/> or <see cref="FfiStatus.StaleTimestamp"/>.
+    /// </returns>
+    public static FfiStatus ValidateHandshake(ReadOnlySpan<byte> handshakePayload)
     {
         unsafe
         {
             fixed (byte* ptr = handshakePayload)
             {
-                int result = ffi_verify_handshake(ptr, (nuint)handshakePayload.Length);
-                return result == 1;
+                return (FfiStatus)ffi_validate_handshake(ptr, (nuint)handshakePayload.Length);
             }
         }
     }
 
+    /// <summary>
+    /// Verifies the integrity, authenticity and freshness of a handshake payload.
+    /// </summary>
+    /// <param name="handshakePayload">The handshake payload to verify.</param>
+    /// <returns>True if the handshake is valid; otherwise, false.</returns>
+    public static bool VerifyHandshake(ReadOnlySpan<byte> handshakePayload) =>
+        ValidateHandshake(handshakePayload) == FfiStatus.Ok;
+
     /// <summary>
     /// Encrypts a data layer using the provided key.
     /// </summary>
@@ -163,6 +316,127 @@ public static class RustCrypto
         }
     }
 
+    /// <summary>
+    /// Encrypts a data layer using the provided key, letting the native library size the result.
+    /// </summary>
+    /// <param name="key">The encryption key as a byte span.</param>
+    /// <param name="plainText">The plaintext data to encrypt.</param>
+    /// <returns>The encrypted data.</returns>
+    /// <exception cref="InvalidOperationException">Thrown if the Rust encryption fails.</exception>
+    public static byte[] EncryptLayer(ReadOnlySpan<byte> key, ReadOnlySpan<byte> plainText)
+    {
+        unsafe
+        {
+            fixed (byte* keyPtr = key)
+            fixed (byte* plainPtr = plainText)
+            {
+                byte* outPtr = null;
+                nuint outLen = 0;
+
+                int result = ffi_encrypt_layer_alloc(keyPtr, plainPtr, (nuint)plainText.Length, &outPtr, &outLen);
+                if (result != (int)FfiStatus.Ok)
+                {
+                    throw new InvalidOperationException($"Rust encryption failed: {GetLastErrorMessage()}");
+                }
+
+                try
+                {
+                    return new ReadOnlySpan<byte>(outPtr, (int)outLen).ToArray();
+                }
+                finally
+                {
+                    ffi_free(outPtr, outLen);
+                }
+            }
+        }
+    }
+
+    /// <summary>
+    /// Encrypts many cells, each under its own key, with a single native call.
+    /// </summary>
+    /// <param name="keys">The keys (32 bytes each), one per plaintext.</param>
+    /// <param name="plainTexts">The plaintexts to encrypt.</param>
+    /// <returns>The encrypted cells, in the same order (each plaintext + 28 bytes).</returns>
+    /// <exception cref="ArgumentException">Thrown if the counts differ or a key is not 32 bytes.</exception>
+    /// <exception cref="InvalidOperationException">Thrown if the Rust encryption fails.</exception>
+    public static byte[][] EncryptLayerBatch(IReadOnlyList<byte[]> keys, IReadOnlyList<byte[]> plainTexts)
+    {
+        if (keys.Count != plainTexts.Count)
+        {
+            throw new ArgumentException("Every plaintext needs exactly one key.", nameof(keys));
+        }
+
+        int count = plainTexts.Count;
+        byte[] keyBytes = new byte[checked(count * 32)];
+        byte[][] outputs = new byte[count][];
+        nuint[] plainLens = new nuint[count];
+        nuint[] outputCaps = new nuint[count];
+        nuint[] outputLens = new nuint[count];
+        GCHandle[] handles = new GCHandle[checked(count * 2)];
+
+        for (int i = 0; i < count; i++)
+        {
+            if (keys[i].Length != 32)
+            {
+                throw new ArgumentException("Every key must be 32 bytes.", nameof(keys));
+            }
+            keys[i].CopyTo(keyBytes, i * 32);
+
+            outputs[i] = new byte[checked(plainTexts[i].Length + 28)];
+            plainLens[i] = (nuint)plainTexts[i].Length;
+            outputCaps[i] = (nuint)outputs[i].Length;
+        }
+
+        unsafe
+        {
+            byte** plainPtrs = stackalloc byte*[count];
+            byte** outputPtrs = stackalloc byte*[count];
+
+            try
+            {
+                for (int i = 0; i < count; i++)
+                {
+                    handles[2 * i] = GCHandle.Alloc(plainTexts[i], GCHandleType.Pinned);
+                    handles[2 * i + 1] = GCHandle.Alloc(outputs[i], GCHandleType.Pinned);
+                    plainPtrs[i] = (byte*)handles[2 * i].AddrOfPinnedObject();
+                    outputPtrs[i] = (byte*)handles[2 * i + 1].AddrOfPinnedObject();
+                }
+
+                fixed (byte* keysPtr = keyBytes)
+                fixed (nuint* plainLensPtr = plainLens)
+                fixed (nuint* outputCapsPtr = outputCaps)
+                fixed (nuint* outputLensPtr = outputLens)
+                {
+                    int result = ffi_encrypt_layer_batch(
+                        keysPtr,
+                        plainPtrs,
+                        plainLensPtr,
+                        (nuint)count,
+                        outputPtrs,
+                        outputCapsPtr,
+                        outputLensPtr
+                    );
+                    if (result < 0)
+                    {
+                        throw new InvalidOperationException($"Rust batch encryption failed: {GetLastErrorMessage()}");
+                    }
+                }
+            }
+            finally
+            {
+                foreach (GCHandle handle in handles)
+                {
+                    if (handle.IsAllocated)
+                    {
+                        handle.Free();
+                    }
+                }
+            }
+        }
+
+        return outputs;
+    }
+
     /// <summary>
     /// Decrypts a data layer using the provided key.
     /// </summary>
@@ -193,6 +467,72 @@ public static class RustCrypto
         }
     }
 
+    /// <summary>
+    /// Wraps a payload in one encryption layer per hop with a single native call.
+    /// </summary>
+    /// <param name="hopKeys">The hop keys (32 bytes each), ordered from the first hop to the last.</param>
+    /// <param name="payload">The payload to wrap.</param>
+    /// <returns>The onion cell (payload + 28 bytes per hop).</returns>
+    /// <exception cref="ArgumentException">Thrown if a key is not 32 bytes.</exception>
+    /// <exception cref="InvalidOperationException">Thrown if the Rust wrapping fails.</exception>
+    public static byte[] OnionWrap(IReadOnlyList<byte[]> hopKeys, ReadOnlySpan<byte> payload)
+    {
+        byte[] keys = new byte[hopKeys.Count * 32];
+        for (int i = 0; i < hopKeys.Count; i++)
+        {
+            if (hopKeys[i].Length != 32)
+            {
+                throw new ArgumentException("Every hop key must be 32 bytes.", nameof(hopKeys));
+            }
+            hopKeys[i].CopyTo(keys, i * 32);
+        }
+
+        byte[] cell = new byte[payload.Length + (28 * hopKeys.Count)];
+
+        unsafe
+        {
+            fixed (byte* keysPtr = keys)
+            fixed (byte* payloadPtr = payload)
+            fixed (byte* cellPtr = cell)
+            {
+                int written = ffi_onion_wrap(
+                    keysPtr,
+                    (nuint)hopKeys.Count,
+                    payloadPtr,
+                    (nuint)payload.Length,
+                    cellPtr,
+                    (nuint)cell.Length
+                );
+                if (written < 0)
+                {
+                    throw new InvalidOperationException($"Rust onion wrap failed: {GetLastErrorMessage()}");
+                }
+            }
+        }
+
+        return cell;
+    }
+
+    /// <summary>
+    /// Removes one encryption layer from a cell.
+    /// </summary>
+    /// <param name="key">The hop key (32 bytes).</param>
+    /// <param name="cell">The cell to peel.</param>
+    /// <param name="output">The output span (at least the cell length minus 28 bytes).</param>
+    /// <returns>The number of bytes written, or a negative <see cref="FfiStatus"/> if the layer is not ours or was tampered with.</returns>
+    public static int OnionPeel(ReadOnlySpan<byte> key, ReadOnlySpan<byte> cell, Span<byte> output)
+    {
+        unsafe
+        {
+            fixed (byte* keyPtr = key)
+            fixed (byte* cellPtr = cell)
+            fixed (byte* outPtr = output)
+            {
+                return ffi_onion_peel(keyPtr, cellPtr, (nuint)cell.Length, outPtr, (nuint)output.Length);
+            }
+        }
+    }
+
     /// <summary>
     /// Calculates the CRC32 checksum of the given data.
     /// </summary>
@@ -0,0 +1,116 @@
+using System.Runtime.InteropServices;
+using FalconNode.Core.Dht;
+
+namespace FalconNode.Core.Interop;
+
+/// <summary>
+/// Provides .NET wrappers for the DHT keyspace math implemented in the native Rust library <c>freedom_core</c>,
+/// so both sides agree byte-for-byte on NodeId derivation, distances and bucket placement.
+/// </summary>
+public static class RustDht
+{
+    private const string DllName = "freedom_core";
+
+    /// <summary>
+    /// Derives the NodeId of a key (SHA-256 of its bytes).
+    /// </summary>
+    /// <param name="key">The key bytes.</param>
+    /// <param name="keyLen">The length of the key.</param>
+    /// <param name="nodeIdOut">The buffer where the NodeId will be stored (32 bytes).</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_node_id_from_key(byte* key, nuint keyLen, byte* nodeIdOut);
+
+    /// <summary>
+    /// Computes the XOR distance between two NodeIds.
+    /// </summary>
+    /// <param name="a">The first NodeId (32 bytes).</param>
+    /// <param name="b">The second NodeId (32 bytes).</param>
+    /// <param name="distanceOut">The buffer where the distance will be stored (32 bytes).</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_xor_distance(byte* a, byte* b, byte* distanceOut);
+
+    /// <summary>
+    /// Computes the routing-table bucket of a NodeId relative to the local NodeId.
+    /// </summary>
+    /// <param name="local">The local NodeId (32 bytes).</param>
+    /// <param name="other">The other NodeId (32 bytes).</param>
+    /// <returns>The bucket index (0..255), or a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_bucket_index(byte* local, byte* other);
+
+    // --- SAFE WRAPPERS ---
+
+    /// <summary>
+    /// Derives the NodeId of a key (SHA-256 of its bytes).
+    /// </summary>
+    /// <param name="key">The key bytes (e.g. an Ed25519 identity key).</param>
+    /// <returns>The derived NodeId.</returns>
+    public static NodeId NodeIdFromKey(ReadOnlySpan<byte> key)
+    {
+        Span<byte> nodeId = stackalloc byte[NodeId.Size];
+
+        unsafe
+        {
+            fixed (byte* keyPtr = key)
+            fixed (byte* outPtr = nodeId)
+            {
+                ThrowIfFailed(ffi_node_id_from_key(keyPtr, (nuint)key.Length, outPtr));
+            }
+        }
+
+        return new NodeId(nodeId);
+    }
+
+    /// <summary>
+    /// Computes the XOR distance between two NodeIds.
+    /// </summary>
+    /// <param name="a">The first NodeId.</param>
+    /// <param name="b">The second NodeId.</param>
+    /// <returns>The distance as big-endian bytes (smaller = closer).</returns>
+    public static byte[] XorDistance(NodeId a, NodeId b)
+    {
+        byte[] distance = new byte[NodeId.Size];
+
+        unsafe
+        {
+            fixed (byte* aPtr = a.Span)
+            fixed (byte* bPtr = b.Span)
+            fixed (byte* outPtr = distance)
+            {
+                ThrowIfFailed(ffi_xor_distance(aPtr, bPtr, outPtr));
+            }
+        }
+
+        return distance;
+    }
+
+    /// <summary>
+    /// Computes the routing-table bucket of <paramref name="other"/> relative to <paramref name="local"/>.
+    /// </summary>
+    /// <param name="local">The local NodeId.</param>
+    /// <param name="other">The other NodeId.</param>
+    /// <returns>The number of leading zero bits of the distance, capped at 255.</returns>
+    public static int BucketIndex(NodeId local, NodeId other)
+    {
+        unsafe
+        {
+            fixed (byte* localPtr = local.Span)
+            fixed (byte* otherPtr = other.Span)
+            {
+                int index = ffi_bucket_index(localPtr, otherPtr);
+                ThrowIfFailed(index);
+                return index;
+            }
+        }
+    }
+
+    private static void ThrowIfFailed(int result)
+    {
+        if (result < 0)
+        {
+            throw new InvalidOperationException($"Rust DHT call failed: {RustCrypto.GetLastErrorMessage()}");
+        }
+    }
+}
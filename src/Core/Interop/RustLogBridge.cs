@@ -0,0 +1,72 @@
+using System.Runtime.CompilerServices;
+using System.Runtime.InteropServices;
+
+namespace FalconNode.Core.Interop;
+
+/// <summary>
+/// Routes log records emitted inside the native library <c>freedom_core</c> to an <see cref="ILogger"/>.
+/// </summary>
+public static unsafe class RustLogBridge
+{
+    private const string DllName = "freedom_core";
+
+    private static ILogger? _logger;
+
+    /// <summary>
+    /// Registers the native log sink.
+    /// </summary>
+    /// <param name="callback">The sink, or null to unregister it. Levels use <see cref="LogLevel"/> values (Trace..Error).</param>
+    /// <param name="minLevel">The minimum level forwarded; anything above <see cref="LogLevel.Error"/> disables native logging.</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern int ffi_set_log_callback(
+        delegate* unmanaged[Cdecl]<int, byte*, nuint, void> callback,
+        int minLevel
+    );
+
+    /// <summary>
+    /// Forwards native log records at or above <paramref name="minLevel"/> to <paramref name="logger"/>.
+    /// </summary>
+    /// <param name="logger">The logger receiving the native records.</param>
+    /// <param name="minLevel">The minimum level forwarded by the native side.</param>
+    /// <exception cref="InvalidOperationException">Thrown if the native logger cannot be installed.</exception>
+    public static void Attach(ILogger logger, LogLevel minLevel = LogLevel.Information)
+    {
+        _logger = logger;
+
+        int result = ffi_set_log_callback(&OnNativeLog, (int)minLevel);
+        if (result != (int)FfiStatus.Ok)
+        {
+            throw new InvalidOperationException(
+                $"Failed to attach native logger: {RustCrypto.GetLastErrorMessage()}"
+            );
+        }
+    }
+
+    /// <summary>
+    /// Stops forwarding native log records.
+    /// </summary>
+    public static void Detach()
+    {
+        ffi_set_log_callback(null, (int)LogLevel.None);
+        _logger = null;
+    }
+
+    [UnmanagedCallersOnly(CallConvs = [typeof(CallConvCdecl)])]
+    private static void OnNativeLog(int level, byte* message, nuint messageLen)
+    {
+        // Exceptions must not cross back into native code
+        try
+        {
+            _logger?.Log(
+                (LogLevel)level,
+                "{NativeMessage}",
+                System.Text.Encoding.UTF8.GetString(message, (int)messageLen)
+            );
+        }
+        catch
+        {
+            // Logging is best effort
+        }
+    }
+}
@@ -0,0 +1,49 @@
+using System.Runtime.InteropServices;
+using FalconNode.Core.Network;
+
+namespace FalconNode.Core.Interop;
+
+/// <summary>
+/// Provides .NET wrappers for the wire-format parsing implemented in the native Rust library <c>freedom_core</c>.
+/// </summary>
+public static class RustProtocol
+{
+    private const string DllName = "freedom_core";
+
+    /// <summary>
+    /// Parses the 16-byte fixed header at the start of a frame.
+    /// </summary>
+    /// <param name="data">The frame bytes (at least 16).</param>
+    /// <param name="len">The length of the frame bytes.</param>
+    /// <param name="header">Receives the decoded header; its layout mirrors <c>FfiFixedHeader</c>.</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_header_parse(byte* data, nuint len, FixedHeader* header);
+
+    // --- SAFE WRAPPERS ---
+
+    /// <summary>
+    /// Parses the fixed header at the start of <paramref name="source"/> using the native layout.
+    /// </summary>
+    /// <param name="source">The bytes to read the header from.</param>
+    /// <returns>The decoded header. The payload checksum is not checked.</returns>
+    /// <exception cref="FormatException">Thrown if the source is shorter than <see cref="FixedHeader.Size"/>, or names a message type the native library does not know.</exception>
+    public static FixedHeader ParseHeader(ReadOnlySpan<byte> source)
+    {
+        FixedHeader header;
+
+        unsafe
+        {
+            fixed (byte* sourcePtr = source)
+            {
+                int result = ffi_header_parse(sourcePtr, (nuint)source.Length, &header);
+                if (result != (int)FfiStatus.Ok)
+                {
+                    throw new FormatException($"Invalid frame header: {RustCrypto.GetLastErrorMessage()}");
+                }
+            }
+        }
+
+        return header;
+    }
+}
@@ -0,0 +1,93 @@
+using System.Runtime.InteropServices;
+
+namespace FalconNode.Core.Interop;
+
+/// <summary>
+/// Mirror of <c>FfiVersion</c> in <c>native/freedom_core/src/ffi/version.rs</c>.
+/// </summary>
+[StructLayout(LayoutKind.Sequential)]
+public readonly struct NativeVersion
+{
+    public readonly uint Major;
+    public readonly uint Minor;
+    public readonly uint Patch;
+    public readonly uint Abi;
+    public readonly uint WireProtocols; // Bit N set = wire protocol version N + 1
+
+    /// <summary>
+    /// Returns whether the native library speaks the given wire protocol version.
+    /// </summary>
+    /// <param name="version">The <see cref="Network.FixedHeader"/> version byte.</param>
+    public bool SupportsWireProtocol(byte version) =>
+        version is >= 1 and <= 32 && (WireProtocols & (1u << (version - 1))) != 0;
+
+    public override string ToString() => $"{Major}.{Minor}.{Patch} (ABI {Abi})";
+}
+
+/// <summary>
+/// Checks that the loaded native library <c>freedom_core</c> matches the ABI this build was compiled against.
+/// </summary>
+public static class RustVersion
+{
+    private const string DllName = "freedom_core";
+
+    /// <summary>
+    /// The native ABI this assembly was written for. Must match <c>ABI_VERSION</c> in
+    /// <c>native/freedom_core/src/ffi/version.rs</c>.
+    /// </summary>
+    public const uint ExpectedAbiVersion = 4;
+
+    /// <summary>
+    /// Reads the version of the loaded native library.
+    /// </summary>
+    /// <param name="version">Receives the version.</param>
+    /// <returns>Returns <see cref="FfiStatus.Ok"/> on success, a negative <see cref="FfiStatus"/> on failure.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern unsafe int ffi_core_version(NativeVersion* version);
+
+    /// <summary>
+    /// Checks whether the native library implements the given ABI version.
+    /// </summary>
+    /// <param name="expected">The ABI version the host expects.</param>
+    /// <returns>1 if compatible, 0 if not.</returns>
+    [DllImport(DllName, CallingConvention = CallingConvention.Cdecl)]
+    private static extern int ffi_abi_compatible(uint expected);
+
+    // --- SAFE WRAPPERS ---
+
+    /// <summary>
+    /// Gets the version of the loaded native library.
+    /// </summary>
+    /// <returns>The native library version.</returns>
+    public static NativeVersion GetNativeVersion()
+    {
+        NativeVersion version;
+
+        unsafe
+        {
+            int result = ffi_core_version(&version);
+            if (result != (int)FfiStatus.Ok)
+            {
+                throw new InvalidOperationException(
+                    $"Failed to query native version: {RustCrypto.GetLastErrorMessage()}"
+                );
+            }
+        }
+
+        return version;
+    }
+
+    /// <summary>
+    /// Refuses to continue if the loaded native library has a different ABI.
+    /// </summary>
+    /// <exception cref="InvalidOperationException">Thrown if the native ABI is incompatible.</exception>
+    public static void EnsureCompatible()
+    {
+        if (ffi_abi_compatible(ExpectedAbiVersion) != 1)
+        {
+            throw new InvalidOperationException(
+                $"Incompatible native library freedom_core {GetNativeVersion()}: expected ABI {ExpectedAbiVersion}."
+            );
+        }
+    }
+}
@@ -11,6 +11,8 @@ namespace FalconNode.Core.Network;
 /// The <see cref="FixedHeader"/> struct is packed to 1 byte alignment and has a fixed size of 16 bytes.
 /// It contains metadata for a network message, including version, flags, message type, reserved byte,
 /// unique request ID, payload length, and CRC32 checksum.
+/// The in-memory layout mirrors <c>FfiFixedHeader</c> in <c>native/freedom_core/src/ffi/protocol.rs</c>,
+/// so <see cref="Interop.RustProtocol.ParseHeader"/> can fill it directly (keep both in sync).
 /// </remarks>
 /// <summary>
 /// Represents the fixed header portion of a network packet.
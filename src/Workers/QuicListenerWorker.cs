@@ -4,6 +4,7 @@ using System.Net.Quic;
 using System.Net.Security;
 using System.Net.Sockets;
 using System.Threading.Channels;
+using FalconNode.Core.Interop;
 using FalconNode.Core.Messages;
 using FalconNode.Core.Network;
 using FalconNode.Core.Security;
@@ -186,7 +187,7 @@ public class QuicListenerWorker : BackgroundService
                     break;
                 }
 
-                var header = FixedHeader.ReadFromSpan(headerBuffer.AsSpan(0, FixedHeader.Size));
+                var header = RustProtocol.ParseHeader(headerBuffer.AsSpan(0, FixedHeader.Size));
 
                 if (header.PayloadLength > 1024 * 1024 * 5)
                 {